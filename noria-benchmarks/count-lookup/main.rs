@@ -0,0 +1,109 @@
+#[macro_use]
+extern crate clap;
+extern crate hdrhistogram;
+extern crate noria;
+
+use std::time::{Duration, Instant};
+
+use clap::{App, Arg};
+use hdrhistogram::Histogram;
+
+use noria::{ControllerBuilder, DataType};
+
+// Rows are inserted into the base table this many at a time.
+const INSERT_BATCH: usize = 1000;
+
+fn as_ns(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000_000 + u64::from(d.subsec_nanos())
+}
+
+fn record(hist: &mut Histogram<u64>, ns: u64) {
+    if hist.record(ns).is_err() {
+        let m = hist.high();
+        hist.record(m).unwrap();
+    }
+}
+
+fn report(name: &str, hist: &Histogram<u64>) {
+    for &q in &[0.5, 0.95, 0.99] {
+        println!(
+            "{}\t{}\t{:.2}\t(ns)",
+            name,
+            (q * 100.0) as usize,
+            hist.value_at_quantile(q)
+        );
+    }
+    println!("{}\t100\t{:.2}\t(ns)", name, hist.max());
+}
+
+fn main() {
+    let args = App::new("count-lookup")
+        .version("0.1")
+        .about(
+            "Benchmarks counting the rows for a key with many rows, by fetching the rows or by \
+             counting them in the reader",
+        )
+        .arg(
+            Arg::with_name("rows")
+                .long("rows")
+                .value_name("N")
+                .default_value("100000")
+                .help("Number of rows for the key."),
+        )
+        .arg(
+            Arg::with_name("reads")
+                .long("reads")
+                .value_name("N")
+                .default_value("100")
+                .help("Number of times to count the rows each way."),
+        )
+        .arg(Arg::with_name("verbose").long("verbose").short("v"))
+        .get_matches();
+
+    let nrows = value_t_or_exit!(args, "rows", i64);
+    let reads = value_t_or_exit!(args, "reads", usize);
+    let verbose = args.is_present("verbose");
+    assert!(nrows > 0);
+
+    let sql = "CREATE TABLE Vote (aid int, uid int);
+               QUERY VotesByArticle: SELECT aid, uid FROM Vote WHERE aid = ?;";
+
+    // keep the view fully materialized, so that we only measure hits
+    let mut builder = ControllerBuilder::default();
+    builder.disable_partial();
+    let mut g = builder.build_local().unwrap();
+    g.install_recipe(sql).unwrap();
+
+    let mut vote = g.table("Vote").unwrap();
+    let mut view = g.view("VotesByArticle").unwrap();
+
+    if verbose {
+        eprintln!("Populating view with {} rows for one key", nrows);
+    }
+    let key = vec![DataType::from(1)];
+    let rows: Vec<Vec<DataType>> = (0..nrows).map(|uid| vec![1.into(), uid.into()]).collect();
+    for chunk in rows.chunks(INSERT_BATCH) {
+        vote.insert_all(chunk.to_vec()).unwrap();
+    }
+    while view.count(&key, true).unwrap() < nrows as usize {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    let mut fetch = Histogram::<u64>::new(4).unwrap();
+    let mut count = Histogram::<u64>::new(4).unwrap();
+    for _ in 0..reads {
+        let start = Instant::now();
+        let n = view.lookup(&key, true).unwrap().len();
+        record(&mut fetch, as_ns(start.elapsed()));
+        assert_eq!(n, nrows as usize);
+
+        let start = Instant::now();
+        let n = view.count(&key, true).unwrap();
+        record(&mut count, as_ns(start.elapsed()));
+        assert_eq!(n, nrows as usize);
+    }
+
+    println!("# {} reads of a key with {} rows", reads, nrows);
+    report("fetch", &fetch);
+    report("count", &count);
+}
@@ -0,0 +1,113 @@
+#[macro_use]
+extern crate clap;
+extern crate dataflow;
+extern crate noria;
+
+use std::time::{Duration, Instant};
+
+use clap::{App, Arg};
+
+use dataflow::node::special::Base;
+use dataflow::ops::identity::Identity;
+use noria::{ControllerBuilder, DataType, DomainStrategy};
+
+fn as_ns(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000_000 + u64::from(d.subsec_nanos())
+}
+
+fn main() {
+    let args = App::new("domain-chain")
+        .version("0.1")
+        .about(
+            "Benchmarks write throughput through a chain of nodes in one process, with every node \
+             in a domain of its own or all of them in one domain. Small batches send many \
+             packets across each domain boundary, which is where handing a domain all of its \
+             ready packets in one channel send shows up",
+        )
+        .arg(
+            Arg::with_name("hops")
+                .long("hops")
+                .value_name("N")
+                .default_value("4")
+                .help("Number of nodes below the base table."),
+        )
+        .arg(
+            Arg::with_name("rows")
+                .long("rows")
+                .value_name("N")
+                .default_value("1000000")
+                .help("Number of rows to write."),
+        )
+        .arg(
+            Arg::with_name("batch")
+                .long("batch")
+                .value_name("N")
+                .default_value("100")
+                .help("Number of rows written at a time."),
+        )
+        .arg(
+            Arg::with_name("one-domain")
+                .long("one-domain")
+                .help("Put the whole chain in a single domain."),
+        )
+        .arg(Arg::with_name("verbose").long("verbose").short("v"))
+        .get_matches();
+
+    let hops = value_t_or_exit!(args, "hops", usize);
+    let nrows = value_t_or_exit!(args, "rows", i64);
+    let batch = value_t_or_exit!(args, "batch", usize);
+    let one_domain = args.is_present("one-domain");
+    let verbose = args.is_present("verbose");
+    assert!(hops > 0);
+    assert!(nrows > 0);
+    assert!(batch > 0);
+
+    let mut builder = ControllerBuilder::default();
+    builder.disable_partial();
+    builder.set_sharding(None);
+    builder.set_domain_strategy(if one_domain {
+        DomainStrategy::FirstParent
+    } else {
+        DomainStrategy::FewestCuts { max_nodes: 1 }
+    });
+    let mut g = builder.build_local().unwrap();
+    g.migrate(move |mig| {
+        let mut last = mig.add_base("Vote", &["aid", "uid"], Base::default());
+        for i in 0..hops {
+            last = mig.add_ingredient(format!("hop{}", i), &["aid", "uid"], Identity::new(last));
+        }
+        mig.maintain("Votes".to_owned(), last, &[0]);
+    });
+
+    let mut vote = g.table("Vote").unwrap();
+    let mut view = g.view("Votes").unwrap();
+    let domains = g.statistics().unwrap().totals.domains;
+
+    if verbose {
+        eprintln!(
+            "Writing {} rows through {} nodes in {} domains",
+            nrows, hops, domains
+        );
+    }
+    let key = vec![DataType::from(1)];
+    let rows: Vec<Vec<DataType>> = (0..nrows).map(|uid| vec![1.into(), uid.into()]).collect();
+    let start = Instant::now();
+    for chunk in rows.chunks(batch) {
+        vote.insert_all(chunk.to_vec()).unwrap();
+    }
+    while view.count(&key, true).unwrap() < nrows as usize {
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    let took = start.elapsed();
+    let coalesced = g.statistics().unwrap().totals.coalesced_packets;
+
+    println!(
+        "# {} rows in batches of {} through {} nodes in {} domains",
+        nrows, batch, hops, domains
+    );
+    println!(
+        "throughput\t{:.0}\t(rows/s)",
+        nrows as f64 / (as_ns(took) as f64 / 1_000_000_000.0)
+    );
+    println!("coalesced\t{}\t(updates)", coalesced);
+}
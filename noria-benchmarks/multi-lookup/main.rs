@@ -0,0 +1,127 @@
+#[macro_use]
+extern crate clap;
+extern crate hdrhistogram;
+extern crate noria;
+extern crate rand;
+
+use std::time::{Duration, Instant};
+
+use clap::{App, Arg};
+use hdrhistogram::Histogram;
+use rand::Rng;
+
+use noria::{ControllerBuilder, DataType};
+
+// Rows are inserted into the base table this many at a time.
+const INSERT_BATCH: usize = 1000;
+
+fn as_ns(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000_000 + u64::from(d.subsec_nanos())
+}
+
+fn record(hist: &mut Histogram<u64>, ns: u64) {
+    if hist.record(ns).is_err() {
+        let m = hist.high();
+        hist.record(m).unwrap();
+    }
+}
+
+fn report(name: &str, hist: &Histogram<u64>) {
+    for &q in &[0.5, 0.95, 0.99] {
+        println!(
+            "{}\t{}\t{:.2}\t(per-key ns)",
+            name,
+            (q * 100.0) as usize,
+            hist.value_at_quantile(q)
+        );
+    }
+    println!("{}\t100\t{:.2}\t(per-key ns)", name, hist.max());
+}
+
+fn main() {
+    let args = App::new("multi-lookup")
+        .version("0.1")
+        .about("Benchmarks the per-key cost of reading many keys one at a time or in one batch")
+        .arg(
+            Arg::with_name("keys")
+                .long("keys")
+                .value_name("N")
+                .default_value("100000")
+                .help("Number of distinct keys in the view."),
+        )
+        .arg(
+            Arg::with_name("batch")
+                .long("batch")
+                .value_name("N")
+                .default_value("100")
+                .help("Number of keys read together."),
+        )
+        .arg(
+            Arg::with_name("batches")
+                .long("batches")
+                .value_name("N")
+                .default_value("1000")
+                .help("Number of batches of keys to read each way."),
+        )
+        .arg(Arg::with_name("verbose").long("verbose").short("v"))
+        .get_matches();
+
+    let nkeys = value_t_or_exit!(args, "keys", i64);
+    let batch = value_t_or_exit!(args, "batch", usize);
+    let batches = value_t_or_exit!(args, "batches", usize);
+    let verbose = args.is_present("verbose");
+    assert!(nkeys > 0 && batch > 0);
+
+    let sql = "CREATE TABLE Article (aid int, title varchar(255), PRIMARY KEY(aid));
+               QUERY ArticleById: SELECT aid, title FROM Article WHERE aid = ?;";
+
+    // keep the view fully materialized, so that we only measure hits
+    let mut builder = ControllerBuilder::default();
+    builder.disable_partial();
+    let mut g = builder.build_local().unwrap();
+    g.install_recipe(sql).unwrap();
+
+    let mut article = g.table("Article").unwrap();
+    let mut view = g.view("ArticleById").unwrap();
+
+    if verbose {
+        eprintln!("Populating view with {} keys", nkeys);
+    }
+    let rows: Vec<Vec<DataType>> = (0..nkeys)
+        .map(|aid| vec![aid.into(), format!("Article {}", aid).into()])
+        .collect();
+    for chunk in rows.chunks(INSERT_BATCH) {
+        article.insert_all(chunk.to_vec()).unwrap();
+    }
+    while view.lookup(&[(nkeys - 1).into()], true).unwrap().is_empty() {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    let mut single = Histogram::<u64>::new(4).unwrap();
+    let mut multi = Histogram::<u64>::new(4).unwrap();
+    let mut rng = rand::thread_rng();
+    for _ in 0..batches {
+        let keys: Vec<Vec<DataType>> = (0..batch)
+            .map(|_| vec![rng.gen_range(0, nkeys).into()])
+            .collect();
+
+        let start = Instant::now();
+        for key in &keys {
+            let rs = view.lookup(key, true).unwrap();
+            assert_eq!(rs.len(), 1);
+        }
+        record(&mut single, as_ns(start.elapsed()) / batch as u64);
+
+        let start = Instant::now();
+        let rs = view.multi_lookup(keys, true).unwrap();
+        assert!(rs.iter().all(|rs| rs.len() == 1));
+        record(&mut multi, as_ns(start.elapsed()) / batch as u64);
+    }
+
+    println!(
+        "# {} batches of {} keys read from a view with {} keys",
+        batches, batch, nkeys
+    );
+    report("single", &single);
+    report("multi", &multi);
+}
@@ -0,0 +1,127 @@
+#[macro_use]
+extern crate clap;
+extern crate hdrhistogram;
+extern crate noria;
+
+use std::fs;
+use std::time::{Duration, Instant};
+
+use clap::{App, Arg};
+use hdrhistogram::Histogram;
+
+use noria::{ControllerBuilder, DataType, Modification, TableOperation};
+
+fn as_ns(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000_000 + u64::from(d.subsec_nanos())
+}
+
+// The peak resident set size of this process, in kB, if the OS tells us.
+fn peak_rss_kb() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmHWM:"))?;
+    line.split_whitespace().nth(1)?.parse().ok()
+}
+
+fn main() {
+    let args = App::new("reader-churn")
+        .version("0.1")
+        .about(
+            "Benchmarks a view over rows that are updated many times in each batch of writes, \
+             so that the reader sees long chains of changes to the same keys",
+        )
+        .arg(
+            Arg::with_name("keys")
+                .long("keys")
+                .value_name("N")
+                .default_value("10")
+                .help("Number of rows being updated."),
+        )
+        .arg(
+            Arg::with_name("updates")
+                .long("updates")
+                .value_name("N")
+                .default_value("100")
+                .help("Number of times each row is updated in each batch."),
+        )
+        .arg(
+            Arg::with_name("batches")
+                .long("batches")
+                .value_name("N")
+                .default_value("1000")
+                .help("Number of batches of updates to issue."),
+        )
+        .get_matches();
+
+    let keys = value_t_or_exit!(args, "keys", i64);
+    let updates = value_t_or_exit!(args, "updates", i64);
+    let batches = value_t_or_exit!(args, "batches", i64);
+    assert!(keys > 0 && updates > 0 && batches > 0);
+
+    let sql = "CREATE TABLE Counter (id int, n int, PRIMARY KEY(id));
+               QUERY CounterById: SELECT id, n FROM Counter WHERE id = ?;";
+
+    let mut builder = ControllerBuilder::default();
+    builder.disable_partial();
+    let mut g = builder.build_local().unwrap();
+    g.install_recipe(sql).unwrap();
+
+    let mut counter = g.table("Counter").unwrap();
+    let mut view = g.view("CounterById").unwrap();
+    counter
+        .insert_all((0..keys).map(|id| vec![id.into(), 0.into()]))
+        .unwrap();
+
+    let mut hist = Histogram::<u64>::new(4).unwrap();
+    let start = Instant::now();
+    for b in 0..batches {
+        let ops: Vec<_> = (0..updates)
+            .flat_map(|u| {
+                let n = b * updates + u + 1;
+                (0..keys).map(move |id| TableOperation::Update {
+                    key: vec![id.into()],
+                    set: vec![Modification::None, Modification::Set(n.into())],
+                })
+            })
+            .collect();
+
+        let batch_start = Instant::now();
+        counter.batch_insert_then_wait(vec![ops]).unwrap();
+        if hist.record(as_ns(batch_start.elapsed())).is_err() {
+            let m = hist.high();
+            hist.record(m).unwrap();
+        }
+    }
+
+    // wait for the last update to every row to reach the reader
+    let last: DataType = (batches * updates).into();
+    for id in 0..keys {
+        loop {
+            let rows = view.lookup(&[id.into()], true).unwrap();
+            if rows.len() == 1 && rows[0][1] == last {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+    let elapsed = start.elapsed();
+
+    println!(
+        "# {} batches of {} updates to each of {} rows",
+        batches, updates, keys
+    );
+    for &q in &[0.5, 0.95, 0.99] {
+        println!(
+            "write\t{}\t{:.2}\t(batch ns)",
+            (q * 100.0) as usize,
+            hist.value_at_quantile(q)
+        );
+    }
+    println!("write\t100\t{:.2}\t(batch ns)", hist.max());
+    println!(
+        "# all updates visible after {:.2}s",
+        elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1_000_000_000.0
+    );
+    if let Some(kb) = peak_rss_kb() {
+        println!("# peak resident memory {} kB", kb);
+    }
+}
@@ -0,0 +1,153 @@
+#[macro_use]
+extern crate clap;
+extern crate hdrhistogram;
+extern crate noria;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use clap::{App, Arg};
+use hdrhistogram::Histogram;
+
+use noria::{ControllerBuilder, DataType};
+
+/// Counts every allocation made by any thread, so that we can tell how many a write causes on its
+/// way through the domains.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn as_ns(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000_000 + u64::from(d.subsec_nanos())
+}
+
+fn main() {
+    let args = App::new("record-dispatch")
+        .version("0.1")
+        .about(
+            "Benchmarks single-row writes through a base that fans out to several views, counting \
+             the allocations each write causes and the updates sent on to the views together",
+        )
+        .arg(
+            Arg::with_name("writes")
+                .long("writes")
+                .value_name("N")
+                .default_value("100000")
+                .help("Number of single-row writes to perform."),
+        )
+        .arg(
+            Arg::with_name("views")
+                .long("views")
+                .value_name("N")
+                .default_value("3")
+                .help("Number of views the base table fans out to."),
+        )
+        .arg(
+            Arg::with_name("window")
+                .long("window")
+                .value_name("N")
+                .default_value("1")
+                .help("Number of writes to send before waiting for them to be acknowledged."),
+        )
+        .arg(Arg::with_name("verbose").long("verbose").short("v"))
+        .get_matches();
+
+    let writes = value_t_or_exit!(args, "writes", i64);
+    let nviews = value_t_or_exit!(args, "views", usize);
+    let window = value_t_or_exit!(args, "window", i64);
+    let verbose = args.is_present("verbose");
+    assert!(writes > 0);
+    assert!(nviews > 0);
+    assert!(window > 0);
+
+    let mut sql = String::from("CREATE TABLE Vote (aid int, uid int);\n");
+    for i in 0..nviews {
+        sql.push_str(&format!(
+            "QUERY Votes{}: SELECT aid, uid FROM Vote WHERE aid = ?;\n",
+            i
+        ));
+    }
+
+    // keep the views fully materialized, so that every write makes it all the way to them
+    let mut builder = ControllerBuilder::default();
+    builder.disable_partial();
+    let mut g = builder.build_local().unwrap();
+    g.install_recipe(&sql).unwrap();
+
+    let mut vote = g.table("Vote").unwrap();
+    let mut views: Vec<_> = (0..nviews)
+        .map(|i| g.view(&format!("Votes{}", i)).unwrap())
+        .collect();
+
+    if verbose {
+        eprintln!("Performing {} writes into {} views", writes, nviews);
+    }
+    let mut hist = Histogram::<u64>::new(4).unwrap();
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for first in (0..writes).step_by(window as usize) {
+        let rows: Vec<Vec<DataType>> = (first..writes.min(first + window))
+            .map(|uid| vec![1.into(), uid.into()])
+            .collect();
+        let t = Instant::now();
+        vote.insert_then_wait(rows).unwrap();
+        if hist.record(as_ns(t.elapsed())).is_err() {
+            let m = hist.high();
+            hist.record(m).unwrap();
+        }
+    }
+
+    // the writes are acknowledged once the base has them, so wait for them to reach the views.
+    // the few lookups this takes are noise next to the writes.
+    let key = vec![DataType::from(1)];
+    for view in &mut views {
+        while view.count(&key, true).unwrap() < writes as usize {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+    let took = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    let coalesced = g.statistics().unwrap().totals.coalesced_packets;
+
+    println!(
+        "# {} single-row writes into {} views, {} at a time",
+        writes, nviews, window
+    );
+    println!(
+        "allocations\t{:.2}\t(per write)",
+        allocations as f64 / writes as f64
+    );
+    println!(
+        "throughput\t{:.0}\t(writes/s)",
+        writes as f64 / (as_ns(took) as f64 / 1_000_000_000.0)
+    );
+    println!("coalesced\t{}\t(updates)", coalesced);
+    for &q in &[0.5, 0.95, 0.99] {
+        println!(
+            "write\t{}\t{:.2}\t(ns)",
+            (q * 100.0) as usize,
+            hist.value_at_quantile(q)
+        );
+    }
+    println!("write\t100\t{:.2}\t(ns)", hist.max());
+}
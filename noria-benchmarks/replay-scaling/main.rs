@@ -0,0 +1,115 @@
+#[macro_use]
+extern crate clap;
+extern crate dataflow;
+extern crate noria;
+
+use std::time::{Duration, Instant};
+
+use clap::{App, Arg};
+
+use dataflow::node::special::Base;
+use dataflow::ops::grouped::aggregate::Aggregation;
+use noria::{ControllerBuilder, DataType};
+
+// Rows are written to the base table this many at a time.
+const BATCH_SIZE: usize = 10_000;
+
+fn as_secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1_000_000_000.0
+}
+
+// Writes `rows` votes, and then times how long it takes to add a count of the votes per article,
+// which has to replay every one of them.
+fn replay(workers: usize, rows: i64, articles: i64, verbose: bool) -> Duration {
+    let mut builder = ControllerBuilder::default();
+    builder.disable_partial();
+    builder.set_sharding(None);
+    builder.set_replay_workers(workers);
+    let mut g = builder.build_local().unwrap();
+    let vote = g.migrate(|mig| mig.add_base("Vote", &["aid", "uid"], Base::default()));
+
+    if verbose {
+        eprintln!("Writing {} votes", rows);
+    }
+    let mut table = g.table("Vote").unwrap();
+    let votes: Vec<Vec<DataType>> = (0..rows)
+        .map(|uid| vec![(uid % articles).into(), uid.into()])
+        .collect();
+    for chunk in votes.chunks(BATCH_SIZE) {
+        table.insert_all(chunk.to_vec()).unwrap();
+    }
+
+    // the base table has taken all the writes once a full replay sees all of them
+    if verbose {
+        eprintln!("Replaying {} votes with {} workers", rows, workers);
+    }
+    let start = Instant::now();
+    g.migrate(move |mig| {
+        let vc = mig.add_ingredient(
+            "VoteCount",
+            &["aid", "votes"],
+            Aggregation::COUNT.over(vote, 1, &[0]),
+        );
+        mig.maintain("VoteCount".to_owned(), vc, &[0]);
+    });
+    let took = start.elapsed();
+
+    let mut view = g.view("VoteCount").unwrap();
+    let count = view.lookup(&[0.into()], true).unwrap();
+    assert_eq!(
+        count,
+        vec![vec![0.into(), ((rows + articles - 1) / articles).into()]]
+    );
+    took
+}
+
+fn main() {
+    let args = App::new("replay-scaling")
+        .version("0.1")
+        .about("Benchmarks how a full replay through a grouped count scales with replay workers")
+        .arg(
+            Arg::with_name("rows")
+                .long("rows")
+                .value_name("N")
+                .default_value("10000000")
+                .help("Number of rows to replay."),
+        )
+        .arg(
+            Arg::with_name("articles")
+                .long("articles")
+                .value_name("N")
+                .default_value("100000")
+                .help("Number of groups the rows are counted in."),
+        )
+        .arg(
+            Arg::with_name("workers")
+                .long("workers")
+                .value_name("N")
+                .multiple(true)
+                .use_delimiter(true)
+                .default_value("1,2,4")
+                .help("Numbers of replay workers to try."),
+        )
+        .arg(Arg::with_name("verbose").long("verbose").short("v"))
+        .get_matches();
+
+    let rows = value_t_or_exit!(args, "rows", i64);
+    let articles = value_t_or_exit!(args, "articles", i64);
+    let workers = values_t_or_exit!(args, "workers", usize);
+    let verbose = args.is_present("verbose");
+    assert!(articles > 0 && articles <= rows);
+    assert!(workers.iter().all(|&w| w > 0));
+
+    println!("# replay of {} rows into {} groups", rows, articles);
+    let mut serial = None;
+    for w in workers {
+        let took = replay(w, rows, articles, verbose);
+        let serial = *serial.get_or_insert(took);
+        println!(
+            "{}\t{:.2}\t(s)\t{:.2}\t(speedup)",
+            w,
+            as_secs(took),
+            as_secs(serial) / as_secs(took)
+        );
+    }
+}
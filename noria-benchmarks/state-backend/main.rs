@@ -0,0 +1,160 @@
+#[macro_use]
+extern crate clap;
+extern crate dataflow;
+extern crate hdrhistogram;
+extern crate itertools;
+extern crate rand;
+
+use std::time::Instant;
+
+use clap::{App, Arg};
+use hdrhistogram::Histogram;
+use itertools::Itertools;
+use rand::Rng;
+
+use dataflow::prelude::*;
+
+// Rows are inserted into state this many at a time.
+const BATCH_SIZE: usize = 10000;
+
+fn populate(state: &mut dyn State, rows: i64, per_key: i64, reserve: bool) {
+    state.add_key(&[0], None);
+    if reserve {
+        // as a full replay does when it is told up front how many rows are coming
+        state.reserve(rows as usize);
+    }
+    (0..rows)
+        .map(|i| {
+            vec![
+                DataType::from(i / per_key),
+                i.into(),
+                format!("row {}", i).into(),
+            ]
+        })
+        .chunks(BATCH_SIZE)
+        .into_iter()
+        .for_each(|chunk| {
+            let mut rs: Records = chunk.collect();
+            state.process_records(&mut rs, None);
+        });
+}
+
+// Looks up `reads` random keys in the state, as a join or an upquery would.
+fn perform_reads(state: &dyn State, name: &str, rows: i64, per_key: i64, reads: i64) {
+    let mut hist = Histogram::<u64>::new(4).unwrap();
+    let mut rng = rand::thread_rng();
+    let keys = rows / per_key;
+
+    for _ in 0..reads {
+        let key: DataType = rng.gen_range(0, keys).into();
+        let start = Instant::now();
+        let found = match state.lookup(&[0], &KeyType::Single(&key)) {
+            LookupResult::Some(rs) => rs.len(),
+            LookupResult::Missing => unreachable!("state is fully materialized"),
+        };
+        let elapsed = start.elapsed();
+        let ns = elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64;
+        assert_eq!(found, per_key as usize);
+
+        if hist.record(ns).is_err() {
+            let m = hist.high();
+            hist.record(m).unwrap();
+        }
+    }
+
+    println!("{}\t50\t{:.2}\t(all ns)", name, hist.value_at_quantile(0.5));
+    println!(
+        "{}\t95\t{:.2}\t(all ns)",
+        name,
+        hist.value_at_quantile(0.95)
+    );
+    println!(
+        "{}\t99\t{:.2}\t(all ns)",
+        name,
+        hist.value_at_quantile(0.99)
+    );
+    println!("{}\t100\t{:.2}\t(all ns)", name, hist.max());
+}
+
+fn main() {
+    let args = App::new("state-backend")
+        .version("0.1")
+        .about("Benchmarks the latency of lookups into materializations kept in memory or on disk")
+        .arg(
+            Arg::with_name("rows")
+                .long("rows")
+                .value_name("N")
+                .default_value("1000000")
+                .help("Number of rows to prepopulate each materialization with."),
+        )
+        .arg(
+            Arg::with_name("rows-per-key")
+                .long("rows-per-key")
+                .value_name("N")
+                .default_value("1")
+                .help("Number of rows that share each key."),
+        )
+        .arg(
+            Arg::with_name("reads")
+                .long("reads")
+                .default_value("100000")
+                .help("Number of keys to look up in each materialization."),
+        )
+        .arg(
+            Arg::with_name("backend")
+                .long("backend")
+                .takes_value(true)
+                .possible_values(&["memory", "disk"])
+                .help("Only benchmark materializations kept here."),
+        )
+        .arg(
+            Arg::with_name("reserve")
+                .long("reserve")
+                .help("Reserve room for all the rows before populating, as a full replay does."),
+        )
+        .arg(Arg::with_name("verbose").long("verbose").short("v"))
+        .get_matches();
+
+    let rows = value_t_or_exit!(args, "rows", i64);
+    let per_key = value_t_or_exit!(args, "rows-per-key", i64);
+    let reads = value_t_or_exit!(args, "reads", i64);
+    let reserve = args.is_present("reserve");
+    let verbose = args.is_present("verbose");
+    assert!(per_key > 0 && per_key <= rows);
+
+    let backends = match args.value_of("backend") {
+        Some(backend) => vec![backend],
+        None => vec!["memory", "disk"],
+    };
+
+    println!(
+        "# {} lookups into {} rows, {} per key",
+        reads, rows, per_key
+    );
+    for backend in backends {
+        let mut state: Box<dyn State> = match backend {
+            "memory" => Box::new(MemoryState::default()),
+            "disk" => Box::new(PersistentState::new(
+                String::from("state-backend"),
+                None,
+                &PersistenceParameters::default(),
+            )),
+            _ => unreachable!(),
+        };
+
+        if verbose {
+            eprintln!("Populating {} state with {} rows", backend, rows);
+        }
+        let start = Instant::now();
+        populate(&mut *state, rows, per_key, reserve);
+        let elapsed = start.elapsed();
+        println!(
+            "# populated {} state in {:.2}s{}",
+            backend,
+            elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1_000_000_000.0,
+            if reserve { " (reserved up front)" } else { "" }
+        );
+
+        perform_reads(&*state, backend, rows, per_key, reads);
+    }
+}
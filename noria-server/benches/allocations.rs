@@ -0,0 +1,226 @@
+//! Benchmarks for how much memory is allocated as writes flow through the data-flow graph of an
+//! in-process Noria instance.
+//!
+//! `cargo bench --bench allocations` runs every benchmark at full size, and prints what it
+//! measures as described in `common`. Under `cargo test --benches`, every benchmark instead runs
+//! once at a small size.
+//!
+//! Allocations are counted by a global allocator that wraps the system allocator, which is why
+//! these benchmarks are kept apart from the timing benchmarks. The counts cover the whole process,
+//! clients and polling included, so they are best compared between runs rather than read on their
+//! own.
+
+#[macro_use]
+extern crate serde_derive;
+
+mod common;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use dataflow::node::special::Base;
+use dataflow::ops::filter::{Filter, FilterCondition, Operator, Value};
+use dataflow::ops::grouped::aggregate::Aggregation;
+use dataflow::ops::latest::Latest;
+use dataflow::ops::project::Project;
+use noria::debug::topology::NodeKind;
+use noria_server::{ControllerBuilder, DataType};
+
+use crate::common::{report, Args};
+
+// Rows are written to base tables this many at a time.
+const BATCH_SIZE: usize = 1_000;
+
+// The number of distinct values in the column that the counts below each child group by.
+const KINDS: i64 = 4;
+
+// The number of columns in the rows that `materialize` keeps.
+const WIDTH: usize = 8;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// The system allocator, counting every allocation and the bytes asked for.
+struct Counting;
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(new_size, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// The allocations made so far, and the bytes they asked for.
+fn allocated() -> (usize, usize) {
+    (
+        ALLOCATIONS.load(Ordering::SeqCst),
+        ALLOCATED_BYTES.load(Ordering::SeqCst),
+    )
+}
+
+// Counts what is allocated per row written to a base table whose batches fan out to the given
+// number of children. Half of the children are filters and half are projections, which are the
+// operators that can work on a batch they share with their siblings without copying it, and each
+// child feeds a small count so that the benchmark can tell when a write has reached all of them.
+fn fan_out(writes: i64, children: usize) {
+    let bench = format!("fan-out/{}-children", children);
+    let mut builder = ControllerBuilder::default();
+    builder.disable_partial();
+    builder.set_sharding(None);
+    let mut g = builder.build_local().unwrap();
+    g.migrate(move |mig| {
+        let event = mig.add_base("Event", &["id", "kind", "body"], Base::default());
+        for i in 0..children {
+            let child = if i % 2 == 0 {
+                let all = FilterCondition::Comparison(
+                    Operator::GreaterOrEqual,
+                    Value::Constant(0.into()),
+                );
+                mig.add_ingredient(
+                    format!("Filter{}", i),
+                    &["id", "kind", "body"],
+                    Filter::new(event, &[None, Some(all), None]),
+                )
+            } else {
+                mig.add_ingredient(
+                    format!("Project{}", i),
+                    &["id", "kind"],
+                    Project::new(event, &[0, 1], None, None),
+                )
+            };
+            let count = mig.add_ingredient(
+                format!("Count{}", i),
+                &["kind", "events"],
+                Aggregation::COUNT.over(child, 0, &[1]),
+            );
+            mig.maintain(format!("Count{}", i), count, &[0]);
+        }
+    });
+
+    let mut event = g.table("Event").unwrap();
+    let mut views: Vec<_> = (0..children)
+        .map(|i| g.view(&format!("Count{}", i)).unwrap())
+        .collect();
+    let rows: Vec<Vec<DataType>> = (0..writes)
+        .map(|id| vec![id.into(), (id % KINDS).into(), "body".into()])
+        .collect();
+    let batches: Vec<Vec<Vec<DataType>>> = rows.chunks(BATCH_SIZE).map(|c| c.to_vec()).collect();
+    let keys: Vec<Vec<DataType>> = (0..KINDS).map(|k| vec![k.into()]).collect();
+
+    let (allocations, bytes) = allocated();
+    for batch in batches {
+        event.insert_all(batch).unwrap();
+    }
+    for view in &mut views {
+        loop {
+            let counted: i64 = view
+                .multi_lookup(keys.clone(), true)
+                .unwrap()
+                .into_iter()
+                .flat_map(|rs| rs.into_iter())
+                .map(|r| -> i64 { (&r[1]).into() })
+                .sum();
+            if counted == writes {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+    let (allocations_after, bytes_after) = allocated();
+
+    let per_row = |n: usize| n as f64 / writes as f64;
+    report(
+        &bench,
+        "allocations",
+        per_row(allocations_after - allocations),
+        "allocations/row",
+    );
+    report(&bench, "bytes", per_row(bytes_after - bytes), "bytes/row");
+}
+
+// Measures how much memory it takes to keep the given number of rows of `WIDTH` integer columns
+// both in a fully materialized operator and in the reader that it feeds. The state and the reader
+// are sized by their own accounting, and the allocations by the allocator above, so that a change
+// to how rows are stored shows up in all three.
+fn materialize(rows: i64) {
+    let bench = format!("materialize/{}-columns", WIDTH);
+    let columns: Vec<String> = (0..WIDTH).map(|c| format!("c{}", c)).collect();
+    let mut builder = ControllerBuilder::default();
+    builder.disable_partial();
+    builder.set_sharding(None);
+    let mut g = builder.build_local().unwrap();
+    let latest = g.migrate(move |mig| {
+        let wide = mig.add_base("Wide", &columns[..], Base::default());
+        let latest = mig.add_ingredient("WideRows", &columns[..], Latest::new(wide, 0));
+        mig.maintain("WideRows".to_owned(), latest, &[0]);
+        latest
+    });
+
+    let mut wide = g.table("Wide").unwrap();
+    let mut view = g.view("WideRows").unwrap();
+    let batches: Vec<Vec<Vec<DataType>>> = (0..rows)
+        .map(|id| (0..WIDTH as i64).map(|c| (id + c).into()).collect())
+        .collect::<Vec<_>>()
+        .chunks(BATCH_SIZE)
+        .map(|c| c.to_vec())
+        .collect();
+
+    let (allocations, bytes) = allocated();
+    for batch in batches {
+        wide.insert_all(batch).unwrap();
+    }
+    while view.len().unwrap() < rows as usize {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let (allocations_after, bytes_after) = allocated();
+
+    let stats = g.statistics().unwrap();
+    let state = stats.node(latest).unwrap();
+    assert_eq!(state.rows, Some(rows as usize));
+    let reader = stats
+        .nodes
+        .iter()
+        .find(|n| n.kind == NodeKind::Reader)
+        .unwrap();
+
+    let per_row = |n: u64| n as f64 / rows as f64;
+    report(&bench, "state", per_row(state.bytes), "bytes/row");
+    report(&bench, "reader", per_row(reader.bytes), "bytes/row");
+    report(
+        &bench,
+        "allocations",
+        per_row((allocations_after - allocations) as u64),
+        "allocations/row",
+    );
+    report(&bench, "bytes", per_row((bytes_after - bytes) as u64), "bytes/row");
+}
+
+fn main() {
+    let args = Args::from_env();
+    let writes = if args.full { 200_000 } else { 1_000 };
+
+    for &children in &[1, 8, 32] {
+        if args.wanted(&format!("fan-out/{}-children", children)) {
+            fan_out(writes, children);
+        }
+    }
+    if args.wanted(&format!("materialize/{}-columns", WIDTH)) {
+        materialize(if args.full { 10_000_000 } else { 1_000 });
+    }
+}
@@ -0,0 +1,80 @@
+//! What the benchmark targets in this directory share: how they are told which benchmarks to run
+//! and at what size, and how they print what they measure.
+//!
+//! `cargo bench --bench <target>` runs every benchmark of the target at full size, and any further
+//! arguments pick out the benchmarks whose names contain one of them. Results are printed to
+//! stdout with one JSON object per line, so that they can be collected and compared over time, and
+//! a summary for humans is printed to stderr. Under `cargo test --benches`, every benchmark
+//! instead runs once at a small size.
+
+// every target includes this module, but not every target uses all of it
+#![allow(dead_code)]
+
+use std::env;
+use std::time::Duration;
+
+/// What a benchmark target was asked to run.
+pub struct Args {
+    /// Whether to run at full size, rather than once at a small size.
+    pub full: bool,
+    filters: Vec<String>,
+}
+
+impl Args {
+    /// The arguments the benchmark target was started with.
+    pub fn from_env() -> Self {
+        // `cargo bench` passes `--bench`, and `cargo test` does not
+        let mut full = false;
+        let mut filters = Vec::new();
+        for arg in env::args().skip(1) {
+            if arg == "--bench" {
+                full = true;
+            } else if !arg.starts_with('-') {
+                filters.push(arg);
+            }
+        }
+        Args { full, filters }
+    }
+
+    /// Whether the benchmark with the given name was asked for.
+    pub fn wanted(&self, name: &str) -> bool {
+        self.filters.is_empty() || self.filters.iter().any(|f| name.contains(&**f))
+    }
+}
+
+/// One measurement, as it is printed to stdout.
+#[derive(Serialize)]
+struct Report<'a> {
+    bench: &'a str,
+    metric: &'a str,
+    value: f64,
+    unit: &'a str,
+}
+
+pub fn report(bench: &str, metric: &str, value: f64, unit: &str) {
+    eprintln!("{}\t{}\t{:.2}\t({})", bench, metric, value, unit);
+    let r = Report {
+        bench,
+        metric,
+        value,
+        unit,
+    };
+    println!("{}", serde_json::to_string(&r).unwrap());
+}
+
+pub fn as_ns(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000_000 + u64::from(d.subsec_nanos())
+}
+
+// Reports the mean and the usual quantiles of the given samples.
+pub fn report_latencies(bench: &str, mut samples: Vec<u64>, unit: &str) {
+    assert!(!samples.is_empty());
+    samples.sort();
+    let mean = samples.iter().sum::<u64>() as f64 / samples.len() as f64;
+    report(bench, "mean", mean, unit);
+    for &(name, q) in &[("p50", 0.5), ("p95", 0.95), ("p99", 0.99)] {
+        let i = (q * (samples.len() - 1) as f64).round() as usize;
+        report(bench, name, samples[i] as f64, unit);
+    }
+    report(bench, "max", *samples.last().unwrap() as f64, unit);
+}
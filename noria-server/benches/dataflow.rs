@@ -0,0 +1,475 @@
+//! Benchmarks for the write, read, and replay paths of an in-process Noria instance.
+//!
+//! `cargo bench --bench dataflow` runs every benchmark at full size, and prints what it measures
+//! as described in `common`. Under `cargo test --benches`, every benchmark instead runs once at a
+//! small size and checks its results, so that the graphs they build double as smoke tests.
+
+#[macro_use]
+extern crate serde_derive;
+
+mod common;
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use rand::Rng;
+
+use dataflow::node::special::Base;
+use dataflow::ops::filter::{Filter, FilterCondition, Operator, Value};
+use dataflow::ops::grouped::aggregate::Aggregation;
+use dataflow::ops::identity::Identity;
+use dataflow::ops::project::Project;
+use noria_server::{
+    ControllerBuilder, DataType, DomainStrategy, DurabilityMode, NodeIndex, PersistenceParameters,
+    Placement, PublishPolicy, SyncPolicy, WalParameters,
+};
+
+use crate::common::{as_ns, report, report_latencies, Args};
+
+// Rows are written to base tables this many at a time.
+const BATCH_SIZE: usize = 100;
+
+// Rows are written this many at a time by the filter and projection benchmark.
+const LARGE_BATCH_SIZE: usize = 10_000;
+
+// Keys are read this many at a time by the multi-key read benchmark.
+const MULTI_READ_KEYS: usize = 100;
+
+/// How large each benchmark is.
+struct Size {
+    /// Writes whose latency is measured one at a time.
+    puts: usize,
+    /// Rows written to measure throughput.
+    writes: i64,
+    /// Keys in the view that reads go to.
+    keys: i64,
+    /// Reads whose latency is measured.
+    reads: usize,
+    /// Rows in the state that is replayed.
+    replay_rows: i64,
+    /// Groups that the replayed rows are counted in.
+    replay_groups: i64,
+    /// Times each replay is done.
+    replays: usize,
+}
+
+impl Size {
+    fn full() -> Self {
+        Size {
+            puts: 10_000,
+            writes: 1_000_000,
+            keys: 1_000_000,
+            reads: 100_000,
+            replay_rows: 5_000_000,
+            replay_groups: 100_000,
+            replays: 3,
+        }
+    }
+
+    fn smoke() -> Self {
+        Size {
+            puts: 10,
+            writes: 1_000,
+            keys: 1_000,
+            reads: 100,
+            replay_rows: 1_000,
+            replay_groups: 10,
+            replays: 1,
+        }
+    }
+}
+
+fn builder() -> ControllerBuilder {
+    // keep every view fully materialized and the graph unsharded, so that runs are comparable
+    let mut builder = ControllerBuilder::default();
+    builder.disable_partial();
+    builder.set_sharding(None);
+    builder
+}
+
+fn write_all(table: &mut noria_server::Table, rows: Vec<Vec<DataType>>) {
+    for chunk in rows.chunks(BATCH_SIZE) {
+        table.insert_all(chunk.to_vec()).unwrap();
+    }
+}
+
+// Times single-row writes from when they are sent until a count below the base reflects them.
+fn put_latency(size: &Size) {
+    let mut g = builder().build_local().unwrap();
+    g.migrate(|mig| {
+        let vote = mig.add_base("Vote", &["aid", "uid"], Base::default());
+        let vc = mig.add_ingredient(
+            "VoteCount",
+            &["aid", "votes"],
+            Aggregation::COUNT.over(vote, 1, &[0]),
+        );
+        mig.maintain("VoteCount".to_owned(), vc, &[0]);
+    });
+
+    let mut vote = g.table("Vote").unwrap();
+    let mut view = g.view("VoteCount").unwrap();
+    let articles = 100;
+    let mut counts = HashMap::new();
+    let mut samples = Vec::with_capacity(size.puts);
+    for uid in 0..size.puts as i64 {
+        let aid = uid % articles;
+        let count = counts.entry(aid).or_insert(0i64);
+        *count += 1;
+        let expected = vec![vec![aid.into(), (*count).into()]];
+
+        let start = Instant::now();
+        vote.insert(vec![aid.into(), uid.into()]).unwrap();
+        while view.lookup(&[aid.into()], true).unwrap() != expected {}
+        samples.push(as_ns(start.elapsed()));
+    }
+    report_latencies("put-latency", samples, "ns");
+}
+
+// Measures how many rows per second make it from a base table to a view through a chain of nodes
+// that spans the given number of domains.
+fn write_throughput(size: &Size, domains: usize) {
+    let bench = format!("write-throughput/{}-domains", domains);
+    let mut builder = builder();
+    builder.set_domain_strategy(DomainStrategy::FewestCuts { max_nodes: 1 });
+    let mut g = builder.build_local().unwrap();
+    g.migrate(move |mig| {
+        // the base is in a domain of its own, and every node below it is in one more
+        let mut last = mig.add_base("Vote", &["aid", "uid"], Base::default());
+        for i in 1..domains {
+            last = mig.add_ingredient(format!("hop{}", i), &["aid", "uid"], Identity::new(last));
+        }
+        mig.maintain("Votes".to_owned(), last, &[0]);
+    });
+    assert_eq!(g.statistics().unwrap().totals.domains, domains);
+
+    let mut vote = g.table("Vote").unwrap();
+    let mut view = g.view("Votes").unwrap();
+    let key = vec![DataType::from(1)];
+    let rows: Vec<Vec<DataType>> = (0..size.writes)
+        .map(|uid| vec![1.into(), uid.into()])
+        .collect();
+    let start = Instant::now();
+    write_all(&mut vote, rows);
+    while view.count(&key, true).unwrap() < size.writes as usize {
+        thread::sleep(Duration::from_millis(1));
+    }
+    let took = as_ns(start.elapsed()) as f64 / 1_000_000_000.0;
+    assert_eq!(view.count(&key, true).unwrap(), size.writes as usize);
+    report(&bench, "throughput", size.writes as f64 / took, "rows/s");
+}
+
+// Measures how fast large batches of writes go through a filter and a projection to a view.
+fn filter_project(size: &Size) {
+    let bench = "filter-project/10k-batch";
+    let mut g = builder().build_local().unwrap();
+    g.migrate(|mig| {
+        let event = mig.add_base("Event", &["id", "kind", "body", "ts"], Base::default());
+        let kind = FilterCondition::Comparison(Operator::Equal, Value::Constant(1.into()));
+        let recent =
+            FilterCondition::Comparison(Operator::GreaterOrEqual, Value::Constant(0.into()));
+        let matching = mig.add_ingredient(
+            "Matching",
+            &["id", "kind", "body", "ts"],
+            Filter::new(event, &[None, Some(kind), None, Some(recent)]),
+        );
+        let narrowed = mig.add_ingredient(
+            "Narrowed",
+            &["id", "ts"],
+            Project::new(matching, &[0, 3], None, None),
+        );
+        mig.maintain("EventById".to_owned(), narrowed, &[0]);
+    });
+
+    let mut event = g.table("Event").unwrap();
+    let mut view = g.view("EventById").unwrap();
+    // one in four events is of the kind that the filter lets through
+    let rows: Vec<Vec<DataType>> = (0..size.writes)
+        .map(|id| vec![id.into(), (id % 4).into(), "body".into(), id.into()])
+        .collect();
+    let expected = (size.writes as usize + 2) / 4;
+    let start = Instant::now();
+    for chunk in rows.chunks(LARGE_BATCH_SIZE) {
+        event.insert_all(chunk.to_vec()).unwrap();
+    }
+    while view.len().unwrap() < expected {
+        thread::sleep(Duration::from_millis(1));
+    }
+    let took = as_ns(start.elapsed()) as f64 / 1_000_000_000.0;
+    assert_eq!(view.len().unwrap(), expected);
+    report(bench, "throughput", size.writes as f64 / took, "rows/s");
+}
+
+// Times reads of random keys from a view, one key at a time and many keys at a time.
+fn read_latency(size: &Size) {
+    let mut g = builder().build_local().unwrap();
+    g.migrate(|mig| {
+        let article = mig.add_base(
+            "Article",
+            &["aid", "title"],
+            Base::new(vec![]).with_key(vec![0]),
+        );
+        mig.maintain("ArticleById".to_owned(), article, &[0]);
+    });
+
+    let mut article = g.table("Article").unwrap();
+    let mut view = g.view("ArticleById").unwrap();
+    let rows: Vec<Vec<DataType>> = (0..size.keys)
+        .map(|aid| vec![aid.into(), format!("Article {}", aid).into()])
+        .collect();
+    write_all(&mut article, rows);
+    while view.len().unwrap() < size.keys as usize {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let mut rng = rand::thread_rng();
+    let mut samples = Vec::with_capacity(size.reads);
+    for _ in 0..size.reads {
+        let key = vec![DataType::from(rng.gen_range(0, size.keys))];
+        let start = Instant::now();
+        let rs = view.lookup(&key, true).unwrap();
+        samples.push(as_ns(start.elapsed()));
+        assert_eq!(rs.len(), 1);
+    }
+    report_latencies("read-latency/point", samples, "ns");
+
+    let batches = (size.reads / MULTI_READ_KEYS).max(1);
+    let mut samples = Vec::with_capacity(batches);
+    for _ in 0..batches {
+        let keys: Vec<Vec<DataType>> = (0..MULTI_READ_KEYS)
+            .map(|_| vec![rng.gen_range(0, size.keys).into()])
+            .collect();
+        let start = Instant::now();
+        let rs = view.multi_lookup(keys, true).unwrap();
+        samples.push(as_ns(start.elapsed()));
+        assert!(rs.iter().all(|rs| rs.len() == 1));
+    }
+    report_latencies(
+        &format!("read-latency/multi-{}", MULTI_READ_KEYS),
+        samples,
+        "ns",
+    );
+}
+
+// Times how long it takes to add a count over a base table full of rows, which has to replay all
+// of them, with the count either in the base table's domain or in a domain of its own.
+fn replay(size: &Size, cross_domain: bool) {
+    let bench = if cross_domain {
+        "replay/across-domains"
+    } else {
+        "replay/within-domain"
+    };
+    let mut g = builder().build_local().unwrap();
+    let vote = g.migrate(|mig| mig.add_base("Vote", &["aid", "uid"], Base::default()));
+
+    let mut table = g.table("Vote").unwrap();
+    let rows: Vec<Vec<DataType>> = (0..size.replay_rows)
+        .map(|uid| vec![(uid % size.replay_groups).into(), uid.into()])
+        .collect();
+    write_all(&mut table, rows);
+
+    // the base table has taken all the writes once a full replay sees all of them
+    let per_group = (size.replay_rows + size.replay_groups - 1) / size.replay_groups;
+    let mut samples = Vec::with_capacity(size.replays);
+    for i in 0..size.replays {
+        let name = format!("VoteCount{}", i);
+        let start = Instant::now();
+        let vc = g.migrate(move |mig| {
+            let vc = mig.add_ingredient(
+                &name,
+                &["aid", "votes"],
+                Aggregation::COUNT.over(vote, 1, &[0]),
+            );
+            mig.place(
+                vc,
+                if cross_domain {
+                    Placement::Dedicated
+                } else {
+                    Placement::With(vote)
+                },
+            );
+            mig.maintain(name, vc, &[0]);
+            vc
+        });
+        samples.push(as_ns(start.elapsed()));
+
+        let mut view = g.view(&format!("VoteCount{}", i)).unwrap();
+        assert_eq!(
+            view.lookup(&[0.into()], true).unwrap(),
+            vec![vec![0.into(), per_group.into()]]
+        );
+        assert_eq!(in_base_domain(&mut g, vote, vc), !cross_domain);
+    }
+    report_latencies(bench, samples, "ns");
+}
+
+// Measures write throughput to a view that makes writes visible by the given policy, and how far
+// behind the writes reads of the view are while the writes are going on.
+fn publish_policy(size: &Size, name: &str, policy: PublishPolicy) {
+    let bench = format!("publish-policy/{}", name);
+    let mut g = builder().build_local().unwrap();
+    g.migrate(move |mig| {
+        let vote = mig.add_base("Vote", &["aid", "uid"], Base::default());
+        let votes = mig.add_ingredient("Votes", &["aid", "uid"], Identity::new(vote));
+        mig.maintain("Votes".to_owned(), votes, &[0]);
+        mig.set_publish_policy(votes, policy);
+    });
+
+    let mut vote = g.table("Vote").unwrap();
+    let mut view = g.view("Votes").unwrap();
+    let done = Arc::new(AtomicBool::new(false));
+    let reads = {
+        let done = done.clone();
+        let mut view = g.view("Votes").unwrap().into_exclusive().unwrap();
+        thread::spawn(move || {
+            // the key is never written, so reads stay cheap, but still say how fresh the view is
+            let key = vec![DataType::from(-1)];
+            let mut staleness = Vec::new();
+            while !done.load(Ordering::SeqCst) {
+                let (_, meta) = view.lookup_with_meta(&key, true).unwrap();
+                if let Some(written) = meta.written {
+                    let behind = SystemTime::now()
+                        .duration_since(written)
+                        .unwrap_or_else(|_| Duration::from_millis(0));
+                    staleness.push(as_ns(behind));
+                }
+            }
+            staleness
+        })
+    };
+
+    let rows: Vec<Vec<DataType>> = (0..size.writes)
+        .map(|uid| vec![uid.into(), uid.into()])
+        .collect();
+    let start = Instant::now();
+    write_all(&mut vote, rows);
+    while view.len().unwrap() < size.writes as usize {
+        thread::sleep(Duration::from_millis(1));
+    }
+    let took = as_ns(start.elapsed()) as f64 / 1_000_000_000.0;
+    done.store(true, Ordering::SeqCst);
+    let staleness = reads.join().unwrap();
+
+    report(&bench, "throughput", size.writes as f64 / took, "rows/s");
+    if !staleness.is_empty() {
+        report_latencies(&format!("{}/staleness", bench), staleness, "ns");
+    }
+}
+
+// Measures how long single writes take to be acknowledged, and write throughput, when base
+// writes are appended to a write-ahead log that is synced by the given policy, if at all.
+fn wal_sync(size: &Size, name: &str, sync: Option<SyncPolicy>) {
+    let bench = format!("wal-sync/{}", name);
+    let dir = tempfile::tempdir().unwrap();
+    let mut persistence = PersistenceParameters::new(
+        DurabilityMode::MemoryOnly,
+        Duration::from_millis(1),
+        Some(String::from("walsync")),
+        1,
+    );
+    persistence.log_dir = Some(dir.path().to_owned());
+    persistence.wal = sync.map(|sync| WalParameters {
+        sync,
+        ..Default::default()
+    });
+    let mut builder = builder();
+    builder.set_persistence(persistence);
+    let mut g = builder.build_local().unwrap();
+    g.migrate(|mig| {
+        let vote = mig.add_base("Vote", &["aid", "uid"], Base::default());
+        let votes = mig.add_ingredient("Votes", &["aid", "uid"], Identity::new(vote));
+        mig.maintain("Votes".to_owned(), votes, &[0]);
+    });
+
+    let mut vote = g.table("Vote").unwrap();
+    let mut view = g.view("Votes").unwrap();
+    let mut samples = Vec::with_capacity(size.puts);
+    for uid in 0..size.puts as i64 {
+        let start = Instant::now();
+        vote.insert(vec![(-1 - uid).into(), uid.into()]).unwrap();
+        samples.push(as_ns(start.elapsed()));
+    }
+    report_latencies(&format!("{}/ack", bench), samples, "ns");
+
+    let rows: Vec<Vec<DataType>> = (0..size.writes)
+        .map(|uid| vec![uid.into(), uid.into()])
+        .collect();
+    let start = Instant::now();
+    write_all(&mut vote, rows);
+    while view.len().unwrap() < size.writes as usize + size.puts {
+        thread::sleep(Duration::from_millis(1));
+    }
+    let took = as_ns(start.elapsed()) as f64 / 1_000_000_000.0;
+    report(&bench, "throughput", size.writes as f64 / took, "rows/s");
+}
+
+fn in_base_domain(
+    g: &mut noria_server::LocalControllerHandle<noria_server::LocalAuthority>,
+    base: NodeIndex,
+    n: NodeIndex,
+) -> bool {
+    g.migrate(move |mig| {
+        let graph = mig.graph();
+        graph[base].domain() == graph[n].domain()
+    })
+}
+
+fn main() {
+    let args = Args::from_env();
+    let size = if args.full {
+        Size::full()
+    } else {
+        Size::smoke()
+    };
+
+    if args.wanted("put-latency") {
+        put_latency(&size);
+    }
+    for &domains in &[1, 4, 8] {
+        if args.wanted(&format!("write-throughput/{}-domains", domains)) {
+            write_throughput(&size, domains);
+        }
+    }
+    if args.wanted("filter-project/10k-batch") {
+        filter_project(&size);
+    }
+    if args.wanted("read-latency") {
+        read_latency(&size);
+    }
+    if args.wanted("replay/within-domain") {
+        replay(&size, false);
+    }
+    if args.wanted("replay/across-domains") {
+        replay(&size, true);
+    }
+    let policies = [
+        ("every-message", PublishPolicy::EveryMessage),
+        ("every-1000-records", PublishPolicy::EveryRecords(1000)),
+        (
+            "interval-1ms",
+            PublishPolicy::Interval(Duration::from_millis(1)),
+        ),
+        ("adaptive", PublishPolicy::default()),
+    ];
+    for &(name, policy) in &policies {
+        if args.wanted(&format!("publish-policy/{}", name)) {
+            publish_policy(&size, name, policy);
+        }
+    }
+    let syncs = [
+        ("off", None),
+        ("every-write", Some(SyncPolicy::EveryWrite)),
+        (
+            "interval-10ms",
+            Some(SyncPolicy::Interval(Duration::from_millis(10))),
+        ),
+        ("never", Some(SyncPolicy::Never)),
+    ];
+    for &(name, sync) in &syncs {
+        if args.wanted(&format!("wal-sync/{}", name)) {
+            wal_sync(&size, name, sync);
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! Benchmarks for the parts of a domain's dispatch path that consult hash maps for every update or
+//! replay piece, run through the data-flow graph of an in-process Noria instance.
+//!
+//! Each dispatch buffers a node's output in a map keyed by the children it goes to, replay pieces
+//! are matched to their replay paths by tag, and unions hold on to the pieces of a partial replay
+//! by the key being replayed until every parent has sent its piece. These benchmarks measure the
+//! paths that do so, rather than the maps on their own, so that how those maps are hashed can be
+//! judged by what it costs a real dispatch. To compare two hashers, run this target on both sides
+//! of a change that switches between them.
+//!
+//! `cargo bench --bench hashing` runs every benchmark at full size, and prints what it measures as
+//! described in `common`. Under `cargo test --benches`, every benchmark instead runs once at a
+//! small size.
+
+#[macro_use]
+extern crate serde_derive;
+
+mod common;
+
+use std::collections::HashMap;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use dataflow::node::special::Base;
+use dataflow::ops::grouped::aggregate::Aggregation;
+use dataflow::ops::identity::Identity;
+use dataflow::ops::union::Union;
+use noria_server::{ControllerBuilder, DataType};
+
+use crate::common::{as_ns, report, report_latencies, Args};
+
+// Rows are written to base tables this many at a time, which keeps updates small so that the
+// per-dispatch work is a large part of what each one costs.
+const BATCH_SIZE: usize = 10;
+
+/// How large each benchmark is.
+struct Size {
+    /// Rows written through the fan-out.
+    writes: i64,
+    /// Keys that are replayed through the union, one at a time.
+    replayed_keys: i64,
+    /// Rows for each replayed key in each of the union's parents.
+    rows_per_key: i64,
+}
+
+impl Size {
+    fn full() -> Self {
+        Size {
+            writes: 500_000,
+            replayed_keys: 10_000,
+            rows_per_key: 10,
+        }
+    }
+
+    fn smoke() -> Self {
+        Size {
+            writes: 1_000,
+            replayed_keys: 10,
+            rows_per_key: 2,
+        }
+    }
+}
+
+// Measures how many rows per second make it from a base table to the given number of children in
+// the base table's domain. Every update the base table emits is buffered for each of its children
+// by the domain before it is handed to them.
+fn fan_out(size: &Size, children: usize) {
+    let bench = format!("dispatch/fan-out/{}-children", children);
+    let mut builder = ControllerBuilder::default();
+    builder.disable_partial();
+    builder.set_sharding(None);
+    let mut g = builder.build_local().unwrap();
+    g.migrate(move |mig| {
+        let vote = mig.add_base("Vote", &["aid", "uid"], Base::default());
+        for i in 0..children {
+            let name = format!("Child{}", i);
+            let child = mig.add_ingredient(&name, &["aid", "uid"], Identity::new(vote));
+            mig.maintain(name, child, &[0]);
+        }
+    });
+
+    let mut vote = g.table("Vote").unwrap();
+    let mut views: Vec<_> = (0..children)
+        .map(|i| g.view(&format!("Child{}", i)).unwrap())
+        .collect();
+    let rows: Vec<Vec<DataType>> = (0..size.writes)
+        .map(|uid| vec![uid.into(), uid.into()])
+        .collect();
+
+    let start = Instant::now();
+    for chunk in rows.chunks(BATCH_SIZE) {
+        vote.insert_all(chunk.to_vec()).unwrap();
+    }
+    for view in &mut views {
+        while view.len().unwrap() < size.writes as usize {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+    let took = as_ns(start.elapsed()) as f64 / 1_000_000_000.0;
+    report(&bench, "throughput", size.writes as f64 / took, "rows/s");
+}
+
+// Times reads of keys that miss in a partially materialized count over the union of two base
+// tables. Each miss is filled by a replay whose pieces come up through both of the union's
+// parents, and which the union holds on to until it has both.
+fn union_replay(size: &Size) {
+    let bench = "dispatch/union-replay";
+    let mut builder = ControllerBuilder::default();
+    builder.set_sharding(None);
+    let mut g = builder.build_local().unwrap();
+    g.migrate(|mig| {
+        let up = mig.add_base("Up", &["aid", "uid"], Base::default());
+        let down = mig.add_base("Down", &["aid", "uid"], Base::default());
+        let mut emits = HashMap::new();
+        emits.insert(up, vec![0, 1]);
+        emits.insert(down, vec![0, 1]);
+        let votes = mig.add_ingredient("Votes", &["aid", "uid"], Union::new(emits));
+        let count = mig.add_ingredient(
+            "VoteCount",
+            &["aid", "votes"],
+            Aggregation::COUNT.over(votes, 1, &[0]),
+        );
+        mig.maintain("VoteCount".to_owned(), count, &[0]);
+    });
+
+    let rows: Vec<Vec<DataType>> = (0..size.replayed_keys * size.rows_per_key)
+        .map(|uid| vec![(uid % size.replayed_keys).into(), uid.into()])
+        .collect();
+    for table in &["Up", "Down"] {
+        let mut table = g.table(table).unwrap();
+        for chunk in rows.chunks(1_000) {
+            table.insert_all(chunk.to_vec()).unwrap();
+        }
+    }
+    // give the writes time to reach the base tables, since nothing below them is materialized
+    // that could say when they have
+    thread::sleep(Duration::from_millis(500));
+
+    let mut view = g.view("VoteCount").unwrap();
+    let expected = DataType::from(2 * size.rows_per_key);
+    let mut samples = Vec::with_capacity(size.replayed_keys as usize);
+    for aid in 0..size.replayed_keys {
+        let start = Instant::now();
+        let rs = view.lookup(&[aid.into()], true).unwrap();
+        samples.push(as_ns(start.elapsed()));
+        assert_eq!(rs.len(), 1);
+        assert_eq!(rs[0][1], expected);
+    }
+    report_latencies(bench, samples, "ns");
+}
+
+fn main() {
+    let args = Args::from_env();
+    let size = if args.full {
+        Size::full()
+    } else {
+        Size::smoke()
+    };
+
+    for &children in &[1, 8, 32] {
+        if args.wanted(&format!("dispatch/fan-out/{}-children", children)) {
+            fan_out(&size, children);
+        }
+    }
+    if args.wanted("dispatch/union-replay") {
+        union_replay(&size);
+    }
+}
@@ -23,6 +23,7 @@ impl SizeOf for DataType {
 
         let inner = match *self {
             DataType::Text(ref t) => size_of_val(t) as u64 + t.to_bytes().len() as u64,
+            DataType::Bytes(ref bs) => size_of_val(&**bs) as u64 + bs.len() as u64,
             _ => 0u64,
         };
 
@@ -80,6 +81,10 @@ mod tests {
         assert_eq!(size_of_val(&time) as u64, time.size_of());
         assert_eq!(time.deep_size_of(), 16); // DataType + inline NaiveDateTime
 
+        let bytes: DataType = vec![1u8, 2, 3].into();
+        assert_eq!(size_of_val(&bytes), 16);
+        assert_eq!(bytes.deep_size_of(), 16 + 24 + 3); // DataType + Vec + 3 bytes
+
         assert_eq!(size_of_val(&rec), 24);
         assert_eq!(rec.size_of(), 24 + 3 * 16);
         assert_eq!(rec.deep_size_of(), 24 + 3 * 16 + (8 + 16));
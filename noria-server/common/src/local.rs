@@ -126,6 +126,8 @@ pub enum KeyType<'a> {
     Quad((DataType, DataType, DataType, DataType)),
     Quin((DataType, DataType, DataType, DataType, DataType)),
     Sex((DataType, DataType, DataType, DataType, DataType, DataType)),
+    /// Keys of more than six columns.
+    Many(Vec<DataType>),
 }
 
 impl<'a> KeyType<'a> {
@@ -163,7 +165,7 @@ impl<'a> KeyType<'a> {
                 more().clone(),
                 more().clone(),
             )),
-            _ => unimplemented!(),
+            _ => KeyType::Many((0..len).map(|_| more().clone()).collect()),
         }
     }
 }
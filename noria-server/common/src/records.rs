@@ -1,5 +1,9 @@
 use noria::DataType;
-use std::ops::{Deref, DerefMut};
+use std::fmt;
+use std::mem;
+use std::ops::{Deref, DerefMut, Range};
+use std::sync::Arc;
+use std::vec;
 
 /// A record is a single positive or negative data record with an associated time stamp.
 #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
@@ -67,7 +71,7 @@ impl From<(Vec<DataType>, bool)> for Record {
 
 impl Into<Vec<Record>> for Records {
     fn into(self) -> Vec<Record> {
-        self.0
+        self.into_vec()
     }
 }
 
@@ -77,7 +81,9 @@ impl FromIterator<Record> for Records {
     where
         I: IntoIterator<Item = Record>,
     {
-        Records(iter.into_iter().collect())
+        let mut rs = Records::default();
+        rs.extend(iter);
+        rs
     }
 }
 impl FromIterator<Vec<DataType>> for Records {
@@ -85,61 +91,534 @@ impl FromIterator<Vec<DataType>> for Records {
     where
         I: IntoIterator<Item = Vec<DataType>>,
     {
-        Records(iter.into_iter().map(Record::Positive).collect())
+        iter.into_iter().map(Record::Positive).collect()
+    }
+}
+
+impl Extend<Record> for Records {
+    fn extend<I>(&mut self, iter: I)
+    where
+        I: IntoIterator<Item = Record>,
+    {
+        let iter = iter.into_iter();
+        if self.len() + iter.size_hint().0 > INLINE {
+            self.spill().extend(iter);
+        } else {
+            for r in iter {
+                self.push(r);
+            }
+        }
     }
 }
 
 impl IntoIterator for Records {
     type Item = Record;
-    type IntoIter = ::std::vec::IntoIter<Record>;
+    type IntoIter = IntoIter;
     fn into_iter(self) -> Self::IntoIter {
-        self.0.into_iter()
+        IntoIter(match self.0 {
+            Batch::Inline(len, rs) => Remaining::Inline(0..len, rs),
+            Batch::Spilled(rs) => {
+                let rs = Arc::try_unwrap(rs).unwrap_or_else(|rs| (*rs).clone());
+                Remaining::Spilled(rs.into_iter())
+            }
+        })
     }
 }
 impl<'a> IntoIterator for &'a Records {
     type Item = &'a Record;
     type IntoIter = ::std::slice::Iter<'a, Record>;
     fn into_iter(self) -> Self::IntoIter {
-        self.0.iter()
+        self.iter()
+    }
+}
+
+/// An iterator that moves the records out of a batch.
+pub struct IntoIter(Remaining);
+
+enum Remaining {
+    Inline(Range<usize>, [Record; INLINE]),
+    Spilled(vec::IntoIter<Record>),
+}
+
+impl Iterator for IntoIter {
+    type Item = Record;
+    fn next(&mut self) -> Option<Record> {
+        match self.0 {
+            Remaining::Inline(ref mut left, ref mut rs) => {
+                left.next().map(|i| mem::replace(&mut rs[i], vacant()))
+            }
+            Remaining::Spilled(ref mut rs) => rs.next(),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self.0 {
+            Remaining::Inline(ref left, _) => left.size_hint(),
+            Remaining::Spilled(ref rs) => rs.size_hint(),
+        }
+    }
+}
+
+impl ExactSizeIterator for IntoIter {}
+
+/// The number of records a batch holds without allocating.
+const INLINE: usize = 2;
+
+/// An empty record, which holds no allocation, for the unused inline slots.
+fn vacant() -> Record {
+    Record::Positive(Vec::new())
+}
+
+#[derive(Clone)]
+enum Batch {
+    /// The first `.0` records are in use, and the rest are vacant.
+    Inline(usize, [Record; INLINE]),
+    Spilled(Arc<Vec<Record>>),
+}
+
+/// A batch of records.
+///
+/// Most batches hold only a record or two, so the first few records are kept inline, and only
+/// larger batches are moved to the heap. There, clones share the records, so that a batch can be
+/// handed to many children without copying it. The records are only copied when one of the
+/// holders changes them, or takes them by value, while they are still shared.
+#[derive(Clone)]
+pub struct Records(Batch);
+
+impl Default for Records {
+    fn default() -> Self {
+        Records(Batch::Inline(0, [vacant(), vacant()]))
+    }
+}
+
+impl Records {
+    /// Add a record to the end of the batch.
+    pub fn push(&mut self, r: Record) {
+        if let Batch::Inline(ref mut len, ref mut rs) = self.0 {
+            if *len < INLINE {
+                rs[*len] = r;
+                *len += 1;
+                return;
+            }
+        }
+        self.spill().push(r);
+    }
+
+    /// Move all the records of `other` to the end of this batch, leaving `other` empty.
+    pub fn append(&mut self, other: &mut Records) {
+        self.extend(mem::replace(other, Records::default()));
+    }
+
+    /// Keep only the records for which `f` returns true. Shared records are only copied if they
+    /// are kept.
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&Record) -> bool,
+    {
+        match self.0 {
+            Batch::Inline(ref mut len, ref mut rs) => {
+                let mut kept = 0;
+                for i in 0..*len {
+                    if f(&rs[i]) {
+                        rs.swap(kept, i);
+                        kept += 1;
+                    }
+                }
+                for r in &mut rs[kept..*len] {
+                    *r = vacant();
+                }
+                *len = kept;
+            }
+            Batch::Spilled(ref mut rs) => {
+                if let Some(owned) = Arc::get_mut(rs) {
+                    owned.retain(f);
+                    return;
+                }
+                let keep: Vec<bool> = rs.iter().map(|r| f(r)).collect();
+                *rs = Arc::new(kept(rs, &keep));
+            }
+        }
+    }
+
+    /// Keep only the records whose entry in `keep` is true. Shared records are only copied if
+    /// they are kept.
+    pub fn retain_marked(&mut self, keep: &[bool]) {
+        assert_eq!(keep.len(), self.len());
+        if let Batch::Spilled(ref mut rs) = self.0 {
+            if Arc::get_mut(rs).is_none() {
+                *rs = Arc::new(kept(rs, keep));
+                return;
+            }
+        }
+
+        let mut i = 0;
+        self.retain(|_| {
+            i += 1;
+            keep[i - 1]
+        });
+    }
+
+    /// Replace the row of every record with what `f` makes of it, keeping the record's sign.
+    /// Shared records aren't copied first.
+    pub fn map_rows<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&[DataType]) -> Vec<DataType>,
+    {
+        match self.0 {
+            Batch::Inline(len, ref mut rs) => {
+                for r in &mut rs[..len] {
+                    let row = f(&r[..]);
+                    **r = row;
+                }
+            }
+            Batch::Spilled(ref mut rs) => {
+                if let Some(owned) = Arc::get_mut(rs) {
+                    for r in owned {
+                        let row = f(&r[..]);
+                        **r = row;
+                    }
+                    return;
+                }
+                let mapped = rs
+                    .iter()
+                    .map(|r| (f(&r[..]), r.is_positive()).into())
+                    .collect();
+                *rs = Arc::new(mapped);
+            }
+        }
+    }
+
+    /// Change the row of every record in place with `f`, keeping the record's sign. If the records
+    /// are shared, each row is instead replaced by what `copy` makes of it, so that the other
+    /// holders of the batch don't see the change.
+    pub fn update_rows<F, C>(&mut self, f: F, copy: C)
+    where
+        F: FnMut(&mut Vec<DataType>),
+        C: FnMut(&[DataType]) -> Vec<DataType>,
+    {
+        let shared = match self.0 {
+            Batch::Inline(..) => false,
+            Batch::Spilled(ref mut rs) => Arc::get_mut(rs).is_none(),
+        };
+        if shared {
+            self.map_rows(copy);
+        } else {
+            self.iter_mut().map(|r| &mut **r).for_each(f);
+        }
+    }
+
+    /// Move the records to the heap, unless they are there already, so that clones of the batch
+    /// share them rather than copy them.
+    pub fn share(&mut self) {
+        if let Batch::Inline(len, _) = self.0 {
+            if len != 0 {
+                self.spill();
+            }
+        }
+    }
+
+    /// Move the records to the heap, unless they are there already, and give out the only copy of
+    /// them.
+    fn spill(&mut self) -> &mut Vec<Record> {
+        let spilled = if let Batch::Inline(len, ref mut rs) = self.0 {
+            let mut spilled = Vec::with_capacity(2 * INLINE);
+            spilled.extend(rs[..len].iter_mut().map(|r| mem::replace(r, vacant())));
+            Some(spilled)
+        } else {
+            None
+        };
+        if let Some(rs) = spilled {
+            self.0 = Batch::Spilled(Arc::new(rs));
+        }
+
+        match self.0 {
+            Batch::Spilled(ref mut rs) => Arc::make_mut(rs),
+            Batch::Inline(..) => unreachable!(),
+        }
+    }
+
+    fn into_vec(self) -> Vec<Record> {
+        match self.0 {
+            Batch::Inline(len, mut rs) => rs[..len]
+                .iter_mut()
+                .map(|r| mem::replace(r, vacant()))
+                .collect(),
+            Batch::Spilled(rs) => Arc::try_unwrap(rs).unwrap_or_else(|rs| (*rs).clone()),
+        }
     }
 }
 
-#[derive(Clone, Default, PartialEq, Debug, Serialize, Deserialize)]
-pub struct Records(Vec<Record>);
+/// Copy the records whose entry in `keep` is true into a new batch of exactly the right size.
+fn kept(rs: &[Record], keep: &[bool]) -> Vec<Record> {
+    let mut kept = Vec::with_capacity(keep.iter().filter(|&&k| k).count());
+    kept.extend(
+        rs.iter()
+            .zip(keep)
+            .filter(|&(_, &k)| k)
+            .map(|(r, _)| r.clone()),
+    );
+    kept
+}
 
 impl Deref for Records {
-    type Target = Vec<Record>;
+    type Target = [Record];
     fn deref(&self) -> &Self::Target {
-        &self.0
+        match self.0 {
+            Batch::Inline(len, ref rs) => &rs[..len],
+            Batch::Spilled(ref rs) => &rs[..],
+        }
     }
 }
 
 impl DerefMut for Records {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+        match self.0 {
+            Batch::Inline(len, ref mut rs) => &mut rs[..len],
+            Batch::Spilled(ref mut rs) => &mut Arc::make_mut(rs)[..],
+        }
+    }
+}
+
+impl PartialEq for Records {
+    fn eq(&self, other: &Records) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl fmt::Debug for Records {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+// batches go over the wire as plain sequences of records, however they are stored
+impl serde::Serialize for Records {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Records {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        <Vec<Record> as serde::Deserialize>::deserialize(deserializer).map(Into::into)
     }
 }
 
 impl Into<Records> for Record {
     fn into(self) -> Records {
-        Records(vec![self])
+        let mut rs = Records::default();
+        rs.push(self);
+        rs
     }
 }
 
 impl Into<Records> for Vec<Record> {
     fn into(self) -> Records {
-        Records(self)
+        if self.len() <= INLINE {
+            self.into_iter().collect()
+        } else {
+            Records(Batch::Spilled(Arc::new(self)))
+        }
     }
 }
 
 impl Into<Records> for Vec<Vec<DataType>> {
     fn into(self) -> Records {
-        Records(self.into_iter().map(|r| r.into()).collect())
+        self.into_iter().map(Record::Positive).collect()
     }
 }
 
 impl Into<Records> for Vec<(Vec<DataType>, bool)> {
     fn into(self) -> Records {
-        Records(self.into_iter().map(|r| r.into()).collect())
+        self.into_iter().map(Record::from).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records() -> Records {
+        vec![
+            (vec![1.into(), "a".into()], true),
+            (vec![2.into(), "b".into()], false),
+            (vec![3.into(), "c".into()], true),
+        ]
+        .into()
+    }
+
+    fn shared(a: &Records, b: &Records) -> bool {
+        match (&a.0, &b.0) {
+            (&Batch::Spilled(ref a), &Batch::Spilled(ref b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+
+    fn inline(rs: &Records) -> bool {
+        match rs.0 {
+            Batch::Inline(..) => true,
+            Batch::Spilled(..) => false,
+        }
+    }
+
+    #[test]
+    fn small_batches_stay_inline() {
+        let mut rs = Records::default();
+        rs.push(vec![1.into()].into());
+        rs.push(vec![2.into()].into());
+        assert!(inline(&rs));
+        assert_eq!(rs.len(), 2);
+
+        rs.push(vec![3.into()].into());
+        assert!(!inline(&rs));
+        assert_eq!(
+            rs,
+            vec![vec![1.into()], vec![2.into()], vec![3.into()]].into()
+        );
+
+        let rs: Records = vec![vec![1.into()]].into();
+        assert!(inline(&rs));
+        assert_eq!(
+            rs.into_iter().collect::<Vec<_>>(),
+            vec![Record::Positive(vec![1.into()])]
+        );
+    }
+
+    #[test]
+    fn retains_inline_records_in_order() {
+        let mut rs: Records = vec![(vec![1.into()], false), (vec![2.into()], true)].into();
+        rs.retain(|r| r.is_positive());
+        assert_eq!(rs, vec![vec![2.into()]].into());
+        rs.push(vec![3.into()].into());
+        assert_eq!(rs, vec![vec![2.into()], vec![3.into()]].into());
+        assert!(inline(&rs));
+    }
+
+    #[test]
+    fn clones_share_records_until_changed() {
+        let a = records();
+        let mut b = a.clone();
+        assert!(shared(&a, &b));
+
+        b[0] = vec![3.into(), "c".into()].into();
+        assert!(!shared(&a, &b));
+        assert_eq!(a, records());
+        assert_eq!(b[0], vec![3.into(), "c".into()].into());
+    }
+
+    #[test]
+    fn shares_inline_records_on_request() {
+        let mut a: Records = vec![vec![1.into()]].into();
+        a.share();
+        let b = a.clone();
+        assert!(shared(&a, &b));
+        assert_eq!(b, vec![vec![1.into()]].into());
+
+        let mut empty = Records::default();
+        empty.share();
+        assert!(inline(&empty));
+    }
+
+    #[test]
+    fn retains_shared_records() {
+        let a = records();
+        let mut b = a.clone();
+        b.retain(|r| r.is_positive());
+        assert_eq!(a, records());
+        assert_eq!(
+            b,
+            vec![vec![1.into(), "a".into()], vec![3.into(), "c".into()]].into()
+        );
+    }
+
+    #[test]
+    fn retains_marked_records() {
+        let keep = [true, false, true];
+        let expected: Records = vec![vec![1.into(), "a".into()], vec![3.into(), "c".into()]].into();
+
+        let a = records();
+        let mut b = a.clone();
+        b.retain_marked(&keep);
+        assert_eq!(a, records());
+        assert_eq!(b, expected);
+        match b.0 {
+            Batch::Spilled(ref rs) => assert_eq!(rs.capacity(), 2),
+            Batch::Inline(..) => unreachable!(),
+        }
+
+        let mut a = a;
+        a.retain_marked(&keep);
+        assert_eq!(a, expected);
+
+        let mut rs: Records = vec![vec![1.into()], vec![2.into()]].into();
+        rs.retain_marked(&[false, true]);
+        assert_eq!(rs, vec![vec![2.into()]].into());
+    }
+
+    #[test]
+    fn updates_owned_rows_in_place() {
+        let a = records();
+        let mut b = a.clone();
+        b.update_rows(|_| unreachable!(), |r| vec![r[1].clone()]);
+        assert_eq!(a, records());
+        assert_eq!(
+            b,
+            vec![
+                (vec!["a".into()], true),
+                (vec!["b".into()], false),
+                (vec!["c".into()], true),
+            ]
+            .into()
+        );
+
+        let mut a = a;
+        drop(b);
+        a.update_rows(|r| r.truncate(1), |_| unreachable!());
+        assert_eq!(
+            a,
+            vec![
+                (vec![1.into()], true),
+                (vec![2.into()], false),
+                (vec![3.into()], true),
+            ]
+            .into()
+        );
+    }
+
+    #[test]
+    fn maps_shared_rows() {
+        let a = records();
+        let mut b = a.clone();
+        b.map_rows(|r| vec![r[1].clone()]);
+        assert_eq!(a, records());
+        assert_eq!(
+            b,
+            vec![
+                (vec!["a".into()], true),
+                (vec!["b".into()], false),
+                (vec!["c".into()], true),
+            ]
+            .into()
+        );
+
+        // a batch that isn't shared is changed in place
+        let mut a = a;
+        drop(b);
+        a.map_rows(|r| vec![r[0].clone()]);
+        assert_eq!(
+            a,
+            vec![
+                (vec![1.into()], true),
+                (vec![2.into()], false),
+                (vec![3.into()], true),
+            ]
+            .into()
+        );
     }
 }
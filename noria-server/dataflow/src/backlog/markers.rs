@@ -0,0 +1,71 @@
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+
+/// The number of markers a reader holds on to for clients that have yet to wait for them, past
+/// which the oldest ones are forgotten.
+pub(super) const CAPACITY: usize = 4096;
+
+/// The markers of the writes that a reader has made visible to reads, shared between its read and
+/// write handles, until the clients that wait for those writes take them.
+///
+/// A client may give up on a write before it is visible, or go away altogether, so markers that
+/// are never taken are only held on to until `CAPACITY` newer ones have been published.
+#[derive(Default)]
+pub(super) struct Markers {
+    published: Mutex<Published>,
+}
+
+#[derive(Default)]
+struct Published {
+    markers: HashSet<u64>,
+    // the markers in the order they were published, some of which may have been taken since
+    order: VecDeque<u64>,
+}
+
+impl Markers {
+    /// Note that the writes with the given markers are now visible to reads.
+    pub(super) fn publish(&self, markers: Vec<u64>) {
+        let mut published = self.published.lock().unwrap();
+        let published = &mut *published;
+        for marker in markers {
+            if !published.markers.insert(marker) {
+                // already published by way of another path through the graph
+                continue;
+            }
+            published.order.push_back(marker);
+            while published.markers.len() > CAPACITY {
+                let oldest = published.order.pop_front().unwrap();
+                published.markers.remove(&oldest);
+            }
+        }
+        if published.order.len() > 2 * CAPACITY {
+            // drop what is left over of markers that have been taken
+            let Published {
+                ref markers,
+                ref mut order,
+            } = *published;
+            order.retain(|m| markers.contains(m));
+        }
+    }
+
+    /// Whether the write with the given marker has been made visible to reads. It is forgotten if
+    /// it has.
+    pub(super) fn take(&self, marker: u64) -> bool {
+        self.published.lock().unwrap().markers.remove(&marker)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_forgets_the_oldest_markers() {
+        let markers = Markers::default();
+        markers.publish((0..CAPACITY as u64 + 1).collect());
+        assert!(!markers.take(0));
+        assert!(markers.take(1));
+        assert!(!markers.take(1));
+        assert!(markers.take(CAPACITY as u64));
+    }
+}
@@ -1,10 +1,22 @@
 use common::SizeOf;
-use fnv::FnvBuildHasher;
+use fnv::{FnvBuildHasher, FnvHashMap, FnvHasher};
+use metrics::ReaderMetrics;
+use noria::filter::RowFilter;
+use noria::{compare_rows, Direction, ReadMeta};
 use prelude::*;
+use state::is_empty_range;
 use std::borrow::Cow;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::iter;
+use std::mem;
+use std::ops::Bound;
+use std::time::SystemTime;
 
 use rand::{Rng, ThreadRng};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::{Arc, Mutex, RwLock};
 
 /// Allocate a new end-user facing result table.
 pub(crate) fn new(cols: usize, key: &[usize]) -> (SingleReadHandle, WriteHandle) {
@@ -25,6 +37,153 @@ where
     new_inner(cols, key, Some(Arc::new(trigger)))
 }
 
+/// Allocate a new end-user facing result table keyed on a single column that also supports
+/// lookups by range.
+pub(crate) fn new_ordered(cols: usize, column: usize) -> (SingleReadHandle, WriteHandle) {
+    let (mut r, mut w) = new_inner(cols, &[column], None);
+    let ordered = Arc::new(RwLock::new(BTreeMap::new()));
+    r.ordered = Some(ordered.clone());
+    w.ordered = Some(ordered);
+    (r, w)
+}
+
+/// The rows of an ordered reader, by the value of the column it is keyed on.
+///
+/// `evmap` doesn't keep its keys in order, so ordered readers also keep their rows here. Writes
+/// are only applied to it when the reader is swapped, so that range lookups see the same rows as
+/// regular lookups do.
+type OrderedRows = BTreeMap<DataType, Vec<Vec<DataType>>>;
+
+/// Make `w` keep track of which of its keys are read through `r` (or any of its clones), so that
+/// the least recently read keys can be evicted first using `WriteHandle::evict_lru_keys`.
+pub(crate) fn track_reads(r: &mut SingleReadHandle, w: &mut WriteHandle) {
+    let recency = Arc::new(Mutex::new(Recency::default()));
+    r.recency = Some(recency.clone());
+    w.recency = Some(recency);
+}
+
+/// Make `w` also keep the rows of each key in order of the given column, so that pages of them
+/// ordered by that column can be read through `r` (or any of its clones) without sorting.
+///
+/// Evictions are not applied to the sorted rows, so this must only be used for fully materialized
+/// readers.
+pub(crate) fn keep_sorted(r: &mut SingleReadHandle, w: &mut WriteHandle, column: usize) {
+    assert!(
+        !w.partial,
+        "only fully materialized readers can be kept sorted"
+    );
+    let sorted = Arc::new(RwLock::new(SortedRows {
+        column,
+        rows: FnvHashMap::default(),
+    }));
+    r.sorted = Some(sorted.clone());
+    w.sorted = Some(sorted);
+}
+
+/// Let the contents of the reader behind `r` (and any of its clones) be read in batches with
+/// `SingleReadHandle::scan_and`.
+///
+/// Scans only see the keys that are present in the reader, so this must only be used for fully
+/// materialized readers.
+pub(crate) fn allow_scans(r: &mut SingleReadHandle) {
+    assert!(
+        r.trigger.is_none(),
+        "only fully materialized readers can be scanned"
+    );
+    r.scannable = true;
+}
+
+/// Whether the given row passes `filter`, if there is one.
+fn passes(filter: Option<&RowFilter>, r: &[DataType]) -> bool {
+    filter.map(|f| f.matches(r)).unwrap_or(true)
+}
+
+/// Sort those of `rows` that pass `filter` by `order_by` (see `compare_rows`), and pass at most
+/// `limit` of them, starting at `offset`, through `then`. Also returns the total number of rows
+/// that passed.
+pub fn page_of<F, T>(
+    rows: &[Vec<DataType>],
+    filter: Option<&RowFilter>,
+    order_by: (usize, Direction),
+    offset: usize,
+    limit: usize,
+    then: F,
+) -> (Vec<T>, usize)
+where
+    F: FnMut(&[DataType]) -> T,
+{
+    let mut sorted: Vec<_> = rows
+        .iter()
+        .map(|r| &r[..])
+        .filter(|r| passes(filter, r))
+        .collect();
+    sorted.sort_by(|a, b| compare_rows(a, b, order_by));
+    let total = sorted.len();
+    let page = sorted
+        .into_iter()
+        .skip(offset)
+        .take(limit)
+        .map(then)
+        .collect();
+    (page, total)
+}
+
+/// The rows of each key of a reader, in order of a single column (see `keep_sorted`).
+///
+/// Like `OrderedRows`, writes are only applied here when the reader is swapped.
+struct SortedRows {
+    column: usize,
+    rows: FnvHashMap<Vec<DataType>, Vec<Vec<DataType>>>,
+}
+
+impl SortedRows {
+    fn apply(&mut self, key: &[usize], contiguous: bool, r: &Record) {
+        let order_by = (self.column, Direction::Ascending);
+        let k = key_from_record(key, contiguous, &r[..]);
+        match *r {
+            Record::Positive(ref r) => {
+                let rs = self.rows.entry(k.into_owned()).or_insert_with(Vec::new);
+                let i = match rs.binary_search_by(|row| compare_rows(row, r, order_by)) {
+                    Ok(i) | Err(i) => i,
+                };
+                rs.insert(i, r.clone());
+            }
+            Record::Negative(ref r) => {
+                let now_empty = match self.rows.get_mut(&*k) {
+                    Some(rs) => {
+                        if let Ok(i) = rs.binary_search_by(|row| compare_rows(row, r, order_by)) {
+                            rs.remove(i);
+                        }
+                        rs.is_empty()
+                    }
+                    None => false,
+                };
+                if now_empty {
+                    self.rows.remove(&*k);
+                }
+            }
+        }
+    }
+}
+
+/// When each filled key was last read (or filled), measured in ticks of a logical clock.
+#[derive(Default)]
+struct Recency {
+    clock: u64,
+    last_read: FnvHashMap<Vec<DataType>, u64>,
+}
+
+impl Recency {
+    fn touch(&mut self, key: &[DataType]) {
+        self.clock += 1;
+        if let Some(t) = self.last_read.get_mut(key) {
+            *t = self.clock;
+            return;
+        }
+        self.last_read.insert(Vec::from(key), self.clock);
+    }
+}
+
 fn new_inner(
     cols: usize,
     key: &[usize],
@@ -49,7 +208,11 @@ fn new_inner(
         ($variant:tt) => {{
             use evmap;
             let (r, w) = evmap::Options::default()
-                .with_meta(-1)
+                .with_meta(Version {
+                    epoch: -1,
+                    written: None,
+                    published: None,
+                })
                 .with_hasher(FnvBuildHasher::default())
                 .construct();
 
@@ -64,6 +227,9 @@ fn new_inner(
         _ => make!(Many),
     };
 
+    let subscribers = Arc::new(Subscribers::default());
+    let markers = Arc::new(Markers::default());
+    let retired = Arc::new(AtomicBool::new(false));
     let w = WriteHandle {
         partial: trigger.is_some(),
         handle: w,
@@ -71,18 +237,58 @@ fn new_inner(
         cols: cols,
         contiguous,
         mem_size: 0,
+        recency: None,
+        ordered: None,
+        sorted: None,
+        pending: Vec::new(),
+        unpublished: FnvHashMap::default(),
+        epoch: -1,
+        written: None,
+        subscribers: subscribers.clone(),
+        subscribed_pending: HashMap::new(),
+        markers: markers.clone(),
+        markers_pending: Vec::new(),
+        retired: retired.clone(),
     };
     let r = SingleReadHandle {
         handle: r,
         trigger: trigger,
         key: Vec::from(key),
+        cols,
+        ordered: None,
+        sorted: None,
+        scannable: false,
+        recency: None,
+        subscribers,
+        markers,
+        retired,
+        metrics: Default::default(),
     };
 
     (r, w)
 }
 
+mod markers;
 mod multir;
 mod multiw;
+mod subscriptions;
+
+/// The version of a reader's state that lookups see, which the writer publishes as the map's meta
+/// whenever it swaps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Version {
+    // 0 for the version swapped in first, which makes the map ready, and one more for every swap
+    // after that, so that readers can tell whether two lookups saw the same version of the map
+    epoch: i64,
+    // when the most recent write applied to this version was accepted by its base
+    written: Option<SystemTime>,
+    // when this version was swapped in
+    published: Option<SystemTime>,
+}
+
+use self::markers::Markers;
+use self::subscriptions::{Deltas, Subscribers};
+pub use self::subscriptions::{Subscription, SubscriptionError};
 
 fn key_to_single<'a>(k: Key<'a>) -> Cow<'a, DataType> {
     assert_eq!(k.len(), 1);
@@ -112,6 +318,25 @@ pub(crate) struct WriteHandle {
     key: Vec<usize>,
     contiguous: bool,
     mem_size: usize,
+    recency: Option<Arc<Mutex<Recency>>>,
+    ordered: Option<Arc<RwLock<OrderedRows>>>,
+    sorted: Option<Arc<RwLock<SortedRows>>>,
+    // records added since the last swap, which have yet to be applied to `ordered` and `sorted`
+    pending: Vec<Record>,
+    // how many copies of each row have been added (or removed, if negative) since the last swap,
+    // which lookups through the handle don't see yet. rows are counted by their hash, so that they
+    // don't have to be copied to be counted; rows whose hashes collide are counted together.
+    unpublished: FnvHashMap<u64, isize>,
+    // published to readers as the map's meta on every swap
+    epoch: i64,
+    written: Option<SystemTime>,
+    subscribers: Arc<Subscribers>,
+    // deltas to subscribed keys since the last swap, which have yet to be sent to the subscribers
+    subscribed_pending: Deltas,
+    markers: Arc<Markers>,
+    // markers of the writes applied since the last swap, which are published along with them
+    markers_pending: Vec<u64>,
+    retired: Arc<AtomicBool>,
 }
 
 type Key<'a> = Cow<'a, [DataType]>;
@@ -131,6 +356,10 @@ impl<'a> MutWriteHandleEntry<'a> {
             .handle
             .meta_get_and(Cow::Borrowed(&*self.key), |rs| rs.is_empty())
         {
+            if let Some(ref recency) = self.handle.recency {
+                // the key was filled because someone wanted to read it
+                recency.lock().unwrap().touch(&self.key);
+            }
             self.handle.handle.clear(self.key)
         } else {
             unreachable!("attempted to fill already-filled key");
@@ -147,6 +376,9 @@ impl<'a> MutWriteHandleEntry<'a> {
             .map(|r| r.0.unwrap_or(0))
             .unwrap_or(0);
         self.handle.mem_size = self.handle.mem_size.checked_sub(size as usize).unwrap();
+        if let Some(ref recency) = self.handle.recency {
+            recency.lock().unwrap().last_read.remove(&self.key[..]);
+        }
         self.handle.handle.empty(self.key)
     }
 }
@@ -159,6 +391,7 @@ impl<'a> WriteHandleEntry<'a> {
         self.handle
             .handle
             .meta_get_and(self.key, &mut then)
+            .map(|(rs, version)| (rs, version.epoch))
             .ok_or(())
     }
 }
@@ -191,7 +424,55 @@ where
     }
 }
 
+/// Drop pairs of positive and negative records for identical rows from `rs`, leaving only the net
+/// change to each row. If a row is added more times than it is removed, the first of its
+/// positive records are kept, and vice versa.
+fn compact(rs: Vec<Record>) -> Vec<Record> {
+    // nothing can cancel out unless there are both positives and negatives
+    let positives = rs.iter().filter(|r| r.is_positive()).count();
+    if positives == 0 || positives == rs.len() {
+        return rs;
+    }
+
+    let keep: Vec<bool> = {
+        let mut net: HashMap<&[DataType], isize> = HashMap::with_capacity(rs.len());
+        for r in &rs {
+            *net.entry(&r[..]).or_insert(0) += if r.is_positive() { 1 } else { -1 };
+        }
+        rs.iter()
+            .map(|r| {
+                let left = net.get_mut(&r[..]).unwrap();
+                if r.is_positive() && *left > 0 {
+                    *left -= 1;
+                    true
+                } else if !r.is_positive() && *left < 0 {
+                    *left += 1;
+                    true
+                } else {
+                    false
+                }
+            })
+            .collect()
+    };
+    rs.into_iter()
+        .zip(keep)
+        .filter_map(|(r, keep)| if keep { Some(r) } else { None })
+        .collect()
+}
+
+// The hash under which `WriteHandle` counts the copies of `r` that are yet to be swapped in.
+fn row_hash(r: &[DataType]) -> u64 {
+    let mut hasher = FnvHasher::default();
+    r.hash(&mut hasher);
+    hasher.finish()
+}
+
 impl WriteHandle {
+    /// Whether reads through the given handle see what is written through this one.
+    pub(crate) fn serves(&self, r: &SingleReadHandle) -> bool {
+        Arc::ptr_eq(&self.subscribers, &r.subscribers)
+    }
+
     pub(crate) fn mut_with_key<'a, K>(&'a mut self, key: K) -> MutWriteHandleEntry<'a>
     where
         K: Into<Key<'a>>,
@@ -230,17 +511,174 @@ impl WriteHandle {
     }
 
     pub(crate) fn swap(&mut self) {
-        self.handle.refresh();
+        self.epoch += 1;
+        self.handle.set_meta(Version {
+            epoch: self.epoch,
+            written: self.written,
+            published: Some(SystemTime::now()),
+        });
+
+        {
+            // hold on to the locks until the evmap has been refreshed too, so that no range or
+            // page lookup sees rows that regular lookups can't see yet
+            let mut ordered = self.ordered.as_ref().map(|o| o.write().unwrap());
+            let mut sorted = self.sorted.as_ref().map(|s| s.write().unwrap());
+            let column = self.key[0];
+            for r in self.pending.drain(..) {
+                if let Some(ref mut sorted) = sorted {
+                    sorted.apply(&self.key[..], self.contiguous, &r);
+                }
+                let ordered = match ordered {
+                    Some(ref mut ordered) => ordered,
+                    None => continue,
+                };
+                match r {
+                    Record::Positive(r) => {
+                        ordered
+                            .entry(r[column].clone())
+                            .or_insert_with(Vec::new)
+                            .push(r);
+                    }
+                    Record::Negative(r) => {
+                        let now_empty = match ordered.get_mut(&r[column]) {
+                            Some(rs) => {
+                                if let Some(i) = rs.iter().position(|row| row == &r) {
+                                    rs.remove(i);
+                                }
+                                rs.is_empty()
+                            }
+                            None => false,
+                        };
+                        if now_empty {
+                            ordered.remove(&r[column]);
+                        }
+                    }
+                }
+            }
+            self.handle.refresh();
+            self.unpublished.clear();
+        }
+
+        // subscribers only hear about changes once they are visible to reads
+        if !self.subscribed_pending.is_empty() {
+            let deltas = mem::replace(&mut self.subscribed_pending, HashMap::new());
+            self.subscribers.publish(deltas);
+        }
+        // and so do the clients that wait for writes
+        if !self.markers_pending.is_empty() {
+            let markers = mem::replace(&mut self.markers_pending, Vec::new());
+            self.markers.publish(markers);
+        }
+    }
+
+    /// Note that a write that was accepted by its base at the given time has been added, so that
+    /// lookups can tell how up to date the rows are once it is swapped in.
+    pub(crate) fn applied_write(&mut self, written: SystemTime) {
+        if self.written.map(|w| w < written).unwrap_or(true) {
+            self.written = Some(written);
+        }
+    }
+
+    /// Note that the writes with the given markers have been added, so that the clients waiting for
+    /// them can tell once they are swapped in.
+    pub(crate) fn applied_markers(&mut self, markers: &[u64]) {
+        self.markers_pending.extend_from_slice(markers);
+    }
+
+    /// A checksum of the rows that are visible to reads (see `noria::checksum`).
+    pub(crate) fn checksum(&self) -> u64 {
+        let mut sum = 0u64;
+        self.handle
+            .for_each(|rs| sum = sum.wrapping_add(noria::checksum(rs)));
+        sum
+    }
+
+    /// The number of rows that are visible to reads.
+    pub(crate) fn rows(&self) -> usize {
+        let mut rows = 0;
+        self.handle.for_each(|rs| rows += rs.len());
+        rows
+    }
+
+    /// A copy of every row that is visible to reads.
+    pub(crate) fn cloned_rows(&self) -> Vec<Vec<DataType>> {
+        let mut rows = Vec::new();
+        self.handle.for_each(|rs| rows.extend(rs.iter().cloned()));
+        rows
+    }
+
+    /// Whether anyone is subscribed to changes to any of the keys of this reader.
+    pub(crate) fn has_subscribers(&self) -> bool {
+        !self.subscribers.is_empty()
+    }
+
+    /// Pass on the given changes to the subscribers of the keys they affect after the next call to
+    /// `swap()`.
+    pub(crate) fn publish<'a, I>(&mut self, rs: I)
+    where
+        I: IntoIterator<Item = &'a Record>,
+    {
+        let key = &self.key[..];
+        let contiguous = self.contiguous;
+        self.subscribers.select(
+            rs,
+            |r| key_from_record(key, contiguous, r),
+            &mut self.subscribed_pending,
+        );
     }
 
     /// Add a new set of records to the backlog.
     ///
-    /// These will be made visible to readers after the next call to `swap()`.
-    pub(crate) fn add<I>(&mut self, rs: I)
+    /// These will be made visible to readers after the next call to `swap()`. Records that cancel
+    /// each other out are dropped first, so that only the net change is applied.
+    ///
+    /// Identical rows are kept once for every time they were added, and each negative record
+    /// removes only one of them. Negative records for rows that have no copies left are not
+    /// applied, and are returned instead, unless their key is a hole.
+    pub(crate) fn add<I>(&mut self, rs: I) -> Vec<Record>
     where
         I: IntoIterator<Item = Record>,
     {
+        let mut rs = compact(rs.into_iter().collect());
+        let mut unmatched = Vec::new();
+        // the evmap drops every copy of a row that it is asked to remove, so the copies that are
+        // meant to stay have to be added back afterwards
+        let mut remaining = FnvHashMap::default();
+        rs.retain(|r| match *r {
+            Record::Positive(ref row) => {
+                *self.unpublished.entry(row_hash(row)).or_insert(0) += 1;
+                true
+            }
+            Record::Negative(ref row) => match self.copies(row) {
+                // nothing to remove from a hole
+                None => false,
+                Some(0) => {
+                    unmatched.push(r.clone());
+                    false
+                }
+                Some(n) => {
+                    *self.unpublished.entry(row_hash(row)).or_insert(0) -= 1;
+                    // only rows that have other copies left need any of them added back
+                    if n > 1 {
+                        remaining.insert(row.clone(), n - 1);
+                    } else {
+                        remaining.remove(row);
+                    }
+                    true
+                }
+            },
+        });
+
+        if self.ordered.is_some() || self.sorted.is_some() {
+            self.pending.extend(rs.iter().cloned());
+        }
         let mem_delta = self.handle.add(&self.key[..], self.cols, rs);
+        // as far as memory goes, the copies that are added back were never removed
+        let restored = remaining
+            .into_iter()
+            .flat_map(|(row, n)| iter::repeat(row).take(n))
+            .map(Record::Positive);
+        self.handle.add(&self.key[..], self.cols, restored);
         if mem_delta > 0 {
             self.mem_size += mem_delta as usize;
         } else if mem_delta < 0 {
@@ -249,6 +687,23 @@ impl WriteHandle {
                 .checked_sub(mem_delta.checked_abs().unwrap() as usize)
                 .unwrap();
         }
+        unmatched
+    }
+
+    /// How many copies of `r` there are, including those added or removed since the last swap, or
+    /// `None` if the key of `r` is a hole.
+    fn copies(&self, r: &[DataType]) -> Option<usize> {
+        let unpublished = self.unpublished.get(&row_hash(r)).cloned().unwrap_or(0);
+        let published = self
+            .entry_from_record(r)
+            .try_find_and(|rs| rs.iter().filter(|row| &row[..] == r).count());
+        let published = match published {
+            Ok((Some(n), _)) => n as isize,
+            Ok((None, _)) if self.partial && unpublished == 0 => return None,
+            // the key has no rows, or nothing has been swapped in yet
+            _ => 0,
+        };
+        Some(cmp::max(published + unpublished, 0) as usize)
     }
 
     pub(crate) fn is_partial(&self) -> bool {
@@ -278,6 +733,61 @@ impl WriteHandle {
         }
         bytes_to_be_freed
     }
+
+    /// Evict the least recently read keys until at least `num_bytes` will have been freed, or
+    /// there are no more keys to evict. Returns the evicted keys along with the number of bytes
+    /// that will be freed once the underlying `evmap` applies the operation.
+    ///
+    /// Only keys whose contents are visible to readers are considered, and nothing is evicted
+    /// unless reads are being tracked (see `track_reads`).
+    pub(crate) fn evict_lru_keys(&mut self, num_bytes: u64) -> (Vec<Vec<DataType>>, u64) {
+        let mut lru: Vec<_> = match self.recency {
+            Some(ref recency) => recency
+                .lock()
+                .unwrap()
+                .last_read
+                .iter()
+                .map(|(k, &t)| (t, k.clone()))
+                .collect(),
+            None => return (Vec::new(), 0),
+        };
+        lru.sort_by_key(|&(t, _)| t);
+
+        let mut evicted = Vec::new();
+        let mut freed = 0;
+        for (_, key) in lru {
+            if freed >= num_bytes {
+                break;
+            }
+            if let Some(size) = self.evict_key(&key[..]) {
+                freed += size;
+                evicted.push(key);
+            }
+        }
+        (evicted, freed)
+    }
+
+    /// Evict the given key if it is filled, returning the number of bytes that will be freed once
+    /// the underlying `evmap` applies the operation.
+    pub(crate) fn evict_key(&mut self, key: &[DataType]) -> Option<u64> {
+        let size = match self
+            .with_key(key)
+            .try_find_and(|rs| rs.iter().map(|r| r.deep_size_of()).sum())
+        {
+            Ok((Some(size), _)) => size,
+            _ => return None,
+        };
+        self.mut_with_key(key).mark_hole();
+        Some(size)
+    }
+}
+
+impl Drop for WriteHandle {
+    fn drop(&mut self) {
+        // the reader is going away, so nothing more will happen to its keys
+        self.subscribers.close();
+        self.retired.store(true, AtomicOrdering::Release);
+    }
 }
 
 impl SizeOf for WriteHandle {
@@ -298,9 +808,54 @@ pub struct SingleReadHandle {
     handle: multir::Handle,
     trigger: Option<Arc<Fn(&[DataType]) + Send + Sync>>,
     key: Vec<usize>,
+    cols: usize,
+    ordered: Option<Arc<RwLock<OrderedRows>>>,
+    sorted: Option<Arc<RwLock<SortedRows>>>,
+    scannable: bool,
+    recency: Option<Arc<Mutex<Recency>>>,
+    subscribers: Arc<Subscribers>,
+    markers: Arc<Markers>,
+    // set once reads should no longer go through this handle, because the reader is gone, or
+    // another reader has taken over from it
+    retired: Arc<AtomicBool>,
+    metrics: Arc<ReaderMetrics>,
 }
 
 impl SingleReadHandle {
+    /// The counters that reads through this handle, or any handle for the same reader, should
+    /// update.
+    pub fn metrics(&self) -> &Arc<ReaderMetrics> {
+        &self.metrics
+    }
+
+    /// The number of columns in the rows of the reader behind this handle.
+    pub fn columns(&self) -> usize {
+        self.cols
+    }
+
+    /// Whether the reader behind this handle has been removed, or replaced by another reader.
+    /// Whoever holds on to the handle should look up the reader's handle again, which will then
+    /// either be missing or lead to its replacement.
+    pub fn is_retired(&self) -> bool {
+        self.retired.load(AtomicOrdering::Acquire)
+    }
+
+    /// Mark this handle, and every other handle for the same reader, as retired.
+    pub(crate) fn retire(&self) {
+        self.retired.store(true, AtomicOrdering::Release);
+    }
+
+    /// Whether this handle and the given one read from the same reader.
+    pub(crate) fn same_as(&self, other: &SingleReadHandle) -> bool {
+        Arc::ptr_eq(&self.subscribers, &other.subscribers)
+    }
+
+    /// Whether the write with the given marker is visible to reads. Each marker is only seen once,
+    /// by whichever client takes it first.
+    pub fn take_marker(&self, marker: u64) -> bool {
+        self.markers.take(marker)
+    }
+
     /// Trigger a replay of a missing key from a partially materialized view.
     pub fn trigger(&self, key: &[DataType]) {
         assert!(
@@ -320,7 +875,46 @@ impl SingleReadHandle {
     /// swapped in by the writer.
     ///
     /// Holes in partially materialized state are returned as `Ok((None, _))`.
-    pub fn try_find_and<F, T>(&self, key: &[DataType], mut then: F) -> Result<(Option<T>, i64), ()>
+    ///
+    /// The returned meta is the epoch of the version of the map that was read: 0 for the version
+    /// that the writer's first swap makes ready, and one more for every swap after that. Writes
+    /// that have not been swapped in don't change it.
+    pub fn try_find_and<F, T>(&self, key: &[DataType], then: F) -> Result<(Option<T>, i64), ()>
+    where
+        F: FnMut(&[Vec<DataType>]) -> T,
+    {
+        self.find_versioned_and(key, then)
+            .map(|(rs, version)| (rs, version.epoch))
+    }
+
+    /// Like `try_find_and`, but also says how up to date the rows are.
+    pub fn try_find_with_meta_and<F, T>(
+        &self,
+        key: &[DataType],
+        then: F,
+    ) -> Result<(Option<T>, ReadMeta), ()>
+    where
+        F: FnMut(&[Vec<DataType>]) -> T,
+    {
+        self.find_versioned_and(key, then)
+            .map(|(rs, version)| (rs, self.meta(version)))
+    }
+
+    // What is said about how up to date the rows read from `version` are. This describes the same
+    // version as the epoch `try_find_and` returns, so all of it was published by a single swap.
+    fn meta(&self, version: Version) -> ReadMeta {
+        ReadMeta {
+            written: version.written,
+            published: version.published,
+            full: self.trigger.is_none(),
+        }
+    }
+
+    fn find_versioned_and<F, T>(
+        &self,
+        key: &[DataType],
+        mut then: F,
+    ) -> Result<(Option<T>, Version), ()>
     where
         F: FnMut(&[Vec<DataType>]) -> T,
     {
@@ -330,16 +924,270 @@ impl SingleReadHandle {
             .map(|(mut records, meta)| {
                 if records.is_none() && self.trigger.is_none() {
                     records = Some(then(&[]));
+                } else if records.is_some() {
+                    if let Some(ref recency) = self.recency {
+                        recency.lock().unwrap().touch(key);
+                    }
                 }
                 (records, meta)
             })
     }
 
+    /// Subscribe to the changes made to the given key from now on. At most `buffer` batches of
+    /// changes are held for the subscriber; if it falls further behind than that, it is cut off.
+    ///
+    /// Subscriptions only work within the process that holds the reader.
+    pub fn subscribe(&self, key: &[DataType], buffer: usize) -> Subscription {
+        assert_eq!(key.len(), self.key.len());
+        Subscribers::subscribe(&self.subscribers, Vec::from(key), buffer, None)
+    }
+
+    /// Like `subscribe`, but the subscriber only hears about changes to the rows that pass
+    /// `filter`. Batches of changes that none of those rows are in are not delivered at all.
+    pub fn subscribe_filtered(
+        &self,
+        key: &[DataType],
+        buffer: usize,
+        filter: RowFilter,
+    ) -> Subscription {
+        assert_eq!(key.len(), self.key.len());
+        Subscribers::subscribe(&self.subscribers, Vec::from(key), buffer, Some(filter))
+    }
+
+    /// Like `try_find_and`, but looks up many keys at once. The result for each key is at the same
+    /// position as the key in `keys`.
+    ///
+    /// All the keys are looked up in the same version of the map; if the writer swaps in a new
+    /// version while we are reading, the lookups are retried.
+    pub fn try_find_many_and<F, T>(
+        &self,
+        keys: &[Vec<DataType>],
+        then: F,
+    ) -> Result<Vec<Option<T>>, ()>
+    where
+        F: FnMut(&[Vec<DataType>]) -> T,
+    {
+        self.try_find_many_with_meta_and(keys, then)
+            .map(|(found, _)| found)
+    }
+
+    /// Like `try_find_many_and`, but also says how up to date the rows are.
+    pub fn try_find_many_with_meta_and<F, T>(
+        &self,
+        keys: &[Vec<DataType>],
+        mut then: F,
+    ) -> Result<(Vec<Option<T>>, ReadMeta), ()>
+    where
+        F: FnMut(&[Vec<DataType>]) -> T,
+    {
+        'retry: loop {
+            let mut version = None;
+            let mut found = Vec::with_capacity(keys.len());
+            for key in keys {
+                let (rs, v) = self.find_versioned_and(key, &mut then)?;
+                if version.get_or_insert(v).epoch != v.epoch {
+                    continue 'retry;
+                }
+                found.push(rs);
+            }
+            // with no keys, nothing was read
+            let version = version.unwrap_or(Version {
+                epoch: 0,
+                written: None,
+                published: None,
+            });
+            return Ok((found, self.meta(version)));
+        }
+    }
+
+    /// Find the rows for the given key that pass `filter`, if given, sort them by `order_by` (see
+    /// `compare_rows`), and pass at most `limit` of them, starting at `offset`, through `then`.
+    /// Also returns the total number of rows for the key that passed.
+    ///
+    /// If this reader keeps its rows sorted by the requested column, they are not sorted again.
+    /// Holes in partially materialized state are returned as `Ok(None)`.
+    pub fn try_find_page_and<F, T>(
+        &self,
+        key: &[DataType],
+        filter: Option<&RowFilter>,
+        order_by: (usize, Direction),
+        offset: usize,
+        limit: usize,
+        mut then: F,
+    ) -> Result<Option<(Vec<T>, usize)>, ()>
+    where
+        F: FnMut(&[DataType]) -> T,
+    {
+        if let Some(ref sorted) = self.sorted {
+            let sorted = sorted.read().unwrap();
+            if sorted.column == order_by.0 {
+                // keys without rows aren't in the sorted rows, and neither are any keys before
+                // the reader is ready, so let the map sort those out
+                if let Some(rs) = sorted.rows.get(key) {
+                    let rs: Vec<_> = rs.iter().filter(|r| passes(filter, r)).collect();
+                    let page = match order_by.1 {
+                        Direction::Ascending => rs
+                            .iter()
+                            .skip(offset)
+                            .take(limit)
+                            .map(|r| then(&r[..]))
+                            .collect(),
+                        Direction::Descending => rs
+                            .iter()
+                            .rev()
+                            .skip(offset)
+                            .take(limit)
+                            .map(|r| then(&r[..]))
+                            .collect(),
+                    };
+                    return Ok(Some((page, rs.len())));
+                }
+            }
+        }
+
+        self.try_find_and(key, |rs| {
+            page_of(rs, filter, order_by, offset, limit, &mut then)
+        })
+        .map(|(page, _)| page)
+    }
+
+    /// Find the rows whose key lies within the given bounds, and that pass `filter` if given, and
+    /// return at most `limit` of them, in key order, after passing each through `then`. Also
+    /// returns whether there were more such rows in range than `limit` allowed for.
+    ///
+    /// Returns `Err(())` if this reader has no ordered index.
+    pub fn find_range_and<F, T>(
+        &self,
+        lower: Bound<&DataType>,
+        upper: Bound<&DataType>,
+        limit: Option<usize>,
+        filter: Option<&RowFilter>,
+        then: F,
+    ) -> Result<(Vec<T>, bool), ()>
+    where
+        F: FnMut(&[DataType]) -> T,
+    {
+        let ordered = match self.ordered {
+            Some(ref ordered) => ordered.read().unwrap(),
+            None => return Err(()),
+        };
+        if is_empty_range(lower, upper) {
+            return Ok((Vec::new(), false));
+        }
+
+        let mut rows = ordered
+            .range((lower, upper))
+            .flat_map(|(_, rs)| rs)
+            .map(|r| &r[..])
+            .filter(|r| passes(filter, r));
+        let found = rows
+            .by_ref()
+            .take(limit.unwrap_or(usize::max_value()))
+            .map(then)
+            .collect();
+        let truncated = rows.next().is_some();
+        Ok((found, truncated))
+    }
+
+    /// Read the next batch of this reader's rows, in order of their keys, starting after the key
+    /// `after`, or at the first key if it is `None`. Only the rows that pass `filter`, if given,
+    /// are read, and each of them is passed through `then`. The batch holds at least `limit` rows
+    /// if there are that many left, but always holds all the rows of each of its keys, so it may
+    /// hold more. Along with the batch, the last key in it is returned
+    /// if there may be more keys after it, which is where the next batch should start.
+    ///
+    /// Each batch is read from a single version of the reader's state, but different batches may
+    /// be read from different versions, since writes are made visible while the scan goes on.
+    /// Since every key is only visited by one batch, a row that is in the reader for the whole of
+    /// a scan is returned by it exactly once. Rows that are added or removed during the scan may
+    /// or may not be returned.
+    ///
+    /// Every batch goes over all of the reader's keys to find the ones that come next, so a scan
+    /// costs time quadratic in the size of the reader over the size of the batches. Readers must
+    /// be set up to allow scans (see `allow_scans`), or `Err(())` is returned. A reader that isn't
+    /// ready yet is returned as `Ok(None)`.
+    pub fn scan_and<F, T>(
+        &self,
+        after: Option<&[DataType]>,
+        limit: usize,
+        filter: Option<&RowFilter>,
+        mut then: F,
+    ) -> Result<Option<(Vec<T>, Option<Vec<DataType>>)>, ()>
+    where
+        F: FnMut(&[DataType]) -> T,
+    {
+        if !self.scannable {
+            return Err(());
+        }
+        // evmap only says whether the map is ready along with a lookup, for which any key will do
+        let probe = vec![DataType::None; self.key.len()];
+        if self.handle.meta_get_and(&probe, |_| ()).is_none() {
+            return Ok(None);
+        }
+
+        // the first keys after `after` that together have at least `limit` rows
+        let mut batch: BTreeMap<Vec<DataType>, Vec<Vec<DataType>>> = BTreeMap::new();
+        let mut rows = 0;
+        let mut more = false;
+        self.handle.for_each_keyed(|k, rs| {
+            if after.map(|after| k <= after).unwrap_or(false) {
+                return;
+            }
+            let n = rs.iter().filter(|r| passes(filter, r)).count();
+            if n == 0 {
+                return;
+            }
+            if rows >= limit && batch.keys().next_back().map(|l| k > &l[..]) == Some(true) {
+                more = true;
+                return;
+            }
+            rows += n;
+            let rs = rs.iter().filter(|r| passes(filter, r)).cloned().collect();
+            batch.insert(Vec::from(k), rs);
+            // drop the last key for as long as the batch is full without it
+            while batch.len() > 1 {
+                let (last, n) = {
+                    let (k, rs) = batch.iter().next_back().unwrap();
+                    (k.clone(), rs.len())
+                };
+                if rows - n < limit {
+                    break;
+                }
+                batch.remove(&last);
+                rows -= n;
+                more = true;
+            }
+        });
+
+        let next = if more {
+            batch.keys().next_back().cloned()
+        } else {
+            None
+        };
+        let found = batch
+            .values()
+            .flat_map(|rs| rs)
+            .map(|r| then(&r[..]))
+            .collect();
+        Ok(Some((found, next)))
+    }
+
     #[allow(dead_code)]
     pub fn len(&self) -> usize {
         self.handle.len()
     }
 
+    /// The number of keys in the reader that have at least one row that passes `filter`.
+    pub fn len_matching(&self, filter: &RowFilter) -> usize {
+        let mut n = 0;
+        self.handle.for_each_keyed(|_, rs| {
+            if rs.iter().any(|r| filter.matches(r)) {
+                n += 1;
+            }
+        });
+        n
+    }
+
     /// Count the number of rows in the reader.
     /// This is a potentially very costly operation, since it will
     /// hold up writers until all rows are iterated through.
@@ -428,17 +1276,17 @@ mod tests {
         w.swap();
 
         // after first swap, it is empty, but ready
-        assert_eq!(r.try_find_and(&a[0..1], |rs| rs.len()), Ok((Some(0), -1)));
+        assert_eq!(r.try_find_and(&a[0..1], |rs| rs.len()), Ok((Some(0), 0)));
 
         w.add(vec![Record::Positive(a.clone())]);
 
         // it is empty even after an add (we haven't swapped yet)
-        assert_eq!(r.try_find_and(&a[0..1], |rs| rs.len()), Ok((Some(0), -1)));
+        assert_eq!(r.try_find_and(&a[0..1], |rs| rs.len()), Ok((Some(0), 0)));
 
         w.swap();
 
         // but after the swap, the record is there!
-        assert_eq!(r.try_find_and(&a[0..1], |rs| rs.len()).unwrap().0, Some(1));
+        assert_eq!(r.try_find_and(&a[0..1], |rs| rs.len()), Ok((Some(1), 1)));
         assert!(
             r.try_find_and(&a[0..1], |rs| rs
                 .iter()
@@ -620,4 +1468,695 @@ mod tests {
                 .unwrap()
         );
     }
+
+    #[test]
+    fn range_works() {
+        fn names(
+            r: &SingleReadHandle,
+            lower: Bound<&DataType>,
+            upper: Bound<&DataType>,
+        ) -> Vec<String> {
+            let (names, truncated) = r
+                .find_range_and(lower, upper, None, None, |r| (&r[0]).into())
+                .unwrap();
+            assert!(!truncated);
+            names
+        }
+
+        let (r, mut w) = new_ordered(2, 1);
+        w.swap();
+        w.add(vec![
+            Record::Positive(vec!["a".into(), 3.into()]),
+            Record::Positive(vec!["b".into(), 1.into()]),
+            Record::Positive(vec!["c".into(), 2.into()]),
+            Record::Positive(vec!["d".into(), 2.into()]),
+        ]);
+
+        // nothing is visible until the writer swaps
+        assert!(names(&r, Bound::Unbounded, Bound::Unbounded).is_empty());
+        w.swap();
+
+        let (one, two, three) = (DataType::from(1), DataType::from(2), DataType::from(3));
+        assert_eq!(
+            names(&r, Bound::Unbounded, Bound::Unbounded),
+            vec!["b", "c", "d", "a"]
+        );
+        assert_eq!(
+            names(&r, Bound::Excluded(&one), Bound::Included(&two)),
+            vec!["c", "d"]
+        );
+        assert_eq!(
+            names(&r, Bound::Included(&one), Bound::Excluded(&three)),
+            vec!["b", "c", "d"]
+        );
+        assert_eq!(
+            names(&r, Bound::Included(&three), Bound::Unbounded),
+            vec!["a"]
+        );
+        assert!(names(&r, Bound::Included(&two), Bound::Excluded(&one)).is_empty());
+        assert!(names(&r, Bound::Excluded(&two), Bound::Excluded(&two)).is_empty());
+        assert!(names(&r, Bound::Excluded(&three), Bound::Unbounded).is_empty());
+
+        // removals are applied to the ordered rows too
+        w.add(vec![Record::Negative(vec!["c".into(), 2.into()])]);
+        w.swap();
+        assert_eq!(
+            names(&r, Bound::Included(&two), Bound::Included(&two)),
+            vec!["d"]
+        );
+
+        // readers without an ordered index can't do range lookups
+        let (r, _) = new(2, &[1]);
+        assert_eq!(
+            r.find_range_and(Bound::Unbounded, Bound::Unbounded, None, None, |_| ()),
+            Err(())
+        );
+    }
+
+    #[test]
+    fn range_limit_works() {
+        let (r, mut w) = new_ordered(1, 0);
+        w.add((0..10).map(|i| Record::Positive(vec![i.into()])));
+        w.swap();
+
+        let range = |lower: Bound<&DataType>, limit| -> (Vec<i64>, bool) {
+            r.find_range_and(lower, Bound::Unbounded, Some(limit), None, |r| (&r[0]).into())
+                .unwrap()
+        };
+        let five = DataType::from(5);
+        assert_eq!(range(Bound::Unbounded, 3), (vec![0, 1, 2], true));
+        assert_eq!(range(Bound::Excluded(&five), 3), (vec![6, 7, 8], true));
+        assert_eq!(range(Bound::Excluded(&five), 4), (vec![6, 7, 8, 9], false));
+        assert_eq!(range(Bound::Excluded(&five), 10), (vec![6, 7, 8, 9], false));
+        assert_eq!(range(Bound::Unbounded, 0), (vec![], true));
+    }
+
+    #[test]
+    fn range_is_consistent_with_concurrent_writes() {
+        use std::thread;
+
+        // every swap moves the last row in the range to its start, so a consistent read always
+        // sees exactly `n` rows, in increasing order
+        let n = 100;
+        let (r, mut w) = new_ordered(1, 0);
+        w.add((0..n).map(|i| Record::Positive(vec![i.into()])));
+        w.swap();
+        let writer = thread::spawn(move || {
+            for i in 0..n {
+                w.add(vec![
+                    Record::Negative(vec![(n - 1 - i).into()]),
+                    Record::Positive(vec![(-1 - i).into()]),
+                ]);
+                w.swap();
+            }
+        });
+
+        for _ in 0..1000 {
+            let (keys, truncated) = r
+                .find_range_and(Bound::Unbounded, Bound::Unbounded, None, None, |r| -> i64 {
+                    (&r[0]).into()
+                })
+                .unwrap();
+            assert!(!truncated);
+            assert_eq!(keys.len(), n as usize);
+            assert!(keys.windows(2).all(|w| w[0] < w[1]));
+        }
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn page_works() {
+        let row = |id: i64, score: i64| vec![DataType::from(1), id.into(), score.into()];
+        let (r, mut w) = new(3, &[0]);
+        w.add(vec![
+            Record::Positive(row(4, 20)),
+            Record::Positive(row(1, 30)),
+            Record::Positive(row(3, 10)),
+            Record::Positive(row(2, 20)),
+        ]);
+        w.swap();
+
+        let page = |order_by, offset, limit| -> (Vec<i64>, usize) {
+            r.try_find_page_and(&[1.into()], None, order_by, offset, limit, |r| (&r[1]).into())
+                .unwrap()
+                .unwrap()
+        };
+        let by_score = |direction| (2, direction);
+
+        // rows with the same score are ordered by the rest of their values
+        assert_eq!(page(by_score(Direction::Ascending), 0, 2), (vec![3, 2], 4));
+        assert_eq!(page(by_score(Direction::Ascending), 2, 2), (vec![4, 1], 4));
+        assert_eq!(page(by_score(Direction::Descending), 0, 3), (vec![1, 4, 2], 4));
+        assert_eq!(page(by_score(Direction::Descending), 3, 3), (vec![3], 4));
+        assert_eq!(page(by_score(Direction::Descending), 4, 3), (vec![], 4));
+
+        // keys without rows have empty pages
+        assert_eq!(
+            r.try_find_page_and(&[2.into()], None, by_score(Direction::Ascending), 0, 2, |_| ()),
+            Ok(Some((vec![], 0)))
+        );
+    }
+
+    #[test]
+    fn sorted_pages_match_unsorted() {
+        let (r, mut w) = new(3, &[0]);
+        let (mut sr, mut sw) = new(3, &[0]);
+        keep_sorted(&mut sr, &mut sw, 2);
+        assert_eq!(
+            sr.try_find_page_and(&[1.into()], None, (2, Direction::Ascending), 0, 10, |_| ()),
+            Err(())
+        );
+
+        let rs: Vec<_> = (0..100)
+            .map(|i: i64| Record::Positive(vec![(i % 2).into(), i.into(), (i % 7).into()]))
+            .collect();
+        w.add(rs.clone());
+        w.swap();
+        sw.add(rs);
+        sw.swap();
+
+        let rs: Vec<_> = (0..50)
+            .map(|i: i64| Record::Negative(vec![(i % 2).into(), i.into(), (i % 7).into()]))
+            .collect();
+        w.add(rs.clone());
+        w.swap();
+        sw.add(rs);
+        sw.swap();
+
+        for &key in &[0, 1, 2] {
+            for &order_by in &[
+                (2, Direction::Ascending),
+                (2, Direction::Descending),
+                (1, Direction::Descending),
+            ] {
+                for &(offset, limit) in &[(0, 10), (5, 10), (20, 10), (0, 100)] {
+                    let page = |r: &SingleReadHandle| {
+                        let key = [key.into()];
+                        r.try_find_page_and(&key, None, order_by, offset, limit, |r| r.to_vec())
+                            .unwrap()
+                            .unwrap()
+                    };
+                    assert_eq!(page(&sr), page(&r));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn compact_keeps_net_changes() {
+        let a = || vec![DataType::from(1), "a".into()];
+        let b = || vec![DataType::from(1), "b".into()];
+        let c = || vec![DataType::from(2), "c".into()];
+
+        // a chain of updates to one key leaves only its first and last rows
+        assert_eq!(
+            compact(vec![
+                Record::Negative(a()),
+                Record::Positive(b()),
+                Record::Negative(b()),
+                Record::Positive(c()),
+            ]),
+            vec![Record::Negative(a()), Record::Positive(c())]
+        );
+
+        // multiplicity is preserved
+        assert_eq!(
+            compact(vec![
+                Record::Positive(a()),
+                Record::Positive(a()),
+                Record::Negative(a()),
+            ]),
+            vec![Record::Positive(a())]
+        );
+        assert_eq!(
+            compact(vec![
+                Record::Negative(a()),
+                Record::Positive(a()),
+                Record::Negative(a()),
+                Record::Negative(a()),
+            ]),
+            vec![Record::Negative(a()), Record::Negative(a())]
+        );
+        assert_eq!(
+            compact(vec![Record::Positive(a()), Record::Negative(a())]),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn add_applies_only_net_changes() {
+        let a = vec![1.into(), "a".into()];
+        let b = vec![1.into(), "b".into()];
+        let (r, mut w) = new(2, &[0]);
+        w.add(vec![Record::Positive(a.clone())]);
+        w.swap();
+        let size = w.deep_size_of();
+
+        w.add(vec![
+            Record::Negative(a.clone()),
+            Record::Positive(b.clone()),
+            Record::Negative(b.clone()),
+            Record::Positive(a.clone()),
+            Record::Positive(b.clone()),
+            Record::Positive(b.clone()),
+            Record::Negative(b.clone()),
+        ]);
+        w.swap();
+        assert_eq!(
+            r.try_find_and(&a[0..1], |rs| rs.to_vec()).unwrap().0,
+            Some(vec![a.clone(), b.clone()])
+        );
+        assert_eq!(w.deep_size_of(), size + b.deep_size_of());
+    }
+
+    #[test]
+    fn add_keeps_a_copy_of_every_identical_row() {
+        let a = vec![1.into(), "a".into()];
+        let (r, mut w) = new(2, &[0]);
+        w.swap();
+        let size = w.deep_size_of();
+        let copies = |r: &SingleReadHandle| {
+            r.try_find_and(&a[0..1], |rs| rs.iter().filter(|row| **row == a).count())
+                .unwrap()
+                .0
+                .unwrap_or(0)
+        };
+
+        w.add(vec![Record::Positive(a.clone()), Record::Positive(a.clone())]);
+        w.swap();
+        assert_eq!(copies(&r), 2);
+        assert_eq!(w.deep_size_of(), size + 2 * a.deep_size_of());
+
+        // a negative only takes away one of them
+        assert!(w.add(vec![Record::Negative(a.clone())]).is_empty());
+        w.swap();
+        assert_eq!(copies(&r), 1);
+        assert_eq!(w.deep_size_of(), size + a.deep_size_of());
+
+        // even if the copies it counts on haven't been swapped in yet
+        w.add(vec![Record::Positive(a.clone()), Record::Positive(a.clone())]);
+        assert!(w.add(vec![Record::Negative(a.clone())]).is_empty());
+        w.swap();
+        assert_eq!(copies(&r), 2);
+
+        // and there is no going below zero
+        let gone = w.add(vec![
+            Record::Negative(a.clone()),
+            Record::Negative(a.clone()),
+            Record::Negative(a.clone()),
+        ]);
+        assert_eq!(gone, vec![Record::Negative(a.clone())]);
+        w.swap();
+        assert_eq!(copies(&r), 0);
+        assert_eq!(w.deep_size_of(), size);
+        w.add(vec![Record::Positive(a.clone())]);
+        w.swap();
+        assert_eq!(copies(&r), 1);
+    }
+
+    #[test]
+    fn add_ignores_negatives_for_holes() {
+        let a = vec![1.into(), "a".into()];
+        let (r, mut w) = new_partial(2, &[0], |_| ());
+        w.swap();
+        assert!(w.add(vec![Record::Negative(a.clone())]).is_empty());
+        w.swap();
+        assert_eq!(r.try_find_and(&a[0..1], |rs| rs.len()).unwrap().0, None);
+
+        // but once the key is filled, its rows are counted like any other
+        w.mut_with_key(&a[0..1]).mark_filled();
+        w.swap();
+        assert_eq!(
+            w.add(vec![Record::Negative(a.clone())]),
+            vec![Record::Negative(a.clone())]
+        );
+    }
+
+    #[test]
+    fn reader_holds_net_multiset_of_random_interleavings() {
+        use rand::rngs::StdRng;
+        use rand::SeedableRng;
+
+        // few keys and values, so that the same rows come up over and over again
+        let row = |k: i64, v: i64| vec![DataType::from(k), DataType::from(v)];
+        let mut rng = StdRng::from_seed([7; 32]);
+        let (r, mut w) = new(2, &[0]);
+        let mut net: HashMap<Vec<DataType>, usize> = HashMap::new();
+        for round in 0..200 {
+            let batch: Vec<Record> = (0..rng.gen_range(1, 20))
+                .map(|_| {
+                    let x = row(rng.gen_range(0, 3), rng.gen_range(0, 3));
+                    if rng.gen_range(0, 3) == 0 {
+                        Record::Negative(x)
+                    } else {
+                        Record::Positive(x)
+                    }
+                })
+                .collect();
+
+            // the batch as a whole is applied to what is there, and negatives that would go below
+            // zero are handed back
+            let mut change: HashMap<Vec<DataType>, isize> = HashMap::new();
+            for x in &batch {
+                *change.entry(x.to_vec()).or_insert(0) += if x.is_positive() { 1 } else { -1 };
+            }
+            let mut expect_gone = 0;
+            for (x, change) in change {
+                let n = net.entry(x).or_insert(0);
+                let after = *n as isize + change;
+                if after < 0 {
+                    expect_gone += -after as usize;
+                }
+                *n = cmp::max(after, 0) as usize;
+            }
+            let gone = w.add(batch);
+            assert_eq!(gone.len(), expect_gone, "in round {}", round);
+            assert!(gone.iter().all(|x| !x.is_positive()));
+
+            // and lookups see every copy that is left once it is swapped in
+            if rng.gen() {
+                w.swap();
+                for k in 0..3 {
+                    let mut found = r
+                        .try_find_and(&[k.into()], |rs| rs.to_vec())
+                        .unwrap()
+                        .0
+                        .unwrap_or_else(Vec::new);
+                    found.sort();
+                    let mut expected: Vec<_> = net
+                        .iter()
+                        .filter(|&(x, _)| x[0] == DataType::from(k))
+                        .flat_map(|(x, &n)| iter::repeat(x.clone()).take(n))
+                        .collect();
+                    expected.sort();
+                    assert_eq!(found, expected, "in round {}", round);
+                }
+                assert_eq!(w.rows(), net.values().sum::<usize>());
+            }
+        }
+    }
+
+    #[test]
+    fn full_replay_preserves_identical_rows() {
+        let a = vec![1.into(), "a".into()];
+        let b = vec![1.into(), "b".into()];
+        let mut state = MemoryState::default();
+        state.add_key(&[0], None);
+        let mut rs: Records = vec![
+            (a.clone(), true),
+            (a.clone(), true),
+            (a.clone(), true),
+            (b.clone(), true),
+            (a.clone(), false),
+        ]
+        .into();
+        state.process_records(&mut rs, None);
+
+        // a full replay sends a copy of every row in the state, duplicates and all
+        let (r, mut w) = new(2, &[0]);
+        w.add(state.cloned_records().into_iter().map(Record::Positive));
+        w.swap();
+        let mut found = r
+            .try_find_and(&a[0..1], |rs| rs.to_vec())
+            .unwrap()
+            .0
+            .unwrap();
+        found.sort();
+        assert_eq!(found, vec![a.clone(), a.clone(), b.clone()]);
+    }
+
+    #[test]
+    fn written_is_published_with_swap() {
+        use std::time::Duration;
+
+        let a = vec![1.into(), "a".into()];
+        let (r, mut w) = new(2, &[0]);
+        w.swap();
+        let meta = |r: &SingleReadHandle| r.try_find_with_meta_and(&a[0..1], |_| ()).unwrap().1;
+        assert_eq!(meta(&r).written, None);
+        assert!(meta(&r).full);
+
+        let t1 = SystemTime::now();
+        let t0 = t1 - Duration::from_secs(1);
+        w.add(vec![Record::Positive(a.clone())]);
+        w.applied_write(t1);
+        assert_eq!(meta(&r).written, None);
+        w.swap();
+        assert_eq!(meta(&r).written, Some(t1));
+
+        // writes that were accepted earlier but arrive later don't move it back
+        w.applied_write(t0);
+        w.swap();
+        assert_eq!(meta(&r).written, Some(t1));
+
+        let (r, mut w) = new_partial(2, &[0], |_| ());
+        w.swap();
+        w.mut_with_key(&a[0..1]).mark_filled();
+        w.swap();
+        let (_, meta) = r.try_find_many_with_meta_and(&[a[0..1].to_vec()], |_| ()).unwrap();
+        assert!(!meta.full);
+    }
+
+    #[test]
+    fn find_many_works() {
+        let key = |i: i64| vec![DataType::from(i)];
+        let (r, mut w) = new_partial(2, &[0], |_| ());
+        w.swap();
+
+        w.mut_with_key(&key(1)[..]).mark_filled();
+        w.mut_with_key(&key(2)[..]).mark_filled();
+        w.add(vec![Record::Positive(vec![1.into(), "a".into()])]);
+        w.swap();
+
+        // results line up with the keys, and holes are reported as such
+        let keys = vec![key(3), key(1), key(2), key(1)];
+        assert_eq!(
+            r.try_find_many_and(&keys, |rs| rs.len()),
+            Ok(vec![None, Some(1), Some(0), Some(1)])
+        );
+        assert_eq!(r.try_find_many_and(&[], |rs| rs.len()), Ok(vec![]));
+    }
+
+    #[test]
+    fn find_many_is_consistent_with_concurrent_writes() {
+        use std::thread;
+
+        // every swap bumps the version stored under both keys, so a consistent read always sees
+        // the same version for the two
+        let n = 1000;
+        let (r, mut w) = new(2, &[0]);
+        w.add(vec![
+            Record::Positive(vec![0.into(), 0.into()]),
+            Record::Positive(vec![1.into(), 0.into()]),
+        ]);
+        w.swap();
+        let writer = thread::spawn(move || {
+            for i in 1..n {
+                w.add(vec![
+                    Record::Negative(vec![0.into(), (i - 1).into()]),
+                    Record::Negative(vec![1.into(), (i - 1).into()]),
+                    Record::Positive(vec![0.into(), i.into()]),
+                    Record::Positive(vec![1.into(), i.into()]),
+                ]);
+                w.swap();
+            }
+        });
+
+        let keys = vec![vec![0.into()], vec![1.into()]];
+        for _ in 0..1000 {
+            let versions = r
+                .try_find_many_and(&keys, |rs| -> i64 {
+                    assert_eq!(rs.len(), 1);
+                    (&rs[0][1]).into()
+                })
+                .unwrap();
+            assert_eq!(versions[0], versions[1]);
+        }
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn subscribers_get_deltas_to_their_key() {
+        let a = vec![1.into(), "a".into()];
+        let b = vec![2.into(), "b".into()];
+        let (r, mut w) = new(2, &[0]);
+        w.swap();
+        assert!(!w.has_subscribers());
+
+        let sub = r.subscribe(&a[0..1], 8);
+        assert!(w.has_subscribers());
+        let rs = vec![Record::Positive(a.clone()), Record::Positive(b.clone())];
+        w.publish(&rs);
+        w.add(rs);
+
+        // nothing is delivered until the changes are visible
+        assert_eq!(sub.try_recv(), Ok(None));
+        w.swap();
+        assert_eq!(sub.recv(), Ok(vec![Record::Positive(a.clone())]));
+        assert_eq!(sub.try_recv(), Ok(None));
+
+        let rs = vec![Record::Negative(a.clone())];
+        w.publish(&rs);
+        w.add(rs);
+        w.swap();
+        assert_eq!(sub.recv(), Ok(vec![Record::Negative(a.clone())]));
+
+        // dropping the subscription unsubscribes
+        drop(sub);
+        assert!(!w.has_subscribers());
+
+        // and the subscription ends when the reader goes away
+        let sub = r.subscribe(&b[0..1], 8);
+        drop(w);
+        assert_eq!(sub.recv(), Err(SubscriptionError::Closed));
+    }
+
+    #[test]
+    fn lagging_subscribers_are_cut_off() {
+        let a = vec![1.into(), "a".into()];
+        let (r, mut w) = new(2, &[0]);
+        w.swap();
+
+        let sub = r.subscribe(&a[0..1], 1);
+        for _ in 0..2 {
+            let rs = vec![Record::Positive(a.clone())];
+            w.publish(&rs);
+            w.add(rs);
+            w.swap();
+        }
+        assert!(!w.has_subscribers());
+
+        // whatever made it into the buffer is still delivered
+        assert_eq!(sub.recv(), Ok(vec![Record::Positive(a.clone())]));
+        assert_eq!(sub.recv(), Err(SubscriptionError::Lagged));
+    }
+
+    #[test]
+    fn lru_eviction_keeps_hot_keys() {
+        let key = |i: i32| vec![DataType::from(i)];
+        let (mut r, mut w) = new_partial(2, &[0], |_| ());
+        track_reads(&mut r, &mut w);
+        w.swap();
+
+        for i in 0..10 {
+            w.mut_with_key(&key(i)[..]).mark_filled();
+            w.add(vec![Record::Positive(vec![i.into(), "x".into()])]);
+        }
+        w.swap();
+
+        // every key is read, but keys 3 and 7 are read again afterwards
+        for i in (0..10).chain(vec![3, 7]) {
+            assert_eq!(r.try_find_and(&key(i), |rs| rs.len()).unwrap().0, Some(1));
+        }
+
+        let size = w.deep_size_of();
+        let (evicted, freed) = w.evict_lru_keys(size * 7 / 10);
+        w.swap();
+
+        assert_eq!(evicted.len(), 7);
+        assert!(freed >= size * 7 / 10);
+        assert_eq!(w.deep_size_of(), size - freed);
+        assert!(!evicted.contains(&key(3)));
+        assert!(!evicted.contains(&key(7)));
+        for k in evicted {
+            assert_eq!(r.try_find_and(&k, |rs| rs.len()).unwrap().0, None);
+        }
+        assert_eq!(r.try_find_and(&key(3), |rs| rs.len()).unwrap().0, Some(1));
+        assert_eq!(r.try_find_and(&key(7), |rs| rs.len()).unwrap().0, Some(1));
+
+        // evicting a key that is already a hole frees nothing
+        assert_eq!(w.evict_key(&key(0)), None);
+    }
+
+    #[test]
+    fn scan_returns_stable_rows_once() {
+        let (mut r, mut w) = new(2, &[0]);
+        assert_eq!(r.scan_and(None, 10, None, |r| r.to_vec()), Err(()));
+        allow_scans(&mut r);
+        assert_eq!(r.scan_and(None, 10, None, |r| r.to_vec()), Ok(None));
+
+        // key 1 has two rows, so the first batch holds three of them
+        let row = |k: i32, v: i32| vec![DataType::from(k), DataType::from(v)];
+        w.add((0..10).map(|i| Record::Positive(row(i, 0))));
+        w.add(vec![Record::Positive(row(1, 1))]);
+        w.swap();
+
+        let (first, next) = r.scan_and(None, 2, None, |r| r.to_vec()).unwrap().unwrap();
+        assert_eq!(first, vec![row(0, 0), row(1, 0), row(1, 1)]);
+        assert_eq!(next, Some(vec![1.into()]));
+
+        // keys are added and removed on both sides of where the scan is at
+        w.add(vec![
+            Record::Negative(row(0, 0)),
+            Record::Negative(row(5, 0)),
+            Record::Positive(row(11, 0)),
+        ]);
+        w.swap();
+
+        let mut rest = Vec::new();
+        let mut after = next;
+        while let Some(key) = after {
+            let (batch, next) = r
+                .scan_and(Some(&key[..]), 3, None, |r| r.to_vec())
+                .unwrap()
+                .unwrap();
+            assert!(batch.len() >= 3 || next.is_none());
+            rest.extend(batch);
+            after = next;
+        }
+        let expected: Vec<_> = (2..10)
+            .filter(|&i| i != 5)
+            .chain(Some(11))
+            .map(|i| row(i, 0))
+            .collect();
+        assert_eq!(rest, expected);
+    }
+
+    #[test]
+    fn filtered_reads_only_see_passing_rows() {
+        // rows are (id, tenant), and only tenant 1's rows, which have even ids, pass
+        let row = |id: i64| vec![DataType::from(id), DataType::from(id % 2)];
+        let tenant = RowFilter::equal(1, 0);
+        let (r, mut w) = new_ordered(2, 0);
+        w.add((0..10).map(|i| Record::Positive(row(i))));
+        w.swap();
+        let (mut s, mut sw) = new(2, &[1]);
+        allow_scans(&mut s);
+        sw.add((0..10).map(|i| Record::Positive(row(i))));
+        sw.swap();
+
+        let ids = |rs: Vec<Vec<DataType>>| -> Vec<i64> {
+            rs.iter().map(|r| (&r[0]).into()).collect()
+        };
+        let (found, more) = r
+            .find_range_and(Bound::Unbounded, Bound::Unbounded, Some(3), Some(&tenant), |r| {
+                r.to_vec()
+            })
+            .unwrap();
+        assert_eq!((ids(found), more), (vec![0, 2, 4], true));
+
+        let (page, total) = s
+            .try_find_page_and(&[0.into()], Some(&tenant), (0, Direction::Descending), 1, 2, |r| {
+                r.to_vec()
+            })
+            .unwrap()
+            .unwrap();
+        assert_eq!((ids(page), total), (vec![6, 4], 5));
+        let (page, total) = s
+            .try_find_page_and(&[1.into()], Some(&tenant), (0, Direction::Ascending), 0, 2, |r| {
+                r.to_vec()
+            })
+            .unwrap()
+            .unwrap();
+        assert_eq!((page, total), (vec![], 0));
+
+        // keys without passing rows are skipped, and don't count towards a batch
+        let (batch, next) = s
+            .scan_and(None, 1, Some(&tenant), |r| r.to_vec())
+            .unwrap()
+            .unwrap();
+        assert_eq!((ids(batch), next), (vec![0, 2, 4, 6, 8], None));
+        assert_eq!(s.len(), 2);
+        assert_eq!(s.len_matching(&tenant), 1);
+    }
 }
@@ -1,12 +1,14 @@
+use super::Version;
 use common::DataType;
 use evmap;
 use fnv::FnvBuildHasher;
+use std::slice;
 
 #[derive(Clone)]
 pub(super) enum Handle {
-    Single(evmap::ReadHandle<DataType, Vec<DataType>, i64, FnvBuildHasher>),
-    Double(evmap::ReadHandle<(DataType, DataType), Vec<DataType>, i64, FnvBuildHasher>),
-    Many(evmap::ReadHandle<Vec<DataType>, Vec<DataType>, i64, FnvBuildHasher>),
+    Single(evmap::ReadHandle<DataType, Vec<DataType>, Version, FnvBuildHasher>),
+    Double(evmap::ReadHandle<(DataType, DataType), Vec<DataType>, Version, FnvBuildHasher>),
+    Many(evmap::ReadHandle<Vec<DataType>, Vec<DataType>, Version, FnvBuildHasher>),
 }
 
 impl Handle {
@@ -29,7 +31,19 @@ impl Handle {
         }
     }
 
-    pub fn meta_get_and<F, T>(&self, key: &[DataType], then: F) -> Option<(Option<T>, i64)>
+    /// Like `for_each`, but also hands `f` the key of each set of rows.
+    pub fn for_each_keyed<F>(&self, mut f: F)
+    where
+        F: FnMut(&[DataType], &[Vec<DataType>]),
+    {
+        match *self {
+            Handle::Single(ref h) => h.for_each(|k, v| f(slice::from_ref(k), v)),
+            Handle::Double(ref h) => h.for_each(|k, v| f(&[k.0.clone(), k.1.clone()], v)),
+            Handle::Many(ref h) => h.for_each(|k, v| f(&k[..], v)),
+        }
+    }
+
+    pub fn meta_get_and<F, T>(&self, key: &[DataType], then: F) -> Option<(Option<T>, Version)>
     where
         F: FnOnce(&[Vec<DataType>]) -> T,
     {
@@ -1,12 +1,12 @@
-use super::{key_to_double, key_to_single, Key};
+use super::{key_to_double, key_to_single, Key, Version};
 use evmap;
 use fnv::FnvBuildHasher;
 use prelude::*;
 
 pub(super) enum Handle {
-    Single(evmap::WriteHandle<DataType, Vec<DataType>, i64, FnvBuildHasher>),
-    Double(evmap::WriteHandle<(DataType, DataType), Vec<DataType>, i64, FnvBuildHasher>),
-    Many(evmap::WriteHandle<Vec<DataType>, Vec<DataType>, i64, FnvBuildHasher>),
+    Single(evmap::WriteHandle<DataType, Vec<DataType>, Version, FnvBuildHasher>),
+    Double(evmap::WriteHandle<(DataType, DataType), Vec<DataType>, Version, FnvBuildHasher>),
+    Many(evmap::WriteHandle<Vec<DataType>, Vec<DataType>, Version, FnvBuildHasher>),
 }
 
 impl Handle {
@@ -18,6 +18,17 @@ impl Handle {
         }
     }
 
+    pub fn for_each<F>(&self, mut f: F)
+    where
+        F: FnMut(&[Vec<DataType>]),
+    {
+        match *self {
+            Handle::Single(ref h) => h.for_each(|_, v| f(v)),
+            Handle::Double(ref h) => h.for_each(|_, v| f(v)),
+            Handle::Many(ref h) => h.for_each(|_, v| f(v)),
+        }
+    }
+
     pub fn clear(&mut self, k: Key) {
         match *self {
             Handle::Single(ref mut h) => h.clear(key_to_single(k).into_owned()),
@@ -52,7 +63,21 @@ impl Handle {
         }
     }
 
-    pub fn meta_get_and<F, T>(&self, key: Key, then: F) -> Option<(Option<T>, i64)>
+    pub fn set_meta(&mut self, meta: Version) {
+        match *self {
+            Handle::Single(ref mut h) => {
+                h.set_meta(meta);
+            }
+            Handle::Double(ref mut h) => {
+                h.set_meta(meta);
+            }
+            Handle::Many(ref mut h) => {
+                h.set_meta(meta);
+            }
+        }
+    }
+
+    pub fn meta_get_and<F, T>(&self, key: Key, then: F) -> Option<(Option<T>, Version)>
     where
         F: FnOnce(&[Vec<DataType>]) -> T,
     {
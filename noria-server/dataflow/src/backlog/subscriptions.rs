@@ -0,0 +1,207 @@
+use noria::filter::RowFilter;
+use prelude::*;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, TryRecvError, TrySendError};
+use std::sync::{Arc, Mutex};
+
+/// Deltas to subscribed keys, by key.
+pub(super) type Deltas = HashMap<Vec<DataType>, Vec<Record>>;
+
+/// The subscriptions to the keys of a single reader, shared between its read and write handles.
+#[derive(Default)]
+pub(super) struct Subscribers {
+    // number of live subscriptions, so that writers can tell that there are none without taking
+    // the lock. only changed while holding the lock.
+    count: AtomicUsize,
+    next_id: AtomicUsize,
+    by_key: Mutex<HashMap<Vec<DataType>, Vec<Subscriber>>>,
+}
+
+struct Subscriber {
+    id: usize,
+    tx: mpsc::SyncSender<Vec<Record>>,
+    lagged: Arc<AtomicBool>,
+    // the rows that the subscriber may hear about, if not all of them
+    filter: Option<RowFilter>,
+}
+
+impl Subscribers {
+    pub(super) fn is_empty(&self) -> bool {
+        self.count.load(Ordering::Acquire) == 0
+    }
+
+    pub(super) fn subscribe(
+        subscribers: &Arc<Self>,
+        key: Vec<DataType>,
+        buffer: usize,
+        filter: Option<RowFilter>,
+    ) -> Subscription {
+        let (tx, rx) = mpsc::sync_channel(buffer);
+        let lagged = Arc::new(AtomicBool::new(false));
+        let id = subscribers.next_id.fetch_add(1, Ordering::Relaxed);
+
+        let mut by_key = subscribers.by_key.lock().unwrap();
+        by_key
+            .entry(key.clone())
+            .or_insert_with(Vec::new)
+            .push(Subscriber {
+                id,
+                tx,
+                lagged: lagged.clone(),
+                filter,
+            });
+        subscribers.count.fetch_add(1, Ordering::Release);
+
+        Subscription {
+            key,
+            id,
+            rx,
+            lagged,
+            subscribers: Arc::clone(subscribers),
+        }
+    }
+
+    /// Set aside those of the given records whose key (as given by `key_of`) someone is
+    /// subscribed to.
+    pub(super) fn select<'a, I, F>(&self, rs: I, key_of: F, into: &mut Deltas)
+    where
+        I: IntoIterator<Item = &'a Record>,
+        F: Fn(&'a [DataType]) -> Cow<'a, [DataType]>,
+    {
+        let by_key = self.by_key.lock().unwrap();
+        for r in rs {
+            let key = key_of(&r[..]);
+            if by_key.contains_key(&*key) {
+                into.entry(key.into_owned())
+                    .or_insert_with(Vec::new)
+                    .push(r.clone());
+            }
+        }
+    }
+
+    /// Send every subscriber the deltas to its key, or those of them that pass its filter, if it
+    /// has one. Subscribers whose buffers are full are cut off, and told that they lagged behind.
+    pub(super) fn publish(&self, deltas: Deltas) {
+        let mut by_key = self.by_key.lock().unwrap();
+        for (key, deltas) in deltas {
+            let now_empty = match by_key.get_mut(&key) {
+                Some(subs) => {
+                    let before = subs.len();
+                    subs.retain(|s| {
+                        let deltas: Vec<_> = match s.filter {
+                            Some(ref f) => deltas
+                                .iter()
+                                .filter(|r| f.matches(&r[..]))
+                                .cloned()
+                                .collect(),
+                            None => deltas.clone(),
+                        };
+                        if deltas.is_empty() {
+                            return true;
+                        }
+                        match s.tx.try_send(deltas) {
+                            Ok(()) => true,
+                            Err(TrySendError::Full(_)) => {
+                                s.lagged.store(true, Ordering::Release);
+                                false
+                            }
+                            Err(TrySendError::Disconnected(_)) => false,
+                        }
+                    });
+                    self.count.fetch_sub(before - subs.len(), Ordering::Release);
+                    subs.is_empty()
+                }
+                None => false,
+            };
+            if now_empty {
+                by_key.remove(&key);
+            }
+        }
+    }
+
+    /// End all subscriptions, as the reader is going away.
+    pub(super) fn close(&self) {
+        let mut by_key = self.by_key.lock().unwrap();
+        by_key.clear();
+        self.count.store(0, Ordering::Release);
+    }
+
+    fn unsubscribe(&self, key: &[DataType], id: usize) {
+        let mut by_key = self.by_key.lock().unwrap();
+        let now_empty = match by_key.get_mut(key) {
+            Some(subs) => {
+                if let Some(i) = subs.iter().position(|s| s.id == id) {
+                    subs.swap_remove(i);
+                    self.count.fetch_sub(1, Ordering::Release);
+                }
+                subs.is_empty()
+            }
+            None => false,
+        };
+        if now_empty {
+            by_key.remove(key);
+        }
+    }
+}
+
+/// A failure to receive from a `Subscription`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SubscriptionError {
+    /// The subscriber fell further behind than its buffer allowed for, and was cut off. Deltas
+    /// that were buffered before that happened have all been received.
+    Lagged,
+    /// The reader has gone away.
+    Closed,
+}
+
+/// A subscription to the changes made to a single key of a reader.
+///
+/// Each batch of changes that the reader applies to the key is delivered as the deltas (i.e.,
+/// positive and negative records) it consists of, once the changes are visible to reads. Rows
+/// replayed into a reader to fill a hole in partial state are not changes, and are not delivered.
+///
+/// The subscription ends when this is dropped.
+pub struct Subscription {
+    key: Vec<DataType>,
+    id: usize,
+    rx: mpsc::Receiver<Vec<Record>>,
+    lagged: Arc<AtomicBool>,
+    subscribers: Arc<Subscribers>,
+}
+
+impl Subscription {
+    /// The key this subscription is for.
+    pub fn key(&self) -> &[DataType] {
+        &self.key[..]
+    }
+
+    /// Wait for the next batch of deltas to the key.
+    pub fn recv(&self) -> Result<Vec<Record>, SubscriptionError> {
+        self.rx.recv().map_err(|_| self.ended())
+    }
+
+    /// Receive the next batch of deltas to the key, or `None` if there isn't one yet.
+    pub fn try_recv(&self) -> Result<Option<Vec<Record>>, SubscriptionError> {
+        match self.rx.try_recv() {
+            Ok(deltas) => Ok(Some(deltas)),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(self.ended()),
+        }
+    }
+
+    fn ended(&self) -> SubscriptionError {
+        if self.lagged.load(Ordering::Acquire) {
+            SubscriptionError::Lagged
+        } else {
+            SubscriptionError::Closed
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.subscribers.unsubscribe(&self.key[..], self.id);
+    }
+}
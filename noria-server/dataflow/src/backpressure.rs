@@ -0,0 +1,148 @@
+//! Whether domains are keeping up with the packets sent to them.
+//!
+//! Packets between domains in the same worker go through unbounded channels, so a domain that
+//! can't keep up lets packets pile up in memory without anything upstream noticing. To prevent
+//! that, every shard of a domain has a `Gauge` that says whether it is overloaded, which the task
+//! that runs the domain updates from the number of packets that are waiting for the domain (see
+//! `Backpressure`). A domain's gauge also points to the gauges of the domains it sends to, so that
+//! the domain of a base can tell whether any domain its writes flow to is overloaded before it
+//! admits another write.
+//!
+//! The gauges of all the domains of a worker are kept in the worker's `Gauges`, which is where a
+//! domain finds the gauges of the domains it sends to, including those that have yet to boot.
+
+use fnv::FnvHashMap;
+use prelude::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use Backpressure;
+
+/// Whether a shard of a domain is overloaded, along with the gauges of the domains it sends to.
+#[derive(Default)]
+pub struct Gauge {
+    overloaded: AtomicBool,
+    below: RwLock<Vec<Arc<Gauge>>>,
+}
+
+impl Gauge {
+    /// Update whether the domain is overloaded, given how many packets are waiting for it.
+    ///
+    /// Returns whether that changed.
+    pub fn update(&self, waiting: usize, limits: &Backpressure) -> bool {
+        let was = self.overloaded.load(Ordering::Relaxed);
+        let is = if was {
+            waiting > limits.low
+        } else {
+            waiting >= limits.high
+        };
+        self.overloaded.store(is, Ordering::Relaxed);
+        is != was
+    }
+
+    /// Whether the domain is overloaded.
+    pub fn overloaded(&self) -> bool {
+        self.overloaded.load(Ordering::Relaxed)
+    }
+
+    /// Whether the domain, or any domain below it, is overloaded.
+    pub fn overloaded_below(&self) -> bool {
+        if self.overloaded() {
+            return true;
+        }
+
+        // a domain can be below another along several paths, and is only looked at once
+        let mut seen: Vec<*const Gauge> = vec![self];
+        let mut stack: Vec<Arc<Gauge>> = self.below.read().unwrap().clone();
+        while let Some(g) = stack.pop() {
+            if seen.contains(&(&*g as *const _)) {
+                continue;
+            }
+            if g.overloaded() {
+                return true;
+            }
+            seen.push(&*g);
+            stack.extend(g.below.read().unwrap().iter().cloned());
+        }
+        false
+    }
+
+    /// Set the gauges of the domains that this domain sends to.
+    pub fn set_below(&self, below: Vec<Arc<Gauge>>) {
+        *self.below.write().unwrap() = below;
+    }
+}
+
+/// The gauges of every domain that a worker runs.
+///
+/// Clones share the same gauges, so a worker can hand a clone to each domain it boots.
+#[derive(Clone, Default)]
+pub struct Gauges {
+    gauges: Arc<RwLock<FnvHashMap<ReplicaAddr, Arc<Gauge>>>>,
+}
+
+impl Gauges {
+    /// Make a set of gauges with no domains in it.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The gauge of the given shard of a domain, which is added if it isn't there yet.
+    pub fn get(&self, addr: ReplicaAddr) -> Arc<Gauge> {
+        if let Some(g) = self.gauges.read().unwrap().get(&addr) {
+            return g.clone();
+        }
+        self.gauges
+            .write()
+            .unwrap()
+            .entry(addr)
+            .or_default()
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> Backpressure {
+        Backpressure {
+            high: 10,
+            low: 2,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn it_overloads_with_hysteresis() {
+        let g = Gauge::default();
+        assert!(!g.update(9, &limits()));
+        assert!(!g.overloaded());
+        assert!(g.update(10, &limits()));
+        assert!(g.overloaded());
+        // stays overloaded until enough of the backlog is gone
+        assert!(!g.update(5, &limits()));
+        assert!(g.overloaded());
+        assert!(g.update(2, &limits()));
+        assert!(!g.overloaded());
+    }
+
+    #[test]
+    fn it_sees_overload_below() {
+        let gauges = Gauges::new();
+        let a = gauges.get((DomainIndex::from(0), 0));
+        let b = gauges.get((DomainIndex::from(1), 0));
+        let c = gauges.get((DomainIndex::from(2), 0));
+        // c is below a both directly and through b
+        a.set_below(vec![b.clone(), c.clone()]);
+        b.set_below(vec![c.clone()]);
+        assert!(Arc::ptr_eq(&c, &gauges.get((DomainIndex::from(2), 0))));
+
+        assert!(!a.overloaded_below());
+        c.update(10, &limits());
+        assert!(a.overloaded_below());
+        assert!(b.overloaded_below());
+        assert!(!a.overloaded());
+        c.update(0, &limits());
+        assert!(!a.overloaded_below());
+    }
+}
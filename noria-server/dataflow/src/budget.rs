@@ -0,0 +1,227 @@
+//! Where the wall-clock time of the task that runs a shard of a domain goes.
+//!
+//! The task alternates between processing the packets that have arrived, handing the packets that
+//! the domain produced to the connections to the domains below, and waiting to be woken up again.
+//! Every shard keeps a `TimeBudget` that the time spent on each of these is added to, so that a
+//! domain that can't keep up can be told apart from one that is held up by the domains it sends
+//! to:
+//!
+//!  - processing is split between the nodes that regular updates pass through and everything else
+//!    the domain does,
+//!  - sending is split by the domain shard sent to, and
+//!  - waiting counts as blocked on sending to the domain shards that the task still had packets
+//!    for when it went to sleep, because they would not take them yet, and as idle otherwise.
+//!
+//! The task only takes timestamps when it is woken up, when it goes back to sleep, and around the
+//! sends to each domain shard, and the domain already times its nodes, so keeping the budget
+//! costs next to nothing per packet.
+//!
+//! Besides the total time in each bucket, the budget keeps a recent time, which decays with a
+//! half-life of ten seconds, to say where the time has been going lately. Since the buckets add up
+//! to the wall-clock time, the time accounted for so far serves as the clock that recent times
+//! are decayed by.
+
+use fnv::FnvHashMap;
+use noria::debug::stats::{NodeTime, SendTime, TimeBudgetStats, TimeSpent};
+use prelude::*;
+use std::time;
+
+/// After how much time the recent times count for half, in nanoseconds.
+const HALF_LIFE_NS: f64 = 10_000_000_000.0;
+
+/// How often the recent times are decayed, in nanoseconds of accounted time.
+const DECAY_EVERY_NS: u64 = 100_000_000;
+
+fn nanos(d: time::Duration) -> u64 {
+    d.as_nanos() as u64
+}
+
+#[derive(Clone, Copy, Default)]
+struct Bucket {
+    total: u64,
+    recent: f64,
+}
+
+impl Bucket {
+    fn add(&mut self, ns: u64) {
+        self.total += ns;
+        self.recent += ns as f64;
+    }
+
+    fn stats(&self) -> TimeSpent {
+        TimeSpent {
+            total: self.total,
+            recent: self.recent.round() as u64,
+        }
+    }
+}
+
+#[derive(Default)]
+struct SendBuckets {
+    sending: Bucket,
+    blocked: Bucket,
+}
+
+/// The time that the task running a domain shard has spent on each part of its work.
+///
+/// See the module documentation for details.
+#[derive(Default)]
+pub struct TimeBudget {
+    nodes: Map<Bucket>,
+    other: Bucket,
+    sends: FnvHashMap<ReplicaAddr, SendBuckets>,
+    idle: Bucket,
+
+    // the time spent in nodes and on sends since the task last said how long it had been working,
+    // which is part of that time
+    worked_in_nodes: u64,
+    worked_on_sends: u64,
+    // the time accounted for since the recent times were last decayed
+    since_decay: u64,
+}
+
+impl TimeBudget {
+    /// Count time spent processing a regular update in the given node.
+    pub(crate) fn processed(&mut self, node: LocalNodeIndex, took: time::Duration) {
+        let ns = nanos(took);
+        self.nodes.entry(node).or_default().add(ns);
+        self.worked_in_nodes += ns;
+    }
+
+    /// Count time spent handing packets to the connection to the given domain shard.
+    pub fn sent(&mut self, to: ReplicaAddr, took: time::Duration) {
+        let ns = nanos(took);
+        self.sends.entry(to).or_default().sending.add(ns);
+        self.worked_on_sends += ns;
+    }
+
+    /// Count time that the task spent working between being woken up and going back to sleep.
+    /// Whatever part of it was not spent in nodes or on sends counts as other processing.
+    pub fn worked(&mut self, took: time::Duration) {
+        let ns = nanos(took);
+        let attributed = self.worked_in_nodes + self.worked_on_sends;
+        self.other.add(ns.saturating_sub(attributed));
+        self.worked_in_nodes = 0;
+        self.worked_on_sends = 0;
+        self.advance(ns);
+    }
+
+    /// Count time that the task spent asleep, while it had packets for the given domain shards
+    /// that they would not take yet. The time is split evenly between those domain shards, and
+    /// counts as idle if there are none.
+    pub fn waited(&mut self, took: time::Duration, blocked_on: &[ReplicaAddr]) {
+        let ns = nanos(took);
+        if blocked_on.is_empty() {
+            self.idle.add(ns);
+        } else {
+            let each = ns / blocked_on.len() as u64;
+            for &to in blocked_on {
+                self.sends.entry(to).or_default().blocked.add(each);
+            }
+        }
+        self.advance(ns);
+    }
+
+    fn advance(&mut self, ns: u64) {
+        self.since_decay += ns;
+        if self.since_decay < DECAY_EVERY_NS {
+            return;
+        }
+
+        let factor = 0.5f64.powf(self.since_decay as f64 / HALF_LIFE_NS);
+        for (_, b) in self.nodes.iter_mut() {
+            b.recent *= factor;
+        }
+        self.other.recent *= factor;
+        for s in self.sends.values_mut() {
+            s.sending.recent *= factor;
+            s.blocked.recent *= factor;
+        }
+        self.idle.recent *= factor;
+        self.since_decay = 0;
+    }
+
+    /// The time spent so far, with nodes identified by their index in the graph, as `global`
+    /// gives it.
+    pub(crate) fn stats<F>(&self, global: F) -> TimeBudgetStats
+    where
+        F: Fn(LocalNodeIndex) -> NodeIndex,
+    {
+        let mut nodes: Vec<_> = self
+            .nodes
+            .iter()
+            .map(|(ni, b)| NodeTime {
+                node: global(ni),
+                time: b.stats(),
+            })
+            .collect();
+        nodes.sort_by_key(|n| n.node);
+        let mut sends: Vec<_> = self
+            .sends
+            .iter()
+            .map(|(&(domain, shard), s)| SendTime {
+                domain,
+                shard,
+                sending: s.sending.stats(),
+                blocked: s.blocked.stats(),
+            })
+            .collect();
+        sends.sort_by_key(|s| (s.domain, s.shard));
+
+        TimeBudgetStats {
+            nodes,
+            other: self.other.stats(),
+            sends,
+            idle: self.idle.stats(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn it_splits_work_between_nodes_sends_and_the_rest() {
+        let mut b = TimeBudget::default();
+        let to = (DomainIndex::from(1), 0);
+        b.processed(unsafe { LocalNodeIndex::make(0) }, Duration::from_millis(3));
+        b.sent(to, Duration::from_millis(2));
+        b.worked(Duration::from_millis(10));
+
+        let stats = b.stats(|ni| NodeIndex::new(ni.id() as usize));
+        assert_eq!(stats.nodes[0].time.total, 3_000_000);
+        assert_eq!(stats.sends[0].sending.total, 2_000_000);
+        assert_eq!(stats.other.total, 5_000_000);
+        assert_eq!(stats.processing().total, 8_000_000);
+    }
+
+    #[test]
+    fn it_counts_waiting_with_undelivered_packets_as_blocked() {
+        let mut b = TimeBudget::default();
+        let (to1, to2) = ((DomainIndex::from(1), 0), (DomainIndex::from(2), 0));
+        b.waited(Duration::from_secs(1), &[]);
+        b.waited(Duration::from_secs(2), &[to1, to2]);
+        b.waited(Duration::from_secs(1), &[to2]);
+
+        let stats = b.stats(|ni| NodeIndex::new(ni.id() as usize));
+        assert_eq!(stats.idle.total, 1_000_000_000);
+        assert_eq!(stats.blocked().total, 3_000_000_000);
+        let on = stats.blocked_on().unwrap();
+        assert_eq!((on.domain, on.shard), to2);
+    }
+
+    #[test]
+    fn it_decays_recent_time() {
+        let mut b = TimeBudget::default();
+        b.waited(Duration::from_secs(10), &[]);
+        b.worked(Duration::from_secs(10));
+
+        let stats = b.stats(|ni| NodeIndex::new(ni.id() as usize));
+        // the idle time was decayed twice: once right away, and again after the work
+        assert_eq!(stats.idle.total, 10_000_000_000);
+        assert!((stats.idle.recent as f64 - 2_500_000_000.0).abs() < 1_000.0);
+        assert!((stats.other.recent as f64 - 5_000_000_000.0).abs() < 1_000.0);
+    }
+}
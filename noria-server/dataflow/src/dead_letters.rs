@@ -0,0 +1,178 @@
+//! Packets that domains could not deliver, because the receiving end had gone away.
+//!
+//! A domain that fails to send a packet to another domain, to itself, or to a client because the
+//! receiver is gone neither fails nor quietly drops the packet. It records a summary of the packet
+//! as a `DeadLetter` instead, counts it in its metrics, and carries on. The dead letters of all the
+//! domains that a worker runs are kept in the worker's `DeadLetters`, which only holds on to the
+//! most recent `CAPACITY` of them.
+//!
+//! Records that an operator could not process are also posted as dead letters if the graph is set
+//! up to (see `MalformedRecords::DeadLetter`), with the record itself, the node that could not
+//! process it, and the node that sent it.
+//!
+//! The controller takes them with `Packet::DrainDeadLetters`. Any domain of the worker answers it
+//! with all of the worker's dead letters, so that those of domains that have since gone away, which
+//! is often the very reason for the dead letters, can still be taken.
+
+use metrics::DomainMetrics;
+use noria::debug::dead_letters::{DeadLetter, Destination};
+use prelude::*;
+use slog::Logger;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// The number of dead letters a worker holds on to, past which the oldest ones are dropped.
+pub const CAPACITY: usize = 1024;
+
+/// The dead letters of every domain that a worker runs.
+///
+/// Clones share the same letters, so a worker can hand a clone to each domain it boots.
+#[derive(Clone, Default)]
+pub struct DeadLetters {
+    letters: Arc<Mutex<VecDeque<DeadLetter>>>,
+}
+
+impl DeadLetters {
+    /// Make an empty set of dead letters.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep a dead letter, dropping the oldest one if there are already `CAPACITY` of them.
+    ///
+    /// Returns whether a letter was dropped.
+    pub fn record(&self, letter: DeadLetter) -> bool {
+        let mut letters = self.letters.lock().unwrap();
+        let full = letters.len() >= CAPACITY;
+        if full {
+            letters.pop_front();
+        }
+        letters.push_back(letter);
+        full
+    }
+
+    /// Take every dead letter, oldest first.
+    pub fn drain(&self) -> Vec<DeadLetter> {
+        self.letters.lock().unwrap().drain(..).collect()
+    }
+}
+
+/// Where a shard of a domain posts the packets it could not deliver.
+///
+/// Clones post on behalf of the same domain shard, so that the threads a domain starts can post
+/// too.
+#[derive(Clone)]
+pub struct DeadLetterBox {
+    from: ReplicaAddr,
+    letters: DeadLetters,
+    metrics: Arc<DomainMetrics>,
+    log: Logger,
+}
+
+impl DeadLetterBox {
+    pub(crate) fn new(
+        from: ReplicaAddr,
+        letters: DeadLetters,
+        metrics: Arc<DomainMetrics>,
+        log: Logger,
+    ) -> Self {
+        DeadLetterBox {
+            from,
+            letters,
+            metrics,
+            log,
+        }
+    }
+
+    /// Record that a packet of the given kind, carrying `records` records, could not be delivered
+    /// to `to`.
+    pub fn post(&self, to: Destination, kind: &str, records: usize, error: &dyn fmt::Display) {
+        let letter = DeadLetter {
+            domain: self.from.0,
+            shard: self.from.1,
+            to,
+            kind: kind.to_owned(),
+            records,
+            at: SystemTime::now(),
+            error: error.to_string(),
+            record: None,
+        };
+        self.keep(letter);
+    }
+
+    /// Record that the operator of `node` could not process `record`, which the node `from` sent
+    /// it, for the given reason.
+    pub(crate) fn post_malformed(
+        &self,
+        node: NodeIndex,
+        from: NodeIndex,
+        record: Record,
+        reason: &str,
+    ) {
+        let (record, positive) = record.extract();
+        let letter = DeadLetter {
+            domain: self.from.0,
+            shard: self.from.1,
+            to: Destination::Node { node, from },
+            kind: if positive { "Positive" } else { "Negative" }.to_owned(),
+            records: 1,
+            at: SystemTime::now(),
+            error: reason.to_owned(),
+            record: Some(record),
+        };
+        self.keep(letter);
+    }
+
+    fn keep(&self, letter: DeadLetter) {
+        warn!(self.log, "{}", letter);
+        self.metrics.dead_letter();
+        if self.letters.record(letter) {
+            warn!(self.log, "too many dead letters, dropped the oldest one");
+        }
+    }
+
+    /// Where the packets that the domain shard sends to itself are headed.
+    pub fn to_self(&self) -> Destination {
+        Destination::Domain(self.from.0, self.from.1)
+    }
+
+    /// Take every dead letter of the worker, not just those of this domain shard.
+    pub(crate) fn drain_all(&self) -> Vec<DeadLetter> {
+        self.letters.drain()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn letter(records: usize) -> DeadLetter {
+        DeadLetter {
+            domain: DomainIndex::from(0),
+            shard: 0,
+            to: Destination::Client,
+            kind: String::from("Ack"),
+            records,
+            at: SystemTime::now(),
+            error: String::from("gone"),
+            record: None,
+        }
+    }
+
+    #[test]
+    fn it_keeps_the_most_recent_letters() {
+        let letters = DeadLetters::new();
+        for i in 0..CAPACITY {
+            assert!(!letters.record(letter(i)));
+        }
+        assert!(letters.record(letter(CAPACITY)));
+
+        let drained = letters.drain();
+        assert_eq!(drained.len(), CAPACITY);
+        assert_eq!(drained[0].records, 1);
+        assert_eq!(drained[CAPACITY - 1].records, CAPACITY);
+        assert!(letters.drain().is_empty());
+    }
+}
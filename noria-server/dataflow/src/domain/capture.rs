@@ -0,0 +1,354 @@
+//! Capturing what a domain sees, and re-running it outside of a running system.
+//!
+//! When a domain is told to start capturing (with `Packet::Capture`), it writes a copy of its
+//! nodes and of the state of its fully materialized nodes to a capture file, and from then on
+//! appends every event it sees to the file: each packet it is handed, each time it is told that
+//! time has passed, and each time it runs out of packets to process. Events are numbered in the
+//! order in which the domain saw them, and stamped with when it did.
+//!
+//! A `Replay` reads a capture file back, builds a fresh domain from the nodes and state in it, and
+//! has that domain go through the captured events one at a time, so that a test or a debugging
+//! session can look at the domain's state after any number of them.
+//!
+//! A few things can't be re-run. Replays involve paths through other domains, so replay-related
+//! packets are skipped, which is also why domains with partial state can't be captured. Packets
+//! that carry a channel to a client (like `Packet::AddStreamer`) are recorded as omitted, and
+//! packets traced by a client are captured without the channel that trace events are sent on.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, Write};
+use std::net;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time;
+
+use bincode;
+use fnv::FnvHashSet;
+use noria::channel::poll::PollEvent;
+use payload::InitialState;
+use prelude::*;
+use slog::Logger;
+use stream_cancel::{Trigger, Valve};
+
+use super::{CheckpointedState, Config, Domain, DomainBuilder, EnqueuedSends, Index};
+
+/// What the domain looked like when the capture started. Written at the start of the file.
+#[derive(Serialize, Deserialize)]
+pub(super) struct Header {
+    pub(super) index: Index,
+    pub(super) shard: Option<usize>,
+    pub(super) nshards: usize,
+    pub(super) nodes: DomainNodes,
+    pub(super) persistence_parameters: PersistenceParameters,
+    pub(super) config: Config,
+    pub(super) state: Vec<CheckpointedState>,
+    pub(super) not_ready: FnvHashSet<LocalNodeIndex>,
+    pub(super) ingress_inject: Map<(usize, Vec<DataType>)>,
+    pub(super) aux: AuxStateMap,
+}
+
+/// Something that a domain saw while it was being captured.
+#[derive(Serialize, Deserialize)]
+pub enum CaptureEvent {
+    /// The domain was handed a packet.
+    Process(Box<Packet>),
+    /// The domain was handed a packet that can't be captured.
+    Omitted,
+    /// The domain was told that time has passed.
+    Timeout,
+    /// The domain ran out of packets to process.
+    ResumePolling,
+}
+
+/// An event in a capture file.
+#[derive(Serialize, Deserialize)]
+pub struct Captured {
+    /// How many events the domain saw before this one since the capture started.
+    pub seq: u64,
+    /// When the domain saw the event.
+    pub at: time::SystemTime,
+    pub event: CaptureEvent,
+}
+
+/// A capture file that a domain is writing to.
+pub(super) struct Capture {
+    path: PathBuf,
+    file: io::BufWriter<fs::File>,
+    next: u64,
+}
+
+impl Capture {
+    pub(super) fn create(path: PathBuf, header: &Header) -> bincode::Result<Self> {
+        let mut file = io::BufWriter::new(fs::File::create(&path)?);
+        bincode::serialize_into(&mut file, header)?;
+        file.flush()?;
+        Ok(Capture {
+            path,
+            file,
+            next: 0,
+        })
+    }
+
+    pub(super) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(super) fn events(&self) -> u64 {
+        self.next
+    }
+
+    /// Append the given event to the file.
+    pub(super) fn record(&mut self, event: &PollEvent<Box<Packet>>) -> bincode::Result<()> {
+        let event = match *event {
+            PollEvent::Process(box Packet::Capture { .. }) => {
+                // turning the capture on or off isn't part of what is captured
+                return Ok(());
+            }
+            PollEvent::Process(ref packet) => match detached(packet) {
+                Some(packet) => CaptureEvent::Process(packet),
+                None => CaptureEvent::Omitted,
+            },
+            PollEvent::Timeout => CaptureEvent::Timeout,
+            PollEvent::ResumePolling(_) => CaptureEvent::ResumePolling,
+        };
+        let idle = match event {
+            CaptureEvent::ResumePolling => true,
+            _ => false,
+        };
+
+        let captured = Captured {
+            seq: self.next,
+            at: time::SystemTime::now(),
+            event,
+        };
+        bincode::serialize_into(&mut self.file, &captured)?;
+        self.next += 1;
+
+        if idle {
+            // the domain has nothing else to do for now, so this is a good time for the events so
+            // far to become readable
+            self.file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// A copy of the given packet that can be written to a capture file, if the packet can be.
+fn detached(packet: &Packet) -> Option<Box<Packet>> {
+    if let Packet::AddStreamer { .. } = *packet {
+        return None;
+    }
+
+    // a write that came from the same process is cloned into one that owns its data
+    let mut packet = box packet.clone();
+    match *packet {
+        Packet::Input { ref mut inner, .. } => {
+            if let Some((_, ref mut sender)) = unsafe { inner.deref_mut() }.tracer {
+                *sender = None;
+            }
+        }
+        Packet::Message { ref mut tracer, .. } => {
+            if let Some((_, ref mut sender)) = *tracer {
+                *sender = None;
+            }
+        }
+        _ => {}
+    }
+    Some(packet)
+}
+
+/// Whether a captured packet can be processed without the other domains it came from or is for.
+fn replayable(packet: &Packet) -> bool {
+    match *packet {
+        Packet::ReplayPiece { .. }
+        | Packet::Finish(..)
+        | Packet::SetupReplayPath { .. }
+        | Packet::RequestPartialReplay { .. }
+        | Packet::RequestReaderReplay { .. }
+        | Packet::StartReplay { .. }
+        | Packet::RecoverBase { .. } => false,
+        Packet::PrepareState {
+            state: InitialState::PartialGlobal { .. },
+            ..
+        } => false,
+        _ => true,
+    }
+}
+
+/// Clients aren't around to be acked when a capture is replayed.
+struct NoAcks;
+
+impl Executor for NoAcks {
+    fn send_back(&mut self, _: SourceChannelIdentifier, _: WriteAck) {}
+}
+
+/// Re-runs a capture file against a fresh copy of the captured domain, one event at a time.
+///
+/// The domain's writes are applied as soon as they are processed, rather than in the batches that
+/// group commit made of them, and its materializations are kept in memory. The packets that it
+/// sends other domains are queued up until taken with `take_sent`.
+pub struct Replay {
+    domain: Domain,
+    events: io::BufReader<fs::File>,
+    seen: u64,
+    skipped: u64,
+    sent: EnqueuedSends,
+    // the domain needs somewhere to send its control replies, though nobody reads them
+    _control: net::TcpListener,
+    _shutdown: Trigger,
+}
+
+impl Replay {
+    /// Read the start of the capture file at `path`, and build the domain that it captured from
+    /// the nodes and state in it.
+    pub fn open<P: AsRef<Path>>(path: P, log: Logger) -> bincode::Result<Self> {
+        let mut events = io::BufReader::new(fs::File::open(path)?);
+        let header: Header = bincode::deserialize_from(&mut events)?;
+
+        let control = net::TcpListener::bind("127.0.0.1:0")?;
+        let persistence_parameters = PersistenceParameters {
+            flush_timeout: time::Duration::from_millis(0),
+            mode: DurabilityMode::MemoryOnly,
+            // so that any state kept on disk doesn't clash with the live domain's
+            log_prefix: format!("{}-replay", header.persistence_parameters.log_prefix),
+            wal: None,
+            ..header.persistence_parameters
+        };
+        let builder = DomainBuilder {
+            index: header.index,
+            shard: header.shard,
+            nshards: header.nshards,
+            nodes: header.nodes,
+            persistence_parameters,
+            control_addr: control.local_addr()?,
+            config: header.config,
+            snapshot: None,
+        };
+        let (shutdown, valve) = Valve::new();
+        let mut domain = builder.build(
+            log,
+            Default::default(),
+            Arc::new(ChannelCoordinator::new()),
+            &valve,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+        );
+
+        domain.not_ready = header.not_ready;
+        domain.ingress_inject = header.ingress_inject;
+        domain.aux = header.aux;
+        for saved in header.state {
+            let node = domain
+                .nodes
+                .values()
+                .map(|n| n.borrow())
+                .find(|n| n.global_addr() == saved.node)
+                .map(|n| n.local_addr())
+                .expect("captured state for a node that isn't in the domain");
+            let state = domain.restored_state(node, saved, &HashSet::new());
+            domain.state.insert(node, state);
+        }
+
+        Ok(Replay {
+            domain,
+            events,
+            seen: 0,
+            skipped: 0,
+            sent: Default::default(),
+            _control: control,
+            _shutdown: shutdown,
+        })
+    }
+
+    /// Have the domain go through the next event in the capture. Returns the event's number and
+    /// when the captured domain saw it, or `None` if there are no more events.
+    pub fn step(&mut self) -> bincode::Result<Option<(u64, time::SystemTime)>> {
+        let captured: Captured = match bincode::deserialize_from(&mut self.events) {
+            Ok(captured) => captured,
+            Err(box bincode::ErrorKind::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        };
+
+        let sent = &mut self.sent;
+        match captured.event {
+            CaptureEvent::Process(packet) => {
+                if replayable(&packet) {
+                    self.domain
+                        .on_event(&mut NoAcks, PollEvent::Process(packet), sent);
+                } else {
+                    self.skipped += 1;
+                }
+            }
+            CaptureEvent::Omitted => self.skipped += 1,
+            CaptureEvent::Timeout => {
+                self.domain.on_event(&mut NoAcks, PollEvent::Timeout, sent);
+            }
+            CaptureEvent::ResumePolling => {
+                let mut timeout = None;
+                self.domain
+                    .on_event(&mut NoAcks, PollEvent::ResumePolling(&mut timeout), sent);
+            }
+        }
+        self.seen += 1;
+        Ok(Some((captured.seq, captured.at)))
+    }
+
+    /// Go through events until the domain has gone through `n` in all, or there are no more.
+    /// Returns how many the domain has gone through.
+    pub fn run_to(&mut self, n: u64) -> bincode::Result<u64> {
+        while self.seen < n {
+            if self.step()?.is_none() {
+                break;
+            }
+        }
+        Ok(self.seen)
+    }
+
+    /// Go through every remaining event. Returns how many the domain has gone through in all.
+    pub fn run(&mut self) -> bincode::Result<u64> {
+        self.run_to(u64::max_value())
+    }
+
+    /// The number of events that the domain has gone through so far.
+    pub fn seen(&self) -> u64 {
+        self.seen
+    }
+
+    /// The number of events so far that were packets the domain could not be handed, because
+    /// they involve other domains, or could not be captured.
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    /// The state of the domain's materialized nodes, by their local index.
+    pub fn state(&self) -> &StateMap {
+        &self.domain.state
+    }
+
+    /// Every row materialized at the given node, or `None` if the node isn't materialized in the
+    /// domain.
+    pub fn rows(&self, node: NodeIndex) -> Option<Vec<Vec<DataType>>> {
+        let local = self
+            .domain
+            .nodes
+            .values()
+            .map(|n| n.borrow())
+            .find(|n| n.global_addr() == node)
+            .map(|n| n.local_addr())?;
+        self.domain.state.get(local).map(|s| s.cloned_records())
+    }
+
+    /// Take the packets the domain has sent to other domains so far, in the order they were sent
+    /// to each of them.
+    pub fn take_sent(&mut self) -> Vec<(ReplicaAddr, Box<Packet>)> {
+        self.sent
+            .drain()
+            .flat_map(|(to, ps)| ps.into_iter().map(move |p| (to, p)))
+            .collect()
+    }
+}
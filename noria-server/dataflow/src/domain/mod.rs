@@ -1,40 +1,134 @@
-use fnv::FnvHashMap;
+use fnv::{FnvHashMap, FnvHashSet};
 use petgraph::graph::NodeIndex;
 use std::borrow::Cow;
 use std::cell;
 use std::cmp;
 use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io::{self, Write};
+use std::iter;
 use std::mem;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time;
 
+use bincode;
 use futures;
 use group_commit::GroupCommitQueueSet;
+use metrics::{DomainMetrics, Metrics, NodeMetrics};
+use node::note_unmatched;
+use node::special::Switch;
 use noria::channel::poll::{PollEvent, ProcessResult};
 use noria::channel::{self, TcpSender};
 pub use noria::internal::DomainIndex as Index;
-use payload::{ControlReplyPacket, ReplayPieceContext};
+use noria::internal::LocalOrNot;
+use noria::{TableOperation, WriteError};
+use payload::{ControlError, ControlReplyPacket, ReplayPieceContext};
 use prelude::*;
 use slog::Logger;
+use state::{RowStream, Snapshot};
 use stream_cancel::Valve;
 
+use backlog;
+use backpressure::{Gauge, Gauges};
+use budget::TimeBudget;
+use dead_letters::{DeadLetterBox, DeadLetters};
 use timekeeper::{RealTime, SimpleTracker, ThreadTime, Timer, TimerSet};
 use tokio::{self, prelude::*};
-use Readers;
+use wal::{self, WriteAheadLogs};
+use {Backpressure, ChannelConfig, MalformedRecords, OverloadPolicy, Readers};
+
+mod capture;
+mod timers;
+pub use self::capture::{CaptureEvent, Captured, Replay};
+use self::timers::{TimerOwner, Timers};
 
 type EnqueuedSends = FnvHashMap<ReplicaAddr, VecDeque<Box<Packet>>>;
 
+/// The token of the domain's own timer for sweeping bases with a TTL for expired rows.
+const SWEEP_EXPIRED: u64 = 0;
+
+fn has_ttl(n: &Node) -> bool {
+    n.get_base().and_then(|b| b.ttl()).is_some()
+}
+
+/// Whether a paused domain holds on to the given packet until it is resumed, rather than handling
+/// it right away. Only control packets are handled while paused.
+fn held_while_paused(packet: &Packet) -> bool {
+    match *packet {
+        Packet::Input { .. }
+        | Packet::Message { .. }
+        | Packet::ReplayPiece { .. }
+        | Packet::Evict { .. }
+        | Packet::EvictKeys { .. }
+        | Packet::ForceEvict { .. }
+        | Packet::Finish(..)
+        | Packet::RequestPartialReplay { .. }
+        | Packet::RequestReaderReplay { .. }
+        | Packet::Barrier { .. }
+        | Packet::Redirected { .. } => true,
+        _ => false,
+    }
+}
+
+/// Cut the given records into pieces of at most `limit` records each, if there is a limit. There
+/// is always at least one piece, even if there are no records.
+fn cut(rs: Records, limit: Option<usize>) -> VecDeque<Records> {
+    match limit {
+        Some(limit) if rs.len() > limit => {
+            let mut rs = rs.into_iter();
+            let mut pieces = VecDeque::new();
+            loop {
+                let piece: Records = rs.by_ref().take(limit).collect();
+                if piece.is_empty() {
+                    break;
+                }
+                pieces.push_back(piece);
+            }
+            pieces
+        }
+        _ => iter::once(rs).collect(),
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct Config {
     pub concurrent_replays: usize,
     pub replay_batch_timeout: time::Duration,
+    pub expiry_sweep_interval: time::Duration,
+    pub expiry_batch_size: usize,
+    /// How many threads a domain may use to push a chunk of a full replay through its operators.
+    /// Anything below two replays serially on the domain thread.
+    #[serde(default)]
+    pub replay_workers: usize,
+    /// When the domain holds back writes to its bases because domains below them are overloaded,
+    /// if it ever does.
+    #[serde(default)]
+    pub backpressure: Option<Backpressure>,
+    /// How many bytes the connections that the domain sends and receives packets over buffer.
+    #[serde(default)]
+    pub channels: ChannelConfig,
+    /// What the domain does with records that its operators can't process.
+    #[serde(default)]
+    pub malformed: MalformedRecords,
 }
 
 const BATCH_SIZE: usize = 256;
 
+/// Full replays are cut into chunks this much larger when they are processed in parallel, so
+/// that each worker gets enough records to be worth handing out.
+const PARALLEL_BATCH_FACTOR: usize = 16;
+
+/// How often a domain that holds back writes checks whether the domains below have caught up.
+const HELD_WRITES_RECHECK_MS: u64 = 5;
+
+/// How often a domain that has received a barrier from every sender checks whether it has drained,
+/// so that it can send the barrier on.
+const BARRIER_RECHECK_MS: u64 = 5;
+
 #[derive(Debug)]
 enum DomainMode {
     Forwarding,
@@ -42,9 +136,92 @@ enum DomainMode {
         to: LocalNodeIndex,
         buffered: VecDeque<Box<Packet>>,
         passes: usize,
+        /// The number of records that the replay has brought into the domain so far.
+        replayed: usize,
     },
 }
 
+/// A full replay from state that is too large to copy, or that has to make way for live updates,
+/// which is sent a chunk at a time from the domain's event loop.
+struct StreamedReplay {
+    tag: Tag,
+    link: Link,
+    from: LocalNodeIndex,
+    rows: iter::Peekable<RowStream>,
+    fix: Box<Fn(Vec<DataType>) -> Vec<DataType> + Send>,
+    priority: ReplayPriority,
+    /// When the last chunk was sent, or the replay started if no chunk has been.
+    sent: time::Instant,
+    /// When the next chunk may be sent, if the replay's rate is capped.
+    due: time::Instant,
+}
+
+/// A barrier sent through the graph to bring it to rest (see `Packet::Quiesce`), as far as it has
+/// come at this domain.
+struct Barrier {
+    id: u64,
+    /// How many shards of other domains send the barrier, once the controller has said.
+    senders: Option<usize>,
+    /// How many of them have sent it so far.
+    arrived: usize,
+}
+
+/// The contents of a domain's checkpoint file.
+#[derive(Serialize, Deserialize)]
+struct Checkpoint {
+    id: u64,
+    nodes: Vec<CheckpointedState>,
+    aux: Vec<(NodeIndex, AuxState)>,
+}
+
+/// The state of one fully materialized node, as of the checkpoint it was saved in.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct CheckpointedState {
+    node: NodeIndex,
+    // used to make sure the node that is restored is the one that was saved
+    name: String,
+    keys: Vec<Vec<usize>>,
+    rows: Vec<Vec<DataType>>,
+    // the last entry of a base's write-ahead log that is reflected in `rows`
+    logged: u64,
+}
+
+/// What a copy of a domain that is moving to another worker starts out from: the domain as it was
+/// at the point where the updates that it goes on to process begin to be logged for the copy.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MoveSnapshot {
+    state: Vec<CheckpointedState>,
+    received: FnvHashMap<(LocalNodeIndex, LocalNodeIndex), u64>,
+    not_ready: FnvHashSet<LocalNodeIndex>,
+    ingress_inject: Map<(usize, Vec<DataType>)>,
+    aux: AuxStateMap,
+}
+
+impl MoveSnapshot {
+    /// The number of rows of state in the snapshot.
+    pub fn rows(&self) -> usize {
+        self.state.iter().map(|s| s.rows.len()).sum()
+    }
+}
+
+/// A domain that is being moved to another worker, as seen from where it was.
+struct Move {
+    // updates processed since the snapshot was taken, yet to be sent to the copy
+    log: Vec<Box<Packet>>,
+    // how many updates have been logged in all
+    logged: usize,
+    // the copy, once it is up
+    to: Option<TcpSender<Box<Packet>>>,
+    // how many senders of updates to the domain are being redirected to the copy, once known, and
+    // how many of them have been
+    senders: Option<usize>,
+    redirected: usize,
+}
+
+/// How many updates a domain that is moving logs for its copy before the copy is up. Updates that
+/// arrive after that are held on to until it is.
+const MOVE_LOG_LIMIT: usize = 4096;
+
 impl PartialEq for DomainMode {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
@@ -116,6 +293,8 @@ pub struct DomainBuilder {
     pub control_addr: SocketAddr,
     /// Configuration parameters for the domain.
     pub config: Config,
+    /// Where the domain starts out from if it is a copy of a domain that is moving here.
+    pub snapshot: Option<Box<MoveSnapshot>>,
 }
 
 unsafe impl Send for DomainBuilder {}
@@ -129,6 +308,9 @@ impl DomainBuilder {
         channel_coordinator: Arc<ChannelCoordinator>,
         shutdown_valve: &Valve,
         state_size: Arc<AtomicUsize>,
+        metrics: Metrics,
+        gauges: Gauges,
+        dead_letters: DeadLetters,
     ) -> Domain {
         // initially, all nodes are not ready
         let not_ready = self
@@ -137,18 +319,52 @@ impl DomainBuilder {
             .map(|n| n.borrow().local_addr())
             .collect();
 
+        // bases with a TTL are swept for expired rows every so often
+        let mut timers = Timers::default();
+        if self.nodes.values().any(|n| has_ttl(&n.borrow())) {
+            let every = self.config.expiry_sweep_interval;
+            let now = time::Instant::now();
+            timers.set(TimerOwner::Domain, SWEEP_EXPIRED, now, every, Some(every));
+        }
+
+        let domain_metrics = metrics.register(self.index, self.shard.unwrap_or(0));
+        let mut node_metrics = Map::default();
+        for n in self.nodes.values() {
+            let n = n.borrow();
+            let m = domain_metrics.add_node(n.local_addr(), n.global_addr(), n.name());
+            node_metrics.insert(n.local_addr(), m);
+        }
+
         let log = log.new(o!("domain" => self.index.index(), "shard" => self.shard.unwrap_or(0)));
-        let control_reply_tx = TcpSender::connect(&self.control_addr).unwrap();
+        let control_reply_tx =
+            TcpSender::connect_with_capacity(&self.control_addr, self.config.channels.control)
+                .unwrap();
         let group_commit_queues = GroupCommitQueueSet::new(&self.persistence_parameters);
-
-        Domain {
+        let wal = WriteAheadLogs::new(&self.persistence_parameters, self.shard.unwrap_or(0));
+        let gauge = gauges.get((self.index, self.shard.unwrap_or(0)));
+        let dead_letters = DeadLetterBox::new(
+            (self.index, self.shard.unwrap_or(0)),
+            dead_letters,
+            domain_metrics.clone(),
+            log.clone(),
+        );
+
+        let snapshot = self.snapshot;
+        let mut domain = Domain {
             index: self.index,
             shard: self.shard,
             _nshards: self.nshards,
 
             persistence_parameters: self.persistence_parameters,
             nodes: self.nodes,
+            replay_streams: Default::default(),
+            live_since_replay: 0,
             state: StateMap::default(),
+            aux: AuxStateMap::default(),
+            checkpointed: Map::default(),
+            checkpointed_aux: AuxStateMap::default(),
+            checkpointed_logs: Map::default(),
+            received: Default::default(),
             log,
             not_ready,
             mode: DomainMode::Forwarding,
@@ -160,6 +376,8 @@ impl DomainBuilder {
 
             shutdown_valve: shutdown_valve.clone(),
             readers,
+            hidden_readers: Map::default(),
+            unpublished_readers: Default::default(),
             control_reply_tx,
             channel_coordinator,
 
@@ -167,12 +385,34 @@ impl DomainBuilder {
             has_buffered_replay_requests: false,
             replay_batch_timeout: self.config.replay_batch_timeout,
 
+            expiry_sweep_interval: self.config.expiry_sweep_interval,
+            expiry_batch_size: self.config.expiry_batch_size,
+            timers,
+
+            replay_workers: self.config.replay_workers,
+
+            backpressure: self.config.backpressure,
+            gauges,
+            gauge,
+            held_writes: Default::default(),
+            writes_held: false,
+            paused: false,
+            paused_packets: Default::default(),
+            barrier: None,
+
+            channels: self.config.channels,
+            malformed: self.config.malformed,
+            domain_connections: 0,
+            input_connections: 0,
+            replay_connections: 0,
+
             concurrent_replays: 0,
             max_concurrent_replays: self.config.concurrent_replays,
             replay_request_queue: Default::default(),
             delayed_for_self: Default::default(),
 
             group_commit_queues,
+            wal,
 
             state_size: state_size,
             total_time: Timer::new(),
@@ -180,7 +420,21 @@ impl DomainBuilder {
             wait_time: Timer::new(),
             process_times: TimerSet::new(),
             process_ptimes: TimerSet::new(),
+            processed_records: Map::default(),
+            time_budget: TimeBudget::default(),
+            metrics,
+            domain_metrics,
+            node_metrics,
+            dead_letters,
+            capture: None,
+
+            moving: None,
+            redirects: Vec::new(),
+        };
+        if let Some(snapshot) = snapshot {
+            domain.restore(*snapshot);
         }
+        domain
     }
 }
 
@@ -190,10 +444,24 @@ pub struct Domain {
     _nshards: usize,
 
     nodes: DomainNodes,
+    // declared before `state`, since the streams read from it, and so must be dropped first
+    replay_streams: VecDeque<StreamedReplay>,
+    // live updates processed since the last chunk of a streamed replay was sent
+    live_since_replay: usize,
     state: StateMap,
+    // the auxiliary state of the nodes whose operators keep any
+    aux: AuxStateMap,
+    checkpointed: Map<CheckpointedState>,
+    // auxiliary state from the checkpoint, until the nodes it is for are prepared
+    checkpointed_aux: AuxStateMap,
+    // the last write-ahead log entry in the state of each base restored from a checkpoint
+    checkpointed_logs: Map<u64>,
+    // the number of the last update that came in over each edge into one of the domain's ingress
+    // nodes, by ingress and by the shard that sends over the edge
+    received: FnvHashMap<(LocalNodeIndex, LocalNodeIndex), u64>,
     log: Logger,
 
-    not_ready: HashSet<LocalNodeIndex>,
+    not_ready: FnvHashSet<LocalNodeIndex>,
 
     ingress_inject: Map<(usize, Vec<DataType>)>,
 
@@ -201,7 +469,7 @@ pub struct Domain {
 
     mode: DomainMode,
     waiting: Map<Waiting>,
-    replay_paths: HashMap<Tag, ReplayPath>,
+    replay_paths: FnvHashMap<Tag, ReplayPath>,
     reader_triggered: Map<HashSet<Vec<DataType>>>,
 
     concurrent_replays: usize,
@@ -210,6 +478,10 @@ pub struct Domain {
 
     shutdown_valve: Valve,
     readers: Readers,
+    // the state of readers that reads shouldn't see until they are exposed, by reader
+    hidden_readers: Map<(NodeIndex, backlog::SingleReadHandle)>,
+    // readers that hold writes that reads can't see yet
+    unpublished_readers: FnvHashSet<LocalNodeIndex>,
     control_reply_tx: TcpSender<ControlReplyPacket>,
     channel_coordinator: Arc<ChannelCoordinator>,
 
@@ -218,7 +490,38 @@ pub struct Domain {
     replay_batch_timeout: time::Duration,
     delayed_for_self: VecDeque<Box<Packet>>,
 
+    expiry_sweep_interval: time::Duration,
+    expiry_batch_size: usize,
+    timers: Timers,
+
+    replay_workers: usize,
+
+    backpressure: Option<Backpressure>,
+    // whether this domain is overloaded, and where to find out whether the domains below it are
+    gauges: Gauges,
+    gauge: Arc<Gauge>,
+    // writes held back until the domains below have caught up, in the order in which they arrived
+    held_writes: VecDeque<Box<Packet>>,
+    // whether every write is held back, rather than only those that arrive while overloaded
+    writes_held: bool,
+    // whether the domain has been paused, and the packets it has held on to since, in the order in
+    // which they arrived
+    paused: bool,
+    paused_packets: VecDeque<Box<Packet>>,
+    // the barrier of the quiesce that is underway, if one is
+    barrier: Option<Barrier>,
+
+    channels: ChannelConfig,
+    // what happens to records that an operator can't process
+    malformed: MalformedRecords,
+    // how many connections from other domains and from clients are open, as last told, and how
+    // many connections the domain has opened to ask domains on other workers for replays
+    domain_connections: usize,
+    input_connections: usize,
+    replay_connections: usize,
+
     group_commit_queues: GroupCommitQueueSet,
+    wal: WriteAheadLogs,
 
     state_size: Arc<AtomicUsize>,
     total_time: Timer<SimpleTracker, RealTime>,
@@ -226,6 +529,24 @@ pub struct Domain {
     wait_time: Timer<SimpleTracker, RealTime>,
     process_times: TimerSet<LocalNodeIndex, SimpleTracker, RealTime>,
     process_ptimes: TimerSet<LocalNodeIndex, SimpleTracker, ThreadTime>,
+    processed_records: Map<u64>,
+    // where the time of the task that runs the domain goes
+    time_budget: TimeBudget,
+    // counters that can be read without asking the domain, registered with the worker's metrics
+    metrics: Metrics,
+    domain_metrics: Arc<DomainMetrics>,
+    node_metrics: Map<Arc<NodeMetrics>>,
+    // where the domain posts the packets it could not deliver
+    dead_letters: DeadLetterBox,
+
+    // where every event the domain sees is written to, if anywhere
+    capture: Option<capture::Capture>,
+
+    // the move of this domain to another worker that is underway, if one is
+    moving: Option<Move>,
+    // the domains that this one has been told to send to at another address, along with where
+    // they were and where they are now, until the worker has seen them
+    redirects: Vec<(ReplicaAddr, SocketAddr, SocketAddr)>,
 }
 
 impl Domain {
@@ -478,10 +799,10 @@ impl Domain {
         enable_output: bool,
         sends: &mut EnqueuedSends,
         executor: Option<&mut Executor>,
-    ) -> HashMap<LocalNodeIndex, Vec<Record>> {
+    ) -> FnvHashMap<LocalNodeIndex, Vec<Record>> {
         let src = m.src();
         let me = m.dst();
-        let mut output_messages = HashMap::new();
+        let mut output_messages = FnvHashMap::default();
 
         match self.mode {
             DomainMode::Forwarding => (),
@@ -502,24 +823,62 @@ impl Domain {
             return output_messages;
         }
 
+        let records = match *m {
+            Packet::Input { ref inner, .. } => unsafe { inner.deref() }.data.len(),
+            _ => m.data().len(),
+        };
+        *self.processed_records.entry(me).or_default() += records as u64;
+
         let (mut m, evictions) = {
             let mut n = self.nodes[me].borrow_mut();
+            let start = time::Instant::now();
             self.process_times.start(me);
             self.process_ptimes.start(me);
             let mut m = Some(m);
+            let mut malformed = Vec::new();
             let (misses, captured) = n.process(
                 &mut m,
                 None,
                 &mut self.state,
+                &mut self.aux,
                 &self.nodes,
                 self.shard,
                 true,
                 sends,
                 executor,
+                1,
+                &mut malformed,
             );
             assert_eq!(captured.len(), 0);
             self.process_ptimes.stop();
             self.process_times.stop();
+            self.timers.request(me, n.take_timers(), start);
+
+            if !malformed.is_empty() {
+                reject_malformed(
+                    self.malformed,
+                    &n,
+                    malformed,
+                    &self.nodes,
+                    &self.node_metrics[me],
+                    &self.dead_letters,
+                    &self.log,
+                );
+            }
+
+            let (out, negative) = match m {
+                Some(box Packet::Message { ref data, .. }) => {
+                    (data.len(), data.iter().filter(|r| !r.is_positive()).count())
+                }
+                _ => (0, 0),
+            };
+            let took = start.elapsed();
+            self.node_metrics[me].processed(records, out, negative, took);
+            self.time_budget.processed(me, took);
+
+            if n.with_reader(|r| r.has_unpublished()).unwrap_or(false) {
+                self.unpublished_readers.insert(me);
+            }
 
             if m.is_none() {
                 // no need to deal with our children if we're not sending them anything
@@ -632,21 +991,117 @@ impl Domain {
             }
         }
 
-        match m.as_ref().unwrap() {
-            m @ &box Packet::Message { .. } if m.is_empty() => {
-                // no need to deal with our children if we're not sending them anything
-                return output_messages;
-            }
-            &box Packet::Message { .. } => {}
+        let written = match m.as_ref().unwrap() {
+            &box Packet::Message { written, .. } => written,
             &box Packet::ReplayPiece { .. } => {
                 unreachable!("replay should never go through dispatch");
             }
             ref m => unreachable!("dispatch process got {:?}", m),
+        };
+
+        // joins hold back their output for keys that match a great many records, so that a single
+        // write can't produce one huge update. that output is produced in bounded pieces now, and
+        // each piece is sent downstream as an update of its own. a node may also be given a limit
+        // on how many records it sends on at once, in which case its output, held back or not, is
+        // cut into pieces of at most that many records as well. either way, the markers of the
+        // writes go with the last piece, as only then have all their effects been sent on.
+        let limit = self.nodes[me].borrow().output_limit();
+        let mut m = m.take().unwrap();
+        let mut out = m.data().len();
+        let mut spilled = self.next_spilled(me);
+        let mut pieces = cut(m.take_data(), limit);
+        m.map_data(|rs| *rs = pieces.pop_front().unwrap());
+        let mut markers = Vec::new();
+        if spilled.is_some() || !pieces.is_empty() {
+            mem::swap(&mut markers, m.markers_mut().unwrap());
+        }
+        self.dispatch_to_children(me, m, enable_output, sends, &mut output_messages);
+
+        loop {
+            if pieces.is_empty() {
+                match spilled {
+                    Some(rs) => {
+                        out += rs.len();
+                        pieces = cut(rs, limit);
+                        spilled = self.next_spilled(me);
+                    }
+                    None => break,
+                }
+            }
+
+            let rs = pieces.pop_front().unwrap();
+            let last = pieces.is_empty() && spilled.is_none();
+            let m = box Packet::Message {
+                link: Link::new(src, me),
+                src: None,
+                data: rs,
+                tracer: None,
+                senders: Vec::new(),
+                written,
+                seq: None,
+                markers: if last {
+                    mem::replace(&mut markers, Vec::new())
+                } else {
+                    Vec::new()
+                },
+            };
+            self.dispatch_to_children(me, m, enable_output, sends, &mut output_messages);
+        }
+        self.node_metrics[me].amplified(records, out);
+
+        output_messages
+    }
+
+    /// The next piece of the output that the given node held back while processing a regular
+    /// update, if it is a join that held any back (see `Ingredient::next_spilled`).
+    fn next_spilled(&mut self, me: LocalNodeIndex) -> Option<Records> {
+        let mut n = self.nodes[me].borrow_mut();
+        if !n.is_internal() || !n.is_join() {
+            return None;
+        }
+        self.process_times.start(me);
+        self.process_ptimes.start(me);
+        let mut malformed = Vec::new();
+        let rs = n.process_spilled(&mut self.state, &self.nodes, &mut malformed);
+        self.process_ptimes.stop();
+        self.process_times.stop();
+        if !malformed.is_empty() {
+            reject_malformed(
+                self.malformed,
+                &n,
+                malformed,
+                &self.nodes,
+                &self.node_metrics[me],
+                &self.dead_letters,
+                &self.log,
+            );
+        }
+        rs
+    }
+
+    fn dispatch_to_children(
+        &mut self,
+        me: LocalNodeIndex,
+        m: Box<Packet>,
+        enable_output: bool,
+        sends: &mut EnqueuedSends,
+        output_messages: &mut FnvHashMap<LocalNodeIndex, Vec<Record>>,
+    ) {
+        if m.is_empty() && !m.has_markers() {
+            // no need to deal with our children if we're not sending them anything
+            return;
         }
 
+        let mut m = Some(m);
         let nchildren = self.nodes[me].borrow().nchildren();
+        if nchildren > 2 {
+            // a clone copies the records of a small batch, which only beats sharing them if there
+            // are just a couple of children.
+            m.as_mut().unwrap().map_data(|rs| rs.share());
+        }
         for i in 0..nchildren {
-            // avoid cloning if we can
+            // avoid cloning if we can. the children share the records of the clones, and each one
+            // only gets a copy of its own if it changes them.
             let mut m = if i == nchildren - 1 {
                 m.take().unwrap()
             } else {
@@ -678,10 +1133,10 @@ impl Domain {
                     }
                 }
             } else {
-                let mut data = m.take_data();
+                let data = m.take_data();
                 match output_messages.entry(childi) {
                     Entry::Occupied(entry) => {
-                        entry.into_mut().append(&mut data);
+                        entry.into_mut().extend(data);
                     }
                     Entry::Vacant(entry) => {
                         entry.insert(data.into());
@@ -689,8 +1144,30 @@ impl Domain {
                 };
             }
         }
+    }
 
-        output_messages
+    /// Let reads find the state of the given reader, unless the reader is hidden, in which case
+    /// its state is held back until the reader is exposed.
+    fn publish_reader(
+        &mut self,
+        node: LocalNodeIndex,
+        gid: NodeIndex,
+        handle: backlog::SingleReadHandle,
+        hidden: bool,
+    ) {
+        self.domain_metrics
+            .add_reader(node, handle.metrics().clone());
+        if hidden {
+            self.hidden_readers.insert(node, (gid, handle));
+        } else {
+            let shard = *self.shard.as_ref().unwrap_or(&0);
+            assert!(self
+                .readers
+                .lock()
+                .unwrap()
+                .insert((gid, shard), handle)
+                .is_none());
+        }
     }
 
     fn handle(
@@ -703,23 +1180,53 @@ impl Domain {
         self.wait_time.stop();
         m.trace(PacketEvent::Handle);
 
+        let refused = self.refuse_control(&m);
         match *m {
-            Packet::Message { .. } | Packet::Input { .. } => {
-                // WO for https://github.com/rust-lang/rfcs/issues/1403
-                self.dispatch(m, true, sends, Some(executor));
+            _ if refused => {}
+            Packet::Message { link, seq, .. } => {
+                let deliver = match seq {
+                    Some(seq) => self.in_sequence(link, seq),
+                    None => true,
+                };
+                if deliver {
+                    if let (true, Some(mv)) = (top, self.moving.as_mut()) {
+                        // the copy processes the update too, but doesn't send on what comes of it
+                        mv.log.push(Box::new(m.clone_data()));
+                        mv.logged += 1;
+                    }
+                    // WO for https://github.com/rust-lang/rfcs/issues/1403
+                    self.dispatch(m, true, sends, Some(executor));
+                }
+            }
+            Packet::Input { .. } => {
+                // large writes (e.g., bulk loads) are applied and sent downstream piece by piece,
+                // so that no single message we send is larger than a replay batch.
+                for m in split_input(m, BATCH_SIZE) {
+                    self.dispatch(m, true, sends, Some(&mut *executor));
+                }
             }
             Packet::ReplayPiece { .. } => {
                 self.handle_replay(m, sends);
             }
-            Packet::Evict { .. } | Packet::EvictKeys { .. } => {
+            Packet::Evict { .. } | Packet::EvictKeys { .. } | Packet::ForceEvict { .. } => {
                 self.handle_eviction(m, sends);
             }
             consumed => {
+                debug!(self.log, "received control packet"; "packet" => consumed.kind());
                 match consumed {
                     // workaround #16223
                     Packet::AddNode { node, parents } => {
                         let addr = node.local_addr();
+                        debug!(self.log, "node added";
+                               "local" => addr.id(),
+                               "global" => node.global_addr().index(),
+                               "name" => node.name(),
+                               "parents" => parents.len());
                         self.not_ready.insert(addr);
+                        let m = self
+                            .domain_metrics
+                            .add_node(addr, node.global_addr(), node.name());
+                        self.node_metrics.insert(addr, m);
 
                         for p in parents {
                             self.nodes
@@ -728,13 +1235,44 @@ impl Domain {
                                 .borrow_mut()
                                 .add_child(node.local_addr());
                         }
+                        if has_ttl(&node) && !self.timers.is_set(TimerOwner::Domain, SWEEP_EXPIRED)
+                        {
+                            let every = self.expiry_sweep_interval;
+                            self.schedule_expiry_sweep(every);
+                        }
                         self.nodes.insert(addr, cell::RefCell::new(node));
-                        trace!(self.log, "new node incorporated"; "local" => addr.id());
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::ack())
+                            .unwrap();
                     }
                     Packet::RemoveNodes { nodes } => {
+                        self.replay_streams.retain(|r| !nodes.contains(&r.from));
+                        self.received
+                            .retain(|&(ingress, _), _| !nodes.contains(&ingress));
                         for &node in &nodes {
-                            self.nodes[node].borrow_mut().remove();
+                            let mut n = self.nodes[node].borrow_mut();
+                            if n.is_reader() {
+                                // so that reads can no longer find the removed reader's state,
+                                // including reads meant for readers that it took over from. reads
+                                // meant for it may have been taken over by another reader, and
+                                // should keep going there.
+                                let readers = &self.readers;
+                                n.with_reader(|r| {
+                                    if let Some(w) = r.writer() {
+                                        readers.lock().unwrap().retain(|_, h| !w.serves(h));
+                                    }
+                                })
+                                .unwrap();
+                                self.hidden_readers.remove(node);
+                                self.unpublished_readers.remove(&node);
+                            }
+                            n.remove();
                             self.state.remove(node);
+                            self.aux.remove(node);
+                            self.timers.cancel_all(TimerOwner::Node(node));
+                            self.wal.remove(n.global_addr());
+                            self.domain_metrics.remove_node(node);
+                            self.node_metrics.remove(node);
                             trace!(self.log, "node removed"; "local" => node.id());
                         }
 
@@ -743,24 +1281,32 @@ impl Domain {
                                 cn.1.borrow_mut().try_remove_child(node);
                             }
                         }
+                        self.update_gauges_below();
                     }
                     Packet::AddBaseColumn {
                         node,
                         field,
                         default,
                     } => {
-                        let mut n = self.nodes[node].borrow_mut();
-                        n.add_column(&field);
-                        if let Some(b) = n.get_base_mut() {
-                            b.add_column(default);
-                        } else if n.is_ingress() {
-                            self.ingress_inject
-                                .entry(node)
-                                .or_insert_with(|| (n.fields().len(), Vec::new()))
-                                .1
-                                .push(default);
-                        } else {
-                            unreachable!("node unrelated to base got AddBaseColumn");
+                        let is_base = {
+                            let mut n = self.nodes[node].borrow_mut();
+                            n.add_column(&field);
+                            if let Some(b) = n.get_base_mut() {
+                                b.add_column(default);
+                                true
+                            } else if n.is_ingress() {
+                                self.ingress_inject
+                                    .entry(node)
+                                    .or_insert_with(|| (n.fields().len(), Vec::new()))
+                                    .1
+                                    .push(default);
+                                false
+                            } else {
+                                unreachable!("node unrelated to base got AddBaseColumn");
+                            }
+                        };
+                        if is_base {
+                            self.backfill_base_columns(node, sends);
                         }
                         self.control_reply_tx
                             .send(ControlReplyPacket::ack())
@@ -789,29 +1335,277 @@ impl Domain {
                                 e.add_tag(new_tag.0, new_tag.1);
                             }
                         });
+                        drop(n);
+                        self.update_gauges_below();
+                    }
+                    Packet::ExposeReaders { nodes } => {
+                        // hold the lock throughout, so that reads see all the readers or none
+                        let shard = *self.shard.as_ref().unwrap_or(&0);
+                        let mut readers = self.readers.lock().unwrap();
+                        for node in nodes {
+                            let (gid, handle) = self
+                                .hidden_readers
+                                .remove(node)
+                                .expect("asked to expose a reader that isn't hidden");
+                            self.nodes[node]
+                                .borrow_mut()
+                                .with_reader_mut(|r| r.set_hidden(false))
+                                .unwrap();
+                            assert!(readers.insert((gid, shard), handle).is_none());
+                        }
+                        drop(readers);
+
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::ack())
+                            .unwrap();
+                    }
+                    Packet::TakeOverReader { node, from } => {
+                        let shard = *self.shard.as_ref().unwrap_or(&0);
+                        let gid = self.nodes[node].borrow().global_addr();
+                        let mut readers = self.readers.lock().unwrap();
+                        let handle = readers
+                            .get(&(gid, shard))
+                            .expect("asked to take over with a reader that can't be read")
+                            .clone();
+                        if let Some(old) = readers.get(&(from, shard)).cloned() {
+                            // reads meant for readers that the old reader had itself taken over
+                            // from should also come here from now on
+                            for h in readers.values_mut() {
+                                if h.same_as(&old) {
+                                    *h = handle.clone();
+                                }
+                            }
+                            // and anyone holding on to the old reader's handle should look again
+                            old.retire();
+                        }
+                        readers.insert((from, shard), handle);
+                        drop(readers);
+
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::ack())
+                            .unwrap();
+                    }
+                    Packet::RemoveEgressTx { node, target } => {
+                        self.nodes[node]
+                            .borrow_mut()
+                            .with_egress_mut(move |e| e.remove_tx(target));
+                        self.update_gauges_below();
                     }
                     Packet::UpdateSharder { node, new_txs } => {
-                        let mut n = self.nodes[node].borrow_mut();
-                        n.with_sharder_mut(move |s| {
+                        self.nodes[node].borrow_mut().with_sharder_mut(move |s| {
                             s.add_sharded_child(new_txs.0, new_txs.1);
                         });
+                        self.update_gauges_below();
                     }
                     Packet::AddStreamer { node, new_streamer } => {
                         let mut n = self.nodes[node].borrow_mut();
                         n.with_reader_mut(|r| r.add_streamer(new_streamer).unwrap())
                             .unwrap();
                     }
+                    Packet::AddIndex { node, columns } => {
+                        let state = self
+                            .state
+                            .get_mut(node)
+                            .expect("asked to add index to node without state");
+                        assert!(!state.is_partial(), "asked to add full index to partial state");
+                        info!(self.log, "adding index to existing state";
+                              "node" => node.id(),
+                              "key" => ?columns,
+                              "rows" => state.rows());
+                        // the state builds the index from the rows it already has
+                        state.add_key(&columns[..], None);
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::ack())
+                            .unwrap();
+                    }
+                    Packet::ModifyOperator {
+                        node,
+                        parent,
+                        operator,
+                        fields,
+                    } => {
+                        let rows = self
+                            .state
+                            .get(parent)
+                            .expect("asked to modify operator whose parent has no state")
+                            .cloned_records();
+                        let rs = {
+                            let mut n = self.nodes[node].borrow_mut();
+                            let rs = n.changes_to(&operator, &rows[..]);
+                            n.replace_operator(operator);
+                            for f in &fields {
+                                n.add_column(f);
+                            }
+                            rs
+                        };
+                        if !fields.is_empty() {
+                            let children = self.nodes[node].borrow().children().to_vec();
+                            for child in children {
+                                let mut c = self.nodes[child].borrow_mut();
+                                for f in &fields {
+                                    c.add_column(f);
+                                }
+                            }
+                        }
+                        info!(self.log, "modified operator in place";
+                              "node" => node.id(),
+                              "added" => fields.len(),
+                              "corrections" => rs.len());
+                        self.emit_from(node, rs, sends);
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::ack())
+                            .unwrap();
+                    }
+                    Packet::AddUnionParent {
+                        node,
+                        parent,
+                        operator,
+                    } => {
+                        self.nodes[node].borrow_mut().replace_operator(operator);
+                        {
+                            // a new ingress already knows its children
+                            let mut p = self.nodes[parent].borrow_mut();
+                            if !p.children().contains(&node) {
+                                p.add_child(node);
+                            }
+                        }
+
+                        // a parent in another domain is fed through its (new) ingress instead
+                        if !self.nodes[parent].borrow().is_ingress() {
+                            let rows = self
+                                .state
+                                .get(parent)
+                                .expect("asked to add a union parent that has no state")
+                                .cloned_records();
+                            let rs: Records = rows
+                                .into_iter()
+                                .map(|r| self.seed_row(parent, Cow::Owned(r)))
+                                .collect();
+                            info!(self.log, "adding union parent";
+                                  "node" => node.id(),
+                                  "parent" => parent.id(),
+                                  "rows" => rs.len());
+                            let m = box Packet::Message {
+                                link: Link::new(parent, node),
+                                src: None,
+                                data: rs,
+                                tracer: None,
+                                senders: Vec::new(),
+                                written: None,
+                                seq: None,
+                                markers: Vec::new(),
+                            };
+                            self.dispatch(m, true, sends, None);
+                        } else {
+                            info!(self.log, "adding union parent";
+                                  "node" => node.id(),
+                                  "ingress" => parent.id());
+                        }
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::ack())
+                            .unwrap();
+                    }
+                    Packet::FeedEgress {
+                        node,
+                        source,
+                        new_tx: (dst_g, dst_l, addr),
+                    } => {
+                        let rows = self
+                            .state
+                            .get(source)
+                            .expect("asked to feed an egress whose parent has no state")
+                            .cloned_records();
+                        let rs: Records = rows
+                            .into_iter()
+                            .map(|r| self.seed_row(source, Cow::Owned(r)))
+                            .collect();
+                        info!(self.log, "feeding new ingress";
+                              "egress" => node.id(),
+                              "ingress" => dst_g.index(),
+                              "rows" => rs.len());
+                        let m = box Packet::Message {
+                            link: Link::new(source, node),
+                            src: None,
+                            data: rs,
+                            tracer: None,
+                            senders: Vec::new(),
+                            written: None,
+                            seq: None,
+                            markers: Vec::new(),
+                        };
+                        let shard = *self.shard.as_ref().unwrap_or(&0);
+                        self.nodes[node].borrow_mut().with_egress_mut(|e| {
+                            e.add_tx_after(dst_g, dst_l, addr, m, shard, sends)
+                        });
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::ack())
+                            .unwrap();
+                    }
+                    Packet::SeedBase { node, rows } => {
+                        let mut input = Input {
+                            dst: node,
+                            data: rows.into_iter().map(TableOperation::Insert).collect(),
+                            tracer: None,
+                            markers: Vec::new(),
+                        };
+                        let res = self
+                            .check_write(&mut input, None, sends, executor)
+                            .map(|_| input.data.len());
+                        match res {
+                            Ok(rows) => {
+                                info!(self.log, "seeding base";
+                                      "node" => node.id(),
+                                      "rows" => rows);
+                                // the base's children aren't ready yet, so the rows only reach them
+                                // through the replays that fill their state
+                                let m = box Packet::Input {
+                                    inner: LocalOrNot::new(input),
+                                    src: None,
+                                    senders: Vec::new(),
+                                };
+                                self.handle(m, sends, executor, true);
+                            }
+                            Err(ref e) => {
+                                error!(self.log, "rejecting seed";
+                                       "node" => node.id(),
+                                       "error" => %e);
+                            }
+                        }
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::Seeded(res))
+                            .unwrap();
+                    }
                     Packet::StateSizeProbe { node } => {
                         let row_count = self.state.get(node).map(|state| state.rows()).unwrap_or(0);
                         let mem_size = self
                             .state
                             .get(node)
                             .map(|state| state.deep_size_of())
-                            .unwrap_or(0);
+                            .unwrap_or(0)
+                            + self.aux.get(node).map(|aux| aux.deep_size_of()).unwrap_or(0);
                         self.control_reply_tx
                             .send(ControlReplyPacket::StateSize(row_count, mem_size))
                             .unwrap();
                     }
+                    Packet::PrepareAuxState { node } => {
+                        // a node that is set up again, such as by a migration that failed and is
+                        // retried, keeps the auxiliary state it has
+                        if !self.aux.contains_key(node) {
+                            let aux = match self.checkpointed_aux.remove(node) {
+                                Some(aux) => {
+                                    info!(self.log, "restoring auxiliary state from checkpoint";
+                                          "node" => node.id(),
+                                          "rows" => aux.rows());
+                                    aux
+                                }
+                                None => AuxState::default(),
+                            };
+                            self.aux.insert(node, aux);
+                        }
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::ack())
+                            .unwrap();
+                    }
                     Packet::PrepareState { node, state } => {
                         use payload::InitialState;
                         match state {
@@ -827,9 +1621,21 @@ impl Domain {
                                     state.add_key(&key[..], Some(tags));
                                 }
                             }
+                            InitialState::Checkpointed(index) => {
+                                let saved = self
+                                    .checkpointed
+                                    .remove(node)
+                                    .expect("asked to restore node that isn't in checkpoint");
+                                info!(self.log, "restoring state from checkpoint";
+                                      "rows" => saved.rows.len());
+                                self.checkpointed_logs.insert(node, saved.logged);
+                                let state = self.restored_state(node, saved, &index);
+                                assert!(self.state.insert(node, state).is_none());
+                            }
                             InitialState::IndexedLocal(index) => {
                                 if !self.state.contains_key(node) {
-                                    self.state.insert(node, box MemoryState::default());
+                                    let s = self.new_state(node);
+                                    self.state.insert(node, s);
                                 }
                                 let state = self.state.get_mut(node).unwrap();
                                 for idx in index {
@@ -844,16 +1650,21 @@ impl Domain {
                                 key,
                                 trigger_domain: (trigger_domain, shards),
                             } => {
-                                use backlog;
                                 let k = key.clone(); // ugh
+                                let remote = cell::Cell::new(0);
                                 let txs = (0..shards)
                                     .map(|shard| {
                                         let key = key.clone();
                                         let (tx, rx) = futures::sync::mpsc::unbounded();
-                                        let sender = self
+                                        let builder = self
                                             .channel_coordinator
                                             .builder_for(&(trigger_domain, shard))
-                                            .unwrap()
+                                            .unwrap();
+                                        if !builder.is_local() {
+                                            remote.set(remote.get() + 1);
+                                        }
+                                        let sender = builder
+                                            .with_capacity(self.channels.replay)
                                             .build_async()
                                             .unwrap();
 
@@ -879,7 +1690,8 @@ impl Domain {
                                         tx
                                     })
                                     .collect::<Vec<_>>();
-                                let (r_part, w_part) =
+                                self.replay_connections += remote.get();
+                                let (mut r_part, mut w_part) =
                                     backlog::new_partial(cols, &k[..], move |miss| {
                                         let n = txs.len();
                                         let tx = if n == 1 {
@@ -893,46 +1705,49 @@ impl Domain {
                                     });
 
                                 let mut n = self.nodes[node].borrow_mut();
-                                n.with_reader_mut(|r| {
-                                    assert!(
-                                        self.readers
-                                            .lock()
-                                            .unwrap()
-                                            .insert(
-                                                (gid, *self.shard.as_ref().unwrap_or(&0)),
-                                                r_part
-                                            )
-                                            .is_none()
-                                    );
-
-                                    // make sure Reader is actually prepared to receive state
-                                    r.set_write_handle(w_part)
-                                })
-                                .unwrap();
+                                if n.with_reader(|r| r.memory_limit().is_some()).unwrap() {
+                                    backlog::track_reads(&mut r_part, &mut w_part);
+                                }
+                                // make sure Reader is actually prepared to receive state
+                                let hidden = n
+                                    .with_reader_mut(|r| {
+                                        r.set_write_handle(w_part);
+                                        r.is_hidden()
+                                    })
+                                    .unwrap();
+                                drop(n);
+                                self.publish_reader(node, gid, r_part, hidden);
                             }
                             InitialState::Global { gid, cols, key } => {
-                                use backlog;
-                                let (r_part, w_part) = backlog::new(cols, &key[..]);
-
                                 let mut n = self.nodes[node].borrow_mut();
-                                n.with_reader_mut(|r| {
-                                    assert!(
-                                        self.readers
-                                            .lock()
-                                            .unwrap()
-                                            .insert(
-                                                (gid, *self.shard.as_ref().unwrap_or(&0)),
-                                                r_part
-                                            )
-                                            .is_none()
-                                    );
+                                let (mut r_part, mut w_part) =
+                                    match n.with_reader(|r| r.index_type()) {
+                                        Ok(IndexType::BTreeMap) => {
+                                            backlog::new_ordered(cols, key[0])
+                                        }
+                                        _ => backlog::new(cols, &key[..]),
+                                    };
+                                if let Ok(Some(column)) = n.with_reader(|r| r.sorted_by()) {
+                                    backlog::keep_sorted(&mut r_part, &mut w_part, column);
+                                }
+                                if n.with_reader(|r| r.is_scannable()) == Ok(true) {
+                                    backlog::allow_scans(&mut r_part);
+                                }
 
-                                    // make sure Reader is actually prepared to receive state
-                                    r.set_write_handle(w_part)
-                                })
-                                .unwrap();
+                                // make sure Reader is actually prepared to receive state
+                                let hidden = n
+                                    .with_reader_mut(|r| {
+                                        r.set_write_handle(w_part);
+                                        r.is_hidden()
+                                    })
+                                    .unwrap();
+                                drop(n);
+                                self.publish_reader(node, gid, r_part, hidden);
                             }
                         }
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::ack())
+                            .unwrap();
                     }
                     Packet::SetupReplayPath {
                         tag,
@@ -959,6 +1774,7 @@ impl Domain {
                         }
 
                         use payload;
+                        let remote = cell::Cell::new(0);
                         let trigger = match trigger {
                             payload::TriggerEndpoint::None => TriggerEndpoint::None,
                             payload::TriggerEndpoint::Start(v) => TriggerEndpoint::Start(v),
@@ -966,9 +1782,15 @@ impl Domain {
                             payload::TriggerEndpoint::End(selection, domain) => {
                                 let shard = |shardi| {
                                     // TODO: make async
-                                    self.channel_coordinator
+                                    let builder = self
+                                        .channel_coordinator
                                         .builder_for(&(domain, shardi))
-                                        .unwrap()
+                                        .unwrap();
+                                    if !builder.is_local() {
+                                        remote.set(remote.get() + 1);
+                                    }
+                                    builder
+                                        .with_capacity(self.channels.replay)
                                         .build_sync()
                                         .unwrap()
                                 };
@@ -991,6 +1813,7 @@ impl Domain {
                                 TriggerEndpoint::End { ask_all, options }
                             }
                         };
+                        self.replay_connections += remote.get();
 
                         self.replay_paths.insert(
                             tag,
@@ -1041,12 +1864,16 @@ impl Domain {
                         );
                         self.seed_replay(tag, &key[..], sends);
                     }
-                    Packet::StartReplay { tag, from } => {
+                    Packet::StartReplay {
+                        tag,
+                        from,
+                        priority,
+                    } => {
                         use std::thread;
                         assert_eq!(self.replay_paths[&tag].source, Some(from));
 
                         let start = time::Instant::now();
-                        info!(self.log, "starting replay");
+                        info!(self.log, "starting replay"; "tag" => tag.id(), "from" => from.id());
 
                         // we know that the node is materialized, as the migration coordinator
                         // picks path that originate with materialized nodes. if this weren't the
@@ -1055,12 +1882,15 @@ impl Domain {
                         //
                         // we clone the entire state so that we can continue to occasionally
                         // process incoming updates to the domain without disturbing the state that
-                        // is being replayed.
-                        let state = self
-                            .state
-                            .get(from)
-                            .expect("migration replay path started with non-materialized node")
-                            .cloned_records();
+                        // is being replayed. state that lives on disk is instead streamed out of a
+                        // snapshot, since it may well not fit in memory.
+                        let (snapshot, rows) = {
+                            let state = self
+                                .state
+                                .get(from)
+                                .expect("migration replay path started with non-materialized node");
+                            (state.snapshot(), state.rows())
+                        };
 
                         debug!(self.log,
                                "current state cloned for replay";
@@ -1069,6 +1899,27 @@ impl Domain {
 
                         let link = Link::new(from, self.replay_paths[&tag].path[0].node);
 
+                        // the event loop sends streamed rows a chunk at a time, and so won't get
+                        // to them until we have told the target domain to start buffering below.
+                        // a copy of the state is streamed too if the replay has to make way for
+                        // live updates, since only the event loop knows when there are some.
+                        let (state, last) = match snapshot {
+                            Snapshot::Cloned(state) => {
+                                if priority == ReplayPriority::FavorReplay {
+                                    let last = state.is_empty();
+                                    (state, last)
+                                } else {
+                                    let rows = RowStream::new(state.into_iter());
+                                    let last = self.stream_replay(tag, &link, from, rows, priority);
+                                    (Vec::new(), last)
+                                }
+                            }
+                            Snapshot::Streamed(rows) => {
+                                let last = self.stream_replay(tag, &link, from, rows, priority);
+                                (Vec::new(), last)
+                            }
+                        };
+
                         // we're been given an entire state snapshot, but we need to digest it
                         // piece by piece spawn off a thread to do that chunking. however, before
                         // we spin off that thread, we need to send a single Replay message to tell
@@ -1080,35 +1931,25 @@ impl Domain {
                             tag: tag,
                             link: link.clone(),
                             context: ReplayPieceContext::Regular {
-                                last: state.is_empty(),
+                                last,
+                                rows: Some(rows),
+                                cut: None,
                             },
                             data: Vec::<Record>::new().into(),
                         };
 
+                        // whatever the replay brings about, including its completion, is reported
+                        // after this
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::ack())
+                            .unwrap();
+
                         if !state.is_empty() {
                             let log = self.log.new(o!());
-
-                            let added_cols = self.ingress_inject.get(from).cloned();
-                            let default = {
-                                let n = self.nodes[from].borrow();
-                                let mut default = None;
-                                if let Some(b) = n.get_base() {
-                                    let mut row = Vec::new();
-                                    b.fix(&mut row);
-                                    default = Some(row);
-                                }
-                                default
-                            };
-                            let fix = move |mut r: Vec<DataType>| -> Vec<DataType> {
-                                if let Some((start, ref added)) = added_cols {
-                                    let rlen = r.len();
-                                    r.extend(added.iter().skip(rlen - start).cloned());
-                                } else if let Some(ref defaults) = default {
-                                    let rlen = r.len();
-                                    r.extend(defaults.iter().skip(rlen).cloned());
-                                }
-                                r
-                            };
+                            let fix = self.replay_fixer(from);
+                            let batch_size = self.replay_batch_size();
+                            let dead_letters = self.dead_letters.clone();
+                            let metrics = self.domain_metrics.clone();
 
                             let replay_tx_desc = self
                                 .channel_coordinator
@@ -1128,44 +1969,25 @@ impl Domain {
                                     link.src
                                 ))
                                 .spawn(move || {
-                                    use itertools::Itertools;
-
                                     // TODO: make async
-                                    let mut chunked_replay_tx =
-                                        replay_tx_desc.build_sync().unwrap();
-
-                                    let start = time::Instant::now();
-                                    debug!(log, "starting state chunker"; "node" => %link.dst);
-
-                                    let iter = state.into_iter().chunks(BATCH_SIZE);
-                                    let mut iter = iter.into_iter().enumerate().peekable();
-
-                                    // process all records in state to completion within domain
-                                    // and then forward on tx (if there is one)
-                                    while let Some((i, chunk)) = iter.next() {
-                                        use std::iter::FromIterator;
-                                        let chunk = Records::from_iter(chunk.into_iter().map(&fix));
-                                        let len = chunk.len();
-                                        let last = iter.peek().is_none();
-                                        let p = box Packet::ReplayPiece {
-                                            tag: tag,
-                                            link: link.clone(), // to is overwritten by receiver
-                                            context: ReplayPieceContext::Regular { last },
-                                            data: chunk,
-                                        };
-
-                                        trace!(log, "sending batch"; "#" => i, "[]" => len);
-                                        if chunked_replay_tx.send(p).is_err() {
-                                            warn!(log, "replayer noticed domain shutdown");
-                                            break;
-                                        }
+                                    match replay_tx_desc.build_sync() {
+                                        Ok(mut tx) => send_replay_chunks(
+                                            state.into_iter().map(fix),
+                                            tag,
+                                            &link,
+                                            batch_size,
+                                            &mut *tx,
+                                            &dead_letters,
+                                            &metrics,
+                                            &log,
+                                        ),
+                                        Err(e) => dead_letters.post(
+                                            dead_letters.to_self(),
+                                            "ReplayPiece",
+                                            state.len(),
+                                            &e,
+                                        ),
                                     }
-
-                                    debug!(log,
-                                   "state chunker finished";
-                                   "node" => %link.dst,
-                                   "μs" => start.elapsed().as_micros()
-                                );
                                 })
                                 .unwrap();
                         }
@@ -1177,26 +1999,12 @@ impl Domain {
                     }
                     Packet::Ready { node, index } => {
                         assert_eq!(self.mode, DomainMode::Forwarding);
+                        debug!(self.log, "node ready";
+                               "local" => node.id(),
+                               "materialized" => !index.is_empty());
 
                         if !index.is_empty() {
-                            let mut s: Box<State> = {
-                                let n = self.nodes[node].borrow();
-                                let params = &self.persistence_parameters;
-                                match (n.get_base(), &params.mode) {
-                                    (Some(base), &DurabilityMode::DeleteOnExit)
-                                    | (Some(base), &DurabilityMode::Permanent) => {
-                                        let base_name = format!(
-                                            "{}-{}-{}",
-                                            params.log_prefix,
-                                            n.name(),
-                                            self.shard.unwrap_or(0),
-                                        );
-
-                                        box PersistentState::new(base_name, base.key(), &params)
-                                    }
-                                    _ => box MemoryState::default(),
-                                }
-                            };
+                            let mut s = self.new_state(node);
                             for idx in index {
                                 s.add_key(&idx[..], None);
                             }
@@ -1229,16 +2037,33 @@ impl Domain {
                             .send(ControlReplyPacket::ack())
                             .unwrap();
                     }
+                    Packet::GetMetrics => {
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::Metrics(self.domain_metrics.snapshot()))
+                            .unwrap();
+                    }
                     Packet::GetStatistics => {
                         let domain_stats = noria::debug::stats::DomainStats {
                             total_time: self.total_time.num_nanoseconds(),
                             total_ptime: self.total_ptime.num_nanoseconds(),
                             wait_time: self.wait_time.num_nanoseconds(),
+                            queued_packets: self.queued_packets() as u64,
+                            coalesced_packets: self
+                                .nodes
+                                .values()
+                                .filter_map(|n| n.borrow().with_egress(|e| e.coalesced()))
+                                .sum(),
+                            channels: self.channel_stats(),
+                            time_budget: self
+                                .time_budget
+                                .stats(|ni| self.nodes[ni].borrow().global_addr()),
+                            paused: self.paused,
                         };
 
                         let node_stats = self
                             .nodes
                             .values()
+                            .filter(|nd| !nd.borrow().is_dropped())
                             .filter_map(|nd| {
                                 let ref n = *nd.borrow();
                                 let local_index = n.local_addr();
@@ -1257,6 +2082,40 @@ impl Domain {
                                         .map(|state| state.deep_size_of())
                                         .unwrap_or(0)
                                 };
+                                let aux_bytes = self
+                                    .aux
+                                    .get(local_index)
+                                    .map(|aux| aux.deep_size_of())
+                                    .unwrap_or(0);
+
+                                let state_size = if n.is_reader() {
+                                    None
+                                } else {
+                                    self.state.get(local_index).map(|s| s.size_stats())
+                                };
+
+                                let (evicted_keys, evicted_bytes) = if n.is_reader() {
+                                    n.with_reader(|r| r.evictions()).unwrap()
+                                } else {
+                                    (0, 0)
+                                };
+
+                                let spilled_keys = if n.is_internal() {
+                                    n.spilled_keys()
+                                } else {
+                                    0
+                                };
+
+                                let (peak_records_out, peak_fanout) = self
+                                    .node_metrics
+                                    .get(local_index)
+                                    .map(|m| m.peaks())
+                                    .unwrap_or((0, 0));
+                                let malformed_records = self
+                                    .node_metrics
+                                    .get(local_index)
+                                    .map(|m| m.malformed_records())
+                                    .unwrap_or(0);
 
                                 let mat_state = if !n.is_reader() {
                                     match self.state.get(local_index) {
@@ -1285,10 +2144,23 @@ impl Domain {
                                         node_index,
                                         noria::debug::stats::NodeStats {
                                             desc: format!("{:?}", n),
+                                            processed_records: self
+                                                .processed_records
+                                                .get(local_index)
+                                                .cloned()
+                                                .unwrap_or(0),
                                             process_time: time.unwrap(),
                                             process_ptime: ptime.unwrap(),
-                                            mem_size: mem_size,
+                                            mem_size: mem_size + aux_bytes,
+                                            aux_bytes,
                                             materialized: mat_state,
+                                            evicted_keys,
+                                            evicted_bytes,
+                                            state_size,
+                                            spilled_keys,
+                                            peak_records_out: peak_records_out as u64,
+                                            peak_fanout: peak_fanout as u64,
+                                            malformed_records: malformed_records as u64,
                                         },
                                     ))
                                 } else {
@@ -1304,6 +2176,321 @@ impl Domain {
                     Packet::UpdateStateSize => {
                         self.update_state_sizes();
                     }
+                    Packet::GetChecksum { node } => {
+                        let checksum = {
+                            let n = self.nodes[node].borrow();
+                            if n.is_reader() {
+                                n.with_reader(|r| r.checksum()).unwrap()
+                            } else {
+                                self.state.get(node).map(|s| s.checksum())
+                            }
+                        };
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::Checksum(checksum))
+                            .unwrap();
+                    }
+                    Packet::GetDigest { node } => {
+                        let digest = {
+                            let n = self.nodes[node].borrow();
+                            if n.is_reader() {
+                                n.with_reader(|r| Some((r.rows()?, r.checksum()?)))
+                                    .unwrap()
+                            } else {
+                                self.state.get(node).map(|s| (s.rows(), s.checksum()))
+                            }
+                        };
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::Digest(digest))
+                            .unwrap();
+                    }
+                    Packet::GetRows { node } => {
+                        let rows = {
+                            let n = self.nodes[node].borrow();
+                            if n.is_reader() {
+                                n.with_reader(|r| r.cloned_rows()).unwrap()
+                            } else {
+                                self.state
+                                    .get(node)
+                                    .filter(|s| !s.is_partial())
+                                    .map(|s| s.cloned_records())
+                            }
+                        };
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::Rows(rows))
+                            .unwrap();
+                    }
+                    Packet::DrainDeadLetters => {
+                        let letters = self.dead_letters.drain_all();
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::DeadLetters(letters))
+                            .unwrap();
+                    }
+                    Packet::HoldWrites(hold) => {
+                        debug!(self.log, "holding back writes"; "hold" => hold);
+                        self.writes_held = hold;
+                    }
+                    Packet::Pause => {
+                        info!(self.log, "pausing domain");
+                        self.paused = true;
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::ack())
+                            .unwrap();
+                    }
+                    Packet::Resume => {
+                        // the packets held on to are processed once this one has been handled
+                        info!(self.log, "resuming domain";
+                              "held" => self.paused_packets.len());
+                        self.paused = false;
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::ack())
+                            .unwrap();
+                    }
+                    Packet::StartMove { control_addr } => {
+                        let reply = match self.move_builder(control_addr) {
+                            Ok(builder) => {
+                                info!(self.log, "starting move";
+                                      "rows" => builder.snapshot.as_ref().map_or(0, |s| s.rows()));
+                                self.moving = Some(Move {
+                                    log: Vec::new(),
+                                    logged: 0,
+                                    to: None,
+                                    senders: None,
+                                    redirected: 0,
+                                });
+                                ControlReplyPacket::Snapshot(Box::new(builder))
+                            }
+                            Err(e) => {
+                                error!(self.log, "failed to copy nodes to move"; "error" => ?e);
+                                ControlReplyPacket::Failed(ControlError::NotMoving)
+                            }
+                        };
+                        self.control_reply_tx.send(reply).unwrap();
+                    }
+                    Packet::ShipMove { to } => {
+                        // the logged updates are sent once this packet has been handled, as are
+                        // those held on to until the copy was up
+                        let reply = match self.moving {
+                            Some(ref mut mv) => {
+                                match channel::DomainConnectionBuilder::for_domain(to)
+                                    .with_capacity(self.channels.domain)
+                                    .build_sync()
+                                {
+                                    Ok(tx) => {
+                                        info!(self.log, "connected to copy";
+                                              "addr" => ?to, "logged" => mv.log.len());
+                                        mv.to = Some(tx);
+                                        None
+                                    }
+                                    Err(e) => {
+                                        error!(self.log, "failed to connect to copy";
+                                               "addr" => ?to, "error" => ?e);
+                                        Some(ControlReplyPacket::Failed(ControlError::NotMoving))
+                                    }
+                                }
+                            }
+                            None => Some(ControlReplyPacket::Failed(ControlError::NotMoving)),
+                        };
+                        if reply.is_some() {
+                            self.moving = None;
+                        }
+                        self.control_reply_tx
+                            .send(reply.unwrap_or_else(ControlReplyPacket::ack))
+                            .unwrap();
+                    }
+                    Packet::CancelMove => {
+                        if self.moving.take().is_some() {
+                            info!(self.log, "cancelled move");
+                        }
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::ack())
+                            .unwrap();
+                    }
+                    Packet::FinishMove { senders } => {
+                        debug!(self.log, "told to finish move"; "senders" => senders);
+                        match self.moving {
+                            Some(ref mut mv) => mv.senders = Some(senders),
+                            None => {
+                                self.control_reply_tx
+                                    .send(ControlReplyPacket::Failed(ControlError::NotMoving))
+                                    .unwrap();
+                            }
+                        }
+                        self.finish_move();
+                    }
+                    Packet::Redirected { from } => {
+                        trace!(self.log, "sender redirected to copy";
+                               "domain" => from.0.index(), "shard" => from.1);
+                        if let Some(ref mut mv) = self.moving {
+                            mv.redirected += 1;
+                        }
+                        self.finish_move();
+                    }
+                    Packet::Redirect {
+                        domain,
+                        shard,
+                        from,
+                        to,
+                    } => {
+                        info!(self.log, "redirecting to moved domain";
+                              "domain" => domain.index(), "shard" => shard, "addr" => ?to);
+                        // the packet that says so goes out before anything else is sent to the
+                        // copy, and the worker connects to the copy once it has
+                        let me = (self.index, self.shard.unwrap_or(0));
+                        sends
+                            .entry((domain, shard))
+                            .or_default()
+                            .push_back(Box::new(Packet::Redirected { from: me }));
+                        self.redirects.push(((domain, shard), from, to));
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::ack())
+                            .unwrap();
+                    }
+                    Packet::CatchUp { packets, last } => {
+                        debug!(self.log, "catching up on logged updates";
+                               "updates" => packets.len(), "last" => last);
+                        // the domain that this is a copy of has already sent on what comes of
+                        // these, so it's thrown away
+                        let mut discarded = EnqueuedSends::default();
+                        for m in packets {
+                            self.handle(m, &mut discarded, executor, true);
+                        }
+                        if last {
+                            info!(self.log, "caught up with moved domain";
+                                  "held" => self.paused_packets.len());
+                            self.paused = false;
+                            self.control_reply_tx
+                                .send(ControlReplyPacket::ack())
+                                .unwrap();
+                        }
+                    }
+                    Packet::Quiesce { id, senders } => {
+                        debug!(self.log, "told to quiesce"; "barrier" => id, "senders" => senders);
+                        if let Some(b) = self.barrier_for(id) {
+                            b.senders = Some(senders);
+                        }
+                    }
+                    Packet::Barrier { id, from } => {
+                        trace!(self.log, "received barrier";
+                               "barrier" => id,
+                               "domain" => from.0.index(),
+                               "shard" => from.1);
+                        if let Some(b) = self.barrier_for(id) {
+                            b.arrived += 1;
+                        }
+                    }
+                    Packet::AddStandby {
+                        node,
+                        primary,
+                        standby,
+                    } => {
+                        let mut added = Ok(());
+                        self.nodes[node]
+                            .borrow_mut()
+                            .with_egress_mut(|e| added = e.add_standby(primary, standby));
+                        match added {
+                            Ok(()) => info!(self.log, "added standby";
+                                            "egress" => node.id(),
+                                            "primary" => primary.index(),
+                                            "standby" => standby.index()),
+                            Err(e) => error!(self.log, "failed to add standby";
+                                             "egress" => node.id(),
+                                             "error" => e),
+                        }
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::ack())
+                            .unwrap();
+                    }
+                    Packet::SwitchEgress { node, from, to } => {
+                        let mut switched = Err(String::new());
+                        self.nodes[node]
+                            .borrow_mut()
+                            .with_egress_mut(|e| switched = e.switch(from, to, sends));
+                        match switched {
+                            Ok(s) => self.switched(node, &s, None),
+                            Err(e) => error!(self.log, "failed to switch edge";
+                                             "egress" => node.id(),
+                                             "error" => e),
+                        }
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::ack())
+                            .unwrap();
+                    }
+                    Packet::ListNodes { reply_to } => {
+                        let mut nodes: Vec<_> = self
+                            .nodes
+                            .values()
+                            .map(|n| {
+                                let n = n.borrow();
+                                (n.global_addr(), n.local_addr())
+                            })
+                            .collect();
+                        nodes.sort();
+                        let reply = ControlReplyPacket::Nodes {
+                            shard: self.shard.unwrap_or(0),
+                            nodes,
+                        };
+                        // whoever asked may have given up on us already
+                        let sent = TcpSender::connect(&reply_to)
+                            .map_err(channel::tcp::SendError::from)
+                            .and_then(|mut tx| tx.send(reply));
+                        if let Err(e) = sent {
+                            warn!(self.log, "failed to list nodes";
+                                  "to" => ?reply_to,
+                                  "error" => ?e);
+                        }
+                    }
+                    Packet::Adopt { control_addr } => {
+                        info!(self.log, "taken over by another controller";
+                              "control" => ?control_addr);
+                        self.control_reply_tx =
+                            TcpSender::connect_with_capacity(&control_addr, self.channels.control)
+                                .unwrap();
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::ack())
+                            .unwrap();
+                    }
+                    Packet::Checkpoint { id } => {
+                        let written = match self.write_checkpoint(id) {
+                            Ok(nodes) => Some(nodes),
+                            Err(e) => {
+                                error!(self.log, "failed to write checkpoint";
+                                       "id" => id, "error" => ?e);
+                                None
+                            }
+                        };
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::Checkpointed(written))
+                            .unwrap();
+                    }
+                    Packet::LoadCheckpoint { id } => {
+                        let loaded = self.load_checkpoint(id);
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::Checkpointed(loaded))
+                            .unwrap();
+                    }
+                    Packet::RecoverBase { node } => {
+                        let recovered = match self.recover_base(node) {
+                            Ok(writes) => {
+                                info!(self.log, "recovered base from write-ahead log";
+                                      "node" => node.id(), "writes" => writes);
+                                Ok(writes)
+                            }
+                            Err(e) => {
+                                error!(self.log, "failed to recover base from write-ahead log";
+                                       "node" => node.id(), "error" => ?e);
+                                Err(e.to_string())
+                            }
+                        };
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::Recovered(recovered))
+                            .unwrap();
+                    }
+                    Packet::Capture { into } => {
+                        let capturing = self.capture_into(into);
+                        self.control_reply_tx
+                            .send(ControlReplyPacket::Capturing(capturing))
+                            .unwrap();
+                    }
                     Packet::Quit => unreachable!("Quit messages are handled by event loop"),
                     Packet::Spin => {
                         // spinning as instructed
@@ -1354,11 +2541,234 @@ impl Domain {
         self.wait_time.start();
     }
 
+    /// Extend the rows in a base's materialization with the defaults of columns added to it.
+    ///
+    /// The change is also sent downstream, as a retraction of each old row followed by its
+    /// extended version, so that materializations below the base keep holding the same rows as
+    /// the base itself. Otherwise, a later delete of an old row would retract the extended row,
+    /// which those materializations have never seen.
+    fn backfill_base_columns(&mut self, node: LocalNodeIndex, sends: &mut EnqueuedSends) {
+        let mut rs = Vec::new();
+        {
+            let n = self.nodes[node].borrow();
+            let b = n.get_base().unwrap();
+            let state = match self.state.get(node) {
+                Some(state) => state,
+                None => return,
+            };
+            for old in state.cloned_records() {
+                let mut new = old.clone();
+                b.fix(&mut new);
+                if new.len() != old.len() {
+                    rs.push(Record::Negative(old));
+                    rs.push(Record::Positive(new));
+                }
+            }
+        }
+        if rs.is_empty() {
+            return;
+        }
+
+        let rs: Records = rs.into();
+        {
+            let mut n = self.nodes[node].borrow_mut();
+            let state = self.state.get(node).map(|s| &**s);
+            n.get_base_mut()
+                .unwrap()
+                .track_expiry(&rs, time::Instant::now(), state);
+        }
+        self.emit_from(node, rs, sends);
+    }
+
+    /// How long until some reader must make the writes it holds visible, if any reader's policy
+    /// bounds how long they may wait.
+    fn duration_until_publish(&self) -> Option<time::Duration> {
+        if self.paused {
+            return None;
+        }
+        let now = time::Instant::now();
+        self.unpublished_readers
+            .iter()
+            .filter_map(|&ni| {
+                self.nodes[ni]
+                    .borrow()
+                    .with_reader(|r| r.publish_deadline())
+                    .ok()?
+            })
+            .min()
+            .map(|deadline| {
+                if deadline > now {
+                    deadline - now
+                } else {
+                    time::Duration::from_millis(0)
+                }
+            })
+    }
+
+    /// Make the writes that readers hold visible to reads wherever their policies say they are
+    /// due, given whether the domain has run out of messages to process.
+    fn publish_readers(&mut self, idle: bool) {
+        if self.unpublished_readers.is_empty() {
+            return;
+        }
+
+        let now = time::Instant::now();
+        let nodes = &self.nodes;
+        self.unpublished_readers.retain(|&ni| {
+            nodes[ni]
+                .borrow_mut()
+                .with_reader_mut(|r| {
+                    r.publish_if_due(now, idle);
+                    r.has_unpublished()
+                })
+                .unwrap_or(false)
+        });
+    }
+
+    /// How long until the next timer is due, unless the domain is paused, since timers don't fire
+    /// until it is resumed.
+    fn duration_until_timer(&self) -> Option<time::Duration> {
+        if self.paused {
+            None
+        } else {
+            self.timers.duration_until_due(time::Instant::now())
+        }
+    }
+
+    /// Fire the timers that are due, between the packets that the domain handles.
+    fn fire_timers(&mut self, sends: &mut EnqueuedSends) {
+        let now = time::Instant::now();
+        for (owner, token) in self.timers.take_due(now) {
+            match owner {
+                TimerOwner::Domain => {
+                    assert_eq!(token, SWEEP_EXPIRED);
+                    self.sweep_expired(sends);
+                }
+                TimerOwner::Node(node) => {
+                    if !self.nodes.contains_key(node) {
+                        continue;
+                    }
+                    let rs = {
+                        let mut n = self.nodes[node].borrow_mut();
+                        self.process_times.start(node);
+                        self.process_ptimes.start(node);
+                        let rs = n.process_timer(token, &self.state, &self.nodes);
+                        self.process_ptimes.stop();
+                        self.process_times.stop();
+                        self.timers.request(node, n.take_timers(), now);
+                        rs
+                    };
+
+                    let rs = match rs {
+                        Some(rs) => rs,
+                        None => continue,
+                    };
+                    if rs.is_empty() {
+                        continue;
+                    }
+                    if self.not_ready.contains(&node) {
+                        // a node that is not ready yet has no children that could take its output
+                        debug!(self.log, "dropping timer output of node that is not ready";
+                               "node" => %node, "token" => token);
+                        continue;
+                    }
+                    self.emit_from(node, rs, sends);
+                }
+            }
+        }
+    }
+
+    /// Remove rows that have outlived their TTL from bases.
+    fn sweep_expired(&mut self, sends: &mut EnqueuedSends) {
+        let now = time::Instant::now();
+        let bases: Vec<_> = self
+            .nodes
+            .iter()
+            .filter(|&(_, n)| has_ttl(&n.borrow()))
+            .map(|(ni, _)| ni)
+            .collect();
+
+        let mut backlogged = false;
+        for node in bases {
+            if self.not_ready.contains(&node) {
+                continue;
+            }
+
+            let rs = {
+                let mut n = self.nodes[node].borrow_mut();
+                let state = self.state.get(node).map(|s| &**s);
+                n.get_base_mut()
+                    .unwrap()
+                    .expire(now, self.expiry_batch_size, state)
+            };
+            if rs.is_empty() {
+                continue;
+            }
+
+            debug!(self.log, "expiring rows"; "node" => %node, "rows" => rs.len());
+            backlogged |= rs.len() == self.expiry_batch_size;
+            self.emit_from(node, rs, sends);
+        }
+
+        // if a base had more expired rows than we removed, sweep again right away
+        if backlogged {
+            self.schedule_expiry_sweep(time::Duration::from_millis(0));
+        }
+    }
+
+    /// Sweep bases with a TTL for expired rows `after` from now, and then every sweep interval.
+    fn schedule_expiry_sweep(&mut self, after: time::Duration) {
+        let every = Some(self.expiry_sweep_interval);
+        let now = time::Instant::now();
+        self.timers
+            .set(TimerOwner::Domain, SWEEP_EXPIRED, now, after, every);
+    }
+
+    /// Apply records that a node produced outside of the regular flow of updates, such as rows
+    /// that a base expired or the corrections for a modified operator, to its materialization, and
+    /// send them to the node's children.
+    fn emit_from(&mut self, node: LocalNodeIndex, mut rs: Records, sends: &mut EnqueuedSends) {
+        let unmatched = match self.state.get_mut(node) {
+            Some(state) => state.process_records(&mut rs, None),
+            None => Vec::new(),
+        };
+        if !unmatched.is_empty() {
+            let mut malformed = Vec::new();
+            note_unmatched(node, unmatched, &mut malformed);
+            reject_malformed(
+                self.malformed,
+                &self.nodes[node].borrow(),
+                malformed,
+                &self.nodes,
+                &self.node_metrics[node],
+                &self.dead_letters,
+                &self.log,
+            );
+        }
+
+        let written = Some(time::SystemTime::now());
+        let children = self.nodes[node].borrow().children().to_vec();
+        for child in children {
+            let m = box Packet::Message {
+                link: Link::new(node, child),
+                src: None,
+                data: rs.clone(),
+                tracer: None,
+                senders: Vec::new(),
+                written,
+                seq: None,
+                markers: Vec::new(),
+            };
+            self.dispatch(m, true, sends, None);
+        }
+    }
+
     fn seed_row<'a>(&self, source: LocalNodeIndex, row: Cow<'a, [DataType]>) -> Record {
         if let Some(&(start, ref defaults)) = self.ingress_inject.get(source) {
+            // rows that arrived after the columns were added already include them
             let mut v = Vec::with_capacity(start + defaults.len());
             v.extend(row.iter().cloned());
-            v.extend(defaults.iter().cloned());
+            v.extend(defaults.iter().skip(row.len() - start).cloned());
             return (v, true).into();
         }
 
@@ -1453,6 +2863,558 @@ impl Domain {
         }
     }
 
+    /// Create the (empty) full materialization for `node`.
+    ///
+    /// The state is kept on disk if the node asked for that, or if it is a base whose writes are
+    /// to be persisted. Only bases can be recovered from disk; other nodes always start out empty.
+    fn new_state(&self, node: LocalNodeIndex) -> Box<State> {
+        let n = self.nodes[node].borrow();
+        let params = &self.persistence_parameters;
+        let name = format!(
+            "{}-{}-{}",
+            params.log_prefix,
+            n.name(),
+            self.shard.unwrap_or(0),
+        );
+
+        match (n.get_base(), &params.mode) {
+            (Some(base), &DurabilityMode::DeleteOnExit)
+            | (Some(base), &DurabilityMode::Permanent) => {
+                box PersistentState::new(name, base.key(), &params)
+            }
+            (base, _) if n.state_backend() == StateBackend::Disk => {
+                let params = PersistenceParameters {
+                    mode: DurabilityMode::DeleteOnExit,
+                    ..params.clone()
+                };
+                box PersistentState::new(name, base.and_then(|b| b.key()), &params)
+            }
+            _ => box MemoryState::default(),
+        }
+    }
+
+    fn checkpoint_path(&self) -> PathBuf {
+        let params = &self.persistence_parameters;
+        let name = format!(
+            "{}-{}_{}.checkpoint",
+            params.log_prefix,
+            self.index.index(),
+            self.shard.unwrap_or(0),
+        );
+        match params.log_dir {
+            Some(ref dir) => dir.join(name),
+            None => PathBuf::from(name),
+        }
+    }
+
+    /// Write the state of every fully materialized node to this domain's checkpoint file, and
+    /// return the nodes that were saved. Partial state is left out, since it can always be
+    /// recomputed on demand.
+    fn write_checkpoint(&mut self, id: u64) -> bincode::Result<Vec<NodeIndex>> {
+        let nodes = self.saved_state();
+        let saved = nodes.iter().map(|s| s.node).collect();
+        let logged: Vec<_> = nodes.iter().map(|s| (s.node, s.logged)).collect();
+
+        // write to a temporary file first, so that a crash midway through doesn't leave behind a
+        // file that can't be read
+        let path = self.checkpoint_path();
+        let tmp = path.with_extension("checkpoint.tmp");
+        {
+            let mut f = io::BufWriter::new(fs::File::create(&tmp)?);
+            let aux = self.saved_aux();
+            bincode::serialize_into(&mut f, &Checkpoint { id, nodes, aux })?;
+            f.flush()?;
+            f.get_ref().sync_all()?;
+        }
+        fs::rename(&tmp, &path)?;
+        info!(self.log, "wrote checkpoint"; "id" => id, "path" => ?path);
+
+        // log entries that made it into the checkpoint are no longer needed to recover
+        for (node, upto) in logged {
+            match self.wal.truncate(node, upto) {
+                Ok(0) => {}
+                Ok(n) => debug!(self.log, "deleted checkpointed log segments";
+                                "node" => node.index(), "segments" => n),
+                Err(e) => warn!(self.log, "failed to delete checkpointed log segments";
+                                "node" => node.index(), "error" => ?e),
+            }
+        }
+        Ok(saved)
+    }
+
+    /// The state of every fully materialized node, as it would be saved in a checkpoint.
+    fn saved_state(&self) -> Vec<CheckpointedState> {
+        self.state
+            .iter()
+            .filter(|&(_, s)| !s.is_partial())
+            .map(|(ni, s)| {
+                let n = self.nodes[ni].borrow();
+                CheckpointedState {
+                    node: n.global_addr(),
+                    name: n.name().to_owned(),
+                    keys: s.keys(),
+                    rows: s.cloned_records(),
+                    logged: self.wal.logged(n.global_addr()),
+                }
+            })
+            .collect()
+    }
+
+    /// The auxiliary state of every node that keeps any, as it would be saved in a checkpoint.
+    fn saved_aux(&self) -> Vec<(NodeIndex, AuxState)> {
+        self.aux
+            .iter()
+            .map(|(ni, aux)| (self.nodes[ni].borrow().global_addr(), aux.clone()))
+            .collect()
+    }
+
+    /// New state for `node` that holds the given saved rows, and is indexed by the columns it was
+    /// saved with as well as by `index`.
+    fn restored_state(
+        &self,
+        node: LocalNodeIndex,
+        saved: CheckpointedState,
+        index: &HashSet<Vec<usize>>,
+    ) -> Box<State> {
+        let mut state = self.new_state(node);
+        for idx in saved.keys.iter().chain(index.iter()) {
+            state.add_key(&idx[..], None);
+        }
+        let mut rs: Records = saved.rows.into_iter().collect();
+        state.process_records(&mut rs, None);
+        state
+    }
+
+    /// Have the operator of `node`, whose state a full replay has just filled, rebuild its
+    /// auxiliary state from the rows it now holds, if it keeps any and chooses to.
+    fn rebuild_aux(&mut self, node: LocalNodeIndex) {
+        if !self.aux.contains_key(node) {
+            return;
+        }
+        let rebuilt = match self.state.get(node) {
+            Some(state) => self.nodes[node].borrow().rebuild_aux(&**state),
+            None => return,
+        };
+        if let Some(aux) = rebuilt {
+            debug!(self.log, "rebuilt auxiliary state";
+                   "node" => node.id(),
+                   "rows" => aux.rows());
+            self.aux.insert(node, aux);
+        }
+    }
+
+    /// The configuration that the domain was built with.
+    fn config(&self) -> Config {
+        Config {
+            concurrent_replays: self.max_concurrent_replays,
+            replay_batch_timeout: self.replay_batch_timeout,
+            expiry_sweep_interval: self.expiry_sweep_interval,
+            expiry_batch_size: self.expiry_batch_size,
+            replay_workers: self.replay_workers,
+            backpressure: self.backpressure.clone(),
+            channels: self.channels.clone(),
+            malformed: self.malformed,
+        }
+    }
+
+    /// Start writing every event the domain sees to a capture file in `into`, or stop if `into`
+    /// is `None`. Returns the file written to, if any.
+    fn capture_into(&mut self, into: Option<PathBuf>) -> Result<Option<PathBuf>, String> {
+        if let Some(capture) = self.capture.take() {
+            info!(self.log, "stopped capture";
+                  "path" => ?capture.path(), "events" => capture.events());
+        }
+        let into = match into {
+            Some(into) => into,
+            None => return Ok(None),
+        };
+
+        if self.state.values().any(|s| s.is_partial()) {
+            // partial state is filled by replays through other domains, which can't be re-run
+            return Err("domains with partial state can't be captured".to_owned());
+        }
+
+        let path = into.join(format!(
+            "{}_{}.capture",
+            self.index.index(),
+            self.shard.unwrap_or(0)
+        ));
+        let header = capture::Header {
+            index: self.index,
+            shard: self.shard,
+            nshards: self._nshards,
+            nodes: self.nodes.clone(),
+            persistence_parameters: self.persistence_parameters.clone(),
+            config: self.config(),
+            state: self.saved_state(),
+            not_ready: self.not_ready.clone(),
+            ingress_inject: self.ingress_inject.clone(),
+            aux: self.aux.clone(),
+        };
+        match capture::Capture::create(path.clone(), &header) {
+            Ok(capture) => {
+                info!(self.log, "started capture"; "path" => ?path);
+                self.capture = Some(capture);
+                Ok(Some(path))
+            }
+            Err(e) => {
+                error!(self.log, "failed to start capture"; "path" => ?path, "error" => ?e);
+                Err(format!("failed to write {:?}: {:?}", path, e))
+            }
+        }
+    }
+
+    /// The builder of a copy of the domain as it is now, which answers to the controller at
+    /// `control_addr`. The nodes are copied by way of serializing them, as they would be to send
+    /// them to a worker, since nodes that send to other domains can't be cloned.
+    fn move_builder(&self, control_addr: SocketAddr) -> bincode::Result<DomainBuilder> {
+        let nodes = bincode::deserialize(&bincode::serialize(&self.nodes)?)?;
+        Ok(DomainBuilder {
+            index: self.index,
+            shard: self.shard,
+            nshards: self._nshards,
+            nodes,
+            persistence_parameters: self.persistence_parameters.clone(),
+            control_addr,
+            config: self.config(),
+            snapshot: Some(Box::new(MoveSnapshot {
+                state: self.saved_state(),
+                received: self.received.clone(),
+                not_ready: self.not_ready.clone(),
+                ingress_inject: self.ingress_inject.clone(),
+                aux: self.aux.clone(),
+            })),
+        })
+    }
+
+    /// Pick up where the domain that this is a copy of was when the given snapshot was taken. The
+    /// copy stays paused until it has caught up on what that domain has processed since.
+    fn restore(&mut self, snapshot: MoveSnapshot) {
+        self.not_ready = snapshot.not_ready;
+        self.ingress_inject = snapshot.ingress_inject;
+        self.received = snapshot.received;
+        self.aux = snapshot.aux;
+        for saved in snapshot.state {
+            let node = self
+                .nodes
+                .values()
+                .map(|n| n.borrow())
+                .find(|n| n.global_addr() == saved.node)
+                .map(|n| n.local_addr())
+                .expect("snapshot of state for a node that isn't in the domain");
+            let state = self.restored_state(node, saved, &HashSet::new());
+            self.state.insert(node, state);
+        }
+        self.update_gauges_below();
+        self.paused = true;
+    }
+
+    /// Whether the domain is moving and has logged as many updates as it may before the copy is
+    /// up.
+    fn move_log_full(&self) -> bool {
+        match self.moving {
+            Some(ref mv) => mv.to.is_none() && mv.log.len() >= MOVE_LOG_LIMIT,
+            None => false,
+        }
+    }
+
+    /// Send the updates logged since they were last sent on to the copy of the domain that is
+    /// moving, if it is up. The move is given up on if the copy can't be reached.
+    fn ship_move_log(&mut self) {
+        let failed = match self.moving {
+            Some(Move { ref mut log, to: Some(ref mut to), .. }) if !log.is_empty() => {
+                let packets = mem::replace(log, Vec::new());
+                to.send(Box::new(Packet::CatchUp { packets, last: false }))
+                    .err()
+            }
+            _ => None,
+        };
+        if let Some(e) = failed {
+            error!(self.log, "failed to send logged updates to copy"; "error" => ?e);
+            self.moving = None;
+        }
+    }
+
+    /// Send the copy of the domain that is moving the last of the updates logged for it, and let
+    /// the controller know, once every sender of updates to the domain has been redirected to the
+    /// copy.
+    fn finish_move(&mut self) {
+        match self.moving {
+            Some(Move {
+                to: Some(_),
+                senders: Some(senders),
+                redirected,
+                ..
+            }) if redirected >= senders => {}
+            _ => return,
+        }
+
+        let mut mv = self.moving.take().unwrap();
+        let packets = mem::replace(&mut mv.log, Vec::new());
+        let sent = mv
+            .to
+            .as_mut()
+            .unwrap()
+            .send(Box::new(Packet::CatchUp { packets, last: true }));
+        let reply = match sent {
+            Ok(()) => {
+                info!(self.log, "finished move"; "logged" => mv.logged);
+                ControlReplyPacket::Moved(mv.logged)
+            }
+            Err(e) => {
+                error!(self.log, "failed to send last logged updates to copy"; "error" => ?e);
+                ControlReplyPacket::Failed(ControlError::NotMoving)
+            }
+        };
+        self.control_reply_tx.send(reply).unwrap();
+    }
+
+    /// Read this domain's checkpoint file and hold on to the state of the nodes in it, so that
+    /// they can be restored instead of replayed. Returns `None` if there is no checkpoint file,
+    /// or if it wasn't written by the checkpoint with the given `id`.
+    fn load_checkpoint(&mut self, id: u64) -> Option<Vec<NodeIndex>> {
+        let path = self.checkpoint_path();
+        let checkpoint: Checkpoint = match fs::File::open(&path)
+            .map_err(bincode::Error::from)
+            .and_then(|f| bincode::deserialize_from(io::BufReader::new(f)))
+        {
+            Ok(checkpoint) => checkpoint,
+            Err(e) => {
+                info!(self.log, "no usable checkpoint"; "path" => ?path, "error" => ?e);
+                return None;
+            }
+        };
+        if checkpoint.id != id {
+            info!(self.log, "ignoring stale checkpoint";
+                  "id" => checkpoint.id, "expected" => id);
+            return None;
+        }
+
+        self.checkpointed = Map::default();
+        let mut restorable = Vec::with_capacity(checkpoint.nodes.len());
+        for saved in checkpoint.nodes {
+            let local = self
+                .nodes
+                .values()
+                .map(|n| n.borrow())
+                .find(|n| n.global_addr() == saved.node && n.name() == saved.name)
+                .map(|n| n.local_addr());
+            match local {
+                Some(ni) => {
+                    restorable.push(saved.node);
+                    self.checkpointed.insert(ni, saved);
+                }
+                None => {
+                    warn!(self.log, "checkpointed node is no longer in domain";
+                          "node" => saved.node.index(), "name" => &saved.name);
+                }
+            }
+        }
+
+        // auxiliary state is picked up when the node it is for is prepared
+        self.checkpointed_aux = AuxStateMap::default();
+        for (node, aux) in checkpoint.aux {
+            let local = self
+                .nodes
+                .values()
+                .map(|n| n.borrow())
+                .find(|n| n.global_addr() == node)
+                .map(|n| n.local_addr());
+            if let Some(ni) = local {
+                self.checkpointed_aux.insert(ni, aux);
+            }
+        }
+        Some(restorable)
+    }
+
+    /// Apply the writes in the write-ahead log of the base `node` that came after those already in
+    /// its state, and return how many there were.
+    ///
+    /// The writes only go into the base's state, since none of its children are ready yet. They
+    /// reach the children through the replays that fill their state.
+    fn recover_base(&mut self, node: LocalNodeIndex) -> bincode::Result<usize> {
+        let from = self.checkpointed_logs.remove(node).unwrap_or(0);
+        let (global, name) = {
+            let n = self.nodes[node].borrow();
+            (n.global_addr(), n.name().to_owned())
+        };
+        if !self.state.contains_key(node) {
+            warn!(self.log, "base is not materialized, so its log can't be recovered";
+                  "node" => node.id());
+            return Ok(0);
+        }
+
+        let entries = wal::read(&self.persistence_parameters, &name, self.shard.unwrap_or(0))?;
+        let mut expected = from + 1;
+        let mut writes = 0;
+        for entry in entries.into_iter().filter(|e| e.seq > from) {
+            if entry.seq != expected {
+                // the entries were deleted after a checkpoint that has not been restored
+                warn!(self.log, "write-ahead log is missing entries";
+                      "node" => node.id(), "from" => expected, "to" => entry.seq - 1);
+            }
+            expected = entry.seq + 1;
+            writes += entry.ops.len();
+
+            let mut rs = self.nodes[node]
+                .borrow_mut()
+                .get_base_mut()
+                .expect("asked to recover non-base node")
+                .process(node, entry.ops, &self.state);
+            self.state[node].process_records(&mut rs, None);
+        }
+
+        // so that the next checkpoint counts the recovered entries as part of the base's state
+        self.wal.open(global, &name)?;
+        Ok(writes)
+    }
+
+    /// Returns a function that extends rows replayed out of `from`'s state with any columns that
+    /// have been added to it since they were stored.
+    fn replay_fixer(&self, from: LocalNodeIndex) -> impl Fn(Vec<DataType>) -> Vec<DataType> + Send {
+        let added_cols = self.ingress_inject.get(from).cloned();
+        let default = {
+            let n = self.nodes[from].borrow();
+            let mut default = None;
+            if let Some(b) = n.get_base() {
+                let mut row = Vec::new();
+                b.fix(&mut row);
+                default = Some(row);
+            }
+            default
+        };
+        move |mut r: Vec<DataType>| -> Vec<DataType> {
+            if let Some((start, ref added)) = added_cols {
+                let rlen = r.len();
+                r.extend(added.iter().skip(rlen - start).cloned());
+            } else if let Some(ref defaults) = default {
+                let rlen = r.len();
+                r.extend(defaults.iter().skip(rlen).cloned());
+            }
+            r
+        }
+    }
+
+    /// How many records to put in each chunk of a full replay sent from this domain.
+    fn replay_batch_size(&self) -> usize {
+        if self.replay_workers > 1 {
+            BATCH_SIZE * PARALLEL_BATCH_FACTOR
+        } else {
+            BATCH_SIZE
+        }
+    }
+
+    /// Start streaming the given rows along the replay path `tag` from the event loop. Returns
+    /// whether there were no rows to stream, in which case there is nothing left to do.
+    fn stream_replay(
+        &mut self,
+        tag: Tag,
+        link: &Link,
+        from: LocalNodeIndex,
+        rows: RowStream,
+        priority: ReplayPriority,
+    ) -> bool {
+        let mut rows = rows.peekable();
+        if rows.peek().is_none() {
+            return true;
+        }
+
+        let fix = Box::new(self.replay_fixer(from));
+        let now = time::Instant::now();
+        self.replay_streams.push_back(StreamedReplay {
+            tag,
+            link: link.clone(),
+            from,
+            rows,
+            fix,
+            priority,
+            sent: now,
+            due: now,
+        });
+        false
+    }
+
+    /// Whether the next chunk of the oldest streamed replay should be sent now, as its priority
+    /// says. `idle` is whether the domain has run out of other packets to process.
+    fn streamed_replay_due(&self, idle: bool) -> bool {
+        if self.paused {
+            return false;
+        }
+        match self.replay_streams.front().map(|r| (r.priority, r.due)) {
+            None => false,
+            Some((ReplayPriority::FavorReplay, _)) => idle,
+            Some((ReplayPriority::FavorLive { every }, _)) => {
+                idle || self.live_since_replay >= every
+            }
+            Some((ReplayPriority::Capped { .. }, due)) => time::Instant::now() >= due,
+        }
+    }
+
+    /// How long until the next chunk of a streamed replay may be sent, if there is one to send.
+    fn duration_until_streamed_replay(&self) -> Option<time::Duration> {
+        if self.paused {
+            return None;
+        }
+        self.replay_streams.front().map(|replay| {
+            let now = time::Instant::now();
+            match replay.priority {
+                ReplayPriority::Capped { .. } if replay.due > now => replay.due - now,
+                // come right back to send the next chunk once there's nothing else to do
+                _ => time::Duration::from_millis(0),
+            }
+        })
+    }
+
+    /// Send the next chunk of the oldest streamed replay that hasn't finished yet, if any.
+    fn continue_streamed_replay(&mut self, sends: &mut EnqueuedSends, executor: &mut Executor) {
+        let batch_size = self.replay_batch_size();
+        let (p, last) = {
+            let replay = match self.replay_streams.front_mut() {
+                Some(replay) => replay,
+                None => return,
+            };
+
+            let fix = &replay.fix;
+            let data: Records = replay
+                .rows
+                .by_ref()
+                .take(batch_size)
+                .map(|r| fix(r))
+                .collect();
+            let last = replay.rows.peek().is_none();
+            trace!(self.log, "sending streamed batch";
+                   "node" => %replay.link.dst,
+                   "[]" => data.len()
+            );
+
+            let now = time::Instant::now();
+            self.domain_metrics.replayed(data.len(), now - replay.sent);
+            replay.sent = now;
+            if let ReplayPriority::Capped { records_per_sec } = replay.priority {
+                let nanos = data.len() as u64 * 1_000_000_000 / records_per_sec as u64;
+                replay.due = now + time::Duration::from_nanos(nanos);
+            }
+            self.live_since_replay = 0;
+
+            let p = box Packet::ReplayPiece {
+                tag: replay.tag,
+                link: replay.link.clone(),
+                context: ReplayPieceContext::Regular {
+                    last,
+                    rows: None,
+                    cut: None,
+                },
+                data,
+            };
+            (p, last)
+        };
+
+        if last {
+            self.replay_streams.pop_front();
+        }
+        self.handle(p, sends, executor, true);
+    }
+
     fn seed_replay(&mut self, tag: Tag, key: &[DataType], sends: &mut EnqueuedSends) {
         if let ReplayPath {
             trigger: TriggerEndpoint::Start(..),
@@ -1547,8 +3509,70 @@ impl Domain {
         }
     }
 
+    /// Whether the update numbered `seq` that came in over the edge `link` is one that the domain
+    /// hasn't been handed before. Updates that were sent over the edge before it, but never came
+    /// in, are counted as lost.
+    fn in_sequence(&mut self, link: Link, seq: u64) -> bool {
+        let last = match self.received.entry((link.dst, link.src)) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                // a domain can only start hearing from an edge midway if it is a copy of one that
+                // heard the rest, such as one that re-runs a capture
+                e.insert(seq);
+                return true;
+            }
+        };
+
+        if seq <= *last {
+            warn!(self.log, "dropping update that was already delivered";
+                  "ingress" => link.dst.id(),
+                  "from" => link.src.id(),
+                  "seq" => seq,
+                  "last" => *last);
+            self.domain_metrics.duplicate();
+            return false;
+        }
+        if seq != *last + 1 {
+            error!(self.log, "updates were lost on the way to ingress";
+                   "ingress" => link.dst.id(),
+                   "from" => link.src.id(),
+                   "seq" => seq,
+                   "lost" => seq - *last - 1);
+            self.domain_metrics.lost(seq - *last - 1);
+        }
+        *last = seq;
+        true
+    }
+
+    /// Take note that the piece of a full replay that came in over the edge `link` reflects the
+    /// first `cut` updates sent over it, and none of those after. Updates up to the cut that are
+    /// yet to come in are dropped when they do, since the replayed state already holds them.
+    fn cut(&mut self, link: Link, cut: u64) {
+        let last = self.received.entry((link.dst, link.src)).or_insert(cut);
+        if *last > cut {
+            // the updates after the cut came in before the replay did, and so were discarded or
+            // applied to state that doesn't hold what came before them
+            error!(self.log, "updates overtook the replay they come after";
+                   "ingress" => link.dst.id(),
+                   "from" => link.src.id(),
+                   "cut" => cut,
+                   "last" => *last);
+            self.domain_metrics.lost(*last - cut);
+        } else {
+            *last = cut;
+        }
+    }
+
     fn handle_replay(&mut self, m: Box<Packet>, sends: &mut EnqueuedSends) {
         let tag = m.tag().unwrap();
+        if let Packet::ReplayPiece {
+            link,
+            context: ReplayPieceContext::Regular { cut: Some(cut), .. },
+            ..
+        } = *m
+        {
+            self.cut(link, cut);
+        }
         if self.nodes[self.replay_paths[&tag].path.last().unwrap().node]
             .borrow()
             .is_dropped()
@@ -1577,11 +3601,14 @@ impl Domain {
                     // messages preceeding the first replay message is that those have already been
                     // accounted for in the state we are being replayed. if we buffered them and
                     // applied them after all the state has been replayed, we would double-apply
-                    // those changes, which is bad.
+                    // those changes, which is bad. a replay that came in from another domain also
+                    // says how many updates were sent over its edge before it, and `cut` makes sure
+                    // that none of those get through later either.
                     self.mode = DomainMode::Replaying {
                         to: path.last().unwrap().node,
                         buffered: VecDeque::new(),
                         passes: 0,
+                        replayed: 0,
                     };
                 }
                 DomainMode::Forwarding => {
@@ -1613,6 +3640,15 @@ impl Domain {
                         debug!(self.log, "replaying batch"; "#" => data.len());
                     }
 
+                    if notify_done {
+                        if let DomainMode::Replaying {
+                            ref mut replayed, ..
+                        } = self.mode
+                        {
+                            *replayed += data.len();
+                        }
+                    }
+
                     // let's collect some information about the destination of this replay
                     let dst = path.last().unwrap().node;
                     let dst_is_reader = self.nodes[dst]
@@ -1662,6 +3698,19 @@ impl Domain {
                         }
                     }
 
+                    // a full replay tells us up front how many rows to expect, so make room for
+                    // them in the target's state before they start arriving.
+                    if notify_done {
+                        if let ReplayPieceContext::Regular {
+                            rows: Some(rows), ..
+                        } = context
+                        {
+                            if let Some(state) = self.state.get_mut(dst) {
+                                state.reserve(rows);
+                            }
+                        }
+                    }
+
                     // forward the current message through all local nodes.
                     let m = box Packet::ReplayPiece {
                         link: link.clone(),
@@ -1725,16 +3774,33 @@ impl Domain {
                         }
 
                         // process the current message in this node
+                        let mut malformed = Vec::new();
                         let (mut misses, captured) = n.process(
                             &mut m,
                             segment.partial_key.as_ref(),
                             &mut self.state,
+                            &mut self.aux,
                             &self.nodes,
                             self.shard,
                             false,
                             sends,
                             None,
+                            self.replay_workers,
+                            &mut malformed,
                         );
+                        let now = time::Instant::now();
+                        self.timers.request(segment.node, n.take_timers(), now);
+                        if !malformed.is_empty() {
+                            reject_malformed(
+                                self.malformed,
+                                &n,
+                                malformed,
+                                &self.nodes,
+                                &self.node_metrics[segment.node],
+                                &self.dead_letters,
+                                &self.log,
+                            );
+                        }
 
                         // ignore duplicate misses
                         misses.sort_unstable_by(|a, b| {
@@ -1908,13 +3974,28 @@ impl Domain {
 
                         // we're all good -- continue propagating
                         if m.as_ref().map(|m| m.is_empty()).unwrap_or(true) {
-                            if let ReplayPieceContext::Regular { last: false } = context {
-                                trace!(self.log, "dropping empty non-terminal full replay packet");
-                                // don't continue processing empty updates, *except* if this is the
-                                // last replay batch. in that case we need to send it so that the
-                                // next domain knows that we're done
-                                // TODO: we *could* skip ahead to path.last() here
-                                break;
+                            if let ReplayPieceContext::Regular { last: false, .. } = context {
+                                // shard mergers need to see empty pieces too, since those still
+                                // tell them that the replay has started in the shard they came
+                                // from. so they go all the way to sharded sharders, and on from
+                                // the ingress to the merger.
+                                let keep = m.is_some() && i != path.len() - 1 && {
+                                    let next = self.nodes[path[i + 1].node].borrow();
+                                    let last = self.nodes[path.last().unwrap().node].borrow();
+                                    let sharded = self.shard.is_some();
+                                    next.is_shard_merger() || (sharded && last.is_sharder())
+                                };
+                                if !keep {
+                                    trace!(
+                                        self.log,
+                                        "dropping empty non-terminal full replay packet"
+                                    );
+                                    // don't continue processing empty updates, *except* if this is
+                                    // the last replay batch. in that case we need to send it so
+                                    // that the next domain knows that we're done
+                                    // TODO: we *could* skip ahead to path.last() here
+                                    break;
+                                }
                             }
                         }
 
@@ -1931,12 +4012,13 @@ impl Domain {
 
                         // preserve whatever `last` flag that may have been set during processing
                         if let Some(box Packet::ReplayPiece {
-                            context: ReplayPieceContext::Regular { last },
+                            context: ReplayPieceContext::Regular { last, .. },
                             ..
                         }) = m
                         {
                             if let ReplayPieceContext::Regular {
                                 last: ref mut old_last,
+                                ..
                             } = context
                             {
                                 *old_last = last;
@@ -1954,7 +4036,7 @@ impl Domain {
                     }
 
                     match context {
-                        ReplayPieceContext::Regular { last } if last => {
+                        ReplayPieceContext::Regular { last, .. } if last => {
                             debug!(self.log,
                                    "last batch processed";
                                    "terminal" => notify_done
@@ -2083,6 +4165,7 @@ impl Domain {
                 unreachable!("got unexpected replay of {:?} for {:?}", for_keys, ni)
             } else {
                 // must be a full replay
+                self.rebuild_aux(ni);
                 // NOTE: node is now ready, in the sense that it shouldn't ignore all updates since
                 // replaying_to is still set, "normal" dispatch calls will continue to be buffered, but
                 // this allows finish_replay to dispatch into the node by overriding replaying_to.
@@ -2147,23 +4230,26 @@ impl Domain {
         if finished {
             use std::mem;
             // node is now ready, and should start accepting "real" updates
-            if let DomainMode::Replaying { passes, .. } =
-                mem::replace(&mut self.mode, DomainMode::Forwarding)
+            let replayed = if let DomainMode::Replaying {
+                passes, replayed, ..
+            } = mem::replace(&mut self.mode, DomainMode::Forwarding)
             {
                 debug!(self.log,
                        "node is fully up-to-date";
                        "local" => node.id(),
-                       "passes" => passes
+                       "passes" => passes,
+                       "replayed" => replayed
                 );
+                replayed
             } else {
                 unreachable!();
-            }
+            };
 
             if self.replay_paths[&tag].notify_done {
                 // NOTE: this will only be Some for non-partial replays
                 info!(self.log, "acknowledging replay completed"; "node" => node.id());
                 self.control_reply_tx
-                    .send(ControlReplyPacket::ack())
+                    .send(ControlReplyPacket::Replayed(replayed))
                     .unwrap();
             } else {
                 unreachable!()
@@ -2182,8 +4268,8 @@ impl Domain {
             keys: &[Vec<DataType>],
             node: LocalNodeIndex,
             sends: &mut EnqueuedSends,
-            not_ready: &HashSet<LocalNodeIndex>,
-            replay_paths: &HashMap<Tag, ReplayPath>,
+            not_ready: &FnvHashSet<LocalNodeIndex>,
+            replay_paths: &FnvHashMap<Tag, ReplayPath>,
             shard: Option<usize>,
             state: &mut StateMap,
             nodes: &mut DomainNodes,
@@ -2298,12 +4384,20 @@ impl Domain {
                         if self.nodes[node].borrow().is_dropped() {
                             break; // Node was dropped. Give up.
                         } else if self.nodes[node].borrow().is_reader() {
-                            // we can only evict one key a time here because the freed memory
-                            // calculation is based on the key that *will* be evicted. We may count
-                            // the same individual key twice if we batch evictions here.
+                            // we can only evict one random key a time here because the freed
+                            // memory calculation is based on the key that *will* be evicted. We
+                            // may count the same individual key twice if we batch evictions here.
+                            // readers with a memory limit know which keys are filled, and can
+                            // evict their least recently read keys in one go instead.
                             let freed_now = self.nodes[node]
                                 .borrow_mut()
-                                .with_reader_mut(|r| r.evict_random_key())
+                                .with_reader_mut(|r| {
+                                    if r.memory_limit().is_some() {
+                                        r.evict_lru_keys(num_bytes as u64 - freed)
+                                    } else {
+                                        r.evict_random_key()
+                                    }
+                                })
                                 .unwrap();
 
                             freed += freed_now;
@@ -2395,6 +4489,24 @@ impl Domain {
                     TriggerEndpoint::None | TriggerEndpoint::Start(..) => {}
                 }
             }
+            (Packet::ForceEvict { node, keys },) => {
+                let mut n = self.nodes[node].borrow_mut();
+                if !n.is_reader() {
+                    warn!(
+                        self.log,
+                        "ignoring forced eviction from non-reader {:?}", node
+                    );
+                    return;
+                }
+
+                let freed = n
+                    .with_reader_mut(|r| match keys {
+                        Some(keys) => r.evict_keys(&keys[..]),
+                        None => r.trim_to_limit(),
+                    })
+                    .unwrap();
+                trace!(self.log, "forcibly evicted {} bytes from {:?}", freed, node);
+            }
             _ => unreachable!(),
         };
     }
@@ -2403,6 +4515,71 @@ impl Domain {
         (self.index, self.shard.unwrap_or(0))
     }
 
+    /// The counters of this domain, and of its nodes and readers.
+    pub fn metrics(&self) -> &DomainMetrics {
+        &self.domain_metrics
+    }
+
+    /// Where the domain posts the packets it could not deliver.
+    pub fn dead_letters(&self) -> &DeadLetterBox {
+        &self.dead_letters
+    }
+
+    /// Where the task that runs the domain accounts for its time.
+    pub fn time_budget(&mut self) -> &mut TimeBudget {
+        &mut self.time_budget
+    }
+
+    /// Switch the edges of the domain's egress nodes that send to the given domain shard, whose
+    /// receiving end has gone away, over to their standbys, if they have any. The standbys are
+    /// first re-sent the updates that they may not have received.
+    ///
+    /// Returns the ingress nodes at the failed domain shard that those edges sent to. The updates
+    /// that were headed for them are the standbys' to deliver now.
+    pub fn fail_over(
+        &mut self,
+        failed: ReplicaAddr,
+        sends: &mut EnqueuedSends,
+    ) -> Vec<LocalNodeIndex> {
+        let mut ingresses = Vec::new();
+        for n in self.nodes.values() {
+            let mut n = n.borrow_mut();
+            if !n.is_egress() {
+                continue;
+            }
+            let egress = n.local_addr();
+            let mut switched = Vec::new();
+            n.with_egress_mut(|e| switched = e.fail_over(failed, sends));
+            for s in switched {
+                self.switched(egress, &s, Some(failed));
+                self.domain_metrics.failed_over();
+                ingresses.push(s.from.1);
+            }
+        }
+        ingresses
+    }
+
+    /// Report that an edge of the given egress node was switched over to another ingress, which
+    /// happened because the shard it sent to had failed if that is given.
+    fn switched(&self, egress: LocalNodeIndex, s: &Switch, failed: Option<ReplicaAddr>) {
+        let cause = match failed {
+            Some((domain, shard)) => format!("domain {}.{} failed", domain.index(), shard),
+            None => "told to".to_owned(),
+        };
+        warn!(self.log, "switched edge over to standby";
+              "egress" => egress.id(),
+              "from" => s.from.0.index(),
+              "to" => s.to.index(),
+              "cause" => cause,
+              "resent" => s.resent);
+        if s.missing != 0 {
+            error!(self.log, "standby is missing updates that were no longer held";
+                   "egress" => egress.id(),
+                   "standby" => s.to.index(),
+                   "missing" => s.missing);
+        }
+    }
+
     pub fn booted(&mut self, addr: SocketAddr) {
         info!(self.log, "booted domain"; "nodes" => self.nodes.len());
         self.control_reply_tx
@@ -2411,6 +4588,25 @@ impl Domain {
         self.wait_time.start();
     }
 
+    /// The domains that this one has been told to send to at another address since this was last
+    /// called, along with where they were and where they are now. The packet that tells each one
+    /// so has been queued to go where it was.
+    pub fn take_redirects(&mut self) -> Vec<(ReplicaAddr, SocketAddr, SocketAddr)> {
+        mem::replace(&mut self.redirects, Vec::new())
+    }
+
+    /// The number of packets that the domain has taken in, but not yet processed.
+    fn queued_packets(&self) -> usize {
+        let buffered = match self.mode {
+            DomainMode::Replaying { ref buffered, .. } => buffered.len(),
+            DomainMode::Forwarding => 0,
+        };
+        buffered
+            + self.delayed_for_self.len()
+            + self.group_commit_queues.queued()
+            + self.paused_packets.len()
+    }
+
     pub fn update_state_sizes(&mut self) {
         let total: u64 = self
             .nodes
@@ -2444,6 +4640,475 @@ impl Domain {
         // no response sent, as worker will read the atomic
     }
 
+    /// Admit an incoming write to the base table it targets.
+    ///
+    /// Omitted values are filled in with their columns' defaults, the write is checked against the
+    /// base's schema and key constraints, and its inserted rows are assigned ids if the base has
+    /// an auto-increment column. This happens before the write is queued for group commit, so a
+    /// rejected write never reaches the base's materialization or anything downstream of it.
+    ///
+    /// While a domain below the base is overloaded, and the domain's policy is to reject writes
+    /// then, the write is rejected without being looked at. So is a write to a base that is not
+    /// ready yet, which would otherwise be dropped.
+    fn admit(
+        &mut self,
+        packet: &mut Packet,
+        sends: &mut EnqueuedSends,
+        executor: &mut Executor,
+    ) -> bool {
+        let (res, src) = match *packet {
+            Packet::Input {
+                ref mut inner, src, ..
+            } => {
+                let input = unsafe { inner.deref_mut() };
+                if self.not_ready.contains(&input.dst) {
+                    // the base is still being set up, and would drop the write on the floor
+                    (Err(WriteError::NotReady), src)
+                } else if self.rejects_writes() {
+                    self.domain_metrics.throttled();
+                    (Err(WriteError::Overloaded), src)
+                } else {
+                    (self.check_write(input, src, sends, executor), src)
+                }
+            }
+            _ => return true,
+        };
+
+        match res {
+            Ok(()) => true,
+            Err(e) => {
+                debug!(self.log, "rejecting write"; "error" => %e);
+                if let Some(src) = src {
+                    executor.send_back(src, Err(e));
+                }
+                false
+            }
+        }
+    }
+
+    /// Fill in, check, and assign ids to the rows of a write to a base, as described for `admit`.
+    fn check_write(
+        &mut self,
+        input: &mut Input,
+        src: Option<SourceChannelIdentifier>,
+        sends: &mut EnqueuedSends,
+        executor: &mut Executor,
+    ) -> Result<(), WriteError> {
+        // the write's keys are checked against the base's materialization, so any writes still
+        // waiting for group commit must be applied first.
+        let checks_keys = self.nodes[input.dst]
+            .borrow()
+            .get_base()
+            .map(|b| b.checks_keys(&input.data))
+            .unwrap_or(false);
+        if checks_keys {
+            if let Some(m) = self.group_commit_queues.flush(input.dst) {
+                self.commit(m, sends, executor);
+            }
+        }
+
+        let mut n = self.nodes[input.dst].borrow_mut();
+        let b = n.get_base_mut().expect("input sent to non-base node");
+        let state = self.state.get(input.dst).map(|s| &**s);
+        let res = b
+            .fill_defaults(&mut input.data)
+            .and_then(|_| b.validate(&input.data))
+            .and_then(|_| b.check_keys(&input.data, state));
+        if res.is_ok() {
+            b.assign_ids(src, &mut input.data, state);
+        }
+        res
+    }
+
+    /// Process a batch of writes that group commit has let through, after appending it to its
+    /// base's write-ahead log if the domain keeps one.
+    fn commit(&mut self, m: Box<Packet>, sends: &mut EnqueuedSends, executor: &mut Executor) {
+        if self.wal.enabled() {
+            if let Packet::Input { ref inner, .. } = *m {
+                let input = unsafe { inner.deref() };
+                let n = self.nodes[input.dst].borrow();
+                // writes that can't be logged must not be acknowledged, or go any further
+                if let Err(e) = self.wal.append(n.global_addr(), n.name(), &input.data) {
+                    panic!("failed to append to write-ahead log of {}: {}", n.name(), e);
+                }
+            }
+        }
+        self.handle(m, sends, executor, true);
+    }
+
+    /// Check that a control packet that refers to nodes, state or replay paths refers to ones that
+    /// the domain has, and if it does not, tell the controller why the packet is refused. Returns
+    /// whether the packet was refused, in which case it must not be handled.
+    fn refuse_control(&mut self, m: &Packet) -> bool {
+        let checked = {
+            let exists = |node: LocalNodeIndex| {
+                self.nodes
+                    .get(node)
+                    .map(|n| !n.borrow().is_dropped())
+                    .unwrap_or(false)
+            };
+            match *m {
+                Packet::AddNode { ref parents, .. } => {
+                    match parents.iter().find(|&&p| !exists(p)) {
+                        Some(&p) => Err(ControlError::NoSuchNode(p)),
+                        None => Ok(()),
+                    }
+                }
+                Packet::PrepareState { node, ref state } => {
+                    use payload::InitialState;
+                    if !exists(node) {
+                        Err(ControlError::NoSuchNode(node))
+                    } else {
+                        let is_reader = self.nodes[node].borrow().is_reader();
+                        match *state {
+                            InitialState::Checkpointed(..)
+                                if !self.checkpointed.contains_key(node) =>
+                            {
+                                Err(ControlError::NotInCheckpoint(node))
+                            }
+                            InitialState::Checkpointed(..) if self.state.contains_key(node) => {
+                                Err(ControlError::AlreadyMaterialized(node))
+                            }
+                            InitialState::Global { .. } | InitialState::PartialGlobal { .. }
+                                if !is_reader =>
+                            {
+                                Err(ControlError::NotAReader(node))
+                            }
+                            InitialState::PartialGlobal {
+                                trigger_domain: (domain, shards),
+                                ..
+                            } => match (0..shards).find(|&shard| {
+                                self.channel_coordinator
+                                    .builder_for(&(domain, shard))
+                                    .is_none()
+                            }) {
+                                Some(shard) => Err(ControlError::Unreachable(domain, shard)),
+                                None => Ok(()),
+                            },
+                            _ => Ok(()),
+                        }
+                    }
+                }
+                Packet::PrepareAuxState { node } => {
+                    if !exists(node) {
+                        Err(ControlError::NoSuchNode(node))
+                    } else {
+                        let n = self.nodes[node].borrow();
+                        if n.is_internal() && n.uses_aux_state() {
+                            Ok(())
+                        } else {
+                            Err(ControlError::NoAuxState(node))
+                        }
+                    }
+                }
+                Packet::Ready { node, ref index } => {
+                    if !exists(node) {
+                        Err(ControlError::NoSuchNode(node))
+                    } else if !index.is_empty() && self.state.contains_key(node) {
+                        Err(ControlError::AlreadyMaterialized(node))
+                    } else {
+                        Ok(())
+                    }
+                }
+                Packet::StartReplay { tag, from, .. } => {
+                    let source = self.replay_paths.get(&tag).and_then(|p| p.source);
+                    if source != Some(from) {
+                        Err(ControlError::NoSuchReplayPath(tag))
+                    } else if !self.state.contains_key(from) {
+                        Err(ControlError::NotMaterialized(from))
+                    } else {
+                        Ok(())
+                    }
+                }
+                _ => Ok(()),
+            }
+        };
+
+        match checked {
+            Ok(()) => false,
+            Err(e) => {
+                warn!(self.log, "refusing control packet";
+                      "packet" => m.kind(),
+                      "error" => %e);
+                self.control_reply_tx
+                    .send(ControlReplyPacket::Failed(e))
+                    .unwrap();
+                true
+            }
+        }
+    }
+
+    /// Hand a packet to the domain to process, with writes to bases going through `admit` and
+    /// group commit first.
+    fn accept(
+        &mut self,
+        mut packet: Box<Packet>,
+        sends: &mut EnqueuedSends,
+        executor: &mut Executor,
+    ) {
+        // TODO: Initialize tracer here, and when flushing group commit
+        // queue.
+        if !self.admit(&mut packet, sends, executor) {
+            // the write was rejected, and its sender has been told why
+        } else if self.group_commit_queues.should_append(&packet, &self.nodes) {
+            packet.trace(PacketEvent::ExitInputChannel);
+            if let Some(packet) = self.group_commit_queues.append(packet) {
+                self.commit(packet, sends, executor);
+            }
+        } else {
+            self.handle(packet, sends, executor, true);
+        }
+    }
+
+    /// The shards of the domains that the domain's egress and sharder nodes send to.
+    fn destinations(&self) -> Vec<ReplicaAddr> {
+        let mut below: Vec<ReplicaAddr> = Vec::new();
+        for n in self.nodes.values() {
+            let n = n.borrow();
+            let addrs = n
+                .with_egress(|e| e.destinations())
+                .or_else(|| n.with_sharder(|s| s.destinations()))
+                .unwrap_or_default();
+            for addr in addrs {
+                if !below.contains(&addr) {
+                    below.push(addr);
+                }
+            }
+        }
+        below
+    }
+
+    /// Point the domain's gauge at the gauges of the domains that it sends to, which changes as
+    /// egress and sharder nodes gain and lose children.
+    fn update_gauges_below(&mut self) {
+        let below = self.destinations();
+        let gauges = &self.gauges;
+        self.gauge
+            .set_below(below.into_iter().map(|addr| gauges.get(addr)).collect());
+    }
+
+    /// The barrier with the given identifier, which replaces that of an earlier quiesce that never
+    /// completed here. Returns `None` for the barrier of such an earlier quiesce.
+    fn barrier_for(&mut self, id: u64) -> Option<&mut Barrier> {
+        match self.barrier.as_ref().map(|b| b.id) {
+            Some(current) if current > id => return None,
+            Some(current) if current == id => {}
+            _ => {
+                self.barrier = Some(Barrier {
+                    id,
+                    senders: None,
+                    arrived: 0,
+                })
+            }
+        }
+        self.barrier.as_mut()
+    }
+
+    /// Whether the domain has nothing left that it has taken in but not yet processed, or that it
+    /// has processed but not yet made visible to reads.
+    fn drained(&self) -> bool {
+        let forwarding = match self.mode {
+            DomainMode::Forwarding => true,
+            DomainMode::Replaying { .. } => false,
+        };
+        forwarding
+            && self.queued_packets() == 0
+            && self.replay_streams.is_empty()
+            && !self.has_buffered_replay_requests
+            && self.waiting.values().all(|w| w.holes.is_empty())
+            && self.unpublished_readers.is_empty()
+    }
+
+    /// Whether the barrier has been received from every sender, but the domain is yet to drain.
+    fn barrier_complete(&self) -> bool {
+        if self.paused {
+            // the domain can't drain until it is resumed
+            return false;
+        }
+        match self.barrier {
+            Some(Barrier {
+                senders: Some(senders),
+                arrived,
+                ..
+            }) => arrived >= senders,
+            _ => false,
+        }
+    }
+
+    /// Send the barrier on to the domains below and tell the controller, if it has been received
+    /// from every sender and the domain has drained.
+    fn pass_barrier(&mut self, sends: &mut EnqueuedSends) {
+        if !self.barrier_complete() {
+            return;
+        }
+        // everything that came before the barrier is to be visible to reads once it is passed
+        self.publish_readers(true);
+        if !self.drained() {
+            return;
+        }
+
+        let id = self.barrier.take().unwrap().id;
+        let from = (self.index, self.shard.unwrap_or(0));
+        for addr in self.destinations() {
+            sends
+                .entry(addr)
+                .or_default()
+                .push_back(box Packet::Barrier { id, from });
+        }
+        debug!(self.log, "passed barrier"; "barrier" => id);
+        self.control_reply_tx
+            .send(ControlReplyPacket::Quiesced {
+                id,
+                shard: self.shard.unwrap_or(0),
+            })
+            .unwrap();
+    }
+
+    /// How long until the domain should check again whether it has drained, if it is holding on
+    /// to a barrier until it has.
+    fn duration_until_barrier(&self) -> Option<time::Duration> {
+        if self.barrier_complete() {
+            Some(time::Duration::from_millis(BARRIER_RECHECK_MS))
+        } else {
+            None
+        }
+    }
+
+    /// Tell the domain how many packets are waiting for it to process or send them, which decides
+    /// whether it is overloaded (see `Backpressure`).
+    pub fn update_pressure(&mut self, waiting: usize) {
+        // a paused domain doesn't process the packets it holds on to either
+        let waiting = waiting + self.paused_packets.len();
+        self.domain_metrics.queued(waiting);
+        if let Some(ref limits) = self.backpressure {
+            if self.gauge.update(waiting, limits) {
+                if self.gauge.overloaded() {
+                    debug!(self.log, "domain is overloaded"; "waiting" => waiting);
+                } else {
+                    debug!(self.log, "domain is no longer overloaded"; "waiting" => waiting);
+                }
+            }
+        }
+    }
+
+    /// How many bytes the connections that the domain sends and receives packets over buffer.
+    pub fn channels(&self) -> &ChannelConfig {
+        &self.channels
+    }
+
+    /// Tell the domain that the connection from a client writing to its bases that is identified
+    /// by `token` has closed, so the bases can drop what they hold for the client.
+    pub fn input_closed(&mut self, token: usize) {
+        for n in self.nodes.values() {
+            if let Some(b) = n.borrow_mut().get_base_mut() {
+                b.forget_source(token);
+            }
+        }
+    }
+
+    /// Tell the domain how many connections from other domains, and from clients writing to its
+    /// bases, are open.
+    pub fn update_connections(&mut self, domains: usize, inputs: usize) {
+        self.domain_connections = domains;
+        self.input_connections = inputs;
+    }
+
+    fn channel_stats(&self) -> noria::debug::stats::DomainChannels {
+        use noria::debug::stats::ChannelStats;
+        noria::debug::stats::DomainChannels {
+            control: ChannelStats {
+                capacity: self.channels.control,
+                connections: 1,
+            },
+            domain: ChannelStats {
+                capacity: self.channels.domain,
+                connections: self.domain_connections,
+            },
+            input: ChannelStats {
+                capacity: self.channels.input,
+                connections: self.input_connections,
+            },
+            replay: ChannelStats {
+                capacity: self.channels.replay,
+                connections: self.replay_connections,
+            },
+        }
+    }
+
+    /// Whether writes to the domain's bases are rejected right now, because a domain below them
+    /// is overloaded.
+    fn rejects_writes(&self) -> bool {
+        match self.backpressure {
+            Some(Backpressure {
+                policy: OverloadPolicy::Reject,
+                ..
+            }) => self.gauge.overloaded_below(),
+            _ => false,
+        }
+    }
+
+    /// Whether the given packet is a write that must wait for the domains below its base to catch
+    /// up before it is processed. Once one write is held back, so are all the writes that arrive
+    /// after it, so that writes are still processed in the order in which they arrived.
+    fn holds_back(&self, packet: &Packet) -> bool {
+        if self.writes_held {
+            if let Packet::Input { .. } = *packet {
+                return true;
+            }
+        }
+        match self.backpressure {
+            Some(Backpressure {
+                policy: OverloadPolicy::Block,
+                ..
+            }) => {}
+            _ => return false,
+        }
+        match *packet {
+            Packet::Input { .. } => !self.held_writes.is_empty() || self.gauge.overloaded_below(),
+            _ => false,
+        }
+    }
+
+    /// Process a packet that has arrived, unless it is a write that must be held back.
+    fn take_in(&mut self, packet: Box<Packet>, sends: &mut EnqueuedSends, executor: &mut Executor) {
+        if self.holds_back(&packet) {
+            // the write isn't acknowledged until it is processed, which keeps its writer from
+            // sending any more in the meantime
+            self.domain_metrics.throttled();
+            self.held_writes.push_back(packet);
+        } else {
+            self.accept(packet, sends, executor);
+        }
+    }
+
+    /// Process the writes that were held back, for as long as no domain below is overloaded, unless
+    /// every write is being held back or the domain is paused.
+    fn release_held_writes(&mut self, sends: &mut EnqueuedSends, executor: &mut Executor) {
+        if self.writes_held || self.paused {
+            return;
+        }
+        while !self.held_writes.is_empty() && !self.gauge.overloaded_below() {
+            let packet = self.held_writes.pop_front().unwrap();
+            self.accept(packet, sends, executor);
+        }
+    }
+
+    /// How long until the domain should check again whether it can process the writes it holds
+    /// back, if it holds back any.
+    fn duration_until_release(&self) -> Option<time::Duration> {
+        if self.held_writes.is_empty() || self.writes_held || self.paused {
+            None
+        } else {
+            Some(time::Duration::from_millis(HELD_WRITES_RECHECK_MS))
+        }
+    }
+
+    /// Whether the domain holds on to the given packet for now, rather than handling it right
+    /// away, since it is paused, or is moving and can't log any more updates for the copy yet.
+    fn holds(&self, packet: &Packet) -> bool {
+        held_while_paused(packet) && (self.paused || self.move_log_full())
+    }
+
     pub fn on_event(
         &mut self,
         executor: &mut Executor,
@@ -2453,9 +5118,28 @@ impl Domain {
         self.wait_time.stop();
         //self.total_time.start();
         //self.total_ptime.start();
+        self.domain_metrics.iteration();
+        if let Some(ref mut capture) = self.capture {
+            if let Err(e) = capture.record(&event) {
+                error!(self.log, "failed to capture event, so stopping capture";
+                       "path" => ?capture.path(), "error" => ?e);
+                self.capture = None;
+            }
+        }
+
         let res = match event {
             PollEvent::ResumePolling(timeout) => {
-                *timeout = self.group_commit_queues.duration_until_flush().or_else(|| {
+                // there is nothing left to process, so this is when batched writes are published
+                if !self.paused {
+                    self.publish_readers(true);
+                }
+
+                let commit = if self.paused {
+                    None
+                } else {
+                    self.group_commit_queues.duration_until_flush()
+                };
+                let flush = commit.or_else(|| {
                     let now = time::Instant::now();
                     self.buffered_replay_requests
                         .iter()
@@ -2467,39 +5151,88 @@ impl Domain {
                         })
                         .min()
                 });
+                *timeout = flush
+                    .into_iter()
+                    .chain(self.wal.duration_until_sync())
+                    .chain(self.duration_until_timer())
+                    .chain(self.duration_until_publish())
+                    .chain(self.duration_until_release())
+                    .chain(self.duration_until_barrier())
+                    .chain(self.duration_until_streamed_replay())
+                    .min();
                 ProcessResult::KeepPolling
             }
             PollEvent::Process(packet) => {
+                self.domain_metrics.handled(&packet);
                 if let Packet::Quit = *packet {
+                    self.metrics.deregister(&self.domain_metrics);
                     return ProcessResult::StopPolling;
                 }
 
-                // TODO: Initialize tracer here, and when flushing group commit
-                // queue.
-                if self.group_commit_queues.should_append(&packet, &self.nodes) {
-                    packet.trace(PacketEvent::ExitInputChannel);
-                    if let Some(packet) = self.group_commit_queues.append(packet) {
-                        self.handle(packet, sends, executor, true);
-                    }
+                if self.holds(&packet) {
+                    // a write isn't acknowledged until it is processed, and the domains that send
+                    // updates see that the domain is overloaded once enough of them pile up
+                    self.paused_packets.push_back(packet);
                 } else {
-                    self.handle(packet, sends, executor, true);
+                    match *packet {
+                        Packet::Input { .. } | Packet::Message { .. } => {
+                            self.live_since_replay += 1;
+                        }
+                        _ => {}
+                    }
+                    self.take_in(packet, sends, executor);
                 }
 
-                while let Some(m) = self.group_commit_queues.flush_if_necessary() {
-                    self.handle(m, sends, executor, true);
+                if !self.paused {
+                    // the domain may have just been resumed, or a copy that it is moving to may
+                    // have come up, and it picks up where it left off
+                    while !self.move_log_full() {
+                        match self.paused_packets.pop_front() {
+                            Some(packet) => self.take_in(packet, sends, executor),
+                            None => break,
+                        }
+                    }
+                    self.release_held_writes(sends, executor);
+
+                    while let Some(m) = self.group_commit_queues.flush_if_necessary() {
+                        self.commit(m, sends, executor);
+                    }
+                    self.fire_timers(sends);
+                    if self.streamed_replay_due(false) {
+                        self.continue_streamed_replay(sends, executor);
+                    }
+                    self.publish_readers(false);
+                    self.pass_barrier(sends);
                 }
+                self.ship_move_log();
 
                 ProcessResult::KeepPolling
             }
             PollEvent::Timeout => {
-                while let Some(m) = self.group_commit_queues.flush_if_necessary() {
-                    self.handle(m, sends, executor, true);
+                // nothing that changes the domain's state happens while it is paused
+                if !self.paused {
+                    self.release_held_writes(sends, executor);
+                    while let Some(m) = self.group_commit_queues.flush_if_necessary() {
+                        self.commit(m, sends, executor);
+                    }
+                }
+                if let Err(e) = self.wal.sync_if_necessary() {
+                    panic!("failed to sync write-ahead log: {}", e);
+                }
+                if !self.paused {
+                    self.fire_timers(sends);
+                    self.publish_readers(false);
                 }
 
                 if self.has_buffered_replay_requests {
                     self.handle(box Packet::Spin, sends, executor, true);
                 }
 
+                if self.streamed_replay_due(true) {
+                    self.continue_streamed_replay(sends, executor);
+                }
+                self.pass_barrier(sends);
+
                 ProcessResult::KeepPolling
             }
         };
@@ -2507,3 +5240,358 @@ impl Domain {
         res
     }
 }
+
+/// Deal with the records that `node` could not process as `policy` says to.
+fn reject_malformed(
+    policy: MalformedRecords,
+    node: &Node,
+    malformed: Vec<Malformed>,
+    nodes: &DomainNodes,
+    metrics: &NodeMetrics,
+    dead_letters: &DeadLetterBox,
+    log: &Logger,
+) {
+    // records that the node's own materialization could not apply come from the node itself,
+    // which the caller is already holding on to
+    let global = |from: LocalNodeIndex| {
+        if from == node.local_addr() {
+            node.global_addr()
+        } else {
+            nodes[from].borrow().global_addr()
+        }
+    };
+    let from = global(malformed[0].from);
+    match policy {
+        MalformedRecords::Panic => {
+            let m = &malformed[0];
+            panic!(
+                "node {} could not process {:?} from node {}: {}",
+                node.global_addr().index(),
+                m.record,
+                from.index(),
+                m.reason
+            );
+        }
+        MalformedRecords::DropAndCount => {
+            metrics.malformed(malformed.len());
+            warn!(log, "dropped records that could not be processed";
+                  "node" => node.global_addr().index(),
+                  "from" => from.index(),
+                  "records" => malformed.len(),
+                  "reason" => &malformed[0].reason);
+        }
+        MalformedRecords::DeadLetter => {
+            metrics.malformed(malformed.len());
+            for m in malformed {
+                let from = global(m.from);
+                dead_letters.post_malformed(node.global_addr(), from, m.record, &m.reason);
+            }
+        }
+    }
+}
+
+/// Split a write into writes of at most `n` operations each.
+///
+/// Only the last piece carries the write's senders and markers, so that they are not acknowledged
+/// or waited for until the whole write has been applied.
+fn split_input(m: Box<Packet>, n: usize) -> Vec<Box<Packet>> {
+    let (
+        Input {
+            dst,
+            mut data,
+            tracer,
+            markers,
+        },
+        src,
+        senders,
+    ) = match *m {
+        Packet::Input {
+            inner,
+            src,
+            senders,
+        } => (unsafe { inner.take() }, src, senders),
+        _ => unreachable!(),
+    };
+
+    let mut pieces = Vec::with_capacity(data.len() / n + 1);
+    while data.len() > n {
+        let rest = data.split_off(n);
+        pieces.push(mem::replace(&mut data, rest));
+    }
+
+    let last = Box::new(Packet::Input {
+        inner: LocalOrNot::new(Input {
+            dst,
+            data,
+            tracer,
+            markers,
+        }),
+        src,
+        senders,
+    });
+    pieces
+        .into_iter()
+        .map(|data| {
+            Box::new(Packet::Input {
+                inner: LocalOrNot::new(Input {
+                    dst,
+                    data,
+                    tracer: None,
+                    markers: Vec::new(),
+                }),
+                src,
+                senders: Vec::new(),
+            })
+        })
+        .chain(Some(last))
+        .collect()
+}
+
+/// Send the rows of a full replay along `tag` to the domain itself through `tx`, in chunks of
+/// `batch_size` rows. If the domain goes away before all of them are sent, the rows that weren't
+/// are posted as a single dead letter.
+fn send_replay_chunks<I>(
+    rows: I,
+    tag: Tag,
+    link: &Link,
+    batch_size: usize,
+    tx: &mut dyn channel::Sender<Item = Box<Packet>>,
+    dead_letters: &DeadLetterBox,
+    metrics: &DomainMetrics,
+    log: &Logger,
+) where
+    I: Iterator<Item = Vec<DataType>>,
+{
+    use itertools::Itertools;
+    use std::iter::FromIterator;
+
+    let start = time::Instant::now();
+    debug!(log, "starting state chunker"; "node" => %link.dst);
+
+    let iter = rows.chunks(batch_size);
+    let mut iter = iter.into_iter().enumerate().peekable();
+    let mut sent = start;
+
+    // process all records in state to completion within domain
+    // and then forward on tx (if there is one)
+    while let Some((i, chunk)) = iter.next() {
+        let chunk = Records::from_iter(chunk);
+        let len = chunk.len();
+        let last = iter.peek().is_none();
+        let p = box Packet::ReplayPiece {
+            tag: tag,
+            link: link.clone(), // to is overwritten by receiver
+            context: ReplayPieceContext::Regular {
+                last,
+                rows: None,
+                cut: None,
+            },
+            data: chunk,
+        };
+
+        trace!(log, "sending batch"; "#" => i, "[]" => len);
+        if let Err(e) = tx.send(p) {
+            let rest: usize = iter.map(|(_, chunk)| chunk.count()).sum();
+            dead_letters.post(dead_letters.to_self(), "ReplayPiece", len + rest, &e);
+            break;
+        }
+        let now = time::Instant::now();
+        metrics.replayed(len, now - sent);
+        sent = now;
+    }
+
+    debug!(log,
+       "state chunker finished";
+       "node" => %link.dst,
+       "μs" => start.elapsed().as_micros()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use noria::debug::dead_letters::Destination;
+    use slog;
+
+    #[test]
+    fn it_posts_the_rest_of_a_replay_whose_domain_is_gone() {
+        let log = Logger::root(slog::Discard, o!());
+        let metrics = Metrics::new();
+        let letters = DeadLetters::new();
+        let me = (Index::from(0), 0);
+        let m = metrics.register(me.0, me.1);
+        let dead_letters = DeadLetterBox::new(me, letters.clone(), m.clone(), log.clone());
+        let link = unsafe { Link::new(LocalNodeIndex::make(0), LocalNodeIndex::make(1)) };
+        let rows = || (0..1000).map(|i| vec![DataType::from(i)]);
+
+        let (mut tx, rx) = futures::sync::mpsc::unbounded();
+        send_replay_chunks(rows(), Tag(0), &link, 256, &mut tx, &dead_letters, &m, &log);
+        let sent: Vec<_> = rx.wait().take(4).map(|p| p.unwrap().records()).collect();
+        assert_eq!(sent, vec![256, 256, 256, 232]);
+        assert!(letters.drain().is_empty());
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.sum("noria_domain_replayed_records", &[]), 1000);
+        assert_eq!(snapshot.sum("noria_domain_replay_chunks", &[]), 4);
+
+        // the domain goes away, and with it, the receiving end
+        let (mut tx, rx) = futures::sync::mpsc::unbounded();
+        drop(rx);
+        send_replay_chunks(rows(), Tag(0), &link, 256, &mut tx, &dead_letters, &m, &log);
+        let posted = letters.drain();
+        assert_eq!(posted.len(), 1);
+        assert_eq!(posted[0].to, Destination::Domain(me.0, me.1));
+        assert_eq!(posted[0].kind, "ReplayPiece");
+        assert_eq!(posted[0].records, 1000);
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.sum("noria_domain_dead_letters", &[]), 1);
+        // and the chunks that weren't sent weren't replayed
+        assert_eq!(snapshot.sum("noria_domain_replayed_records", &[]), 1000);
+    }
+
+    // Two nodes, the second of which reads the first, and a malformed record of each sign that the
+    // first sent the second.
+    fn malformed() -> (DomainNodes, Vec<Malformed>) {
+        use node::NodeType;
+
+        let mut nodes = DomainNodes::default();
+        for i in 0..2 {
+            let local = unsafe { LocalNodeIndex::make(i as u32) };
+            let mut addr = IndexPair::from(NodeIndex::new(i + 10));
+            addr.set_local(local);
+            let mut n = Node::new(format!("n{}", i), &["x"], NodeType::Ingress);
+            n.set_finalized_addr(addr);
+            nodes.insert(local, cell::RefCell::new(n));
+        }
+        let from = unsafe { LocalNodeIndex::make(0) };
+        let malformed = vec![
+            Malformed {
+                from,
+                record: Record::Positive(vec!["x".into()]),
+                reason: String::from("column 0 holds Text, which is not an integer"),
+            },
+            Malformed {
+                from,
+                record: Record::Negative(vec!["x".into()]),
+                reason: String::from("column 0 holds Text, which is not an integer"),
+            },
+        ];
+        (nodes, malformed)
+    }
+
+    #[test]
+    fn it_drops_or_dead_letters_malformed_records() {
+        let log = Logger::root(slog::Discard, o!());
+        let metrics = Metrics::new();
+        let letters = DeadLetters::new();
+        let me = (Index::from(0), 0);
+        let domain_metrics = metrics.register(me.0, me.1);
+        let dead_letters =
+            DeadLetterBox::new(me, letters.clone(), domain_metrics.clone(), log.clone());
+        let (nodes, malformed) = self::malformed();
+        let node = nodes[unsafe { LocalNodeIndex::make(1) }].borrow();
+        let node_metrics = domain_metrics.add_node(node.local_addr(), node.global_addr(), "n1");
+
+        reject_malformed(
+            MalformedRecords::DropAndCount,
+            &node,
+            malformed,
+            &nodes,
+            &node_metrics,
+            &dead_letters,
+            &log,
+        );
+        assert_eq!(node_metrics.malformed_records(), 2);
+        assert!(letters.drain().is_empty());
+
+        let (_, malformed) = self::malformed();
+        reject_malformed(
+            MalformedRecords::DeadLetter,
+            &node,
+            malformed,
+            &nodes,
+            &node_metrics,
+            &dead_letters,
+            &log,
+        );
+        assert_eq!(node_metrics.malformed_records(), 4);
+        let posted = letters.drain();
+        assert_eq!(posted.len(), 2);
+        assert_eq!(
+            posted[0].to,
+            Destination::Node {
+                node: NodeIndex::new(11),
+                from: NodeIndex::new(10),
+            }
+        );
+        assert_eq!(posted[0].kind, "Positive");
+        assert_eq!(posted[1].kind, "Negative");
+        assert_eq!(posted[1].record, Some(vec!["x".into()]));
+        assert_eq!(
+            metrics
+                .snapshot()
+                .sum("noria_node_malformed_records", &[("node", "11")]),
+            4
+        );
+    }
+
+    #[test]
+    fn it_dead_letters_negatives_below_zero_as_from_the_node_itself() {
+        let log = Logger::root(slog::Discard, o!());
+        let letters = DeadLetters::new();
+        let me = (Index::from(0), 0);
+        let domain_metrics = Metrics::new().register(me.0, me.1);
+        let dead_letters =
+            DeadLetterBox::new(me, letters.clone(), domain_metrics.clone(), log.clone());
+        let (nodes, _) = self::malformed();
+        // the domain holds on to the node while it processes, just like here
+        let local = unsafe { LocalNodeIndex::make(1) };
+        let node = nodes[local].borrow_mut();
+        let node_metrics = domain_metrics.add_node(node.local_addr(), node.global_addr(), "n1");
+
+        let mut malformed = Vec::new();
+        note_unmatched(local, vec![Record::Negative(vec!["x".into()])], &mut malformed);
+        reject_malformed(
+            MalformedRecords::DeadLetter,
+            &node,
+            malformed,
+            &nodes,
+            &node_metrics,
+            &dead_letters,
+            &log,
+        );
+        assert_eq!(node_metrics.malformed_records(), 1);
+        let posted = letters.drain();
+        assert_eq!(posted.len(), 1);
+        assert_eq!(
+            posted[0].to,
+            Destination::Node {
+                node: NodeIndex::new(11),
+                from: NodeIndex::new(11),
+            }
+        );
+        assert_eq!(posted[0].kind, "Negative");
+    }
+
+    #[test]
+    #[should_panic(expected = "node 11 could not process")]
+    fn it_panics_on_malformed_records_by_default() {
+        let log = Logger::root(slog::Discard, o!());
+        let letters = DeadLetters::new();
+        let me = (Index::from(0), 0);
+        let domain_metrics = Metrics::new().register(me.0, me.1);
+        let dead_letters = DeadLetterBox::new(me, letters, domain_metrics.clone(), log.clone());
+        let (nodes, malformed) = self::malformed();
+        let node = nodes[unsafe { LocalNodeIndex::make(1) }].borrow();
+        let node_metrics = domain_metrics.add_node(node.local_addr(), node.global_addr(), "n1");
+
+        reject_malformed(
+            MalformedRecords::default(),
+            &node,
+            malformed,
+            &nodes,
+            &node_metrics,
+            &dead_letters,
+            &log,
+        );
+    }
+}
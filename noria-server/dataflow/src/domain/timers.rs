@@ -0,0 +1,243 @@
+//! Timers that wake a domain up at some point, whether or not any packets arrive by then.
+//!
+//! Both the domain itself and the operators of its nodes set timers. Each timer is known by its
+//! owner and a token that the owner chose, and firing a timer tells the owner which token fired.
+
+use prelude::*;
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
+use std::time::{Duration, Instant};
+
+/// Who set a timer, and so who is told when it fires.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum TimerOwner {
+    /// The domain itself.
+    Domain,
+    /// The operator of the given node.
+    Node(LocalNodeIndex),
+}
+
+struct SetTimer {
+    due: Instant,
+    seq: u64,
+    every: Option<Duration>,
+}
+
+/// The timers that are set in a domain.
+#[derive(Default)]
+pub(crate) struct Timers {
+    // the timers in the order they come due. timers that are due at the same time fire in the
+    // order they were set in, which is what the sequence number is for.
+    due: BTreeMap<(Instant, u64), (TimerOwner, u64)>,
+    set: HashMap<(TimerOwner, u64), SetTimer>,
+    next_seq: u64,
+}
+
+impl Timers {
+    /// Set the timer with the given owner and token to fire `after` from `now`, and then every
+    /// `every` after that, if given. The owner's timer with the same token is replaced if set.
+    pub(crate) fn set(
+        &mut self,
+        owner: TimerOwner,
+        token: u64,
+        now: Instant,
+        after: Duration,
+        every: Option<Duration>,
+    ) {
+        assert_ne!(
+            every,
+            Some(Duration::from_millis(0)),
+            "a recurring timer must have a period"
+        );
+        self.cancel(owner, token);
+        self.insert(owner, token, now + after, every);
+    }
+
+    fn insert(&mut self, owner: TimerOwner, token: u64, due: Instant, every: Option<Duration>) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.due.insert((due, seq), (owner, token));
+        self.set
+            .insert((owner, token), SetTimer { due, seq, every });
+    }
+
+    /// Whether the owner has a timer with the given token set.
+    pub(crate) fn is_set(&self, owner: TimerOwner, token: u64) -> bool {
+        self.set.contains_key(&(owner, token))
+    }
+
+    /// Cancel the owner's timer with the given token. Returns whether it was set.
+    pub(crate) fn cancel(&mut self, owner: TimerOwner, token: u64) -> bool {
+        match self.set.remove(&(owner, token)) {
+            Some(t) => {
+                self.due.remove(&(t.due, t.seq));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Cancel every timer that the given owner set, such as when a node is removed.
+    pub(crate) fn cancel_all(&mut self, owner: TimerOwner) {
+        let tokens: Vec<_> = self
+            .set
+            .keys()
+            .filter(|&&(o, _)| o == owner)
+            .map(|&(_, token)| token)
+            .collect();
+        for token in tokens {
+            self.cancel(owner, token);
+        }
+    }
+
+    /// Set and cancel the timers that the operator of `node` asked for.
+    pub(crate) fn request(
+        &mut self,
+        node: LocalNodeIndex,
+        requests: Vec<TimerRequest>,
+        now: Instant,
+    ) {
+        let owner = TimerOwner::Node(node);
+        for r in requests {
+            match r {
+                TimerRequest::Once { token, after } => self.set(owner, token, now, after, None),
+                TimerRequest::Every { token, every } => {
+                    self.set(owner, token, now, every, Some(every))
+                }
+                TimerRequest::Cancel(token) => {
+                    self.cancel(owner, token);
+                }
+            }
+        }
+    }
+
+    /// How long from `now` until the next timer is due, if any timer is set.
+    pub(crate) fn duration_until_due(&self, now: Instant) -> Option<Duration> {
+        self.due.keys().next().map(|&(due, _)| {
+            if due > now {
+                due - now
+            } else {
+                Duration::from_millis(0)
+            }
+        })
+    }
+
+    /// Take the timers that are due at `now`, in the order they came due.
+    ///
+    /// A recurring timer is set to fire again one period after it was due, or one period after
+    /// `now` if that time has passed too. A timer that fires late, for example because the domain
+    /// was busy with a replay, thus fires once rather than again and again to catch up.
+    pub(crate) fn take_due(&mut self, now: Instant) -> Vec<(TimerOwner, u64)> {
+        let later = self.due.split_off(&(now, u64::max_value()));
+        let due = mem::replace(&mut self.due, later);
+
+        let mut fired = Vec::with_capacity(due.len());
+        for ((at, _), (owner, token)) in due {
+            let t = self.set.remove(&(owner, token)).unwrap();
+            if let Some(every) = t.every {
+                let next = if at + every > now {
+                    at + every
+                } else {
+                    now + every
+                };
+                self.insert(owner, token, next, Some(every));
+            }
+            fired.push((owner, token));
+        }
+        fired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_fires_recurring_timers_a_bounded_number_of_times() {
+        let node = unsafe { LocalNodeIndex::make(0) };
+        let owner = TimerOwner::Node(node);
+        let every = Duration::from_millis(10);
+        let start = Instant::now();
+        let mut timers = Timers::default();
+        timers.request(node, vec![TimerRequest::Every { token: 7, every }], start);
+        assert_eq!(timers.duration_until_due(start), Some(every));
+
+        // however often the domain wakes up, a 10ms timer fires about 100 times a second
+        for &step in &[1, 3, 10, 25] {
+            let mut timers = Timers::default();
+            timers.request(node, vec![TimerRequest::Every { token: 7, every }], start);
+            let mut fired = 0;
+            for i in 1..=(1000 / step) {
+                let now = start + Duration::from_millis(i * step);
+                for f in timers.take_due(now) {
+                    assert_eq!(f, (owner, 7));
+                    fired += 1;
+                }
+            }
+            assert!(fired >= 1000 / 25 && fired <= 100, "fired {} times", fired);
+        }
+
+        // a domain that is held up, say by a replay, fires the timer once when it gets to it
+        let now = start + Duration::from_millis(500);
+        assert_eq!(timers.take_due(now), vec![(owner, 7)]);
+        assert!(timers.take_due(now + Duration::from_millis(9)).is_empty());
+        assert_eq!(
+            timers.take_due(now + Duration::from_millis(10)),
+            vec![(owner, 7)]
+        );
+    }
+
+    #[test]
+    fn it_replaces_and_cancels_timers() {
+        let a = unsafe { LocalNodeIndex::make(0) };
+        let b = unsafe { LocalNodeIndex::make(1) };
+        let ms = Duration::from_millis;
+        let now = Instant::now();
+        let mut timers = Timers::default();
+        timers.request(
+            a,
+            vec![
+                TimerRequest::Once {
+                    token: 1,
+                    after: ms(5),
+                },
+                TimerRequest::Once {
+                    token: 2,
+                    after: ms(5),
+                },
+                TimerRequest::Once {
+                    token: 1,
+                    after: ms(10),
+                },
+            ],
+            now,
+        );
+        timers.request(
+            b,
+            vec![TimerRequest::Every {
+                token: 1,
+                every: ms(1),
+            }],
+            now,
+        );
+        timers.set(TimerOwner::Domain, 1, now, ms(5), None);
+
+        // timers that are due at the same time fire in the order they were set in
+        assert_eq!(
+            timers.take_due(now + ms(5)),
+            vec![
+                (TimerOwner::Node(b), 1),
+                (TimerOwner::Node(a), 2),
+                (TimerOwner::Domain, 1),
+            ]
+        );
+        assert!(timers.is_set(TimerOwner::Node(a), 1));
+        assert!(!timers.is_set(TimerOwner::Node(a), 2));
+        assert!(!timers.is_set(TimerOwner::Domain, 1));
+
+        timers.request(a, vec![TimerRequest::Cancel(1)], now);
+        timers.cancel_all(TimerOwner::Node(b));
+        assert_eq!(timers.duration_until_due(now), None);
+        assert!(timers.take_due(now + ms(100)).is_empty());
+    }
+}
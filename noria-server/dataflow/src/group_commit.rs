@@ -43,11 +43,27 @@ impl GroupCommitQueueSet {
         }
     }
 
+    /// Merge any pending packets for the given node, regardless of how long they have waited.
+    pub fn flush(&mut self, node: LocalNodeIndex) -> Option<Box<Packet>> {
+        match self.pending_packets.get_mut(node) {
+            Some(&mut (_, ref mut ps)) => Self::merge_packets(ps),
+            None => None,
+        }
+    }
+
     /// Merge any pending packets.
     fn flush_internal(&mut self, node: LocalNodeIndex) -> Option<Box<Packet>> {
         Self::merge_packets(&mut self.pending_packets[node].1)
     }
 
+    /// The number of packets that are waiting to be persisted.
+    pub fn queued(&self) -> usize {
+        self.pending_packets
+            .values()
+            .map(|&(_, ref ps)| ps.len())
+            .sum()
+    }
+
     /// Add a new packet to be persisted, and if this triggered a flush return an iterator over the
     /// packets that were written.
     pub fn append<'a>(&mut self, p: Box<Packet>) -> Option<Box<Packet>> {
@@ -91,6 +107,7 @@ impl GroupCommitQueueSet {
         let mut merged_tracer: Tracer = None;
 
         let mut all_senders = vec![];
+        let mut all_markers = vec![];
         let merged_data = packets.fold(Vec::new(), |mut acc, p| {
             match *p {
                 Packet::Input {
@@ -98,11 +115,17 @@ impl GroupCommitQueueSet {
                     src,
                     senders,
                 } => {
-                    let Input { dst, data, tracer } = unsafe { inner.take() };
+                    let Input {
+                        dst,
+                        data,
+                        tracer,
+                        markers,
+                    } = unsafe { inner.take() };
 
                     assert_eq!(senders.len(), 0);
                     assert_eq!(merged_dst, dst);
                     acc.extend(data);
+                    all_markers.extend(markers);
 
                     if let Some(src) = src {
                         all_senders.push(src);
@@ -134,6 +157,7 @@ impl GroupCommitQueueSet {
                 dst: merged_dst,
                 data: merged_data,
                 tracer: merged_tracer,
+                markers: all_markers,
             }),
             src: None,
             senders: all_senders,
@@ -8,7 +8,9 @@
 #[cfg(debug_assertions)]
 extern crate backtrace;
 extern crate bincode;
+extern crate chrono;
 extern crate common;
+extern crate crossbeam_utils;
 extern crate evmap;
 extern crate fnv;
 extern crate futures;
@@ -34,11 +36,16 @@ extern crate tokio;
 extern crate vec_map;
 
 pub mod backlog;
+pub mod backpressure;
+pub mod budget;
+pub mod dead_letters;
+pub mod metrics;
 pub mod node;
 pub mod ops;
 pub mod payload;
 pub mod prelude;
 pub mod state;
+pub mod wal;
 
 mod domain;
 mod group_commit;
@@ -53,7 +60,7 @@ pub type Readers =
     Arc<Mutex<HashMap<(petgraph::graph::NodeIndex, usize), backlog::SingleReadHandle>>>;
 pub type DomainConfig = domain::Config;
 
-pub use domain::{Domain, DomainBuilder, Index};
+pub use domain::{CaptureEvent, Captured, Domain, DomainBuilder, Index, MoveSnapshot, Replay};
 pub use payload::Packet;
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
@@ -80,6 +87,128 @@ impl Sharding {
     }
 }
 
+/// The kind of index used to look up rows in a materialization.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum IndexType {
+    /// A hash index, which only supports lookups of exact keys.
+    HashMap,
+    /// An index ordered by a single column, which also supports lookups by range.
+    BTreeMap,
+}
+
+impl Default for IndexType {
+    fn default() -> Self {
+        IndexType::HashMap
+    }
+}
+
+/// Where the state of a materialized node is kept.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum StateBackend {
+    /// In memory, which is fastest, but bounded by the memory of the worker.
+    Memory,
+    /// On disk, in RocksDB. Every lookup goes to the database, and the node is always fully
+    /// materialized.
+    Disk,
+}
+
+impl Default for StateBackend {
+    fn default() -> Self {
+        StateBackend::Memory
+    }
+}
+
+/// Whether the migration planner should keep state for a node.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum MaterializationHint {
+    /// Keep state for the node only if something needs it.
+    Auto,
+    /// Always keep state for the node, even if it could be computed on the fly instead.
+    Forced,
+    /// Never keep state for the node. Migrations that need its state fail instead.
+    Forbidden,
+}
+
+impl Default for MaterializationHint {
+    fn default() -> Self {
+        MaterializationHint::Auto
+    }
+}
+
+/// When a reader makes the writes that reach it visible to reads.
+///
+/// Making writes visible means swapping the reader's two copies of its state, which has to wait
+/// for reads of the old copy to finish. Swapping after every message keeps reads as fresh as
+/// possible, but makes every message pay for a swap, which hurts throughput under heavy writes.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum PublishPolicy {
+    /// After every message.
+    EveryMessage,
+    /// Once this many records have arrived since the last swap, or whenever the domain runs out
+    /// of messages to process.
+    EveryRecords(usize),
+    /// Once the oldest write that reads can't see yet has waited this long, even if the domain
+    /// has nothing else to do. This bounds how often the reader swaps.
+    Interval(time::Duration),
+    /// Whenever the domain runs out of messages to process, or once the oldest write that reads
+    /// can't see yet has waited `max_delay`. While writes are rare, this swaps after every
+    /// message, and the busier the domain gets, the more writes each swap publishes.
+    Adaptive {
+        /// The longest a write may wait to become visible while the domain is busy.
+        max_delay: time::Duration,
+    },
+}
+
+impl Default for PublishPolicy {
+    fn default() -> Self {
+        PublishPolicy::Adaptive {
+            max_delay: time::Duration::from_millis(1),
+        }
+    }
+}
+
+/// How the domain that a full replay starts at shares its time between sending the replay and
+/// processing the live updates that keep arriving while the replay runs.
+///
+/// The replay is sent a chunk of records at a time, and each chunk has to make its way through
+/// every node on the replay path. The more chunks the domain sends in a row, the longer live
+/// writes wait, and the fewer it sends, the longer the migration takes.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum ReplayPriority {
+    /// Send the replay as fast as the domain will take it, alongside live updates.
+    FavorReplay,
+    /// Send a chunk of the replay once `every` live updates have been processed since the last
+    /// one, and whenever the domain runs out of live updates to process.
+    FavorLive {
+        /// The number of live updates to process for every chunk of the replay.
+        every: usize,
+    },
+    /// Send the replay at no more than the given number of records a second, whether or not there
+    /// are live updates to process.
+    Capped {
+        /// The most records to replay a second.
+        records_per_sec: usize,
+    },
+}
+
+impl Default for ReplayPriority {
+    fn default() -> Self {
+        ReplayPriority::FavorReplay
+    }
+}
+
+/// Where the migration planner should put a new node, rather than leaving it to choose.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Placement {
+    /// In the same domain as the given node, which must already be in a domain, or be new and
+    /// come before this node in the graph.
+    With(petgraph::graph::NodeIndex),
+    /// In a new domain of its own.
+    Dedicated,
+    /// In the domain with the given index.
+    Domain(usize),
+}
+
 /// Indicates to what degree updates should be persisted.
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub enum DurabilityMode {
@@ -104,6 +233,9 @@ pub struct PersistenceParameters {
     pub log_dir: Option<PathBuf>,
     /// Number of background threads PersistentState can use (shared acrosss all worker threads).
     pub persistence_threads: i32,
+    /// If set, writes to base nodes are appended to a write-ahead log before they are processed.
+    #[serde(default)]
+    pub wal: Option<WalParameters>,
 }
 
 impl Default for PersistenceParameters {
@@ -114,7 +246,158 @@ impl Default for PersistenceParameters {
             log_prefix: String::from("soup"),
             log_dir: None,
             persistence_threads: 1,
+            wal: None,
+        }
+    }
+}
+
+/// When a write-ahead log is forced to disk.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub enum SyncPolicy {
+    /// After every batch of writes that is appended, before the writes are processed or
+    /// acknowledged.
+    EveryWrite,
+    /// At most this long after a batch of writes is appended. Writes that are acknowledged in the
+    /// meantime can be lost if the machine crashes.
+    Interval(time::Duration),
+    /// Never, which leaves it to the operating system. Writes survive the process crashing, but
+    /// not the machine.
+    Never,
+}
+
+/// Parameters for the write-ahead logs that writes to base nodes are appended to.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct WalParameters {
+    /// When the logs are forced to disk.
+    pub sync: SyncPolicy,
+    /// A log moves on to a new segment file once its current segment is at least this many bytes.
+    pub segment_size: usize,
+    /// Whether bases are recovered from their logs when the controller restores the graph on
+    /// startup. This only applies to bases that are kept in memory, since bases that are kept on
+    /// disk are recovered from there.
+    pub recover: bool,
+}
+
+impl Default for WalParameters {
+    fn default() -> Self {
+        WalParameters {
+            sync: SyncPolicy::EveryWrite,
+            segment_size: 64 * 1024 * 1024,
+            recover: true,
+        }
+    }
+}
+
+/// What happens to a write to a base while the domains below it are overloaded.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub enum OverloadPolicy {
+    /// The write is rejected with `WriteError::Overloaded`, and it is up to the writer to try
+    /// again later.
+    Reject,
+    /// The write is held back, unacknowledged, until the domains below have caught up. Writes are
+    /// still applied in the order in which they arrived.
+    Block,
+}
+
+/// When the domains below a base are considered overloaded, and what happens to writes to the base
+/// while they are.
+///
+/// A domain counts the packets that are waiting for it to process them, and those it has yet to
+/// send on. It becomes overloaded once that is `high` or more, and stays so until it is `low` or
+/// less. Writes to a base are held to `policy` while its own domain, or any domain its updates
+/// flow to, is overloaded. Only domains run by the same worker as the base's are taken into
+/// account.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Backpressure {
+    /// The number of waiting packets at which a domain becomes overloaded.
+    pub high: usize,
+    /// The number of waiting packets at which an overloaded domain is no longer overloaded.
+    pub low: usize,
+    /// What happens to writes while a domain below their base is overloaded.
+    pub policy: OverloadPolicy,
+}
+
+impl Default for Backpressure {
+    fn default() -> Self {
+        Backpressure {
+            high: 4096,
+            low: 1024,
+            policy: OverloadPolicy::Reject,
+        }
+    }
+}
+
+/// What domains do with a record that reaches an operator which can't process it, because it
+/// lacks a column that the operator reads, or holds a value of a type that the operator can't
+/// work with (see `Ingredient::validate`). Such records usually stem from a bug, or from a schema
+/// change that left old rows behind. The same goes for a negative record that would leave fewer
+/// than zero copies of a row in the materialized state of a node or in a reader.
+///
+/// Positive and negative records are treated alike, so that an operator whose state a malformed
+/// row never made it into doesn't see that row retracted either.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub enum MalformedRecords {
+    /// The domain panics. This is mostly useful in tests.
+    Panic,
+    /// The record is dropped and counted in the statistics and metrics of the node, and a warning
+    /// is logged.
+    DropAndCount,
+    /// The record is dropped and counted like with `DropAndCount`, and it is also posted as a dead
+    /// letter, along with the node that could not process it and the node that sent it.
+    DeadLetter,
+}
+
+impl Default for MalformedRecords {
+    fn default() -> Self {
+        MalformedRecords::Panic
+    }
+}
+
+/// How many bytes the connections that domains send and receive packets over buffer.
+///
+/// Every capacity must be greater than zero. A connection without room to read into looks like it
+/// was closed by the other end as soon as it is read from, which would cut a domain off from the
+/// controller, from other domains, or from its clients.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ChannelConfig {
+    /// The buffer of the connection over which a domain answers the controller.
+    pub control: usize,
+    /// The read buffer of each connection over which another domain sends packets to a domain.
+    pub domain: usize,
+    /// The buffer of each connection over which a client writes to the bases of a domain, and is
+    /// sent acknowledgements in return.
+    pub input: usize,
+    /// The buffer of each connection over which a domain asks a domain run by another worker to
+    /// replay state.
+    pub replay: usize,
+}
+
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        ChannelConfig {
+            control: 8 * 1024,
+            domain: 2 * 1024 * 1024,
+            input: 8 * 1024,
+            replay: 8 * 1024,
+        }
+    }
+}
+
+impl ChannelConfig {
+    /// Check that every capacity is one that domains can work with.
+    pub fn validate(&self) -> Result<(), String> {
+        let capacities = [
+            ("control", self.control),
+            ("domain", self.domain),
+            ("input", self.input),
+            ("replay", self.replay),
+        ];
+        for &(channel, capacity) in &capacities {
+            if capacity == 0 {
+                return Err(format!("the {} channel capacity must be non-zero", channel));
+            }
         }
+        Ok(())
     }
 }
 
@@ -0,0 +1,433 @@
+//! Counters that domains keep up to date as they process packets, and that readers keep up to
+//! date as they are read from.
+//!
+//! Every shard of a domain registers a `DomainMetrics` with the `Metrics` of the worker that runs
+//! it, and keeps the `NodeMetrics` of each of its nodes, and the `ReaderMetrics` of each of its
+//! readers, in it. Updating a counter is a relaxed atomic add, so domains and reads update them as
+//! they go, and anyone holding on to a worker's `Metrics` can take a snapshot of all of them at
+//! any time, without the domains being involved.
+
+use noria::debug::metrics::{MetricsSnapshot, Sample};
+use payload::PACKET_KINDS;
+use prelude::*;
+use std::cmp;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time;
+
+fn add(counter: &AtomicUsize, n: usize) {
+    counter.fetch_add(n, Ordering::Relaxed);
+}
+
+fn nanos(d: time::Duration) -> usize {
+    d.as_nanos() as usize
+}
+
+/// The upper bounds, in nanoseconds, of the buckets that the latencies of writes are counted in.
+/// Writes that take longer than the last bound are counted in a bucket of their own.
+const WRITE_LATENCY_BOUNDS_NS: [usize; 12] = [
+    100_000,
+    250_000,
+    500_000,
+    1_000_000,
+    2_500_000,
+    5_000_000,
+    10_000_000,
+    25_000_000,
+    50_000_000,
+    100_000_000,
+    250_000_000,
+    1_000_000_000,
+];
+
+/// Counters about what a node has processed.
+#[derive(Default)]
+pub struct NodeMetrics {
+    records_in: AtomicUsize,
+    records_out: AtomicUsize,
+    negative_records: AtomicUsize,
+    process_calls: AtomicUsize,
+    process_time: AtomicUsize,
+    /// The most records the node has sent on for a single regular update.
+    peak_records_out: AtomicUsize,
+    /// The most records the node has sent on for each record of a single regular update.
+    peak_fanout: AtomicUsize,
+    /// Records that the node's operator could not process, and that were dropped or dead-lettered.
+    malformed: AtomicUsize,
+}
+
+impl NodeMetrics {
+    /// Count a call to the node's `process`, which was handed `records` records, produced `out`
+    /// records of which `negative` were negative, and took `took`.
+    pub(crate) fn processed(
+        &self,
+        records: usize,
+        out: usize,
+        negative: usize,
+        took: time::Duration,
+    ) {
+        add(&self.records_in, records);
+        add(&self.records_out, out);
+        add(&self.negative_records, negative);
+        add(&self.process_calls, 1);
+        add(&self.process_time, nanos(took));
+    }
+
+    /// Note that a regular update of `records` records made the node send on `out` records in all,
+    /// whether in one piece or in several.
+    pub(crate) fn amplified(&self, records: usize, out: usize) {
+        // only the domain itself stores to the peaks, so there is no race between load and store
+        if out > self.peak_records_out.load(Ordering::Relaxed) {
+            self.peak_records_out.store(out, Ordering::Relaxed);
+        }
+        let fanout = out / cmp::max(records, 1);
+        if fanout > self.peak_fanout.load(Ordering::Relaxed) {
+            self.peak_fanout.store(fanout, Ordering::Relaxed);
+        }
+    }
+
+    /// Count records that the node's operator could not process, and that were passed over.
+    pub(crate) fn malformed(&self, records: usize) {
+        add(&self.malformed, records);
+    }
+
+    /// The number of records that the node's operator could not process so far.
+    pub(crate) fn malformed_records(&self) -> usize {
+        self.malformed.load(Ordering::Relaxed)
+    }
+
+    /// The most records the node has sent on for a single regular update, and the most it has
+    /// sent on for each record of a single regular update.
+    pub(crate) fn peaks(&self) -> (usize, usize) {
+        (
+            self.peak_records_out.load(Ordering::Relaxed),
+            self.peak_fanout.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Counters about the reads of a reader.
+#[derive(Default)]
+pub struct ReaderMetrics {
+    gets: AtomicUsize,
+    misses: AtomicUsize,
+    blocking_waits: AtomicUsize,
+}
+
+impl ReaderMetrics {
+    /// Count a read of `keys` keys, of which `missed` were not found.
+    pub fn read(&self, keys: usize, missed: usize) {
+        add(&self.gets, keys);
+        add(&self.misses, missed);
+    }
+
+    /// Count a read that has to wait for the reader to be filled.
+    pub fn blocked(&self) {
+        add(&self.blocking_waits, 1);
+    }
+}
+
+/// A node of a domain, as far as its metrics are concerned.
+struct Registered {
+    node: NodeIndex,
+    name: String,
+    metrics: Arc<NodeMetrics>,
+    reader: Option<Arc<ReaderMetrics>>,
+}
+
+/// Counters about what a shard of a domain has done, along with those of its nodes and readers.
+pub struct DomainMetrics {
+    domain: DomainIndex,
+    shard: usize,
+    /// Number of packets handled, by `Packet::kind_index`.
+    handled: Vec<AtomicUsize>,
+    iterations: AtomicUsize,
+    send_time: AtomicUsize,
+    lost: AtomicUsize,
+    duplicates: AtomicUsize,
+    started: time::Instant,
+    /// The number of packets waiting, summed over time, up to when it was last updated.
+    queue_time: AtomicUsize,
+    /// When the number of packets waiting was last updated, and what it was then.
+    last_queued: Mutex<(time::Instant, usize)>,
+    peak_queued: AtomicUsize,
+    throttled_writes: AtomicUsize,
+    dead_letters: AtomicUsize,
+    failovers: AtomicUsize,
+    replayed_records: AtomicUsize,
+    replay_chunks: AtomicUsize,
+    replay_time: AtomicUsize,
+    /// Number of writes acknowledged within each of `WRITE_LATENCY_BOUNDS_NS` but not the one
+    /// before it, followed by the number that took longer than all of them.
+    write_latency: Vec<AtomicUsize>,
+    write_latency_sum: AtomicUsize,
+    nodes: Mutex<Map<Registered>>,
+}
+
+impl DomainMetrics {
+    fn new(domain: DomainIndex, shard: usize) -> Self {
+        DomainMetrics {
+            domain,
+            shard,
+            handled: PACKET_KINDS.iter().map(|_| AtomicUsize::new(0)).collect(),
+            iterations: AtomicUsize::new(0),
+            send_time: AtomicUsize::new(0),
+            lost: AtomicUsize::new(0),
+            duplicates: AtomicUsize::new(0),
+            started: time::Instant::now(),
+            queue_time: AtomicUsize::new(0),
+            last_queued: Mutex::new((time::Instant::now(), 0)),
+            peak_queued: AtomicUsize::new(0),
+            throttled_writes: AtomicUsize::new(0),
+            dead_letters: AtomicUsize::new(0),
+            failovers: AtomicUsize::new(0),
+            replayed_records: AtomicUsize::new(0),
+            replay_chunks: AtomicUsize::new(0),
+            replay_time: AtomicUsize::new(0),
+            write_latency: (0..=WRITE_LATENCY_BOUNDS_NS.len())
+                .map(|_| AtomicUsize::new(0))
+                .collect(),
+            write_latency_sum: AtomicUsize::new(0),
+            nodes: Mutex::new(Map::default()),
+        }
+    }
+
+    /// Count a packet handed to the domain.
+    pub(crate) fn handled(&self, packet: &Packet) {
+        add(&self.handled[packet.kind_index()], 1);
+    }
+
+    /// Count a turn of the domain's event loop.
+    pub(crate) fn iteration(&self) {
+        add(&self.iterations, 1);
+    }
+
+    /// Count time that the domain spent sending packets to other domains.
+    pub fn sending(&self, took: time::Duration) {
+        add(&self.send_time, nanos(took));
+    }
+
+    /// Count updates that were sent to the domain from another domain, but never came in.
+    pub(crate) fn lost(&self, packets: u64) {
+        add(&self.lost, packets as usize);
+    }
+
+    /// Count an update that came in from another domain after it had already been delivered.
+    pub(crate) fn duplicate(&self) {
+        add(&self.duplicates, 1);
+    }
+
+    /// Record how many packets are waiting for the domain to process or send them, of which the
+    /// most there have ever been is kept. Until the next time this is called, that many packets
+    /// are taken to be waiting.
+    pub(crate) fn queued(&self, packets: usize) {
+        let now = time::Instant::now();
+        let mut last = self.last_queued.lock().unwrap();
+        add(&self.queue_time, last.1 * nanos(now - last.0));
+        *last = (now, packets);
+
+        // only the domain itself stores to the peak, so there is no race between load and store
+        if packets > self.peak_queued.load(Ordering::Relaxed) {
+            self.peak_queued.store(packets, Ordering::Relaxed);
+        }
+    }
+
+    /// Count a write that was rejected or held back because a domain below its base was
+    /// overloaded.
+    pub(crate) fn throttled(&self) {
+        add(&self.throttled_writes, 1);
+    }
+
+    /// Count a packet that the domain could not deliver, because the receiving end had gone away.
+    pub(crate) fn dead_letter(&self) {
+        add(&self.dead_letters, 1);
+    }
+
+    /// Count an edge that was switched over to a standby, because the ingress that it sent to had
+    /// failed.
+    pub(crate) fn failed_over(&self) {
+        add(&self.failovers, 1);
+    }
+
+    /// Count a chunk of `records` records of a full replay that the domain sent, `took` after it
+    /// sent the chunk before it, or started the replay.
+    pub(crate) fn replayed(&self, records: usize, took: time::Duration) {
+        add(&self.replayed_records, records);
+        add(&self.replay_chunks, 1);
+        add(&self.replay_time, nanos(took));
+    }
+
+    /// Count a write from a client that was acknowledged `took` after it arrived at the domain.
+    pub fn write_acked(&self, took: time::Duration) {
+        let took = nanos(took);
+        let bucket = WRITE_LATENCY_BOUNDS_NS
+            .iter()
+            .position(|&bound| took <= bound)
+            .unwrap_or(WRITE_LATENCY_BOUNDS_NS.len());
+        add(&self.write_latency[bucket], 1);
+        add(&self.write_latency_sum, took);
+    }
+
+    /// Start keeping metrics for a node of the domain.
+    pub(crate) fn add_node(
+        &self,
+        local: LocalNodeIndex,
+        node: NodeIndex,
+        name: &str,
+    ) -> Arc<NodeMetrics> {
+        let metrics = Arc::new(NodeMetrics::default());
+        self.nodes.lock().unwrap().insert(
+            local,
+            Registered {
+                node,
+                name: name.to_owned(),
+                metrics: metrics.clone(),
+                reader: None,
+            },
+        );
+        metrics
+    }
+
+    /// Also keep the metrics of the given reader node's reads.
+    pub(crate) fn add_reader(&self, local: LocalNodeIndex, metrics: Arc<ReaderMetrics>) {
+        if let Some(n) = self.nodes.lock().unwrap().get_mut(local) {
+            n.reader = Some(metrics);
+        }
+    }
+
+    /// Stop keeping metrics for a node that has been removed from the domain.
+    pub(crate) fn remove_node(&self, local: LocalNodeIndex) {
+        self.nodes.lock().unwrap().remove(local);
+    }
+
+    /// The current value of every counter of the domain, and of its nodes and readers.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let domain = self.domain.index().to_string();
+        let shard = self.shard.to_string();
+        let mut samples = Vec::new();
+        {
+            let mut sample = |name: &str, labels: &[(&str, &str)], counter: &AtomicUsize| {
+                let mut all = vec![
+                    ("domain".to_owned(), domain.clone()),
+                    ("shard".to_owned(), shard.clone()),
+                ];
+                all.extend(labels.iter().map(|&(l, v)| (l.to_owned(), v.to_owned())));
+                samples.push(Sample {
+                    name: name.to_owned(),
+                    labels: all,
+                    value: counter.load(Ordering::Relaxed) as u64,
+                });
+            };
+
+            for (kind, handled) in PACKET_KINDS.iter().zip(&self.handled) {
+                if handled.load(Ordering::Relaxed) != 0 {
+                    sample("noria_domain_packets_handled", &[("kind", *kind)], handled);
+                }
+            }
+            sample("noria_domain_loop_iterations", &[], &self.iterations);
+            sample("noria_domain_send_time_ns", &[], &self.send_time);
+            sample("noria_domain_lost_packets", &[], &self.lost);
+            sample("noria_domain_duplicate_packets", &[], &self.duplicates);
+            sample("noria_domain_peak_queued_packets", &[], &self.peak_queued);
+            {
+                // the packets that are waiting now have been waiting since the last update
+                let last = self.last_queued.lock().unwrap();
+                let now = time::Instant::now();
+                let queue_time = self.queue_time.load(Ordering::Relaxed);
+                let queue_time = AtomicUsize::new(queue_time + last.1 * nanos(now - last.0));
+                sample("noria_domain_queue_time_ns", &[], &queue_time);
+                let uptime = AtomicUsize::new(nanos(now - self.started));
+                sample("noria_domain_uptime_ns", &[], &uptime);
+            }
+            sample("noria_domain_throttled_writes", &[], &self.throttled_writes);
+            sample("noria_domain_dead_letters", &[], &self.dead_letters);
+            sample("noria_domain_failovers", &[], &self.failovers);
+            sample("noria_domain_replayed_records", &[], &self.replayed_records);
+            sample("noria_domain_replay_chunks", &[], &self.replay_chunks);
+            sample("noria_domain_replay_time_ns", &[], &self.replay_time);
+            {
+                // each bucket counts every write that took no longer than its bound, as the
+                // buckets of Prometheus histograms do
+                let bounds = WRITE_LATENCY_BOUNDS_NS.iter().map(|b| b.to_string());
+                let bounds = bounds.chain(Some("+Inf".to_owned()));
+                let mut writes = 0;
+                for (le, n) in bounds.zip(&self.write_latency) {
+                    writes += n.load(Ordering::Relaxed);
+                    let name = "noria_domain_write_latency_ns_bucket";
+                    sample(name, &[("le", &le)], &AtomicUsize::new(writes));
+                }
+                let writes = AtomicUsize::new(writes);
+                sample("noria_domain_write_latency_ns_count", &[], &writes);
+                let sum = &self.write_latency_sum;
+                sample("noria_domain_write_latency_ns_sum", &[], sum);
+            }
+
+            for n in self.nodes.lock().unwrap().values() {
+                let node = n.node.index().to_string();
+                let labels = [("node", &node[..]), ("name", &n.name[..])];
+                let m = &n.metrics;
+                sample("noria_node_records_in", &labels, &m.records_in);
+                sample("noria_node_records_out", &labels, &m.records_out);
+                sample("noria_node_negative_records", &labels, &m.negative_records);
+                sample("noria_node_process_calls", &labels, &m.process_calls);
+                sample("noria_node_process_time_ns", &labels, &m.process_time);
+                sample("noria_node_peak_records_out", &labels, &m.peak_records_out);
+                sample("noria_node_peak_fanout", &labels, &m.peak_fanout);
+                sample("noria_node_malformed_records", &labels, &m.malformed);
+                if let Some(ref r) = n.reader {
+                    sample("noria_reader_gets", &labels, &r.gets);
+                    sample("noria_reader_misses", &labels, &r.misses);
+                    sample("noria_reader_blocking_waits", &labels, &r.blocking_waits);
+                }
+            }
+        }
+
+        let mut snapshot = MetricsSnapshot::default();
+        snapshot.extend(MetricsSnapshot { samples });
+        snapshot
+    }
+}
+
+/// The metrics of every domain that a worker runs.
+///
+/// Clones share the same domains, so a worker can hand a clone to each domain it boots while
+/// keeping one to read from.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    domains: Arc<Mutex<Vec<Arc<DomainMetrics>>>>,
+}
+
+impl Metrics {
+    /// Make a set of metrics with no domains in it.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start keeping metrics for a shard of a domain, replacing those of any earlier instance of
+    /// the same shard.
+    pub(crate) fn register(&self, domain: DomainIndex, shard: usize) -> Arc<DomainMetrics> {
+        let metrics = Arc::new(DomainMetrics::new(domain, shard));
+        let mut domains = self.domains.lock().unwrap();
+        domains.retain(|d| (d.domain, d.shard) != (domain, shard));
+        domains.push(metrics.clone());
+        metrics
+    }
+
+    /// Stop keeping the given metrics of a domain shard that has stopped.
+    pub(crate) fn deregister(&self, metrics: &Arc<DomainMetrics>) {
+        self.domains
+            .lock()
+            .unwrap()
+            .retain(|d| !Arc::ptr_eq(d, metrics));
+    }
+
+    /// The current value of every counter of every domain, and of their nodes and readers.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let domains = self.domains.lock().unwrap().clone();
+        let mut snapshot = MetricsSnapshot::default();
+        for d in domains {
+            snapshot.extend(d.snapshot());
+        }
+        snapshot
+    }
+}
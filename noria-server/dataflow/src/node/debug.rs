@@ -23,6 +23,8 @@ impl Node {
         idx: NodeIndex,
         detailed: bool,
         materialization_status: MaterializationStatus,
+        indices: &[Vec<usize>],
+        size: Option<u64>,
     ) -> String {
         let mut s = String::new();
         let border = match self.sharded_by {
@@ -96,13 +98,19 @@ impl Node {
                 }
             }
         } else {
+            // the domain a node is in is shown by the cluster it is drawn in
+            let (shape, fillcolor) = match self.inner {
+                NodeType::Base(..) => ("record", "\"#9ECAE1\""),
+                NodeType::Reader(..) => ("Mrecord", "\"#A1D99B\""),
+                NodeType::Ingress | NodeType::Egress { .. } => ("record", "\"#D9D9D9\""),
+                NodeType::Sharder(..) => ("record", "\"#FDAE6B\""),
+                NodeType::Source | NodeType::Dropped | NodeType::Internal(..) => {
+                    ("record", "white")
+                }
+            };
             s.push_str(&format!(
-                " [style=\"{}\", fillcolor={}, label=\"",
-                border,
-                self.domain
-                    .map(|d| -> usize { d.into() })
-                    .map(|d| format!("\"/set312/{}\"", (d % 12) + 1))
-                    .unwrap_or("white".into())
+                " [shape={}, style=\"{}\", fillcolor={}, label=\"",
+                shape, border, fillcolor
             ));
 
             let materialized = match materialization_status {
@@ -111,8 +119,32 @@ impl Node {
                 MaterializationStatus::Full => "| █",
             };
 
+            // what the state is indexed on, and how large it is, as an extra row
+            let mut state = Vec::new();
+            if !indices.is_empty() {
+                let indices: Vec<_> = indices.iter().map(|cols| format!("{:?}", cols)).collect();
+                state.push(format!("indexed on {}", indices.join(", ")));
+            }
+            match (materialization_status, size) {
+                (MaterializationStatus::Not, _) | (_, None) => {}
+                (_, Some(bytes)) => state.push(format!("~{} bytes", bytes)),
+            }
+            let state = if state.is_empty() {
+                String::new()
+            } else {
+                format!(" | {}", state.join(", \\n"))
+            };
+            let fields = self
+                .fields()
+                .iter()
+                .map(|f| Self::escape(f))
+                .collect::<Vec<_>>()
+                .join(", \\n");
+
             let sharding = match self.sharded_by {
-                Sharding::ByColumn(k, w) => format!("shard ⚷: {} / {}-way", self.fields[k], w),
+                Sharding::ByColumn(k, w) => {
+                    format!("shard ⚷: {} / {}-way", Self::escape(&self.fields[k]), w)
+                }
                 Sharding::Random(_) => format!("shard randomly"),
                 Sharding::None => "unsharded".to_owned(),
                 Sharding::ForcedNone => "desharded to avoid SS".to_owned(),
@@ -134,18 +166,19 @@ impl Node {
                 NodeType::Dropped => s.push_str(&format!("{{ {} | dropped }}", addr)),
                 NodeType::Base(..) => {
                     s.push_str(&format!(
-                        "{{ {{ {} / {} | {} {} }} | {} | {} }}",
+                        "{{ {{ {} / {} | {} {} }} | {} | {}{} }}",
                         addr,
                         Self::escape(self.name()),
                         "B",
                         materialized,
-                        self.fields().join(", \\n"),
-                        sharding
+                        fields,
+                        sharding,
+                        state
                     ));
                 }
                 NodeType::Ingress => s.push_str(&format!(
-                    "{{ {{ {} {} }} | (ingress) | {} }}",
-                    addr, materialized, sharding
+                    "{{ {{ {} {} }} | (ingress) | {}{} }}",
+                    addr, materialized, sharding, state
                 )),
                 NodeType::Egress { .. } => {
                     s.push_str(&format!("{{ {} | (egress) | {} }}", addr, sharding))
@@ -153,7 +186,7 @@ impl Node {
                 NodeType::Sharder(ref sharder) => s.push_str(&format!(
                     "{{ {} | shard by {} | {} }}",
                     addr,
-                    self.fields[sharder.sharded_by()],
+                    Self::escape(&self.fields[sharder.sharded_by()]),
                     sharding
                 )),
                 NodeType::Reader(ref r) => {
@@ -162,12 +195,13 @@ impl Node {
                         Some(k) => format!("{:?}", k),
                     };
                     s.push_str(&format!(
-                        "{{ {{ {} / {} {} }} | (reader / ⚷: {}) | {} }}",
+                        "{{ {{ {} / {} {} }} | (reader / ⚷: {}) | {}{} }}",
                         addr,
                         Self::escape(self.name()),
                         materialized,
                         key,
                         sharding,
+                        state,
                     ))
                 }
                 NodeType::Internal(ref i) => {
@@ -183,8 +217,8 @@ impl Node {
                     ));
 
                     // Output node outputs. Second row.
-                    s.push_str(&format!(" | {}", self.fields().join(", \\n")));
-                    s.push_str(&format!(" | {} }}", sharding))
+                    s.push_str(&format!(" | {}", fields));
+                    s.push_str(&format!(" | {}{} }}", sharding, state))
                 }
             };
             s.push_str("\"]\n");
@@ -197,12 +231,13 @@ impl Node {
         name.starts_with("sp_")
     }
 
+    /// Escape `s` so that it can be used in a record label in a DOT file.
     fn escape(s: &str) -> String {
         use regex::Regex;
 
-        Regex::new("([\"|{}])")
+        Regex::new(r#"([\\"|{}<>])"#)
             .unwrap()
             .replace_all(s, "\\$1")
-            .to_string()
+            .replace('\n', "\\n")
     }
 }
@@ -3,10 +3,12 @@ use ops;
 use petgraph;
 use prelude::*;
 use std::collections::HashMap;
+use std::mem;
 use std::ops::{Deref, DerefMut};
 
 mod process;
 pub use self::process::materialize;
+pub(crate) use self::process::note_unmatched;
 
 pub mod special;
 pub use self::special::StreamUpdate;
@@ -28,6 +30,9 @@ pub struct Node {
     taken: bool,
 
     sharded_by: Sharding,
+    state_backend: StateBackend,
+    materialization_hint: MaterializationHint,
+    output_limit: Option<usize>,
 }
 
 // constructors
@@ -50,6 +55,9 @@ impl Node {
             taken: false,
 
             sharded_by: Sharding::None,
+            state_backend: StateBackend::default(),
+            materialization_hint: MaterializationHint::default(),
+            output_limit: None,
         }
     }
 
@@ -92,6 +100,8 @@ impl Node {
         let mut n = self.mirror(inner);
         n.index = self.index;
         n.domain = self.domain;
+        n.state_backend = self.state_backend;
+        n.output_limit = self.output_limit;
         self.taken = true;
 
         DanglingDomainNode(n)
@@ -106,6 +116,37 @@ impl Node {
         self.sharded_by = s;
     }
 
+    /// Set where this node's state is kept if it is materialized.
+    pub fn set_state_backend(&mut self, backend: StateBackend) {
+        self.state_backend = backend;
+    }
+
+    /// Tell the migration planner whether to keep state for this node.
+    pub fn set_materialization_hint(&mut self, hint: MaterializationHint) {
+        self.materialization_hint = hint;
+    }
+
+    /// Send on the output of this node for a single regular update in pieces of at most `records`
+    /// records, each as an update of its own. Operators that can hold back some of their output
+    /// to produce it bit by bit are told to keep their output at about that size as well.
+    pub fn set_output_limit(&mut self, records: usize) {
+        assert!(records > 0);
+        self.output_limit = Some(records);
+        if let NodeType::Internal(ref mut i) = self.inner {
+            i.limit_output(records);
+        }
+    }
+
+    /// Replace the operator of this internal node, and return the old one. Unlike other changes
+    /// to the operator, this may be done after the node has been given to its domain, so that the
+    /// controller's copy keeps up with the one that the domain runs.
+    pub fn replace_operator(&mut self, op: ops::NodeOperator) -> ops::NodeOperator {
+        match self.inner {
+            NodeType::Internal(ref mut i) => mem::replace(i, op),
+            _ => unreachable!("only internal nodes have an operator to replace"),
+        }
+    }
+
     pub fn on_commit(&mut self, remap: &HashMap<NodeIndex, IndexPair>) {
         // this is *only* overwritten for these asserts.
         assert!(!self.taken);
@@ -148,6 +189,17 @@ impl Node {
         }
     }
 
+    pub fn with_egress<'a, F, R>(&'a self, f: F) -> Option<R>
+    where
+        F: FnOnce(&'a special::Egress) -> R,
+        R: 'a,
+    {
+        match self.inner {
+            NodeType::Egress(Some(ref e)) => Some(f(e)),
+            _ => None,
+        }
+    }
+
     pub fn with_reader_mut<'a, F, R>(&'a mut self, f: F) -> Result<R, ()>
     where
         F: FnOnce(&'a mut special::Reader) -> R,
@@ -224,6 +276,19 @@ impl Node {
         self.sharded_by
     }
 
+    pub fn state_backend(&self) -> StateBackend {
+        self.state_backend
+    }
+
+    pub fn materialization_hint(&self) -> MaterializationHint {
+        self.materialization_hint
+    }
+
+    /// The most records this node sends on in a single update, if it is limited.
+    pub fn output_limit(&self) -> Option<usize> {
+        self.output_limit
+    }
+
     pub fn add_child(&mut self, child: LocalNodeIndex) {
         self.children.push(child);
     }
@@ -247,6 +312,10 @@ impl Node {
         &*self.name
     }
 
+    pub fn set_name(&mut self, name: String) {
+        self.name = name;
+    }
+
     pub fn fields(&self) -> &[String] {
         &self.fields[..]
     }
@@ -404,6 +473,18 @@ impl Node {
         }
     }
 
+    /// A reader is scattered if it is sharded, but not by its key, so that the rows for any one
+    /// key may be in any of its shards.
+    pub fn is_scattered_reader(&self) -> bool {
+        let sharding = self.sharded_by();
+        self.with_reader(|r| match (r.key(), sharding) {
+            (Some(key), Sharding::ByColumn(c, _)) => key != [c],
+            (Some(_), Sharding::Random(_)) => true,
+            _ => false,
+        })
+        .unwrap_or(false)
+    }
+
     /// A node is considered to be an output node if changes to its state are visible outside of
     /// its domain.
     pub fn is_output(&self) -> bool {
@@ -1,9 +1,16 @@
-use fnv::FnvHashMap;
+use crossbeam_utils::thread;
+use fnv::{FnvHashMap, FnvHasher};
 use node::NodeType;
 use payload;
 use prelude::*;
+use processing::take_malformed;
 use std::collections::{HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
 use std::mem;
+use std::time;
+
+/// Chunks of a full replay smaller than this are not worth handing out to replay workers.
+const PARALLEL_REPLAY_MIN: usize = 1024;
 
 impl Node {
     pub(crate) fn process(
@@ -11,11 +18,14 @@ impl Node {
         m: &mut Option<Box<Packet>>,
         keyed_by: Option<&Vec<usize>>,
         state: &mut StateMap,
+        aux: &mut AuxStateMap,
         nodes: &DomainNodes,
         on_shard: Option<usize>,
         swap: bool,
         output: &mut FnvHashMap<ReplicaAddr, VecDeque<Box<Packet>>>,
         executor: Option<&mut Executor>,
+        replay_workers: usize,
+        malformed: &mut Vec<Malformed>,
     ) -> (Vec<Miss>, HashSet<Vec<DataType>>) {
         m.as_mut().unwrap().trace(PacketEvent::Process);
 
@@ -25,7 +35,7 @@ impl Node {
                 let m = m.as_mut().unwrap();
                 let tag = m.tag();
                 m.map_data(|rs| {
-                    materialize(rs, tag, state.get_mut(addr));
+                    note_unmatched(addr, materialize(rs, tag, state.get_mut(addr)), malformed);
                 });
                 (vec![], HashSet::new())
             }
@@ -37,7 +47,12 @@ impl Node {
                         src,
                         mut senders,
                     }) => {
-                        let Input { dst, data, tracer } = unsafe { inner.take() };
+                        let Input {
+                            dst,
+                            data,
+                            tracer,
+                            markers,
+                        } = unsafe { inner.take() };
                         let mut rs = b.process(addr, data, &*state);
 
                         // When a replay originates at a base node, we replay the data *through* that
@@ -48,13 +63,21 @@ impl Node {
                         //
                         // So: only materialize if the message we're processing is not a replay!
                         if keyed_by.is_none() {
-                            materialize(&mut rs, None, state.get_mut(addr));
+                            let now = time::Instant::now();
+                            b.track_expiry(&rs, now, state.get(addr).map(|s| &**s));
+                            let gone = materialize(&mut rs, None, state.get_mut(addr));
+                            note_unmatched(addr, gone, malformed);
                         }
 
+                        // As far as their clients are concerned, this is when the writes are accepted
+                        let written = Some(time::SystemTime::now());
+
                         // Send write-ACKs to all the clients with updates that made
                         // it into this merged packet:
                         if let Some(ex) = executor {
-                            senders.drain(..).for_each(|src| ex.send_back(src, ()));
+                            senders
+                                .drain(..)
+                                .for_each(|src| ex.send_back(src, Ok(b.take_ids(src))));
                         }
 
                         *m = Some(Box::new(Packet::Message {
@@ -63,6 +86,9 @@ impl Node {
                             data: rs,
                             tracer,
                             senders,
+                            written,
+                            seq: None,
+                            markers,
                         }));
                     }
                     Some(ref p) => {
@@ -75,7 +101,7 @@ impl Node {
                 (vec![], HashSet::new())
             }
             NodeType::Reader(ref mut r) => {
-                r.process(m, swap);
+                note_unmatched(addr, r.process(m, swap), malformed);
                 (vec![], HashSet::new())
             }
             NodeType::Egress(None) => unreachable!(),
@@ -84,7 +110,7 @@ impl Node {
                 (vec![], HashSet::new())
             }
             NodeType::Sharder(ref mut s) => {
-                s.process(m, addr, on_shard.is_some(), output);
+                s.process(m, addr, on_shard, output);
                 (vec![], HashSet::new())
             }
             NodeType::Internal(ref mut i) => {
@@ -114,19 +140,56 @@ impl Node {
                             }
                         }
                         (&mut Packet::ReplayPiece {
-                            context: payload::ReplayPieceContext::Regular { last },
+                            context: payload::ReplayPieceContext::Regular { last, .. },
                             ..
                         },) => ReplayContext::Full { last: last },
                         _ => ReplayContext::None,
                     };
 
+                    // full replays may be spread across several threads if the operator allows it,
+                    // unless it keeps auxiliary state, which the threads would each have to change
+                    let parallel = match replay {
+                        ReplayContext::Full { .. }
+                            if replay_workers > 1 && !aux.contains_key(addr) =>
+                        {
+                            i.parallel_replay_key()
+                        }
+                        _ => None,
+                    };
+
                     let mut set_replay_last = None;
+                    let from_global = nodes[from].borrow().global_addr();
                     tracer = m.tracer().and_then(|t| t.take());
                     m.map_data(|data| {
                         // we need to own the data
-                        let old_data = mem::replace(data, Records::default());
+                        let mut old_data = mem::replace(data, Records::default());
+                        // records the operator can't process are left for the domain to deal with
+                        malformed.extend(take_malformed(&*i, from, from_global, &mut old_data));
+
+                        if let Some(ref key) = parallel {
+                            if old_data.len() >= PARALLEL_REPLAY_MIN {
+                                let rs = on_input_parallel(
+                                    i,
+                                    replay_workers,
+                                    key,
+                                    from,
+                                    old_data,
+                                    state,
+                                );
+                                mem::replace(data, rs);
+                                return;
+                            }
+                        }
 
-                        match i.on_input_raw(from, old_data, &mut tracer, &replay, nodes, state) {
+                        match i.on_input_raw(
+                            from,
+                            old_data,
+                            &mut tracer,
+                            &replay,
+                            nodes,
+                            state,
+                            aux,
+                        ) {
                             RawProcessingResult::Regular(m) => {
                                 mem::replace(data, m.results);
                                 misses = m.misses;
@@ -160,7 +223,7 @@ impl Node {
 
                     if let Some(new_last) = set_replay_last {
                         if let Packet::ReplayPiece {
-                            context: payload::ReplayPieceContext::Regular { ref mut last },
+                            context: payload::ReplayPieceContext::Regular { ref mut last, .. },
                             ..
                         } = **m
                         {
@@ -195,6 +258,11 @@ impl Node {
                 if let Some(t) = m.tracer() {
                     *t = tracer.take();
                 }
+                if let NodeOperator::Union(ref mut u) = *i {
+                    if let Some(markers) = m.markers_mut() {
+                        u.merge_markers(markers);
+                    }
+                }
 
                 let tag = match **m {
                     Packet::ReplayPiece {
@@ -212,7 +280,7 @@ impl Node {
                     _ => None,
                 };
                 m.map_data(|rs| {
-                    materialize(rs, tag, state.get_mut(addr));
+                    note_unmatched(addr, materialize(rs, tag, state.get_mut(addr)), malformed);
                 });
 
                 for miss in misses.iter_mut() {
@@ -231,6 +299,46 @@ impl Node {
         }
     }
 
+    /// Produce the next piece of output that this node held back while processing a regular
+    /// update (see `Ingredient::next_spilled`), materialized just like `process` would have.
+    pub(crate) fn process_spilled(
+        &mut self,
+        state: &mut StateMap,
+        nodes: &DomainNodes,
+        malformed: &mut Vec<Malformed>,
+    ) -> Option<Records> {
+        let addr = self.local_addr();
+        let mut rs = match self.inner {
+            NodeType::Internal(ref mut i) => i.next_spilled(nodes, &*state)?,
+            _ => return None,
+        };
+        note_unmatched(addr, materialize(&mut rs, None, state.get_mut(addr)), malformed);
+        Some(rs)
+    }
+
+    /// The timers that this node's operator wants set or cancelled (see
+    /// `Ingredient::take_timers`).
+    pub(crate) fn take_timers(&mut self) -> Vec<TimerRequest> {
+        match self.inner {
+            NodeType::Internal(ref mut i) => i.take_timers(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Tell this node's operator that a timer it set has fired. The records it produces, if any,
+    /// are not yet materialized.
+    pub(crate) fn process_timer(
+        &mut self,
+        token: u64,
+        state: &StateMap,
+        nodes: &DomainNodes,
+    ) -> Option<Records> {
+        match self.inner {
+            NodeType::Internal(ref mut i) => i.on_timer(token, nodes, state),
+            _ => None,
+        }
+    }
+
     pub fn process_eviction(
         &mut self,
         from: LocalNodeIndex,
@@ -258,7 +366,7 @@ impl Node {
                 );
             }
             NodeType::Sharder(ref mut s) => {
-                s.process_eviction(key_columns, tag, keys, addr, on_shard.is_some(), output);
+                s.process_eviction(key_columns, tag, keys, addr, output);
             }
             NodeType::Internal(ref mut i) => {
                 i.on_eviction(from, key_columns, keys);
@@ -310,13 +418,106 @@ fn reroute_miss(nodes: &DomainNodes, miss: &mut Miss) {
     }
 }
 
-pub fn materialize(rs: &mut Records, partial: Option<Tag>, state: Option<&mut Box<State>>) {
+/// Apply `rs` to `state`, if the node is materialized. Returns the negative records that had no row
+/// left to remove, which are no longer in `rs` (see `State::process_records`).
+pub fn materialize(
+    rs: &mut Records,
+    partial: Option<Tag>,
+    state: Option<&mut Box<State>>,
+) -> Vec<Record> {
     // our output changed -- do we need to modify materialized state?
     if state.is_none() {
         // nope
-        return;
+        return Vec::new();
     }
 
     // yes!
-    state.unwrap().process_records(rs, partial);
+    state.unwrap().process_records(rs, partial)
+}
+
+/// Note the negative records that the materialization of `node` had no row left to remove as
+/// malformed. Going below zero copies of a row means that something upstream removed a row it
+/// never added, so the records are dealt with like any other the node could not process.
+pub(crate) fn note_unmatched(
+    node: LocalNodeIndex,
+    rs: Vec<Record>,
+    malformed: &mut Vec<Malformed>,
+) {
+    malformed.extend(rs.into_iter().map(|record| Malformed {
+        from: node,
+        record,
+        reason: String::from("removes a row that has no copies left"),
+    }));
+}
+
+/// Split a chunk of a full replay on `key` into (at most) `workers` parts, feed each part to a
+/// clone of `op` on a thread of its own, and stitch the results back together in part order.
+///
+/// All records with the same key end up in the same part, in the order they arrived, so the
+/// output for each key is just what `op` would have produced on its own.
+fn on_input_parallel(
+    op: &NodeOperator,
+    workers: usize,
+    key: &[usize],
+    from: LocalNodeIndex,
+    data: Records,
+    states: &StateMap,
+) -> Records {
+    let mut parts: Vec<Vec<Record>> = (0..workers)
+        .map(|_| Vec::with_capacity(data.len() / workers + 1))
+        .collect();
+    if key.is_empty() {
+        // the records are independent, so just cut the chunk into runs
+        let per_part = (data.len() + workers - 1) / workers;
+        for (i, r) in data.into_iter().enumerate() {
+            parts[i / per_part].push(r);
+        }
+    } else {
+        for r in data {
+            let mut hasher = FnvHasher::default();
+            for &col in key {
+                r[col].hash(&mut hasher);
+            }
+            parts[hasher.finish() as usize % workers].push(r);
+        }
+    }
+
+    let results: Vec<Records> = thread::scope(|scope| {
+        let workers: Vec<_> = parts
+            .into_iter()
+            .filter(|part| !part.is_empty())
+            .map(|part| {
+                let mut op = op.clone();
+                scope.spawn(move || {
+                    // operators that opt in never look at other nodes, and keep no auxiliary state,
+                    // so they get none
+                    let nodes = DomainNodes::default();
+                    let mut aux = AuxStateMap::default();
+                    let mut tracer = None;
+                    let m = op.on_input(
+                        from,
+                        part.into(),
+                        &mut tracer,
+                        None,
+                        &nodes,
+                        states,
+                        &mut aux,
+                    );
+                    debug_assert!(m.misses.is_empty(), "full replays cannot miss");
+                    m.results
+                })
+            })
+            .collect();
+
+        workers
+            .into_iter()
+            .map(|w| w.join().expect("replay worker panicked"))
+            .collect()
+    });
+
+    let mut rs = Records::default();
+    for mut part in results {
+        rs.append(&mut part);
+    }
+    rs
 }
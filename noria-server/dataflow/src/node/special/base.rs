@@ -1,10 +1,67 @@
-use noria::{Modification, Operation, TableOperation};
+use chrono::Utc;
+use noria::error::WriteError;
+use noria::{ColumnSchema, ColumnType, Modification, Operation, TableOperation};
 use prelude::*;
 use std::borrow::Cow;
-use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::cmp::{self, Ordering};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::mem;
+use std::time::{Duration, Instant};
 use vec_map::VecMap;
 
+/// What a base with a primary key does when a row is inserted with a key that is already present.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OnDuplicateKey {
+    /// Keep the existing row, and drop the inserted one.
+    Ignore,
+    /// Reject the whole write with `WriteError::DuplicateKey`.
+    Reject,
+    /// Replace the existing row with the inserted one.
+    Replace,
+}
+
+/// When each row in a base with a TTL was inserted.
+#[derive(Debug, Default)]
+struct Expiry {
+    /// Every row in the base by insertion time, with a counter to order rows inserted together.
+    rows: BTreeMap<(Instant, u64), Vec<DataType>>,
+    /// Where each copy of each row in the base is in `rows`.
+    index: HashMap<Vec<DataType>, Vec<(Instant, u64)>>,
+    next: u64,
+}
+
+impl Expiry {
+    fn insert(&mut self, row: Vec<DataType>, at: Instant) {
+        let id = (at, self.next);
+        self.next += 1;
+        self.index
+            .entry(row.clone())
+            .or_insert_with(Vec::new)
+            .push(id);
+        self.rows.insert(id, row);
+    }
+
+    fn remove_id(&mut self, id: (Instant, u64)) -> Vec<DataType> {
+        let row = self.rows.remove(&id).unwrap();
+        let gone = {
+            let ids = self.index.get_mut(&row).unwrap();
+            ids.retain(|&i| i != id);
+            ids.is_empty()
+        };
+        if gone {
+            self.index.remove(&row);
+        }
+        row
+    }
+
+    /// Forget one copy of `row`, and return when that copy was inserted.
+    fn remove(&mut self, row: &[DataType]) -> Option<Instant> {
+        let id = *self.index.get(row)?.first()?;
+        self.remove_id(id);
+        Some(id.0)
+    }
+}
+
 /// Base is used to represent the root nodes of the Noria data flow graph.
 ///
 /// These nodes perform no computation, and their job is merely to persist all received updates and
@@ -13,6 +70,23 @@ use vec_map::VecMap;
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Base {
     primary_key: Option<Vec<usize>>,
+    on_duplicate_key: OnDuplicateKey,
+    schema: Option<Vec<ColumnSchema>>,
+    auto_increment: Option<usize>,
+    max_value_size: Option<usize>,
+
+    // the next auto-increment id, which is derived from the base's state when first needed
+    #[serde(skip)]
+    next_id: Option<i64>,
+    // ids assigned to admitted writes whose senders have not yet been acknowledged
+    #[serde(skip)]
+    assigned: HashMap<usize, VecDeque<Vec<i64>>>,
+
+    ttl: Option<Duration>,
+    // insertion times of the base's rows, which are derived from the base's state when first
+    // needed (so rows recovered from disk are treated as if they were just inserted)
+    #[serde(skip)]
+    expiry: Option<Expiry>,
 
     defaults: Vec<DataType>,
     dropped: Vec<usize>,
@@ -33,10 +107,471 @@ impl Base {
         self
     }
 
+    /// Builder with a policy for inserts of keys that are already present.
+    ///
+    /// By default, such inserts are ignored. Only applies to bases with a primary key.
+    pub fn on_duplicate_key(mut self, policy: OnDuplicateKey) -> Base {
+        self.on_duplicate_key = policy;
+        self
+    }
+
+    /// Builder with a declared schema.
+    ///
+    /// Writes to a base with a schema are checked against it before they are processed (see
+    /// `Base::validate`). The schema describes the base's initial columns, so it must have one
+    /// entry per field of the base.
+    pub fn with_schema(mut self, schema: Vec<ColumnSchema>) -> Base {
+        self.schema = Some(schema);
+        self
+    }
+
+    /// Builder with an auto-increment column.
+    ///
+    /// The base chooses the value of column `col` for every row inserted into it, replacing the
+    /// value given by the write, and reports the chosen values back to the writer. Ids are assigned
+    /// in increasing order starting after the largest id in the base's state, so the base must
+    /// also have a primary key (which ensures that it is materialized). If the base has a schema,
+    /// it must declare `col` to hold integers, or the migration that adds the base fails.
+    pub fn with_auto_increment(mut self, col: usize) -> Base {
+        self.auto_increment = Some(col);
+        self
+    }
+
+    /// Builder with a maximum size for variable-length values.
+    ///
+    /// Writes that contain a string or binary value longer than `bytes` bytes are rejected with
+    /// `WriteError::ValueTooLarge` (see `Base::validate`). Tables for the base turn such writes
+    /// away before sending them.
+    pub fn with_max_value_size(mut self, bytes: usize) -> Base {
+        self.max_value_size = Some(bytes);
+        self
+    }
+
+    /// Builder with a time-to-live for rows.
+    ///
+    /// Rows are removed from the base, and retracted downstream, once they have been in the base
+    /// for longer than `ttl`. An updated row keeps the insertion time of the row it replaced.
+    /// Expired rows are removed by a periodic sweep in the base's domain, so a row may outlive its
+    /// TTL by up to the sweep interval (see `ControllerBuilder::set_expiry_sweep`).
+    pub fn with_ttl(mut self, ttl: Duration) -> Base {
+        self.ttl = Some(ttl);
+        self
+    }
+
     pub fn key(&self) -> Option<&[usize]> {
         self.primary_key.as_ref().map(|cols| &cols[..])
     }
 
+    pub fn auto_increment(&self) -> Option<usize> {
+        self.auto_increment
+    }
+
+    pub fn duplicate_key_policy(&self) -> OnDuplicateKey {
+        self.on_duplicate_key
+    }
+
+    pub fn schema(&self) -> Option<&[ColumnSchema]> {
+        self.schema.as_ref().map(|cols| &cols[..])
+    }
+
+    pub fn ttl(&self) -> Option<Duration> {
+        self.ttl
+    }
+
+    pub fn max_value_size(&self) -> Option<usize> {
+        self.max_value_size
+    }
+
+    /// The name of column `col` as given by the base's schema, or its index if it has none.
+    fn column_name(&self, col: usize) -> String {
+        self.schema
+            .as_ref()
+            .and_then(|s| s.get(col))
+            .map(|c| c.name.clone())
+            .unwrap_or_else(|| col.to_string())
+    }
+
+    /// Check that every operation in a write conforms to this base's schema and value size limit.
+    ///
+    /// Bases without a declared schema accept values of any type. Columns that were added after the
+    /// schema was declared are not type-checked.
+    pub fn validate(&self, ops: &[TableOperation]) -> Result<(), WriteError> {
+        if let Some(limit) = self.max_value_size {
+            self.check_value_sizes(ops, limit)?;
+        }
+
+        let schema = match self.schema {
+            Some(ref schema) => schema,
+            None => return Ok(()),
+        };
+
+        // rows written by clients that predate added columns are filled in by `fix`
+        let ncols = if self.defaults.is_empty() {
+            schema.len()
+        } else {
+            self.defaults.len()
+        };
+        let check_row = |row: &[DataType], skip: Option<usize>| {
+            if row.len() < schema.len() || row.len() > ncols {
+                return Err(WriteError::WrongColumnCount(ncols, row.len()));
+            }
+            schema
+                .iter()
+                .zip(row)
+                .enumerate()
+                .filter(|&(i, _)| Some(i) != skip)
+                .map(|(_, (col, v))| col.check(v))
+                .collect::<Result<(), _>>()
+        };
+
+        for op in ops {
+            match *op {
+                // the value of the auto-increment column of inserted rows is chosen by the base
+                TableOperation::Insert(ref row) => check_row(row, self.auto_increment)?,
+                TableOperation::InsertOrUpdate { ref row, .. } => check_row(row, None)?,
+                TableOperation::Modify { ref set, .. } => {
+                    for &(col, ref v) in set {
+                        match schema.get(col) {
+                            Some(c) => c.check(v)?,
+                            None if col < ncols => {}
+                            None => return Err(WriteError::WrongColumnCount(ncols, col + 1)),
+                        }
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+
+            let set = match *op {
+                TableOperation::Update { ref set, .. }
+                | TableOperation::InsertOrUpdate {
+                    update: ref set, ..
+                } => set,
+                _ => continue,
+            };
+            for (col, m) in schema.iter().zip(set) {
+                match *m {
+                    Modification::Set(ref v) => col.check(v)?,
+                    Modification::Apply(..) if col.column_type != ColumnType::Int => {
+                        return Err(WriteError::WrongType(
+                            col.name.clone(),
+                            col.column_type,
+                            ColumnType::Int,
+                        ));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that no value in a write is longer than `limit` bytes.
+    fn check_value_sizes(&self, ops: &[TableOperation], limit: usize) -> Result<(), WriteError> {
+        let check = |col: usize, v: &DataType| match v.value_size() {
+            Some(size) if size > limit => Err(WriteError::ValueTooLarge(
+                self.column_name(col),
+                size,
+                limit,
+            )),
+            _ => Ok(()),
+        };
+        let check_set = |set: &[Modification]| {
+            set.iter()
+                .enumerate()
+                .filter_map(|(col, m)| match *m {
+                    Modification::Set(ref v) => Some((col, v)),
+                    _ => None,
+                })
+                .map(|(col, v)| check(col, v))
+                .collect::<Result<(), _>>()
+        };
+
+        for op in ops {
+            match *op {
+                TableOperation::Insert(ref row)
+                | TableOperation::InsertDefaulted { ref row, .. } => {
+                    for (col, v) in row.iter().enumerate() {
+                        check(col, v)?;
+                    }
+                }
+                TableOperation::InsertOrUpdate {
+                    ref row,
+                    ref update,
+                } => {
+                    for (col, v) in row.iter().enumerate() {
+                        check(col, v)?;
+                    }
+                    check_set(update)?;
+                }
+                TableOperation::Update { ref set, .. } => check_set(set)?,
+                TableOperation::Modify { ref set, .. } => {
+                    for &(col, ref v) in set {
+                        check(col, v)?;
+                    }
+                }
+                TableOperation::Delete { .. } => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Fill in the default values of columns that a write did not give values for.
+    ///
+    /// Inserted rows that are shorter than the base's schema have their remaining columns filled
+    /// in, and rows inserted with `TableOperation::InsertDefaulted` have their defaulted columns
+    /// filled in. All `ColumnDefault::CurrentTimestamp` columns in a write get the same timestamp.
+    ///
+    /// Defaults are filled in before auto-increment ids are assigned, so a default value for the
+    /// auto-increment column of an inserted row is always replaced by its assigned id.
+    pub fn fill_defaults(&self, ops: &mut [TableOperation]) -> Result<(), WriteError> {
+        let no_default = |col: usize| WriteError::NoDefault(self.column_name(col));
+        let schema = match self.schema {
+            Some(ref schema) => schema,
+            None => {
+                // without a schema, no column has a default
+                for op in ops {
+                    if let TableOperation::InsertDefaulted { ref defaulted, .. } = *op {
+                        if let Some(&col) = defaulted.first() {
+                            return Err(no_default(col));
+                        }
+                    }
+                }
+                return Ok(());
+            }
+        };
+
+        let now: DataType = Utc::now().naive_utc().into();
+        let default = |col: usize| {
+            schema
+                .get(col)
+                .and_then(|c| c.default.as_ref())
+                .map(|d| d.value(&now))
+                .ok_or_else(|| no_default(col))
+        };
+
+        for op in ops.iter_mut() {
+            let filled = match *op {
+                TableOperation::Insert(ref mut row) => {
+                    for col in row.len()..schema.len() {
+                        let v = default(col)?;
+                        row.push(v);
+                    }
+                    continue;
+                }
+                TableOperation::InsertDefaulted {
+                    ref mut row,
+                    ref defaulted,
+                } => {
+                    for &col in defaulted {
+                        if col >= row.len() {
+                            return Err(WriteError::WrongColumnCount(schema.len(), row.len()));
+                        }
+                        row[col] = default(col)?;
+                    }
+                    mem::replace(row, Vec::new())
+                }
+                _ => continue,
+            };
+            *op = TableOperation::Insert(filled);
+        }
+        Ok(())
+    }
+
+    /// Returns whether `Base::check_keys` must consult the base's materialization for this write.
+    pub fn checks_keys(&self, ops: &[TableOperation]) -> bool {
+        self.primary_key.is_some()
+            && (self.on_duplicate_key == OnDuplicateKey::Reject
+                || ops.iter().any(modifies_existing))
+    }
+
+    /// Check a write's keys against the base's materialization.
+    ///
+    /// Deletes and updates must target a key that is present. If the base rejects duplicate
+    /// keys, inserts must also not target a key that is already present. `state` is the base's
+    /// materialization, which must reflect all earlier writes. Inserts into an auto-increment key
+    /// are not checked, since they are assigned fresh keys.
+    pub fn check_keys(
+        &self,
+        ops: &[TableOperation],
+        state: Option<&State>,
+    ) -> Result<(), WriteError> {
+        let key_cols = match self.primary_key {
+            Some(ref key_cols) => &key_cols[..],
+            None => return Ok(()),
+        };
+        if !self.checks_keys(ops) {
+            return Ok(());
+        }
+        let unique = self.on_duplicate_key == OnDuplicateKey::Reject;
+        let fresh_keys = self
+            .auto_increment
+            .map(|col| key_cols.contains(&col))
+            .unwrap_or(false);
+        let state = state.expect("base with primary key must be materialized");
+
+        // whether each key the write touches is present, as of the operation being checked
+        let mut present = HashMap::new();
+        for op in ops {
+            if let TableOperation::Insert(..) = *op {
+                if fresh_keys {
+                    continue;
+                }
+            }
+
+            let key: Vec<_> = key_of(key_cols, op).cloned().collect();
+            let exists = match present.get(&key) {
+                Some(&exists) => exists,
+                None => match state.lookup(key_cols, &KeyType::from(&key[..])) {
+                    LookupResult::Some(rows) => !rows.is_empty(),
+                    LookupResult::Missing => unreachable!(),
+                },
+            };
+
+            let exists = match *op {
+                TableOperation::Insert(..) if exists && unique => {
+                    return Err(WriteError::DuplicateKey(key));
+                }
+                TableOperation::Delete { .. }
+                | TableOperation::Update { .. }
+                | TableOperation::Modify { .. }
+                    if !exists =>
+                {
+                    return Err(WriteError::KeyNotFound(key));
+                }
+                TableOperation::Insert(..) | TableOperation::InsertOrUpdate { .. } => true,
+                TableOperation::Delete { .. } => false,
+                _ => exists,
+            };
+            present.insert(key, exists);
+        }
+        Ok(())
+    }
+
+    /// Assign auto-increment ids to the rows inserted by a write from `src`.
+    ///
+    /// The assigned ids are held until the write is acknowledged (see `Base::take_ids`). `state`
+    /// is the base's materialization, and is used to find the largest id that is already in use
+    /// the first time ids are assigned (e.g., after the base has been recovered from disk).
+    pub(crate) fn assign_ids(
+        &mut self,
+        src: Option<SourceChannelIdentifier>,
+        ops: &mut [TableOperation],
+        state: Option<&State>,
+    ) {
+        let col = match self.auto_increment {
+            Some(col) => col,
+            None => return,
+        };
+
+        let mut next = match self.next_id {
+            Some(next) => next,
+            None => {
+                state
+                    .expect("base with auto-increment column must be materialized")
+                    .cloned_records()
+                    .iter()
+                    .filter_map(|r| as_id(&r[col]))
+                    .max()
+                    .unwrap_or(0)
+                    + 1
+            }
+        };
+
+        let mut ids = Vec::new();
+        for op in ops {
+            match *op {
+                TableOperation::Insert(ref mut row) if col < row.len() => {
+                    row[col] = next.into();
+                    ids.push(next);
+                    next += 1;
+                }
+                TableOperation::InsertOrUpdate { ref row, .. } if col < row.len() => {
+                    // the writer chose an id itself, so make sure we never hand it out again
+                    if let Some(id) = as_id(&row[col]) {
+                        next = cmp::max(next, id + 1);
+                    }
+                }
+                _ => {}
+            }
+        }
+        self.next_id = Some(next);
+
+        if let Some(src) = src {
+            self.assigned
+                .entry(src.token)
+                .or_insert_with(VecDeque::new)
+                .push_back(ids);
+        }
+    }
+
+    /// Take the ids that were assigned to the oldest unacknowledged write from `src`.
+    pub(crate) fn take_ids(&mut self, src: SourceChannelIdentifier) -> Vec<i64> {
+        self.assigned
+            .get_mut(&src.token)
+            .and_then(|ids| ids.pop_front())
+            .unwrap_or_else(Vec::new)
+    }
+
+    /// Forget the ids assigned to writes from the client connection identified by `token`, which
+    /// has closed, so they will never be acknowledged.
+    pub(crate) fn forget_source(&mut self, token: usize) {
+        self.assigned.remove(&token);
+    }
+
+    /// Record the rows inserted and removed by records that are about to be applied to this
+    /// base's materialization, `state`, at time `now`.
+    pub(crate) fn track_expiry(&mut self, rs: &Records, now: Instant, state: Option<&State>) {
+        if self.ttl.is_none() {
+            return;
+        }
+
+        let key_cols = self.primary_key.as_ref();
+        let expiry = self.expiry.get_or_insert_with(|| seed_expiry(now, state));
+        let mut replaced = None;
+        for r in rs.iter() {
+            match *r {
+                Record::Negative(ref row) => {
+                    replaced = expiry.remove(row).map(|at| (at, row));
+                }
+                Record::Positive(ref row) => {
+                    // an update shows up as a negative and a positive for the same key
+                    let at = match (replaced.take(), key_cols) {
+                        (Some((at, old)), Some(cols)) if cols.iter().all(|&c| old[c] == row[c]) => {
+                            at
+                        }
+                        _ => now,
+                    };
+                    expiry.insert(row.clone(), at);
+                }
+            }
+        }
+    }
+
+    /// Remove up to `max` rows that have outlived this base's TTL as of `now`.
+    ///
+    /// Returns retractions of the expired rows, which the caller must apply to the base's
+    /// materialization, `state`, and send downstream.
+    pub(crate) fn expire(&mut self, now: Instant, max: usize, state: Option<&State>) -> Records {
+        let ttl = match self.ttl {
+            Some(ttl) => ttl,
+            None => return Records::default(),
+        };
+
+        let expiry = self.expiry.get_or_insert_with(|| seed_expiry(now, state));
+        let expired: Vec<_> = expiry
+            .rows
+            .keys()
+            .take_while(|&&(at, _)| at + ttl <= now)
+            .take(max)
+            .cloned()
+            .collect();
+        expired
+            .into_iter()
+            .map(|id| Record::Negative(expiry.remove_id(id)))
+            .collect()
+    }
+
     /// Add a new column to this base node.
     pub fn add_column(&mut self, default: DataType) -> usize {
         assert!(
@@ -91,6 +626,16 @@ impl Clone for Base {
     fn clone(&self) -> Base {
         Base {
             primary_key: self.primary_key.clone(),
+            on_duplicate_key: self.on_duplicate_key,
+            schema: self.schema.clone(),
+            auto_increment: self.auto_increment,
+            max_value_size: self.max_value_size,
+
+            next_id: None,
+            assigned: HashMap::new(),
+
+            ttl: self.ttl,
+            expiry: None,
 
             defaults: self.defaults.clone(),
             dropped: self.dropped.clone(),
@@ -103,6 +648,16 @@ impl Default for Base {
     fn default() -> Self {
         Base {
             primary_key: None,
+            on_duplicate_key: OnDuplicateKey::Ignore,
+            schema: None,
+            auto_increment: None,
+            max_value_size: None,
+
+            next_id: None,
+            assigned: HashMap::new(),
+
+            ttl: None,
+            expiry: None,
 
             defaults: Vec::new(),
             dropped: Vec::new(),
@@ -116,7 +671,36 @@ fn key_val(i: usize, col: usize, r: &TableOperation) -> &DataType {
         TableOperation::Insert(ref row) => &row[col],
         TableOperation::Delete { ref key } => &key[i],
         TableOperation::Update { ref key, .. } => &key[i],
+        TableOperation::Modify { ref key, .. } => &key[i],
         TableOperation::InsertOrUpdate { ref row, .. } => &row[col],
+        TableOperation::InsertDefaulted { ref row, .. } => &row[col],
+    }
+}
+
+/// The value of an auto-increment column as an id. Values that are not integers (which can only
+/// be in bases without a schema) are never taken for ids.
+fn as_id(v: &DataType) -> Option<i64> {
+    match *v {
+        DataType::Int(n) => Some(i64::from(n)),
+        DataType::BigInt(n) => Some(n),
+        _ => None,
+    }
+}
+
+fn seed_expiry(now: Instant, state: Option<&State>) -> Expiry {
+    let mut expiry = Expiry::default();
+    for row in state.map(|s| s.cloned_records()).unwrap_or_default() {
+        expiry.insert(row, now);
+    }
+    expiry
+}
+
+fn modifies_existing(r: &TableOperation) -> bool {
+    match *r {
+        TableOperation::Delete { .. }
+        | TableOperation::Update { .. }
+        | TableOperation::Modify { .. } => true,
+        _ => false,
     }
 }
 
@@ -201,11 +785,11 @@ impl Base {
 
             let update = match op {
                 TableOperation::Insert(row) => {
-                    if let Some(ref was) = was {
-                        eprintln!("base ignoring {:?} since it already has {:?}", row, was);
-                    } else {
-                        //assert!(was.is_none());
+                    if current.is_none() || self.on_duplicate_key == OnDuplicateKey::Replace {
                         current = Some(Cow::Owned(row));
+                    } else if let Some(ref current) = current {
+                        // duplicates of rejected keys were already turned away when admitted
+                        eprintln!("base ignoring {:?} since it already has {:?}", row, current);
                     }
                     continue;
                 }
@@ -218,6 +802,21 @@ impl Base {
                     }
                     continue;
                 }
+                TableOperation::InsertDefaulted { .. } => {
+                    unreachable!("defaults are filled in when a write is admitted")
+                }
+                TableOperation::Modify { set, .. } => {
+                    // only the modified columns were sent, and the rest are taken from the row
+                    // that the base already has
+                    if let Some(row) = current.take() {
+                        let mut future = row.into_owned();
+                        for (col, v) in set {
+                            future[col] = v;
+                        }
+                        current = Some(Cow::Owned(future));
+                    }
+                    continue;
+                }
                 TableOperation::Update { set, .. } => set,
                 TableOperation::InsertOrUpdate { row, update } => {
                     if current.is_none() {
@@ -307,6 +906,418 @@ mod tests {
         assert_eq!(b.unmodified, true);
     }
 
+    #[test]
+    fn it_validates_against_schema() {
+        use noria::{ColumnSchema, ColumnType};
+
+        let b = Base::default().with_key(vec![0]).with_schema(vec![
+            ColumnSchema::new("id", ColumnType::Int, false),
+            ColumnSchema::new("name", ColumnType::Text, true),
+        ]);
+
+        let ok = vec![
+            TableOperation::Insert(vec![1.into(), "a".into()]),
+            TableOperation::Insert(vec![2.into(), DataType::None]),
+            TableOperation::Update {
+                key: vec![1.into()],
+                set: vec![Modification::None, Modification::Set("b".into())],
+            },
+            TableOperation::Modify {
+                key: vec![1.into()],
+                set: vec![(1, "b".into())],
+            },
+        ];
+        assert_eq!(b.validate(&ok), Ok(()));
+
+        let ops = vec![TableOperation::Insert(vec![1.into()])];
+        assert_eq!(b.validate(&ops), Err(WriteError::WrongColumnCount(2, 1)));
+
+        let ops = vec![TableOperation::Modify {
+            key: vec![1.into()],
+            set: vec![(1, 2.into())],
+        }];
+        assert_eq!(
+            b.validate(&ops),
+            Err(WriteError::WrongType(
+                "name".to_owned(),
+                ColumnType::Text,
+                ColumnType::Int
+            ))
+        );
+        let ops = vec![TableOperation::Modify {
+            key: vec![1.into()],
+            set: vec![(2, "b".into())],
+        }];
+        assert_eq!(b.validate(&ops), Err(WriteError::WrongColumnCount(2, 3)));
+
+        let ops = vec![
+            TableOperation::Insert(vec![1.into(), "a".into()]),
+            TableOperation::Insert(vec![2.into(), 3.into()]),
+        ];
+        assert_eq!(
+            b.validate(&ops),
+            Err(WriteError::WrongType(
+                "name".to_owned(),
+                ColumnType::Text,
+                ColumnType::Int
+            ))
+        );
+
+        let ops = vec![TableOperation::Update {
+            key: vec![1.into()],
+            set: vec![Modification::Set(DataType::None), Modification::None],
+        }];
+        assert_eq!(
+            b.validate(&ops),
+            Err(WriteError::NotNullable("id".to_owned()))
+        );
+
+        let ops = vec![TableOperation::Update {
+            key: vec![1.into()],
+            set: vec![
+                Modification::None,
+                Modification::Apply(Operation::Add, 1.into()),
+            ],
+        }];
+        assert_eq!(
+            b.validate(&ops),
+            Err(WriteError::WrongType(
+                "name".to_owned(),
+                ColumnType::Text,
+                ColumnType::Int
+            ))
+        );
+    }
+
+    #[test]
+    fn it_assigns_ids() {
+        let mut b = Base::default().with_key(vec![0]).with_auto_increment(0);
+        let src = SourceChannelIdentifier { token: 0 };
+
+        // ids continue after the largest one already in the base's state
+        let mut state = MemoryState::default();
+        state.add_key(&[0], None);
+        let mut rs: Records = vec![(vec![41.into(), "x".into()], true)].into();
+        state.process_records(&mut rs, None);
+
+        let mut ops = vec![
+            TableOperation::Insert(vec![DataType::None, "a".into()]),
+            TableOperation::Insert(vec![DataType::None, "b".into()]),
+        ];
+        b.assign_ids(Some(src), &mut ops, Some(&state));
+        assert_eq!(ops[1], TableOperation::Insert(vec![43.into(), "b".into()]));
+
+        // ids chosen by the writer are never handed out again
+        let mut ops = vec![
+            TableOperation::InsertOrUpdate {
+                row: vec![50.into(), "c".into()],
+                update: vec![Modification::None, Modification::Set("c".into())],
+            },
+            TableOperation::Insert(vec![DataType::None, "d".into()]),
+        ];
+        b.assign_ids(Some(src), &mut ops, None);
+
+        assert_eq!(b.take_ids(src), vec![42, 43]);
+        assert_eq!(b.take_ids(src), vec![51]);
+        assert_eq!(b.take_ids(src), Vec::<i64>::new());
+    }
+
+    #[test]
+    fn it_skips_non_integer_ids() {
+        let mut b = Base::default().with_key(vec![0]).with_auto_increment(0);
+        let src = SourceChannelIdentifier { token: 0 };
+
+        // without a schema, the auto-increment column may hold values of any type
+        let mut state = MemoryState::default();
+        state.add_key(&[0], None);
+        let mut rs: Records = vec![
+            (vec![7.into(), "x".into()], true),
+            (vec!["eight".into(), "y".into()], true),
+        ]
+        .into();
+        state.process_records(&mut rs, None);
+
+        let mut ops = vec![
+            TableOperation::InsertOrUpdate {
+                row: vec!["nine".into(), "z".into()],
+                update: vec![Modification::None, Modification::Set("z".into())],
+            },
+            TableOperation::Insert(vec![DataType::None, "a".into()]),
+        ];
+        b.assign_ids(Some(src), &mut ops, Some(&state));
+        assert_eq!(b.take_ids(src), vec![8]);
+
+        // ids that a closed connection was never told about are dropped
+        let mut ops = vec![TableOperation::Insert(vec![DataType::None, "b".into()])];
+        b.assign_ids(Some(src), &mut ops, None);
+        b.forget_source(src.token);
+        assert!(b.assigned.is_empty());
+    }
+
+    #[test]
+    fn it_fills_defaults() {
+        use noria::{ColumnDefault, ColumnSchema, ColumnType};
+
+        let mut b = Base::default()
+            .with_key(vec![0])
+            .with_auto_increment(0)
+            .with_schema(vec![
+                ColumnSchema::new("id", ColumnType::Int, false)
+                    .with_default(ColumnDefault::Value(0.into())),
+                ColumnSchema::new("name", ColumnType::Text, false),
+                ColumnSchema::new("score", ColumnType::Int, true)
+                    .with_default(ColumnDefault::Value(10.into())),
+                ColumnSchema::new("at", ColumnType::Timestamp, false)
+                    .with_default(ColumnDefault::CurrentTimestamp),
+            ]);
+
+        let mut ops = vec![
+            TableOperation::Insert(vec![DataType::None, "a".into()]),
+            TableOperation::InsertDefaulted {
+                row: vec![DataType::None, "b".into(), DataType::None, DataType::None],
+                defaulted: vec![0, 2, 3],
+            },
+        ];
+        assert_eq!(b.fill_defaults(&mut ops), Ok(()));
+        assert_eq!(b.validate(&ops), Ok(()));
+
+        // every row of a write gets the same timestamp
+        let at = ops[0].row().unwrap()[3].clone();
+        assert_eq!(ColumnType::of(&at), Some(ColumnType::Timestamp));
+        assert_eq!(
+            ops[1],
+            TableOperation::Insert(vec![0.into(), "b".into(), 10.into(), at.clone()])
+        );
+
+        // the auto-increment id replaces the default
+        b.next_id = Some(1);
+        b.assign_ids(None, &mut ops, None);
+        assert_eq!(
+            ops[0],
+            TableOperation::Insert(vec![1.into(), "a".into(), 10.into(), at.clone()])
+        );
+        assert_eq!(ops[1].row().unwrap()[0], 2.into());
+
+        let mut ops = vec![TableOperation::InsertDefaulted {
+            row: vec![DataType::None; 4],
+            defaulted: vec![1],
+        }];
+        assert_eq!(
+            b.fill_defaults(&mut ops),
+            Err(WriteError::NoDefault("name".to_owned()))
+        );
+    }
+
+    #[test]
+    fn it_handles_duplicate_keys() {
+        use node;
+
+        let local = unsafe { LocalNodeIndex::make(0 as u32) };
+        let mut state = MemoryState::default();
+        state.add_key(&[0], None);
+        let mut states = StateMap::new();
+        states.insert(local, box state);
+
+        let mut one = |b: &mut Base, ops: Vec<TableOperation>| {
+            let mut rs = b.process(local, ops, &states);
+            node::materialize(&mut rs, None, states.get_mut(local));
+            rs
+        };
+
+        let insert = |v: &str| vec![TableOperation::Insert(vec![1.into(), v.into()])];
+
+        let mut b = Base::default().with_key(vec![0]);
+        one(&mut b, insert("a"));
+        // by default, a duplicate insert is ignored
+        assert_eq!(one(&mut b, insert("b")), Records::default());
+
+        // when replacing, the old row is retracted
+        let mut b = b.on_duplicate_key(OnDuplicateKey::Replace);
+        assert_eq!(
+            one(&mut b, insert("c")),
+            vec![
+                Record::Negative(vec![1.into(), "a".into()]),
+                Record::Positive(vec![1.into(), "c".into()]),
+            ]
+            .into()
+        );
+
+        // when rejecting, writes are checked before they are processed
+        let b = b.on_duplicate_key(OnDuplicateKey::Reject);
+        let state = states.get(local).map(|s| &**s);
+        assert_eq!(
+            b.check_keys(&insert("d"), state),
+            Err(WriteError::DuplicateKey(vec![1.into()]))
+        );
+        let ops = vec![
+            TableOperation::Delete {
+                key: vec![1.into()],
+            },
+            TableOperation::Insert(vec![1.into(), "d".into()]),
+        ];
+        assert_eq!(b.check_keys(&ops, state), Ok(()));
+        let ops = vec![
+            TableOperation::Insert(vec![2.into(), "e".into()]),
+            TableOperation::Insert(vec![2.into(), "f".into()]),
+        ];
+        assert_eq!(
+            b.check_keys(&ops, state),
+            Err(WriteError::DuplicateKey(vec![2.into()]))
+        );
+    }
+
+    #[test]
+    fn it_rejects_missing_keys() {
+        use node;
+
+        let local = unsafe { LocalNodeIndex::make(0 as u32) };
+        let mut state = MemoryState::default();
+        state.add_key(&[0], None);
+        let mut states = StateMap::new();
+        states.insert(local, box state);
+
+        let mut b = Base::default().with_key(vec![0]);
+        let mut rs = b.process(
+            local,
+            vec![TableOperation::Insert(vec![1.into(), "a".into()])],
+            &states,
+        );
+        node::materialize(&mut rs, None, states.get_mut(local));
+        let state = states.get(local).map(|s| &**s);
+
+        let delete = |k: i32| TableOperation::Delete {
+            key: vec![k.into()],
+        };
+        let update = |k: i32| TableOperation::Update {
+            key: vec![k.into()],
+            set: vec![Modification::None, Modification::Set("b".into())],
+        };
+
+        assert_eq!(b.check_keys(&[delete(1), update(1)], state), Ok(()));
+        assert_eq!(
+            b.check_keys(&[delete(2)], state),
+            Err(WriteError::KeyNotFound(vec![2.into()]))
+        );
+        assert_eq!(
+            b.check_keys(&[update(2)], state),
+            Err(WriteError::KeyNotFound(vec![2.into()]))
+        );
+
+        // earlier operations in the same write are taken into account
+        assert_eq!(
+            b.check_keys(&[delete(1), delete(1)], state),
+            Err(WriteError::KeyNotFound(vec![1.into()]))
+        );
+        let ops = vec![
+            TableOperation::Insert(vec![2.into(), "c".into()]),
+            update(2),
+            delete(2),
+        ];
+        assert_eq!(b.check_keys(&ops, state), Ok(()));
+    }
+
+    #[test]
+    fn it_expands_modifications() {
+        use node;
+
+        let local = unsafe { LocalNodeIndex::make(0 as u32) };
+        let mut state = MemoryState::default();
+        state.add_key(&[0], None);
+        let mut states = StateMap::new();
+        states.insert(local, box state);
+
+        let mut b = Base::default().with_key(vec![0]);
+        let modify = |k: i32, set: Vec<(usize, DataType)>| TableOperation::Modify {
+            key: vec![k.into()],
+            set,
+        };
+        {
+            let mut process = |b: &mut Base, ops| {
+                let mut rs = b.process(local, ops, &states);
+                node::materialize(&mut rs, None, states.get_mut(local));
+                rs
+            };
+
+            process(
+                &mut b,
+                vec![TableOperation::Insert(vec![1.into(), "a".into(), 1.into()])],
+            );
+            assert_eq!(
+                process(&mut b, vec![modify(1, vec![(2, 2.into())])]),
+                vec![
+                    Record::Negative(vec![1.into(), "a".into(), 1.into()]),
+                    Record::Positive(vec![1.into(), "a".into(), 2.into()]),
+                ]
+                .into()
+            );
+
+            // modifications of the same row in one write are folded together
+            assert_eq!(
+                process(
+                    &mut b,
+                    vec![
+                        modify(1, vec![(1, "b".into())]),
+                        modify(1, vec![(2, 3.into()), (1, "c".into())]),
+                        modify(2, vec![(1, "x".into())]),
+                    ]
+                ),
+                vec![
+                    Record::Negative(vec![1.into(), "a".into(), 2.into()]),
+                    Record::Positive(vec![1.into(), "c".into(), 3.into()]),
+                ]
+                .into()
+            );
+        }
+
+        let state = states.get(local).map(|s| &**s);
+        assert_eq!(
+            b.check_keys(&[modify(2, vec![(1, "x".into())])], state),
+            Err(WriteError::KeyNotFound(vec![2.into()]))
+        );
+    }
+
+    #[test]
+    fn it_expires_rows() {
+        let ttl = Duration::from_secs(10);
+        let t0 = Instant::now();
+        let t1 = t0 + Duration::from_secs(5);
+        let mut b = Base::default().with_key(vec![0]).with_ttl(ttl);
+
+        let row = |k: i32, v: &str| vec![k.into(), v.into()];
+        let rs = vec![Record::Positive(row(1, "a")), Record::Positive(row(2, "b"))];
+        b.track_expiry(&rs.into(), t0, None);
+
+        // an updated row keeps its insertion time, but a re-inserted one does not
+        let rs = vec![
+            Record::Negative(row(1, "a")),
+            Record::Positive(row(1, "c")),
+            Record::Negative(row(2, "b")),
+        ];
+        b.track_expiry(&rs.into(), t1, None);
+        b.track_expiry(&vec![Record::Positive(row(2, "b"))].into(), t1, None);
+
+        assert!(b.expire(t1, 10, None).is_empty());
+        assert_eq!(
+            b.expire(t0 + ttl, 10, None),
+            vec![Record::Negative(row(1, "c"))].into()
+        );
+        assert_eq!(
+            b.expire(t1 + ttl, 10, None),
+            vec![Record::Negative(row(2, "b"))].into()
+        );
+
+        // at most the given number of rows are expired at a time
+        let rs = vec![
+            Record::Positive(row(3, "d")),
+            Record::Positive(row(4, "e")),
+            Record::Positive(row(5, "f")),
+        ];
+        b.track_expiry(&rs.into(), t1, None);
+        assert_eq!(b.expire(t1 + ttl, 2, None).len(), 2);
+        assert_eq!(b.expire(t1 + ttl, 2, None).len(), 1);
+        assert!(b.expire(t1 + ttl, 2, None).is_empty());
+    }
+
     fn test_lots_of_changes_in_same_batch(mut state: Box<State>) {
         use node;
         use prelude::*;
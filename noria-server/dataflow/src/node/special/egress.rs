@@ -1,18 +1,143 @@
 use fnv::FnvHashMap;
+use payload::ReplayPieceContext;
 use prelude::*;
 use std::collections::{HashMap, VecDeque};
+use std::mem;
+
+/// The most updates that an egress holds on to for each edge that has standby destinations, for
+/// re-sending to the standby that the edge is switched over to.
+const FAILOVER_WINDOW: usize = 1024;
+
+/// An ingress that an edge can be switched over to, and that is sent nothing until it is.
+#[derive(Serialize, Deserialize)]
+struct Standby {
+    node: NodeIndex,
+    local: LocalNodeIndex,
+    dest: ReplicaAddr,
+    /// What positions on the edge are offset by in the numbering of the updates that the ingress
+    /// has been sent (see `EgressTx::offset`).
+    offset: u64,
+    /// The position on the edge of the first update that the ingress may not have received.
+    from: u64,
+    /// Whether the ingress failed while it was being sent updates, in which case the edge is only
+    /// switched back to it once it has been re-added.
+    failed: bool,
+}
 
 #[derive(Serialize, Deserialize)]
 struct EgressTx {
     node: NodeIndex,
     local: LocalNodeIndex,
     dest: ReplicaAddr,
+    /// Number of updates sent over the edge so far, each of which is numbered with its position.
+    sent: u64,
+    /// What positions on the edge are offset by (wrapping around) in the numbering of the updates
+    /// that the ingress sees, which is not zero once the edge has been switched over to an
+    /// ingress that was numbering the updates it was sent before then.
+    offset: u64,
+    standbys: Vec<Standby>,
+    /// The most recent updates sent over the edge, by position, if it has any standbys.
+    #[serde(skip)]
+    window: VecDeque<(u64, Box<Packet>)>,
+}
+
+impl EgressTx {
+    /// Number the given update as the next one sent to the ingress.
+    fn sequence(&mut self, m: &mut Packet) {
+        match *m {
+            Packet::Message { ref mut seq, .. } => {
+                self.sent += 1;
+                *seq = Some(self.sent.wrapping_sub(self.offset));
+            }
+            Packet::ReplayPiece {
+                context: ReplayPieceContext::Regular { ref mut cut, .. },
+                ..
+            } => *cut = Some(self.sent.wrapping_sub(self.offset)),
+            _ => return,
+        }
+
+        if !self.standbys.is_empty() && m.is_regular() {
+            if self.window.len() == FAILOVER_WINDOW {
+                self.window.pop_front();
+            }
+            self.window.push_back((self.sent, box m.clone_data()));
+        }
+    }
+
+    /// Switch the edge over to its `i`th standby, which is first re-sent the updates held in the
+    /// window that it may not have received, numbered as it expects them. The ingress that is
+    /// switched away from becomes a standby of the edge.
+    fn switch_to(
+        &mut self,
+        i: usize,
+        failed: bool,
+        output: &mut FnvHashMap<ReplicaAddr, VecDeque<Box<Packet>>>,
+    ) -> Switch {
+        let to = self.standbys.swap_remove(i);
+        let oldest = self
+            .window
+            .front()
+            .map(|&(n, _)| n)
+            .unwrap_or(self.sent + 1);
+
+        let queue = output.entry(to.dest).or_default();
+        let mut resent = 0;
+        for &(n, ref m) in self.window.iter().filter(|&&(n, _)| n >= to.from) {
+            let mut m = box m.clone_data();
+            m.link_mut().dst = to.local;
+            if let Packet::Message { ref mut seq, .. } = *m {
+                *seq = Some(n.wrapping_sub(to.offset));
+            }
+            queue.push_back(m);
+            resent += 1;
+        }
+
+        let from = Standby {
+            node: mem::replace(&mut self.node, to.node),
+            local: mem::replace(&mut self.local, to.local),
+            dest: mem::replace(&mut self.dest, to.dest),
+            offset: mem::replace(&mut self.offset, to.offset),
+            // an ingress that failed may have lost any of the updates that were sent to it
+            from: if failed { oldest } else { self.sent + 1 },
+            failed,
+        };
+        let switch = Switch {
+            from: (from.node, from.local),
+            to: to.node,
+            resent,
+            missing: oldest.saturating_sub(to.from),
+        };
+        self.standbys.push(from);
+        switch
+    }
+}
+
+/// An edge of an egress that has been switched over from one ingress to another.
+#[derive(Debug)]
+pub struct Switch {
+    /// The ingress that the edge was switched away from, and its index in its domain.
+    pub from: (NodeIndex, LocalNodeIndex),
+    /// The ingress that the edge now sends to.
+    pub to: NodeIndex,
+    /// The number of updates that were re-sent to the ingress that the edge now sends to.
+    pub resent: usize,
+    /// The number of updates that the ingress that the edge now sends to may not have received,
+    /// but that were no longer held for re-sending.
+    pub missing: u64,
 }
 
+/// The most records that queued updates are folded into a single packet for, so that a burst of
+/// updates still reaches the next domain in pieces it can get through without stalling.
+const COALESCE_LIMIT: usize = 256;
+
 #[derive(Serialize, Deserialize)]
 pub struct Egress {
     txs: Vec<EgressTx>,
     tags: HashMap<Tag, NodeIndex>,
+
+    /// Number of updates that were folded into an update already queued for the same ingress.
+    #[serde(skip)]
+    coalesced: u64,
 }
 
 impl Clone for Egress {
@@ -22,6 +147,7 @@ impl Clone for Egress {
         Self {
             txs: Vec::new(),
             tags: self.tags.clone(),
+            coalesced: 0,
         }
     }
 }
@@ -31,6 +157,7 @@ impl Default for Egress {
         Self {
             tags: Default::default(),
             txs: Default::default(),
+            coalesced: 0,
         }
     }
 }
@@ -41,6 +168,10 @@ impl Egress {
             node: dst_g,
             local: dst_l,
             dest: addr,
+            sent: 0,
+            offset: 0,
+            standbys: Vec::new(),
+            window: VecDeque::new(),
         });
     }
 
@@ -48,6 +179,134 @@ impl Egress {
         self.tags.insert(tag, dst);
     }
 
+    /// Start sending to the given ingress node, which gets `first` before any of the updates that
+    /// are forwarded after it.
+    pub fn add_tx_after(
+        &mut self,
+        dst_g: NodeIndex,
+        dst_l: LocalNodeIndex,
+        addr: ReplicaAddr,
+        mut first: Box<Packet>,
+        shard: usize,
+        output: &mut FnvHashMap<ReplicaAddr, VecDeque<Box<Packet>>>,
+    ) {
+        first.link_mut().src = unsafe { LocalNodeIndex::make(shard as u32) };
+        first.link_mut().dst = dst_l;
+        self.add_tx(dst_g, dst_l, addr);
+        self.txs.last_mut().unwrap().sequence(&mut first);
+        output.entry(addr).or_default().push_back(first);
+    }
+
+    /// Stop sending to the given ingress node, which has been removed.
+    ///
+    /// Replay tags that lead to it are kept, so that evictions that are still on their way along
+    /// those paths are silently dropped here.
+    pub fn remove_tx(&mut self, dst_g: NodeIndex) {
+        self.txs.retain(|tx| tx.node != dst_g);
+        for tx in &mut self.txs {
+            tx.standbys.retain(|s| s.node != dst_g);
+            if tx.standbys.is_empty() {
+                tx.window.clear();
+            }
+        }
+    }
+
+    /// Stop sending to the ingress node `standby`, and hold it in reserve for the edge that now
+    /// sends to `primary` instead, so that the edge can be switched over to it. If `standby` is
+    /// an ingress that the edge was switched away from after it failed, it is re-added.
+    ///
+    /// `standby` must have been sent the same updates as `primary` until now.
+    pub fn add_standby(&mut self, primary: NodeIndex, standby: NodeIndex) -> Result<(), String> {
+        let i = self
+            .txs
+            .iter()
+            .position(|tx| tx.node == primary)
+            .ok_or_else(|| format!("egress does not send to {}", primary.index()))?;
+        if let Some(s) = self.txs[i].standbys.iter_mut().find(|s| s.node == standby) {
+            s.failed = false;
+            return Ok(());
+        }
+
+        let j = self
+            .txs
+            .iter()
+            .position(|tx| tx.node == standby)
+            .ok_or_else(|| format!("egress does not send to {}", standby.index()))?;
+        if i == j || !self.txs[j].standbys.is_empty() {
+            return Err(format!("{} can't be a standby", standby.index()));
+        }
+        let s = self.txs.remove(j);
+        let tx = self.txs.iter_mut().find(|tx| tx.node == primary).unwrap();
+        let standby = Standby {
+            node: s.node,
+            local: s.local,
+            dest: s.dest,
+            // the next update on the edge is the next one the standby expects
+            offset: tx.sent.wrapping_sub(s.sent.wrapping_sub(s.offset)),
+            from: tx.sent + 1,
+            failed: false,
+        };
+        tx.standbys.push(standby);
+        Ok(())
+    }
+
+    /// Switch the edge that sends to the ingress node `from` over to its standby `to`.
+    pub fn switch(
+        &mut self,
+        from: NodeIndex,
+        to: NodeIndex,
+        output: &mut FnvHashMap<ReplicaAddr, VecDeque<Box<Packet>>>,
+    ) -> Result<Switch, String> {
+        let tx = self
+            .txs
+            .iter_mut()
+            .find(|tx| tx.node == from)
+            .ok_or_else(|| format!("egress does not send to {}", from.index()))?;
+        let i = tx
+            .standbys
+            .iter()
+            .position(|s| s.node == to && !s.failed)
+            .ok_or_else(|| format!("{} is not a standby of {}", to.index(), from.index()))?;
+        Ok(tx.switch_to(i, false, output))
+    }
+
+    /// Switch every edge that sends to the given domain shard, whose receiving end has gone away,
+    /// over to the first of its standbys that is elsewhere and has not failed. Edges without such
+    /// a standby are left as they are.
+    pub fn fail_over(
+        &mut self,
+        failed: ReplicaAddr,
+        output: &mut FnvHashMap<ReplicaAddr, VecDeque<Box<Packet>>>,
+    ) -> Vec<Switch> {
+        let mut switched = Vec::new();
+        for tx in self.txs.iter_mut().filter(|tx| tx.dest == failed) {
+            let standby = tx
+                .standbys
+                .iter()
+                .position(|s| !s.failed && s.dest != failed);
+            if let Some(i) = standby {
+                switched.push(tx.switch_to(i, true, output));
+            }
+        }
+        switched
+    }
+
+    /// The shards of the domains that the egress sends to, or may be switched over to.
+    pub fn destinations(&self) -> Vec<ReplicaAddr> {
+        let mut destinations = Vec::new();
+        for tx in &self.txs {
+            destinations.push(tx.dest);
+            destinations.extend(tx.standbys.iter().map(|s| s.dest));
+        }
+        destinations
+    }
+
+    /// Number of updates that were sent as part of an earlier update to the same ingress, rather
+    /// than on their own.
+    pub fn coalesced(&self) -> u64 {
+        self.coalesced
+    }
+
     pub fn process(
         &mut self,
         m: &mut Option<Box<Packet>>,
@@ -57,10 +316,17 @@ impl Egress {
         let &mut Self {
             ref mut txs,
             ref tags,
+            ref mut coalesced,
         } = self;
 
+        // an egress that was added for an ingress that it hasn't started feeding yet has no one
+        // to send to, and whatever it gets until then is part of the state that it feeds first
+        if txs.is_empty() {
+            assert!(m.as_ref().unwrap().tag().is_none());
+            return;
+        }
+
         // send any queued updates to all external children
-        assert!(txs.len() > 0);
         let txn = txs.len() - 1;
 
         // we need to find the ingress node following this egress according to the path
@@ -97,10 +363,233 @@ impl Egress {
             m.link_mut().src = unsafe { LocalNodeIndex::make(shard as u32) };
             m.link_mut().dst = tx.local;
 
-            output.entry(tx.dest).or_default().push_back(m);
+            // updates that are headed for the same ingress before the domain gets to send them
+            // are sent as one, unless the edge has standbys, since the copy of the update that is
+            // held for them would then miss what is folded into it
+            let queue = output.entry(tx.dest).or_default();
+            let folded = match queue.back_mut() {
+                Some(last) if tx.standbys.is_empty() => coalesce(last, &mut m),
+                _ => false,
+            };
+            if folded {
+                *coalesced += 1;
+            } else {
+                tx.sequence(&mut m);
+                queue.push_back(m);
+            }
             if take {
                 break;
             }
         }
     }
 }
+
+/// Fold the records of `m` into `into`, which is queued just before it, if both are regular
+/// updates for the same ingress. Updates that carry acknowledgements or a tracer are never
+/// folded, and neither is anything else, so nothing is reordered across them. The write markers
+/// of both are kept.
+fn coalesce(into: &mut Packet, m: &mut Packet) -> bool {
+    match (into, m) {
+        (
+            &mut Packet::Message {
+                ref link,
+                ref mut src,
+                ref mut data,
+                ref tracer,
+                ref senders,
+                ref mut written,
+                seq: _,
+                ref mut markers,
+            },
+            &mut Packet::Message {
+                link: ref next_link,
+                src: ref next_src,
+                data: ref mut next_data,
+                tracer: ref next_tracer,
+                senders: ref next_senders,
+                written: next_written,
+                seq: _,
+                markers: ref mut next_markers,
+            },
+        ) => {
+            if link != next_link
+                || tracer.is_some()
+                || next_tracer.is_some()
+                || !senders.is_empty()
+                || !next_senders.is_empty()
+                || data.len() + next_data.len() > COALESCE_LIMIT
+            {
+                return false;
+            }
+
+            data.append(next_data);
+            if src.map(|s| s.token) != next_src.map(|s| s.token) {
+                *src = None;
+            }
+            if next_written > *written {
+                *written = next_written;
+            }
+            markers.append(next_markers);
+            true
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(rs: Vec<Vec<DataType>>) -> Box<Packet> {
+        let l = unsafe { LocalNodeIndex::make(0) };
+        Box::new(Packet::Message {
+            link: Link::new(l, l),
+            src: None,
+            data: rs.into(),
+            tracer: None,
+            senders: vec![],
+            written: None,
+            seq: None,
+            markers: vec![],
+        })
+    }
+
+    fn setup() -> (Egress, ReplicaAddr) {
+        let mut e = Egress::default();
+        let addr = (0.into(), 0);
+        e.add_tx(NodeIndex::new(1), unsafe { LocalNodeIndex::make(1) }, addr);
+        (e, addr)
+    }
+
+    #[test]
+    fn it_coalesces_queued_updates() {
+        let (mut e, addr) = setup();
+        let mut output = FnvHashMap::default();
+        e.process(&mut Some(message(vec![vec![1.into()]])), 0, &mut output);
+        e.process(&mut Some(message(vec![vec![2.into()]])), 0, &mut output);
+        assert_eq!(e.coalesced(), 1);
+
+        let queue = &output[&addr];
+        assert_eq!(queue.len(), 1);
+        assert_eq!(
+            *queue[0].data(),
+            vec![vec![1.into()], vec![2.into()]].into()
+        );
+    }
+
+    #[test]
+    fn it_keeps_acknowledged_updates_apart() {
+        let (mut e, addr) = setup();
+        let mut output = FnvHashMap::default();
+        e.process(&mut Some(message(vec![vec![1.into()]])), 0, &mut output);
+
+        let mut m = message(vec![vec![2.into()]]);
+        if let Packet::Message {
+            ref mut senders, ..
+        } = *m
+        {
+            senders.push(SourceChannelIdentifier { token: 1 });
+        }
+        e.process(&mut Some(m), 0, &mut output);
+        e.process(&mut Some(message(vec![vec![3.into()]])), 0, &mut output);
+        assert_eq!(e.coalesced(), 0);
+        assert_eq!(output[&addr].len(), 3);
+    }
+
+    #[test]
+    fn it_bounds_coalesced_updates() {
+        let (mut e, addr) = setup();
+        let mut output = FnvHashMap::default();
+        let full = (0..COALESCE_LIMIT as i32).map(|i| vec![i.into()]).collect();
+        e.process(&mut Some(message(full)), 0, &mut output);
+        e.process(&mut Some(message(vec![vec![0.into()]])), 0, &mut output);
+        assert_eq!(e.coalesced(), 0);
+        assert_eq!(output[&addr].len(), 2);
+    }
+
+    #[test]
+    fn it_numbers_updates_and_cuts_replays() {
+        let (mut e, addr) = setup();
+        let mut output = FnvHashMap::default();
+        let seq = |m: &Packet| match *m {
+            Packet::Message { seq, .. } => seq,
+            _ => unreachable!(),
+        };
+
+        e.process(&mut Some(message(vec![vec![1.into()]])), 0, &mut output);
+        e.process(&mut Some(message(vec![vec![2.into()]])), 0, &mut output);
+        assert_eq!(seq(&output[&addr][0]), Some(1));
+        output.clear();
+
+        // a replay piece is cut after the one update sent so far, as the folded one went with it
+        e.add_tag(Tag(0), NodeIndex::new(1));
+        let l = unsafe { LocalNodeIndex::make(0) };
+        let mut piece = Some(Box::new(Packet::ReplayPiece {
+            link: Link::new(l, l),
+            tag: Tag(0),
+            data: Records::default(),
+            context: ReplayPieceContext::Regular {
+                last: true,
+                rows: None,
+                cut: None,
+            },
+        }));
+        e.process(&mut piece, 0, &mut output);
+        e.process(&mut Some(message(vec![vec![3.into()]])), 0, &mut output);
+
+        let queue = &output[&addr];
+        match *queue[0] {
+            Packet::ReplayPiece {
+                context: ReplayPieceContext::Regular { cut, .. },
+                ..
+            } => assert_eq!(cut, Some(1)),
+            _ => unreachable!(),
+        }
+        assert_eq!(seq(&queue[1]), Some(2));
+    }
+
+    #[test]
+    fn it_resends_held_updates_to_the_standby_it_fails_over_to() {
+        let (mut e, primary) = setup();
+        let standby = (1.into(), 0);
+        let (p, s) = (NodeIndex::new(1), NodeIndex::new(2));
+        e.add_tx(s, unsafe { LocalNodeIndex::make(2) }, standby);
+        let seqs = |q: &VecDeque<Box<Packet>>| -> Vec<_> {
+            q.iter()
+                .map(|m| match **m {
+                    Packet::Message { seq, .. } => seq.unwrap(),
+                    _ => unreachable!(),
+                })
+                .collect()
+        };
+        let send = |e: &mut Egress, n: i32| {
+            let mut output = FnvHashMap::default();
+            e.process(&mut Some(message(vec![vec![n.into()]])), 0, &mut output);
+            output
+        };
+
+        // both are sent the first update, and only the primary the ones after that
+        send(&mut e, 1);
+        e.add_standby(p, s).unwrap();
+        assert_eq!(e.destinations(), vec![primary, standby]);
+        let output = send(&mut e, 2);
+        assert_eq!(seqs(&output[&primary]), vec![2]);
+        assert!(!output.contains_key(&standby));
+        send(&mut e, 3);
+
+        // the standby gets the updates it missed, and then takes over
+        let mut output = FnvHashMap::default();
+        let switched = e.fail_over(primary, &mut output);
+        assert_eq!(switched.len(), 1);
+        assert_eq!(switched[0].to, s);
+        assert_eq!(switched[0].missing, 0);
+        assert_eq!(seqs(&output[&standby]), vec![2, 3]);
+        assert_eq!(seqs(&send(&mut e, 4)[&standby]), vec![4]);
+
+        // the primary, once re-added, is re-sent what it may have lost, numbered as it was before
+        e.add_standby(s, p).unwrap();
+        let mut output = FnvHashMap::default();
+        e.switch(s, p, &mut output).unwrap();
+        assert_eq!(seqs(&output[&primary]), vec![2, 3, 4]);
+    }
+}
@@ -1,6 +1,8 @@
 use backlog;
+use common::SizeOf;
 use noria::channel;
 use prelude::*;
+use std::time;
 
 /// A StreamUpdate reflects the addition or deletion of a row from a reader node.
 #[derive(Clone, Debug, PartialEq)]
@@ -36,6 +38,23 @@ pub struct Reader {
 
     for_node: NodeIndex,
     state: Option<Vec<usize>>,
+    index: IndexType,
+    memory_limit: Option<usize>,
+    sorted_by: Option<usize>,
+    scannable: bool,
+    hidden: bool,
+    publish: PublishPolicy,
+
+    #[serde(skip)]
+    evicted_keys: u64,
+    #[serde(skip)]
+    evicted_bytes: u64,
+
+    // the records that reads can't see yet, and when the oldest of them arrived
+    #[serde(skip)]
+    unpublished: usize,
+    #[serde(skip)]
+    unpublished_since: Option<time::Instant>,
 }
 
 impl Clone for Reader {
@@ -45,6 +64,16 @@ impl Clone for Reader {
             writer: None,
             streamers: self.streamers.clone(),
             state: self.state.clone(),
+            index: self.index,
+            memory_limit: self.memory_limit,
+            sorted_by: self.sorted_by,
+            scannable: self.scannable,
+            hidden: self.hidden,
+            publish: self.publish,
+            evicted_keys: self.evicted_keys,
+            evicted_bytes: self.evicted_bytes,
+            unpublished: 0,
+            unpublished_since: None,
             for_node: self.for_node,
         }
     }
@@ -56,6 +85,16 @@ impl Reader {
             writer: None,
             streamers: Vec::new(),
             state: None,
+            index: IndexType::default(),
+            memory_limit: None,
+            sorted_by: None,
+            scannable: false,
+            hidden: false,
+            publish: PublishPolicy::default(),
+            evicted_keys: 0,
+            evicted_bytes: 0,
+            unpublished: 0,
+            unpublished_since: None,
             for_node,
         }
     }
@@ -66,7 +105,6 @@ impl Reader {
         self.for_node
     }
 
-    #[allow(dead_code)]
     pub(crate) fn writer(&self) -> Option<&backlog::WriteHandle> {
         self.writer.as_ref()
     }
@@ -81,6 +119,16 @@ impl Reader {
             writer: self.writer.take(),
             streamers: mem::replace(&mut self.streamers, Vec::new()),
             state: self.state.clone(),
+            index: self.index,
+            memory_limit: self.memory_limit,
+            sorted_by: self.sorted_by,
+            scannable: self.scannable,
+            hidden: self.hidden,
+            publish: self.publish,
+            evicted_keys: self.evicted_keys,
+            evicted_bytes: self.evicted_bytes,
+            unpublished: self.unpublished,
+            unpublished_since: self.unpublished_since,
             for_node: self.for_node,
         }
     }
@@ -121,11 +169,160 @@ impl Reader {
         }
     }
 
+    pub fn index_type(&self) -> IndexType {
+        self.index
+    }
+
+    /// Choose what kind of index serves lookups into this reader. Ordered readers must be keyed
+    /// on a single column.
+    pub fn set_index_type(&mut self, index: IndexType) {
+        if index == IndexType::BTreeMap {
+            assert_eq!(
+                self.key().map(|k| k.len()),
+                Some(1),
+                "ordered readers must be keyed on exactly one column"
+            );
+        }
+        self.index = index;
+    }
+
+    pub fn memory_limit(&self) -> Option<usize> {
+        self.memory_limit
+    }
+
+    /// Bound the memory used by this reader's state. Whenever the limit is exceeded, the least
+    /// recently read keys are evicted until the state fits again.
+    ///
+    /// The limit is only enforced if the reader ends up being partially materialized, since the
+    /// evicted keys must be replayed if they are read again.
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        self.memory_limit = limit;
+    }
+
+    pub fn sorted_by(&self) -> Option<usize> {
+        self.sorted_by
+    }
+
+    /// Keep the rows of each key of this reader sorted by the given column, so that pages of them
+    /// ordered by that column can be read without sorting them first.
+    ///
+    /// Sorted readers are always fully materialized.
+    pub fn set_sorted_by(&mut self, column: Option<usize>) {
+        self.sorted_by = column;
+    }
+
+    pub fn is_scannable(&self) -> bool {
+        self.scannable
+    }
+
+    /// Let the whole of this reader's state be read in batches (see `SingleReadHandle::scan_and`).
+    ///
+    /// Scannable readers are always fully materialized.
+    pub fn set_scannable(&mut self, scannable: bool) {
+        self.scannable = scannable;
+    }
+
+    pub fn is_hidden(&self) -> bool {
+        self.hidden
+    }
+
+    /// Keep reads from seeing this reader's state, even once it is ready, until the reader is
+    /// exposed (see `Packet::ExposeReaders`).
+    pub fn set_hidden(&mut self, hidden: bool) {
+        self.hidden = hidden;
+    }
+
+    pub fn publish_policy(&self) -> PublishPolicy {
+        self.publish
+    }
+
+    /// Choose when this reader makes the writes that reach it visible to reads.
+    pub fn set_publish_policy(&mut self, policy: PublishPolicy) {
+        self.publish = policy;
+    }
+
+    /// Whether this reader holds writes that reads can't see yet.
+    pub fn has_unpublished(&self) -> bool {
+        self.unpublished_since.is_some()
+    }
+
+    /// When the writes this reader holds must be made visible, even if the domain is busy, if
+    /// it holds any and its policy bounds how long they may wait.
+    pub fn publish_deadline(&self) -> Option<time::Instant> {
+        let since = self.unpublished_since?;
+        match self.publish {
+            PublishPolicy::Interval(d) | PublishPolicy::Adaptive { max_delay: d } => {
+                Some(since + d)
+            }
+            PublishPolicy::EveryMessage | PublishPolicy::EveryRecords(_) => None,
+        }
+    }
+
+    /// Make the writes this reader holds visible if its policy says they are due at `now`, given
+    /// whether the domain has run out of messages to process. Returns whether it swapped.
+    pub fn publish_if_due(&mut self, now: time::Instant, idle: bool) -> bool {
+        let since = match self.unpublished_since {
+            Some(since) => since,
+            None => return false,
+        };
+        let due = match self.publish {
+            PublishPolicy::EveryMessage => true,
+            PublishPolicy::EveryRecords(n) => idle || self.unpublished >= n,
+            PublishPolicy::Interval(d) => now.duration_since(since) >= d,
+            PublishPolicy::Adaptive { max_delay } => idle || now.duration_since(since) >= max_delay,
+        };
+        if due {
+            self.publish();
+        }
+        due
+    }
+
+    /// Make all the writes this reader holds visible to reads.
+    fn publish(&mut self) {
+        if let Some(ref mut state) = self.writer {
+            if let Some(limit) = self.memory_limit {
+                let size = state.deep_size_of();
+                if size > limit as u64 {
+                    let (keys, freed) = state.evict_lru_keys(size - limit as u64);
+                    self.evicted_keys += keys.len() as u64;
+                    self.evicted_bytes += freed;
+                }
+            }
+
+            // TODO: avoid doing the pointer swap if we didn't modify anything (inc. ts)
+            state.swap();
+        }
+        self.unpublished = 0;
+        self.unpublished_since = None;
+    }
+
+    /// The number of keys, and the number of bytes, that have been evicted from this reader.
+    pub fn evictions(&self) -> (u64, u64) {
+        (self.evicted_keys, self.evicted_bytes)
+    }
+
     pub fn state_size(&self) -> Option<u64> {
-        use common::SizeOf;
         self.writer.as_ref().map(|w| w.deep_size_of())
     }
 
+    /// A checksum of the rows that are visible to reads, if this reader is materialized.
+    pub fn checksum(&self) -> Option<u64> {
+        self.writer.as_ref().map(|w| w.checksum())
+    }
+
+    /// The number of rows that are visible to reads, if this reader is materialized.
+    pub fn rows(&self) -> Option<usize> {
+        self.writer.as_ref().map(|w| w.rows())
+    }
+
+    /// A copy of every row that is visible to reads, if this reader is fully materialized.
+    pub fn cloned_rows(&self) -> Option<Vec<Vec<DataType>>> {
+        match self.writer {
+            Some(ref w) if !w.is_partial() => Some(w.cloned_rows()),
+            _ => None,
+        }
+    }
+
     /// Evict a randomly selected key, returning the number of bytes evicted.
     /// Note that due to how `evmap` applies the evictions asynchronously, we can only evict a
     /// single key at a time here.
@@ -136,10 +333,55 @@ impl Reader {
             let mut rng = rand::thread_rng();
             bytes_freed = handle.evict_random_key(&mut rng);
             handle.swap();
+            if bytes_freed > 0 {
+                self.evicted_keys += 1;
+                self.evicted_bytes += bytes_freed;
+            }
+        }
+        bytes_freed
+    }
+
+    /// Evict the least recently read keys until at least `num_bytes` have been freed, returning
+    /// the number of bytes evicted. Evicts nothing unless the reader has a memory limit.
+    pub fn evict_lru_keys(&mut self, num_bytes: u64) -> u64 {
+        let mut bytes_freed = 0;
+        if let Some(ref mut handle) = self.writer {
+            let (keys, freed) = handle.evict_lru_keys(num_bytes);
+            handle.swap();
+            self.evicted_keys += keys.len() as u64;
+            self.evicted_bytes += freed;
+            bytes_freed = freed;
         }
         bytes_freed
     }
 
+    /// Evict the given keys, returning the number of bytes evicted. Keys that are not present
+    /// are ignored.
+    pub fn evict_keys(&mut self, keys: &[Vec<DataType>]) -> u64 {
+        let mut bytes_freed = 0;
+        if let Some(ref mut handle) = self.writer {
+            for k in keys {
+                if let Some(freed) = handle.evict_key(&k[..]) {
+                    self.evicted_keys += 1;
+                    bytes_freed += freed;
+                }
+            }
+            handle.swap();
+        }
+        self.evicted_bytes += bytes_freed;
+        bytes_freed
+    }
+
+    /// Evict the least recently read keys until this reader is within its memory limit,
+    /// returning the number of bytes evicted.
+    pub fn trim_to_limit(&mut self) -> u64 {
+        let excess = match (self.memory_limit, self.state_size()) {
+            (Some(limit), Some(size)) if size > limit as u64 => size - limit as u64,
+            _ => return 0,
+        };
+        self.evict_lru_keys(excess)
+    }
+
     pub fn on_eviction(&mut self, _key_columns: &[usize], keys: &[Vec<DataType>]) {
         // NOTE: *could* be None if reader has been created but its state hasn't been built yet
         if let Some(w) = self.writer.as_mut() {
@@ -150,7 +392,14 @@ impl Reader {
         }
     }
 
-    pub fn process(&mut self, m: &mut Option<Box<Packet>>, swap: bool) {
+    /// Apply the given message to this reader's state. Replays are never made visible here, and
+    /// regular messages are made visible as the reader's `PublishPolicy` says if `swap` is set.
+    ///
+    /// Returns the negative records that had no copy of their row left to remove (see
+    /// `WriteHandle::add`).
+    pub fn process(&mut self, m: &mut Option<Box<Packet>>, swap: bool) -> Vec<Record> {
+        let mut added = None;
+        let mut unmatched = Vec::new();
         if let Some(ref mut state) = self.writer {
             let m = m.as_mut().unwrap();
             // make sure we don't fill a partial materialization
@@ -202,16 +451,43 @@ impl Reader {
                 });
             }
 
-            if self.streamers.is_empty() {
-                state.add(m.take_data());
-            } else {
-                state.add(m.data().iter().cloned());
+            if state.has_subscribers() && m.is_regular() {
+                // replays only fill in state, so subscribers don't need to hear about them
+                state.publish(m.data().iter());
             }
 
-            if swap {
-                // TODO: avoid doing the pointer swap if we didn't modify anything (inc. ts)
-                state.swap();
+            if let Packet::Message {
+                written: Some(written),
+                ..
+            } = **m
+            {
+                state.applied_write(written);
+            }
+            if let Packet::Message { ref markers, .. } = **m {
+                // clients waiting for these writes can tell that they are visible after the swap
+                state.applied_markers(markers);
             }
+
+            added = Some(m.data().len());
+            unmatched = if self.streamers.is_empty() {
+                state.add(m.take_data())
+            } else {
+                state.add(m.data().iter().cloned())
+            };
+        }
+
+        match added {
+            Some(added) if swap => {
+                self.unpublished += added;
+                if self.publish == PublishPolicy::EveryMessage {
+                    self.publish();
+                } else {
+                    let now = time::Instant::now();
+                    self.unpublished_since.get_or_insert(now);
+                    self.publish_if_due(now, false);
+                }
+            }
+            _ => {}
         }
 
         // TODO: don't send replays to streams?
@@ -239,5 +515,172 @@ impl Reader {
                 .is_ok()
             });
         }
+        unmatched
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use noria::filter::RowFilter;
+    use payload::ReplayPieceContext;
+
+    #[test]
+    fn it_forwards_changes_to_subscribers() {
+        let mut r = Reader::new(NodeIndex::new(0));
+        r.set_key(&[0]);
+        r.set_publish_policy(PublishPolicy::EveryMessage);
+        let (rh, wh) = backlog::new(2, &[0]);
+        r.set_write_handle(wh);
+        let sub = rh.subscribe(&[1.into()], 8);
+        let only_b = rh.subscribe_filtered(&[1.into()], 8, RowFilter::equal(1, "b"));
+        let only_z = rh.subscribe_filtered(&[1.into()], 8, RowFilter::equal(1, "z"));
+
+        let link = unsafe { Link::new(LocalNodeIndex::make(0), LocalNodeIndex::make(1)) };
+        let a = vec![1.into(), "a".into()];
+        let b = vec![1.into(), "b".into()];
+        let c = vec![2.into(), "c".into()];
+
+        // replays just fill in state
+        let mut m = Some(box Packet::ReplayPiece {
+            link,
+            tag: Tag(0),
+            data: vec![a.clone()].into(),
+            context: ReplayPieceContext::Regular {
+                last: true,
+                rows: None,
+                cut: None,
+            },
+        });
+        r.process(&mut m, true);
+        assert_eq!(sub.try_recv(), Ok(None));
+        assert_eq!(only_b.try_recv(), Ok(None));
+
+        let mut m = Some(box Packet::Message {
+            link,
+            src: None,
+            data: vec![Record::Negative(a.clone()), b.clone().into(), c.into()].into(),
+            tracer: None,
+            senders: vec![],
+            written: None,
+            seq: None,
+            markers: vec![],
+        });
+        r.process(&mut m, true);
+        assert_eq!(
+            sub.try_recv(),
+            Ok(Some(vec![Record::Negative(a), b.clone().into()]))
+        );
+        // filtered subscribers only hear about the rows that pass their filters, if any
+        assert_eq!(only_b.try_recv(), Ok(Some(vec![b.clone().into()])));
+        assert_eq!(only_z.try_recv(), Ok(None));
+        assert_eq!(
+            rh.try_find_and(&[1.into()], |rs| rs.to_vec()).unwrap().0,
+            Some(vec![b])
+        );
+
+        drop(sub);
+        drop(only_b);
+        drop(only_z);
+        assert!(!r.writer().unwrap().has_subscribers());
+    }
+
+    #[test]
+    fn it_hands_back_negatives_below_zero() {
+        let mut r = Reader::new(NodeIndex::new(0));
+        r.set_key(&[0]);
+        r.set_publish_policy(PublishPolicy::EveryMessage);
+        let (rh, wh) = backlog::new(2, &[0]);
+        r.set_write_handle(wh);
+        r.writer_mut().unwrap().swap();
+
+        let link = unsafe { Link::new(LocalNodeIndex::make(0), LocalNodeIndex::make(1)) };
+        let a = vec![1.into(), "a".into()];
+        let mut write = |rs: Vec<Record>| {
+            let mut m = Some(box Packet::Message {
+                link,
+                src: None,
+                data: rs.into(),
+                tracer: None,
+                senders: vec![],
+                written: None,
+                seq: None,
+                markers: vec![],
+            });
+            r.process(&mut m, true)
+        };
+
+        // two identical rows make two copies, and the one negative only takes away one of them
+        assert!(write(vec![a.clone().into(), a.clone().into()]).is_empty());
+        assert!(write(vec![Record::Negative(a.clone())]).is_empty());
+        assert_eq!(
+            rh.try_find_and(&[1.into()], |rs| rs.to_vec()).unwrap().0,
+            Some(vec![a.clone()])
+        );
+
+        // after which there is only one left to remove
+        assert_eq!(
+            write(vec![Record::Negative(a.clone()), Record::Negative(a.clone())]),
+            vec![Record::Negative(a.clone())]
+        );
+        assert_eq!(
+            rh.try_find_and(&[1.into()], |rs| rs.len())
+                .unwrap()
+                .0
+                .unwrap_or(0),
+            0
+        );
+    }
+
+    #[test]
+    fn it_publishes_batches_of_records() {
+        let mut r = Reader::new(NodeIndex::new(0));
+        r.set_key(&[0]);
+        r.set_publish_policy(PublishPolicy::EveryRecords(3));
+        let (rh, wh) = backlog::new(2, &[0]);
+        r.set_write_handle(wh);
+        r.writer_mut().unwrap().swap();
+
+        let link = unsafe { Link::new(LocalNodeIndex::make(0), LocalNodeIndex::make(1)) };
+        let write = |r: &mut Reader, rs: Vec<Vec<DataType>>| {
+            let mut m = Some(box Packet::Message {
+                link,
+                src: None,
+                data: rs.into_iter().map(Record::from).collect::<Vec<_>>().into(),
+                tracer: None,
+                senders: vec![],
+                written: None,
+                seq: None,
+                markers: vec![],
+            });
+            r.process(&mut m, true);
+        };
+        let rows = |key: i32| {
+            rh.try_find_and(&[key.into()], |rs| rs.len())
+                .unwrap()
+                .0
+                .unwrap_or(0)
+        };
+
+        // nothing is visible until three records have arrived
+        write(
+            &mut r,
+            vec![vec![1.into(), "a".into()], vec![1.into(), "b".into()]],
+        );
+        assert_eq!(rows(1), 0);
+        assert!(r.has_unpublished());
+        assert_eq!(r.publish_deadline(), None);
+        assert!(!r.publish_if_due(time::Instant::now(), false));
+        write(&mut r, vec![vec![2.into(), "c".into()]]);
+        assert_eq!(rows(1), 2);
+        assert_eq!(rows(2), 1);
+        assert!(!r.has_unpublished());
+
+        // but fewer are published once the domain has nothing else to do
+        write(&mut r, vec![vec![3.into(), "d".into()]]);
+        assert_eq!(rows(3), 0);
+        assert!(r.publish_if_due(time::Instant::now(), true));
+        assert_eq!(rows(3), 1);
+        assert!(!r.has_unpublished());
     }
 }
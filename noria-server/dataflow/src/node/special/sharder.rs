@@ -4,6 +4,17 @@ use prelude::*;
 use std::collections::VecDeque;
 use vec_map::VecMap;
 
+/// Sends each record to the shard of the domain below that its value in one column hashes to.
+///
+/// A sharder in a sharded domain shuffles records from every shard of its own domain to the
+/// shards below, each of which therefore hears from all of them. Those shards have a shard merger
+/// right below their ingress that combines the replays from the different senders, and that tells
+/// them apart by the shard index each sharder puts in the source of what it sends.
+///
+/// All records for a given value are sent to the same shard, in the order the sharder processes
+/// them. Records for the same value from *different* shards above are interleaved arbitrarily.
+/// Since those shards are sharded by another column, such records always stem from different rows
+/// above, and so they only ever need to be applied in some order, not in a particular one.
 #[derive(Serialize, Deserialize)]
 pub struct Sharder {
     txs: Vec<(LocalNodeIndex, ReplicaAddr)>,
@@ -50,6 +61,11 @@ impl Sharder {
         }
     }
 
+    /// The shards of the domain that the sharder sends to.
+    pub fn destinations(&self) -> Vec<ReplicaAddr> {
+        self.txs.iter().map(|&(_, addr)| addr).collect()
+    }
+
     pub fn sharded_by(&self) -> usize {
         self.shard_by
     }
@@ -68,11 +84,13 @@ impl Sharder {
         &mut self,
         m: &mut Option<Box<Packet>>,
         index: LocalNodeIndex,
-        is_sharded: bool,
+        on_shard: Option<usize>,
         output: &mut FnvHashMap<ReplicaAddr, VecDeque<Box<Packet>>>,
     ) {
         // we need to shard the records inside `m` by their key,
         let mut m = m.take().unwrap();
+        // the edges from a sharder aren't numbered like those from an egress are
+        m.clear_seq();
         for record in m.take_data() {
             let shard = self.to_shard(&record);
             let p = self
@@ -84,13 +102,15 @@ impl Sharder {
 
         let mut force_all = false;
         if let Packet::ReplayPiece {
-            context: payload::ReplayPieceContext::Regular { last: true },
+            context: payload::ReplayPieceContext::Regular { last, .. },
             ..
         } = *m
         {
-            // this is the last replay piece for a full replay
-            // we need to make sure it gets to every shard so they know to ready the node
-            force_all = true;
+            // the last replay piece for a full replay needs to get to every shard so they know to
+            // ready the node. and if we are sharded, the shard merger in each shard below needs
+            // to know that our replay has started before it gets anything else from us, as it
+            // would otherwise let through (and so lose) updates that our replay doesn't include.
+            force_all = last || on_shard.is_some();
         }
         if let Packet::ReplayPiece {
             context: payload::ReplayPieceContext::Partial { .. },
@@ -102,6 +122,11 @@ impl Sharder {
             // eventual shard merged! pretty unfortunate. TODO
             force_all = true;
         }
+        if m.has_markers() {
+            // the write may be waited for in a reader below any of the shards, and the shard
+            // mergers below need to hear about it from every shard before they pass it on
+            force_all = true;
+        }
         if force_all {
             for shard in 0..self.txs.len() {
                 self.sharded
@@ -110,19 +135,14 @@ impl Sharder {
             }
         }
 
-        if is_sharded {
-            // FIXME: we don't know how many shards in the destination domain our sibling Sharders
-            // sent to, so we don't know what to put here. we *could* put self.txs.len() and send
-            // empty messages to all other shards, which is probably pretty sensible, but that only
-            // solves half the problem. the destination shard domains will then recieve *multiple*
-            // replay pieces for each incoming replay piece, and needs to combine them somehow.
-            // it's unclear how we do that.
-            unimplemented!();
-        }
-
+        // the shard mergers below a sharded sharder need to know which shard sent what
+        let src = match on_shard {
+            Some(shard) => unsafe { LocalNodeIndex::make(shard as u32) },
+            None => index,
+        };
         for (i, &mut (dst, addr)) in self.txs.iter_mut().enumerate() {
             if let Some(mut shard) = self.sharded.remove(i) {
-                shard.link_mut().src = index.into();
+                shard.link_mut().src = src;
                 shard.link_mut().dst = dst;
                 output.entry(addr).or_default().push_back(shard);
             }
@@ -135,11 +155,8 @@ impl Sharder {
         tag: Tag,
         keys: &[Vec<DataType>],
         src: LocalNodeIndex,
-        is_sharded: bool,
         output: &mut FnvHashMap<ReplicaAddr, VecDeque<Box<Packet>>>,
     ) {
-        assert!(!is_sharded);
-
         if key_columns.len() == 1 && key_columns[0] == self.shard_by {
             // Send only to the shards that must evict something.
             for key in keys {
@@ -32,6 +32,14 @@ impl Ingredient for Distinct {
         Clone::clone(self).into()
     }
 
+    fn fingerprint(&self) -> Option<String> {
+        Some(format!(
+            "distinct({}, {:?})",
+            self.src.as_global().index(),
+            self.group_by
+        ))
+    }
+
     fn ancestors(&self) -> Vec<NodeIndex> {
         vec![self.src.as_global()]
     }
@@ -44,6 +52,7 @@ impl Ingredient for Distinct {
         _: Option<&[usize]>,
         _: &DomainNodes,
         state: &StateMap,
+        _: &mut AuxStateMap,
     ) -> ProcessingResult {
         debug_assert_eq!(from, *self.src);
 
@@ -146,10 +155,19 @@ impl Ingredient for Distinct {
         self.us = Some(remap[&us]);
     }
 
+    fn reparent(&mut self, parents: &HashMap<NodeIndex, IndexPair>) {
+        self.src.remap(parents);
+    }
+
     fn parent_columns(&self, column: usize) -> Vec<(NodeIndex, Option<usize>)> {
         vec![(self.src.as_global(), Some(column))]
     }
 
+    fn referenced_columns(&self) -> Vec<(NodeIndex, usize)> {
+        let src = self.src.as_global();
+        self.group_by.iter().map(|&c| (src, c)).collect()
+    }
+
     fn resolve(&self, col: usize) -> Option<Vec<(NodeIndex, usize)>> {
         Some(vec![(self.src.as_global(), col)])
     }
@@ -163,6 +181,10 @@ impl Ingredient for Distinct {
             .into_iter()
             .collect()
     }
+
+    fn row_bound(&self) -> RowBound {
+        RowBound::AtMostSome
+    }
 }
 
 /// Tests for the Distinct Operator
@@ -1,9 +1,8 @@
 use std::borrow::Cow;
 use std::collections::HashMap;
-use std::fmt::{self, Display};
 use std::sync;
 
-pub use nom_sql::Operator;
+pub use noria::filter::{FilterCondition, Operator, Value};
 use prelude::*;
 
 /// Filters incoming records according to some filter.
@@ -13,32 +12,9 @@ pub struct Filter {
     filter: sync::Arc<Vec<Option<FilterCondition>>>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-pub enum Value {
-    Constant(DataType),
-    Column(usize),
-}
-
-impl From<DataType> for Value {
-    fn from(dt: DataType) -> Self {
-        Value::Constant(dt)
-    }
-}
-
-impl Display for Value {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            Value::Constant(ref c) => write!(f, "{}", c),
-            Value::Column(ref ci) => write!(f, "col: {}", ci),
-        }
-    }
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-pub enum FilterCondition {
-    Comparison(Operator, Value),
-    In(Vec<DataType>),
-}
+/// Batches with fewer records than this are filtered a record at a time, since evaluating the
+/// conditions across the batch only pays for its mask once there are a few records to share it.
+const BATCH_MIN: usize = 8;
 
 impl Filter {
     /// Construct a new filter operator. The `filter` vector must have as many elements as the
@@ -50,6 +26,54 @@ impl Filter {
             filter: sync::Arc::new(Vec::from(filter)),
         }
     }
+
+    /// Whether the given row passes this filter.
+    fn matches(&self, r: &[DataType]) -> bool {
+        self.filter.iter().enumerate().all(|(i, fi)| match *fi {
+            Some(ref cond) => cond.holds(r, i),
+            // everything matches no condition
+            None => true,
+        })
+    }
+
+    /// Which of the given records pass this filter.
+    ///
+    /// Rather than checking every condition for one record before moving on to the next, each
+    /// condition is checked across the whole batch in turn. That keeps the inner loop on a single
+    /// column and a single comparison, and records that fail one condition are skipped by the rest.
+    fn matching(&self, rs: &[Record]) -> Vec<bool> {
+        let mut keep = vec![true; rs.len()];
+        for (i, fi) in self.filter.iter().enumerate() {
+            let cond = match *fi {
+                Some(ref cond) => cond,
+                None => continue,
+            };
+            for (k, r) in keep.iter_mut().zip(rs) {
+                if *k {
+                    *k = cond.holds(r, i);
+                }
+            }
+        }
+        keep
+    }
+
+    /// Whether `new` filters the rows of the same parent as this filter.
+    pub fn reads_same_parent(&self, new: &Filter) -> bool {
+        self.src.as_global() == new.src.as_global()
+    }
+
+    /// The records that the children of this filter need to see for it to be replaced by `new`,
+    /// given all the rows of its parent: the rows that only this filter lets through, retracted,
+    /// and the rows that only `new` lets through.
+    pub fn changes_to(&self, new: &Filter, rows: &[Vec<DataType>]) -> Records {
+        rows.iter()
+            .filter_map(|r| match (self.matches(r), new.matches(r)) {
+                (true, false) => Some(Record::Negative(r.clone())),
+                (false, true) => Some(Record::Positive(r.clone())),
+                _ => None,
+            })
+            .collect()
+    }
 }
 
 impl Ingredient for Filter {
@@ -57,6 +81,14 @@ impl Ingredient for Filter {
         Clone::clone(self).into()
     }
 
+    fn fingerprint(&self) -> Option<String> {
+        Some(format!(
+            "filter({}, {:?})",
+            self.src.as_global().index(),
+            self.filter
+        ))
+    }
+
     fn ancestors(&self) -> Vec<NodeIndex> {
         vec![self.src.as_global()]
     }
@@ -72,6 +104,10 @@ impl Ingredient for Filter {
         self.src.remap(remap);
     }
 
+    fn reparent(&mut self, parents: &HashMap<NodeIndex, IndexPair>) {
+        self.src.remap(parents);
+    }
+
     fn on_input(
         &mut self,
         _: LocalNodeIndex,
@@ -80,37 +116,14 @@ impl Ingredient for Filter {
         _: Option<&[usize]>,
         _: &DomainNodes,
         _: &StateMap,
+        _: &mut AuxStateMap,
     ) -> ProcessingResult {
-        rs.retain(|r| {
-            self.filter.iter().enumerate().all(|(i, fi)| {
-                // check if this filter matches
-                let d = &r[i];
-                if let Some(ref cond) = *fi {
-                    match *cond {
-                        FilterCondition::Comparison(ref op, ref f) => {
-                            let v = match *f {
-                                Value::Constant(ref dt) => dt,
-                                Value::Column(c) => &r[c],
-                            };
-                            match *op {
-                                Operator::Equal => d == v,
-                                Operator::NotEqual => d != v,
-                                Operator::Greater => d > v,
-                                Operator::GreaterOrEqual => d >= v,
-                                Operator::Less => d < v,
-                                Operator::LessOrEqual => d <= v,
-                                Operator::In => unreachable!(),
-                                _ => unimplemented!(),
-                            }
-                        }
-                        FilterCondition::In(ref fs) => fs.contains(d),
-                    }
-                } else {
-                    // everything matches no condition
-                    true
-                }
-            })
-        });
+        if rs.len() < BATCH_MIN {
+            rs.retain(|r| self.matches(r));
+        } else {
+            let keep = self.matching(&rs);
+            rs.retain_marked(&keep);
+        }
 
         ProcessingResult {
             results: rs,
@@ -118,6 +131,10 @@ impl Ingredient for Filter {
         }
     }
 
+    fn parallel_replay_key(&self) -> Option<Vec<usize>> {
+        Some(Vec::new())
+    }
+
     fn suggest_indexes(&self, _: NodeIndex) -> HashMap<NodeIndex, (Vec<usize>, bool)> {
         HashMap::new()
     }
@@ -182,31 +199,10 @@ impl Ingredient for Filter {
             .and_then(|result| {
                 let f = self.filter.clone();
                 let filter = move |r: &[DataType]| {
-                    r.iter().enumerate().all(|(i, d)| {
-                        // check if this filter matches
-                        if let Some(ref cond) = f[i] {
-                            match *cond {
-                                FilterCondition::Comparison(ref op, ref f) => {
-                                    let v = match *f {
-                                        Value::Constant(ref dt) => dt,
-                                        Value::Column(c) => &r[c],
-                                    };
-                                    match *op {
-                                        Operator::Equal => d == v,
-                                        Operator::NotEqual => d != v,
-                                        Operator::Greater => d > v,
-                                        Operator::GreaterOrEqual => d >= v,
-                                        Operator::Less => d < v,
-                                        Operator::LessOrEqual => d <= v,
-                                        _ => unimplemented!(),
-                                    }
-                                }
-                                FilterCondition::In(ref fs) => fs.contains(d),
-                            }
-                        } else {
-                            // everything matches no condition
-                            true
-                        }
+                    f.iter().enumerate().all(|(i, fi)| match *fi {
+                        Some(ref cond) => cond.holds(r, i),
+                        // everything matches no condition
+                        None => true,
                     })
                 };
 
@@ -224,9 +220,54 @@ impl Ingredient for Filter {
         vec![(self.src.as_global(), Some(column))]
     }
 
+    fn ordered_values(&self) -> Vec<(NodeIndex, Value)> {
+        let src = self.src.as_global();
+        let mut values = Vec::new();
+        for (i, cond) in self.filter.iter().enumerate() {
+            match *cond {
+                Some(FilterCondition::Comparison(Operator::Equal, _))
+                | Some(FilterCondition::Comparison(Operator::NotEqual, _)) => {}
+                Some(FilterCondition::Comparison(_, ref v)) => {
+                    values.push((src, Value::Column(i)));
+                    values.push((src, v.clone()));
+                }
+                _ => {}
+            }
+        }
+        values
+    }
+
+    fn referenced_columns(&self) -> Vec<(NodeIndex, usize)> {
+        let src = self.src.as_global();
+        let mut cols = Vec::new();
+        for (i, cond) in self.filter.iter().enumerate() {
+            match *cond {
+                None => {}
+                Some(FilterCondition::Comparison(_, Value::Column(c))) => {
+                    cols.push((src, i));
+                    cols.push((src, c));
+                }
+                Some(_) => cols.push((src, i)),
+            }
+        }
+        cols
+    }
+
     fn is_selective(&self) -> bool {
         true
     }
+
+    fn row_bound(&self) -> RowBound {
+        RowBound::AtMost
+    }
+
+    fn evaluate(
+        &self,
+        inputs: &HashMap<NodeIndex, Vec<Vec<DataType>>>,
+    ) -> Option<Vec<Vec<DataType>>> {
+        let rows = inputs.get(&self.src.as_global())?;
+        Some(rows.iter().filter(|r| self.matches(r)).cloned().collect())
+    }
 }
 
 #[cfg(test)]
@@ -322,6 +363,43 @@ mod tests {
         assert!(g.narrow_one_row(left.clone(), false).is_empty());
     }
 
+    #[test]
+    fn it_filters_batches() {
+        let mut g = setup(
+            false,
+            Some(&[
+                Some(FilterCondition::Comparison(
+                    Operator::GreaterOrEqual,
+                    Value::Constant(3.into()),
+                )),
+                Some(FilterCondition::Comparison(
+                    Operator::Equal,
+                    Value::Constant("a".into()),
+                )),
+            ]),
+        );
+
+        let rs: Records = (0..100)
+            .map(|i| {
+                let y = if i % 3 == 0 { "a" } else { "b" };
+                (vec![(i % 7).into(), y.into()], i % 2 == 0)
+            })
+            .collect::<Vec<_>>()
+            .into();
+        let expected: Records = rs
+            .iter()
+            .filter(|r| r[0] >= 3.into() && r[1] == "a".into())
+            .cloned()
+            .collect();
+        assert!(!expected.is_empty());
+        assert!(expected.len() < rs.len());
+
+        // a batch shared with someone else
+        assert_eq!(g.narrow_one(rs.clone(), false), expected);
+        // and one the filter can trim in place
+        assert_eq!(g.narrow_one(rs, false), expected);
+    }
+
     #[test]
     fn it_suggests_indices() {
         let g = setup(false, None);
@@ -439,4 +517,118 @@ mod tests {
         left = vec![42.into(), "b".into()];
         assert_eq!(g.narrow_one_row(left.clone(), false), vec![left].into());
     }
+
+    #[test]
+    fn it_works_with_bytes() {
+        let bytes: DataType = vec![0xca, 0xfe].into();
+        let mut g = setup(
+            false,
+            Some(&[
+                None,
+                Some(FilterCondition::Comparison(
+                    Operator::Equal,
+                    Value::Constant(bytes.clone()),
+                )),
+            ]),
+        );
+
+        let left = vec![1.into(), bytes];
+        assert_eq!(g.narrow_one_row(left.clone(), false), vec![left].into());
+
+        let left = vec![1.into(), vec![0xca].into()];
+        assert!(g.narrow_one_row(left, false).is_empty());
+    }
+
+    #[test]
+    fn it_reports_ordered_values() {
+        let g = setup(
+            false,
+            Some(&[
+                Some(FilterCondition::Comparison(
+                    Operator::Equal,
+                    Value::Constant(1.into()),
+                )),
+                Some(FilterCondition::Comparison(
+                    Operator::Less,
+                    Value::Constant(vec![0xca, 0xfe].into()),
+                )),
+            ]),
+        );
+
+        let src = g.narrow_base_id().as_global();
+        assert_eq!(
+            g.node().ordered_values(),
+            vec![
+                (src, Value::Column(1)),
+                (src, Value::Constant(vec![0xca, 0xfe].into())),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_never_matches_null() {
+        let mut g = setup(
+            false,
+            Some(&[
+                Some(FilterCondition::Comparison(
+                    Operator::NotEqual,
+                    Value::Constant(2.into()),
+                )),
+                Some(FilterCondition::Comparison(
+                    Operator::Equal,
+                    Value::Constant(DataType::None),
+                )),
+            ]),
+        );
+
+        // NULL = NULL is not true
+        let left = vec![1.into(), DataType::None];
+        assert!(g.narrow_one_row(left, false).is_empty());
+
+        // and neither is NULL != 2
+        let left = vec![DataType::None, DataType::None];
+        assert!(g.narrow_one_row(left, false).is_empty());
+
+        let mut g = setup(
+            false,
+            Some(&[
+                Some(FilterCondition::Comparison(
+                    Operator::LessOrEqual,
+                    Value::Column(1),
+                )),
+                Some(FilterCondition::In(vec![DataType::None, 1.into()])),
+            ]),
+        );
+
+        // column comparisons against NULL don't match either
+        let left = vec![DataType::None, 1.into()];
+        assert!(g.narrow_one_row(left, false).is_empty());
+
+        // NULL IN (NULL, 1) is not true
+        let left = vec![1.into(), DataType::None];
+        assert!(g.narrow_one_row(left, false).is_empty());
+
+        let left: Vec<DataType> = vec![1.into(), 1.into()];
+        assert_eq!(g.narrow_one_row(left.clone(), false), vec![left].into());
+    }
+
+    #[test]
+    fn it_computes_changes_to_new_filter() {
+        let above = |x: i32| {
+            let cond = FilterCondition::Comparison(Operator::Greater, Value::Constant(x.into()));
+            Filter::new(NodeIndex::new(0), &[Some(cond)])
+        };
+        let rows: Vec<Vec<DataType>> = (0..6).map(|x| vec![x.into()]).collect();
+
+        // rows that only the old filter lets through go away, and those only the new one does come
+        assert_eq!(
+            above(1).changes_to(&above(3), &rows[..]),
+            vec![(vec![2.into()], false), (vec![3.into()], false)].into()
+        );
+        assert_eq!(
+            above(3).changes_to(&above(1), &rows[..]),
+            vec![(vec![2.into()], true), (vec![3.into()], true)].into()
+        );
+        assert!(above(2).changes_to(&above(2), &rows[..]).is_empty());
+    }
 }
@@ -2,13 +2,22 @@ use ops::grouped::GroupedOperation;
 use ops::grouped::GroupedOperator;
 
 use prelude::*;
+use processing::expect_integer;
 
 /// Supported aggregation operators.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[allow(non_camel_case_types)]
 pub enum Aggregation {
-    /// Count the number of records for each group. The value for the `over` column is ignored.
+    /// Count the number of records for each group whose `over` column is not `NULL`.
+    ///
+    /// This matches SQL's `COUNT(col)`.
     COUNT,
-    /// Sum the value of the `over` column for all records of each group.
+    /// Count the number of records for each group, whatever their `over` column holds.
+    ///
+    /// This matches SQL's `COUNT(*)`. The `over` column is only there because every aggregation
+    /// has one, and any column that is not grouped by will do.
+    COUNT_ALL,
+    /// Sum the value of the `over` column for all records of each group. `NULL`s are skipped.
     SUM,
 }
 
@@ -73,10 +82,21 @@ impl GroupedOperation for Aggregator {
         &self.group[..]
     }
 
+    fn validate(&self, r: &[DataType]) -> Result<(), String> {
+        match self.op {
+            Aggregation::COUNT | Aggregation::COUNT_ALL if self.over < r.len() => Ok(()),
+            Aggregation::COUNT | Aggregation::COUNT_ALL => {
+                Err(format!("column {} is missing", self.over))
+            }
+            Aggregation::SUM => expect_integer(r, self.over),
+        }
+    }
+
     fn to_diff(&self, r: &[DataType], pos: bool) -> Self::Diff {
         match self.op {
-            Aggregation::COUNT if pos => 1,
-            Aggregation::COUNT => -1,
+            Aggregation::COUNT if r[self.over].is_none() => 0,
+            Aggregation::COUNT | Aggregation::COUNT_ALL if pos => 1,
+            Aggregation::COUNT | Aggregation::COUNT_ALL => -1,
             Aggregation::SUM => {
                 let v = match r[self.over] {
                     DataType::Int(n) => n as i64,
@@ -110,13 +130,14 @@ impl GroupedOperation for Aggregator {
     fn description(&self, detailed: bool) -> String {
         if !detailed {
             return String::from(match self.op {
-                Aggregation::COUNT => "+",
+                Aggregation::COUNT | Aggregation::COUNT_ALL => "+",
                 Aggregation::SUM => "𝛴",
             });
         }
 
         let op_string = match self.op {
             Aggregation::COUNT => "|*|".into(),
+            Aggregation::COUNT_ALL => "|*|(*)".into(),
             Aggregation::SUM => format!("𝛴({})", self.over),
         };
         let group_cols = self
@@ -170,6 +191,34 @@ mod tests {
         assert_eq!(s.description(true), "𝛴(1) γ[2, 0]");
     }
 
+    #[test]
+    fn it_evaluates() {
+        use std::collections::HashMap;
+
+        let c = setup(true);
+        let s = c.narrow_base_id();
+        let mut inputs = HashMap::new();
+        inputs.insert(
+            s.as_global(),
+            vec![
+                vec![1.into(), 1.into()],
+                vec![2.into(), 1.into()],
+                vec![1.into(), 2.into()],
+            ],
+        );
+
+        let mut rows = c.node().evaluate(&inputs).unwrap();
+        rows.sort();
+        assert_eq!(
+            rows,
+            vec![vec![1.into(), 2.into()], vec![2.into(), 1.into()]]
+        );
+
+        // a group whose records have all been removed may still be around with a count of zero
+        assert!(c.node().may_linger(&[3.into(), 0.into()]));
+        assert!(!c.node().may_linger(&[1.into(), 2.into()]));
+    }
+
     #[test]
     fn it_forwards() {
         let mut c = setup(true);
@@ -377,7 +426,98 @@ mod tests {
         }
     }
 
-    // TODO: also test SUM
+    #[test]
+    fn it_handles_nulls() {
+        let mut c = setup(true);
+
+        // NULL values are not counted
+        let rs = c.narrow_one_row(vec![1.into(), DataType::None], true);
+        assert_eq!(rs, vec![(vec![1.into(), 0.into()], true)].into());
+        let rs = c.narrow_one_row(vec![1.into(), 1.into()], true);
+        assert_eq!(
+            rs,
+            vec![
+                (vec![1.into(), 0.into()], false),
+                (vec![1.into(), 1.into()], true),
+            ]
+            .into()
+        );
+        let rs = c.narrow_one_row((vec![1.into(), DataType::None], false), true);
+        assert!(rs.is_empty());
+
+        // but NULL group keys all end up in the same group
+        let rs = c.narrow_one_row(vec![DataType::None, 1.into()], true);
+        assert_eq!(rs, vec![(vec![DataType::None, 1.into()], true)].into());
+        let rs = c.narrow_one_row(vec![DataType::None, 2.into()], true);
+        assert_eq!(
+            rs,
+            vec![
+                (vec![DataType::None, 1.into()], false),
+                (vec![DataType::None, 2.into()], true),
+            ]
+            .into()
+        );
+    }
+
+    #[test]
+    fn it_counts_all_rows_including_nulls() {
+        let mut g = ops::test::MockGraph::new();
+        let s = g.add_base("source", &["x", "y"]);
+        g.set_op(
+            "count",
+            &["x", "rows"],
+            Aggregation::COUNT_ALL.over(s.as_global(), 1, &[0]),
+            true,
+        );
+
+        let rs = g.narrow_one_row(vec![1.into(), DataType::None], true);
+        assert_eq!(rs, vec![(vec![1.into(), 1.into()], true)].into());
+        let rs = g.narrow_one_row(vec![1.into(), 1.into()], true);
+        assert_eq!(
+            rs,
+            vec![
+                (vec![1.into(), 1.into()], false),
+                (vec![1.into(), 2.into()], true),
+            ]
+            .into()
+        );
+        let rs = g.narrow_one_row((vec![1.into(), DataType::None], false), true);
+        assert_eq!(
+            rs,
+            vec![
+                (vec![1.into(), 2.into()], false),
+                (vec![1.into(), 1.into()], true),
+            ]
+            .into()
+        );
+        assert_eq!(g.node().description(true), "|*|(*) γ[0]");
+    }
+
+    #[test]
+    fn it_sums_skipping_nulls() {
+        let mut g = ops::test::MockGraph::new();
+        let s = g.add_base("source", &["x", "y"]);
+        g.set_op(
+            "sum",
+            &["x", "ys"],
+            Aggregation::SUM.over(s.as_global(), 1, &[0]),
+            true,
+        );
+
+        let rs = g.narrow_one_row(vec![1.into(), 2.into()], true);
+        assert_eq!(rs, vec![(vec![1.into(), 2.into()], true)].into());
+        let rs = g.narrow_one_row(vec![1.into(), DataType::None], true);
+        assert!(rs.is_empty());
+        let rs = g.narrow_one_row(vec![1.into(), 3.into()], true);
+        assert_eq!(
+            rs,
+            vec![
+                (vec![1.into(), 2.into()], false),
+                (vec![1.into(), 5.into()], true),
+            ]
+            .into()
+        );
+    }
 
     #[test]
     fn it_suggests_indices() {
@@ -402,4 +542,36 @@ mod tests {
         );
         assert_eq!(c.node().resolve(1), None);
     }
+
+    #[test]
+    fn it_takes_out_records_it_cannot_sum() {
+        use processing::take_malformed;
+
+        let mut g = ops::test::MockGraph::new();
+        let s = g.add_base("source", &["x", "y"]);
+        g.set_op(
+            "sum",
+            &["x", "ys"],
+            Aggregation::SUM.over(s.as_global(), 1, &[0]),
+            true,
+        );
+
+        let mut rs: Records = vec![
+            Record::Positive(vec![1.into(), 2.into()]),
+            Record::Positive(vec![1.into(), "two".into()]),
+            Record::Negative(vec![1.into(), "two".into()]),
+            Record::Negative(vec![1.into()]),
+            Record::Positive(vec![1.into(), DataType::None]),
+        ]
+        .into();
+        let malformed = take_malformed(&**g.node(), *s, s.as_global(), &mut rs);
+
+        // negative records are checked just like positive ones
+        assert_eq!(malformed.len(), 3);
+        assert!(malformed[0].record.is_positive());
+        assert!(!malformed[1].record.is_positive());
+        assert_eq!(malformed[2].reason, "column 1 is missing");
+        assert!(malformed.iter().all(|m| m.from == *s));
+        assert_eq!(rs.len(), 2);
+    }
 }
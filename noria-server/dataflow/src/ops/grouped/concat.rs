@@ -98,6 +98,7 @@ impl GroupConcat {
                     DataType::BigInt(ref n) => s.push_str(&n.to_string()),
                     DataType::Real(..) => s.push_str(&rec[*i].to_string()),
                     DataType::Timestamp(ref ts) => s.push_str(&ts.format("%+").to_string()),
+                    DataType::Bytes(..) => s.push_str(&rec[*i].to_string()),
                     DataType::None => unreachable!(),
                 },
             }
@@ -140,6 +141,21 @@ impl GroupedOperation for GroupConcat {
         &self.group[..]
     }
 
+    fn validate(&self, r: &[DataType]) -> Result<(), String> {
+        for tc in &self.components {
+            if let TextComponent::Column(i) = *tc {
+                match r.get(i) {
+                    Some(&DataType::None) => {
+                        return Err(format!("column {} is NULL, which can't be concatenated", i))
+                    }
+                    Some(_) => {}
+                    None => return Err(format!("column {} is missing", i)),
+                }
+            }
+        }
+        Ok(())
+    }
+
     fn to_diff(&self, r: &[DataType], pos: bool) -> Self::Diff {
         let v = self.build(r);
         if pos {
@@ -2,6 +2,7 @@ use ops::grouped::GroupedOperation;
 use ops::grouped::GroupedOperator;
 
 use prelude::*;
+use processing::expect_integer;
 
 /// Supported kinds of extremum operators.
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
@@ -61,6 +62,8 @@ pub struct ExtremumOperator {
 pub enum DiffType {
     Insert(i64),
     Remove(i64),
+    /// A `NULL` value, which never affects the extremum.
+    Null,
 }
 
 impl GroupedOperation for ExtremumOperator {
@@ -77,10 +80,15 @@ impl GroupedOperation for ExtremumOperator {
         &self.group[..]
     }
 
+    fn validate(&self, r: &[DataType]) -> Result<(), String> {
+        expect_integer(r, self.over)
+    }
+
     fn to_diff(&self, r: &[DataType], pos: bool) -> Self::Diff {
         let v = match r[self.over] {
             DataType::Int(n) => n as i64,
             DataType::BigInt(n) => n,
+            DataType::None => return DiffType::Null,
             _ => {
                 // the column we're aggregating over is non-numerical (or rather, this value is).
                 // if you've removed a column, chances are the  default value has the wrong type.
@@ -109,22 +117,19 @@ impl GroupedOperation for ExtremumOperator {
     ) -> DataType {
         // Extreme values are those that are at least as extreme as the current min/max (if any).
         // let mut is_extreme_value : Box<Fn(i64) -> bool> = Box::new(|_|true);
-        let mut extreme_values: Vec<i64> = vec![];
-        if let Some(data) = current {
-            match *data {
-                DataType::Int(n) => extreme_values.push(n as i64),
-                DataType::BigInt(n) => extreme_values.push(n),
-                _ => unreachable!(),
-            }
+        //
+        // A current value of NULL means that the group has only seen NULLs so far, and is treated
+        // the same as there being no current value.
+        let current = match current {
+            Some(DataType::Int(n)) => Some(*n as i64),
+            Some(DataType::BigInt(n)) => Some(*n),
+            Some(DataType::None) | None => None,
+            _ => unreachable!(),
         };
+        let mut extreme_values: Vec<i64> = current.into_iter().collect();
 
         let is_extreme_value = |x: i64| {
-            if let Some(data) = current {
-                let n = match *data {
-                    DataType::Int(n) => n as i64,
-                    DataType::BigInt(n) => n,
-                    _ => unreachable!(),
-                };
+            if let Some(n) = current {
                 match self.op {
                     Extremum::MAX => x >= n,
                     Extremum::MIN => x <= n,
@@ -155,6 +160,11 @@ impl GroupedOperation for ExtremumOperator {
             return extreme.into();
         }
 
+        if current.is_none() {
+            // the group has only ever seen NULLs
+            return DataType::None;
+        }
+
         // TODO: handle this case by querying into the parent.
         unimplemented!();
     }
@@ -312,6 +322,30 @@ mod tests {
         assert!(out.is_empty());
     }
 
+    #[test]
+    fn it_skips_nulls() {
+        let mut c = setup(Extremum::MAX, true);
+
+        // a group with only NULLs has a NULL extremum
+        let rs = c.narrow_one_row(vec![1.into(), DataType::None], true);
+        assert_eq!(rs, vec![(vec![1.into(), DataType::None], true)].into());
+
+        // which is replaced as soon as a real value arrives
+        let rs = c.narrow_one_row(vec![1.into(), 3.into()], true);
+        assert_eq!(
+            rs,
+            vec![
+                (vec![1.into(), DataType::None], false),
+                (vec![1.into(), 3.into()], true),
+            ]
+            .into()
+        );
+
+        // further NULLs don't affect it
+        let rs = c.narrow_one_row(vec![1.into(), DataType::None], true);
+        assert!(rs.is_empty());
+    }
+
     #[test]
     fn it_suggests_indices() {
         let me = 1.into();
@@ -47,6 +47,12 @@ pub trait GroupedOperation: fmt::Debug + Clone {
     /// All records with the same value for the returned columns are assigned to the same group.
     fn group_by(&self) -> &[usize];
 
+    /// Check that a record holds values that `to_diff` can extract the aggregation value from. Any
+    /// column the operation reads beyond those it groups by may be missing from the record.
+    fn validate(&self, _record: &[DataType]) -> Result<(), String> {
+        Ok(())
+    }
+
     /// Extract the aggregation value from a single record.
     fn to_diff(&self, record: &[DataType], is_positive: bool) -> Self::Diff;
 
@@ -99,6 +105,14 @@ where
         Clone::clone(self).into()
     }
 
+    fn fingerprint(&self) -> Option<String> {
+        Some(format!(
+            "{:?}({})",
+            self.inner,
+            self.src.as_global().index()
+        ))
+    }
+
     fn ancestors(&self) -> Vec<NodeIndex> {
         vec![self.src.as_global()]
     }
@@ -141,6 +155,14 @@ where
         self.us = Some(remap[&us]);
     }
 
+    fn reparent(&mut self, parents: &HashMap<NodeIndex, IndexPair>) {
+        self.src.remap(parents);
+    }
+
+    fn validate(&self, _: LocalNodeIndex, record: &[DataType]) -> Result<(), String> {
+        self.inner.validate(record)
+    }
+
     fn on_input(
         &mut self,
         from: LocalNodeIndex,
@@ -149,6 +171,7 @@ where
         replay_key_cols: Option<&[usize]>,
         _: &DomainNodes,
         state: &StateMap,
+        _: &mut AuxStateMap,
     ) -> ProcessingResult {
         debug_assert_eq!(from, *self.src);
 
@@ -272,6 +295,11 @@ where
         }
     }
 
+    fn parallel_replay_key(&self) -> Option<Vec<usize>> {
+        // each group's output only depends on the group's records and its current value
+        Some(self.group_by.clone())
+    }
+
     fn suggest_indexes(&self, this: NodeIndex) -> HashMap<NodeIndex, (Vec<usize>, bool)> {
         // index by our primary key
         Some((this, (self.out_key.clone(), true)))
@@ -297,7 +325,46 @@ where
         vec![(self.src.as_global(), Some(self.colfix[column]))]
     }
 
+    fn referenced_columns(&self) -> Vec<(NodeIndex, usize)> {
+        // the column that is aggregated over is checked by the inner operation's `setup`
+        let src = self.src.as_global();
+        self.inner.group_by().iter().map(|&c| (src, c)).collect()
+    }
+
     fn is_selective(&self) -> bool {
         true
     }
+
+    fn row_bound(&self) -> RowBound {
+        RowBound::AtMostSome
+    }
+
+    fn evaluate(
+        &self,
+        inputs: &HashMap<NodeIndex, Vec<Vec<DataType>>>,
+    ) -> Option<Vec<Vec<DataType>>> {
+        let rows = inputs.get(&self.src.as_global())?;
+        let mut groups: HashMap<Vec<DataType>, Vec<&Vec<DataType>>> = HashMap::new();
+        for r in rows {
+            let group = self.group_by.iter().map(|&c| r[c].clone()).collect();
+            groups.entry(group).or_default().push(r);
+        }
+
+        Some(
+            groups
+                .into_iter()
+                .map(|(mut group, rs)| {
+                    let mut diffs = rs.into_iter().map(|r| self.inner.to_diff(r, true));
+                    group.push(self.inner.apply(None, &mut diffs));
+                    group
+                })
+                .collect(),
+        )
+    }
+
+    fn may_linger(&self, row: &[DataType]) -> bool {
+        // a group whose records have all been removed is left with the value of an empty group
+        let empty = self.inner.apply(None, &mut ::std::iter::empty::<T::Diff>());
+        row.last() == Some(&empty)
+    }
 }
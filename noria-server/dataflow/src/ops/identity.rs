@@ -21,6 +21,10 @@ impl Ingredient for Identity {
         Clone::clone(self).into()
     }
 
+    fn fingerprint(&self) -> Option<String> {
+        Some(format!("identity({})", self.src.as_global().index()))
+    }
+
     fn ancestors(&self) -> Vec<NodeIndex> {
         vec![self.src.as_global()]
     }
@@ -31,6 +35,10 @@ impl Ingredient for Identity {
         self.src.remap(remap);
     }
 
+    fn reparent(&mut self, parents: &HashMap<NodeIndex, IndexPair>) {
+        self.src.remap(parents);
+    }
+
     fn on_input(
         &mut self,
         _: LocalNodeIndex,
@@ -39,6 +47,7 @@ impl Ingredient for Identity {
         _: Option<&[usize]>,
         _: &DomainNodes,
         _: &StateMap,
+        _: &mut AuxStateMap,
     ) -> ProcessingResult {
         ProcessingResult {
             results: rs,
@@ -61,6 +70,22 @@ impl Ingredient for Identity {
     fn parent_columns(&self, column: usize) -> Vec<(NodeIndex, Option<usize>)> {
         vec![(self.src.as_global(), Some(column))]
     }
+
+    fn referenced_columns(&self) -> Vec<(NodeIndex, usize)> {
+        // emits whatever its parent has
+        vec![]
+    }
+
+    fn row_bound(&self) -> RowBound {
+        RowBound::Same
+    }
+
+    fn evaluate(
+        &self,
+        inputs: &HashMap<NodeIndex, Vec<Vec<DataType>>>,
+    ) -> Option<Vec<Vec<DataType>>> {
+        inputs.get(&self.src.as_global()).cloned()
+    }
 }
 
 #[cfg(test)]
@@ -1,5 +1,7 @@
+use std::cmp;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::collections::VecDeque;
 use std::mem;
 
 use prelude::*;
@@ -24,6 +26,10 @@ pub enum JoinSource {
     B(usize, usize),
 }
 
+/// By default, a key whose join output would exceed this many records has its output produced
+/// piece by piece instead.
+pub const SPILL_THRESHOLD: usize = 10_000;
+
 /// Join provides a left outer join between two views.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Join {
@@ -43,6 +49,28 @@ pub struct Join {
     in_place_right_emit: Vec<(bool, usize)>,
 
     kind: JoinType,
+
+    // Keys in a regular update that would produce more output records than this have their output
+    // held back in `spilled`, and produced in pieces of about this size through `next_spilled`.
+    spill_threshold: usize,
+    #[serde(skip)]
+    spilled: VecDeque<Spill>,
+    #[serde(skip)]
+    spills: u64,
+}
+
+/// The input records for a single join key whose output has not been produced yet.
+#[derive(Debug, Clone)]
+struct Spill {
+    key: DataType,
+    from_left: bool,
+    rows: VecDeque<Record>,
+    make_null: Option<bool>,
+
+    // the first input record for a key also emits the +/- NULL rows, if there are any
+    first: bool,
+    // number of records on the other side that the front of `rows` has already been joined with
+    done: usize,
 }
 
 enum Preprocessed {
@@ -121,9 +149,24 @@ impl Join {
             in_place_left_emit,
             in_place_right_emit,
             kind: kind,
+            spill_threshold: SPILL_THRESHOLD,
+            spilled: VecDeque::new(),
+            spills: 0,
         }
     }
 
+    /// Builder with a different spill threshold.
+    ///
+    /// When a regular update would make this join produce more than `records` output records for
+    /// a single join key, the output for that key is produced in pieces of at most about
+    /// `records` records, each of which is sent downstream as a separate update. Replays are
+    /// never split up like this.
+    pub fn with_spill_threshold(mut self, records: usize) -> Self {
+        assert!(records > 0);
+        self.spill_threshold = records;
+        self
+    }
+
     fn generate_row(
         &self,
         left: &[DataType],
@@ -194,47 +237,17 @@ impl Join {
             })
             .collect()
     }
-}
-
-impl Ingredient for Join {
-    fn take(&mut self) -> NodeOperator {
-        Clone::clone(self).into()
-    }
-
-    fn ancestors(&self) -> Vec<NodeIndex> {
-        vec![self.left.as_global(), self.right.as_global()]
-    }
-
-    fn is_join(&self) -> bool {
-        true
-    }
-
-    fn must_replay_among(&self) -> Option<HashSet<NodeIndex>> {
-        match self.kind {
-            JoinType::Left => Some(Some(self.left.as_global()).into_iter().collect()),
-            JoinType::Inner => Some(
-                vec![self.left.as_global(), self.right.as_global()]
-                    .into_iter()
-                    .collect(),
-            ),
-        }
-    }
-
-    fn on_connected(&mut self, _g: &Graph) {}
 
-    fn on_commit(&mut self, _: NodeIndex, remap: &HashMap<NodeIndex, IndexPair>) {
-        self.left.remap(remap);
-        self.right.remap(remap);
-    }
-
-    fn on_input(
+    // Join the given records from one parent with the other parent. If `spill` is set, the output
+    // for keys that would produce too many records is held back instead (see `next_spilled`).
+    fn join(
         &mut self,
         from: LocalNodeIndex,
         rs: Records,
-        _: &mut Tracer,
         replay_key_cols: Option<&[usize]>,
         nodes: &DomainNodes,
         state: &StateMap,
+        spill: bool,
     ) -> ProcessingResult {
         let mut misses = Vec::new();
 
@@ -292,6 +305,21 @@ impl Ingredient for Join {
             let mut new_right_count = None;
             let prev_join_key = rs[at][from_key].clone();
 
+            if prev_join_key.is_none() {
+                // NULL never matches anything (not even another NULL), so there's no need to look
+                // at the other side at all. lefts with a NULL key always get NULL padding in a
+                // left join, and rights with a NULL key can never affect the output.
+                while at != rs.len() && rs[at][from_key].is_none() {
+                    if self.kind == JoinType::Left && from == *self.left {
+                        let r = mem::replace(&mut rs[at], Record::Positive(Vec::new()));
+                        let (row, positive) = r.extract();
+                        ret.push((self.generate_null(&row), positive).into());
+                    }
+                    at += 1;
+                }
+                continue;
+            }
+
             if from == *self.right && self.kind == JoinType::Left {
                 let rc = self
                     .lookup(
@@ -410,6 +438,24 @@ impl Ingredient for Join {
                     .unwrap_or(rs.len());
             }
 
+            let matches = other_rows.as_ref().unwrap().size_hint().0;
+            if spill && matches.saturating_mul(at - start) > self.spill_threshold {
+                // producing all the output for this key at once would make for a huge update.
+                // hold on to the input records, and produce the output bit by bit later.
+                self.spilled.push_back(Spill {
+                    key: prev_join_key,
+                    from_left: from == *self.left,
+                    rows: (start..at)
+                        .map(|ri| mem::replace(&mut rs[ri], Record::Positive(Vec::new())))
+                        .collect(),
+                    make_null,
+                    first: true,
+                    done: 0,
+                });
+                self.spills += 1;
+                continue;
+            }
+
             let mut other_rows_count = 0;
             for ri in start..at {
                 use std::mem;
@@ -522,6 +568,160 @@ impl Ingredient for Join {
             misses: misses,
         }
     }
+}
+
+impl Ingredient for Join {
+    fn take(&mut self) -> NodeOperator {
+        Clone::clone(self).into()
+    }
+
+    fn fingerprint(&self) -> Option<String> {
+        Some(format!(
+            "join({}, {}, {:?}, {:?}, {:?})",
+            self.left.as_global().index(),
+            self.right.as_global().index(),
+            self.kind,
+            self.on,
+            self.emit
+        ))
+    }
+
+    fn ancestors(&self) -> Vec<NodeIndex> {
+        vec![self.left.as_global(), self.right.as_global()]
+    }
+
+    fn is_join(&self) -> bool {
+        true
+    }
+
+    fn must_replay_among(&self) -> Option<HashSet<NodeIndex>> {
+        match self.kind {
+            JoinType::Left => Some(Some(self.left.as_global()).into_iter().collect()),
+            JoinType::Inner => Some(
+                vec![self.left.as_global(), self.right.as_global()]
+                    .into_iter()
+                    .collect(),
+            ),
+        }
+    }
+
+    fn on_connected(&mut self, _g: &Graph) {}
+
+    fn on_commit(&mut self, _: NodeIndex, remap: &HashMap<NodeIndex, IndexPair>) {
+        self.left.remap(remap);
+        self.right.remap(remap);
+    }
+
+    fn reparent(&mut self, parents: &HashMap<NodeIndex, IndexPair>) {
+        self.left.remap(parents);
+        self.right.remap(parents);
+    }
+
+    fn on_input(
+        &mut self,
+        from: LocalNodeIndex,
+        rs: Records,
+        _: &mut Tracer,
+        replay_key_cols: Option<&[usize]>,
+        nodes: &DomainNodes,
+        state: &StateMap,
+        _: &mut AuxStateMap,
+    ) -> ProcessingResult {
+        self.join(from, rs, replay_key_cols, nodes, state, false)
+    }
+
+    fn on_input_raw(
+        &mut self,
+        from: LocalNodeIndex,
+        rs: Records,
+        _: &mut Tracer,
+        replay: &ReplayContext,
+        nodes: &DomainNodes,
+        state: &StateMap,
+        _: &mut AuxStateMap,
+    ) -> RawProcessingResult {
+        // the output of a replay must be produced all at once, so only regular updates spill
+        let spill = if let ReplayContext::None = *replay {
+            true
+        } else {
+            false
+        };
+        RawProcessingResult::Regular(self.join(from, rs, replay.key(), nodes, state, spill))
+    }
+
+    fn next_spilled(&mut self, nodes: &DomainNodes, state: &StateMap) -> Option<Records> {
+        let mut spill = self.spilled.pop_front()?;
+        let (other, other_key) = if spill.from_left {
+            (*self.right, self.on.1)
+        } else {
+            (*self.left, self.on.0)
+        };
+
+        let mut ret: Vec<Record> = Vec::with_capacity(self.spill_threshold + 2);
+        while ret.len() < self.spill_threshold && !spill.rows.is_empty() {
+            // nothing else happens in the domain until all the spilled output has been produced,
+            // so the other side still holds the same rows, in the same order, as when we spilled.
+            let mut other_rows = self
+                .lookup(
+                    other,
+                    &[other_key],
+                    &KeyType::Single(&spill.key),
+                    nodes,
+                    state,
+                )
+                .unwrap()
+                .expect("spilled join key went missing in the other side");
+            if spill.done != 0 {
+                other_rows.nth(spill.done - 1);
+            }
+
+            let exhausted = {
+                let r = &spill.rows[0];
+                let positive = r.is_positive();
+                let mut exhausted = true;
+                for other in other_rows {
+                    if ret.len() >= self.spill_threshold {
+                        exhausted = false;
+                        break;
+                    }
+
+                    if spill.first && spill.make_null == Some(false) {
+                        ret.push((self.generate_null(&other), false).into());
+                    }
+                    let row = if spill.from_left {
+                        self.generate_row(&r[..], &other, Preprocessed::Neither)
+                    } else {
+                        self.generate_row(&other, &r[..], Preprocessed::Neither)
+                    };
+                    ret.push((row, positive).into());
+                    if spill.first && spill.make_null == Some(true) {
+                        ret.push((self.generate_null(&other), true).into());
+                    }
+                    spill.done += 1;
+                }
+                exhausted
+            };
+
+            if exhausted {
+                spill.rows.pop_front();
+                spill.first = false;
+                spill.done = 0;
+            }
+        }
+
+        if !spill.rows.is_empty() {
+            self.spilled.push_front(spill);
+        }
+        Some(ret.into())
+    }
+
+    fn spilled_keys(&self) -> u64 {
+        self.spills
+    }
+
+    fn limit_output(&mut self, records: usize) {
+        self.spill_threshold = cmp::min(self.spill_threshold, records);
+    }
 
     fn suggest_indexes(&self, _this: NodeIndex) -> HashMap<NodeIndex, (Vec<usize>, bool)> {
         vec![
@@ -590,6 +790,46 @@ impl Ingredient for Join {
             )]
         }
     }
+
+    fn evaluate(
+        &self,
+        inputs: &HashMap<NodeIndex, Vec<Vec<DataType>>>,
+    ) -> Option<Vec<Vec<DataType>>> {
+        let left = inputs.get(&self.left.as_global())?;
+        let right = inputs.get(&self.right.as_global())?;
+
+        let mut by_key: HashMap<&DataType, Vec<&Vec<DataType>>> = HashMap::new();
+        for r in right {
+            if !r[self.on.1].is_none() {
+                by_key.entry(&r[self.on.1]).or_default().push(r);
+            }
+        }
+
+        let mut rows = Vec::new();
+        for l in left {
+            // NULL never matches anything, not even another NULL
+            match by_key.get(&l[self.on.0]) {
+                Some(rs) if !l[self.on.0].is_none() => rows.extend(
+                    rs.iter()
+                        .map(|r| self.generate_row(l, r, Preprocessed::Neither)),
+                ),
+                _ if self.kind == JoinType::Left => rows.push(self.generate_null(l)),
+                _ => {}
+            }
+        }
+        Some(rows)
+    }
+
+    fn referenced_columns(&self) -> Vec<(NodeIndex, usize)> {
+        let (left, right) = (self.left.as_global(), self.right.as_global());
+        let mut cols = vec![(left, self.on.0), (right, self.on.1)];
+        cols.extend(
+            self.emit
+                .iter()
+                .map(|&(from_left, c)| (if from_left { left } else { right }, c)),
+        );
+        cols
+    }
 }
 
 #[cfg(test)]
@@ -599,6 +839,12 @@ mod tests {
     use ops;
 
     fn setup() -> (ops::test::MockGraph, IndexPair, IndexPair) {
+        setup_with_spill_threshold(SPILL_THRESHOLD)
+    }
+
+    fn setup_with_spill_threshold(
+        threshold: usize,
+    ) -> (ops::test::MockGraph, IndexPair, IndexPair) {
         let mut g = ops::test::MockGraph::new();
         let l = g.add_base("left", &["l0", "l1"]);
         let r = g.add_base("right", &["r0", "r1"]);
@@ -609,7 +855,8 @@ mod tests {
             r.as_global(),
             JoinType::Left,
             vec![B(0, 0), L(1), R(1)],
-        );
+        )
+        .with_spill_threshold(threshold);
 
         g.set_op("join", &["j0", "j1", "j2"], j, false);
         (g, l, r)
@@ -624,6 +871,44 @@ mod tests {
         );
     }
 
+    #[test]
+    fn it_evaluates() {
+        let (j, l, r) = setup();
+        let mut inputs = HashMap::new();
+        inputs.insert(
+            l.as_global(),
+            vec![
+                vec![1.into(), "a".into()],
+                vec![2.into(), "b".into()],
+                vec![DataType::None, "c".into()],
+            ],
+        );
+        inputs.insert(
+            r.as_global(),
+            vec![
+                vec![1.into(), "x".into()],
+                vec![1.into(), "y".into()],
+                vec![DataType::None, "z".into()],
+            ],
+        );
+
+        // NULLs don't join with each other, so the left row with a NULL key is padded
+        let mut rows = j.node().evaluate(&inputs).unwrap();
+        rows.sort();
+        let mut expected: Vec<Vec<DataType>> = vec![
+            vec![1.into(), "a".into(), "x".into()],
+            vec![1.into(), "a".into(), "y".into()],
+            vec![2.into(), "b".into(), DataType::None],
+            vec![DataType::None, "c".into(), DataType::None],
+        ];
+        expected.sort();
+        assert_eq!(rows, expected);
+
+        // nothing can be computed without both sides
+        inputs.remove(&r.as_global());
+        assert_eq!(j.node().evaluate(&inputs), None);
+    }
+
     #[test]
     fn it_works() {
         let (mut j, l, r) = setup();
@@ -727,6 +1012,119 @@ mod tests {
         assert_eq!(rs.len(), 0);
     }
 
+    #[test]
+    fn it_spills_keys_with_many_matches() {
+        // applies each update to its base, and then sends it through the join
+        fn run(
+            g: &mut ops::test::MockGraph,
+            l: IndexPair,
+            r: IndexPair,
+            spill: bool,
+        ) -> Vec<Vec<Records>> {
+            let lefts = (0..5)
+                .map(|i| (vec![1.into(), i.into()], true))
+                .chain(Some((vec![2.into(), 5.into()], true)))
+                .collect::<Vec<_>>();
+            let updates: Vec<(IndexPair, Vec<(Vec<DataType>, bool)>)> = vec![
+                (l, lefts),
+                // revokes the NULLs for all the lefts with key 1
+                (
+                    r,
+                    vec![
+                        (vec![1.into(), "x".into()], true),
+                        (vec![2.into(), "z".into()], true),
+                    ],
+                ),
+                (r, vec![(vec![1.into(), "y".into()], true)]),
+                (
+                    l,
+                    vec![
+                        (vec![1.into(), 7.into()], true),
+                        (vec![1.into(), 8.into()], true),
+                    ],
+                ),
+                // brings the NULLs back
+                (
+                    r,
+                    vec![
+                        (vec![1.into(), "x".into()], false),
+                        (vec![1.into(), "y".into()], false),
+                    ],
+                ),
+            ];
+
+            updates
+                .into_iter()
+                .map(|(src, rs)| {
+                    let rs: Records = rs.into();
+                    g.states
+                        .get_mut(*src)
+                        .unwrap()
+                        .process_records(&mut rs.clone(), None);
+                    if spill {
+                        g.one_spilling(src, rs)
+                    } else {
+                        vec![g.one(src, rs, false)]
+                    }
+                })
+                .collect()
+        }
+
+        let (mut whole, l, r) = setup();
+        let whole = run(&mut whole, l, r, false);
+        let (mut spilling, l, r) = setup_with_spill_threshold(3);
+        let spilled = run(&mut spilling, l, r, true);
+
+        // every key with more than three output records was spilled
+        assert_eq!(spilling.node().spilled_keys(), 4);
+        assert_eq!(spilled.iter().filter(|pieces| pieces.len() > 1).count(), 4);
+
+        for (whole, pieces) in whole.into_iter().zip(spilled) {
+            // a piece may overshoot the threshold by the NULLs of the last record in it
+            assert!(pieces.iter().all(|rs| rs.len() <= 3 + 2));
+
+            let mut whole: Vec<_> = whole.into_iter().map(|r| r.extract()).collect();
+            let mut pieces: Vec<_> = pieces
+                .into_iter()
+                .flat_map(|rs| rs.into_iter())
+                .map(|r| r.extract())
+                .collect();
+            whole.sort();
+            pieces.sort();
+            assert_eq!(pieces, whole);
+        }
+    }
+
+    #[test]
+    fn it_never_joins_on_null() {
+        let (mut j, l, r) = setup();
+        let l_null = vec![DataType::None, "a".into()];
+        let r_null = vec![DataType::None, "x".into()];
+
+        j.seed(r, r_null.clone());
+        j.one_row(r, r_null.clone(), false);
+
+        // a NULL key on the left must not match the NULL key on the right, so we get NULL padding
+        j.seed(l, l_null.clone());
+        let rs = j.one_row(l, l_null.clone(), false);
+        assert_eq!(
+            rs,
+            vec![(vec![DataType::None, "a".into(), DataType::None], true)].into()
+        );
+
+        // and a NULL key arriving from the right must not revoke that padding
+        j.seed(r, r_null.clone());
+        let rs = j.one_row(r, r_null.clone(), false);
+        assert!(rs.is_empty());
+
+        // retracting the left row retracts its padded row
+        let rs = j.one_row(l, (l_null.clone(), false), false);
+        assert_eq!(
+            rs,
+            vec![(vec![DataType::None, "a".into(), DataType::None], false)].into()
+        );
+    }
+
     #[test]
     fn it_suggests_indices() {
         use std::collections::HashMap;
@@ -748,4 +1146,20 @@ mod tests {
         assert_eq!(g.node().resolve(1), Some(vec![(l.as_global(), 1)]));
         assert_eq!(g.node().resolve(2), Some(vec![(r.as_global(), 1)]));
     }
+
+    #[test]
+    fn it_references_columns() {
+        let (g, l, r) = setup();
+        let mut cols = g.node().referenced_columns();
+        cols.sort();
+        cols.dedup();
+        let mut expected = vec![
+            (l.as_global(), 0),
+            (l.as_global(), 1),
+            (r.as_global(), 0),
+            (r.as_global(), 1),
+        ];
+        expected.sort();
+        assert_eq!(cols, expected);
+    }
 }
@@ -33,6 +33,14 @@ impl Ingredient for Latest {
         Clone::clone(self).into()
     }
 
+    fn fingerprint(&self) -> Option<String> {
+        Some(format!(
+            "latest({}, {})",
+            self.src.as_global().index(),
+            self.key
+        ))
+    }
+
     fn ancestors(&self) -> Vec<NodeIndex> {
         vec![self.src.as_global()]
     }
@@ -44,6 +52,10 @@ impl Ingredient for Latest {
         self.us = Some(remap[&us]);
     }
 
+    fn reparent(&mut self, parents: &HashMap<NodeIndex, IndexPair>) {
+        self.src.remap(parents);
+    }
+
     fn on_input(
         &mut self,
         from: LocalNodeIndex,
@@ -52,6 +64,7 @@ impl Ingredient for Latest {
         replay_key_cols: Option<&[usize]>,
         _: &DomainNodes,
         state: &StateMap,
+        _: &mut AuxStateMap,
     ) -> ProcessingResult {
         debug_assert_eq!(from, *self.src);
 
@@ -132,6 +145,14 @@ impl Ingredient for Latest {
     fn parent_columns(&self, column: usize) -> Vec<(NodeIndex, Option<usize>)> {
         vec![(self.src.as_global(), Some(column))]
     }
+
+    fn referenced_columns(&self) -> Vec<(NodeIndex, usize)> {
+        vec![(self.src.as_global(), self.key)]
+    }
+
+    fn row_bound(&self) -> RowBound {
+        RowBound::AtMostSome
+    }
 }
 
 #[cfg(test)]
@@ -1,6 +1,8 @@
 use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
 
+use noria::filter::Value;
+use noria::ColumnType;
 use prelude::*;
 
 pub mod distinct;
@@ -105,6 +107,55 @@ macro_rules! impl_ingredient_fn_ref {
     }
 }
 
+impl NodeOperator {
+    /// Check whether this operator can be replaced by `new` in place, without rebuilding the
+    /// state downstream of it, and if so, how many columns `new` adds to its output.
+    ///
+    /// Only filters, and projections that only add columns, can be replaced this way, and only by
+    /// an operator of the same kind that reads from the same parent.
+    pub fn check_modify(&self, new: &NodeOperator) -> Result<usize, String> {
+        match (self, new) {
+            (&NodeOperator::Filter(ref old), &NodeOperator::Filter(ref new)) => {
+                if old.reads_same_parent(new) {
+                    Ok(0)
+                } else {
+                    Err("a filter can only be replaced by one with the same parent".to_owned())
+                }
+            }
+            (&NodeOperator::Project(ref old), &NodeOperator::Project(ref new)) => {
+                old.added_columns(new).ok_or_else(|| {
+                    "a projection can only be replaced by one with the same parent that emits the \
+                     same columns, followed by new ones"
+                        .to_owned()
+                })
+            }
+            (&NodeOperator::Filter(_), _) | (&NodeOperator::Project(_), _) => Err(format!(
+                "{} can't be replaced by an operator of a different kind ({})",
+                self.description(false),
+                new.description(false)
+            )),
+            _ => Err(format!(
+                "{} can't be modified in place; only filters and projections can",
+                self.description(false)
+            )),
+        }
+    }
+
+    /// The records that the children of this operator need to see for it to be replaced by `new`,
+    /// given all the rows of its parent. `new` must have passed `check_modify`.
+    pub fn changes_to(&self, new: &NodeOperator, rows: &[Vec<DataType>]) -> Records {
+        match (self, new) {
+            (&NodeOperator::Filter(ref old), &NodeOperator::Filter(ref new)) => {
+                old.changes_to(new, rows)
+            }
+            (&NodeOperator::Project(ref old), &NodeOperator::Project(ref new)) => {
+                old.changes_to(new, rows)
+            }
+            _ => unreachable!("operator can't be modified in place"),
+        }
+    }
+}
+
 impl Ingredient for NodeOperator {
     fn take(&mut self) -> NodeOperator {
         impl_ingredient_fn_mut!(self, take,)
@@ -133,6 +184,9 @@ impl Ingredient for NodeOperator {
     fn on_commit(&mut self, you: NodeIndex, remap: &HashMap<NodeIndex, IndexPair>) {
         impl_ingredient_fn_mut!(self, on_commit, you, remap)
     }
+    fn reparent(&mut self, parents: &HashMap<NodeIndex, IndexPair>) {
+        impl_ingredient_fn_mut!(self, reparent, parents)
+    }
     fn on_input(
         &mut self,
         from: LocalNodeIndex,
@@ -141,6 +195,7 @@ impl Ingredient for NodeOperator {
         replay_key_col: Option<&[usize]>,
         domain: &DomainNodes,
         states: &StateMap,
+        aux: &mut AuxStateMap,
     ) -> ProcessingResult {
         impl_ingredient_fn_mut!(
             self,
@@ -150,7 +205,8 @@ impl Ingredient for NodeOperator {
             tracer,
             replay_key_col,
             domain,
-            states
+            states,
+            aux
         )
     }
     fn on_input_raw(
@@ -161,6 +217,7 @@ impl Ingredient for NodeOperator {
         replay: &ReplayContext,
         domain: &DomainNodes,
         states: &StateMap,
+        aux: &mut AuxStateMap,
     ) -> RawProcessingResult {
         impl_ingredient_fn_mut!(
             self,
@@ -170,9 +227,37 @@ impl Ingredient for NodeOperator {
             tracer,
             replay,
             domain,
-            states
+            states,
+            aux
         )
     }
+    fn validate(&self, from: LocalNodeIndex, record: &[DataType]) -> Result<(), String> {
+        impl_ingredient_fn_ref!(self, validate, from, record)
+    }
+    fn next_spilled(&mut self, domain: &DomainNodes, states: &StateMap) -> Option<Records> {
+        impl_ingredient_fn_mut!(self, next_spilled, domain, states)
+    }
+    fn spilled_keys(&self) -> u64 {
+        impl_ingredient_fn_ref!(self, spilled_keys,)
+    }
+    fn limit_output(&mut self, records: usize) {
+        impl_ingredient_fn_mut!(self, limit_output, records)
+    }
+    fn parallel_replay_key(&self) -> Option<Vec<usize>> {
+        impl_ingredient_fn_ref!(self, parallel_replay_key,)
+    }
+    fn take_timers(&mut self) -> Vec<TimerRequest> {
+        impl_ingredient_fn_mut!(self, take_timers,)
+    }
+    fn on_timer(&mut self, token: u64, domain: &DomainNodes, states: &StateMap) -> Option<Records> {
+        impl_ingredient_fn_mut!(self, on_timer, token, domain, states)
+    }
+    fn uses_aux_state(&self) -> bool {
+        impl_ingredient_fn_ref!(self, uses_aux_state,)
+    }
+    fn rebuild_aux(&self, from_state: &State) -> Option<AuxState> {
+        impl_ingredient_fn_ref!(self, rebuild_aux, from_state)
+    }
     fn on_eviction(
         &mut self,
         from: LocalNodeIndex,
@@ -206,12 +291,39 @@ impl Ingredient for NodeOperator {
     fn parent_columns(&self, column: usize) -> Vec<(NodeIndex, Option<usize>)> {
         impl_ingredient_fn_ref!(self, parent_columns, column)
     }
+    fn row_bound(&self) -> RowBound {
+        impl_ingredient_fn_ref!(self, row_bound,)
+    }
+    fn evaluate(
+        &self,
+        inputs: &HashMap<NodeIndex, Vec<Vec<DataType>>>,
+    ) -> Option<Vec<Vec<DataType>>> {
+        impl_ingredient_fn_ref!(self, evaluate, inputs)
+    }
+    fn may_linger(&self, row: &[DataType]) -> bool {
+        impl_ingredient_fn_ref!(self, may_linger, row)
+    }
     fn is_selective(&self) -> bool {
         impl_ingredient_fn_ref!(self, is_selective,)
     }
     fn requires_full_materialization(&self) -> bool {
         impl_ingredient_fn_ref!(self, requires_full_materialization,)
     }
+    fn fingerprint(&self) -> Option<String> {
+        impl_ingredient_fn_ref!(self, fingerprint,)
+    }
+    fn referenced_columns(&self) -> Vec<(NodeIndex, usize)> {
+        impl_ingredient_fn_ref!(self, referenced_columns,)
+    }
+    fn emitted_columns(&self, ancestor: NodeIndex, columns: usize) -> Option<usize> {
+        impl_ingredient_fn_ref!(self, emitted_columns, ancestor, columns)
+    }
+    fn ordered_values(&self) -> Vec<(NodeIndex, Value)> {
+        impl_ingredient_fn_ref!(self, ordered_values,)
+    }
+    fn typed_columns(&self) -> Vec<(NodeIndex, usize, ColumnType)> {
+        impl_ingredient_fn_ref!(self, typed_columns,)
+    }
 }
 
 #[cfg(test)]
@@ -229,6 +341,7 @@ pub mod test {
         source: NodeIndex,
         nut: Option<IndexPair>, // node under test
         pub states: StateMap,
+        pub aux: AuxStateMap,
         nodes: DomainNodes,
         remap: HashMap<NodeIndex, IndexPair>,
     }
@@ -246,6 +359,7 @@ pub mod test {
                 source: source,
                 nut: None,
                 states: StateMap::new(),
+                aux: AuxStateMap::new(),
                 nodes: DomainNodes::default(),
                 remap: HashMap::new(),
             }
@@ -289,7 +403,7 @@ pub mod test {
             let indentln = |s: &mut String| s.push_str("    ");
 
             // header.
-            s.push_str("digraph {{\n");
+            s.push_str("digraph {\n");
 
             // global formatting.
             indentln(&mut s);
@@ -314,7 +428,7 @@ pub mod test {
                 };
                 indentln(&mut s);
                 s.push_str(&format!("{}", index.index()));
-                s.push_str(&node.describe(index, true, materialization_status));
+                s.push_str(&node.describe(index, true, materialization_status, &[], None));
             }
 
             // edges.
@@ -329,7 +443,7 @@ pub mod test {
             }
 
             // footer.
-            s.push_str("}}");
+            s.push_str("}");
 
             s
         }
@@ -351,6 +465,9 @@ pub mod test {
             if materialized {
                 self.states.insert(local, box MemoryState::default());
             }
+            if i.uses_aux_state() {
+                self.aux.insert(local, AuxState::default());
+            }
             for parent in parents {
                 self.graph.add_edge(parent, global, ());
             }
@@ -463,7 +580,15 @@ pub mod test {
             let mut u = {
                 let id = self.nut.unwrap();
                 let mut n = self.nodes[*id].borrow_mut();
-                let m = n.on_input(*src, u.into(), &mut None, None, &self.nodes, &self.states);
+                let m = n.on_input(
+                    *src,
+                    u.into(),
+                    &mut None,
+                    None,
+                    &self.nodes,
+                    &self.states,
+                    &mut self.aux,
+                );
                 assert_eq!(m.misses, vec![]);
                 m.results
             };
@@ -476,6 +601,31 @@ pub mod test {
             u
         }
 
+        pub fn one_spilling<U: Into<Records>>(&mut self, src: IndexPair, u: U) -> Vec<Records> {
+            let id = self.nut.unwrap();
+            let mut n = self.nodes[*id].borrow_mut();
+            let m = n.on_input_raw(
+                *src,
+                u.into(),
+                &mut None,
+                &ReplayContext::None,
+                &self.nodes,
+                &self.states,
+                &mut self.aux,
+            );
+            let mut out = match m {
+                RawProcessingResult::Regular(m) => {
+                    assert_eq!(m.misses, vec![]);
+                    vec![m.results]
+                }
+                _ => unreachable!(),
+            };
+            while let Some(rs) = n.next_spilled(&self.nodes, &self.states) {
+                out.push(rs);
+            }
+            out
+        }
+
         pub fn one_row<R: Into<Record>>(
             &mut self,
             src: IndexPair,
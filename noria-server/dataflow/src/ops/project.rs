@@ -1,22 +1,30 @@
 use nom_sql::ArithmeticOperator;
+use noria::{ColumnType, TimeUnit};
 
 use std::borrow::Cow;
 use std::collections::HashMap;
 use std::fmt;
 
 use prelude::*;
+use processing::expect_numeric;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum ProjectExpressionBase {
     Column(usize),
     Literal(DataType),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ProjectExpression {
-    op: ArithmeticOperator,
-    left: ProjectExpressionBase,
-    right: ProjectExpressionBase,
+/// An expression computed by a `Project` and emitted as an additional column.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ProjectExpression {
+    /// An arithmetic operation over two operands.
+    Arithmetic {
+        op: ArithmeticOperator,
+        left: ProjectExpressionBase,
+        right: ProjectExpressionBase,
+    },
+    /// A timestamp truncated to the given unit (e.g., the date of a timestamp).
+    Truncate(TimeUnit, ProjectExpressionBase),
 }
 
 impl ProjectExpression {
@@ -25,7 +33,7 @@ impl ProjectExpression {
         left: ProjectExpressionBase,
         right: ProjectExpressionBase,
     ) -> ProjectExpression {
-        ProjectExpression {
+        ProjectExpression::Arithmetic {
             op: op,
             left: left,
             right: right,
@@ -44,17 +52,34 @@ impl fmt::Display for ProjectExpressionBase {
 
 impl fmt::Display for ProjectExpression {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let op = match self.op {
-            ArithmeticOperator::Add => "+",
-            ArithmeticOperator::Subtract => "-",
-            ArithmeticOperator::Divide => "/",
-            ArithmeticOperator::Multiply => "*",
-        };
-
-        write!(f, "{} {} {}", self.left, op, self.right)
+        match *self {
+            ProjectExpression::Arithmetic {
+                ref op,
+                ref left,
+                ref right,
+            } => {
+                let op = match *op {
+                    ArithmeticOperator::Add => "+",
+                    ArithmeticOperator::Subtract => "-",
+                    ArithmeticOperator::Divide => "/",
+                    ArithmeticOperator::Multiply => "*",
+                };
+
+                write!(f, "{} {} {}", left, op, right)
+            }
+            ProjectExpression::Truncate(unit, ref arg) => write!(f, "{}({})", unit, arg),
+        }
     }
 }
 
+/// What a column that a `Project` emits is made of.
+#[derive(PartialEq)]
+enum Output<'a> {
+    Column(usize),
+    Expression(&'a ProjectExpression),
+    Literal(&'a DataType),
+}
+
 /// Permutes or omits columns from its source node, or adds additional literal value columns.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Project {
@@ -94,24 +119,115 @@ impl Project {
             self.emit.as_ref().map_or(col, |emit| emit[col])
         }
     }
-}
 
-fn eval_expression(expression: &ProjectExpression, record: &[DataType]) -> DataType {
-    let left = match expression.left {
-        ProjectExpressionBase::Column(i) => &record[i],
-        ProjectExpressionBase::Literal(ref data) => data,
-    };
+    /// What each of the columns that this projection emits is made of.
+    fn outputs(&self) -> Vec<Output> {
+        let mut outputs: Vec<_> = match self.emit {
+            Some(ref emit) => emit.iter().map(|&c| Output::Column(c)).collect(),
+            None => (0..self.cols).map(Output::Column).collect(),
+        };
+        outputs.extend(self.expressions.iter().flatten().map(Output::Expression));
+        outputs.extend(self.additional.iter().flatten().map(Output::Literal));
+        outputs
+    }
 
-    let right = match expression.right {
+    /// Emit the given row of the parent as this projection does.
+    fn project(&self, r: &[DataType]) -> Vec<DataType> {
+        let emit = match self.emit {
+            Some(ref emit) => emit,
+            None => return r.to_vec(),
+        };
+
+        let width = emit.len()
+            + self.expressions.as_ref().map(Vec::len).unwrap_or(0)
+            + self.additional.as_ref().map(Vec::len).unwrap_or(0);
+        let mut new_r = Vec::with_capacity(width);
+        for &i in emit {
+            new_r.push(r[i].clone());
+        }
+        if let Some(ref e) = self.expressions {
+            new_r.extend(e.into_iter().map(|i| eval_expression(i, r)));
+        }
+        if let Some(ref a) = self.additional {
+            new_r.extend(a.iter().cloned());
+        }
+        new_r
+    }
+
+    /// Whether this projection only keeps some of its parent's columns, in their original order,
+    /// and possibly adds literals, so that a row can be projected by cutting it down in place.
+    fn narrows(&self) -> bool {
+        match self.emit {
+            Some(ref emit) => self.expressions.is_none() && emit.windows(2).all(|w| w[0] < w[1]),
+            None => false,
+        }
+    }
+
+    /// Project a row that this projection `narrows` without copying the columns it keeps.
+    fn project_in_place(&self, r: &mut Vec<DataType>) {
+        let emit = self.emit.as_ref().unwrap();
+        // emit is increasing, so column emit[i] is never one of the i columns already moved
+        for (i, &c) in emit.iter().enumerate() {
+            r.swap(i, c);
+        }
+        r.truncate(emit.len());
+        if let Some(ref a) = self.additional {
+            r.extend(a.iter().cloned());
+        }
+    }
+
+    /// The number of columns that `new` emits after the ones this projection emits, if it reads
+    /// from the same parent, and emits every column that this projection emits unchanged and in
+    /// the same place.
+    pub fn added_columns(&self, new: &Project) -> Option<usize> {
+        if self.src.as_global() != new.src.as_global() {
+            return None;
+        }
+        let (old, new) = (self.outputs(), new.outputs());
+        if new.starts_with(&old[..]) {
+            Some(new.len() - old.len())
+        } else {
+            None
+        }
+    }
+
+    /// The records that the children of this projection need to see for it to be replaced by
+    /// `new`, given all the rows of its parent: every row as this projection emits it, retracted,
+    /// and as `new` emits it.
+    pub fn changes_to(&self, new: &Project, rows: &[Vec<DataType>]) -> Records {
+        let mut rs = Vec::with_capacity(rows.len() * 2);
+        for r in rows {
+            rs.push(Record::Negative(self.project(r)));
+            rs.push(Record::Positive(new.project(r)));
+        }
+        rs.into()
+    }
+}
+
+fn eval_base<'a>(base: &'a ProjectExpressionBase, record: &'a [DataType]) -> &'a DataType {
+    match *base {
         ProjectExpressionBase::Column(i) => &record[i],
         ProjectExpressionBase::Literal(ref data) => data,
-    };
+    }
+}
 
-    match expression.op {
-        ArithmeticOperator::Add => left + right,
-        ArithmeticOperator::Subtract => left - right,
-        ArithmeticOperator::Multiply => left * right,
-        ArithmeticOperator::Divide => left / right,
+fn eval_expression(expression: &ProjectExpression, record: &[DataType]) -> DataType {
+    match *expression {
+        ProjectExpression::Arithmetic {
+            ref op,
+            ref left,
+            ref right,
+        } => {
+            let left = eval_base(left, record);
+            let right = eval_base(right, record);
+            match *op {
+                ArithmeticOperator::Add => left + right,
+                ArithmeticOperator::Subtract => left - right,
+                ArithmeticOperator::Multiply => left * right,
+                ArithmeticOperator::Divide => left / right,
+            }
+        }
+        ProjectExpression::Truncate(unit, ref arg) => eval_base(arg, record).truncate(unit),
     }
 }
 
@@ -120,6 +236,16 @@ impl Ingredient for Project {
         Clone::clone(self).into()
     }
 
+    fn fingerprint(&self) -> Option<String> {
+        Some(format!(
+            "project({}, {:?}, {:?}, {:?})",
+            self.src.as_global().index(),
+            self.emit,
+            self.additional,
+            self.expressions
+        ))
+    }
+
     fn ancestors(&self) -> Vec<NodeIndex> {
         vec![self.src.as_global()]
     }
@@ -215,6 +341,41 @@ impl Ingredient for Project {
         });
     }
 
+    fn reparent(&mut self, parents: &HashMap<NodeIndex, IndexPair>) {
+        self.src.remap(parents);
+    }
+
+    fn validate(&self, _: LocalNodeIndex, record: &[DataType]) -> Result<(), String> {
+        for e in self.expressions.iter().flat_map(|es| es.iter()) {
+            match *e {
+                ProjectExpression::Arithmetic {
+                    ref left,
+                    ref right,
+                    ..
+                } => {
+                    for arg in &[left, right] {
+                        if let ProjectExpressionBase::Column(c) = **arg {
+                            expect_numeric(record, c)?;
+                        }
+                    }
+                }
+                ProjectExpression::Truncate(_, ProjectExpressionBase::Column(c)) => {
+                    match record[c] {
+                        DataType::Timestamp(..) | DataType::None => {}
+                        ref v => {
+                            return Err(format!(
+                                "column {} holds {:?}, which is not a timestamp",
+                                c, v
+                            ))
+                        }
+                    }
+                }
+                ProjectExpression::Truncate(..) => {}
+            }
+        }
+        Ok(())
+    }
+
     fn on_input(
         &mut self,
         from: LocalNodeIndex,
@@ -223,26 +384,13 @@ impl Ingredient for Project {
         _: Option<&[usize]>,
         _: &DomainNodes,
         _: &StateMap,
+        _: &mut AuxStateMap,
     ) -> ProcessingResult {
         debug_assert_eq!(from, *self.src);
-        if let Some(ref emit) = self.emit {
-            for r in &mut *rs {
-                let mut new_r = Vec::with_capacity(r.len());
-
-                for &i in emit {
-                    new_r.push(r[i].clone());
-                }
-
-                if let Some(ref e) = self.expressions {
-                    new_r.extend(e.into_iter().map(|i| eval_expression(i, &r[..])));
-                }
-
-                if let Some(ref a) = self.additional {
-                    new_r.append(&mut a.clone());
-                }
-
-                **r = new_r;
-            }
+        if self.narrows() {
+            rs.update_rows(|r| self.project_in_place(r), |r| self.project(r));
+        } else if self.emit.is_some() {
+            rs.map_rows(|r| self.project(r));
         }
 
         ProcessingResult {
@@ -251,6 +399,10 @@ impl Ingredient for Project {
         }
     }
 
+    fn parallel_replay_key(&self) -> Option<Vec<usize>> {
+        Some(Vec::new())
+    }
+
     fn suggest_indexes(&self, _: NodeIndex) -> HashMap<NodeIndex, (Vec<usize>, bool)> {
         HashMap::new()
     }
@@ -299,6 +451,56 @@ impl Ingredient for Project {
         };
         vec![(self.src.as_global(), result)]
     }
+
+    fn referenced_columns(&self) -> Vec<(NodeIndex, usize)> {
+        let src = self.src.as_global();
+        let mut cols: Vec<_> = match self.emit {
+            Some(ref emit) => emit.iter().map(|&c| (src, c)).collect(),
+            None => (0..self.cols).map(|c| (src, c)).collect(),
+        };
+        for e in self.expressions.iter().flat_map(|es| es.iter()) {
+            let args = match *e {
+                ProjectExpression::Arithmetic {
+                    ref left,
+                    ref right,
+                    ..
+                } => vec![left, right],
+                ProjectExpression::Truncate(_, ref arg) => vec![arg],
+            };
+            for arg in args {
+                if let ProjectExpressionBase::Column(c) = *arg {
+                    cols.push((src, c));
+                }
+            }
+        }
+        cols
+    }
+
+    fn typed_columns(&self) -> Vec<(NodeIndex, usize, ColumnType)> {
+        let src = self.src.as_global();
+        self.expressions
+            .iter()
+            .flat_map(|es| es.iter())
+            .filter_map(|e| match *e {
+                ProjectExpression::Truncate(_, ProjectExpressionBase::Column(c)) => {
+                    Some((src, c, ColumnType::Timestamp))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn row_bound(&self) -> RowBound {
+        RowBound::AsMany
+    }
+
+    fn evaluate(
+        &self,
+        inputs: &HashMap<NodeIndex, Vec<Vec<DataType>>>,
+    ) -> Option<Vec<Vec<DataType>>> {
+        let rows = inputs.get(&self.src.as_global())?;
+        Some(rows.iter().map(|r| self.project(r)).collect())
+    }
 }
 
 #[cfg(test)]
@@ -346,11 +548,11 @@ mod tests {
     }
 
     fn setup_column_arithmetic(op: ArithmeticOperator) -> ops::test::MockGraph {
-        let expression = ProjectExpression {
-            left: ProjectExpressionBase::Column(0),
-            right: ProjectExpressionBase::Column(1),
-            op: op,
-        };
+        let expression = ProjectExpression::new(
+            op,
+            ProjectExpressionBase::Column(0),
+            ProjectExpressionBase::Column(1),
+        );
 
         setup_arithmetic(expression)
     }
@@ -396,6 +598,35 @@ mod tests {
         );
     }
 
+    #[test]
+    fn it_narrows_batches() {
+        let mut g = ops::test::MockGraph::new();
+        let s = g.add_base("source", &["x", "y", "z"]);
+        g.set_op(
+            "permute",
+            &["x", "z", "lit"],
+            Project::new(s.as_global(), &[0, 2], Some(vec![42.into()]), None),
+            false,
+        );
+
+        let rs: Records = (0..10)
+            .map(|i| (vec![i.into(), "b".into(), (i * 2).into()], i % 3 != 0))
+            .collect::<Vec<_>>()
+            .into();
+        let expected: Records = rs
+            .iter()
+            .map(|r| (vec![r[0].clone(), r[2].clone(), 42.into()], r.is_positive()))
+            .collect::<Vec<_>>()
+            .into();
+
+        // a batch shared with someone else is copied
+        assert_eq!(g.narrow_one(rs.clone(), false), expected);
+        assert_eq!(rs.len(), 10);
+        assert_eq!(rs[1].len(), 3);
+        // and one that isn't is cut down in place
+        assert_eq!(g.narrow_one(rs, false), expected);
+    }
+
     #[test]
     fn it_forwards_all() {
         let mut p = setup(false, true, false);
@@ -468,11 +699,11 @@ mod tests {
     #[test]
     fn it_forwards_arithmetic_w_literals() {
         let number: DataType = 40.into();
-        let expression = ProjectExpression {
-            left: ProjectExpressionBase::Column(0),
-            right: ProjectExpressionBase::Literal(number),
-            op: ArithmeticOperator::Multiply,
-        };
+        let expression = ProjectExpression::new(
+            ArithmeticOperator::Multiply,
+            ProjectExpressionBase::Column(0),
+            ProjectExpressionBase::Literal(number),
+        );
 
         let mut p = setup_arithmetic(expression);
         let rec = vec![10.into(), 0.into()];
@@ -486,11 +717,11 @@ mod tests {
     fn it_forwards_arithmetic_w_only_literals() {
         let a: DataType = 80.into();
         let b: DataType = 40.into();
-        let expression = ProjectExpression {
-            left: ProjectExpressionBase::Literal(a),
-            right: ProjectExpressionBase::Literal(b),
-            op: ArithmeticOperator::Divide,
-        };
+        let expression = ProjectExpression::new(
+            ArithmeticOperator::Divide,
+            ProjectExpressionBase::Literal(a),
+            ProjectExpressionBase::Literal(b),
+        );
 
         let mut p = setup_arithmetic(expression);
         let rec = vec![0.into(), 0.into()];
@@ -612,11 +843,11 @@ mod tests {
     #[test]
     fn it_queries_through_w_arithmetic_and_literals() {
         let additional = Some(vec![DataType::Int(42)]);
-        let expressions = Some(vec![ProjectExpression {
-            left: ProjectExpressionBase::Column(0),
-            right: ProjectExpressionBase::Column(1),
-            op: ArithmeticOperator::Add,
-        }]);
+        let expressions = Some(vec![ProjectExpression::new(
+            ArithmeticOperator::Add,
+            ProjectExpressionBase::Column(0),
+            ProjectExpressionBase::Column(1),
+        )]);
 
         let state = box MemoryState::default();
         let (p, states) = setup_query_through(state, &[1], additional, expressions);
@@ -627,11 +858,11 @@ mod tests {
     #[test]
     fn it_queries_through_w_arithmetic_and_literals_persistent() {
         let additional = Some(vec![DataType::Int(42)]);
-        let expressions = Some(vec![ProjectExpression {
-            left: ProjectExpressionBase::Column(0),
-            right: ProjectExpressionBase::Column(1),
-            op: ArithmeticOperator::Add,
-        }]);
+        let expressions = Some(vec![ProjectExpression::new(
+            ArithmeticOperator::Add,
+            ProjectExpressionBase::Column(0),
+            ProjectExpressionBase::Column(1),
+        )]);
 
         let state = box PersistentState::new(
             String::from("it_queries_through_w_arithmetic_and_literals_persistent"),
@@ -644,6 +875,34 @@ mod tests {
         assert_query_through(p, 0, 2.into(), states, expected);
     }
 
+    #[test]
+    fn it_forwards_truncated_timestamps() {
+        use chrono::NaiveDate;
+
+        let expression =
+            ProjectExpression::Truncate(TimeUnit::Day, ProjectExpressionBase::Column(1));
+        let mut p = setup_arithmetic(expression);
+        assert_eq!(p.node().description(true), "π[0, 1, date(1)]");
+        assert_eq!(
+            p.node().typed_columns(),
+            vec![(p.narrow_base_id().as_global(), 1, ColumnType::Timestamp)]
+        );
+
+        let ts: DataType = NaiveDate::from_ymd(2018, 10, 1).and_hms(13, 37, 42).into();
+        let day: DataType = NaiveDate::from_ymd(2018, 10, 1).and_hms(0, 0, 0).into();
+        let rec = vec![1.into(), ts.clone()];
+        assert_eq!(
+            p.narrow_one_row(rec, false),
+            vec![vec![1.into(), ts, day]].into()
+        );
+
+        let rec = vec![1.into(), DataType::None];
+        assert_eq!(
+            p.narrow_one_row(rec, false),
+            vec![vec![1.into(), DataType::None, DataType::None]].into()
+        );
+    }
+
     #[test]
     fn it_suggests_indices() {
         let me = 1.into();
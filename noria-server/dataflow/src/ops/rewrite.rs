@@ -52,6 +52,17 @@ impl Ingredient for Rewrite {
         Clone::clone(self).into()
     }
 
+    fn fingerprint(&self) -> Option<String> {
+        Some(format!(
+            "rewrite({}, {}, {}, {:?}, {})",
+            self.src.as_global().index(),
+            self.signal.as_global().index(),
+            self.rw_col,
+            self.value,
+            self.signal_key
+        ))
+    }
+
     fn ancestors(&self) -> Vec<NodeIndex> {
         vec![self.src.as_global(), self.signal.as_global()]
     }
@@ -71,6 +82,11 @@ impl Ingredient for Rewrite {
         self.signal.remap(remap);
     }
 
+    fn reparent(&mut self, parents: &HashMap<NodeIndex, IndexPair>) {
+        self.src.remap(parents);
+        self.signal.remap(parents);
+    }
+
     fn on_input(
         &mut self,
         from: LocalNodeIndex,
@@ -79,6 +95,7 @@ impl Ingredient for Rewrite {
         replay_key_cols: Option<&[usize]>,
         nodes: &DomainNodes,
         state: &StateMap,
+        _: &mut AuxStateMap,
     ) -> ProcessingResult {
         debug_assert!(from == *self.src || from == *self.signal);
         let mut misses = Vec::new();
@@ -195,6 +212,13 @@ impl Ingredient for Rewrite {
     fn parent_columns(&self, column: usize) -> Vec<(NodeIndex, Option<usize>)> {
         vec![(self.src.as_global(), Some(column))]
     }
+
+    fn referenced_columns(&self) -> Vec<(NodeIndex, usize)> {
+        vec![
+            (self.src.as_global(), self.rw_col),
+            (self.signal.as_global(), self.signal_key),
+        ]
+    }
 }
 
 #[cfg(test)]
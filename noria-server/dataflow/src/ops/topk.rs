@@ -6,7 +6,7 @@ use prelude::*;
 
 use nom_sql::OrderType;
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 struct Order(Vec<(usize, OrderType)>);
 impl Order {
     fn cmp(&self, a: &[DataType], b: &[DataType]) -> Ordering {
@@ -94,6 +94,16 @@ impl Ingredient for TopK {
         .into()
     }
 
+    fn fingerprint(&self) -> Option<String> {
+        Some(format!(
+            "topk({}, {:?}, {:?}, {})",
+            self.src.as_global().index(),
+            self.group_by,
+            self.order.0,
+            self.k
+        ))
+    }
+
     fn ancestors(&self) -> Vec<NodeIndex> {
         vec![self.src.as_global()]
     }
@@ -111,6 +121,10 @@ impl Ingredient for TopK {
         self.us = Some(remap[&us]);
     }
 
+    fn reparent(&mut self, parents: &HashMap<NodeIndex, IndexPair>) {
+        self.src.remap(parents);
+    }
+
     fn on_input(
         &mut self,
         from: LocalNodeIndex,
@@ -119,6 +133,7 @@ impl Ingredient for TopK {
         replay_key_cols: Option<&[usize]>,
         _: &DomainNodes,
         state: &StateMap,
+        _: &mut AuxStateMap,
     ) -> ProcessingResult {
         debug_assert_eq!(from, *self.src);
 
@@ -315,6 +330,21 @@ impl Ingredient for TopK {
     fn parent_columns(&self, col: usize) -> Vec<(NodeIndex, Option<usize>)> {
         vec![(self.src.as_global(), Some(col))]
     }
+
+    fn referenced_columns(&self) -> Vec<(NodeIndex, usize)> {
+        let src = self.src.as_global();
+        let order = self.order.0.iter().map(|&(c, _)| c);
+        self.group_by
+            .iter()
+            .cloned()
+            .chain(order)
+            .map(|c| (src, c))
+            .collect()
+    }
+
+    fn row_bound(&self) -> RowBound {
+        RowBound::AtMostSome
+    }
 }
 
 #[cfg(test)]
@@ -112,6 +112,10 @@ impl Ingredient for Trigger {
         self.us = Some(remap[&us]);
     }
 
+    fn reparent(&mut self, parents: &HashMap<NodeIndex, IndexPair>) {
+        self.src.remap(parents);
+    }
+
     fn on_input(
         &mut self,
         from: LocalNodeIndex,
@@ -120,6 +124,7 @@ impl Ingredient for Trigger {
         _: Option<&[usize]>,
         _: &DomainNodes,
         state: &StateMap,
+        _: &mut AuxStateMap,
     ) -> ProcessingResult {
         debug_assert_eq!(from, *self.src);
 
@@ -174,6 +179,10 @@ impl Ingredient for Trigger {
         vec![(self.src.as_global(), Some(column))]
     }
 
+    fn referenced_columns(&self) -> Vec<(NodeIndex, usize)> {
+        vec![(self.src.as_global(), self.key)]
+    }
+
     // Trigger nodes require full materialization because we want group universes
     // to be long lived and to exist even if no user makes use of it.
     // We do this for two reasons: 1) to make user universe creation faster and
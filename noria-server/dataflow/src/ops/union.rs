@@ -1,3 +1,4 @@
+use fnv::FnvHashMap;
 use std::collections::{BTreeMap, HashMap, HashSet};
 
 use prelude::*;
@@ -27,7 +28,7 @@ enum FullWait {
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct ReplayPieces {
-    buffered: HashMap<LocalNodeIndex, Records>,
+    buffered: FnvHashMap<LocalNodeIndex, Records>,
     evict: bool,
 }
 
@@ -39,12 +40,17 @@ pub struct Union {
     // to sanity check that we're not asked to manage multiple replay paths with different keys
     replay_key_orig: Vec<usize>,
 
-    replay_key: Option<HashMap<LocalNodeIndex, Vec<usize>>>,
-    replay_pieces: HashMap<Vec<DataType>, ReplayPieces>,
+    replay_key: Option<FnvHashMap<LocalNodeIndex, Vec<usize>>>,
+    replay_pieces: FnvHashMap<Vec<DataType>, ReplayPieces>,
 
     required: usize,
 
     full_wait_state: FullWait,
+
+    // for a shard merger, the number of shards that have sent each of the write markers that not
+    // every shard has sent yet
+    #[serde(skip)]
+    markers: FnvHashMap<u64, usize>,
 }
 
 impl Clone for Union {
@@ -55,8 +61,9 @@ impl Clone for Union {
             replay_key_orig: Vec::new(),
             // nothing can have been received yet
             replay_key: None,
-            replay_pieces: HashMap::new(),
+            replay_pieces: FnvHashMap::default(),
             full_wait_state: FullWait::None,
+            markers: FnvHashMap::default(),
         }
     }
 }
@@ -92,8 +99,9 @@ impl Union {
             required: parents,
             replay_key_orig: Vec::new(),
             replay_key: None,
-            replay_pieces: HashMap::new(),
+            replay_pieces: FnvHashMap::default(),
             full_wait_state: FullWait::None,
+            markers: FnvHashMap::default(),
         }
     }
 
@@ -105,11 +113,34 @@ impl Union {
             required: shards,
             replay_key_orig: Vec::new(),
             replay_key: None,
-            replay_pieces: HashMap::new(),
+            replay_pieces: FnvHashMap::default(),
             full_wait_state: FullWait::None,
+            markers: FnvHashMap::default(),
         }
     }
 
+    /// Also emit the columns selected in `emit` from the records of another parent, which have
+    /// `columns` columns each, and reach the union through `parent` in its domain.
+    ///
+    /// This is for a union that has already been committed, so `parent` must be remapped.
+    pub fn add_parent(&mut self, parent: IndexPair, emit: Vec<usize>, columns: usize) {
+        match self.emit {
+            Emit::AllFrom(..) => unreachable!("can't add parents to a shard merger"),
+            Emit::Project {
+                emit: ref mut e,
+                ref mut emit_l,
+                ref mut cols,
+                ref mut cols_l,
+            } => {
+                emit_l.insert(*parent, emit.clone());
+                cols_l.insert(*parent, columns);
+                e.insert(parent, emit);
+                cols.insert(parent, columns);
+            }
+        }
+        self.required += 1;
+    }
+
     pub fn is_shard_merger(&self) -> bool {
         if let Emit::AllFrom(..) = self.emit {
             true
@@ -117,6 +148,33 @@ impl Union {
             false
         }
     }
+
+    /// Hold back the write markers in an update that a shard merger has yet to hear about from
+    /// every shard, and let through those it now has heard about from all of them.
+    ///
+    /// Every shard above sends on every marker, so the writes with the markers that are let
+    /// through have been processed by all of them.
+    pub fn merge_markers(&mut self, markers: &mut Vec<u64>) {
+        if !self.is_shard_merger() || self.required == 1 {
+            return;
+        }
+
+        let required = self.required; // can't borrow self in closure below
+        let seen = &mut self.markers;
+        markers.retain(|&marker| {
+            let n = {
+                let n = seen.entry(marker).or_insert(0);
+                *n += 1;
+                *n
+            };
+            if n == required {
+                seen.remove(&marker);
+                true
+            } else {
+                false
+            }
+        });
+    }
 }
 
 impl Ingredient for Union {
@@ -124,6 +182,21 @@ impl Ingredient for Union {
         Clone::clone(self).into()
     }
 
+    fn fingerprint(&self) -> Option<String> {
+        match self.emit {
+            // unions that merge the shards of a node are only added by the migration itself
+            Emit::AllFrom(..) => None,
+            Emit::Project { ref emit, .. } => {
+                let mut emit: Vec<_> = emit
+                    .iter()
+                    .map(|(k, cols)| (k.as_global().index(), cols))
+                    .collect();
+                emit.sort();
+                Some(format!("union({:?})", emit))
+            }
+        }
+    }
+
     fn ancestors(&self) -> Vec<NodeIndex> {
         match self.emit {
             Emit::AllFrom(p, _) => vec![p.as_global()],
@@ -176,14 +249,30 @@ impl Ingredient for Union {
         }
     }
 
+    fn reparent(&mut self, parents: &HashMap<NodeIndex, IndexPair>) {
+        match self.emit {
+            Emit::AllFrom(ref mut p, _) => p.remap(parents),
+            Emit::Project { ref mut emit, .. } => {
+                *emit = emit
+                    .drain()
+                    .map(|(mut k, v)| {
+                        k.remap(parents);
+                        (k, v)
+                    })
+                    .collect();
+            }
+        }
+    }
+
     fn on_input(
         &mut self,
         from: LocalNodeIndex,
-        rs: Records,
+        mut rs: Records,
         _: &mut Tracer,
         _: Option<&[usize]>,
         _: &DomainNodes,
         _: &StateMap,
+        _: &mut AuxStateMap,
     ) -> ProcessingResult {
         match self.emit {
             Emit::AllFrom(..) => ProcessingResult {
@@ -191,23 +280,10 @@ impl Ingredient for Union {
                 misses: Vec::new(),
             },
             Emit::Project { ref emit_l, .. } => {
-                let rs = rs
-                    .into_iter()
-                    .map(move |rec| {
-                        let (r, pos) = rec.extract();
-
-                        // yield selected columns for this source
-                        // TODO: if emitting all in same order then avoid clone
-                        let res = emit_l[&from].iter().map(|&col| r[col].clone()).collect();
-
-                        // return new row with appropriate sign
-                        if pos {
-                            Record::Positive(res)
-                        } else {
-                            Record::Negative(res)
-                        }
-                    })
-                    .collect();
+                // yield selected columns for this source
+                // TODO: if emitting all in same order then avoid clone
+                let emit = &emit_l[&from];
+                rs.map_rows(|r| emit.iter().map(|&col| r[col].clone()).collect());
                 ProcessingResult {
                     results: rs,
                     misses: Vec::new(),
@@ -224,6 +300,7 @@ impl Ingredient for Union {
         replay: &ReplayContext,
         n: &DomainNodes,
         s: &StateMap,
+        aux: &mut AuxStateMap,
     ) -> RawProcessingResult {
         use std::mem;
 
@@ -258,7 +335,7 @@ impl Ingredient for Union {
                     assert!(self.replay_key.is_none() || self.replay_pieces.is_empty());
 
                     // process the results (self is okay to have mutably borrowed here)
-                    let rs = self.on_input(from, rs, tracer, None, n, s).results;
+                    let rs = self.on_input(from, rs, tracer, None, n, s, aux).results;
 
                     // *then* borrow self.full_wait_state again
                     if let FullWait::Ongoing {
@@ -280,7 +357,7 @@ impl Ingredient for Union {
                 if self.replay_key.is_none() || self.replay_pieces.is_empty() {
                     // no replay going on, so we're done.
                     return RawProcessingResult::Regular(
-                        self.on_input(from, rs, tracer, None, n, s),
+                        self.on_input(from, rs, tracer, None, n, s, aux),
                     );
                 }
 
@@ -313,7 +390,7 @@ impl Ingredient for Union {
                     }
                 }
 
-                RawProcessingResult::Regular(self.on_input(from, rs, tracer, None, n, s))
+                RawProcessingResult::Regular(self.on_input(from, rs, tracer, None, n, s, aux))
             }
             ReplayContext::Full { last } => {
                 // this part is actually surpringly straightforward, but the *reason* it is
@@ -369,7 +446,7 @@ impl Ingredient for Union {
                 // arm). feel free to go check. interestingly enough, it's also fine for us to
                 // still emit 2 (i.e., not capture it), since it'll just be dropped by the target
                 // domain.
-                let mut rs = self.on_input(from, rs, tracer, None, n, s).results;
+                let mut rs = self.on_input(from, rs, tracer, None, n, s, aux).results;
                 if let FullWait::None = self.full_wait_state {
                     if self.required == 1 {
                         // no need to ever buffer
@@ -401,19 +478,17 @@ impl Ingredient for Union {
                         if *finished == self.required {
                             // we can just send everything and we're done!
                             // make sure to include what's in *this* replay.
-                            buffered.append(&mut *rs);
-                            exit =
-                                RawProcessingResult::FullReplay(buffered.split_off(0).into(), true);
+                            buffered.append(&mut rs);
+                            let rs = mem::replace(buffered, Records::default());
+                            exit = RawProcessingResult::FullReplay(rs, true);
                         // fall through to below match where we'll set FullWait::None
                         } else {
                             if started.len() != self.required {
                                 if started.insert(from) && started.len() == self.required {
                                     // we can release all buffered replays!
-                                    buffered.append(&mut *rs);
-                                    return RawProcessingResult::FullReplay(
-                                        buffered.split_off(0).into(),
-                                        false,
-                                    );
+                                    buffered.append(&mut rs);
+                                    let rs = mem::replace(buffered, Records::default());
+                                    return RawProcessingResult::FullReplay(rs, false);
                                 }
                             } else {
                                 // common case: replay has started, and not yet finished
@@ -424,7 +499,7 @@ impl Ingredient for Union {
 
                             // if we fell through here, it means we're still missing the first
                             // replay from at least one ancestor, so we need to buffer
-                            buffered.append(&mut *rs);
+                            buffered.append(&mut rs);
                             return RawProcessingResult::CapturedFull;
                         }
                     }
@@ -536,7 +611,7 @@ impl Ingredient for Union {
                                     }
                                 }
                                 Entry::Vacant(h) => {
-                                    let mut m = HashMap::new();
+                                    let mut m = FnvHashMap::default();
                                     m.insert(from, rs);
                                     if required == 1 {
                                         Some((
@@ -566,7 +641,7 @@ impl Ingredient for Union {
                             pieces.buffered.into_iter()
                         })
                         .flat_map(|(from, rs)| {
-                            self.on_input(from, rs, tracer, Some(&key_cols[..]), n, s)
+                            self.on_input(from, rs, tracer, Some(&key_cols[..]), n, s, aux)
                                 .results
                         })
                         .collect()
@@ -672,6 +747,47 @@ impl Ingredient for Union {
                 .collect(),
         }
     }
+
+    fn referenced_columns(&self) -> Vec<(NodeIndex, usize)> {
+        match self.emit {
+            Emit::AllFrom(..) => vec![],
+            Emit::Project { ref emit, .. } => emit
+                .iter()
+                .flat_map(|(src, emit)| emit.iter().map(move |&c| (src.as_global(), c)))
+                .collect(),
+        }
+    }
+
+    fn evaluate(
+        &self,
+        inputs: &HashMap<NodeIndex, Vec<Vec<DataType>>>,
+    ) -> Option<Vec<Vec<DataType>>> {
+        match self.emit {
+            // the shards of the parent together hold all of its rows
+            Emit::AllFrom(p, _) => inputs.get(&p.as_global()).cloned(),
+            Emit::Project { ref emit, .. } => {
+                let mut rows = Vec::new();
+                for (p, emit) in emit {
+                    let from = inputs.get(&p.as_global())?;
+                    rows.extend(
+                        from.iter()
+                            .map(|r| emit.iter().map(|&c| r[c].clone()).collect::<Vec<_>>()),
+                    );
+                }
+                Some(rows)
+            }
+        }
+    }
+
+    fn emitted_columns(&self, ancestor: NodeIndex, columns: usize) -> Option<usize> {
+        match self.emit {
+            Emit::AllFrom(..) => Some(columns),
+            Emit::Project { ref emit, .. } => emit
+                .iter()
+                .find(|&(src, _)| src.as_global() == ancestor)
+                .map(|(_, emit)| emit.len()),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -701,6 +817,30 @@ mod tests {
         );
     }
 
+    #[test]
+    fn it_evaluates() {
+        let (u, l, r) = setup();
+        let mut inputs = HashMap::new();
+        inputs.insert(l.as_global(), vec![vec![1.into(), "a".into()]]);
+        inputs.insert(
+            r.as_global(),
+            vec![
+                vec![1.into(), "skipped".into(), "x".into()],
+                vec![2.into(), "skipped".into(), "y".into()],
+            ],
+        );
+
+        let mut rows = u.node().evaluate(&inputs).unwrap();
+        rows.sort();
+        let mut expected: Vec<Vec<DataType>> = vec![
+            vec![1.into(), "a".into()],
+            vec![1.into(), "x".into()],
+            vec![2.into(), "y".into()],
+        ];
+        expected.sort();
+        assert_eq!(rows, expected);
+    }
+
     #[test]
     fn it_works() {
         let (mut u, l, r) = setup();
@@ -755,4 +895,61 @@ mod tests {
                 .any(|&(n, c)| n == r.as_global() && c == 2)
         );
     }
+
+    #[test]
+    fn it_references_columns() {
+        let (u, l, r) = setup();
+        let mut cols = u.node().referenced_columns();
+        cols.sort();
+        let mut expected = vec![
+            (l.as_global(), 0),
+            (l.as_global(), 1),
+            (r.as_global(), 0),
+            (r.as_global(), 2),
+        ];
+        expected.sort();
+        assert_eq!(cols, expected);
+
+        // each parent contributes as many columns as it has in the emit list
+        assert_eq!(u.node().emitted_columns(l.as_global(), 2), Some(2));
+        assert_eq!(u.node().emitted_columns(r.as_global(), 3), Some(2));
+    }
+
+    #[test]
+    fn it_reparents() {
+        let (l, r) = (NodeIndex::new(1), NodeIndex::new(2));
+        let mut emits = HashMap::new();
+        emits.insert(l, vec![0, 1]);
+        emits.insert(r, vec![0, 2]);
+        let mut u = Union::new(emits);
+
+        let (l2, r2) = (NodeIndex::new(5), NodeIndex::new(7));
+        let mut parents = HashMap::new();
+        parents.insert(l, l2.into());
+        parents.insert(r, r2.into());
+        u.reparent(&parents);
+
+        let mut ancestors = u.ancestors();
+        ancestors.sort();
+        assert_eq!(ancestors, vec![l2, r2]);
+        assert_eq!(u.emitted_columns(l2, 2), Some(2));
+        assert_eq!(u.emitted_columns(l, 2), None);
+    }
+
+    #[test]
+    fn it_merges_markers_from_every_shard() {
+        let mut u = Union::new_deshard(NodeIndex::new(0), Sharding::ByColumn(0, 2));
+
+        let mut markers = vec![1, 2];
+        u.merge_markers(&mut markers);
+        assert!(markers.is_empty());
+
+        // a marker is let through once the last shard has sent it, and not again after that
+        let mut markers = vec![2, 3];
+        u.merge_markers(&mut markers);
+        assert_eq!(markers, vec![2]);
+        let mut markers = vec![2];
+        u.merge_markers(&mut markers);
+        assert!(markers.is_empty());
+    }
 }
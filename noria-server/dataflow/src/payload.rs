@@ -13,6 +13,7 @@ use prelude::*;
 use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::time;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -58,6 +59,9 @@ pub enum InitialState {
         cols: usize,
         key: Vec<usize>,
     },
+    /// Fill the node's state with the rows saved for it in the last loaded checkpoint, and index
+    /// it by the given columns in addition to those it was saved with.
+    Checkpointed(HashSet<Vec<usize>>),
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -68,6 +72,13 @@ pub enum ReplayPieceContext {
     },
     Regular {
         last: bool,
+        /// How many rows the whole replay will carry, if the source knows. Only set on the first
+        /// piece, so that the target can make room for them before they start arriving.
+        rows: Option<usize>,
+        /// How many updates the egress that sent this piece on to another domain had sent over
+        /// the same edge before it. The state being replayed reflects all of those updates and
+        /// none of the ones after them, so the ingress at the other end applies only the latter.
+        cut: Option<u64>,
     },
 }
 
@@ -93,6 +104,16 @@ pub enum Packet {
         data: Records,
         tracer: Tracer,
         senders: Vec<SourceChannelIdentifier>,
+        /// When the write that this update stems from was accepted by its base, if it stems from
+        /// one.
+        written: Option<time::SystemTime>,
+        /// Where this update is among those sent over the egress-to-ingress edge it last crossed,
+        /// counting from 1, if it crossed one.
+        seq: Option<u64>,
+        /// Markers of the writes that this update completes the effects of, and that clients are
+        /// waiting to see in some reader (see `Input::markers`). An update that carries markers is
+        /// passed on even if it has no records left.
+        markers: Vec<u64>,
     },
 
     /// Update that is part of a tagged data-flow replay path.
@@ -117,6 +138,13 @@ pub enum Packet {
         keys: Vec<Vec<DataType>>,
     },
 
+    /// Evict the given keys from the reader `node`, or, if no keys are given, evict its least
+    /// recently read keys until it is within its memory limit.
+    ForceEvict {
+        node: LocalNodeIndex,
+        keys: Option<Vec<Vec<DataType>>>,
+    },
+
     //
     // Internal control
     //
@@ -124,7 +152,8 @@ pub enum Packet {
 
     // Control messages
     //
-    /// Add a new node to this domain below the given parents.
+    /// Add a new node to this domain below the given parents. The domain acknowledges once the
+    /// node has been added, or replies with why it could not be.
     AddNode {
         node: Node,
         parents: Vec<LocalNodeIndex>,
@@ -155,6 +184,24 @@ pub enum Packet {
         new_tag: Option<(Tag, NodeIndex)>,
     },
 
+    /// Let reads see the given hidden readers, all at once.
+    ExposeReaders {
+        nodes: Vec<LocalNodeIndex>,
+    },
+
+    /// Have the given reader also serve reads meant for another reader on the same worker, which
+    /// it is replacing.
+    TakeOverReader {
+        node: LocalNodeIndex,
+        from: NodeIndex,
+    },
+
+    /// Stop an egress node from sending to an ingress node that has been removed.
+    RemoveEgressTx {
+        node: LocalNodeIndex,
+        target: NodeIndex,
+    },
+
     /// Add a shard to a Sharder node.
     ///
     /// Note that this *must* be done *before* the sharder starts being used!
@@ -171,12 +218,69 @@ pub enum Packet {
 
     /// Set up a fresh, empty state for a node, indexed by a particular column.
     ///
-    /// This is done in preparation of a subsequent state replay.
+    /// This is done in preparation of a subsequent state replay. The domain acknowledges once the
+    /// state has been set up, or replies with why it could not be.
     PrepareState {
         node: LocalNodeIndex,
         state: InitialState,
     },
 
+    /// Add an index on the given columns to the existing, fully materialized state of a node.
+    ///
+    /// The index is built from the rows the state already holds, so the node needs no replay. The
+    /// domain handles no other packet until it is done, so no update that arrives meanwhile can
+    /// miss the new index. The domain acks once the index is built.
+    AddIndex {
+        node: LocalNodeIndex,
+        columns: Vec<usize>,
+    },
+
+    /// Write the given rows to a new base that has just been readied, before any of its children
+    /// are. The rows are checked and persisted like any other write, and reach the base's children
+    /// through the replays that fill their state. The domain replies with the number of rows
+    /// written, or with why they were rejected.
+    SeedBase {
+        node: LocalNodeIndex,
+        rows: Vec<Vec<DataType>>,
+    },
+
+    /// Replace the operator of an internal node in place, and send its children the records that
+    /// make what they hold match the new operator's output over the parent's full state.
+    ///
+    /// The domain handles no other packet until it is done, so updates that arrive before it are
+    /// seen by the children as the old operator emits them, and those that arrive after as the
+    /// new one does. `fields` are the names of the columns the new operator adds to the output.
+    /// The domain acks once the records have been sent.
+    ModifyOperator {
+        node: LocalNodeIndex,
+        parent: LocalNodeIndex,
+        operator: NodeOperator,
+        fields: Vec<String>,
+    },
+
+    /// Make `parent` another parent of the running union `node`, which is replaced by `operator`.
+    ///
+    /// If `parent` is a materialized node in this domain, the union is sent all of its rows before
+    /// any of the updates that come after them. Otherwise `parent` is a new ingress that nothing
+    /// sends to yet, and the rows arrive once the egress that is to send to it gets `FeedEgress`.
+    /// The domain acks once done.
+    AddUnionParent {
+        node: LocalNodeIndex,
+        parent: LocalNodeIndex,
+        operator: NodeOperator,
+    },
+
+    /// Send the new ingress `new_tx` all the rows of `source`, which the egress `node` is a child
+    /// of, and from then on the updates that the egress forwards.
+    ///
+    /// Nothing else runs in the domain in between, so the ingress sees every row exactly once,
+    /// either as part of the state or as an update. The domain acks once the rows have been sent.
+    FeedEgress {
+        node: LocalNodeIndex,
+        source: LocalNodeIndex,
+        new_tx: (NodeIndex, LocalNodeIndex, ReplicaAddr),
+    },
+
     /// Probe for the number of records in the given node's state
     StateSizeProbe {
         node: LocalNodeIndex,
@@ -205,13 +309,17 @@ pub enum Packet {
     },
 
     /// Instruct domain to replay the state of a particular node along an existing replay path.
+    /// The domain acknowledges once it has started the replay, or replies with why it could not.
     StartReplay {
         tag: Tag,
         from: LocalNodeIndex,
+        /// How to share the domain between the replay and live updates.
+        priority: ReplayPriority,
     },
 
     /// Sent to instruct a domain that a particular node should be considered ready to process
-    /// updates.
+    /// updates. The domain acknowledges once the node is ready, or replies with why it could not
+    /// make it so.
     Ready {
         node: LocalNodeIndex,
         index: HashSet<Vec<usize>>,
@@ -227,10 +335,236 @@ pub enum Packet {
     /// Argument specifies if we wish to get the full state size or just the partial nodes.
     GetStatistics,
 
+    /// Request that a domain send the current values of its metrics, and of those of its nodes
+    /// and readers, on the control reply channel.
+    GetMetrics,
+
     /// Ask domain to log its state size
     UpdateStateSize,
+
+    /// Request that a domain send a checksum of the rows materialized at the given node on the
+    /// control reply channel.
+    GetChecksum {
+        node: LocalNodeIndex,
+    },
+
+    /// Request that a domain send the number of rows materialized at the given node, along with
+    /// their checksum, on the control reply channel.
+    GetDigest {
+        node: LocalNodeIndex,
+    },
+
+    /// Write the rows and indices of every fully materialized node's state to the domain's
+    /// checkpoint file, tagged with the given checkpoint identifier.
+    Checkpoint {
+        id: u64,
+    },
+
+    /// Load the domain's checkpoint file, if it was written by the checkpoint with the given
+    /// identifier, so that nodes can later be prepared with `InitialState::Checkpointed`.
+    LoadCheckpoint {
+        id: u64,
+    },
+
+    /// Apply the writes in a base's write-ahead log that are not yet in its state, which was
+    /// either restored from a checkpoint or starts out empty. Sent while recovering, once the base
+    /// has been readied, but before any of its children are. The domain replies with the number
+    /// of writes it applied.
+    RecoverBase {
+        node: LocalNodeIndex,
+    },
+
+    /// Start appending every event the domain sees to a capture file in the given directory, or
+    /// stop capturing if no directory is given. The domain replies with the file it captures to.
+    Capture {
+        into: Option<PathBuf>,
+    },
+
+    /// Request that a domain send every row materialized at the given node on the control reply
+    /// channel.
+    GetRows {
+        node: LocalNodeIndex,
+    },
+
+    /// Request that a domain send every packet that its worker's domains could not deliver on the
+    /// control reply channel, and forget about them.
+    DrainDeadLetters,
+
+    /// Hold back every write to the domain's bases, unacknowledged, until told otherwise, or
+    /// process the writes that were held back and admit writes again.
+    HoldWrites(bool),
+
+    /// Start looking out for the barrier with the given identifier, which `senders` shards of
+    /// other domains will each send on once they have processed everything that came before it.
+    /// Once all of them have, and the domain has nothing left to process or send, it sends the
+    /// barrier on to the domains it sends updates to, and replies on the control reply channel.
+    Quiesce {
+        id: u64,
+        senders: usize,
+    },
+
+    /// A barrier that the given shard of a domain sent on after everything it had sent before it.
+    Barrier {
+        id: u64,
+        from: ReplicaAddr,
+    },
+
+    /// Have the given egress node stop sending to the ingress node `standby`, and hold it in
+    /// reserve for the edge that sends to `primary`, which is switched over to it if `primary`
+    /// fails. Re-adds `standby` if the edge was switched away from it when it failed.
+    AddStandby {
+        node: LocalNodeIndex,
+        primary: NodeIndex,
+        standby: NodeIndex,
+    },
+
+    /// Switch the edge of the given egress node that sends to the ingress node `from` over to its
+    /// standby `to`.
+    SwitchEgress {
+        node: LocalNodeIndex,
+        from: NodeIndex,
+        to: NodeIndex,
+    },
+
+    /// Send the global and local index of every node the domain has, removed ones included, over
+    /// a connection of its own to `reply_to`. The domain's connection to its controller is left
+    /// alone, so that another controller can check what the domain runs before taking it over.
+    ListNodes {
+        reply_to: SocketAddr,
+    },
+
+    /// Send control replies over a new connection to `control_addr` from now on, since another
+    /// controller has taken over. The domain acknowledges over the new connection.
+    Adopt {
+        control_addr: SocketAddr,
+    },
+
+    /// Stop processing the packets that carry updates, replays, evictions and writes, and keep
+    /// them, in the order they arrive, until told to resume. The domain keeps handling control
+    /// packets, and acknowledges once it has paused.
+    Pause,
+
+    /// Process the packets that arrived while the domain was paused, and go back to processing
+    /// packets as they arrive. The domain acknowledges once it has resumed.
+    Resume,
+
+    /// Start moving the domain to another worker: take a snapshot of its nodes and state, send it
+    /// on the control reply channel as the builder of a copy that answers to `control_addr`, and
+    /// log the updates processed after it until the copy can be sent them.
+    StartMove {
+        control_addr: SocketAddr,
+    },
+
+    /// Send the copy of the domain that listens at `to` the updates logged since the snapshot, and
+    /// each one processed from now on. The domain acknowledges once it has connected to the copy.
+    ShipMove {
+        to: SocketAddr,
+    },
+
+    /// Stop moving the domain, and forget about the copy. The domain acknowledges.
+    CancelMove,
+
+    /// Finish moving the domain, once `senders` shards of other domains have each sent it
+    /// `Packet::Redirected`, by sending the copy the last updates. The domain then replies with
+    /// the number of updates that it logged.
+    FinishMove {
+        senders: usize,
+    },
+
+    /// Send the given shard of a domain that is moving from `from` to `to` one last packet where
+    /// it was, and everything after it to where it is now. The domain acknowledges.
+    Redirect {
+        domain: DomainIndex,
+        shard: usize,
+        from: SocketAddr,
+        to: SocketAddr,
+    },
+
+    /// The last packet that the given shard of a domain sends to a domain that is moving.
+    Redirected {
+        from: ReplicaAddr,
+    },
+
+    /// Updates that a domain that is moving processed after the snapshot it made this copy from.
+    /// Once it has processed the `last` of them, the copy acknowledges, and starts processing
+    /// packets as they arrive.
+    CatchUp {
+        packets: Vec<Box<Packet>>,
+        last: bool,
+    },
+
+    /// Set up empty auxiliary state for a new node whose operator keeps some, or the state saved
+    /// for it in the domain's checkpoint if there is any. This is done before the node's state is
+    /// prepared. The domain acknowledges once the state has been set up, or replies with why it
+    /// could not be.
+    PrepareAuxState {
+        node: LocalNodeIndex,
+    },
 }
 
+/// The names of the kinds of packets there are, by `Packet::kind_index`.
+pub const PACKET_KINDS: [&str; 59] = [
+    "Input",
+    "Message",
+    "ReplayPiece",
+    "Evict",
+    "EvictKeys",
+    "ForceEvict",
+    "Finish",
+    "AddNode",
+    "RemoveNodes",
+    "AddBaseColumn",
+    "DropBaseColumn",
+    "UpdateEgress",
+    "ExposeReaders",
+    "TakeOverReader",
+    "RemoveEgressTx",
+    "UpdateSharder",
+    "AddStreamer",
+    "PrepareState",
+    "AddIndex",
+    "SeedBase",
+    "ModifyOperator",
+    "AddUnionParent",
+    "FeedEgress",
+    "StateSizeProbe",
+    "SetupReplayPath",
+    "RequestPartialReplay",
+    "RequestReaderReplay",
+    "StartReplay",
+    "Ready",
+    "Quit",
+    "Spin",
+    "GetStatistics",
+    "GetMetrics",
+    "UpdateStateSize",
+    "GetChecksum",
+    "GetDigest",
+    "Checkpoint",
+    "LoadCheckpoint",
+    "RecoverBase",
+    "Capture",
+    "GetRows",
+    "DrainDeadLetters",
+    "HoldWrites",
+    "Quiesce",
+    "Barrier",
+    "AddStandby",
+    "SwitchEgress",
+    "ListNodes",
+    "Adopt",
+    "Pause",
+    "Resume",
+    "StartMove",
+    "ShipMove",
+    "CancelMove",
+    "FinishMove",
+    "Redirect",
+    "Redirected",
+    "CatchUp",
+    "PrepareAuxState",
+];
+
 impl Packet {
     pub fn src(&self) -> LocalNodeIndex {
         match *self {
@@ -284,6 +618,76 @@ impl Packet {
         }
     }
 
+    /// The position of the kind of packet this is in `PACKET_KINDS`.
+    pub fn kind_index(&self) -> usize {
+        match *self {
+            Packet::Input { .. } => 0,
+            Packet::Message { .. } => 1,
+            Packet::ReplayPiece { .. } => 2,
+            Packet::Evict { .. } => 3,
+            Packet::EvictKeys { .. } => 4,
+            Packet::ForceEvict { .. } => 5,
+            Packet::Finish(..) => 6,
+            Packet::AddNode { .. } => 7,
+            Packet::RemoveNodes { .. } => 8,
+            Packet::AddBaseColumn { .. } => 9,
+            Packet::DropBaseColumn { .. } => 10,
+            Packet::UpdateEgress { .. } => 11,
+            Packet::ExposeReaders { .. } => 12,
+            Packet::TakeOverReader { .. } => 13,
+            Packet::RemoveEgressTx { .. } => 14,
+            Packet::UpdateSharder { .. } => 15,
+            Packet::AddStreamer { .. } => 16,
+            Packet::PrepareState { .. } => 17,
+            Packet::AddIndex { .. } => 18,
+            Packet::SeedBase { .. } => 19,
+            Packet::ModifyOperator { .. } => 20,
+            Packet::AddUnionParent { .. } => 21,
+            Packet::FeedEgress { .. } => 22,
+            Packet::StateSizeProbe { .. } => 23,
+            Packet::SetupReplayPath { .. } => 24,
+            Packet::RequestPartialReplay { .. } => 25,
+            Packet::RequestReaderReplay { .. } => 26,
+            Packet::StartReplay { .. } => 27,
+            Packet::Ready { .. } => 28,
+            Packet::Quit => 29,
+            Packet::Spin => 30,
+            Packet::GetStatistics => 31,
+            Packet::GetMetrics => 32,
+            Packet::UpdateStateSize => 33,
+            Packet::GetChecksum { .. } => 34,
+            Packet::GetDigest { .. } => 35,
+            Packet::Checkpoint { .. } => 36,
+            Packet::LoadCheckpoint { .. } => 37,
+            Packet::RecoverBase { .. } => 38,
+            Packet::Capture { .. } => 39,
+            Packet::GetRows { .. } => 40,
+            Packet::DrainDeadLetters => 41,
+            Packet::HoldWrites(..) => 42,
+            Packet::Quiesce { .. } => 43,
+            Packet::Barrier { .. } => 44,
+            Packet::AddStandby { .. } => 45,
+            Packet::SwitchEgress { .. } => 46,
+            Packet::ListNodes { .. } => 47,
+            Packet::Adopt { .. } => 48,
+            Packet::Pause => 49,
+            Packet::Resume => 50,
+            Packet::StartMove { .. } => 51,
+            Packet::ShipMove { .. } => 52,
+            Packet::CancelMove => 53,
+            Packet::FinishMove { .. } => 54,
+            Packet::Redirect { .. } => 55,
+            Packet::Redirected { .. } => 56,
+            Packet::CatchUp { .. } => 57,
+            Packet::PrepareAuxState { .. } => 58,
+        }
+    }
+
+    /// The name of the kind of packet this is, for logging.
+    pub fn kind(&self) -> &'static str {
+        PACKET_KINDS[self.kind_index()]
+    }
+
     pub fn is_regular(&self) -> bool {
         match *self {
             Packet::Message { .. } => true,
@@ -307,6 +711,14 @@ impl Packet {
         }
     }
 
+    /// The number of records the packet carries, which is zero for packets that don't carry any.
+    pub fn records(&self) -> usize {
+        match *self {
+            Packet::Message { ref data, .. } | Packet::ReplayPiece { ref data, .. } => data.len(),
+            _ => 0,
+        }
+    }
+
     pub fn swap_data(&mut self, new_data: Records) -> Records {
         use std::mem;
         let inner = match *self {
@@ -335,12 +747,18 @@ impl Packet {
                 ref data,
                 ref tracer,
                 ref senders,
+                written,
+                seq,
+                ref markers,
             } => Packet::Message {
                 link: link.clone(),
                 src: None,
                 data: data.clone(),
                 tracer: tracer.clone(),
                 senders: senders.clone(),
+                written,
+                seq,
+                markers: markers.clone(),
             },
             Packet::ReplayPiece {
                 ref link,
@@ -357,6 +775,24 @@ impl Packet {
         }
     }
 
+    /// The markers of the writes that this update completes the effects of, if it is an update.
+    pub fn markers_mut(&mut self) -> Option<&mut Vec<u64>> {
+        match *self {
+            Packet::Message {
+                ref mut markers, ..
+            } => Some(markers),
+            _ => None,
+        }
+    }
+
+    /// Whether this is an update that clients are waiting to see the effects of in some reader.
+    pub fn has_markers(&self) -> bool {
+        match *self {
+            Packet::Message { ref markers, .. } => !markers.is_empty(),
+            _ => false,
+        }
+    }
+
     pub fn trace(&self, event: PacketEvent) {
         match *self {
             Packet::Message {
@@ -375,6 +811,20 @@ impl Packet {
         }
     }
 
+    /// Forget where this update, or piece of a full replay, was in the sequence of packets sent
+    /// over the last egress-to-ingress edge it crossed, as it is about to cross an edge that isn't
+    /// numbered.
+    pub fn clear_seq(&mut self) {
+        match *self {
+            Packet::Message { ref mut seq, .. } => *seq = None,
+            Packet::ReplayPiece {
+                context: ReplayPieceContext::Regular { ref mut cut, .. },
+                ..
+            } => *cut = None,
+            _ => {}
+        }
+    }
+
     pub fn tracer(&mut self) -> Option<&mut Tracer> {
         match *self {
             Packet::Message { ref mut tracer, .. } => Some(tracer),
@@ -399,10 +849,7 @@ impl fmt::Debug for Packet {
                 tag.id(),
                 data.len()
             ),
-            ref p => {
-                use std::mem;
-                write!(f, "Packet::Control({:?})", mem::discriminant(p))
-            }
+            ref p => write!(f, "Packet::{}", p.kind()),
         }
     }
 }
@@ -420,6 +867,49 @@ pub enum ControlReplyPacket {
         HashMap<petgraph::graph::NodeIndex, noria::debug::stats::NodeStats>,
     ),
     Booted(usize, SocketAddr),
+    /// The nodes whose state was written to (or is available in) the domain's checkpoint file, or
+    /// `None` if the checkpoint could not be written (or there is no matching checkpoint).
+    Checkpointed(Option<Vec<petgraph::graph::NodeIndex>>),
+    /// A checksum of the rows materialized at a node, or `None` if it isn't materialized.
+    Checksum(Option<u64>),
+    /// The number of rows materialized at a node and their checksum, or `None` if it isn't
+    /// materialized.
+    Digest(Option<(usize, u64)>),
+    /// A full replay has finished, after bringing the given number of records into the domain.
+    Replayed(usize),
+    /// A base was seeded with the given number of rows, or the rows were rejected.
+    Seeded(Result<usize, noria::WriteError>),
+    /// The file that the domain has started capturing to, `None` if it has stopped capturing, or
+    /// why it could not start.
+    Capturing(Result<Option<PathBuf>, String>),
+    /// The current values of the domain's metrics, and of those of its nodes and readers.
+    Metrics(noria::debug::metrics::MetricsSnapshot),
+    /// A base was recovered by applying the given number of writes from its write-ahead log, or
+    /// the log could not be read.
+    Recovered(Result<usize, String>),
+    /// Every row materialized at a node, or `None` if the node isn't fully materialized.
+    Rows(Option<Vec<Vec<DataType>>>),
+    /// The packets that the domains of the worker could not deliver, oldest first.
+    DeadLetters(Vec<noria::debug::dead_letters::DeadLetter>),
+    /// The given shard has received the barrier with the given identifier from every shard it
+    /// was to receive it from, and processed and sent on everything that came before it.
+    Quiesced {
+        id: u64,
+        shard: usize,
+    },
+    /// The global and local index of every node that the given shard has.
+    Nodes {
+        shard: usize,
+        nodes: Vec<(petgraph::graph::NodeIndex, LocalNodeIndex)>,
+    },
+    /// The domain refused a control packet that it would otherwise have acknowledged, for the
+    /// given reason, and left everything as it was.
+    Failed(ControlError),
+    /// The builder of a copy of the domain, with a snapshot of its state, to move the domain to
+    /// another worker with.
+    Snapshot(Box<domain::DomainBuilder>),
+    /// A domain has finished moving, after logging the given number of updates for its copy.
+    Moved(usize),
 }
 
 impl ControlReplyPacket {
@@ -433,3 +923,49 @@ impl ControlReplyPacket {
         ControlReplyPacket::Ack(())
     }
 }
+
+/// Why a domain refused a control packet.
+///
+/// The packets that add nodes, prepare their state, ready them and start replays are checked
+/// before the domain acts on them, so that a controller that has lost track of the domain gets an
+/// error back rather than a domain that crashes.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ControlError {
+    /// The domain has no such node, or the node has been removed.
+    NoSuchNode(LocalNodeIndex),
+    /// The node is not a reader, but was asked to prepare reader state.
+    NotAReader(LocalNodeIndex),
+    /// The node already has state, and was asked to create it.
+    AlreadyMaterialized(LocalNodeIndex),
+    /// The node has no state, and was asked to replay it.
+    NotMaterialized(LocalNodeIndex),
+    /// The domain's checkpoint has nothing for the node.
+    NotInCheckpoint(LocalNodeIndex),
+    /// The domain was told to start a replay along a path that it was never told about, or that
+    /// starts at another node.
+    NoSuchReplayPath(Tag),
+    /// The domain does not know how to reach the given shard of another domain.
+    Unreachable(DomainIndex, usize),
+    /// The domain was told to go on with a move that it was never told to start, or that failed.
+    NotMoving,
+    /// The node's operator keeps no auxiliary state, but the node was asked to set some up.
+    NoAuxState(LocalNodeIndex),
+}
+
+impl fmt::Display for ControlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ControlError::NoSuchNode(n) => write!(f, "no node {}", n),
+            ControlError::NotAReader(n) => write!(f, "node {} is not a reader", n),
+            ControlError::AlreadyMaterialized(n) => write!(f, "node {} already has state", n),
+            ControlError::NotMaterialized(n) => write!(f, "node {} has no state", n),
+            ControlError::NotInCheckpoint(n) => write!(f, "node {} is not in the checkpoint", n),
+            ControlError::NoSuchReplayPath(tag) => write!(f, "no replay path {}", tag.id()),
+            ControlError::Unreachable(d, shard) => {
+                write!(f, "domain {}.{} is unreachable", d.index(), shard)
+            }
+            ControlError::NotMoving => write!(f, "the domain is not being moved"),
+            ControlError::NoAuxState(n) => write!(f, "node {} keeps no auxiliary state", n),
+        }
+    }
+}
@@ -6,8 +6,10 @@ pub use common::*;
 pub use noria::internal::*;
 pub use ops::NodeOperator;
 pub use petgraph::graph::NodeIndex;
-pub use processing::Ingredient;
-pub(crate) use processing::{Miss, ProcessingResult, RawProcessingResult, ReplayContext};
+pub use processing::{Ingredient, RowBound, TimerRequest};
+pub(crate) use processing::{
+    Malformed, Miss, ProcessingResult, RawProcessingResult, ReplayContext,
+};
 
 // graph types
 pub use node::Node;
@@ -15,23 +17,35 @@ pub type Edge = ();
 pub type Graph = petgraph::Graph<Node, Edge>;
 
 // dataflow types
+pub use noria::channel::WriteAck;
 pub use noria::debug::trace::{Event, PacketEvent, Tracer};
 pub use noria::Input;
 pub use payload::{Packet, ReplayPathSegment, SourceChannelIdentifier};
+pub use IndexType;
+pub use MaterializationHint;
+pub use Placement;
+pub use PublishPolicy;
+pub use ReplayPriority;
 pub use Sharding;
+pub use StateBackend;
 
 // domain local state
-pub use state::{LookupResult, MemoryState, PersistentState, RecordResult, Row, State};
+pub use state::{AuxState, LookupResult, MemoryState, PersistentState, RecordResult, Row, State};
+// a domain's nodes and their state are found by indexing directly with their local indices, which
+// are dense, rather than by hashing them
 pub type StateMap = Map<Box<State>>;
+pub type AuxStateMap = Map<AuxState>;
 pub type DomainNodes = Map<cell::RefCell<Node>>;
 pub type ReplicaAddr = (DomainIndex, usize);
 
 // persistence configuration
 pub use DurabilityMode;
 pub use PersistenceParameters;
+pub use SyncPolicy;
+pub use WalParameters;
 
 /// Channel coordinator type specialized for domains
 pub type ChannelCoordinator = noria::channel::ChannelCoordinator<(DomainIndex, usize), Box<Packet>>;
 pub trait Executor {
-    fn send_back(&mut self, client: SourceChannelIdentifier, ack: ());
+    fn send_back(&mut self, client: SourceChannelIdentifier, ack: WriteAck);
 }
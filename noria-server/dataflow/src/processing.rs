@@ -1,6 +1,9 @@
 use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
+use std::time::Duration;
 
+use noria::filter::Value;
+use noria::ColumnType;
 use ops;
 use prelude::*;
 
@@ -46,6 +49,92 @@ impl Miss {
     }
 }
 
+/// A record that an operator could not process, and why.
+#[derive(Debug)]
+pub(crate) struct Malformed {
+    /// The ancestor the record came from, or the node itself if it is one of the node's own
+    /// records that its materialization could not apply.
+    pub(crate) from: LocalNodeIndex,
+    pub(crate) record: Record,
+    pub(crate) reason: String,
+}
+
+/// Take the records that `op` can't process out of `rs`, which came from the ancestor `from`
+/// (known as `from_global` in the graph). A record is malformed if it lacks a column that `op`
+/// reads from that ancestor, or if `op.validate` finds fault with it. Positive and negative records
+/// are checked alike.
+pub(crate) fn take_malformed<I>(
+    op: &I,
+    from: LocalNodeIndex,
+    from_global: NodeIndex,
+    rs: &mut Records,
+) -> Vec<Malformed>
+where
+    I: Ingredient + ?Sized,
+{
+    let width = op
+        .referenced_columns()
+        .into_iter()
+        .filter(|&(ni, _)| ni == from_global)
+        .map(|(_, c)| c + 1)
+        .max()
+        .unwrap_or(0);
+
+    let mut malformed = Vec::new();
+    let keep: Vec<bool> = rs
+        .iter()
+        .map(|r| {
+            let checked = if r.len() < width {
+                Err(format!(
+                    "expected at least {} columns, got {}",
+                    width,
+                    r.len()
+                ))
+            } else {
+                op.validate(from, r)
+            };
+            match checked {
+                Ok(()) => true,
+                Err(reason) => {
+                    malformed.push(Malformed {
+                        from,
+                        record: r.clone(),
+                        reason,
+                    });
+                    false
+                }
+            }
+        })
+        .collect();
+
+    if !malformed.is_empty() {
+        rs.retain_marked(&keep);
+    }
+    malformed
+}
+
+/// Check that column `col` of `record` exists and holds an integer or `NULL`.
+pub(crate) fn expect_integer(record: &[DataType], col: usize) -> Result<(), String> {
+    match record.get(col) {
+        Some(&DataType::Int(..)) | Some(&DataType::BigInt(..)) | Some(&DataType::None) => Ok(()),
+        Some(v) => Err(format!(
+            "column {} holds {:?}, which is not an integer",
+            col, v
+        )),
+        None => Err(format!("column {} is missing", col)),
+    }
+}
+
+/// Check that column `col` of `record` exists and holds a number or `NULL`.
+pub(crate) fn expect_numeric(record: &[DataType], col: usize) -> Result<(), String> {
+    match record.get(col) {
+        Some(&DataType::Real(..)) => Ok(()),
+        Some(v) => expect_integer(record, col)
+            .map_err(|_| format!("column {} holds {:?}, which is not a number", col, v)),
+        None => Err(format!("column {} is missing", col)),
+    }
+}
+
 pub struct ProcessingResult {
     pub(crate) results: Records,
     pub(crate) misses: Vec<Miss>,
@@ -75,7 +164,7 @@ pub enum ReplayContext {
 }
 
 impl ReplayContext {
-    fn key(&self) -> Option<&[usize]> {
+    pub(crate) fn key(&self) -> Option<&[usize]> {
         if let ReplayContext::Partial { ref key_cols, .. } = *self {
             Some(&key_cols[..])
         } else {
@@ -84,6 +173,43 @@ impl ReplayContext {
     }
 }
 
+/// What an operator says about how many rows it holds, compared to the ancestor that its rows come
+/// from. The variants are ordered from the most to the least that is known.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RowBound {
+    /// The very same rows as the ancestor.
+    Same,
+    /// As many rows as the ancestor, though not necessarily the same ones.
+    AsMany,
+    /// At most as many rows as the ancestor, and some if the ancestor has any.
+    AtMostSome,
+    /// At most as many rows as the ancestor, possibly none.
+    AtMost,
+    /// Nothing is known about how many rows there are.
+    Unknown,
+}
+
+impl RowBound {
+    /// What is known about how many rows there are after two operators, one reading the other.
+    pub fn then(self, other: RowBound) -> RowBound {
+        ::std::cmp::max(self, other)
+    }
+}
+
+/// A timer that an operator asks its domain to set or cancel (see `Ingredient::take_timers`).
+///
+/// An operator knows its timers by tokens of its own choosing. Setting a timer with a token that
+/// is already in use replaces the timer that was set with it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimerRequest {
+    /// Fire once, `after` from now.
+    Once { token: u64, after: Duration },
+    /// Fire every `every` from now on, until cancelled.
+    Every { token: u64, every: Duration },
+    /// Cancel the timer with the given token, if it is set.
+    Cancel(u64),
+}
+
 pub trait Ingredient
 where
     Self: Send,
@@ -144,8 +270,18 @@ where
     /// The provided arguments give mappings from global to local addresses.
     fn on_commit(&mut self, you: NodeIndex, remap: &HashMap<NodeIndex, IndexPair>);
 
+    /// Point this operator, which has not been connected yet, at other ancestors.
+    ///
+    /// Each ancestor the operator refers to is replaced by the one it maps to. This is how an
+    /// operator that was recorded in one graph is added to another, where its ancestors have
+    /// different indices.
+    fn reparent(&mut self, parents: &HashMap<NodeIndex, IndexPair>);
+
     /// Process a single incoming message, optionally producing an update to be propagated to
     /// children.
+    ///
+    /// If the operator keeps auxiliary state (see `uses_aux_state`), it finds it in `aux` under its
+    /// own local index, just as it finds materialized state in `states`.
     fn on_input(
         &mut self,
         from: LocalNodeIndex,
@@ -154,6 +290,7 @@ where
         replay_key_cols: Option<&[usize]>,
         domain: &DomainNodes,
         states: &StateMap,
+        aux: &mut AuxStateMap,
     ) -> ProcessingResult;
 
     fn on_input_raw(
@@ -164,6 +301,7 @@ where
         replay: &ReplayContext,
         domain: &DomainNodes,
         states: &StateMap,
+        aux: &mut AuxStateMap,
     ) -> RawProcessingResult {
         RawProcessingResult::Regular(self.on_input(
             from,
@@ -172,9 +310,82 @@ where
             replay.key(),
             domain,
             states,
+            aux,
         ))
     }
 
+    /// Check that a record that came from the given ancestor holds values this operator can
+    /// process, if the operator can't process just any value. The domain takes records that fail
+    /// the check out of a batch before the operator sees it, and deals with them as the graph is
+    /// configured to (see `MalformedRecords`).
+    ///
+    /// Records are known to have every column that `referenced_columns` lists for the ancestor.
+    fn validate(&self, _from: LocalNodeIndex, _record: &[DataType]) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Produce the next piece of output that was held back while processing regular (i.e.,
+    /// non-replay) input, if any. The domain keeps calling this after forwarding the output of
+    /// `on_input_raw`, and forwards each piece as a separate message, until it returns `None`.
+    fn next_spilled(&mut self, _domain: &DomainNodes, _states: &StateMap) -> Option<Records> {
+        None
+    }
+
+    /// The number of times this operator has held back output to produce it piece by piece.
+    fn spilled_keys(&self) -> u64 {
+        0
+    }
+
+    /// Hold back output beyond about `records` records for a single regular update, to produce
+    /// it through `next_spilled`, if this operator can. The domain cuts whatever output is still
+    /// larger than that into pieces itself, but only after the operator has produced it all.
+    fn limit_output(&mut self, _records: usize) {}
+
+    /// The columns by which a chunk of a full replay may be split up, so that each part can be fed
+    /// to a separate clone of this operator on another thread. `None` if this operator must see
+    /// every chunk whole on the domain thread.
+    ///
+    /// An operator may only opt in if its output for one key never depends on records or state
+    /// under another key, and if it never looks anything up in other nodes. No columns at all
+    /// means that every record can be processed on its own.
+    fn parallel_replay_key(&self) -> Option<Vec<usize>> {
+        None
+    }
+
+    /// The timers that this operator wants set or cancelled. The domain takes these after every
+    /// call to `on_input_raw`, whether for a regular update or for a replay, and after every call
+    /// to `on_timer`.
+    fn take_timers(&mut self) -> Vec<TimerRequest> {
+        Vec::new()
+    }
+
+    /// Triggered when a timer that this operator set fires. The domain fires timers between the
+    /// packets it handles, so this is never called in the middle of processing an update. Any
+    /// records that are returned are sent on as an update from this node.
+    fn on_timer(
+        &mut self,
+        _token: u64,
+        _domain: &DomainNodes,
+        _states: &StateMap,
+    ) -> Option<Records> {
+        None
+    }
+
+    /// Whether this operator keeps auxiliary state: bookkeeping of its own that is not the same
+    /// shape as the rows it emits, such as the candidates it may emit later. The migration that
+    /// adds the node has its domain set up an empty `AuxState` for it, which the domain hands to
+    /// `on_input` and saves along with its materialized state.
+    fn uses_aux_state(&self) -> bool {
+        false
+    }
+
+    /// Rebuild this operator's auxiliary state from the rows it now holds, once a full replay has
+    /// filled its materialization. `None` keeps the auxiliary state that `on_input` built up while
+    /// processing the replay.
+    fn rebuild_aux(&self, _from_state: &State) -> Option<AuxState> {
+        None
+    }
+
     /// Triggered whenever a replay occurs, to allow the operator to react evict from any auxillary
     /// state other than what is stored in its materialization.
     fn on_eviction(
@@ -237,6 +448,59 @@ where
     // materialization, and returns results even for computed columns.
     fn parent_columns(&self, column: usize) -> Vec<(NodeIndex, Option<usize>)>;
 
+    /// Every column of an ancestor that this operator reads, whether to emit it, to compare it, or
+    /// to key on it, along with the ancestor it belongs to. Used to check that the columns exist
+    /// before the operator is sent to a domain.
+    fn referenced_columns(&self) -> Vec<(NodeIndex, usize)>;
+
+    /// The number of columns this operator emits for records that come from the given ancestor,
+    /// which has `columns` columns, if the operator says so on its own. Operators that emit rows
+    /// from several ancestors as they are should say, so that it can be checked that the ancestors
+    /// fit together.
+    fn emitted_columns(&self, _ancestor: NodeIndex, _columns: usize) -> Option<usize> {
+        None
+    }
+
+    /// The values this operator compares by order (with `<`, `>=`, and the like): columns of an
+    /// ancestor, and constants, each along with the ancestor whose columns they are compared with.
+    /// Used to check that none of them are binary values, which can only be compared for equality,
+    /// before the operator is sent to a domain.
+    fn ordered_values(&self) -> Vec<(NodeIndex, Value)> {
+        Vec::new()
+    }
+
+    /// The columns of an ancestor that this operator only accepts values of a single type in (or
+    /// `NULL`), along with the ancestor they belong to and that type. Used to check the columns
+    /// against the schemas of the bases they come from before the operator is sent to a domain.
+    fn typed_columns(&self) -> Vec<(NodeIndex, usize, ColumnType)> {
+        Vec::new()
+    }
+
+    /// How many rows this operator holds compared to the ancestor that its rows come from, once
+    /// both have seen the same records. Used to check the state that a replay fills.
+    fn row_bound(&self) -> RowBound {
+        RowBound::Unknown
+    }
+
+    /// Every row this operator holds once it has seen all the rows of its ancestors, given here by
+    /// ancestor, or `None` if the operator can't be evaluated outside of a domain.
+    ///
+    /// This computes over whole inputs rather than over updates to them, and so says what the
+    /// updates the operator emits should add up to.
+    fn evaluate(
+        &self,
+        _inputs: &HashMap<NodeIndex, Vec<Vec<DataType>>>,
+    ) -> Option<Vec<Vec<DataType>>> {
+        None
+    }
+
+    /// Whether the operator may go on holding the given row after every row of its ancestors that
+    /// the row stems from is gone, such as a group whose count has dropped to zero. `evaluate`
+    /// leaves such rows out, but holding them isn't wrong.
+    fn may_linger(&self, _row: &[DataType]) -> bool {
+        false
+    }
+
     /// Performance hint: should return true if this operator reduces the size of its input
     fn is_selective(&self) -> bool {
         false
@@ -246,4 +510,13 @@ where
     fn requires_full_materialization(&self) -> bool {
         false
     }
+
+    /// A description of everything that determines what this operator emits, including its
+    /// parents, or `None` if it should never be considered equivalent to another operator.
+    ///
+    /// Two operators with the same fingerprint must produce the same output, so that a migration
+    /// can reuse an existing node instead of adding an equivalent one.
+    fn fingerprint(&self) -> Option<String> {
+        None
+    }
 }
@@ -0,0 +1,301 @@
+use fnv::FnvHashMap;
+
+use common::SizeOf;
+use prelude::*;
+
+/// Bookkeeping that an operator keeps for itself, apart from the rows that it emits and that are
+/// materialized for it (see `Ingredient::uses_aux_state`).
+///
+/// The state is a set of rows under each of a number of keys, and it is up to the operator what
+/// the keys and rows mean. A top-k operator might keep the candidates for each group that didn't
+/// make it into the top k, say, and a count of distinct values the count of each value.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct AuxState {
+    entries: FnvHashMap<Vec<DataType>, Vec<Vec<DataType>>>,
+}
+
+impl AuxState {
+    /// The rows kept under `key`, if there are any.
+    pub fn get(&self, key: &[DataType]) -> Option<&[Vec<DataType>]> {
+        self.entries.get(key).map(|rs| &rs[..])
+    }
+
+    /// The rows kept under `key`, to change as the operator sees fit. A key that ends up with no
+    /// rows is still kept until it is removed.
+    pub fn entry(&mut self, key: Vec<DataType>) -> &mut Vec<Vec<DataType>> {
+        self.entries.entry(key).or_insert_with(Vec::new)
+    }
+
+    /// Replace the rows kept under `key`, returning those that were there before.
+    pub fn insert(
+        &mut self,
+        key: Vec<DataType>,
+        rows: Vec<Vec<DataType>>,
+    ) -> Option<Vec<Vec<DataType>>> {
+        self.entries.insert(key, rows)
+    }
+
+    /// Forget `key` and the rows kept under it.
+    pub fn remove(&mut self, key: &[DataType]) -> Option<Vec<Vec<DataType>>> {
+        self.entries.remove(key)
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// The number of keys that rows are kept under.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The number of rows kept under all keys.
+    pub fn rows(&self) -> usize {
+        self.entries.values().map(Vec::len).sum()
+    }
+}
+
+impl SizeOf for AuxState {
+    fn size_of(&self) -> u64 {
+        use std::mem::size_of;
+        size_of::<Self>() as u64
+    }
+
+    fn deep_size_of(&self) -> u64 {
+        use std::mem::size_of;
+        self.size_of()
+            + self
+                .entries
+                .iter()
+                .map(|(k, rs)| {
+                    k.deep_size_of()
+                        + size_of::<Vec<Vec<DataType>>>() as u64
+                        + rs.iter().map(SizeOf::deep_size_of).sum::<u64>()
+                })
+                .sum::<u64>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bincode;
+    use std::collections::HashMap;
+
+    /// Passes its input through as is, and keeps a tally of how many of the rows it has passed on
+    /// are still there for each value of their first column.
+    struct Tally {
+        src: IndexPair,
+        us: LocalNodeIndex,
+    }
+
+    fn bump(tally: &mut AuxState, value: &DataType, by: i64) {
+        let rows = tally.entry(vec![value.clone()]);
+        if rows.is_empty() {
+            rows.push(vec![0.into()]);
+        }
+        let n: i64 = (&rows[0][0]).into();
+        rows[0][0] = (n + by).into();
+    }
+
+    impl Ingredient for Tally {
+        fn take(&mut self) -> NodeOperator {
+            unreachable!()
+        }
+
+        fn ancestors(&self) -> Vec<NodeIndex> {
+            vec![self.src.as_global()]
+        }
+
+        fn suggest_indexes(&self, _: NodeIndex) -> HashMap<NodeIndex, (Vec<usize>, bool)> {
+            HashMap::new()
+        }
+
+        fn resolve(&self, col: usize) -> Option<Vec<(NodeIndex, usize)>> {
+            Some(vec![(self.src.as_global(), col)])
+        }
+
+        fn description(&self, _: bool) -> String {
+            "tally".into()
+        }
+
+        fn on_connected(&mut self, _: &Graph) {}
+
+        fn on_commit(&mut self, _: NodeIndex, _: &HashMap<NodeIndex, IndexPair>) {}
+
+        fn reparent(&mut self, _: &HashMap<NodeIndex, IndexPair>) {}
+
+        fn on_input(
+            &mut self,
+            _: LocalNodeIndex,
+            rs: Records,
+            _: &mut Tracer,
+            _: Option<&[usize]>,
+            _: &DomainNodes,
+            _: &StateMap,
+            aux: &mut AuxStateMap,
+        ) -> ProcessingResult {
+            let tally = aux.get_mut(self.us).unwrap();
+            for r in rs.iter() {
+                bump(tally, &r[0], if r.is_positive() { 1 } else { -1 });
+            }
+            ProcessingResult {
+                results: rs,
+                misses: Vec::new(),
+            }
+        }
+
+        fn uses_aux_state(&self) -> bool {
+            true
+        }
+
+        fn rebuild_aux(&self, from_state: &State) -> Option<AuxState> {
+            let mut tally = AuxState::default();
+            for r in from_state.cloned_records() {
+                bump(&mut tally, &r[0], 1);
+            }
+            Some(tally)
+        }
+
+        fn parent_columns(&self, column: usize) -> Vec<(NodeIndex, Option<usize>)> {
+            vec![(self.src.as_global(), Some(column))]
+        }
+
+        fn referenced_columns(&self) -> Vec<(NodeIndex, usize)> {
+            vec![(self.src.as_global(), 0)]
+        }
+    }
+
+    // a tally, along with the auxiliary state that a domain would set up for it
+    fn setup() -> (Tally, AuxStateMap) {
+        let us = unsafe { LocalNodeIndex::make(1) };
+        let mut src = IndexPair::from(NodeIndex::new(0));
+        src.set_local(unsafe { LocalNodeIndex::make(0) });
+        let tally = Tally { src, us };
+        let mut aux = AuxStateMap::default();
+        assert!(tally.uses_aux_state());
+        aux.insert(us, AuxState::default());
+        (tally, aux)
+    }
+
+    fn feed(
+        tally: &mut Tally,
+        aux: &mut AuxStateMap,
+        rs: Vec<(Vec<DataType>, bool)>,
+    ) -> Records {
+        let rs: Records = rs.into();
+        let from = *tally.src;
+        let nodes = DomainNodes::default();
+        let states = StateMap::default();
+        let m = tally.on_input(from, rs, &mut None, None, &nodes, &states, aux);
+        m.results
+    }
+
+    fn count(aux: &AuxStateMap, us: LocalNodeIndex, value: i32) -> Option<i64> {
+        aux[us]
+            .get(&[DataType::from(value)])
+            .map(|rows| (&rows[0][0]).into())
+    }
+
+    #[test]
+    fn it_creates_and_updates_aux_state() {
+        let (mut tally, mut aux) = setup();
+        let us = tally.us;
+        assert!(aux[us].is_empty());
+
+        let rs = vec![
+            (vec![1.into(), "a".into()], true),
+            (vec![1.into(), "b".into()], true),
+            (vec![2.into(), "c".into()], true),
+        ];
+        // the tally is kept on the side, and doesn't change what the operator emits
+        let emitted: Records = rs.clone().into();
+        assert_eq!(feed(&mut tally, &mut aux, rs), emitted);
+        assert_eq!(count(&aux, us, 1), Some(2));
+        assert_eq!(count(&aux, us, 2), Some(1));
+        assert_eq!(count(&aux, us, 3), None);
+
+        feed(
+            &mut tally,
+            &mut aux,
+            vec![
+                (vec![1.into(), "a".into()], false),
+                (vec![3.into(), "d".into()], true),
+            ],
+        );
+        assert_eq!(count(&aux, us, 1), Some(1));
+        assert_eq!(count(&aux, us, 3), Some(1));
+        assert_eq!(aux[us].len(), 3);
+        assert_eq!(aux[us].rows(), 3);
+        assert!(aux[us].deep_size_of() > AuxState::default().deep_size_of());
+
+        aux.get_mut(us).unwrap().remove(&[DataType::from(2)]);
+        assert_eq!(count(&aux, us, 2), None);
+    }
+
+    #[test]
+    fn it_rebuilds_aux_state_from_replayed_state() {
+        let (mut tally, mut aux) = setup();
+        let us = tally.us;
+        let rows: Vec<Vec<DataType>> = (0..100)
+            .map(|i| vec![(i % 7).into(), i.into()])
+            .collect();
+
+        // a full replay fills the operator's state with what it emits
+        let mut state = MemoryState::default();
+        state.add_key(&[0], None);
+        let mut rs = feed(
+            &mut tally,
+            &mut aux,
+            rows.iter().cloned().map(|r| (r, true)).collect(),
+        );
+        state.process_records(&mut rs, None);
+
+        // and once it lands, the state can be rebuilt from there alone
+        let rebuilt = tally.rebuild_aux(&state).unwrap();
+        assert_eq!(rebuilt, aux[us]);
+        assert_eq!(rebuilt.len(), 7);
+        assert_eq!(rebuilt.rows(), 7);
+
+        // which is what makes up for auxiliary state that was lost, or never built
+        let mut fresh = AuxStateMap::default();
+        fresh.insert(us, tally.rebuild_aux(&state).unwrap());
+        assert_eq!(count(&fresh, us, 0), Some(15));
+        assert_eq!(count(&fresh, us, 6), Some(14));
+    }
+
+    #[test]
+    fn it_snapshots_and_restores_aux_state() {
+        let (mut tally, mut aux) = setup();
+        let us = tally.us;
+        feed(
+            &mut tally,
+            &mut aux,
+            vec![
+                (vec![1.into(), "a".into()], true),
+                (vec![2.into(), "b".into()], true),
+                (vec![2.into(), "c".into()], true),
+            ],
+        );
+
+        // a domain's auxiliary state is saved and restored with the rest of it
+        let saved = bincode::serialize(&aux).unwrap();
+        let mut restored: AuxStateMap = bincode::deserialize(&saved).unwrap();
+        assert_eq!(restored[us], aux[us]);
+
+        // and the restored copy goes on just like the original
+        let more = vec![
+            (vec![2.into(), "b".into()], false),
+            (vec![4.into(), "d".into()], true),
+        ];
+        feed(&mut tally, &mut aux, more.clone());
+        feed(&mut tally, &mut restored, more);
+        assert_eq!(restored[us], aux[us]);
+        assert_eq!(count(&restored, us, 2), Some(1));
+        assert_eq!(count(&restored, us, 4), Some(1));
+    }
+}
@@ -1,8 +1,6 @@
 use fnv::FnvBuildHasher;
 use rahashmap::HashMap as RaHashMap;
-use std::rc::Rc;
 
-use common::SizeOf;
 use prelude::*;
 
 type FnvHashMap<K, V> = RaHashMap<K, V, FnvBuildHasher>;
@@ -14,6 +12,7 @@ pub enum KeyedState {
     Quad(FnvHashMap<(DataType, DataType, DataType, DataType), Vec<Row>>),
     Quin(FnvHashMap<(DataType, DataType, DataType, DataType, DataType), Vec<Row>>),
     Sex(FnvHashMap<(DataType, DataType, DataType, DataType, DataType, DataType), Vec<Row>>),
+    Many(FnvHashMap<Vec<DataType>, Vec<Row>>),
 }
 
 impl KeyedState {
@@ -25,6 +24,7 @@ impl KeyedState {
             KeyedState::Quad(ref m) => m.is_empty(),
             KeyedState::Quin(ref m) => m.is_empty(),
             KeyedState::Sex(ref m) => m.is_empty(),
+            KeyedState::Many(ref m) => m.is_empty(),
         }
     }
 
@@ -36,6 +36,20 @@ impl KeyedState {
             KeyedState::Quad(ref m) => m.len(),
             KeyedState::Quin(ref m) => m.len(),
             KeyedState::Sex(ref m) => m.len(),
+            KeyedState::Many(ref m) => m.len(),
+        }
+    }
+
+    /// Make room for at least `additional` more keys.
+    pub fn reserve(&mut self, additional: usize) {
+        match *self {
+            KeyedState::Single(ref mut m) => m.reserve(additional),
+            KeyedState::Double(ref mut m) => m.reserve(additional),
+            KeyedState::Tri(ref mut m) => m.reserve(additional),
+            KeyedState::Quad(ref mut m) => m.reserve(additional),
+            KeyedState::Quin(ref mut m) => m.reserve(additional),
+            KeyedState::Sex(ref mut m) => m.reserve(additional),
+            KeyedState::Many(ref mut m) => m.reserve(additional),
         }
     }
 
@@ -47,14 +61,15 @@ impl KeyedState {
             (&KeyedState::Quad(ref m), &KeyType::Quad(ref k)) => m.get(k),
             (&KeyedState::Quin(ref m), &KeyType::Quin(ref k)) => m.get(k),
             (&KeyedState::Sex(ref m), &KeyType::Sex(ref k)) => m.get(k),
+            (&KeyedState::Many(ref m), &KeyType::Many(ref k)) => m.get(k),
             _ => unreachable!(),
         }
     }
 
-    /// Remove all rows for the first key at or after `index`, returning that key along with the
-    /// number of bytes freed. Returns None if already empty.
-    pub fn evict_at_index(&mut self, index: usize) -> Option<(u64, Vec<DataType>)> {
-        let (rs, key) = match *self {
+    /// Remove all rows for the first key at or after `index`, returning those rows along with the
+    /// key. Returns None if already empty.
+    pub fn evict_at_index(&mut self, index: usize) -> Option<(Vec<Row>, Vec<DataType>)> {
+        match *self {
             KeyedState::Single(ref mut m) => m.remove_at_index(index).map(|(k, rs)| (rs, vec![k])),
             KeyedState::Double(ref mut m) => {
                 m.remove_at_index(index).map(|(k, rs)| (rs, vec![k.0, k.1]))
@@ -71,18 +86,12 @@ impl KeyedState {
             KeyedState::Sex(ref mut m) => m
                 .remove_at_index(index)
                 .map(|(k, rs)| (rs, vec![k.0, k.1, k.2, k.3, k.4, k.5])),
-        }?;
-        Some((
-            rs.iter()
-                .filter(|r| Rc::strong_count(&r.0) == 1)
-                .map(SizeOf::deep_size_of)
-                .sum(),
-            key,
-        ))
+            KeyedState::Many(ref mut m) => m.remove_at_index(index).map(|(k, rs)| (rs, k)),
+        }
     }
 
-    /// Remove all rows for the given key, returning the number of bytes freed.
-    pub fn evict(&mut self, key: &[DataType]) -> u64 {
+    /// Remove all rows for the given key, returning them. Returns None if the key is not present.
+    pub fn evict(&mut self, key: &[DataType]) -> Option<Vec<Row>> {
         match *self {
             KeyedState::Single(ref mut m) => m.remove(&(key[0])),
             KeyedState::Double(ref mut m) => m.remove(&(key[0].clone(), key[1].clone())),
@@ -110,14 +119,8 @@ impl KeyedState {
                 key[4].clone(),
                 key[5].clone(),
             )),
+            KeyedState::Many(ref mut m) => m.remove(key),
         }
-        .map(|rows| {
-            rows.iter()
-                .filter(|r| Rc::strong_count(&r.0) == 1)
-                .map(SizeOf::deep_size_of)
-                .sum()
-        })
-        .unwrap_or(0)
     }
 }
 
@@ -131,7 +134,7 @@ impl<'a> Into<KeyedState> for &'a [usize] {
             4 => KeyedState::Quad(FnvHashMap::default()),
             5 => KeyedState::Quin(FnvHashMap::default()),
             6 => KeyedState::Sex(FnvHashMap::default()),
-            x => panic!("invalid compound key of length: {}", x),
+            _ => KeyedState::Many(FnvHashMap::default()),
         }
     }
 }
@@ -1,17 +1,26 @@
-use std::collections::HashMap;
-use std::rc::Rc;
+use fnv::FnvHashMap;
+use std::ops::Bound;
 
 use rand::{self, Rng};
 
 use common::SizeOf;
+use noria::debug::stats::StateSizeStats;
 use prelude::*;
+use state::ordered_state::OrderedState;
 use state::single_state::SingleState;
+use state::{take_unmatched, Freed};
+
+/// The most rows `reserve` will set aside room for at once. Size hints arrive over the network
+/// from other domains, so a bogus one must not make us try to allocate the world.
+const MAX_RESERVE: usize = 1 << 22;
 
 #[derive(Default)]
 pub struct MemoryState {
     state: Vec<SingleState>,
-    by_tag: HashMap<Tag, usize>,
+    ordered: Vec<OrderedState>,
+    by_tag: FnvHashMap<Tag, usize>,
     mem_size: u64,
+    rows: usize,
 }
 
 impl SizeOf for MemoryState {
@@ -64,6 +73,26 @@ impl State for MemoryState {
         }
     }
 
+    fn add_ordered_key(&mut self, column: usize) {
+        assert!(
+            !self.is_partial(),
+            "ordered indexes require full materialization"
+        );
+        if self.ordered.iter().any(|o| o.column() == column) {
+            return;
+        }
+
+        let mut new = OrderedState::new(column);
+        if let Some(existing) = self.state.first() {
+            for rs in existing.values() {
+                for r in rs {
+                    new.insert_row(Row(r.0.clone()));
+                }
+            }
+        }
+        self.ordered.push(new);
+    }
+
     fn is_useful(&self) -> bool {
         !self.state.is_empty()
     }
@@ -72,8 +101,9 @@ impl State for MemoryState {
         self.state.iter().any(|s| s.partial())
     }
 
-    fn process_records(&mut self, records: &mut Records, partial_tag: Option<Tag>) {
+    fn process_records(&mut self, records: &mut Records, partial_tag: Option<Tag>) -> Vec<Record> {
         if self.is_partial() {
+            let mut unmatched = Vec::new();
             records.retain(|r| {
                 // we need to check that we're not erroneously filling any holes
                 // there are two cases here:
@@ -90,28 +120,71 @@ impl State for MemoryState {
                 //    XXX: we could potentially save come computation here in joins by not forcing
                 //    `right` to backfill the lookup key only to then throw the record away
                 match *r {
-                    Record::Positive(ref r) => self.insert(r.clone(), partial_tag),
-                    Record::Negative(ref r) => self.remove(r),
+                    Record::Positive(ref row) => self.insert(row.clone(), partial_tag),
+                    Record::Negative(ref row) => match self.remove(row) {
+                        (false, _) => false,
+                        (true, true) => true,
+                        // the key is there, but every copy of the row is already gone
+                        (true, false) => {
+                            unmatched.push(r.clone());
+                            false
+                        }
+                    },
                 }
             });
+            unmatched
         } else {
-            for r in records.iter() {
+            // bulk loads (and full replays) arrive as large batches, so make room for them in one
+            // go rather than growing each index several times over while inserting.
+            self.reserve(records.len());
+            let mut unmatched = Vec::new();
+            for (i, r) in records.iter().enumerate() {
                 match *r {
                     Record::Positive(ref r) => {
                         let hit = self.insert(r.clone(), None);
                         debug_assert!(hit);
                     }
                     Record::Negative(ref r) => {
-                        let hit = self.remove(r);
-                        debug_assert!(hit);
+                        // there is nothing to remove if every copy of the row is already gone
+                        if !self.remove(r).1 {
+                            unmatched.push(i);
+                        }
                     }
                 }
             }
+            take_unmatched(records, unmatched)
+        }
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        let additional = additional.min(MAX_RESERVE);
+        for s in &mut self.state {
+            s.reserve(additional);
         }
     }
 
     fn rows(&self) -> usize {
-        self.state.iter().map(|s| s.rows()).sum()
+        self.rows
+    }
+
+    fn size_stats(&self) -> StateSizeStats {
+        StateSizeStats {
+            rows: self.rows,
+            bytes: self.mem_size,
+            indexes: self
+                .state
+                .iter()
+                .map(|s| s.size_stats())
+                .chain(self.ordered.iter().map(|o| o.size_stats()))
+                .collect(),
+        }
+    }
+
+    fn checksum(&self) -> u64 {
+        match self.state.first() {
+            Some(s) => noria::checksum(s.values().flat_map(|rs| rs.iter().map(|r| &r[..]))),
+            None => 0,
+        }
     }
 
     fn mark_filled(&mut self, key: Vec<DataType>, tag: &Tag) {
@@ -123,8 +196,8 @@ impl State for MemoryState {
     fn mark_hole(&mut self, key: &[DataType], tag: &Tag) {
         debug_assert!(!self.state.is_empty(), "filling uninitialized index");
         let index = self.by_tag[tag];
-        let freed_bytes = self.state[index].mark_hole(key);
-        self.mem_size = self.mem_size.checked_sub(freed_bytes).unwrap();
+        let freed = self.state[index].mark_hole(key);
+        self.forget(freed);
     }
 
     fn lookup<'a>(&'a self, columns: &[usize], key: &KeyType) -> LookupResult<'a> {
@@ -135,13 +208,27 @@ impl State for MemoryState {
         self.state[index].lookup(key)
     }
 
+    fn lookup_range<'a>(
+        &'a self,
+        column: usize,
+        lower: Bound<&DataType>,
+        upper: Bound<&DataType>,
+    ) -> RecordResult<'a> {
+        let index = self
+            .ordered
+            .iter()
+            .find(|o| o.column() == column)
+            .expect("range lookup on column without ordered index");
+        RecordResult::Owned(index.range(lower, upper).map(|r| r.to_vec()).collect())
+    }
+
     fn keys(&self) -> Vec<Vec<usize>> {
         self.state.iter().map(|s| s.key().to_vec()).collect()
     }
 
     fn cloned_records(&self) -> Vec<Vec<DataType>> {
         fn fix<'a>(rs: &'a Vec<Row>) -> impl Iterator<Item = Vec<DataType>> + 'a {
-            rs.iter().map(|r| Vec::clone(&**r))
+            rs.iter().map(|r| r.to_vec())
         }
 
         assert!(!self.state[0].partial());
@@ -151,9 +238,9 @@ impl State for MemoryState {
     fn evict_random_keys(&mut self, count: usize) -> (&[usize], Vec<Vec<DataType>>, u64) {
         let mut rng = rand::thread_rng();
         let index = rng.gen_range(0, self.state.len());
-        let (bytes_freed, keys) = self.state[index].evict_random_keys(count, &mut rng);
-        self.mem_size = self.mem_size.saturating_sub(bytes_freed);
-        (self.state[index].key(), keys, bytes_freed)
+        let (freed, keys) = self.state[index].evict_random_keys(count, &mut rng);
+        self.forget(freed);
+        (self.state[index].key(), keys, freed.bytes)
     }
 
     fn evict_keys(&mut self, tag: &Tag, keys: &[Vec<DataType>]) -> Option<(&[usize], u64)> {
@@ -161,9 +248,9 @@ impl State for MemoryState {
         // this can happen if an upstream domain issues an eviction for a replay path that we have
         // been told about, but that has not yet been finalized.
         self.by_tag.get(tag).cloned().map(move |index| {
-            let bytes = self.state[index].evict_keys(keys);
-            self.mem_size = self.mem_size.saturating_sub(bytes);
-            (self.state[index].key(), bytes)
+            let freed = self.state[index].evict_keys(keys);
+            self.forget(freed);
+            (self.state[index].key(), freed.bytes)
         })
     }
 }
@@ -176,7 +263,7 @@ impl MemoryState {
     }
 
     fn insert(&mut self, r: Vec<DataType>, partial_tag: Option<Tag>) -> bool {
-        let r = Rc::new(r);
+        let r = Row::from(r);
 
         if let Some(tag) = partial_tag {
             let i = match self.by_tag.get(&tag) {
@@ -188,31 +275,59 @@ impl MemoryState {
                     return true;
                 }
             };
-            self.mem_size += r.deep_size_of();
-            self.state[i].insert_row(Row(r))
+            let hit = self.state[i].insert_row(r.clone());
+            if hit {
+                self.mem_size += r.deep_size_of();
+                self.rows += 1;
+            }
+            hit
         } else {
             let mut hit_any = false;
             for i in 0..self.state.len() {
-                hit_any |= self.state[i].insert_row(Row(r.clone()));
+                hit_any |= self.state[i].insert_row(r.clone());
+            }
+            for o in &mut self.ordered {
+                o.insert_row(r.clone());
+                hit_any = true;
             }
             if hit_any {
                 self.mem_size += r.deep_size_of();
+                self.rows += 1;
             }
             hit_any
         }
     }
 
-    fn remove(&mut self, r: &[DataType]) -> bool {
+    /// Remove one copy of `r` from every index that has it. Returns whether the key of `r` was
+    /// present in any index, and whether a copy of `r` was found to remove.
+    fn remove(&mut self, r: &[DataType]) -> (bool, bool) {
         let mut hit = false;
+        let mut freed = Freed::default();
+        // if there are several identical copies of `r`, all indices must drop the same one, or
+        // none of the copies they drop is ever freed
+        let mut same = None;
         for s in &mut self.state {
-            if let Some(row) = s.remove_row(r, &mut hit) {
-                if Rc::strong_count(&row.0) == 1 {
-                    self.mem_size = self.mem_size.checked_sub(row.deep_size_of()).unwrap();
-                }
+            if let Some(row) = s.remove_row(r, same, &mut hit) {
+                same = Some(row.as_ptr());
+                freed += Freed::of(&[row]);
+            }
+        }
+        for o in &mut self.ordered {
+            if let Some(row) = o.remove_row(r, same) {
+                hit = true;
+                same = Some(row.as_ptr());
+                freed += Freed::of(&[row]);
             }
         }
+        self.forget(freed);
+
+        (hit, same.is_some())
+    }
 
-        hit
+    /// Account for rows that are no longer in any index.
+    fn forget(&mut self, freed: Freed) {
+        self.mem_size = self.mem_size.checked_sub(freed.bytes).unwrap();
+        self.rows = self.rows.checked_sub(freed.rows).unwrap();
     }
 }
 
@@ -258,6 +373,35 @@ mod tests {
         }
     }
 
+    #[test]
+    fn memory_state_checksum() {
+        let rows: Vec<Vec<DataType>> = vec![
+            vec![1.into(), "A".into()],
+            vec![2.into(), "B".into()],
+            vec![1.into(), "C".into()],
+        ];
+
+        let mut a = MemoryState::default();
+        a.add_key(&[0], None);
+        a.add_key(&[1], None);
+        assert_eq!(a.checksum(), 0);
+        for row in &rows {
+            insert(&mut a, row.clone());
+        }
+        assert_eq!(a.checksum(), noria::checksum(&rows));
+
+        // neither the order of the rows nor how the state is indexed matters
+        let mut b = MemoryState::default();
+        b.add_key(&[1], None);
+        for row in rows.iter().rev() {
+            insert(&mut b, row.clone());
+        }
+        assert_eq!(a.checksum(), b.checksum());
+
+        a.process_records(&mut vec![(rows[2].clone(), false)].into(), None);
+        assert_eq!(a.checksum(), noria::checksum(&rows[..2]));
+    }
+
     #[test]
     fn memory_state_old_records_new_index() {
         let mut state = MemoryState::default();
@@ -271,4 +415,307 @@ mod tests {
             _ => unreachable!(),
         };
     }
+
+    #[test]
+    fn memory_state_caps_reservation() {
+        let mut state = MemoryState::default();
+        state.add_key(&[0], None);
+        state.add_key(&[0, 1], None);
+
+        // an absurd size hint must not abort the domain
+        state.reserve(usize::max_value());
+
+        let row: Vec<DataType> = vec![10.into(), "Cat".into()];
+        insert(&mut state, row.clone());
+        assert_eq!(state.rows(), 1);
+        match state.lookup(&[0], &KeyType::Single(&row[0])) {
+            LookupResult::Some(RecordResult::Borrowed(rows)) => assert_eq!(&*rows[0], &row),
+            _ => unreachable!(),
+        };
+    }
+
+    #[test]
+    fn memory_state_ordered_index() {
+        fn range(
+            state: &MemoryState,
+            lower: Bound<&DataType>,
+            upper: Bound<&DataType>,
+        ) -> Vec<i64> {
+            state
+                .lookup_range(1, lower, upper)
+                .into_iter()
+                .map(|r| (&r[0]).into())
+                .collect()
+        }
+
+        let mut state = MemoryState::default();
+        state.add_key(&[0], None);
+        insert(&mut state, vec![1.into(), 30.into()]);
+        insert(&mut state, vec![2.into(), 10.into()]);
+
+        // existing rows are indexed when the ordered index is added
+        state.add_ordered_key(1);
+        insert(&mut state, vec![3.into(), 20.into()]);
+        insert(&mut state, vec![4.into(), DataType::BigInt(20)]);
+
+        let (ten, twenty) = (DataType::from(10), DataType::from(20));
+        assert_eq!(
+            range(&state, Bound::Unbounded, Bound::Unbounded),
+            vec![2, 3, 4, 1]
+        );
+        assert_eq!(
+            range(&state, Bound::Included(&ten), Bound::Included(&twenty)),
+            vec![2, 3, 4]
+        );
+        assert_eq!(
+            range(&state, Bound::Excluded(&ten), Bound::Excluded(&twenty)),
+            Vec::<i64>::new()
+        );
+        assert_eq!(
+            range(&state, Bound::Excluded(&twenty), Bound::Unbounded),
+            vec![1]
+        );
+
+        // inverted ranges are empty rather than an error
+        assert!(range(&state, Bound::Included(&twenty), Bound::Excluded(&ten)).is_empty());
+        assert!(range(&state, Bound::Excluded(&ten), Bound::Excluded(&ten)).is_empty());
+
+        // removals are reflected in the ordered index
+        let mut delete: Records = vec![(vec![3.into(), 20.into()], false)].into();
+        state.process_records(&mut delete, None);
+        assert_eq!(
+            range(&state, Bound::Included(&ten), Bound::Included(&twenty)),
+            vec![2, 4]
+        );
+    }
+
+    #[test]
+    fn memory_state_compound_keys() {
+        let mut state = MemoryState::default();
+        let wide: Vec<usize> = (0..7).collect();
+        state.add_key(&[0, 1], None);
+        state.add_key(&wide[..], None);
+
+        let a: Vec<DataType> = (0..8).map(DataType::from).collect();
+        let mut b = a.clone();
+        b[6] = 42.into();
+        insert(&mut state, a.clone());
+        insert(&mut state, b.clone());
+
+        match state.lookup(&[0, 1], &KeyType::from(&a[0..2])) {
+            LookupResult::Some(RecordResult::Borrowed(rows)) => assert_eq!(rows.len(), 2),
+            _ => unreachable!(),
+        };
+        match state.lookup(&wide[..], &KeyType::from(&b[0..7])) {
+            LookupResult::Some(RecordResult::Borrowed(rows)) => assert_eq!(&*rows[0], &b),
+            _ => unreachable!(),
+        };
+
+        let mut delete: Records = vec![(a.clone(), false)].into();
+        state.process_records(&mut delete, None);
+        match state.lookup(&wide[..], &KeyType::from(&a[0..7])) {
+            LookupResult::Some(RecordResult::Borrowed(rows)) => assert!(rows.is_empty()),
+            _ => unreachable!(),
+        };
+        match state.lookup(&[0, 1], &KeyType::from(&a[0..2])) {
+            LookupResult::Some(RecordResult::Borrowed(rows)) => assert_eq!(&*rows[0], &b),
+            _ => unreachable!(),
+        };
+    }
+
+    #[test]
+    fn memory_state_partial_compound_key() {
+        let mut state = MemoryState::default();
+        let tag = Tag(1);
+        let wide: Vec<usize> = (0..7).collect();
+        state.add_key(&wide[..], Some(vec![tag]));
+
+        let row: Vec<DataType> = (0..7).map(DataType::from).collect();
+        match state.lookup(&wide[..], &KeyType::from(&row[..])) {
+            LookupResult::Missing => {}
+            _ => unreachable!(),
+        };
+
+        state.mark_filled(row.clone(), &tag);
+        insert(&mut state, row.clone());
+        match state.lookup(&wide[..], &KeyType::from(&row[..])) {
+            LookupResult::Some(RecordResult::Borrowed(rows)) => assert_eq!(&*rows[0], &row),
+            _ => unreachable!(),
+        };
+
+        state.mark_hole(&row[..], &tag);
+        match state.lookup(&wide[..], &KeyType::from(&row[..])) {
+            LookupResult::Missing => {}
+            _ => unreachable!(),
+        };
+    }
+
+    #[test]
+    fn memory_state_size_stats() {
+        use std::mem::size_of;
+
+        let text = "twenty bytes of text";
+        let mut state = MemoryState::default();
+        state.add_key(&[0], None);
+        state.add_key(&[1], None);
+        for i in 0..100 {
+            insert(&mut state, vec![i.into(), text.into()]);
+        }
+
+        // each row is two DataTypes behind two reference counts, and one of them points to the text
+        let row = 2 * size_of::<usize>() + 2 * size_of::<DataType>() + text.len();
+        let expected = 100 * row as u64;
+
+        let stats = state.size_stats();
+        assert_eq!(stats.rows, 100);
+        assert_eq!(state.rows(), 100);
+        // rows are shared between the two indexes, and must only be counted once
+        assert!(stats.bytes >= expected / 2 && stats.bytes <= expected * 2);
+        assert_eq!(stats.indexes.len(), 2);
+        assert_eq!(stats.indexes[0].keys, 100);
+        assert_eq!(stats.indexes[1].keys, 1);
+        for index in &stats.indexes {
+            assert_eq!(index.rows, 100);
+            assert!(index.overhead > 0);
+        }
+        assert!(stats.total_bytes() > stats.bytes);
+
+        // deletes shrink the estimate
+        let mut delete: Records = (0..50)
+            .map(|i| (vec![i.into(), text.into()], false))
+            .collect::<Vec<_>>()
+            .into();
+        state.process_records(&mut delete, None);
+        let after = state.size_stats();
+        assert_eq!(after.rows, 50);
+        assert_eq!(after.bytes, stats.bytes / 2);
+        assert_eq!(after.indexes[0].keys, 50);
+        assert!(after.indexes[0].overhead < stats.indexes[0].overhead);
+    }
+
+    #[test]
+    fn memory_state_size_stats_partial() {
+        let mut state = MemoryState::default();
+        let tag = Tag(1);
+        state.add_key(&[0], Some(vec![tag]));
+
+        state.mark_filled(vec![1.into()], &tag);
+        insert(&mut state, vec![1.into(), 10.into()]);
+        insert(&mut state, vec![1.into(), 20.into()]);
+        // misses a hole, and so isn't stored
+        insert(&mut state, vec![2.into(), 30.into()]);
+
+        let stats = state.size_stats();
+        assert_eq!(stats.rows, 2);
+        assert_eq!(stats.indexes[0].keys, 1);
+
+        // evicted rows are no longer counted
+        state.mark_hole(&[DataType::from(1)], &tag);
+        let stats = state.size_stats();
+        assert_eq!(stats.rows, 0);
+        assert_eq!(stats.bytes, 0);
+        assert_eq!(stats.indexes[0].rows, 0);
+    }
+
+    #[test]
+    fn memory_state_hands_back_negatives_below_zero() {
+        let a = vec![1.into(), "a".into()];
+        let b = vec![2.into(), "b".into()];
+        let mut state = MemoryState::default();
+        state.add_key(&[0], None);
+        insert(&mut state, a.clone());
+
+        let mut rs: Records = vec![
+            (a.clone(), false),
+            (a.clone(), false),
+            (b.clone(), true),
+        ]
+        .into();
+        let gone = state.process_records(&mut rs, None);
+
+        // the second negative has nothing left to remove, and doesn't go any further
+        assert_eq!(gone, vec![Record::Negative(a.clone())]);
+        let expected: Records = vec![(a.clone(), false), (b.clone(), true)].into();
+        assert_eq!(rs, expected);
+        assert_eq!(state.rows(), 1);
+        assert_eq!(state.cloned_records(), vec![b]);
+    }
+
+    #[test]
+    fn partial_memory_state_hands_back_negatives_below_zero() {
+        let tag = Tag(1);
+        let a = vec![1.into(), "a".into()];
+        let b = vec![2.into(), "b".into()];
+        let mut state = MemoryState::default();
+        state.add_key(&[0], Some(vec![tag]));
+        state.mark_filled(vec![1.into()], &tag);
+        insert(&mut state, a.clone());
+
+        let mut rs: Records = vec![
+            (a.clone(), false),
+            (a.clone(), false),
+            (b.clone(), false),
+        ]
+        .into();
+        let gone = state.process_records(&mut rs, None);
+
+        // the negative for a hole is dropped as before, but the one with nothing left to remove
+        // under a filled key is handed back
+        assert_eq!(gone, vec![Record::Negative(a.clone())]);
+        let expected: Records = vec![(a.clone(), false)].into();
+        assert_eq!(rs, expected);
+        assert_eq!(state.rows(), 0);
+    }
+
+    #[test]
+    fn memory_state_holds_net_multiset_of_random_interleavings() {
+        use rand::rngs::StdRng;
+        use rand::SeedableRng;
+        use std::collections::HashMap;
+
+        // few values, so that the same rows come up over and over again, and several indices,
+        // which must all agree on which copy of a row they drop
+        let mut rng = StdRng::from_seed([11; 32]);
+        let mut state = MemoryState::default();
+        state.add_key(&[0], None);
+        state.add_key(&[1], None);
+        state.add_ordered_key(2);
+        let mut net: HashMap<Vec<DataType>, usize> = HashMap::new();
+        for round in 0..500 {
+            let row: Vec<DataType> = (0..3).map(|_| rng.gen_range(0, 3).into()).collect();
+            let positive = rng.gen_range(0, 3) != 0;
+            let expect_gone = {
+                let n = net.entry(row.clone()).or_insert(0);
+                let below_zero = !positive && *n == 0;
+                if positive {
+                    *n += 1;
+                } else if !below_zero {
+                    *n -= 1;
+                }
+                below_zero
+            };
+
+            let gone = state.process_records(&mut vec![(row, positive)].into(), None);
+            assert_eq!(gone.len(), expect_gone as usize, "in round {}", round);
+            let total = net.values().sum::<usize>();
+            assert_eq!(state.rows(), total, "in round {}", round);
+            assert_eq!(state.size_stats().rows, total, "in round {}", round);
+        }
+
+        let mut found = state.cloned_records();
+        found.sort();
+        let mut expected: Vec<_> = net.iter().flat_map(|(r, &n)| vec![r.clone(); n]).collect();
+        expected.sort();
+        assert_eq!(found, expected);
+
+        // once every copy is gone again, none of them is still counted
+        let mut rest: Records = expected
+            .into_iter()
+            .map(|r| (r, false))
+            .collect::<Vec<_>>()
+            .into();
+        assert!(state.process_records(&mut rest, None).is_empty());
+        assert_eq!(state.rows(), 0);
+        assert_eq!(state.deep_size_of(), 0);
+    }
 }
@@ -1,23 +1,36 @@
+mod auxiliary;
 mod keyed_state;
 mod memory_state;
+mod ordered_state;
 mod persistent_state;
 mod single_state;
 
 use std::borrow::Cow;
-use std::ops::Deref;
-use std::rc::Rc;
+use std::ops::{AddAssign, Bound, Deref};
+use std::sync::Arc;
 use std::{slice, vec};
 
 use common::SizeOf;
+use noria::debug::stats::StateSizeStats;
 use prelude::*;
 
+pub use self::auxiliary::AuxState;
 pub use self::memory_state::MemoryState;
 pub use self::persistent_state::PersistentState;
 
-pub trait State: SizeOf + Send {
+/// The rows materialized by a node, and the indices they are kept under.
+///
+/// States are `Sync` so that the replay workers of an operator can all look up into the domain's
+/// states at once (see `Ingredient::parallel_replay_key`). Nothing writes to a state while they do.
+pub trait State: SizeOf + Send + Sync {
     /// Add an index keyed by the given columns and replayed to by the given partial tags.
     fn add_key(&mut self, columns: &[usize], partial: Option<Vec<Tag>>);
 
+    /// Add an index ordered by the given column, which can be used for `lookup_range`.
+    ///
+    /// Ordered indexes can only be added to fully materialized state.
+    fn add_ordered_key(&mut self, column: usize);
+
     /// Returns whether this state is currently keyed on anything. If not, then it cannot store any
     /// infromation and is thus "not useful".
     fn is_useful(&self) -> bool;
@@ -26,7 +39,15 @@ pub trait State: SizeOf + Send {
 
     // Inserts or removes each record into State. Records that miss all indices in partial state
     // are removed from `records` (thus the mutable reference).
-    fn process_records(&mut self, records: &mut Records, partial_tag: Option<Tag>);
+    //
+    // Identical rows are kept once for every time they were inserted, and each negative removes a
+    // single one of them. Negatives for rows that have no copies left are removed from `records`
+    // as well, and returned so that they can be reported, unless their key is a hole.
+    fn process_records(
+        &mut self,
+        records: &mut Records,
+        partial_tag: Option<Tag>,
+    ) -> Vec<Record>;
 
     fn mark_hole(&mut self, key: &[DataType], tag: &Tag);
 
@@ -34,13 +55,46 @@ pub trait State: SizeOf + Send {
 
     fn lookup<'a>(&'a self, columns: &[usize], key: &KeyType) -> LookupResult<'a>;
 
+    /// Returns all rows whose value in `column` lies within the given bounds, in that column's
+    /// order. Panics if there is no ordered index on `column`.
+    fn lookup_range<'a>(
+        &'a self,
+        column: usize,
+        lower: Bound<&DataType>,
+        upper: Bound<&DataType>,
+    ) -> RecordResult<'a>;
+
+    /// Make room for `additional` more rows ahead of a bulk insert, such as a full replay.
+    ///
+    /// This is only a hint: it may come from a remote domain and be wildly off, so
+    /// implementations cap how much they set aside up front rather than fail.
+    fn reserve(&mut self, _additional: usize) {}
+
+    /// The number of distinct rows in this state.
     fn rows(&self) -> usize;
 
+    /// An estimate of how much memory this state takes up, broken down by index.
+    fn size_stats(&self) -> StateSizeStats;
+
+    /// An order-independent checksum of the rows in this state (see `noria::checksum`).
+    ///
+    /// Only the rows that are present count towards the checksum, so the holes in partial state
+    /// are ignored. If partial state has several indices, only the rows in the first one count.
+    fn checksum(&self) -> u64;
+
     fn keys(&self) -> Vec<Vec<usize>>;
 
     /// Return a copy of all records. Panics if the state is only partially materialized.
     fn cloned_records(&self) -> Vec<Vec<DataType>>;
 
+    /// Return a point-in-time view of all records for a full replay. Panics if the state is only
+    /// partially materialized.
+    ///
+    /// State that does not fit in memory should stream its records rather than copy them.
+    fn snapshot(&self) -> Snapshot {
+        Snapshot::Cloned(self.cloned_records())
+    }
+
     /// Evict `count` randomly selected keys, returning key colunms of the index chosen to evict
     /// from along with the keys evicted and the number of bytes evicted.
     fn evict_random_keys(&mut self, count: usize) -> (&[usize], Vec<Vec<DataType>>, u64);
@@ -50,24 +104,124 @@ pub trait State: SizeOf + Send {
     fn evict_keys(&mut self, tag: &Tag, keys: &[Vec<DataType>]) -> Option<(&[usize], u64)>;
 }
 
+/// Whether `v` lies within the given bounds.
+pub(crate) fn within_bounds(
+    v: &DataType,
+    lower: Bound<&DataType>,
+    upper: Bound<&DataType>,
+) -> bool {
+    let above = match lower {
+        Bound::Included(l) => v >= l,
+        Bound::Excluded(l) => v > l,
+        Bound::Unbounded => true,
+    };
+    let below = match upper {
+        Bound::Included(u) => v <= u,
+        Bound::Excluded(u) => v < u,
+        Bound::Unbounded => true,
+    };
+    above && below
+}
+
+/// Whether no value can lie within the given bounds. `BTreeMap::range` panics on such bounds,
+/// whereas range lookups should just come back empty.
+pub(crate) fn is_empty_range(lower: Bound<&DataType>, upper: Bound<&DataType>) -> bool {
+    match (lower, upper) {
+        (Bound::Included(l), Bound::Included(u)) => l > u,
+        (Bound::Included(l), Bound::Excluded(u))
+        | (Bound::Excluded(l), Bound::Included(u))
+        | (Bound::Excluded(l), Bound::Excluded(u)) => l >= u,
+        _ => false,
+    }
+}
+
+/// Take the negative records at the given positions, which had no row left to remove, out of
+/// `records`.
+pub(super) fn take_unmatched(records: &mut Records, at: Vec<usize>) -> Vec<Record> {
+    if at.is_empty() {
+        return Vec::new();
+    }
+    let unmatched = at.iter().map(|&i| records[i].clone()).collect();
+    let mut keep = vec![true; records.len()];
+    for i in at {
+        keep[i] = false;
+    }
+    records.retain_marked(&keep);
+    unmatched
+}
+
+/// The rows dropped when removing rows from a single index, along with how many bytes they took
+/// up. Rows that are still held by another index of the same state are not counted.
+#[derive(Clone, Copy, Debug, Default)]
+pub(super) struct Freed {
+    pub(super) rows: usize,
+    pub(super) bytes: u64,
+}
+
+impl Freed {
+    pub(super) fn of(rs: &[Row]) -> Self {
+        let mut freed = Freed::default();
+        for r in rs.iter().filter(|r| Arc::strong_count(&r.0) == 1) {
+            freed.rows += 1;
+            freed.bytes += r.deep_size_of();
+        }
+        freed
+    }
+}
+
+impl AddAssign for Freed {
+    fn add_assign(&mut self, other: Freed) {
+        self.rows += other.rows;
+        self.bytes += other.bytes;
+    }
+}
+
+/// A row held by a state, and shared between all of its indices.
+///
+/// The values are stored right after the reference counts, in a single allocation that is exactly
+/// as large as the row, so a row costs no more than its values plus two counts.
+///
+/// TODO: `Record`s and reader rows still hold their values in a `Vec<DataType>` of their own, so a
+/// row is copied into a new allocation every time it is stored here or in a reader. Storing them
+/// as shared slices as well would let all three share one allocation.
 #[derive(Clone, Debug)]
-pub struct Row(pub(crate) Rc<Vec<DataType>>);
+pub struct Row(pub(crate) Arc<[DataType]>);
 
-unsafe impl Send for Row {}
+impl From<Vec<DataType>> for Row {
+    fn from(r: Vec<DataType>) -> Self {
+        Row(r.into())
+    }
+}
 
 impl Deref for Row {
-    type Target = Vec<DataType>;
+    type Target = [DataType];
     fn deref(&self) -> &Self::Target {
         &*self.0
     }
 }
+impl Row {
+    /// The position in `rs` of the copy of `r` to remove. That is the copy at `same`, if another
+    /// index has already removed a copy held at that address, so that all indices of a state remove
+    /// the same one of several identical rows. Otherwise it is any row equal to `r`.
+    pub(super) fn position(
+        rs: &[Row],
+        r: &[DataType],
+        same: Option<*const DataType>,
+    ) -> Option<usize> {
+        same.and_then(|p| rs.iter().position(|row| row.as_ptr() == p))
+            .or_else(|| rs.iter().position(|row| &row[..] == r))
+    }
+}
+
 impl SizeOf for Row {
     fn size_of(&self) -> u64 {
         use std::mem::size_of;
         size_of::<Self>() as u64
     }
     fn deep_size_of(&self) -> u64 {
-        (*self.0).deep_size_of()
+        use std::mem::size_of;
+        let counts = 2 * size_of::<usize>() as u64;
+        counts + self.iter().map(SizeOf::deep_size_of).sum::<u64>()
     }
 }
 
@@ -111,6 +265,54 @@ impl<'a> Iterator for RecordResultIterator<'a> {
             RecordResultIterator::Owned(iter) => iter.next().map(|r| Cow::from(r)),
         }
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self {
+            RecordResultIterator::Borrowed(iter) => iter.size_hint(),
+            RecordResultIterator::Owned(iter) => iter.size_hint(),
+        }
+    }
+
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        match self {
+            RecordResultIterator::Borrowed(iter) => iter.nth(n).map(|r| Cow::from(&r[..])),
+            RecordResultIterator::Owned(iter) => iter.nth(n).map(|r| Cow::from(r)),
+        }
+    }
+}
+
+/// All the records of a fully materialized state, as of when the snapshot was taken.
+pub enum Snapshot {
+    /// A copy of all the records, which can be handed off to another thread.
+    Cloned(Vec<Vec<DataType>>),
+    /// Records that are read lazily, and so must be consumed by the domain that owns the state.
+    Streamed(RowStream),
+}
+
+/// An iterator over records that reads them from the underlying state as it goes.
+///
+/// The iterator may borrow resources from the state it was created from, and so must be dropped
+/// before that state is.
+pub struct RowStream(Box<Iterator<Item = Vec<DataType>>>);
+
+// RocksDB iterators can be moved between threads, they just can't be shared. since a stream is
+// only ever consumed by the domain that owns its state, that's all we need.
+unsafe impl Send for RowStream {}
+
+impl RowStream {
+    pub(crate) fn new<I>(rows: I) -> Self
+    where
+        I: Iterator<Item = Vec<DataType>> + 'static,
+    {
+        RowStream(Box::new(rows))
+    }
+}
+
+impl Iterator for RowStream {
+    type Item = Vec<DataType>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next()
+    }
 }
 
 pub enum LookupResult<'a> {
@@ -0,0 +1,83 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+use noria::debug::stats::IndexSizeStats;
+use prelude::*;
+use state::is_empty_range;
+
+/// An index over a single column that keeps rows in the column's order, so that they can be
+/// looked up by range. Only used for fully materialized state.
+pub(super) struct OrderedState {
+    column: usize,
+    state: BTreeMap<DataType, Vec<Row>>,
+    rows: usize,
+}
+
+impl OrderedState {
+    pub(super) fn new(column: usize) -> Self {
+        OrderedState {
+            column,
+            state: BTreeMap::new(),
+            rows: 0,
+        }
+    }
+
+    pub(super) fn column(&self) -> usize {
+        self.column
+    }
+
+    pub(super) fn insert_row(&mut self, r: Row) {
+        self.state
+            .entry(r[self.column].clone())
+            .or_insert_with(Vec::new)
+            .push(r);
+        self.rows += 1;
+    }
+
+    /// Remove one row equal to `r`, preferring the copy at `same` (see `Row::position`), and
+    /// return it if it was present.
+    pub(super) fn remove_row(
+        &mut self,
+        r: &[DataType],
+        same: Option<*const DataType>,
+    ) -> Option<Row> {
+        let key = &r[self.column];
+        let (row, now_empty) = {
+            let rs = self.state.get_mut(key)?;
+            let i = Row::position(rs, r, same)?;
+            (rs.swap_remove(i), rs.is_empty())
+        };
+        if now_empty {
+            self.state.remove(key);
+        }
+        self.rows -= 1;
+        Some(row)
+    }
+
+    /// All rows whose indexed value lies within the given bounds, in order of that value.
+    pub(super) fn range<'a>(
+        &'a self,
+        lower: Bound<&DataType>,
+        upper: Bound<&DataType>,
+    ) -> Box<Iterator<Item = &'a Row> + 'a> {
+        if is_empty_range(lower, upper) {
+            return Box::new(None::<&Row>.into_iter());
+        }
+
+        Box::new(self.state.range((lower, upper)).flat_map(|(_, rs)| rs))
+    }
+
+    /// An estimate of the memory used by this index, not counting the rows themselves.
+    pub(super) fn size_stats(&self) -> IndexSizeStats {
+        use std::mem::size_of;
+
+        let per_key = size_of::<DataType>() + size_of::<Vec<Row>>();
+        let keys = self.state.len();
+        IndexSizeStats {
+            columns: vec![self.column],
+            keys,
+            rows: self.rows,
+            overhead: (keys * per_key + self.rows * size_of::<Row>()) as u64,
+        }
+    }
+}
@@ -2,11 +2,15 @@ use bincode;
 use itertools::Itertools;
 use rocksdb::{self, ColumnFamily, SliceTransform, SliceTransformFns, WriteBatch};
 use serde;
+use std::collections::HashSet;
+use std::mem;
+use std::ops::Bound;
 use tempfile::{tempdir, TempDir};
 
 use common::SizeOf;
+use noria::debug::stats::{IndexSizeStats, StateSizeStats};
 use prelude::*;
-use state::{RecordResult, State};
+use state::{take_unmatched, within_bounds, RecordResult, RowStream, Snapshot, State};
 
 // Incremented on each PersistentState initialization so that IndexSeq
 // can be used to create unique identifiers for rows.
@@ -48,6 +52,9 @@ impl PersistentIndex {
 }
 
 /// PersistentState stores data in RocksDB.
+///
+/// It is used for bases whose writes are persisted, and for any other node whose state is kept on
+/// disk (`StateBackend::Disk`). State for the latter is deleted when it is dropped.
 pub struct PersistentState {
     db_opts: rocksdb::Options,
     // We don't really want DB to be an option, but doing so lets us drop it manually in
@@ -59,6 +66,9 @@ pub struct PersistentState {
     // read during lookups. When `self.has_unique_index` is true the first index is a primary key,
     // and all its keys are considered unique.
     indices: Vec<PersistentIndex>,
+    // Columns with ordered indexes. Row keys are bincode-encoded, which doesn't preserve the order
+    // of DataType, so range lookups on these columns scan all rows instead.
+    ordered: Vec<usize>,
     seq: IndexSeq,
     epoch: IndexEpoch,
     has_unique_index: bool,
@@ -67,6 +77,11 @@ pub struct PersistentState {
     _directory: Option<TempDir>,
 }
 
+// a RocksDB database and its column family handles can be used from several threads at once, and
+// the options are only read once the database is open. the bindings just don't say so for the
+// column family handles kept in `indices`.
+unsafe impl Sync for PersistentState {}
+
 struct PrefixTransform;
 
 // SliceTransforms are used to create prefixes of all inserted keys, which can then be used for
@@ -137,28 +152,54 @@ impl SizeOf for PersistentState {
 }
 
 impl State for PersistentState {
-    fn process_records(&mut self, records: &mut Records, partial_tag: Option<Tag>) {
+    fn process_records(&mut self, records: &mut Records, partial_tag: Option<Tag>) -> Vec<Record> {
         assert!(partial_tag.is_none(), "PersistentState can't be partial");
         if records.len() == 0 {
-            return;
+            return Vec::new();
         }
 
         let mut batch = WriteBatch::default();
-        for r in records.iter() {
+        let mut pending = false;
+        // With a unique key, the keys of the rows that were written to `batch`.
+        let mut touched = HashSet::new();
+        let mut unmatched = Vec::new();
+        for (i, r) in records.iter().enumerate() {
             match *r {
                 Record::Positive(ref r) => {
+                    if self.has_unique_index {
+                        touched.insert(self.unique_key(r));
+                    }
                     self.insert(&mut batch, r);
                 }
                 Record::Negative(ref r) => {
-                    self.remove(&mut batch, r);
+                    // We have to look up the row to remove, and it may have been inserted or
+                    // removed earlier in this batch. With a unique key, that can only be so if
+                    // its key was written to.
+                    let key = if self.has_unique_index {
+                        Some(self.unique_key(r))
+                    } else {
+                        None
+                    };
+                    let flush = match key {
+                        Some(ref key) => touched.contains(key),
+                        None => pending,
+                    };
+                    if flush {
+                        self.write(mem::replace(&mut batch, WriteBatch::default()));
+                        touched.clear();
+                    }
+                    if !self.remove(&mut batch, r) {
+                        unmatched.push(i);
+                    } else if let Some(key) = key {
+                        touched.insert(key);
+                    }
                 }
             }
+            pending = true;
         }
 
-        // Sync the writes to RocksDB's WAL:
-        let mut opts = rocksdb::WriteOptions::default();
-        opts.set_sync(true);
-        self.db.as_ref().unwrap().write_opt(batch, &opts).unwrap();
+        self.write(batch);
+        take_unmatched(records, unmatched)
     }
 
     fn lookup(&self, columns: &[usize], key: &KeyType) -> LookupResult {
@@ -191,6 +232,25 @@ impl State for PersistentState {
         LookupResult::Some(RecordResult::Owned(data))
     }
 
+    fn lookup_range<'a>(
+        &'a self,
+        column: usize,
+        lower: Bound<&DataType>,
+        upper: Bound<&DataType>,
+    ) -> RecordResult<'a> {
+        assert!(
+            self.ordered.contains(&column),
+            "range lookup on column without ordered index"
+        );
+        let mut rows: Vec<Vec<DataType>> = self
+            .all_rows()
+            .map(|(_, ref value)| bincode::deserialize(&value).unwrap())
+            .filter(|row: &Vec<DataType>| within_bounds(&row[column], lower, upper))
+            .collect();
+        rows.sort_by(|a, b| a[column].cmp(&b[column]));
+        RecordResult::Owned(rows)
+    }
+
     fn add_key(&mut self, columns: &[usize], partial: Option<Vec<Tag>>) {
         assert!(partial.is_none(), "Bases can't be partial");
         let existing = self
@@ -236,6 +296,12 @@ impl State for PersistentState {
         self.persist_meta();
     }
 
+    fn add_ordered_key(&mut self, column: usize) {
+        if !self.ordered.contains(&column) {
+            self.ordered.push(column);
+        }
+    }
+
     fn keys(&self) -> Vec<Vec<usize>> {
         self.indices
             .iter()
@@ -249,6 +315,15 @@ impl State for PersistentState {
             .collect()
     }
 
+    // The iterator reads from an implicit RocksDB snapshot taken when it is created, so writes
+    // that happen while the replay is streaming out don't show up in it.
+    fn snapshot(&self) -> Snapshot {
+        Snapshot::Streamed(RowStream::new(
+            self.all_rows()
+                .map(|(_, value)| bincode::deserialize(&value).unwrap()),
+        ))
+    }
+
     // Returns a row count estimate from RocksDB.
     fn rows(&self) -> usize {
         let db = self.db.as_ref().unwrap();
@@ -260,6 +335,36 @@ impl State for PersistentState {
         (total_keys / self.indices.len())
     }
 
+    fn checksum(&self) -> u64 {
+        if self.indices.is_empty() {
+            return 0;
+        }
+
+        noria::checksum(
+            self.all_rows()
+                .map(|(_, value)| bincode::deserialize::<Vec<DataType>>(&value).unwrap()),
+        )
+    }
+
+    // RocksDB manages its own indexes, so all we have to go on are its estimates.
+    fn size_stats(&self) -> StateSizeStats {
+        let rows = self.rows();
+        StateSizeStats {
+            rows,
+            bytes: self.deep_size_of(),
+            indexes: self
+                .indices
+                .iter()
+                .map(|index| IndexSizeStats {
+                    columns: index.columns.clone(),
+                    keys: 0,
+                    rows,
+                    overhead: 0,
+                })
+                .collect(),
+        }
+    }
+
     fn is_useful(&self) -> bool {
         self.indices.len() > 0
     }
@@ -350,6 +455,7 @@ impl PersistentState {
         let mut state = Self {
             seq: 0,
             indices,
+            ordered: Vec::new(),
             has_unique_index: primary_key.is_some(),
             epoch: meta.epoch,
             db_opts: opts,
@@ -493,6 +599,7 @@ impl PersistentState {
             KeyType::Quad(k) => serialize(k, extra),
             KeyType::Quin(k) => serialize(k, extra),
             KeyType::Sex(k) => serialize(k, extra),
+            KeyType::Many(k) => serialize(k, extra),
         }
     }
 
@@ -500,6 +607,11 @@ impl PersistentState {
         Self::serialize_raw_key(key, ())
     }
 
+    // The key that `r` is stored under when the primary index is unique.
+    fn unique_key(&self, r: &[DataType]) -> Vec<u8> {
+        Self::serialize_prefix(&Self::build_key(r, &self.indices[0].columns))
+    }
+
     fn serialize_secondary(key: &KeyType, raw_primary: &[u8]) -> Vec<u8> {
         let mut bytes = Self::serialize_raw_key(key, ());
         bytes.extend_from_slice(raw_primary);
@@ -551,7 +663,15 @@ impl PersistentState {
         }
     }
 
-    fn remove(&self, batch: &mut WriteBatch, r: &[DataType]) {
+    fn write(&self, batch: WriteBatch) {
+        // Sync the writes to RocksDB's WAL:
+        let mut opts = rocksdb::WriteOptions::default();
+        opts.set_sync(true);
+        self.db.as_ref().unwrap().write_opt(batch, &opts).unwrap();
+    }
+
+    // Removes one copy of `r`, or returns false if there are none left to remove.
+    fn remove(&self, batch: &mut WriteBatch, r: &[DataType]) -> bool {
         let db = self.db.as_ref().unwrap();
         let pk_index = &self.indices[0];
         let value_cf = pk_index.column_family;
@@ -572,30 +692,37 @@ impl PersistentState {
         let pk = Self::build_key(&r, &pk_index.columns);
         let prefix = Self::serialize_prefix(&pk);
         if self.has_unique_index {
-            if cfg!(debug_assertions) {
-                // This would imply that we're trying to delete a different row than the one we
-                // found when we resolved the DeleteRequest in Base. This really shouldn't happen,
-                // but we'll leave a check here in debug mode for now.
-                let raw = db
-                    .get_cf(value_cf, &prefix)
-                    .unwrap()
-                    .expect("tried removing non-existant primary key row");
-                let value: Vec<DataType> = bincode::deserialize(&*raw).unwrap();
-                assert_eq!(r, &value[..], "tried removing non-matching primary key row");
+            // The key is unique, so the only row that can be a copy of `r` is the one stored under
+            // it. If there is none, or it is a different row, there is nothing to remove.
+            let found = match db.get_cf(value_cf, &prefix).unwrap() {
+                Some(raw) => {
+                    let value: Vec<DataType> = bincode::deserialize(&*raw).unwrap();
+                    r == &value[..]
+                }
+                None => false,
+            };
+            if found {
+                do_remove(&prefix[..]);
             }
-
-            do_remove(&prefix[..]);
+            found
         } else {
-            let (key, _value) = db
+            // Identical rows are each stored under a sequence number of their own, so this only
+            // removes one of them.
+            let found = db
                 .prefix_iterator_cf(value_cf, &prefix)
                 .unwrap()
                 .find(|(_, raw_value)| {
                     let value: Vec<DataType> = bincode::deserialize(&*raw_value).unwrap();
                     r == &value[..]
-                })
-                .expect("tried removing non-existant row");
-            do_remove(&key[..]);
-        };
+                });
+            match found {
+                Some((key, _value)) => {
+                    do_remove(&key[..]);
+                    true
+                }
+                None => false,
+            }
+        }
     }
 }
 
@@ -811,6 +938,30 @@ mod tests {
         }
     }
 
+    #[test]
+    fn persistent_state_counts_identical_rows() {
+        let mut state = setup_persistent("persistent_state_counts_identical_rows");
+        let row: Vec<DataType> = vec![0.into(), 0.into()];
+        state.add_key(&[0], None);
+        state.process_records(&mut vec![row.clone(), row.clone()].into(), None);
+
+        // each negative removes a single copy, and one that finds none is handed back
+        let mut rs: Records = vec![(row.clone(), false), (row.clone(), false)].into();
+        assert!(state.process_records(&mut rs, None).is_empty());
+        let mut rs: Records = vec![(row.clone(), false)].into();
+        assert_eq!(
+            state.process_records(&mut rs, None),
+            vec![Record::Negative(row.clone())]
+        );
+        assert!(rs.is_empty());
+        assert!(state.cloned_records().is_empty());
+
+        state.process_records(&mut vec![row.clone(), row.clone()].into(), None);
+        let mut rs: Records = vec![(row.clone(), false)].into();
+        state.process_records(&mut rs, None);
+        assert_eq!(state.cloned_records(), vec![row]);
+    }
+
     #[test]
     fn persistent_state_different_indices() {
         let mut state = setup_persistent("persistent_state_different_indices");
@@ -946,6 +1097,45 @@ mod tests {
         }
     }
 
+    #[test]
+    fn persistent_state_hands_back_negatives_below_zero() {
+        let first: Vec<DataType> = vec![10.into(), "Cat".into()];
+        let other: Vec<DataType> = vec![10.into(), "Dog".into()];
+        let missing: Vec<DataType> = vec![20.into(), "Cat".into()];
+        let params = PersistenceParameters::default();
+        for &unique in &[false, true] {
+            let name = format!("persistent_state_hands_back_negatives_below_zero_{}", unique);
+            let key: &[usize] = &[0];
+            let primary_key = if unique { Some(key) } else { None };
+            let mut state = PersistentState::new(name, primary_key, &params);
+            state.add_key(key, None);
+            insert(&mut state, first.clone());
+
+            let mut rs: Records = vec![
+                (first.clone(), false),
+                (first.clone(), false),
+                (other.clone(), false),
+                (missing.clone(), false),
+            ]
+            .into();
+            let gone = state.process_records(&mut rs, None);
+            assert_eq!(
+                gone,
+                vec![
+                    Record::Negative(first.clone()),
+                    Record::Negative(other.clone()),
+                    Record::Negative(missing.clone()),
+                ]
+            );
+            let expected: Records = vec![(first.clone(), false)].into();
+            assert_eq!(rs, expected);
+            match state.lookup(key, &KeyType::Single(&first[0])) {
+                LookupResult::Some(RecordResult::Owned(rows)) => assert_eq!(rows.len(), 0),
+                _ => unreachable!(),
+            }
+        }
+    }
+
     #[test]
     fn persistent_state_is_useful() {
         let mut state = setup_persistent("persistent_state_is_useful");
@@ -1068,6 +1258,52 @@ mod tests {
         assert_eq!(state.cloned_records(), vec![first, second]);
     }
 
+    #[test]
+    fn persistent_state_snapshot() {
+        let mut state = setup_persistent("persistent_state_snapshot");
+        let first: Vec<DataType> = vec![10.into(), "Cat".into()];
+        let second: Vec<DataType> = vec![20.into(), "Cat".into()];
+        state.add_key(&[0], None);
+        state.process_records(&mut vec![first.clone()].into(), None);
+
+        let snapshot = state.snapshot();
+        state.process_records(&mut vec![second.clone()].into(), None);
+        state.process_records(&mut vec![(first.clone(), false)].into(), None);
+
+        // the snapshot is unaffected by later writes
+        match snapshot {
+            Snapshot::Streamed(rows) => assert_eq!(rows.collect::<Vec<_>>(), vec![first]),
+            Snapshot::Cloned(..) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn persistent_state_ordered_index() {
+        let mut state = setup_persistent("persistent_state_ordered_index");
+        let rows: Vec<Vec<DataType>> = vec![
+            vec![1.into(), 30.into()],
+            vec![2.into(), 10.into()],
+            vec![3.into(), 20.into()],
+        ];
+        state.add_key(&[0], None);
+        state.add_ordered_key(1);
+        state.process_records(&mut rows.clone().into(), None);
+
+        let (ten, twenty) = (DataType::from(10), DataType::from(20));
+        let found: Vec<_> = state
+            .lookup_range(1, Bound::Included(&ten), Bound::Unbounded)
+            .into_iter()
+            .map(|r| r.into_owned())
+            .collect();
+        assert_eq!(
+            found,
+            vec![rows[1].clone(), rows[2].clone(), rows[0].clone()]
+        );
+
+        let found = state.lookup_range(1, Bound::Excluded(&ten), Bound::Excluded(&twenty));
+        assert_eq!(found.len(), 0);
+    }
+
     #[test]
     fn persistent_state_drop() {
         let path = {
@@ -1130,6 +1366,29 @@ mod tests {
         }
     }
 
+    #[test]
+    fn persistent_state_process_records_removes_own_inserts() {
+        let mut state = setup_persistent("persistent_state_process_records_removes_own_inserts");
+        let mut records: Records = vec![
+            (vec![1.into(), "A".into()], true),
+            (vec![1.into(), "B".into()], true),
+            (vec![1.into(), "A".into()], false),
+            (vec![1.into(), "B".into()], false),
+            (vec![1.into(), "C".into()], true),
+        ]
+        .into();
+
+        state.add_key(&[0], None);
+        state.process_records(&mut records, None);
+
+        match state.lookup(&[0], &KeyType::Single(&1.into())) {
+            LookupResult::Some(RecordResult::Owned(rows)) => {
+                assert_eq!(rows, vec![vec![1.into(), "C".into()]])
+            }
+            _ => unreachable!(),
+        };
+    }
+
     #[test]
     fn persistent_state_prefix_transform() {
         let mut state = setup_persistent("persistent_state_prefix_transform");
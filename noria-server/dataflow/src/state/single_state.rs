@@ -1,9 +1,9 @@
 use rand::{Rng, ThreadRng};
-use std::rc::Rc;
 
-use common::SizeOf;
+use noria::debug::stats::IndexSizeStats;
 use prelude::*;
 use state::keyed_state::KeyedState;
+use state::Freed;
 
 pub struct SingleState {
     key: Vec<usize>,
@@ -103,27 +103,31 @@ impl SingleState {
                     rs @ Entry::Vacant(..) => rs.or_default().push(r),
                 }
             }
+            KeyedState::Many(ref mut map) => {
+                let key = self.key.iter().map(|&k| r[k].clone()).collect::<Vec<_>>();
+                match map.entry(key) {
+                    Entry::Occupied(mut rs) => rs.get_mut().push(r),
+                    Entry::Vacant(..) if self.partial => return false,
+                    rs @ Entry::Vacant(..) => rs.or_default().push(r),
+                }
+            }
         }
 
         self.rows += 1;
         true
     }
 
-    /// Attempt to remove row `r`.
-    pub fn remove_row(&mut self, r: &[DataType], hit: &mut bool) -> Option<Row> {
+    /// Attempt to remove one copy of row `r`, preferring the copy at `same` (see `Row::position`).
+    /// `hit` is set if the key of `r` is present, whether or not a copy of `r` was.
+    pub fn remove_row(
+        &mut self,
+        r: &[DataType],
+        same: Option<*const DataType>,
+        hit: &mut bool,
+    ) -> Option<Row> {
         let mut do_remove = |self_rows: &mut usize, rs: &mut Vec<Row>| -> Option<Row> {
             *hit = true;
-            let rm = if rs.len() == 1 {
-                // it *should* be impossible to get a negative for a record that we don't have
-                debug_assert_eq!(r, &rs[0][..]);
-                Some(rs.swap_remove(0))
-            } else {
-                if let Some(i) = rs.iter().position(|rsr| &rsr[..] == r) {
-                    Some(rs.swap_remove(i))
-                } else {
-                    None
-                }
-            };
+            let rm = Row::position(rs, r, same).map(|i| rs.swap_remove(i));
 
             if rm.is_some() {
                 *self_rows = self_rows.checked_sub(1).unwrap();
@@ -190,6 +194,12 @@ impl SingleState {
                     return do_remove(&mut self.rows, rs);
                 }
             }
+            KeyedState::Many(ref mut map) => {
+                let key = self.key.iter().map(|&k| r[k].clone()).collect::<Vec<_>>();
+                if let Some(ref mut rs) = map.get_mut(&key) {
+                    return do_remove(&mut self.rows, rs);
+                }
+            }
         }
         None
     }
@@ -239,11 +249,12 @@ impl SingleState {
                 ),
                 Vec::new(),
             ),
+            KeyedState::Many(ref mut map) => map.insert(key.collect(), Vec::new()),
         };
         assert!(replaced.is_none());
     }
 
-    pub fn mark_hole(&mut self, key: &[DataType]) -> u64 {
+    pub fn mark_hole(&mut self, key: &[DataType]) -> Freed {
         let removed = match self.state {
             KeyedState::Single(ref mut map) => map.remove(&key[0]),
             KeyedState::Double(ref mut map) => map.remove(&(key[0].clone(), key[1].clone())),
@@ -271,39 +282,63 @@ impl SingleState {
                 key[4].clone(),
                 key[5].clone(),
             )),
+            KeyedState::Many(ref mut map) => map.remove(key),
         };
         // mark_hole should only be called on keys we called mark_filled on
-        removed
-            .unwrap()
-            .iter()
-            .filter(|r| Rc::strong_count(&r.0) == 1)
-            .map(SizeOf::deep_size_of)
-            .sum()
+        self.removed(&removed.unwrap())
     }
 
-    /// Evict `count` randomly selected keys from state and return them along with the number of
-    /// bytes freed.
+    /// Evict `count` randomly selected keys from state and return them along with what was freed.
     pub fn evict_random_keys(
         &mut self,
         count: usize,
         rng: &mut ThreadRng,
-    ) -> (u64, Vec<Vec<DataType>>) {
-        let mut bytes_freed = 0;
+    ) -> (Freed, Vec<Vec<DataType>>) {
+        let mut freed = Freed::default();
         let mut keys = Vec::with_capacity(count);
         for _ in 0..count {
-            if let Some((n, key)) = self.state.evict_at_index(rng.gen()) {
-                bytes_freed += n;
+            if let Some((rs, key)) = self.state.evict_at_index(rng.gen()) {
+                freed += self.removed(&rs);
                 keys.push(key);
             } else {
                 break;
             }
         }
-        (bytes_freed, keys)
+        (freed, keys)
+    }
+
+    /// Evicts the specified keys from this state, returning what was freed.
+    pub fn evict_keys(&mut self, keys: &[Vec<DataType>]) -> Freed {
+        let mut freed = Freed::default();
+        for k in keys {
+            if let Some(rs) = self.state.evict(k) {
+                freed += self.removed(&rs);
+            }
+        }
+        freed
+    }
+
+    /// Account for rows that have been taken out of this index.
+    fn removed(&mut self, rs: &[Row]) -> Freed {
+        self.rows = self.rows.checked_sub(rs.len()).unwrap();
+        Freed::of(rs)
     }
 
-    /// Evicts a specified key from this state, returning the number of bytes freed.
-    pub fn evict_keys(&mut self, keys: &[Vec<DataType>]) -> u64 {
-        keys.iter().map(|k| self.state.evict(k)).sum()
+    /// An estimate of the memory used by this index, not counting the rows themselves.
+    pub fn size_stats(&self) -> IndexSizeStats {
+        use std::mem::size_of;
+
+        // every key holds a copy of the key columns and a list of rows, and the hash table keeps
+        // a hash alongside each entry. every row in those lists is a pointer.
+        let per_key =
+            self.key.len() * size_of::<DataType>() + size_of::<Vec<Row>>() + size_of::<u64>();
+        let keys = self.state.len();
+        IndexSizeStats {
+            columns: self.key.clone(),
+            keys,
+            rows: self.rows,
+            overhead: (keys * per_key + self.rows * size_of::<Row>()) as u64,
+        }
     }
 
     pub fn values<'a>(&'a self) -> Box<Iterator<Item = &'a Vec<Row>> + 'a> {
@@ -314,8 +349,19 @@ impl SingleState {
             KeyedState::Quad(ref map) => Box::new(map.values()),
             KeyedState::Quin(ref map) => Box::new(map.values()),
             KeyedState::Sex(ref map) => Box::new(map.values()),
+            KeyedState::Many(ref map) => Box::new(map.values()),
         }
     }
+    /// Make room for `additional` more rows, assuming (at worst) that each has a distinct key.
+    ///
+    /// Partial indices are filled one key at a time, so there is nothing to gain from reserving
+    /// ahead for them.
+    pub fn reserve(&mut self, additional: usize) {
+        if !self.partial {
+            self.state.reserve(additional);
+        }
+    }
+
     pub fn key(&self) -> &[usize] {
         &self.key
     }
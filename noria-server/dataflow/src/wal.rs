@@ -0,0 +1,444 @@
+//! Write-ahead logs of the writes made to base nodes.
+//!
+//! When `PersistenceParameters::wal` is set, the domain that holds a base appends each batch of
+//! writes to the base to the base's log before it processes the batch, so that the writes are in
+//! the log before anything downstream of the base sees them, or they are acknowledged. Batches
+//! are appended as group commit lets them through, so one append, and one sync, covers every
+//! write that group commit merged into a batch.
+//!
+//! A base's log is a series of segment files named `{prefix}-{base}-{shard}.wal.{first}`, where
+//! `first` is the sequence number of the first entry in the segment. Entries are numbered one
+//! after the other, starting at 1. Once a segment reaches `WalParameters::segment_size`, the log
+//! moves on to a new one, and a segment is deleted once all of its entries are covered by a
+//! checkpoint of the base's state.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time;
+
+use bincode;
+use fnv::FnvHashMap;
+use noria::TableOperation;
+use prelude::*;
+
+/// A batch of writes in a base's log.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct LogEntry {
+    /// The entry's number in the log.
+    pub seq: u64,
+    /// The writes, as they were handed to the base.
+    pub ops: Vec<TableOperation>,
+}
+
+/// A `LogEntry` as it is written, without copying the writes.
+#[derive(Serialize)]
+struct Appended<'a> {
+    seq: u64,
+    ops: &'a [TableOperation],
+}
+
+fn log_dir(params: &PersistenceParameters) -> PathBuf {
+    params.log_dir.clone().unwrap_or_else(|| PathBuf::from("."))
+}
+
+fn stem(params: &PersistenceParameters, base: &str, shard: usize) -> String {
+    format!("{}-{}-{}.wal.", params.log_prefix, base, shard)
+}
+
+/// The segment files of the log of the given shard of a base, along with the sequence number of
+/// the first entry in each, ordered by that number.
+pub fn segments(
+    params: &PersistenceParameters,
+    base: &str,
+    shard: usize,
+) -> io::Result<Vec<(u64, PathBuf)>> {
+    let stem = stem(params, base, shard);
+    let mut segments = Vec::new();
+    for e in fs::read_dir(log_dir(params))? {
+        let e = e?;
+        let first = e
+            .file_name()
+            .to_str()
+            .filter(|name| name.starts_with(&stem))
+            .and_then(|name| name[stem.len()..].parse().ok());
+        if let Some(first) = first {
+            segments.push((first, e.path()));
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+/// Every entry in the given segment file, in order.
+///
+/// An entry that was only partly written when the process writing the log stopped is ignored,
+/// along with anything after it.
+pub fn read_segment<P: AsRef<Path>>(path: P) -> bincode::Result<Vec<LogEntry>> {
+    let mut f = io::BufReader::new(fs::File::open(path)?);
+    let mut entries = Vec::new();
+    loop {
+        match bincode::deserialize_from(&mut f) {
+            Ok(entry) => entries.push(entry),
+            Err(box bincode::ErrorKind::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Ok(entries);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Every entry in the log of the given shard of a base, in order.
+pub fn read(
+    params: &PersistenceParameters,
+    base: &str,
+    shard: usize,
+) -> bincode::Result<Vec<LogEntry>> {
+    let mut entries = Vec::new();
+    for (_, path) in segments(params, base, shard)? {
+        entries.extend(read_segment(path)?);
+    }
+    Ok(entries)
+}
+
+/// The log of one base that a domain is appending to.
+struct BaseLog {
+    // the stem of the log's segment files, including their directory
+    stem: PathBuf,
+    // every segment, the last of which is appended to
+    segments: VecDeque<(u64, PathBuf)>,
+    file: fs::File,
+    size: usize,
+    next: u64,
+    // when the oldest append that has not been synced was made
+    unsynced: Option<time::Instant>,
+    params: WalParameters,
+    delete_on_drop: bool,
+}
+
+impl BaseLog {
+    /// Open the log of the given shard of a base, carrying on from the last entry in any segments
+    /// that are already there.
+    fn open(params: &PersistenceParameters, base: &str, shard: usize) -> bincode::Result<Self> {
+        let mut segments: VecDeque<_> = segments(params, base, shard)?.into_iter().collect();
+        let mut next = 1;
+        for &(first, ref path) in segments.iter().rev() {
+            match read_segment(path)?.last() {
+                Some(last) => {
+                    next = last.seq + 1;
+                    break;
+                }
+                None => next = first,
+            }
+        }
+
+        // the last segment may end with a partly written entry, so start a new one rather than
+        // appending after it
+        segments.retain(|&(first, _)| first != next);
+        let stem = log_dir(params).join(stem(params, base, shard));
+        let path = PathBuf::from(format!("{}{}", stem.display(), next));
+        let file = fs::File::create(&path)?;
+        segments.push_back((next, path));
+
+        Ok(BaseLog {
+            stem,
+            segments,
+            file,
+            size: 0,
+            next,
+            unsynced: None,
+            params: params.wal.clone().unwrap_or_default(),
+            delete_on_drop: params.mode == DurabilityMode::DeleteOnExit,
+        })
+    }
+
+    fn append(&mut self, ops: &[TableOperation]) -> bincode::Result<u64> {
+        let seq = self.next;
+        let entry = bincode::serialize(&Appended { seq, ops })?;
+        self.file.write_all(&entry)?;
+        self.size += entry.len();
+        self.next += 1;
+
+        match self.params.sync {
+            SyncPolicy::EveryWrite => self.file.sync_data()?,
+            SyncPolicy::Interval(_) => {
+                self.unsynced.get_or_insert_with(time::Instant::now);
+            }
+            SyncPolicy::Never => {}
+        }
+
+        if self.size >= self.params.segment_size {
+            self.rotate()?;
+        }
+        Ok(seq)
+    }
+
+    /// Move on to a new segment, starting with the next entry.
+    fn rotate(&mut self) -> io::Result<()> {
+        self.sync()?;
+        let path = PathBuf::from(format!("{}{}", self.stem.display(), self.next));
+        self.file = fs::File::create(&path)?;
+        self.segments.push_back((self.next, path));
+        self.size = 0;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        if self.params.sync != SyncPolicy::Never {
+            self.file.sync_data()?;
+        }
+        self.unsynced = None;
+        Ok(())
+    }
+
+    fn sync_deadline(&self) -> Option<time::Instant> {
+        match (self.params.sync, self.unsynced) {
+            (SyncPolicy::Interval(every), Some(since)) => Some(since + every),
+            _ => None,
+        }
+    }
+
+    /// Delete every segment whose entries all have sequence numbers of at most `upto`. The
+    /// segment that is being appended to is kept regardless.
+    fn truncate(&mut self, upto: u64) -> io::Result<usize> {
+        let mut deleted = 0;
+        while self.segments.len() > 1 && self.segments[1].0 <= upto + 1 {
+            let (_, path) = self.segments.pop_front().unwrap();
+            fs::remove_file(path)?;
+            deleted += 1;
+        }
+        Ok(deleted)
+    }
+}
+
+impl Drop for BaseLog {
+    fn drop(&mut self) {
+        if self.delete_on_drop {
+            for &(_, ref path) in &self.segments {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+}
+
+/// The write-ahead logs of the bases of a domain shard, if the domain is to keep them.
+pub(crate) struct WriteAheadLogs {
+    params: PersistenceParameters,
+    shard: usize,
+    logs: FnvHashMap<NodeIndex, BaseLog>,
+}
+
+impl WriteAheadLogs {
+    pub(crate) fn new(params: &PersistenceParameters, shard: usize) -> Self {
+        WriteAheadLogs {
+            params: params.clone(),
+            shard,
+            logs: FnvHashMap::default(),
+        }
+    }
+
+    /// Whether writes are to be logged at all.
+    pub(crate) fn enabled(&self) -> bool {
+        self.params.wal.is_some()
+    }
+
+    /// Open the log of the given base, unless it is already open.
+    pub(crate) fn open(&mut self, base: NodeIndex, name: &str) -> bincode::Result<()> {
+        if !self.logs.contains_key(&base) {
+            let log = BaseLog::open(&self.params, name, self.shard)?;
+            self.logs.insert(base, log);
+        }
+        Ok(())
+    }
+
+    /// Append a batch of writes to the log of the given base, opening the log first if this is
+    /// the base's first batch, and return the entry's sequence number. Depending on the sync
+    /// policy, the entry is on disk by the time this returns.
+    pub(crate) fn append(
+        &mut self,
+        base: NodeIndex,
+        name: &str,
+        ops: &[TableOperation],
+    ) -> bincode::Result<u64> {
+        self.open(base, name)?;
+        self.logs.get_mut(&base).unwrap().append(ops)
+    }
+
+    /// The sequence number of the last entry appended to the log of the given base, or 0 if
+    /// nothing has been.
+    pub(crate) fn logged(&self, base: NodeIndex) -> u64 {
+        self.logs.get(&base).map(|l| l.next - 1).unwrap_or(0)
+    }
+
+    /// Sync every log whose sync interval has passed since its oldest unsynced append.
+    pub(crate) fn sync_if_necessary(&mut self) -> io::Result<()> {
+        let now = time::Instant::now();
+        for log in self.logs.values_mut() {
+            if log.sync_deadline().map(|d| d <= now).unwrap_or(false) {
+                log.sync()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// How long until a log should be synced, if any of them have appends that have not been.
+    pub(crate) fn duration_until_sync(&self) -> Option<time::Duration> {
+        let now = time::Instant::now();
+        self.logs
+            .values()
+            .filter_map(|l| l.sync_deadline())
+            .min()
+            .map(|d| {
+                if d > now {
+                    d - now
+                } else {
+                    time::Duration::from_millis(0)
+                }
+            })
+    }
+
+    /// The state of the given base up to and including entry `upto` of its log has been saved,
+    /// so delete the segments that only hold entries up to there. Returns how many were deleted.
+    pub(crate) fn truncate(&mut self, base: NodeIndex, upto: u64) -> io::Result<usize> {
+        match self.logs.get_mut(&base) {
+            Some(log) => log.truncate(upto),
+            None => Ok(0),
+        }
+    }
+
+    /// Stop logging writes to a base that has been removed.
+    pub(crate) fn remove(&mut self, base: NodeIndex) {
+        self.logs.remove(&base);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile;
+
+    fn params(dir: &Path, sync: SyncPolicy, segment_size: usize) -> PersistenceParameters {
+        PersistenceParameters {
+            log_prefix: String::from("wal"),
+            log_dir: Some(dir.to_path_buf()),
+            wal: Some(WalParameters {
+                sync,
+                segment_size,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn insert(i: i32) -> Vec<TableOperation> {
+        vec![TableOperation::Insert(vec![i.into(), "x".into()])]
+    }
+
+    #[test]
+    fn it_rotates_and_reads_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let params = params(dir.path(), SyncPolicy::EveryWrite, 64);
+        let base = NodeIndex::new(1);
+        {
+            let mut wal = WriteAheadLogs::new(&params, 0);
+            for i in 0..10 {
+                assert_eq!(wal.append(base, "a", &insert(i)).unwrap(), i as u64 + 1);
+            }
+            assert_eq!(wal.logged(base), 10);
+        }
+
+        let segs = segments(&params, "a", 0).unwrap();
+        assert!(segs.len() > 2);
+        let entries = read(&params, "a", 0).unwrap();
+        assert_eq!(entries.len(), 10);
+        assert_eq!(
+            entries[3],
+            LogEntry {
+                seq: 4,
+                ops: insert(3)
+            }
+        );
+
+        // reopening carries on from the last entry
+        let mut wal = WriteAheadLogs::new(&params, 0);
+        assert_eq!(wal.append(base, "a", &insert(10)).unwrap(), 11);
+        assert_eq!(read(&params, "a", 0).unwrap().len(), 11);
+    }
+
+    #[test]
+    fn it_ignores_torn_appends() {
+        let dir = tempfile::tempdir().unwrap();
+        let params = params(dir.path(), SyncPolicy::Never, 1 << 20);
+        let base = NodeIndex::new(1);
+        {
+            let mut wal = WriteAheadLogs::new(&params, 0);
+            wal.append(base, "a", &insert(1)).unwrap();
+            wal.append(base, "a", &insert(2)).unwrap();
+        }
+
+        // chop off the end of the last entry, as if the process died while appending it
+        let (_, path) = segments(&params, "a", 0).unwrap().pop().unwrap();
+        let len = fs::metadata(&path).unwrap().len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+        assert_eq!(read(&params, "a", 0).unwrap().len(), 1);
+
+        let mut wal = WriteAheadLogs::new(&params, 0);
+        assert_eq!(wal.append(base, "a", &insert(3)).unwrap(), 2);
+        let seqs: Vec<_> = read(&params, "a", 0)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.seq)
+            .collect();
+        assert_eq!(seqs, vec![1, 2]);
+    }
+
+    #[test]
+    fn it_deletes_checkpointed_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let params = params(dir.path(), SyncPolicy::EveryWrite, 1);
+        let base = NodeIndex::new(1);
+        let mut wal = WriteAheadLogs::new(&params, 0);
+        for i in 0..5 {
+            wal.append(base, "a", &insert(i)).unwrap();
+        }
+        // every entry has a segment of its own, and there is an empty one to append to
+        assert_eq!(segments(&params, "a", 0).unwrap().len(), 6);
+
+        assert_eq!(wal.truncate(base, 3).unwrap(), 3);
+        let firsts: Vec<_> = segments(&params, "a", 0)
+            .unwrap()
+            .into_iter()
+            .map(|(first, _)| first)
+            .collect();
+        assert_eq!(firsts, vec![4, 5, 6]);
+
+        assert_eq!(wal.truncate(base, 5).unwrap(), 2);
+        assert_eq!(wal.truncate(base, 5).unwrap(), 0);
+        assert!(read(&params, "a", 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn it_syncs_on_interval() {
+        let dir = tempfile::tempdir().unwrap();
+        let every = time::Duration::from_millis(50);
+        let params = params(dir.path(), SyncPolicy::Interval(every), 1 << 20);
+        let base = NodeIndex::new(1);
+        let mut wal = WriteAheadLogs::new(&params, 0);
+        assert_eq!(wal.duration_until_sync(), None);
+
+        wal.append(base, "a", &insert(1)).unwrap();
+        assert!(wal.duration_until_sync().unwrap() <= every);
+        wal.sync_if_necessary().unwrap();
+        assert!(wal.duration_until_sync().is_some());
+
+        ::std::thread::sleep(every);
+        wal.sync_if_necessary().unwrap();
+        assert_eq!(wal.duration_until_sync(), None);
+    }
+}
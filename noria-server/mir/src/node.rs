@@ -693,6 +693,7 @@ impl Debug for MirNodeType {
             } => {
                 let op_string = match *kind {
                     AggregationKind::COUNT => format!("|*|({})", on.name.as_str()),
+                    AggregationKind::COUNT_ALL => "|*|(*)".to_owned(),
                     AggregationKind::SUM => format!("𝛴({})", on.name.as_str()),
                 };
                 let group_cols = group_by
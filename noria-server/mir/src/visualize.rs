@@ -105,6 +105,7 @@ impl GraphViz for MirNodeType {
             } => {
                 let op_string = match *kind {
                     AggregationKind::COUNT => format!("\\|*\\|({})", print_col(on)),
+                    AggregationKind::COUNT_ALL => "\\|*\\|(*)".to_owned(),
                     AggregationKind::SUM => format!("𝛴({})", print_col(on)),
                 };
                 let group_cols = group_by
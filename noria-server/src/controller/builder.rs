@@ -1,10 +1,13 @@
+use crate::controller::migrate::graph_log::GraphLog;
 use crate::controller::sql::reuse::ReuseConfigType;
-use crate::controller::{self, ControllerConfig, LocalControllerHandle};
-use dataflow::PersistenceParameters;
+use crate::controller::{self, ControllerConfig, FaultInjector, LocalControllerHandle};
+use dataflow::{Backpressure, ChannelConfig, MalformedRecords, PersistenceParameters};
 use failure;
 use noria::consensus::{Authority, LocalAuthority};
+use noria::debug::plan::DomainStrategy;
 use slog;
 use std::net::IpAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time;
 
@@ -13,6 +16,8 @@ pub struct ControllerBuilder {
     config: ControllerConfig,
     memory_limit: Option<usize>,
     memory_check_frequency: Option<time::Duration>,
+    rebuild: Option<GraphLog>,
+    faults: FaultInjector,
     listen_addr: IpAddr,
     log: slog::Logger,
 }
@@ -24,6 +29,8 @@ impl Default for ControllerBuilder {
             log: slog::Logger::root(slog::Discard, o!()),
             memory_limit: None,
             memory_check_frequency: None,
+            rebuild: None,
+            faults: FaultInjector::new(),
         }
     }
 }
@@ -42,6 +49,43 @@ impl ControllerBuilder {
         self.config.domain_config.replay_batch_timeout = t;
     }
 
+    /// Set how often domains sweep bases with a TTL for expired rows, and the largest number of
+    /// rows a sweep removes from a single base.
+    pub fn set_expiry_sweep(&mut self, interval: time::Duration, batch_size: usize) {
+        assert_ne!(batch_size, 0);
+        self.config.domain_config.expiry_sweep_interval = interval;
+        self.config.domain_config.expiry_batch_size = batch_size;
+    }
+
+    /// Set how many threads each domain may use to push a full replay through operators that
+    /// allow it, such as aggregations and filters. Replays are processed serially by default.
+    pub fn set_replay_workers(&mut self, n: usize) {
+        assert_ne!(n, 0);
+        self.config.domain_config.replay_workers = n;
+    }
+
+    /// Hold back writes to base tables while the domains below them are overloaded, as described
+    /// by `bp`. Writes are admitted no matter how far behind those domains are by default.
+    pub fn set_backpressure(&mut self, bp: Backpressure) {
+        assert!(bp.low < bp.high);
+        self.config.domain_config.backpressure = Some(bp);
+    }
+
+    /// Set how many bytes the connections that domains send and receive packets over buffer. The
+    /// capacities are checked with `ChannelConfig::validate`.
+    pub fn set_channels(&mut self, channels: ChannelConfig) {
+        if let Err(e) = channels.validate() {
+            panic!("{}", e);
+        }
+        self.config.domain_config.channels = channels;
+    }
+
+    /// Choose what domains do with records that reach an operator which can't process them. They
+    /// panic by default.
+    pub fn set_malformed_records(&mut self, policy: MalformedRecords) {
+        self.config.domain_config.malformed = policy;
+    }
+
     /// Set the persistence parameters used by the system.
     pub fn set_persistence(&mut self, p: PersistenceParameters) {
         self.config.persistence = p;
@@ -53,6 +97,8 @@ impl ControllerBuilder {
     }
 
     /// Set sharding policy for all subsequent migrations; `None` disables
+    ///
+    /// With sharding disabled, individual bases can still be sharded with `Migration::shard_base`.
     pub fn set_sharding(&mut self, shards: Option<usize>) {
         self.config.sharding = shards;
     }
@@ -92,6 +138,42 @@ impl ControllerBuilder {
         self.config.threads = Some(threads);
     }
 
+    /// Set how migrations assign the nodes they add to domains (default is
+    /// `DomainStrategy::FewestCuts` with at most 256 nodes per domain).
+    pub fn set_domain_strategy(&mut self, strategy: DomainStrategy) {
+        self.config.domain_strategy = strategy;
+    }
+
+    /// Run the domains of each worker in a single task, and hand them their packets one at a time
+    /// in an order picked by `seed`, so that a run can be repeated with the same interleaving of
+    /// the packets that domains send each other. Meant for tests; see `Scheduler`.
+    pub fn set_deterministic(&mut self, seed: u64) {
+        self.config.deterministic = Some(seed);
+    }
+
+    /// Write a log of the graph to the given file after every migration. The log records what
+    /// each migration added and changed, and a controller can be rebuilt from it with
+    /// `rebuild_from`.
+    pub fn set_graph_log<P: Into<PathBuf>>(&mut self, path: P) {
+        self.config.graph_log = Some(path.into());
+    }
+
+    /// Rebuild the graph recorded in the given log (see `set_graph_log`) once the controller has
+    /// started and enough workers have joined. The log's migrations are replayed in order, and
+    /// the controller doesn't answer requests until they have all been committed.
+    ///
+    /// The controller must start out with an empty graph.
+    pub fn rebuild_from(&mut self, log: GraphLog) {
+        self.rebuild = Some(log);
+    }
+
+    /// Have the controller's domains consult the given injector for faults to inject into the
+    /// packets they receive and send. Keep a clone of the injector to register the faults with
+    /// while the controller runs. By default, no faults are injected.
+    pub fn set_fault_injector(&mut self, faults: FaultInjector) {
+        self.faults = faults;
+    }
+
     /// Build a controller and return a handle to it.
     pub fn build<A: Authority + 'static>(
         self,
@@ -103,6 +185,8 @@ impl ControllerBuilder {
             self.config,
             self.memory_limit,
             self.memory_check_frequency,
+            self.rebuild,
+            self.faults,
             self.log,
         )
     }
@@ -0,0 +1,191 @@
+//! Checks that every fully materialized node holds the rows its operator computes from the rows in
+//! the base tables.
+//!
+//! The graph is walked in topological order. The rows in the bases are taken as they are, and
+//! every other node is expected to hold what its operator computes from the rows of its parents
+//! (see `Ingredient::evaluate`). Nodes that are fully materialized are then compared to what they
+//! are expected to hold, and it is the rows they actually hold that their children are computed
+//! from, so that a divergence is reported where it arises rather than at every node below it.
+//!
+//! Nodes whose rows can't be known, because they aren't materialized and their operator can't be
+//! evaluated outside of a domain, can't be checked, and nor can the nodes computed from them.
+
+use crate::controller::domain_handle::DomainHandle;
+use crate::controller::{WorkerIdentifier, WorkerStatus};
+use dataflow::payload;
+use dataflow::prelude::*;
+use noria::debug::consistency::{ConsistencyReport, Divergence};
+use petgraph;
+use std::collections::HashMap;
+
+/// Every row materialized at the given node across all its shards, or `None` if it isn't fully
+/// materialized.
+fn rows(
+    graph: &Graph,
+    ni: NodeIndex,
+    domains: &mut HashMap<DomainIndex, DomainHandle>,
+    workers: &HashMap<WorkerIdentifier, WorkerStatus>,
+) -> Result<Option<Vec<Vec<DataType>>>, String> {
+    let n = &graph[ni];
+    let domain = domains.get_mut(&n.domain()).unwrap();
+    domain
+        .send_to_healthy(
+            box payload::Packet::GetRows {
+                node: n.local_addr(),
+            },
+            workers,
+        )
+        .map_err(|e| format!("failed to request rows of {}: {:?}", ni.index(), e))?;
+    let shards = domain
+        .wait_for_rows()
+        .map_err(|e| format!("failed to get rows of {}: {:?}", ni.index(), e))?;
+
+    // each shard has a disjoint subset of the rows
+    Ok(shards.into_iter().fold(Some(Vec::new()), |all, rs| {
+        let mut all = all?;
+        all.extend(rs?);
+        Some(all)
+    }))
+}
+
+/// The rows that are in `expected` but not in `actual`, and those that are in `actual` but not in
+/// `expected`, counting duplicates.
+fn diff(
+    expected: &[Vec<DataType>],
+    actual: &[Vec<DataType>],
+) -> (Vec<Vec<DataType>>, Vec<Vec<DataType>>) {
+    let mut counts: HashMap<&[DataType], isize> = HashMap::new();
+    for r in expected {
+        *counts.entry(&r[..]).or_insert(0) += 1;
+    }
+    for r in actual {
+        *counts.entry(&r[..]).or_insert(0) -= 1;
+    }
+
+    let mut missing = Vec::new();
+    let mut extra = Vec::new();
+    for (r, n) in counts {
+        if n > 0 {
+            missing.extend((0..n).map(|_| r.to_vec()));
+        } else if n < 0 {
+            extra.extend((0..-n).map(|_| r.to_vec()));
+        }
+    }
+    (missing, extra)
+}
+
+/// Recompute every fully materialized node in the graph from the rows in the base tables, and
+/// compare the result to the rows that the node holds.
+pub(super) fn check(
+    graph: &Graph,
+    domains: &mut HashMap<DomainIndex, DomainHandle>,
+    workers: &HashMap<WorkerIdentifier, WorkerStatus>,
+) -> Result<ConsistencyReport, String> {
+    let mut report = ConsistencyReport::default();
+
+    // the rows each node holds, where they are known
+    let mut known: HashMap<NodeIndex, Vec<Vec<DataType>>> = HashMap::new();
+    // the nodes that each node's known rows were computed along
+    let mut paths: HashMap<NodeIndex, Vec<NodeIndex>> = HashMap::new();
+
+    let mut topo = petgraph::visit::Topo::new(graph);
+    while let Some(ni) = topo.next(graph) {
+        let n = &graph[ni];
+        if n.is_source() || n.is_dropped() {
+            continue;
+        }
+
+        let actual = rows(graph, ni, domains, workers)?;
+        if n.is_base() {
+            // the bases are what everything else is checked against
+            if let Some(actual) = actual {
+                known.insert(ni, actual);
+                paths.insert(ni, vec![ni]);
+            }
+            continue;
+        }
+
+        let parents: Vec<_> = if n.is_internal() {
+            n.ancestors()
+        } else {
+            graph
+                .neighbors_directed(ni, petgraph::EdgeDirection::Incoming)
+                .collect()
+        };
+        let mut path = Vec::new();
+        for p in &parents {
+            for &pi in paths.get(p).into_iter().flatten() {
+                if !path.contains(&pi) {
+                    path.push(pi);
+                }
+            }
+        }
+        path.push(ni);
+
+        let expected = if parents.iter().all(|p| known.contains_key(p)) {
+            if n.is_internal() {
+                let inputs: HashMap<_, _> =
+                    parents.iter().map(|p| (*p, known[p].clone())).collect();
+                n.evaluate(&inputs)
+            } else {
+                // ingress, egress, sharder, and reader nodes pass their input on as it is
+                Some(known[&parents[0]].clone())
+            }
+        } else {
+            None
+        };
+
+        match (actual, expected) {
+            (Some(actual), Some(expected)) => {
+                let (missing, mut extra) = diff(&expected, &actual);
+                if n.is_internal() {
+                    extra.retain(|r| !n.may_linger(r));
+                }
+                if !missing.is_empty() || !extra.is_empty() {
+                    report.divergences.push(Divergence {
+                        node: ni,
+                        name: n.name().to_owned(),
+                        path,
+                        missing,
+                        extra,
+                    });
+                }
+                report.checked.push(ni);
+                known.insert(ni, actual);
+                paths.insert(ni, vec![ni]);
+            }
+            (Some(actual), None) => {
+                let why = if n.is_internal() && parents.iter().all(|p| known.contains_key(p)) {
+                    format!("{} can't be evaluated", n.description(false))
+                } else {
+                    String::from("the rows of its parents aren't known")
+                };
+                report.unchecked.push((ni, why));
+                known.insert(ni, actual);
+                paths.insert(ni, vec![ni]);
+            }
+            (None, Some(expected)) => {
+                known.insert(ni, expected);
+                paths.insert(ni, path);
+            }
+            (None, None) => {}
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diffs_count_duplicates() {
+        let expected: Vec<Vec<DataType>> = vec![vec![1.into()], vec![1.into()], vec![2.into()]];
+        let actual: Vec<Vec<DataType>> = vec![vec![1.into()], vec![3.into()], vec![3.into()]];
+        let (mut missing, extra) = diff(&expected, &actual);
+        missing.sort();
+        assert_eq!(missing, vec![vec![DataType::from(1)], vec![2.into()]]);
+        assert_eq!(extra, vec![vec![DataType::from(3)], vec![3.into()]]);
+    }
+}
@@ -0,0 +1,193 @@
+//! Finds the domains that hold up the rest of the graph, from the metrics that domains keep.
+//!
+//! A domain is the bottleneck if packets keep piling up at it while it isn't held up sending to
+//! the domains below it, that is, if it simply can't process packets as fast as they are sent to
+//! it. How much packets piled up is judged by the average number of packets that were waiting for
+//! the domain, which is how long packets waited for it in all (`noria_domain_queue_time_ns`)
+//! divided by how long that was measured over (`noria_domain_uptime_ns`).
+//!
+//! The metrics only ever go up, so what happened over some window of time is the difference
+//! between a snapshot of them taken at the end of the window and one taken at its start. The
+//! controller keeps the snapshot of each diagnosis to start the window of the next one with, so
+//! the first window of each domain starts when the domain did.
+
+use noria::debug::diagnosis::{SlowDomain, SlowDomainThresholds, SlowNode};
+use noria::debug::metrics::MetricsSnapshot;
+use noria::internal::DomainIndex;
+use petgraph::graph::NodeIndex;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// The metrics of a domain shard that go into a diagnosis.
+#[derive(Default)]
+struct Counters {
+    uptime: u64,
+    queue_time: u64,
+    send_time: u64,
+    /// The name and process time of each node, by node index.
+    nodes: HashMap<usize, (String, u64)>,
+}
+
+/// The counters of every domain shard in the snapshot, by domain and shard index.
+fn counters(m: &MetricsSnapshot) -> HashMap<(usize, usize), Counters> {
+    let mut all: HashMap<_, Counters> = HashMap::new();
+    for s in &m.samples {
+        let domain = s.label("domain").and_then(|d| d.parse().ok());
+        let shard = s.label("shard").and_then(|s| s.parse().ok());
+        let c = match (domain, shard) {
+            (Some(domain), Some(shard)) => all.entry((domain, shard)).or_default(),
+            _ => continue,
+        };
+        match &s.name[..] {
+            "noria_domain_uptime_ns" => c.uptime = s.value,
+            "noria_domain_queue_time_ns" => c.queue_time = s.value,
+            "noria_domain_send_time_ns" => c.send_time = s.value,
+            "noria_node_process_time_ns" => {
+                let node = s.label("node").and_then(|n| n.parse().ok());
+                if let (Some(node), Some(name)) = (node, s.label("name")) {
+                    c.nodes.insert(node, (name.to_owned(), s.value));
+                }
+            }
+            _ => {}
+        }
+    }
+    all
+}
+
+/// The domain shards that were the bottleneck between when the metrics in `before` and those in
+/// `after` were taken, ordered by domain and shard.
+pub(super) fn slow_domains(
+    before: &MetricsSnapshot,
+    after: &MetricsSnapshot,
+    thresholds: &SlowDomainThresholds,
+) -> Vec<SlowDomain> {
+    let before = counters(before);
+    let none = Counters::default();
+
+    let mut slow = Vec::new();
+    for ((domain, shard), now) in counters(after) {
+        // a domain that has been restarted since starts over
+        let then = match before.get(&(domain, shard)) {
+            Some(then) if then.uptime <= now.uptime => then,
+            _ => &none,
+        };
+        let window = now.uptime - then.uptime;
+        if window == 0 {
+            continue;
+        }
+
+        let share = |now: u64, then: u64| now.saturating_sub(then) as f64 / window as f64;
+        let queued = share(now.queue_time, then.queue_time);
+        let send_share = share(now.send_time, then.send_time);
+        if queued < thresholds.min_queued || send_share > thresholds.max_send_share {
+            continue;
+        }
+
+        let mut process_time = 0;
+        let mut slowest: Option<(usize, &str, u64)> = None;
+        for (&node, &(ref name, t)) in &now.nodes {
+            let t = t.saturating_sub(then.nodes.get(&node).map(|&(_, t)| t).unwrap_or(0));
+            process_time += t;
+            if t > 0 && slowest.map(|(_, _, most)| t > most).unwrap_or(true) {
+                slowest = Some((node, name, t));
+            }
+        }
+
+        slow.push(SlowDomain {
+            domain: DomainIndex::from(domain),
+            shard,
+            window: Duration::from_nanos(window),
+            queued,
+            send_share,
+            process_share: share(process_time, 0),
+            slowest: slowest.map(|(node, name, t)| SlowNode {
+                node: NodeIndex::new(node),
+                name: name.to_owned(),
+                process_time: Duration::from_nanos(t),
+            }),
+        });
+    }
+
+    slow.sort_by_key(|d| (d.domain, d.shard));
+    slow
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use noria::debug::metrics::Sample;
+
+    fn sample(name: &str, domain: usize, node: Option<(usize, &str)>, value: u64) -> Sample {
+        let mut labels = vec![
+            ("domain".to_owned(), domain.to_string()),
+            ("shard".to_owned(), "0".to_owned()),
+        ];
+        if let Some((node, name)) = node {
+            labels.push(("node".to_owned(), node.to_string()));
+            labels.push(("name".to_owned(), name.to_owned()));
+        }
+        Sample {
+            name: name.to_owned(),
+            labels,
+            value,
+        }
+    }
+
+    /// The metrics of a domain that was up for `up` ms, had packets wait `queue` ms in all, spent
+    /// `send` ms sending, and has a node that spent `process` ms processing.
+    fn domain(domain: usize, up: u64, queue: u64, send: u64, process: u64) -> Vec<Sample> {
+        let ms = 1_000_000;
+        let node = Some((domain * 10, "n"));
+        vec![
+            sample("noria_domain_uptime_ns", domain, None, up * ms),
+            sample("noria_domain_queue_time_ns", domain, None, queue * ms),
+            sample("noria_domain_send_time_ns", domain, None, send * ms),
+            sample("noria_node_process_time_ns", domain, node, process * ms),
+        ]
+    }
+
+    fn snapshot(domains: Vec<Vec<Sample>>) -> MetricsSnapshot {
+        let mut m = MetricsSnapshot::default();
+        for samples in domains {
+            m.extend(MetricsSnapshot { samples });
+        }
+        m
+    }
+
+    #[test]
+    fn it_finds_domains_that_packets_pile_up_at() {
+        let thresholds = SlowDomainThresholds {
+            min_queued: 10.0,
+            max_send_share: 0.1,
+        };
+        let before = snapshot(vec![domain(0, 1000, 0, 0, 0), domain(1, 1000, 0, 0, 0)]);
+        let after = snapshot(vec![
+            // 20 packets waiting on average, but held up sending half the time
+            domain(0, 2000, 20_000, 500, 100),
+            // 50 packets waiting on average, while busy processing
+            domain(1, 2000, 50_000, 10, 900),
+            // started after the first snapshot, and only had a few packets waiting
+            domain(2, 1000, 5_000, 0, 100),
+        ]);
+
+        let slow = slow_domains(&before, &after, &thresholds);
+        assert_eq!(slow.len(), 1);
+        let d = &slow[0];
+        assert_eq!(d.domain, DomainIndex::from(1));
+        assert_eq!(d.window, Duration::from_secs(1));
+        assert!((d.queued - 50.0).abs() < 1e-9);
+        assert!((d.send_share - 0.01).abs() < 1e-9);
+        assert!((d.process_share - 0.9).abs() < 1e-9);
+        let n = d.slowest.as_ref().unwrap();
+        assert_eq!(n.node, NodeIndex::new(10));
+        assert_eq!(n.process_time, Duration::from_millis(900));
+
+        // over the next window, things are better
+        let later = snapshot(vec![
+            domain(0, 3000, 20_000, 500, 100),
+            domain(1, 3000, 51_000, 10, 1000),
+            domain(2, 2000, 5_000, 0, 100),
+        ]);
+        assert!(slow_domains(&after, &later, &thresholds).is_empty());
+    }
+}
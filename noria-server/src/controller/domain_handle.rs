@@ -1,27 +1,43 @@
 use crate::controller::{WorkerEndpoint, WorkerIdentifier, WorkerStatus};
 use crate::coordination::{CoordinationMessage, CoordinationPayload, DomainDescriptor};
-use dataflow::payload::ControlReplyPacket;
+use dataflow::payload::{ControlError, ControlReplyPacket};
 use dataflow::prelude::*;
 use dataflow::{DomainBuilder, DomainConfig};
 use mio;
 use noria::channel::poll::{KeepPolling, PollEvent, PollingLoop, StopPolling};
-use noria::channel::{tcp, TcpReceiver};
+use noria::channel::{tcp, DomainConnectionBuilder, TcpReceiver};
 use noria::consensus::Epoch;
-use noria::debug::stats::{DomainStats, NodeStats};
+use noria::debug::dead_letters::DeadLetter;
+use noria::debug::metrics::MetricsSnapshot;
+use noria::debug::stats::{DomainFailure, DomainStats, NodeStats};
+use noria::WriteError;
 use slog::Logger;
 use std::collections::HashMap;
 use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::{self, cell, io};
 
+// how long (in ms) to wait for a reply before checking whether the domain has gone away
+const CHECK_ALIVE_EVERY_MS: u64 = 100;
+
 #[derive(Debug)]
 pub enum WaitError {
     WrongReply(ControlReplyPacket),
+    /// A shard of the domain exited, so no reply is coming.
+    Exited,
+    /// No reply came before the deadline.
+    TimedOut,
+    /// A shard refused what it was asked to do, for the given reason.
+    Failed(ControlError),
 }
 
 struct DomainShardHandle {
     worker: WorkerIdentifier,
     tx: Box<dyn noria::channel::Sender<Item = Box<Packet>> + Send>,
+    /// Why the shard stopped processing, if it has.
+    failure: Option<String>,
 }
 
 pub struct DomainHandle {
@@ -29,6 +45,11 @@ pub struct DomainHandle {
 
     cr_poll: PollingLoop<ControlReplyPacket>,
     shards: Vec<DomainShardHandle>,
+    // statistics that shards still owe from requests that were given up on, and that must not be
+    // taken for replies to later requests
+    late_statistics: usize,
+    // the same, for barriers of quiesces that were given up on
+    late_quiesced: usize,
 
     log: Logger,
 }
@@ -75,6 +96,7 @@ impl DomainHandle {
                 nodes,
                 persistence_parameters: persistence_params.clone(),
                 control_addr: control_listener.local_addr().unwrap(),
+                snapshot: None,
             };
 
             // TODO(malte): simple round-robin placement for the moment
@@ -159,7 +181,11 @@ impl DomainHandle {
             .enumerate()
             .map(|(i, worker)| {
                 let tx = txs.remove(&i).unwrap();
-                DomainShardHandle { worker, tx }
+                DomainShardHandle {
+                    worker,
+                    tx,
+                    failure: None,
+                }
             })
             .collect();
 
@@ -167,10 +193,293 @@ impl DomainHandle {
             idx: idx,
             cr_poll,
             shards,
+            late_statistics: 0,
+            late_quiesced: 0,
             log: log.clone(),
         }
     }
 
+    /// Connect to a domain that another controller started, whose shards run on the given
+    /// workers, and whose addresses `channel_coordinator` already knows. Control replies keep
+    /// going to the other controller until the domain is adopted with `adopt`.
+    pub(super) fn reconnect(
+        idx: DomainIndex,
+        workers: Vec<WorkerIdentifier>,
+        log: &Logger,
+        channel_coordinator: &Arc<ChannelCoordinator>,
+    ) -> io::Result<Self> {
+        let mut shards = Vec::with_capacity(workers.len());
+        for (i, worker) in workers.into_iter().enumerate() {
+            let tx = match channel_coordinator.builder_for(&(idx, i)) {
+                Some(builder) => builder.build_sync()?,
+                None => {
+                    let e = format!("no address for domain {}.{}", idx.index(), i);
+                    return Err(io::Error::new(io::ErrorKind::NotFound, e));
+                }
+            };
+            shards.push(DomainShardHandle {
+                worker,
+                tx,
+                failure: None,
+            });
+        }
+
+        Ok(DomainHandle {
+            idx,
+            cr_poll: PollingLoop::from_receivers(Vec::new()),
+            shards,
+            late_statistics: 0,
+            late_quiesced: 0,
+            log: log.clone(),
+        })
+    }
+
+    /// Ask every shard which nodes it has, as global and local index, without taking the domain
+    /// over. Fails if some shard hasn't answered by `deadline`.
+    pub(super) fn list_nodes(
+        &mut self,
+        listen_addr: &IpAddr,
+        deadline: Instant,
+    ) -> Result<Vec<Vec<(NodeIndex, LocalNodeIndex)>>, String> {
+        let mut replies = PollingLoop::new(SocketAddr::new(listen_addr.clone(), 0));
+        let reply_to = replies.get_listener_addr().unwrap();
+        let idx = self.idx;
+        for (i, shard) in self.shards.iter_mut().enumerate() {
+            shard
+                .tx
+                .send(box Packet::ListNodes { reply_to })
+                .map_err(|e| format!("domain {}.{} is gone: {:?}", idx.index(), i, e))?;
+        }
+
+        // every shard answers over a connection of its own, which it then hangs up
+        let mut listed = vec![None; self.shards.len()];
+        let mut pending = self.shards.len();
+        replies.run_polling_loop(|event| match event {
+            PollEvent::Process(ControlReplyPacket::Nodes { shard, nodes }) => {
+                if listed[shard].is_none() {
+                    pending -= 1;
+                }
+                listed[shard] = Some(nodes);
+                if pending == 0 {
+                    StopPolling
+                } else {
+                    KeepPolling
+                }
+            }
+            PollEvent::Process(_) => unreachable!(),
+            PollEvent::ResumePolling(timeout) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return StopPolling;
+                }
+                *timeout = Some(deadline - now);
+                KeepPolling
+            }
+            PollEvent::Timeout => StopPolling,
+        });
+
+        listed
+            .into_iter()
+            .enumerate()
+            .map(|(i, nodes)| {
+                nodes.ok_or_else(|| format!("domain {}.{} did not list its nodes", idx.index(), i))
+            })
+            .collect()
+    }
+
+    /// Have every shard send its control replies to us rather than to the controller that
+    /// started it. Fails if some shard hasn't acknowledged by `deadline`.
+    pub(super) fn adopt(&mut self, listen_addr: &IpAddr, deadline: Instant) -> Result<(), String> {
+        self.cr_poll = PollingLoop::new(SocketAddr::new(listen_addr.clone(), 0));
+        let control_addr = self.cr_poll.get_listener_addr().unwrap();
+        let idx = self.idx;
+        for (i, shard) in self.shards.iter_mut().enumerate() {
+            shard
+                .tx
+                .send(box Packet::Adopt { control_addr })
+                .map_err(|e| format!("domain {}.{} is gone: {:?}", idx.index(), i, e))?;
+        }
+        for _ in 0..self.shards() {
+            match self.wait_for_reply_until(Some(deadline)) {
+                Ok(ControlReplyPacket::Ack(_)) => {}
+                r => {
+                    return Err(format!(
+                        "domain {} was not taken over: {:?}",
+                        self.idx.index(),
+                        r
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Start moving the domain, which has a single shard, to the worker behind `endpoint`: have it
+    /// snapshot itself, and boot a copy from the snapshot on that worker, which stays paused and
+    /// answers on a control channel of its own. Returns where the copy listens, its control
+    /// channel, and the number of rows in the snapshot. Fails if either hasn't answered by
+    /// `deadline`.
+    pub(super) fn start_move(
+        &mut self,
+        listen_addr: &IpAddr,
+        endpoint: &WorkerEndpoint,
+        workers: &HashMap<WorkerIdentifier, WorkerStatus>,
+        epoch: Epoch,
+        deadline: Instant,
+    ) -> Result<(SocketAddr, PollingLoop<ControlReplyPacket>, usize), String> {
+        let idx = self.idx;
+        let mut cr_poll = PollingLoop::new(SocketAddr::new(listen_addr.clone(), 0));
+        let control_addr = cr_poll.get_listener_addr().unwrap();
+        self.send_to_healthy_shard(0, box Packet::StartMove { control_addr }, workers)
+            .map_err(|e| format!("failed to move domain {}: {:?}", idx.index(), e))?;
+        let builder = match self.wait_for_reply_until(Some(deadline)) {
+            Ok(ControlReplyPacket::Snapshot(builder)) => builder,
+            r => {
+                return Err(format!(
+                    "domain {} did not snapshot itself: {:?}",
+                    idx.index(),
+                    r
+                ));
+            }
+        };
+        let rows = builder.snapshot.as_ref().map_or(0, |s| s.rows());
+
+        {
+            let mut w = endpoint.lock().unwrap();
+            info!(
+                self.log,
+                "sending copy of domain {} to worker {:?}",
+                idx.index(),
+                w.peer_addr();
+                "rows" => rows
+            );
+            let src = w.local_addr().unwrap();
+            w.send(CoordinationMessage {
+                epoch,
+                source: src,
+                payload: CoordinationPayload::AssignDomain(*builder),
+            })
+            .map_err(|e| format!("failed to send copy of domain {}: {:?}", idx.index(), e))?;
+        }
+
+        let mut booted = None;
+        cr_poll.run_polling_loop(|event| match event {
+            PollEvent::Process(ControlReplyPacket::Booted(_, addr)) => {
+                booted = Some(addr);
+                StopPolling
+            }
+            PollEvent::Process(_) => unreachable!(),
+            PollEvent::ResumePolling(timeout) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return StopPolling;
+                }
+                *timeout = Some(deadline - now);
+                KeepPolling
+            }
+            PollEvent::Timeout => StopPolling,
+        });
+        match booted {
+            Some(addr) => Ok((addr, cr_poll, rows)),
+            None => Err(format!("copy of domain {} did not boot", idx.index())),
+        }
+    }
+
+    /// Have the domain that is moving send its copy at `to` the updates it has logged, and those
+    /// it processes from now on.
+    pub(super) fn ship_move(
+        &mut self,
+        to: SocketAddr,
+        workers: &HashMap<WorkerIdentifier, WorkerStatus>,
+    ) -> Result<(), String> {
+        let idx = self.idx;
+        self.send_to_healthy_shard(0, box Packet::ShipMove { to }, workers)
+            .map_err(|e| format!("failed to move domain {}: {:?}", idx.index(), e))?;
+        self.wait_for_ack()
+            .map_err(|e| format!("domain {} did not reach its copy: {:?}", idx.index(), e))
+    }
+
+    /// Give up on moving the domain, and have its copy at `copy`, if it booted, exit.
+    pub(super) fn cancel_move(
+        &mut self,
+        copy: Option<SocketAddr>,
+        workers: &HashMap<WorkerIdentifier, WorkerStatus>,
+    ) {
+        if let Some(copy) = copy {
+            let quit = DomainConnectionBuilder::for_domain(copy)
+                .build_sync()
+                .map_err(|e| format!("{:?}", e))
+                .and_then(|mut tx| tx.send(box Packet::Quit).map_err(|e| format!("{:?}", e)));
+            if let Err(e) = quit {
+                warn!(self.log, "failed to stop copy of domain {}", self.idx.index(); "error" => e);
+            }
+        }
+        let cancelled = self
+            .send_to_healthy_shard(0, box Packet::CancelMove, workers)
+            .map_err(|e| format!("{:?}", e))
+            .and_then(|_| self.wait_for_ack().map_err(|e| format!("{:?}", e)));
+        if let Err(e) = cancelled {
+            warn!(self.log, "failed to cancel move of domain {}", self.idx.index(); "error" => e);
+        }
+    }
+
+    /// Have the domain that is moving send its copy the last of the updates it logged, once
+    /// `senders` shards of other domains have been redirected to the copy. Returns the number of
+    /// updates it logged in all.
+    pub(super) fn finish_move(
+        &mut self,
+        senders: usize,
+        workers: &HashMap<WorkerIdentifier, WorkerStatus>,
+    ) -> Result<usize, String> {
+        let idx = self.idx;
+        self.send_to_healthy_shard(0, box Packet::FinishMove { senders }, workers)
+            .map_err(|e| format!("failed to move domain {}: {:?}", idx.index(), e))?;
+        match self.wait_for_next_reply() {
+            Ok(ControlReplyPacket::Moved(logged)) => Ok(logged),
+            Ok(ControlReplyPacket::Failed(e)) => Err(format!(
+                "domain {} failed to finish moving: {}",
+                idx.index(),
+                e
+            )),
+            r => Err(format!(
+                "domain {} failed to finish moving: {:?}",
+                idx.index(),
+                r
+            )),
+        }
+    }
+
+    /// Have the domain exit where it was, and take over its copy on `worker`, which answers on
+    /// `cr_poll`, as its only shard. Waits for the copy to acknowledge that it has caught up.
+    /// `channel_coordinator` must already know where the copy listens.
+    pub(super) fn moved(
+        &mut self,
+        worker: WorkerIdentifier,
+        cr_poll: PollingLoop<ControlReplyPacket>,
+        channel_coordinator: &ChannelCoordinator,
+    ) -> Result<(), String> {
+        let idx = self.idx;
+        if let Err(e) = self.shards[0].tx.send(box Packet::Quit) {
+            warn!(self.log, "failed to stop domain {} where it was", idx.index(); "error" => ?e);
+        }
+        let tx = channel_coordinator
+            .builder_for(&(idx, 0))
+            .ok_or_else(|| format!("no address for domain {}.0", idx.index()))?
+            .build_sync()
+            .map_err(|e| format!("failed to connect to domain {}: {:?}", idx.index(), e))?;
+        self.shards[0] = DomainShardHandle {
+            worker,
+            tx,
+            failure: None,
+        };
+        self.cr_poll = cr_poll;
+        // whatever the domain still owed where it was won't come
+        self.late_statistics = 0;
+        self.late_quiesced = 0;
+        self.wait_for_ack()
+            .map_err(|e| format!("copy of domain {} did not catch up: {:?}", idx.index(), e))
+    }
+
     pub fn index(&self) -> DomainIndex {
         self.idx
     }
@@ -187,6 +496,44 @@ impl DomainHandle {
         self.shards.iter().any(|s| s.worker == *worker)
     }
 
+    /// Note that a shard of this domain has stopped processing, so that later sends to it fail
+    /// right away.
+    pub(super) fn mark_failed(&mut self, failure: &DomainFailure) {
+        self.shards[failure.shard].failure = Some(failure.message.clone());
+    }
+
+    /// Whether any shard of this domain has stopped processing.
+    pub fn failed(&self) -> bool {
+        self.shards.iter().any(|s| s.failure.is_some())
+    }
+
+    /// Check that shard `i` can still be sent to.
+    fn check_shard(
+        &self,
+        i: usize,
+        workers: &HashMap<WorkerIdentifier, WorkerStatus>,
+    ) -> Result<(), tcp::SendError> {
+        let shard = &self.shards[i];
+        if let Some(ref failure) = shard.failure {
+            error!(
+                self.log,
+                "Tried to send packet to failed domain {}.{}; ignoring!",
+                self.idx.index(),
+                i
+            );
+            let e = format!("domain {}.{} failed: {}", self.idx.index(), i, failure);
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, e).into());
+        }
+        if !workers[&shard.worker].healthy {
+            error!(
+                self.log,
+                "Tried to send packet to failed worker {:?}; ignoring!", shard.worker
+            );
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "worker failed").into());
+        }
+        Ok(())
+    }
+
     fn build_descriptors(graph: &mut Graph, nodes: Vec<(NodeIndex, bool)>) -> DomainNodes {
         nodes
             .into_iter()
@@ -203,16 +550,9 @@ impl DomainHandle {
         p: Box<Packet>,
         workers: &HashMap<WorkerIdentifier, WorkerStatus>,
     ) -> Result<(), tcp::SendError> {
-        for shard in self.shards.iter_mut() {
-            if workers[&shard.worker].healthy {
-                shard.tx.send(p.clone())?;
-            } else {
-                error!(
-                    self.log,
-                    "Tried to send packet to failed worker {:?}; ignoring!", shard.worker
-                );
-                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "worker failed").into());
-            }
+        for i in 0..self.shards.len() {
+            self.check_shard(i, workers)?;
+            self.shards[i].tx.send(p.clone())?;
         }
         Ok(())
     }
@@ -223,51 +563,291 @@ impl DomainHandle {
         p: Box<Packet>,
         workers: &HashMap<WorkerIdentifier, WorkerStatus>,
     ) -> Result<(), tcp::SendError> {
-        if workers[&self.shards[i].worker].healthy {
-            self.shards[i].tx.send(p)?;
-        } else {
-            error!(
-                self.log,
-                "Tried to send packet to failed worker {:?}; ignoring!", &self.shards[i].worker
-            );
-            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "worker failed").into());
-        }
+        self.check_shard(i, workers)?;
+        self.shards[i].tx.send(p)?;
         Ok(())
     }
 
-    fn wait_for_next_reply(&mut self) -> ControlReplyPacket {
-        let mut reply = None;
-        self.cr_poll.run_polling_loop(|event| match event {
-            PollEvent::Process(packet) => {
-                reply = Some(packet);
-                StopPolling
+    fn wait_for_next_reply(&mut self) -> Result<ControlReplyPacket, WaitError> {
+        loop {
+            match self.wait_for_reply_until(None)? {
+                ControlReplyPacket::Statistics(..) if self.late_statistics != 0 => {
+                    self.late_statistics -= 1;
+                }
+                ControlReplyPacket::Quiesced { .. } if self.late_quiesced != 0 => {
+                    self.late_quiesced -= 1;
+                }
+                reply => return Ok(reply),
             }
-            PollEvent::ResumePolling(_) => KeepPolling,
-            PollEvent::Timeout => unreachable!(),
-        });
-        reply.unwrap()
+        }
     }
 
+    fn wait_for_reply_until(
+        &mut self,
+        deadline: Option<Instant>,
+    ) -> Result<ControlReplyPacket, WaitError> {
+        loop {
+            // replies that are already in are taken even if the deadline has passed
+            let mut check_alive = Duration::from_millis(CHECK_ALIVE_EVERY_MS);
+            if let Some(deadline) = deadline {
+                let now = Instant::now();
+                check_alive = if now >= deadline {
+                    Duration::from_millis(0)
+                } else {
+                    check_alive.min(deadline - now)
+                };
+            }
+
+            let mut reply = None;
+            self.cr_poll.run_polling_loop(|event| match event {
+                PollEvent::Process(packet) => {
+                    reply = Some(packet);
+                    StopPolling
+                }
+                PollEvent::ResumePolling(timeout) => {
+                    *timeout = Some(check_alive);
+                    KeepPolling
+                }
+                PollEvent::Timeout => StopPolling,
+            });
+
+            if let Some(reply) = reply {
+                return Ok(reply);
+            }
+            if self.cr_poll.closed_channels() != 0 {
+                error!(self.log, "domain exited before replying");
+                return Err(WaitError::Exited);
+            }
+            if deadline.map(|d| Instant::now() >= d).unwrap_or(false) {
+                return Err(WaitError::TimedOut);
+            }
+        }
+    }
+
+    /// Wait for every shard to acknowledge a control packet. If any shard refused it, the reason
+    /// is returned once all the shards have replied, so that no reply is left for a later wait.
     pub fn wait_for_ack(&mut self) -> Result<(), WaitError> {
+        let mut failed = None;
         for _ in 0..self.shards() {
-            match self.wait_for_next_reply() {
+            match self.wait_for_next_reply()? {
                 ControlReplyPacket::Ack(_) => {}
+                ControlReplyPacket::Failed(e) => {
+                    failed.get_or_insert(e);
+                }
                 r => return Err(WaitError::WrongReply(r)),
             }
         }
-        Ok(())
+        match failed {
+            Some(e) => Err(WaitError::Failed(e)),
+            None => Ok(()),
+        }
+    }
+
+    /// Wait for every shard to report that a full replay has finished, and return the total number
+    /// of records that the replay brought into the domain.
+    ///
+    /// If the domain is also where `started` of the replay's paths start, the acknowledgments of
+    /// those can come in before or after the replay finishes, and are waited for as well. A shard
+    /// that refused to start one means the replay won't finish, so that is returned right away.
+    pub fn wait_for_replay(&mut self, started: usize) -> Result<usize, WaitError> {
+        let mut acks = started * self.shards();
+        let mut done = self.shards();
+        let mut records = 0;
+        while acks != 0 || done != 0 {
+            match self.wait_for_next_reply()? {
+                ControlReplyPacket::Ack(_) if acks != 0 => acks -= 1,
+                ControlReplyPacket::Replayed(n) if done != 0 => {
+                    done -= 1;
+                    records += n;
+                }
+                ControlReplyPacket::Failed(e) => return Err(WaitError::Failed(e)),
+                r => return Err(WaitError::WrongReply(r)),
+            }
+        }
+        Ok(records)
     }
 
+    /// Ask every shard for its statistics, once the shards have caught up on the statistics they
+    /// owe from earlier requests. Gives up if they haven't by `deadline`.
+    pub fn request_statistics(
+        &mut self,
+        workers: &HashMap<WorkerIdentifier, WorkerStatus>,
+        deadline: Option<Instant>,
+    ) -> Result<(), WaitError> {
+        // a shard answers requests in order, but the answers of different shards can interleave,
+        // so the late answers must all be in before the next request can be told apart from them
+        while self.late_statistics != 0 {
+            match self.wait_for_reply_until(deadline)? {
+                ControlReplyPacket::Statistics(..) => self.late_statistics -= 1,
+                r => return Err(WaitError::WrongReply(r)),
+            }
+        }
+        self.send_to_healthy(box Packet::GetStatistics, workers)
+            .map_err(|_| WaitError::Exited)
+    }
+
+    /// Wait until `deadline` for every shard to report its statistics. If some shard doesn't, the
+    /// statistics of the shards that did are dropped, and those that are still to come will be
+    /// ignored.
     pub fn wait_for_statistics(
         &mut self,
+        deadline: Option<Instant>,
     ) -> Result<Vec<(DomainStats, HashMap<NodeIndex, NodeStats>)>, WaitError> {
         let mut stats = Vec::with_capacity(self.shards());
+        while stats.len() < self.shards() {
+            match self.wait_for_reply_until(deadline) {
+                Ok(ControlReplyPacket::Statistics(d, s)) => stats.push((d, s)),
+                Ok(r) => return Err(WaitError::WrongReply(r)),
+                Err(WaitError::TimedOut) => {
+                    self.late_statistics += self.shards() - stats.len();
+                    return Err(WaitError::TimedOut);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Wait for every shard to report a checksum of the rows it has materialized for a node.
+    pub fn wait_for_checksums(&mut self) -> Result<Vec<Option<u64>>, WaitError> {
+        let mut checksums = Vec::with_capacity(self.shards());
         for _ in 0..self.shards() {
-            match self.wait_for_next_reply() {
-                ControlReplyPacket::Statistics(d, s) => stats.push((d, s)),
+            match self.wait_for_next_reply()? {
+                ControlReplyPacket::Checksum(c) => checksums.push(c),
                 r => return Err(WaitError::WrongReply(r)),
             }
         }
-        Ok(stats)
+        Ok(checksums)
+    }
+
+    /// Wait for every shard to report the number of rows it has materialized for a node, along
+    /// with their checksum.
+    pub fn wait_for_digests(&mut self) -> Result<Vec<Option<(usize, u64)>>, WaitError> {
+        let mut digests = Vec::with_capacity(self.shards());
+        for _ in 0..self.shards() {
+            match self.wait_for_next_reply()? {
+                ControlReplyPacket::Digest(d) => digests.push(d),
+                r => return Err(WaitError::WrongReply(r)),
+            }
+        }
+        Ok(digests)
+    }
+
+    /// Wait for every shard to send the rows it has materialized for a node.
+    pub fn wait_for_rows(&mut self) -> Result<Vec<Option<Vec<Vec<DataType>>>>, WaitError> {
+        let mut rows = Vec::with_capacity(self.shards());
+        for _ in 0..self.shards() {
+            match self.wait_for_next_reply()? {
+                ControlReplyPacket::Rows(rs) => rows.push(rs),
+                r => return Err(WaitError::WrongReply(r)),
+            }
+        }
+        Ok(rows)
+    }
+
+    /// Wait for every shard to report how many rows it seeded a base with, or why it rejected them.
+    pub fn wait_for_seeded(&mut self) -> Result<Vec<Result<usize, WriteError>>, WaitError> {
+        let mut seeded = Vec::with_capacity(self.shards());
+        for _ in 0..self.shards() {
+            match self.wait_for_next_reply()? {
+                ControlReplyPacket::Seeded(r) => seeded.push(r),
+                r => return Err(WaitError::WrongReply(r)),
+            }
+        }
+        Ok(seeded)
+    }
+
+    /// Wait for every shard to report how many writes it recovered a base with from its
+    /// write-ahead log, or why it could not.
+    pub fn wait_for_recovered(&mut self) -> Result<Vec<Result<usize, String>>, WaitError> {
+        let mut recovered = Vec::with_capacity(self.shards());
+        for _ in 0..self.shards() {
+            match self.wait_for_next_reply()? {
+                ControlReplyPacket::Recovered(r) => recovered.push(r),
+                r => return Err(WaitError::WrongReply(r)),
+            }
+        }
+        Ok(recovered)
+    }
+
+    /// Wait for every shard to report which nodes it has written to, or loaded from, its
+    /// checkpoint file.
+    pub fn wait_for_checkpoint(&mut self) -> Result<Vec<Option<Vec<NodeIndex>>>, WaitError> {
+        let mut saved = Vec::with_capacity(self.shards());
+        for _ in 0..self.shards() {
+            match self.wait_for_next_reply()? {
+                ControlReplyPacket::Checkpointed(nodes) => saved.push(nodes),
+                r => return Err(WaitError::WrongReply(r)),
+            }
+        }
+        Ok(saved)
+    }
+
+    /// Wait for every shard to report the current values of its metrics.
+    pub fn wait_for_metrics(&mut self) -> Result<Vec<MetricsSnapshot>, WaitError> {
+        let mut metrics = Vec::with_capacity(self.shards());
+        for _ in 0..self.shards() {
+            match self.wait_for_next_reply()? {
+                ControlReplyPacket::Metrics(m) => metrics.push(m),
+                r => return Err(WaitError::WrongReply(r)),
+            }
+        }
+        Ok(metrics)
+    }
+
+    /// Wait for every shard to report the packets that the domains of its worker could not
+    /// deliver.
+    pub fn wait_for_dead_letters(&mut self) -> Result<Vec<Vec<DeadLetter>>, WaitError> {
+        let mut letters = Vec::with_capacity(self.shards());
+        for _ in 0..self.shards() {
+            match self.wait_for_next_reply()? {
+                ControlReplyPacket::DeadLetters(l) => letters.push(l),
+                r => return Err(WaitError::WrongReply(r)),
+            }
+        }
+        Ok(letters)
+    }
+
+    /// Wait until `deadline` for every shard to report that it has passed the barrier with the
+    /// given identifier, and return the shards that didn't. Their reports are ignored if they do
+    /// come.
+    pub fn wait_for_quiesced(
+        &mut self,
+        id: u64,
+        deadline: Instant,
+    ) -> Result<Vec<usize>, WaitError> {
+        let mut pending: Vec<_> = (0..self.shards()).collect();
+        while !pending.is_empty() {
+            match self.wait_for_reply_until(Some(deadline)) {
+                Ok(ControlReplyPacket::Quiesced { id: i, shard }) if i == id => {
+                    pending.retain(|&s| s != shard);
+                }
+                Ok(ControlReplyPacket::Quiesced { .. }) if self.late_quiesced != 0 => {
+                    self.late_quiesced -= 1;
+                }
+                Ok(ControlReplyPacket::Statistics(..)) if self.late_statistics != 0 => {
+                    self.late_statistics -= 1;
+                }
+                Ok(r) => return Err(WaitError::WrongReply(r)),
+                Err(WaitError::TimedOut) => {
+                    self.late_quiesced += pending.len();
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(pending)
+    }
+
+    /// Wait for every shard to report the file it has started capturing to, if any.
+    pub fn wait_for_capture(&mut self) -> Result<Vec<Result<Option<PathBuf>, String>>, WaitError> {
+        let mut files = Vec::with_capacity(self.shards());
+        for _ in 0..self.shards() {
+            match self.wait_for_next_reply()? {
+                ControlReplyPacket::Capturing(file) => files.push(file),
+                r => return Err(WaitError::WrongReply(r)),
+            }
+        }
+        Ok(files)
     }
 }
@@ -0,0 +1,249 @@
+//! Faults injected into the packets that domains receive and send, for resilience tests.
+//!
+//! A `FaultInjector` is handed to a controller through `ControllerBuilder::set_fault_injector`,
+//! and every domain the controller's worker runs consults it. Tests register policies with it
+//! while the graph is running, and remove them again to let the graph recover. Each policy
+//! applies to the packets for one domain:
+//!
+//!  - dropping every `n`th packet that arrives at the domain and that matches a predicate,
+//!  - holding back packets that arrive at the domain and that match a predicate for a while,
+//!  - making the domain fail once a packet that matches a predicate arrives at it,
+//!  - failing the next `k` sends from other domains to the domain with an I/O error, or
+//!  - holding back the sends from other domains to the domain, as if it had stopped taking packets
+//!    off its connections.
+//!
+//! Packets that arrive at a domain come from other domains, from the controller, and from clients
+//! writing to base tables, and the policies see them all. A failed send is not taken to mean that
+//! the receiving domain has gone away, so rather than posting the packet as a dead letter, the
+//! sending domain stops and reports the error to the controller.
+//!
+//! With no policies registered, the injector costs domains a single atomic load per packet.
+
+use dataflow::Packet;
+use noria::internal::DomainIndex;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time;
+
+/// Identifies a policy registered with a `FaultInjector`, so that it can be removed again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FaultId(usize);
+
+/// What should happen to a packet that arrives at a domain.
+pub(super) enum Arrival {
+    /// The packet is lost.
+    Drop,
+    /// The packet should only be processed once this much time has passed.
+    Delay(time::Duration),
+    /// The domain should fail rather than process the packet.
+    Crash,
+}
+
+/// What should happen to a packet that a domain sends to another domain.
+pub(super) enum Departure {
+    /// The send fails with the given error.
+    Fail(io::Error),
+    /// The receiving domain is not taking packets, so the packet has to wait until it is.
+    Stall,
+}
+
+enum Policy {
+    DropEvery {
+        n: usize,
+        seen: usize,
+    },
+    Delay(time::Duration),
+    Crash,
+    FailSends {
+        remaining: usize,
+        kind: io::ErrorKind,
+    },
+    StallSends,
+}
+
+struct Rule {
+    id: FaultId,
+    to: DomainIndex,
+    matches: Option<Box<dyn Fn(&Packet) -> bool + Send>>,
+    policy: Policy,
+}
+
+#[derive(Default)]
+struct Rules {
+    next: usize,
+    rules: Vec<Rule>,
+}
+
+/// A set of faults to inject into the packets of a controller's domains.
+///
+/// Clones share the same set of policies, so a test can keep one clone to control the faults
+/// while the controller's domains consult another. See the module documentation for details.
+#[derive(Clone, Default)]
+pub struct FaultInjector {
+    /// The number of registered policies, so that domains can skip the lock when there are none.
+    active: Arc<AtomicUsize>,
+    rules: Arc<Mutex<Rules>>,
+}
+
+impl FaultInjector {
+    /// Make a new injector with no policies, which leaves every packet alone.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn add(
+        &self,
+        to: DomainIndex,
+        matches: Option<Box<dyn Fn(&Packet) -> bool + Send>>,
+        policy: Policy,
+    ) -> FaultId {
+        let mut rules = self.rules.lock().unwrap();
+        let id = FaultId(rules.next);
+        rules.next += 1;
+        rules.rules.push(Rule {
+            id,
+            to,
+            matches,
+            policy,
+        });
+        self.active.store(rules.rules.len(), Ordering::SeqCst);
+        id
+    }
+
+    /// Drop every `n`th packet that arrives at any shard of domain `to` and for which `matches`
+    /// returns true, starting with the `n`th such packet.
+    pub fn drop_every<F>(&self, n: usize, to: DomainIndex, matches: F) -> FaultId
+    where
+        F: Fn(&Packet) -> bool + Send + 'static,
+    {
+        assert_ne!(n, 0);
+        self.add(
+            to,
+            Some(Box::new(matches)),
+            Policy::DropEvery { n, seen: 0 },
+        )
+    }
+
+    /// Hold back every packet that arrives at any shard of domain `to` and for which `matches`
+    /// returns true for `by`, while the domain goes on processing other packets.
+    pub fn delay<F>(&self, by: time::Duration, to: DomainIndex, matches: F) -> FaultId
+    where
+        F: Fn(&Packet) -> bool + Send + 'static,
+    {
+        self.add(to, Some(Box::new(matches)), Policy::Delay(by))
+    }
+
+    /// Make any shard of domain `to` fail as soon as a packet for which `matches` returns true
+    /// arrives at it, as if processing the packet had panicked. The failed shard stops running, so
+    /// nothing receives the packets that are sent to it after that.
+    pub fn crash<F>(&self, to: DomainIndex, matches: F) -> FaultId
+    where
+        F: Fn(&Packet) -> bool + Send + 'static,
+    {
+        self.add(to, Some(Box::new(matches)), Policy::Crash)
+    }
+
+    /// Fail the next `k` sends from other domains to any shard of domain `to` with an I/O error of
+    /// the given kind. The policy is removed once it has failed `k` sends.
+    pub fn fail_sends(&self, k: usize, to: DomainIndex, kind: io::ErrorKind) -> FaultId {
+        assert_ne!(k, 0);
+        self.add(to, None, Policy::FailSends { remaining: k, kind })
+    }
+
+    /// Hold back every send from other domains to any shard of domain `to` until the policy is
+    /// removed, as if the domain had stopped taking packets off its connections. The sending
+    /// domains keep the packets queued up, and try to send them again every so often.
+    pub fn stall_sends(&self, to: DomainIndex) -> FaultId {
+        self.add(to, None, Policy::StallSends)
+    }
+
+    /// Remove a policy, so that it no longer affects any packets. Packets that it is already
+    /// holding back are still processed once their delay is up. Returns false if the policy had
+    /// already been removed.
+    pub fn remove(&self, id: FaultId) -> bool {
+        let mut rules = self.rules.lock().unwrap();
+        let before = rules.rules.len();
+        rules.rules.retain(|r| r.id != id);
+        self.active.store(rules.rules.len(), Ordering::SeqCst);
+        rules.rules.len() != before
+    }
+
+    /// Remove every policy.
+    pub fn clear(&self) {
+        let mut rules = self.rules.lock().unwrap();
+        rules.rules.clear();
+        self.active.store(0, Ordering::SeqCst);
+    }
+
+    /// Decide what should happen to a packet that has arrived at a shard of domain `at`. The
+    /// first policy for the domain that matches the packet decides.
+    pub(super) fn on_arrival(&self, at: DomainIndex, packet: &Packet) -> Option<Arrival> {
+        if self.active.load(Ordering::Relaxed) == 0 {
+            return None;
+        }
+
+        let mut rules = self.rules.lock().unwrap();
+        for rule in rules.rules.iter_mut().filter(|r| r.to == at) {
+            match rule.matches {
+                Some(ref matches) if matches(packet) => {}
+                _ => continue,
+            }
+
+            match rule.policy {
+                Policy::DropEvery { n, ref mut seen } => {
+                    *seen += 1;
+                    if *seen % n == 0 {
+                        return Some(Arrival::Drop);
+                    }
+                }
+                Policy::Delay(by) => return Some(Arrival::Delay(by)),
+                Policy::Crash => return Some(Arrival::Crash),
+                Policy::FailSends { .. } | Policy::StallSends => unreachable!(),
+            }
+        }
+        None
+    }
+
+    /// Decide what should happen to a send to a shard of domain `to`, if it should not simply go
+    /// through. The first policy for the domain that applies to sends decides.
+    pub(super) fn on_send(&self, to: DomainIndex) -> Option<Departure> {
+        if self.active.load(Ordering::Relaxed) == 0 {
+            return None;
+        }
+
+        let mut rules = self.rules.lock().unwrap();
+        let mut departure = None;
+        let mut exhausted = None;
+        for (i, rule) in rules.rules.iter_mut().enumerate() {
+            if rule.to != to {
+                continue;
+            }
+            match rule.policy {
+                Policy::FailSends {
+                    ref mut remaining,
+                    kind,
+                } => {
+                    *remaining -= 1;
+                    if *remaining == 0 {
+                        exhausted = Some(i);
+                    }
+                    let e = io::Error::new(kind, "injected send failure");
+                    departure = Some(Departure::Fail(e));
+                    break;
+                }
+                Policy::StallSends => {
+                    departure = Some(Departure::Stall);
+                    break;
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(i) = exhausted {
+            rules.rules.remove(i);
+            self.active.store(rules.rules.len(), Ordering::SeqCst);
+        }
+        departure
+    }
+}
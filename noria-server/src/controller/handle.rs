@@ -1,9 +1,13 @@
 #[cfg(test)]
 use crate::controller::migrate::Migration;
-use crate::controller::Event;
+use crate::controller::{ControlState, Event};
+use bincode;
+use dataflow::metrics::Metrics;
 use dataflow::prelude::*;
+use failure;
 use futures::{self, Future};
 use noria::consensus::Authority;
+use noria::debug::metrics::MetricsSnapshot;
 use noria::prelude::*;
 use std::collections::HashMap;
 use std::ops::{Deref, DerefMut};
@@ -20,6 +24,7 @@ pub struct LocalControllerHandle<A: Authority> {
     kill: Option<Trigger>,
     runtime: Option<tokio::runtime::Runtime>,
     iopool: Option<tokio_io_pool::Runtime>,
+    metrics: Metrics,
 }
 
 impl<A: Authority> Deref for LocalControllerHandle<A> {
@@ -42,6 +47,7 @@ impl<A: Authority> LocalControllerHandle<A> {
         kill: Trigger,
         rt: tokio::runtime::Runtime,
         io: tokio_io_pool::Runtime,
+        metrics: Metrics,
     ) -> Self {
         LocalControllerHandle {
             c: Some(ControllerHandle::make(authority).unwrap()),
@@ -49,9 +55,31 @@ impl<A: Authority> LocalControllerHandle<A> {
             kill: Some(kill),
             runtime: Some(rt),
             iopool: Some(io),
+            metrics,
         }
     }
 
+    /// The current values of the counters of the domains that run in this process, and of their
+    /// nodes and readers. Unlike `ControllerHandle::metrics`, this reads the counters directly,
+    /// without asking the domains, and so doesn't include domains that run on other workers.
+    pub fn local_metrics(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    /// Export everything that another controller needs to take over the graph of this one while
+    /// its domains keep running. Fails for graphs that were built from recipes.
+    pub fn export_control_state(&mut self) -> Result<ControlState, failure::Error> {
+        let bytes: Vec<u8> = self.rpc("export_control_state", ())?;
+        Ok(bincode::deserialize(&bytes)?)
+    }
+
+    /// Take over the graph of the controller that `state` was exported from. Fails, and leaves the
+    /// graph to that controller, if any of its domains no longer has the nodes it was exported
+    /// with.
+    pub fn import_control_state(&mut self, state: &ControlState) -> Result<(), failure::Error> {
+        self.rpc("import_control_state", bincode::serialize(state)?)
+    }
+
     #[cfg(test)]
     pub(crate) fn wait_until_ready(&mut self) {
         let snd = self.event_tx.clone().unwrap();
@@ -72,6 +100,15 @@ impl<A: Authority> LocalControllerHandle<A> {
 
     #[cfg(test)]
     pub fn migrate<F, T>(&mut self, f: F) -> T
+    where
+        F: FnOnce(&mut Migration) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.try_migrate(f).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    #[cfg(test)]
+    pub fn try_migrate<F, T>(&mut self, f: F) -> Result<T, String>
     where
         F: FnOnce(&mut Migration) -> T + Send + 'static,
         T: Send + 'static,
@@ -91,7 +128,8 @@ impl<A: Authority> LocalControllerHandle<A> {
             .unwrap();
 
         match fin_rx.wait() {
-            Ok(()) => ret_rx.wait().unwrap(),
+            Ok(Ok(())) => Ok(ret_rx.wait().unwrap()),
+            Ok(Err(e)) => Err(e),
             Err(e) => unreachable!("{:?}", e),
         }
     }
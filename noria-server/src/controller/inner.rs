@@ -1,6 +1,15 @@
+use crate::controller::consistency;
+use crate::controller::diagnosis;
+use crate::controller::domain_handle::WaitError;
+use crate::controller::migrate::graph_log::{self, GraphLog, GraphRecorder};
 use crate::controller::migrate::materialization::Materializations;
-use crate::controller::{ControllerState, DomainHandle, Migration, Recipe, WorkerIdentifier};
-use crate::coordination::CoordinationMessage;
+use crate::controller::quiesce;
+use crate::controller::takeover::{self, ControlState, DomainPlacement, WorkerPlacement};
+use crate::controller::{
+    Checkpoint, ControllerState, DomainHandle, Migration, Recipe, WorkerIdentifier,
+};
+use crate::coordination::{CoordinationMessage, CoordinationPayload, DomainDescriptor};
+use bincode;
 use dataflow::prelude::*;
 use dataflow::{node, payload, DomainConfig};
 use hyper::{self, Method, StatusCode};
@@ -8,14 +17,28 @@ use mio::net::TcpListener;
 use noria::builders::*;
 use noria::channel::tcp::{SendError, TcpSender};
 use noria::consensus::{Authority, Epoch, STATE_KEY};
-use noria::debug::stats::GraphStats;
+use noria::debug::consistency::ConsistencyReport;
+use noria::debug::dead_letters::DeadLetter;
+use noria::debug::diagnosis::{SlowDomain, SlowDomainThresholds};
+use noria::debug::metrics::MetricsSnapshot;
+use noria::debug::plan::{DomainStrategy, MigrationPlan};
+use noria::debug::stats::{
+    DomainEntry, DomainFailure, DomainStats, Freshness, GraphStats, MoveStats, NodeEntry,
+    NodeStats,
+};
+use noria::debug::topology::{
+    BaseDescription, DomainDescription, NodeDescription, NodeKind, TopologyDescription,
+    ViewDescription,
+};
 use noria::ActivationResult;
 use petgraph;
 use petgraph::visit::Bfs;
 use slog;
-use std::collections::{BTreeMap, HashMap};
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::mem;
 use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use std::{io, time};
@@ -37,6 +60,18 @@ impl WorkerStatus {
     }
 }
 
+// how long (in ms) to wait for the domains of a graph that is being taken over to list their nodes,
+// and to acknowledge that they have been taken over.
+const TAKEOVER_TIMEOUT_MS: u64 = 5_000;
+
+// how long (in ms) to wait for a domain that is being moved to snapshot itself, and for its copy
+// to boot.
+const MOVE_TIMEOUT_MS: u64 = 10_000;
+
+// how long (in ms) to wait for domains to report their statistics. the statistics of domains that
+// take longer are reported as stale, so that one busy domain doesn't hold up the whole report.
+const STATISTICS_TIMEOUT_MS: u64 = 2_000;
+
 /// `Controller` is the core component of the alternate Soup implementation.
 ///
 /// It keeps track of the structure of the underlying data flow graph and its domains. `Controller`
@@ -48,6 +83,7 @@ pub struct ControllerInner {
     pub(super) source: NodeIndex,
     pub(super) ndomains: usize,
     pub(super) sharding: Option<usize>,
+    pub(super) domain_strategy: DomainStrategy,
 
     pub(super) domain_config: DomainConfig,
 
@@ -63,6 +99,8 @@ pub struct ControllerInner {
     pub(super) debug_channel: Option<SocketAddr>,
 
     pub(super) listen_addr: IpAddr,
+    /// The address that workers send messages for the controller to.
+    internal_addr: SocketAddr,
 
     /// Map from worker address to the address the worker is listening on for reads.
     read_addrs: HashMap<WorkerIdentifier, SocketAddr>,
@@ -74,6 +112,27 @@ pub struct ControllerInner {
     pub(super) epoch: Epoch,
 
     pending_recovery: Option<(Vec<String>, usize)>,
+    /// A log of the graph to rebuild once enough workers have joined.
+    pending_rebuild: Option<GraphLog>,
+    /// The log of the graph as built by migrations so far.
+    pub(super) graph_log: GraphRecorder,
+    /// The last checkpoint that every domain completed, if any.
+    checkpoint: Option<Checkpoint>,
+    /// The last statistics that every shard of each domain reported.
+    last_statistics: HashMap<DomainIndex, Vec<(DomainStats, HashMap<NodeIndex, NodeStats>)>>,
+    /// The metrics that the last diagnosis was made from, which the next one starts from.
+    last_diagnosed: MetricsSnapshot,
+    /// The identifier of the barrier that the next quiesce sends through the graph.
+    next_quiesce: u64,
+    /// The domains that have been paused, and not resumed since.
+    pub(super) paused: HashSet<DomainIndex>,
+    /// The domain shards that have stopped processing, in the order they were reported.
+    domain_failures: Vec<DomainFailure>,
+    /// Bumped whenever the graph changes, so that clients can tell if a `TopologyDescription`
+    /// they have is still current.
+    pub(super) topology_version: u64,
+    /// When the last migration that changed the graph was committed, if any was.
+    pub(super) last_migration: Option<time::SystemTime>,
 
     quorum: usize,
     heartbeat_every: Duration,
@@ -83,17 +142,40 @@ pub struct ControllerInner {
     log: slog::Logger,
 }
 
+/// What kind of node `n` is, as described to clients.
+fn node_kind(n: &Node) -> NodeKind {
+    if n.is_base() {
+        NodeKind::Base
+    } else if n.is_reader() {
+        NodeKind::Reader
+    } else if n.is_ingress() {
+        NodeKind::Ingress
+    } else if n.is_egress() {
+        NodeKind::Egress
+    } else if n.is_sharder() {
+        NodeKind::Sharder
+    } else {
+        NodeKind::Internal
+    }
+}
+
+/// Render the graph in the DOT format understood by graphviz.
+///
+/// The detailed rendering draws the nodes of each domain in a cluster of their own, dashes the
+/// edges that cross domains, and lists the indexes of materialized nodes. If `stats` are given,
+/// materialized nodes are also labeled with the approximate size of their state.
 pub(crate) fn graphviz(
     graph: &Graph,
     detailed: bool,
     materializations: &Materializations,
+    stats: Option<&GraphStats>,
 ) -> String {
     let mut s = String::new();
 
     let indentln = |s: &mut String| s.push_str("    ");
 
     // header.
-    s.push_str("digraph {{\n");
+    s.push_str("digraph {\n");
 
     // global formatting.
     indentln(&mut s);
@@ -105,25 +187,75 @@ pub(crate) fn graphviz(
         s.push_str("node [ color=\"#0C6fA9\", shape=box, style=\"rounded,bold\" ]\n");
     }
 
-    // node descriptions.
+    // the size of each node's state, summed over its shards.
+    let mut sizes: HashMap<NodeIndex, u64> = HashMap::new();
+    if let Some(stats) = stats {
+        for (_, nodes) in stats.values() {
+            for (&ni, ns) in nodes {
+                *sizes.entry(ni).or_insert(0) += ns.mem_size;
+            }
+        }
+    }
+
+    // node descriptions, grouped by domain if detailed.
+    let mut clusters: BTreeMap<Option<DomainIndex>, Vec<NodeIndex>> = BTreeMap::new();
     for index in graph.node_indices() {
         let node = &graph[index];
-        let materialization_status = materializations.get_status(&index, node);
-        indentln(&mut s);
-        s.push_str(&format!("n{}", index.index()));
-        s.push_str(&node.describe(index, detailed, materialization_status));
+        let domain = if detailed && node.has_domain() {
+            Some(node.domain())
+        } else {
+            None
+        };
+        clusters.entry(domain).or_insert_with(Vec::new).push(index);
+    }
+    for (domain, nodes) in clusters {
+        let indent = if let Some(domain) = domain {
+            indentln(&mut s);
+            s.push_str(&format!("subgraph cluster_d{} {{\n", domain.index()));
+            indentln(&mut s);
+            s.push_str(&format!(
+                "    label=\"domain {}\", style=filled, color=\"/set312/{}\"\n",
+                domain.index(),
+                (domain.index() % 12) + 1
+            ));
+            "    "
+        } else {
+            ""
+        };
+        for index in nodes {
+            let node = &graph[index];
+            let materialization_status = materializations.get_status(&index, node);
+            indentln(&mut s);
+            s.push_str(indent);
+            s.push_str(&format!("n{}", index.index()));
+            s.push_str(&node.describe(
+                index,
+                detailed,
+                materialization_status,
+                &materializations.get_indices(&index),
+                sizes.get(&index).cloned(),
+            ));
+        }
+        if domain.is_some() {
+            indentln(&mut s);
+            s.push_str("}\n");
+        }
     }
 
     // edges.
     for (_, edge) in graph.raw_edges().iter().enumerate() {
+        let (src, dst) = (&graph[edge.source()], &graph[edge.target()]);
+        let crosses_domains = src.has_domain() && dst.has_domain() && src.domain() != dst.domain();
         indentln(&mut s);
         s.push_str(&format!(
             "n{} -> n{} [ {} ]",
             edge.source().index(),
             edge.target().index(),
-            if graph[edge.source()].is_egress() {
+            if crosses_domains {
+                "color=\"#CCCCCC\", style=dashed"
+            } else if src.is_egress() {
                 "color=\"#CCCCCC\""
-            } else if graph[edge.source()].is_source() {
+            } else if src.is_source() {
                 "style=invis"
             } else {
                 ""
@@ -133,7 +265,7 @@ pub(crate) fn graphviz(
     }
 
     // footer.
-    s.push_str("}}");
+    s.push_str("}");
 
     s
 }
@@ -158,13 +290,16 @@ impl ControllerInner {
             (&Method::POST, "/graphviz") => {
                 return Ok(Ok(json::to_string(&self.graphviz(true)).unwrap()))
             }
-            (&Method::GET, "/get_statistics") => {
+            (&Method::GET, "/get_statistics") | (&Method::POST, "/get_statistics") => {
                 return Ok(Ok(json::to_string(&self.get_statistics()).unwrap()))
             }
             _ => {}
         }
 
-        if self.pending_recovery.is_some() || self.workers.len() < self.quorum {
+        if self.pending_recovery.is_some()
+            || self.pending_rebuild.is_some()
+            || self.workers.len() < self.quorum
+        {
             return Err(StatusCode::SERVICE_UNAVAILABLE);
         }
 
@@ -174,7 +309,10 @@ impl ControllerInner {
             }
             (Method::POST, "/inputs") => Ok(Ok(json::to_string(&self.inputs()).unwrap())),
             (Method::POST, "/outputs") => Ok(Ok(json::to_string(&self.outputs()).unwrap())),
-            (Method::GET, "/instances") => Ok(Ok(json::to_string(&self.get_instances()).unwrap())),
+            (Method::GET, "/instances") | (Method::POST, "/instances") => {
+                Ok(Ok(json::to_string(&self.get_instances()).unwrap()))
+            }
+            (Method::POST, "/describe") => Ok(Ok(json::to_string(&self.describe()).unwrap())),
             (Method::GET, "/nodes") => {
                 // TODO(malte): this is a pretty yucky hack, but hyper doesn't provide easy access
                 // to individual query variables unfortunately. We'll probably want to factor this
@@ -217,6 +355,15 @@ impl ControllerInner {
                     self.extend_recipe(authority, args)
                         .map(|r| json::to_string(&r).unwrap())
                 }),
+            (Method::POST, "/plan_recipe") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|args| self.plan_recipe(args).map(|r| json::to_string(&r).unwrap())),
+            (Method::POST, "/move_views") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|args| {
+                    self.move_views(args)
+                        .map(|r| json::to_string(&r.index()).unwrap())
+                }),
             (Method::POST, "/install_recipe") => json::from_slice(&body)
                 .map_err(|_| StatusCode::BAD_REQUEST)
                 .map(|args| {
@@ -235,6 +382,78 @@ impl ControllerInner {
                     self.create_universe(args)
                         .map(|r| json::to_string(&r).unwrap())
                 }),
+            (Method::POST, "/largest_materializations") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|n| Ok(json::to_string(&self.largest_materializations(n)).unwrap())),
+            (Method::POST, "/evict_view") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|args| self.evict_view(args).map(|r| json::to_string(&r).unwrap())),
+            (Method::POST, "/checksum") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|args| self.checksum(args).map(|r| json::to_string(&r).unwrap())),
+            (Method::POST, "/view_checksum") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|args| {
+                    self.view_checksum(args)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
+            (Method::POST, "/check_consistency") => Ok(self
+                .check_consistency()
+                .map(|r| json::to_string(&r).unwrap())),
+            (Method::POST, "/diagnose") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|args| self.diagnose(args).map(|r| json::to_string(&r).unwrap())),
+            (Method::POST, "/quiesce") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|args| self.quiesce(args).map(|r| json::to_string(&r).unwrap())),
+            (Method::POST, "/dead_letters") => {
+                Ok(self.dead_letters().map(|r| json::to_string(&r).unwrap()))
+            }
+            (Method::POST, "/capture") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|args| self.capture(args).map(|r| json::to_string(&r).unwrap())),
+            (Method::POST, "/pause_domain") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|args| {
+                    self.pause_domain(args)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
+            (Method::POST, "/resume_domain") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|args| {
+                    self.resume_domain(args)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
+            (Method::POST, "/move_domain") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|args| self.move_domain(args).map(|r| json::to_string(&r).unwrap())),
+            (Method::POST, "/add_standby") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|args| self.add_standby(args).map(|r| json::to_string(&r).unwrap())),
+            (Method::POST, "/switch_to_standby") => json::from_slice(&body)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+                .map(|args| {
+                    self.switch_to_standby(args)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
+            // the graph has maps with keys that JSON can't represent, so the control state goes
+            // over the wire as bincode
+            (Method::POST, "/export_control_state") => Ok(self
+                .export_control_state()
+                .map(|s| json::to_string(&bincode::serialize(&s).unwrap()).unwrap())),
+            (Method::POST, "/import_control_state") => json::from_slice::<Vec<u8>>(&body)
+                .ok()
+                .and_then(|bytes| bincode::deserialize(&bytes).ok())
+                .ok_or(StatusCode::BAD_REQUEST)
+                .map(|state| {
+                    self.import_control_state(state)
+                        .map(|r| json::to_string(&r).unwrap())
+                }),
+            (Method::GET, "/metrics") => Ok(self.metrics().map(|m| m.to_string())),
+            (Method::POST, "/metrics") => Ok(self.metrics().map(|m| json::to_string(&m).unwrap())),
+            (Method::POST, "/checkpoint") => Ok(self
+                .checkpoint(authority)
+                .map(|r| json::to_string(&r).unwrap())),
             (Method::POST, "/remove_node") => json::from_slice(&body)
                 .map_err(|_| StatusCode::BAD_REQUEST)
                 .map(|args| {
@@ -272,10 +491,40 @@ impl ControllerInner {
                     recipe_version + 1 - recipes.len(),
                     Some(self.log.clone()),
                 );
+
+                // state saved by the last checkpoint can be restored rather than replayed, as long
+                // as the graph is rebuilt from the same recipe as it was taken with
+                let checkpoint = self
+                    .checkpoint
+                    .filter(|c| c.recipe_version == recipe_version);
+                if let Some(c) = checkpoint {
+                    info!(self.log, "Restoring materializations from checkpoint"; "id" => c.id);
+                }
+                self.materializations.restore_from(checkpoint.map(|c| c.id));
+
+                // bases kept on disk are recovered from there, and other bases from their logs
+                let recover_logs = match self.persistence.wal {
+                    Some(ref wal) => {
+                        wal.recover && self.persistence.mode == DurabilityMode::MemoryOnly
+                    }
+                    None => false,
+                };
+                if recover_logs {
+                    info!(self.log, "Recovering bases from write-ahead logs");
+                }
+                self.materializations.recover_logs(recover_logs);
                 for r in recipes {
                     self.apply_recipe(self.recipe.clone().extend(&r).unwrap())
                         .unwrap();
                 }
+                self.materializations.restore_from(None);
+                self.materializations.recover_logs(false);
+            }
+
+            if let Some(log) = self.pending_rebuild.take() {
+                info!(self.log, "Rebuilding graph from log"; "#migrations" => log.migrations.len());
+                self.rebuild(log)
+                    .unwrap_or_else(|e| panic!("failed to rebuild graph: {}", e));
             }
         }
 
@@ -356,8 +605,33 @@ impl ControllerInner {
         Ok(())
     }
 
+    pub(crate) fn handle_domain_failure(&mut self, failure: DomainFailure) {
+        crit!(self.log, "domain has failed";
+              "domain" => failure.domain.index(),
+              "shard" => failure.shard,
+              "message" => &failure.message);
+        if let Some(ref backtrace) = failure.backtrace {
+            debug!(self.log, "domain failed at:\n{}", backtrace);
+        }
+
+        match self.domains.get_mut(&failure.domain) {
+            Some(dh) => dh.mark_failed(&failure),
+            None => {
+                // the domain was removed while it was failing
+                return;
+            }
+        }
+        self.domain_failures.push(failure);
+    }
+
     /// Construct `ControllerInner` with a specified listening interface
-    pub(super) fn new(listen_addr: IpAddr, log: slog::Logger, state: ControllerState) -> Self {
+    pub(super) fn new(
+        listen_addr: IpAddr,
+        internal_addr: SocketAddr,
+        log: slog::Logger,
+        state: ControllerState,
+        rebuild: Option<GraphLog>,
+    ) -> Self {
         let mut g = petgraph::Graph::new();
         let source = g.add_node(node::Node::new(
             "source",
@@ -382,14 +656,18 @@ impl ControllerInner {
         let mut recipe = Recipe::blank(Some(log.clone()));
         recipe.enable_reuse(state.config.reuse);
 
+        let graph_log = GraphRecorder::new(state.config.graph_log.clone());
+
         ControllerInner {
             ingredients: g,
             source: source,
             ndomains: 0,
             listen_addr,
+            internal_addr,
 
             materializations,
             sharding: state.config.sharding,
+            domain_strategy: state.config.domain_strategy,
             domain_config: state.config.domain_config,
             persistence: state.config.persistence,
             heartbeat_every: state.config.heartbeat_every,
@@ -409,6 +687,16 @@ impl ControllerInner {
             workers: HashMap::default(),
 
             pending_recovery,
+            pending_rebuild: rebuild,
+            graph_log,
+            checkpoint: state.checkpoint,
+            last_statistics: HashMap::default(),
+            last_diagnosed: MetricsSnapshot::default(),
+            next_quiesce: 0,
+            paused: HashSet::new(),
+            domain_failures: Vec::new(),
+            topology_version: 0,
+            last_migration: None,
             last_checked_workers: Instant::now(),
         }
     }
@@ -468,19 +756,49 @@ impl ControllerInner {
         let mut m = Migration {
             mainline: self,
             added: Default::default(),
+            removed: Default::default(),
             columns: Default::default(),
+            modified: Default::default(),
+            union_parents: Default::default(),
+            seeds: Default::default(),
             readers: Default::default(),
+            placements: Default::default(),
+            worker: None,
+            expose_atomically: false,
+            verify_replays: false,
+            replay_priority: ReplayPriority::default(),
+            crash_before_replay: None,
+            remove_before_ready: None,
+            events: None,
+            replaced: Vec::new(),
+            reuse: true,
+            reused: Vec::new(),
+            released: Vec::new(),
+            renamed: Vec::new(),
+            recorded: Vec::new(),
+            keys: HashMap::new(),
             context: context,
             start: time::Instant::now(),
             log: miglog,
         };
         let r = f(&mut m);
-        m.commit();
+        m.commit().unwrap_or_else(|e| panic!("{}", e));
         r
     }
 
     /// Perform a new query schema migration.
+    ///
+    /// Panics if the migration fails.
     pub fn migrate<F, T>(&mut self, f: F) -> T
+    where
+        F: FnOnce(&mut Migration) -> T,
+    {
+        self.try_migrate(f).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Perform a new query schema migration. If the migration fails part way through, it is
+    /// rolled back (see `Migration::commit`), and the error says what went wrong.
+    pub fn try_migrate<F, T>(&mut self, f: F) -> Result<T, String>
     where
         F: FnOnce(&mut Migration) -> T,
     {
@@ -489,15 +807,102 @@ impl ControllerInner {
         let mut m = Migration {
             mainline: self,
             added: Default::default(),
+            removed: Default::default(),
             columns: Default::default(),
+            modified: Default::default(),
+            union_parents: Default::default(),
+            seeds: Default::default(),
             readers: Default::default(),
+            placements: Default::default(),
+            worker: None,
+            expose_atomically: false,
+            verify_replays: false,
+            replay_priority: ReplayPriority::default(),
+            crash_before_replay: None,
+            remove_before_ready: None,
+            events: None,
+            replaced: Vec::new(),
+            reuse: true,
+            reused: Vec::new(),
+            released: Vec::new(),
+            renamed: Vec::new(),
+            recorded: Vec::new(),
+            keys: HashMap::new(),
             context: Default::default(),
             start: time::Instant::now(),
             log: miglog,
         };
         let r = f(&mut m);
-        m.commit();
-        r
+        m.commit()?;
+        Ok(r)
+    }
+
+    /// Work out what a query schema migration would do, without doing it (see
+    /// `Migration::plan`). The graph is left as it was, and no domain hears of the migration.
+    pub fn plan_migration<F, T>(&mut self, f: F) -> Result<(T, MigrationPlan), String>
+    where
+        F: FnOnce(&mut Migration) -> T,
+    {
+        // replays are estimated from the size of the state they start at
+        let mut rows = HashMap::new();
+        for (_, (_, nodes)) in self.get_statistics().domains {
+            for (ni, ns) in nodes {
+                if let Some(size) = ns.state_size {
+                    *rows.entry(ni).or_insert(0) += size.rows;
+                }
+            }
+        }
+
+        info!(self.log, "planning migration");
+        let ingredients = self.ingredients.clone();
+        let ndomains = self.ndomains;
+        let remap = self.remap.clone();
+
+        let miglog = self.log.new(o!());
+        let mut m = Migration {
+            mainline: self,
+            added: Default::default(),
+            removed: Default::default(),
+            columns: Default::default(),
+            modified: Default::default(),
+            union_parents: Default::default(),
+            seeds: Default::default(),
+            readers: Default::default(),
+            placements: Default::default(),
+            worker: None,
+            expose_atomically: false,
+            verify_replays: false,
+            replay_priority: ReplayPriority::default(),
+            crash_before_replay: None,
+            remove_before_ready: None,
+            events: None,
+            replaced: Vec::new(),
+            reuse: true,
+            reused: Vec::new(),
+            released: Vec::new(),
+            renamed: Vec::new(),
+            recorded: Vec::new(),
+            keys: HashMap::new(),
+            context: Default::default(),
+            start: time::Instant::now(),
+            log: miglog,
+        };
+        let r = f(&mut m);
+        let plan = m.plan(&rows);
+
+        self.ingredients = ingredients;
+        self.ndomains = ndomains;
+        self.remap = remap;
+        plan.map(|plan| (r, plan))
+    }
+
+    /// Replay the migrations in the given log, one at a time.
+    fn rebuild(&mut self, log: GraphLog) -> Result<(), String> {
+        let mut nodes = HashMap::new();
+        for ops in log.migrations {
+            self.try_migrate(|mig| graph_log::replay(mig, ops, &mut nodes))??;
+        }
+        Ok(())
     }
 
     #[cfg(test)]
@@ -540,7 +945,102 @@ impl ControllerInner {
             .collect()
     }
 
-    fn find_view_for(&self, node: NodeIndex) -> Option<NodeIndex> {
+    /// Describe the bases, views, and nodes of the graph, along with the domains that the nodes are
+    /// in and the workers that those domains run on.
+    pub fn describe(&self) -> TopologyDescription {
+        let graph = &self.ingredients;
+        let live = |ni: &NodeIndex| *ni != self.source && !graph[*ni].is_dropped();
+        let neighbors = |ni: NodeIndex, direction: petgraph::EdgeDirection| {
+            let mut neighbors: Vec<_> = graph
+                .neighbors_directed(ni, direction)
+                .filter(|ni| live(ni))
+                .collect();
+            neighbors.sort();
+            neighbors
+        };
+
+        let bases = self
+            .inputs()
+            .into_iter()
+            .map(|(name, ni)| {
+                let n = &graph[ni];
+                let base = n.get_base().unwrap();
+                let description = BaseDescription {
+                    node: ni,
+                    columns: n.fields().to_vec(),
+                    schema: base.schema().map(|schema| schema.to_vec()),
+                    key: base.key().map(|key| key.to_vec()),
+                };
+                (name, description)
+            })
+            .collect();
+
+        let views = graph
+            .node_indices()
+            .filter(|ni| live(ni))
+            .filter_map(|ni| {
+                let n = &graph[ni];
+                n.with_reader(|r| {
+                    let description = ViewDescription {
+                        reader: ni,
+                        of: r.is_for(),
+                        columns: n.fields().to_vec(),
+                        key: r.key().map(|key| key.to_vec()),
+                    };
+                    (n.name().to_owned(), description)
+                })
+                .ok()
+            })
+            .collect();
+
+        let nodes: Vec<_> = graph
+            .node_indices()
+            .filter(|ni| live(ni))
+            .map(|ni| {
+                let n = &graph[ni];
+                NodeDescription {
+                    node: ni,
+                    name: n.name().to_owned(),
+                    kind: node_kind(n),
+                    operator: if n.is_internal() {
+                        Some(n.description(true))
+                    } else {
+                        None
+                    },
+                    columns: n.fields().to_vec(),
+                    parents: neighbors(ni, petgraph::EdgeDirection::Incoming),
+                    children: neighbors(ni, petgraph::EdgeDirection::Outgoing),
+                    domain: n.domain(),
+                    shards: n.sharded_by().shards(),
+                }
+            })
+            .collect();
+
+        let mut domains: Vec<_> = self
+            .domains
+            .values()
+            .map(|dh| DomainDescription {
+                domain: dh.index(),
+                workers: (0..dh.shards()).map(|shard| dh.assignment(shard)).collect(),
+                nodes: nodes
+                    .iter()
+                    .filter(|n| n.domain == dh.index())
+                    .map(|n| n.node)
+                    .collect(),
+            })
+            .collect();
+        domains.sort_by_key(|d| d.domain);
+
+        TopologyDescription {
+            version: self.topology_version,
+            bases,
+            views,
+            nodes,
+            domains,
+        }
+    }
+
+    pub(super) fn find_view_for(&self, node: NodeIndex) -> Option<NodeIndex> {
         // reader should be a child of the given node. however, due to sharding, it may not be an
         // *immediate* child. furthermore, once we go beyond depth 1, we may accidentally hit an
         // *unrelated* reader node. to account for this, readers keep track of what node they are
@@ -561,9 +1061,8 @@ impl ControllerInner {
         reader
     }
 
-    /// Obtain a `ViewBuilder` that can be sent to a client and then used to query a given
-    /// (already maintained) reader node called `name`.
-    pub fn view_builder(&self, name: &str) -> Option<ViewBuilder> {
+    /// Find the reader node of the view called `name`.
+    fn find_reader(&self, name: &str) -> Option<NodeIndex> {
         // first try to resolve the node via the recipe, which handles aliasing between identical
         // queries.
         let node = match self.recipe.node_addr_for(name) {
@@ -575,7 +1074,13 @@ impl ControllerInner {
             }
         };
 
-        self.find_view_for(node).map(|r| {
+        self.find_view_for(node)
+    }
+
+    /// Obtain a `ViewBuilder` that can be sent to a client and then used to query a given
+    /// (already maintained) reader node called `name`.
+    pub fn view_builder(&self, name: &str) -> Option<ViewBuilder> {
+        self.find_reader(name).map(|r| {
             let domain = self.ingredients[r].domain();
             let columns = self.ingredients[r].fields().to_vec();
             let shards = (0..self.domains[&domain].shards())
@@ -587,10 +1092,85 @@ impl ControllerInner {
                 node: r,
                 columns,
                 shards,
+                scatter: self.ingredients[r].is_scattered_reader(),
+                filter: None,
             }
         })
     }
 
+    /// Move the readers of the given views out of the domain they are in, and into a new domain
+    /// on the same worker, so that keeping them up to date no longer takes up the old domain's
+    /// thread. Returns the new domain.
+    ///
+    /// Each view gets a new reader, which is filled like that of any new view, and then takes over
+    /// serving reads from the old reader before that is removed. `View`s of the moved views thus
+    /// keep working throughout, though reads into partial views may block while they are filled
+    /// again. Only readers can be moved, and only out of domains that aren't sharded.
+    pub fn move_views(&mut self, names: Vec<String>) -> Result<DomainIndex, String> {
+        let mut readers = Vec::with_capacity(names.len());
+        for name in &names {
+            let r = self
+                .find_reader(name)
+                .ok_or_else(|| format!("no view named {}", name))?;
+            readers.push(r);
+        }
+        if readers.is_empty() {
+            return Err("no views to move".to_owned());
+        }
+
+        let from = self.ingredients[readers[0]].domain();
+        if readers
+            .iter()
+            .any(|&r| self.ingredients[r].domain() != from)
+        {
+            return Err("only views in the same domain can be moved together".to_owned());
+        }
+        if self.domains[&from].shards() != 1 {
+            return Err(format!(
+                "domain {} is sharded, and can't be split",
+                from.index()
+            ));
+        }
+        let worker = self.domains[&from].assignment(0);
+
+        let mut to = None;
+        for old in readers {
+            // the first reader gets a new domain, and the others join it
+            let placement = match to {
+                None => Placement::Dedicated,
+                Some(d) => Placement::Domain(d.index()),
+            };
+            let worker = worker.clone();
+            let new = self.try_migrate(move |mig| {
+                mig.worker = Some(worker);
+                mig.copy_reader(old, placement)
+            })?;
+
+            let n = &self.ingredients[new];
+            let domain = n.domain();
+            to = Some(domain);
+            info!(self.log, "moving reader";
+                  "from" => old.index(),
+                  "to" => new.index(),
+                  "domain" => domain.index());
+
+            let d = self.domains.get_mut(&domain).unwrap();
+            d.send_to_healthy(
+                box payload::Packet::TakeOverReader {
+                    node: n.local_addr(),
+                    from: old,
+                },
+                &self.workers,
+            )
+            .map_err(|e| format!("failed to move reader {}: {:?}", old.index(), e))?;
+            d.wait_for_ack()
+                .map_err(|e| format!("failed to move reader {}: {:?}", old.index(), e))?;
+
+            self.remove_with_unused_ancestors(&[old])?;
+        }
+        Ok(to.unwrap())
+    }
+
     /// Obtain a TableBuild that can be used to construct a Table to perform writes and deletes
     /// from the given named base node.
     pub fn table_builder(&self, base: &str) -> Option<TableBuilder> {
@@ -639,6 +1219,13 @@ impl ControllerInner {
             node.fields().len() - base_operator.get_dropped().len()
         );
         let schema = self.recipe.get_base_schema(base);
+        let base_schema = base_operator.schema().map(|cols| {
+            cols.iter()
+                .enumerate()
+                .filter(|&(n, _)| !base_operator.get_dropped().contains_key(n))
+                .map(|(_, c)| c.clone())
+                .collect()
+        });
 
         Some(TableBuilder {
             local_port: None,
@@ -650,35 +1237,257 @@ impl ControllerInner {
             table_name: node.name().to_owned(),
             columns,
             schema,
+            base_schema,
+            max_value_size: base_operator.max_value_size(),
         })
     }
 
-    /// Get statistics about the time spent processing different parts of the graph.
+    /// Get statistics about the time spent processing different parts of the graph, and about
+    /// the state the graph holds.
+    ///
+    /// Every domain is asked at once, and the domains that haven't answered within
+    /// `STATISTICS_TIMEOUT_MS` are reported with the last statistics they did give, if any.
+    /// Domains that have failed aren't asked, and are reported along with why they failed.
     pub fn get_statistics(&mut self) -> GraphStats {
+        let deadline = Instant::now() + Duration::from_millis(STATISTICS_TIMEOUT_MS);
         let workers = &self.workers;
-        // TODO: request stats from domains in parallel.
-        let domains = self
+        let mut requested: HashMap<_, _> = self
             .domains
             .iter_mut()
-            .flat_map(|(di, s)| {
-                s.send_to_healthy(box payload::Packet::GetStatistics, workers)
-                    .unwrap();
-                s.wait_for_statistics()
-                    .unwrap()
-                    .into_iter()
-                    .enumerate()
-                    .map(move |(i, (domain_stats, node_stats))| {
-                        let node_map = node_stats
-                            .into_iter()
-                            .map(|(ni, ns)| (ni.into(), ns))
-                            .collect();
+            .filter(|&(_, ref dh)| !dh.failed())
+            .map(|(&di, dh)| (di, dh.request_statistics(workers, Some(deadline))))
+            .collect();
 
-                        ((di.clone(), i), (domain_stats, node_map))
-                    })
+        let mut freshness = HashMap::new();
+        for (&di, dh) in &mut self.domains {
+            if dh.failed() {
+                // there's no use waiting for a domain that won't answer
+                freshness.insert(di, Freshness::Failed);
+                continue;
+            }
+
+            let answer: Result<_, WaitError> = requested
+                .remove(&di)
+                .unwrap()
+                .and_then(|()| dh.wait_for_statistics(Some(deadline)));
+            match answer {
+                Ok(shards) => {
+                    self.last_statistics.insert(di, shards);
+                    freshness.insert(di, Freshness::Fresh);
+                }
+                Err(e) => {
+                    warn!(self.log, "domain did not report statistics";
+                          "domain" => di.index(),
+                          "error" => ?e);
+                    let f = if self.last_statistics.contains_key(&di) {
+                        Freshness::Stale
+                    } else {
+                        Freshness::Unreachable
+                    };
+                    freshness.insert(di, f);
+                }
+            }
+        }
+        let domains = &self.domains;
+        self.last_statistics
+            .retain(|di, _| domains.contains_key(di));
+        self.domain_failures
+            .retain(|f| domains.contains_key(&f.domain));
+
+        let mut stats = HashMap::new();
+        for (&di, shards) in &self.last_statistics {
+            for (shard, (domain_stats, node_stats)) in shards.iter().enumerate() {
+                stats.insert((di, shard), (domain_stats.clone(), node_stats.clone()));
+            }
+        }
+        self.summarize_statistics(stats, &freshness)
+    }
+
+    /// Add up what the shards of each domain reported per node and per domain, and name the nodes.
+    fn summarize_statistics(
+        &self,
+        stats: HashMap<(DomainIndex, usize), (DomainStats, HashMap<NodeIndex, NodeStats>)>,
+        freshness: &HashMap<DomainIndex, Freshness>,
+    ) -> GraphStats {
+        let graph = &self.ingredients;
+        let outputs: HashMap<_, _> = self
+            .outputs()
+            .into_iter()
+            .map(|(name, ni)| (ni, name))
+            .collect();
+
+        let mut nodes: Vec<_> = graph
+            .node_indices()
+            .filter(|&ni| ni != self.source && !graph[ni].is_dropped())
+            .filter(|&ni| graph[ni].has_domain() && self.domains.contains_key(&graph[ni].domain()))
+            .map(|ni| {
+                let n = &graph[ni];
+                let public_name = if n.is_base() || n.is_reader() {
+                    Some(n.name().to_owned())
+                } else {
+                    outputs.get(&ni).cloned()
+                };
+                let mut entry = NodeEntry {
+                    node: ni,
+                    name: n.name().to_owned(),
+                    public_name,
+                    kind: node_kind(n),
+                    operator: if n.is_internal() {
+                        Some(n.description(true))
+                    } else {
+                        None
+                    },
+                    domain: n.domain(),
+                    freshness: freshness[&n.domain()],
+                    processed_records: 0,
+                    process_time: 0,
+                    peak_records_out: 0,
+                    peak_fanout: 0,
+                    malformed_records: 0,
+                    materialized: MaterializationStatus::Not,
+                    rows: if n.is_reader() { None } else { Some(0) },
+                    bytes: 0,
+                };
+                for shard in 0..self.domains[&n.domain()].shards() {
+                    let ns = match stats.get(&(n.domain(), shard)) {
+                        Some(&(_, ref nodes)) => nodes.get(&ni),
+                        None => None,
+                    };
+                    if let Some(ns) = ns {
+                        entry.processed_records += ns.processed_records;
+                        entry.process_time += ns.process_time;
+                        entry.peak_records_out =
+                            cmp::max(entry.peak_records_out, ns.peak_records_out);
+                        entry.peak_fanout = cmp::max(entry.peak_fanout, ns.peak_fanout);
+                        entry.malformed_records += ns.malformed_records;
+                        entry.materialized = ns.materialized;
+                        match ns.state_size {
+                            Some(ref size) => {
+                                entry.rows = entry.rows.map(|rows| rows + size.rows);
+                                entry.bytes += size.total_bytes() + ns.aux_bytes;
+                            }
+                            None => entry.bytes += ns.mem_size,
+                        }
+                    }
+                }
+                entry
+            })
+            .collect();
+        nodes.sort_by_key(|n| n.node);
+
+        let mut domain_entries: Vec<_> = self
+            .domains
+            .values()
+            .map(|dh| {
+                let di = dh.index();
+                let mut entry = DomainEntry {
+                    domain: di,
+                    shards: dh.shards(),
+                    freshness: freshness[&di],
+                    nodes: 0,
+                    total_time: 0,
+                    wait_time: 0,
+                    processed_records: 0,
+                    rows: 0,
+                    bytes: 0,
+                    queued_packets: 0,
+                    coalesced_packets: 0,
+                    time_budget: Default::default(),
+                };
+                for shard in 0..dh.shards() {
+                    if let Some(&(ref ds, _)) = stats.get(&(di, shard)) {
+                        entry.total_time += ds.total_time;
+                        entry.wait_time += ds.wait_time;
+                        entry.queued_packets += ds.queued_packets;
+                        entry.coalesced_packets += ds.coalesced_packets;
+                        entry.time_budget.merge(&ds.time_budget);
+                    }
+                }
+                for n in nodes.iter().filter(|n| n.domain == di) {
+                    entry.nodes += 1;
+                    entry.processed_records += n.processed_records;
+                    entry.rows += n.rows.unwrap_or(0);
+                    entry.bytes += n.bytes;
+                }
+                entry
             })
             .collect();
+        domain_entries.sort_by_key(|d| d.domain);
+
+        GraphStats::new(stats, nodes, domain_entries, self.domain_failures.clone())
+    }
+
+    /// List the `n` materializations that take up the most memory, largest first, along with
+    /// their estimated size in bytes. Sharded materializations are listed once per shard.
+    pub fn largest_materializations(&mut self, n: usize) -> Vec<(NodeIndex, String, u64)> {
+        let stats = self.get_statistics();
+        let mut sizes: Vec<_> = stats
+            .domains
+            .into_iter()
+            .flat_map(|(_, (_, nodes))| nodes)
+            .filter(|&(_, ref ns)| match ns.materialized {
+                MaterializationStatus::Not => false,
+                _ => true,
+            })
+            .map(|(ni, ns)| {
+                let bytes = ns
+                    .state_size
+                    .as_ref()
+                    .map(|s| s.total_bytes())
+                    .unwrap_or(ns.mem_size);
+                (ni, self.ingredients[ni].name().to_owned(), bytes)
+            })
+            .collect();
+        sizes.sort_by(|a, b| b.2.cmp(&a.2));
+        sizes.truncate(n);
+        sizes
+    }
+
+    /// Save the state of every fully materialized node to disk, so that it can be restored rather
+    /// than replayed when the controller is restarted with the same recipe.
+    ///
+    /// Domains are checkpointed one at a time, so writes that are in flight while the checkpoint
+    /// is taken may be reflected in some of the saved state but not in the rest.
+    pub fn checkpoint<A: Authority + 'static>(&mut self, authority: &Arc<A>) -> Result<(), String> {
+        if self.persistence.mode == DurabilityMode::Permanent {
+            // base tables are recovered from disk, and may have seen writes since the last
+            // checkpoint that the state derived from them would be missing
+            return Err("cannot checkpoint when base tables are persisted".to_owned());
+        }
 
-        GraphStats { domains: domains }
+        let id = self.checkpoint.map(|c| c.id + 1).unwrap_or(0);
+        let workers = &self.workers;
+        for (di, domain) in self.domains.iter_mut() {
+            domain
+                .send_to_healthy(box payload::Packet::Checkpoint { id }, workers)
+                .map_err(|e| format!("failed to checkpoint domain {}: {:?}", di.index(), e))?;
+            let saved = domain
+                .wait_for_checkpoint()
+                .map_err(|e| format!("failed to checkpoint domain {}: {:?}", di.index(), e))?;
+            if saved.iter().any(Option::is_none) {
+                return Err(format!("domain {} failed to write checkpoint", di.index()));
+            }
+        }
+
+        let checkpoint = Checkpoint {
+            id,
+            recipe_version: self.recipe.version(),
+        };
+        match authority.read_modify_write(STATE_KEY, |state: Option<ControllerState>| match state {
+            None => unreachable!(),
+            Some(ref state) if state.epoch > self.epoch => Err(()),
+            Some(mut state) => {
+                state.checkpoint = Some(checkpoint);
+                Ok(state)
+            }
+        }) {
+            Ok(Ok(_)) => {}
+            _ => return Err("Failed to persist checkpoint".to_owned()),
+        }
+
+        info!(self.log, "checkpoint completed"; "id" => id);
+        self.checkpoint = Some(checkpoint);
+        Ok(())
     }
 
     pub fn get_instances(&self) -> Vec<(WorkerIdentifier, bool, Duration)> {
@@ -696,10 +1505,9 @@ impl ControllerInner {
             .domains
             .iter_mut()
             .map(|(di, s)| {
-                s.send_to_healthy(box payload::Packet::GetStatistics, workers)
-                    .unwrap();
+                s.request_statistics(workers, None).unwrap();
                 let to_evict: Vec<(NodeIndex, u64)> = s
-                    .wait_for_statistics()
+                    .wait_for_statistics(None)
                     .unwrap()
                     .into_iter()
                     .flat_map(move |(_, node_stats)| {
@@ -742,6 +1550,683 @@ impl ControllerInner {
         total_evicted
     }
 
+    /// Force the reader for the view `name` to evict the given keys, or, if no keys are given, to
+    /// evict its least recently read keys until it is within its memory limit.
+    pub fn evict_view(
+        &mut self,
+        (name, keys): (String, Option<Vec<Vec<DataType>>>),
+    ) -> Result<(), String> {
+        let r = match self.view_builder(&name) {
+            Some(vb) => vb.node,
+            None => return Err(format!("no view named {}", name)),
+        };
+        let domain = self.ingredients[r].domain();
+        let node = self.ingredients[r].local_addr();
+        self.domains
+            .get_mut(&domain)
+            .unwrap()
+            .send_to_healthy(
+                box payload::Packet::ForceEvict { node, keys },
+                &self.workers,
+            )
+            .map_err(|e| format!("failed to send eviction to {}: {:?}", name, e))
+    }
+
+    /// Compute a checksum of the rows materialized at the given node, or `None` if it isn't
+    /// materialized. The checksums of a node's shards are combined into one.
+    ///
+    /// This can be used to check that the source and the sink of a replay path agree once the
+    /// replay has completed, or that a view agrees with its inputs.
+    pub fn checksum(&mut self, node: NodeIndex) -> Result<Option<u64>, String> {
+        if self.ingredients.node_weight(node).is_none() || node == self.source {
+            return Err(format!("no node {}", node.index()));
+        }
+
+        let domain = self.ingredients[node].domain();
+        let local = self.ingredients[node].local_addr();
+        let workers = &self.workers;
+        let dh = self.domains.get_mut(&domain).unwrap();
+        dh.send_to_healthy(box payload::Packet::GetChecksum { node: local }, workers)
+            .map_err(|e| format!("failed to request checksum of {}: {:?}", node.index(), e))?;
+        let shards = dh
+            .wait_for_checksums()
+            .map_err(|e| format!("failed to get checksum of {}: {:?}", node.index(), e))?;
+
+        // each shard has a disjoint subset of the rows, so their checksums add up
+        Ok(shards
+            .into_iter()
+            .fold(Some(0u64), |sum, c| Some(sum?.wrapping_add(c?))))
+    }
+
+    /// Compute a checksum of the rows in the view `name` that are visible to reads.
+    pub fn view_checksum(&mut self, name: String) -> Result<Option<u64>, String> {
+        let r = match self.view_builder(&name) {
+            Some(vb) => vb.node,
+            None => return Err(format!("no view named {}", name)),
+        };
+        self.checksum(r)
+    }
+
+    /// Recompute every fully materialized node from the rows in the base tables, and report the
+    /// rows that each one is missing or has in excess.
+    pub fn check_consistency(&mut self) -> Result<ConsistencyReport, String> {
+        consistency::check(&self.ingredients, &mut self.domains, &self.workers)
+    }
+
+    /// Find the domain shards that packets kept piling up at since the last diagnosis, while they
+    /// weren't held up sending to the domains below them.
+    pub fn diagnose(
+        &mut self,
+        thresholds: SlowDomainThresholds,
+    ) -> Result<Vec<SlowDomain>, String> {
+        let metrics = self.metrics()?;
+        let slow = diagnosis::slow_domains(&self.last_diagnosed, &metrics, &thresholds);
+        self.last_diagnosed = metrics;
+        Ok(slow)
+    }
+
+    /// Hold back writes until everything that was written before has been processed and made
+    /// visible to reads, or until `timeout` has passed. Returns the domain shards that had yet to
+    /// drain by then.
+    pub fn quiesce(&mut self, timeout: Duration) -> Result<Vec<(DomainIndex, usize)>, String> {
+        let id = self.next_quiesce;
+        self.next_quiesce += 1;
+        let deadline = Instant::now() + timeout;
+        quiesce::quiesce(
+            &self.ingredients,
+            &mut self.domains,
+            &self.workers,
+            id,
+            deadline,
+        )
+    }
+
+    /// Take the packets that domains could not deliver from every worker that still runs a domain
+    /// that hasn't failed, oldest first.
+    pub fn dead_letters(&mut self) -> Result<Vec<DeadLetter>, String> {
+        let workers = &self.workers;
+        let mut asked = Vec::new();
+        for (&di, dh) in self.domains.iter_mut().filter(|&(_, ref dh)| !dh.failed()) {
+            dh.send_to_healthy(box payload::Packet::DrainDeadLetters, workers)
+                .map_err(|e| format!("failed to drain dead letters of {}: {:?}", di.index(), e))?;
+            asked.push(di);
+        }
+
+        // whichever domain of a worker is asked first hands over all of the worker's letters
+        let mut letters = Vec::new();
+        for di in asked {
+            let shards = self
+                .domains
+                .get_mut(&di)
+                .unwrap()
+                .wait_for_dead_letters()
+                .map_err(|e| format!("failed to drain dead letters of {}: {:?}", di.index(), e))?;
+            letters.extend(shards.into_iter().flatten());
+        }
+        letters.sort_by_key(|l| l.at);
+        Ok(letters)
+    }
+
+    /// Have every shard of `domain` start writing the events it sees to a capture file in `into`,
+    /// or stop writing them if `into` is `None`. Returns the files that the shards capture to.
+    pub fn capture(
+        &mut self,
+        (domain, into): (DomainIndex, Option<PathBuf>),
+    ) -> Result<Vec<PathBuf>, String> {
+        let workers = &self.workers;
+        let dh = self
+            .domains
+            .get_mut(&domain)
+            .ok_or_else(|| format!("no domain {}", domain.index()))?;
+        dh.send_to_healthy(box payload::Packet::Capture { into }, workers)
+            .map_err(|e| format!("failed to capture domain {}: {:?}", domain.index(), e))?;
+        let files = dh
+            .wait_for_capture()
+            .map_err(|e| format!("failed to capture domain {}: {:?}", domain.index(), e))?;
+
+        let mut captured = Vec::with_capacity(files.len());
+        for file in files {
+            captured.extend(file?);
+        }
+        Ok(captured)
+    }
+
+    /// Have every shard of `domain` stop processing updates and writes, and hold on to them until
+    /// the domain is resumed. The shards still answer the controller, so that, for example, the
+    /// checksums of their state can be taken while nothing changes it.
+    ///
+    /// No migration can be made while any domain is paused.
+    pub fn pause_domain(&mut self, domain: DomainIndex) -> Result<(), String> {
+        self.pause_or_resume(domain, true)?;
+        self.paused.insert(domain);
+        Ok(())
+    }
+
+    /// Have every shard of a paused `domain` process what it held on to, and carry on as before.
+    pub fn resume_domain(&mut self, domain: DomainIndex) -> Result<(), String> {
+        self.pause_or_resume(domain, false)?;
+        self.paused.remove(&domain);
+        Ok(())
+    }
+
+    fn pause_or_resume(&mut self, domain: DomainIndex, pause: bool) -> Result<(), String> {
+        let (what, p) = if pause {
+            ("pause", payload::Packet::Pause)
+        } else {
+            ("resume", payload::Packet::Resume)
+        };
+        info!(self.log, "{} domain", what; "domain" => domain.index());
+        let workers = &self.workers;
+        let dh = self
+            .domains
+            .get_mut(&domain)
+            .ok_or_else(|| format!("no domain {}", domain.index()))?;
+        dh.send_to_healthy(box p, workers)
+            .map_err(|e| format!("failed to {} domain {}: {:?}", what, domain.index(), e))?;
+        dh.wait_for_ack()
+            .map_err(|e| format!("failed to {} domain {}: {:?}", what, domain.index(), e))
+    }
+
+    /// Move `domain` to the worker `to` while it keeps running.
+    ///
+    /// The domain snapshots itself, and a copy is booted from the snapshot on `to`. The domain
+    /// logs the updates it processes from then on, and sends them to the copy once it is up. The
+    /// domains that send updates to the domain are then paused, and redirected to the copy, which
+    /// catches up on the last of the logged updates before they are resumed.
+    pub fn move_domain(
+        &mut self,
+        (domain, to): (DomainIndex, WorkerIdentifier),
+    ) -> Result<MoveStats, String> {
+        let start = Instant::now();
+        let from = {
+            let dh = self
+                .domains
+                .get(&domain)
+                .ok_or_else(|| format!("no domain {}", domain.index()))?;
+            if dh.shards() != 1 {
+                return Err(format!("domain {} is sharded", domain.index()));
+            }
+            if dh.failed() {
+                return Err(format!("domain {} has failed", domain.index()));
+            }
+            dh.assignment(0)
+        };
+        if from == to {
+            return Err(format!("domain {} is already on {:?}", domain.index(), to));
+        }
+        match self.workers.get(&to) {
+            Some(ws) if ws.healthy => {}
+            _ => return Err(format!("no healthy worker {:?}", to)),
+        }
+        if let Some(d) = self.paused.iter().next() {
+            return Err(format!("cannot move while domain {} is paused", d.index()));
+        }
+
+        // the domain's senders, and anything below it that may ask it for replays, reach it by
+        // where the worker last heard it was, so only updates can be redirected
+        let mut senders = HashSet::new();
+        let mut bfs = Bfs::new(&self.ingredients, self.source);
+        while let Some(ni) = bfs.next(&self.ingredients) {
+            let n = &self.ingredients[ni];
+            if n.is_dropped() || n.domain() != domain {
+                continue;
+            }
+            if n.is_base() || n.is_reader() {
+                return Err(format!(
+                    "domain {} has bases or readers, which can't be moved",
+                    domain.index()
+                ));
+            }
+            let mut below = Bfs::new(&self.ingredients, ni);
+            while let Some(b) = below.next(&self.ingredients) {
+                let status = self.materializations.get_status(&b, &self.ingredients[b]);
+                if status == MaterializationStatus::Partial {
+                    return Err(format!(
+                        "domain {} has partial state in or below it",
+                        domain.index()
+                    ));
+                }
+            }
+            if n.is_ingress() {
+                senders.extend(
+                    self.ingredients
+                        .neighbors_directed(ni, petgraph::EdgeDirection::Incoming)
+                        .map(|p| self.ingredients[p].domain())
+                        .filter(|&d| d != domain),
+                );
+            }
+        }
+        let nsenders: usize = senders.iter().map(|d| self.domains[d].shards()).sum();
+        let old_addr = self
+            .channel_coordinator
+            .get_addr(&(domain, 0))
+            .ok_or_else(|| format!("no address for domain {}.0", domain.index()))?;
+        info!(self.log, "moving domain";
+              "domain" => domain.index(), "from" => ?from, "to" => ?to, "senders" => nsenders);
+
+        // phase one: ship a snapshot to a copy on `to`, while the domain keeps going
+        let deadline = start + Duration::from_millis(MOVE_TIMEOUT_MS);
+        let endpoint = self.workers[&to].sender.clone();
+        let (addr, cr_poll, snapshot_rows) = {
+            let workers = &self.workers;
+            let dh = self.domains.get_mut(&domain).unwrap();
+            let started =
+                dh.start_move(&self.listen_addr, &endpoint, workers, self.epoch, deadline);
+            let (addr, cr_poll, rows) = match started {
+                Ok(started) => started,
+                Err(e) => {
+                    dh.cancel_move(None, workers);
+                    return Err(e);
+                }
+            };
+            if let Err(e) = dh.ship_move(addr, workers) {
+                dh.cancel_move(Some(addr), workers);
+                return Err(e);
+            }
+            (addr, cr_poll, rows)
+        };
+
+        // phase two: the senders are paused while they switch over to the copy, which catches up
+        // on the updates that the domain has processed since the snapshot
+        let pause = Instant::now();
+        let mut paused = Vec::new();
+        let mut redirected = Ok(());
+        for &d in &senders {
+            redirected = self.pause_or_resume(d, true);
+            if redirected.is_err() {
+                break;
+            }
+            paused.push(d);
+        }
+        let mut any_redirected = false;
+        if redirected.is_ok() {
+            let workers = &self.workers;
+            for &d in &senders {
+                let redirect = payload::Packet::Redirect {
+                    domain,
+                    shard: 0,
+                    from: old_addr,
+                    to: addr,
+                };
+                let dh = self.domains.get_mut(&d).unwrap();
+                redirected = dh
+                    .send_to_healthy(box redirect, workers)
+                    .map_err(|e| format!("failed to redirect domain {}: {:?}", d.index(), e))
+                    .and_then(|_| {
+                        dh.wait_for_ack().map_err(|e| {
+                            format!("failed to redirect domain {}: {:?}", d.index(), e)
+                        })
+                    });
+                if redirected.is_err() {
+                    break;
+                }
+                any_redirected = true;
+            }
+        }
+        if redirected.is_err() && !any_redirected {
+            let workers = &self.workers;
+            self.domains
+                .get_mut(&domain)
+                .unwrap()
+                .cancel_move(Some(addr), workers);
+        }
+        // once any sender has been redirected, only the copy is sent everything that comes after
+        let moved = redirected.and_then(|_| {
+            let workers = &self.workers;
+            let dh = self.domains.get_mut(&domain).unwrap();
+            let logged = dh.finish_move(nsenders, workers)?;
+            self.channel_coordinator.update_remote((domain, 0), addr);
+            dh.moved(to, cr_poll, &self.channel_coordinator)?;
+            Ok(logged)
+        });
+        if moved.is_ok() {
+            for ws in self.workers.values() {
+                let mut s = ws.sender.lock().unwrap();
+                let msg = CoordinationMessage {
+                    epoch: self.epoch,
+                    source: s.local_addr().unwrap(),
+                    payload: CoordinationPayload::DomainMoved(DomainDescriptor::new(
+                        domain, 0, addr,
+                    )),
+                };
+                if let Err(e) = s.send(msg) {
+                    warn!(self.log, "failed to tell worker that domain moved"; "error" => ?e);
+                }
+            }
+        }
+        for d in paused {
+            if let Err(e) = self.pause_or_resume(d, false) {
+                error!(self.log, "failed to resume domain after move";
+                       "domain" => d.index(), "error" => e);
+            }
+        }
+        let pause = pause.elapsed();
+        let logged_updates = moved?;
+
+        let stats = MoveStats {
+            domain,
+            from,
+            to,
+            snapshot_rows,
+            logged_updates,
+            total: start.elapsed().as_nanos() as u64,
+            paused: pause.as_nanos() as u64,
+        };
+        info!(self.log, "moved domain";
+              "domain" => domain.index(),
+              "rows" => stats.snapshot_rows,
+              "logged" => stats.logged_updates,
+              "paused" => ?pause);
+        Ok(stats)
+    }
+
+    /// The edges from egress nodes to the ingress nodes of `primary`, as the egress node, the
+    /// ingress node, and the ingress node of `standby` that the same egress node sends to.
+    fn standby_edges(
+        &self,
+        primary: DomainIndex,
+        standby: DomainIndex,
+    ) -> Result<Vec<(NodeIndex, NodeIndex, NodeIndex)>, String> {
+        let shards = |d: DomainIndex| {
+            self.domains
+                .get(&d)
+                .map(|dh| dh.shards())
+                .ok_or_else(|| format!("no domain {}", d.index()))
+        };
+        if primary == standby || shards(primary)? != shards(standby)? {
+            return Err(format!(
+                "domain {} can't stand by for domain {}",
+                standby.index(),
+                primary.index()
+            ));
+        }
+
+        let mut edges = Vec::new();
+        for ni in self.ingredients.node_indices() {
+            let n = &self.ingredients[ni];
+            if !n.is_ingress() || n.is_dropped() || n.domain() != primary {
+                continue;
+            }
+            let egress = self
+                .ingredients
+                .neighbors_directed(ni, petgraph::EdgeDirection::Incoming)
+                .next()
+                .unwrap();
+            if !self.ingredients[egress].is_egress() {
+                return Err(format!("ingress {} is not fed by an egress", ni.index()));
+            }
+            let counterpart = self
+                .ingredients
+                .neighbors_directed(egress, petgraph::EdgeDirection::Outgoing)
+                .find(|&c| {
+                    let c = &self.ingredients[c];
+                    c.is_ingress() && !c.is_dropped() && c.domain() == standby
+                })
+                .ok_or_else(|| {
+                    format!(
+                        "domain {} has no ingress fed by the same egress as ingress {}",
+                        standby.index(),
+                        ni.index()
+                    )
+                })?;
+            edges.push((egress, ni, counterpart));
+        }
+        Ok(edges)
+    }
+
+    /// Hold `standby` in reserve for `primary`: the egress nodes that feed both stop sending to
+    /// `standby`, and switch over to it if `primary` fails, re-sending the updates that it missed.
+    /// If the egress nodes switched away from `standby` when it failed, it is re-added instead.
+    ///
+    /// The two domains are to hold the same nodes, and to have been sent the same updates until
+    /// now, such as two copies of a view that were added in the same migration.
+    pub fn add_standby(
+        &mut self,
+        (primary, standby): (DomainIndex, DomainIndex),
+    ) -> Result<(), String> {
+        for (egress, p, s) in self.standby_edges(primary, standby)? {
+            let n = &self.ingredients[egress];
+            let dh = self.domains.get_mut(&n.domain()).unwrap();
+            let add = payload::Packet::AddStandby {
+                node: n.local_addr(),
+                primary: p,
+                standby: s,
+            };
+            dh.send_to_healthy(box add, &self.workers)
+                .map_err(|e| format!("failed to add standby {}: {:?}", s.index(), e))?;
+            dh.wait_for_ack()
+                .map_err(|e| format!("failed to add standby {}: {:?}", s.index(), e))?;
+        }
+        info!(self.log, "added standby";
+              "primary" => primary.index(),
+              "standby" => standby.index());
+        Ok(())
+    }
+
+    /// Switch the egress nodes that send to `from` over to its standby `to`, which is re-sent the
+    /// updates that it missed first.
+    pub fn switch_to_standby(
+        &mut self,
+        (from, to): (DomainIndex, DomainIndex),
+    ) -> Result<(), String> {
+        for (egress, f, t) in self.standby_edges(from, to)? {
+            let n = &self.ingredients[egress];
+            let dh = self.domains.get_mut(&n.domain()).unwrap();
+            let switch = payload::Packet::SwitchEgress {
+                node: n.local_addr(),
+                from: f,
+                to: t,
+            };
+            dh.send_to_healthy(box switch, &self.workers)
+                .map_err(|e| format!("failed to switch to standby {}: {:?}", t.index(), e))?;
+            dh.wait_for_ack()
+                .map_err(|e| format!("failed to switch to standby {}: {:?}", t.index(), e))?;
+        }
+        info!(self.log, "switched to standby";
+              "from" => from.index(),
+              "to" => to.index());
+        Ok(())
+    }
+
+    /// Export everything that another controller needs to take over the graph while its domains
+    /// keep running, such as a warm standby that is to replace this controller.
+    ///
+    /// Graphs built from recipes cannot be exported, since the SQL state behind them is only
+    /// rebuilt by installing the recipe again.
+    pub fn export_control_state(&self) -> Result<ControlState, String> {
+        if self.recipe.version() != 0 {
+            return Err("cannot export the state of a graph built from a recipe".to_owned());
+        }
+        if let Some((id, _)) = self.workers.iter().find(|&(_, ws)| !ws.healthy) {
+            return Err(format!("cannot export while worker {:?} has failed", id));
+        }
+        if let Some((di, _)) = self.domains.iter().find(|&(_, dh)| dh.failed()) {
+            return Err(format!(
+                "cannot export while domain {} has failed",
+                di.index()
+            ));
+        }
+
+        let mut workers = Vec::with_capacity(self.workers.len());
+        for (&id, ws) in &self.workers {
+            let listen_addr = ws
+                .sender
+                .lock()
+                .unwrap()
+                .peer_addr()
+                .map_err(|e| format!("lost connection to worker {:?}: {}", id, e))?;
+            workers.push(WorkerPlacement {
+                id,
+                listen_addr,
+                read_addr: self.read_addrs[&id],
+            });
+        }
+
+        let mut domains: Vec<_> = self
+            .domains
+            .iter()
+            .map(|(&index, dh)| DomainPlacement {
+                index,
+                shards: (0..dh.shards())
+                    .map(|i| {
+                        let addr = self.channel_coordinator.get_addr(&(index, i)).unwrap();
+                        (dh.assignment(i), addr)
+                    })
+                    .collect(),
+            })
+            .collect();
+        domains.sort_by_key(|d| d.index);
+
+        let (graph_log, keys) = self.graph_log.recorded();
+        Ok(ControlState {
+            graph: self.ingredients.clone(),
+            source: self.source,
+            ndomains: self.ndomains,
+            remap: self.remap.clone(),
+            sharding: self.sharding,
+            persistence: self.persistence.clone(),
+            materializations: self.materializations.export(),
+            graph_log,
+            keys,
+            checkpoint: self.checkpoint,
+            topology_version: self.topology_version,
+            last_migration: self.last_migration,
+            workers,
+            domains,
+        })
+    }
+
+    /// Take over a graph that another controller exported with `export_control_state`.
+    ///
+    /// Every shard of every domain is first asked which nodes it has, and nothing is taken over if
+    /// any of them differ from the exported graph; the differences are listed in the error
+    /// instead. Otherwise, the domains send their control replies to this controller from then
+    /// on, and the workers their heartbeats. This controller must not have built a graph yet.
+    pub fn import_control_state(&mut self, state: ControlState) -> Result<(), String> {
+        if self.ingredients.node_count() != 1 || !self.domains.is_empty() {
+            return Err("cannot take over a graph when one has already been built".to_owned());
+        }
+
+        let mut workers = HashMap::new();
+        for w in &state.workers {
+            let sender = TcpSender::connect(&w.listen_addr)
+                .map_err(|e| format!("failed to connect to worker {:?}: {}", w.id, e))?;
+            workers.insert(w.id, WorkerStatus::new(Arc::new(Mutex::new(sender))));
+        }
+
+        // check that the domains are still as they were exported before touching any of them
+        let deadline = Instant::now() + Duration::from_millis(TAKEOVER_TIMEOUT_MS);
+        let mut domains = HashMap::new();
+        let mut drift = Vec::new();
+        for d in &state.domains {
+            for (i, &(_, addr)) in d.shards.iter().enumerate() {
+                self.channel_coordinator.insert_remote((d.index, i), addr);
+            }
+            let assignments = d.shards.iter().map(|&(worker, _)| worker).collect();
+            let mut dh =
+                DomainHandle::reconnect(d.index, assignments, &self.log, &self.channel_coordinator)
+                    .map_err(|e| {
+                        format!("failed to connect to domain {}: {}", d.index.index(), e)
+                    })?;
+            match dh.list_nodes(&self.listen_addr, deadline) {
+                Ok(shards) => {
+                    for (i, nodes) in shards.into_iter().enumerate() {
+                        drift.extend(takeover::drift(&state.graph, d.index, i, nodes));
+                    }
+                }
+                Err(e) => drift.push(e),
+            }
+            domains.insert(d.index, dh);
+        }
+        if !drift.is_empty() {
+            return Err(format!(
+                "refusing to take over a graph that has drifted from the exported state:\n  {}",
+                drift.join("\n  ")
+            ));
+        }
+
+        for dh in domains.values_mut() {
+            dh.adopt(&self.listen_addr, deadline)?;
+        }
+
+        // the workers only accept domain addresses from the controller of their current epoch, so
+        // they must learn about this controller first
+        for (id, ws) in &workers {
+            let mut s = ws.sender.lock().unwrap();
+            let msg = CoordinationMessage {
+                epoch: self.epoch,
+                source: s.local_addr().unwrap(),
+                payload: CoordinationPayload::TakeOver(self.internal_addr),
+            };
+            s.send(msg)
+                .map_err(|e| format!("failed to take over worker {:?}: {:?}", id, e))?;
+        }
+        for ws in workers.values().chain(self.workers.values()) {
+            let mut s = ws.sender.lock().unwrap();
+            for d in &state.domains {
+                for (i, &(_, addr)) in d.shards.iter().enumerate() {
+                    let msg = CoordinationMessage {
+                        epoch: self.epoch,
+                        source: s.local_addr().unwrap(),
+                        payload: CoordinationPayload::DomainBooted(DomainDescriptor::new(
+                            d.index, i, addr,
+                        )),
+                    };
+                    s.send(msg)
+                        .map_err(|e| format!("failed to tell workers about domains: {:?}", e))?;
+                }
+            }
+        }
+
+        self.ingredients = state.graph;
+        self.source = state.source;
+        self.ndomains = state.ndomains;
+        self.remap = state.remap;
+        self.sharding = state.sharding;
+        self.persistence = state.persistence;
+        self.materializations.import(state.materializations);
+        self.graph_log
+            .resume(&self.log, state.graph_log, state.keys);
+        self.checkpoint = state.checkpoint;
+        self.topology_version = state.topology_version;
+        self.last_migration = state.last_migration;
+        self.read_addrs
+            .extend(state.workers.iter().map(|w| (w.id, w.read_addr)));
+        self.workers.extend(workers);
+        self.domains = domains;
+
+        info!(self.log, "took over graph";
+              "#domains" => self.domains.len(),
+              "#workers" => state.workers.len());
+        Ok(())
+    }
+
+    /// Collect the current values of the counters of every domain, and of their nodes and readers,
+    /// from the domains themselves, wherever they run. Domains that have failed are skipped.
+    pub fn metrics(&mut self) -> Result<MetricsSnapshot, String> {
+        let workers = &self.workers;
+        let mut asked = Vec::new();
+        for (&di, dh) in self.domains.iter_mut().filter(|&(_, ref dh)| !dh.failed()) {
+            dh.send_to_healthy(box payload::Packet::GetMetrics, workers)
+                .map_err(|e| format!("failed to request metrics of {}: {:?}", di.index(), e))?;
+            asked.push(di);
+        }
+
+        let mut metrics = MetricsSnapshot::default();
+        for di in asked {
+            let shards = self
+                .domains
+                .get_mut(&di)
+                .unwrap()
+                .wait_for_metrics()
+                .map_err(|e| format!("failed to get metrics of {}: {:?}", di.index(), e))?;
+            for shard in shards {
+                metrics.extend(shard);
+            }
+        }
+        Ok(metrics)
+    }
+
     pub fn create_universe(&mut self, context: HashMap<String, DataType>) -> Result<(), String> {
         let log = self.log.clone();
         let mut r = self.recipe.clone();
@@ -796,10 +2281,12 @@ impl ControllerInner {
     }
 
     fn apply_recipe(&mut self, mut new: Recipe) -> Result<ActivationResult, String> {
-        let r = self.migrate(|mig| {
-            new.activate(mig)
-                .map_err(|e| format!("failed to activate recipe: {}", e))
-        });
+        let r = self
+            .try_migrate(|mig| {
+                new.activate(mig)
+                    .map_err(|e| format!("failed to activate recipe: {}", e))
+            })
+            .and_then(|r| r);
 
         match r {
             Ok(ref ra) => {
@@ -820,7 +2307,7 @@ impl ControllerInner {
                 topo_removals.reverse();
 
                 for leaf in topo_removals {
-                    self.remove_leaf(leaf)?;
+                    self.remove_with_unused_ancestors(&[leaf])?;
                 }
 
                 // now remove bases
@@ -892,6 +2379,21 @@ impl ControllerInner {
         }
     }
 
+    /// Work out what extending the current recipe with the given text would do to the graph,
+    /// without doing it (see `Migration::plan`). The recipe stays as it was.
+    pub fn plan_recipe(&mut self, add_txt: String) -> Result<MigrationPlan, String> {
+        let mut new = self
+            .recipe
+            .clone()
+            .extend(&add_txt)
+            .map_err(|(_, e)| format!("failed to extend recipe: {}", e))?;
+        self.plan_migration(|mig| {
+            new.activate(mig)
+                .map_err(|e| format!("failed to activate recipe: {}", e))
+        })
+        .and_then(|(r, plan)| r.map(|_| plan))
+    }
+
     pub fn install_recipe<A: Authority + 'static>(
         &mut self,
         authority: &Arc<A>,
@@ -925,104 +2427,125 @@ impl ControllerInner {
         }
     }
 
-    pub fn graphviz(&self, detailed: bool) -> String {
-        graphviz(&self.ingredients, detailed, &self.materializations)
+    pub fn graphviz(&mut self, detailed: bool) -> String {
+        // state sizes can only be had from the domains while all of them are up
+        let stats =
+            if detailed && self.pending_recovery.is_none() && self.workers.len() >= self.quorum {
+                Some(self.get_statistics())
+            } else {
+                None
+            };
+        graphviz(
+            &self.ingredients,
+            detailed,
+            &self.materializations,
+            stats.as_ref(),
+        )
     }
 
-    fn remove_leaf(&mut self, mut leaf: NodeIndex) -> Result<(), String> {
-        let mut removals = vec![];
-        let start = leaf;
-        assert!(!self.ingredients[leaf].is_source());
-
-        info!(
-            self.log,
-            "Computing removals for removing node {}",
-            leaf.index()
-        );
+    /// Remove the given nodes, along with the readers (and the ingress, egress, and sharder nodes
+    /// leading to them) that deliver their output, and every ancestor that nothing else uses any
+    /// more. Base nodes, and the leaves of other queries, are never removed as ancestors.
+    ///
+    /// Fails without removing anything if any of the given nodes still has other children.
+    pub(super) fn remove_with_unused_ancestors(
+        &mut self,
+        nodes: &[NodeIndex],
+    ) -> Result<(), String> {
+        let mut removing = HashSet::new();
+
+        // first, the given nodes and everything that only forwards their output
+        let mut below = nodes.to_vec();
+        while let Some(ni) = below.pop() {
+            if ni == self.source || self.ingredients[ni].is_base() {
+                return Err(format!("cannot remove base node {}", ni.index()));
+            }
+            removing.insert(ni);
 
-        if self
-            .ingredients
-            .neighbors_directed(leaf, petgraph::EdgeDirection::Outgoing)
-            .count()
-            > 0
-        {
-            // This query leaf node has children -- typically, these are readers, but they can also
-            // include egress nodes or other, dependent queries.
-            let mut has_non_reader_children = false;
-            let readers: Vec<_> = self
+            for child in self
                 .ingredients
-                .neighbors_directed(leaf, petgraph::EdgeDirection::Outgoing)
-                .filter(|ni| {
-                    if self.ingredients[*ni].is_reader() {
-                        true
-                    } else {
-                        has_non_reader_children = true;
-                        false
-                    }
-                })
-                .collect();
-            if has_non_reader_children {
-                // should never happen, since we remove nodes in reverse topological order
-                crit!(
-                    self.log,
-                    "not removing node {} yet, as it still has non-reader children",
-                    leaf.index()
-                );
-                unreachable!();
+                .neighbors_directed(ni, petgraph::EdgeDirection::Outgoing)
+            {
+                let c = &self.ingredients[child];
+                if c.is_reader() || c.is_egress() || c.is_ingress() || c.is_sharder() {
+                    below.push(child);
+                } else if !nodes.contains(&child) {
+                    return Err(format!(
+                        "cannot remove node {}, as node {} still depends on it",
+                        ni.index(),
+                        child.index()
+                    ));
+                }
             }
-            // nodes can have only one reader attached
-            assert!(readers.len() <= 1);
-            debug!(
-                        self.log,
-                        "Removing query leaf \"{}\"", self.ingredients[leaf].name();
-                        "node" => leaf.index(),
-                    );
-            if !readers.is_empty() {
-                removals.push(readers[0]);
-                leaf = readers[0];
-            } else {
-                unreachable!();
+        }
+
+        // then, every ancestor whose children are all going away
+        let mut above: Vec<_> = removing.iter().cloned().collect();
+        while let Some(ni) = above.pop() {
+            for parent in self
+                .ingredients
+                .neighbors_directed(ni, petgraph::EdgeDirection::Incoming)
+            {
+                if removing.contains(&parent)
+                    || parent == self.source
+                    || self.ingredients[parent].is_base()
+                    || self.recipe.sql_inc().is_leaf_address(parent)
+                {
+                    continue;
+                }
+
+                let unused = self
+                    .ingredients
+                    .neighbors_directed(parent, petgraph::EdgeDirection::Outgoing)
+                    .all(|c| removing.contains(&c) || self.ingredients[c].is_dropped());
+                if unused {
+                    removing.insert(parent);
+                    above.push(parent);
+                }
             }
         }
 
-        // `node` now does not have any children any more
-        assert_eq!(
-            self.ingredients
-                .neighbors_directed(leaf, petgraph::EdgeDirection::Outgoing)
-                .count(),
-            0
+        let mut removals: Vec<_> = removing.iter().cloned().collect();
+        removals.sort();
+        info!(
+            self.log,
+            "removing nodes";
+            "nodes" => ?removals.iter().map(|ni| ni.index()).collect::<Vec<_>>(),
         );
 
-        let mut nodes = vec![leaf];
-        while let Some(node) = nodes.pop() {
-            let mut parents = self
+        for &ni in &removals {
+            let parents: Vec<_> = self
                 .ingredients
-                .neighbors_directed(node, petgraph::EdgeDirection::Incoming)
-                .detach();
-            while let Some(parent) = parents.next_node(&self.ingredients) {
-                let edge = self.ingredients.find_edge(parent, node).unwrap();
-                self.ingredients.remove_edge(edge);
-
-                if !self.ingredients[parent].is_source()
-                    && !self.ingredients[parent].is_base()
-                    // ok to remove original start leaf
-                    && (parent == start || !self.recipe.sql_inc().is_leaf_address(parent))
-                    && self
-                        .ingredients
-                        .neighbors_directed(parent, petgraph::EdgeDirection::Outgoing)
-                        .count() == 0
-                {
-                    nodes.push(parent);
+                .neighbors_directed(ni, petgraph::EdgeDirection::Incoming)
+                .collect();
+            for parent in parents {
+                // egress nodes that stay must stop sending to the ingress nodes that go
+                if !removing.contains(&parent) && self.ingredients[parent].is_egress() {
+                    let n = &self.ingredients[parent];
+                    self.domains
+                        .get_mut(&n.domain())
+                        .unwrap()
+                        .send_to_healthy(
+                            box payload::Packet::RemoveEgressTx {
+                                node: n.local_addr(),
+                                target: ni,
+                            },
+                            &self.workers,
+                        )
+                        .unwrap();
                 }
-            }
 
-            removals.push(node);
+                let edge = self.ingredients.find_edge(parent, ni).unwrap();
+                self.ingredients.remove_edge(edge);
+            }
         }
 
         self.remove_nodes(removals.as_slice())
     }
 
     fn remove_nodes(&mut self, removals: &[NodeIndex]) -> Result<(), String> {
+        self.topology_version += 1;
+
         // Remove node from controller local state
         let mut domain_removals: HashMap<DomainIndex, Vec<LocalNodeIndex>> = HashMap::default();
         for ni in removals {
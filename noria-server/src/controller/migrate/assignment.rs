@@ -1,17 +1,272 @@
 //! Functions for assigning new nodes to thread domains.
 
 use dataflow::prelude::*;
+use noria::debug::plan::DomainStrategy;
 use petgraph;
 use slog::Logger;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
+/// Whether putting `node` in the domain `candidate` would make some path through its ancestors
+/// leave that domain and then come back into it (an a-b-a path).
+fn reenters(graph: &Graph, node: NodeIndex, candidate: usize) -> bool {
+    let mut stack: Vec<_> = graph
+        .neighbors_directed(node, petgraph::EdgeDirection::Incoming)
+        .filter(|&p| graph[p].has_domain() && graph[p].domain().index() != candidate)
+        .collect();
+    while let Some(p) = stack.pop() {
+        if graph[p].is_source() {
+            continue;
+        }
+        if graph[p].domain().index() == candidate {
+            return true;
+        }
+        stack.extend(graph.neighbors_directed(p, petgraph::EdgeDirection::Incoming));
+    }
+    false
+}
+
+/// Check that `node` can be put in the domain `domain` as asked by a placement hint.
+fn check_placement(graph: &Graph, node: NodeIndex, domain: usize) -> Result<(), String> {
+    let n = &graph[node];
+    let members: Vec<_> = graph
+        .node_indices()
+        .filter(|&ni| ni != node)
+        .map(|ni| &graph[ni])
+        .filter(|m| !m.is_source() && !m.is_dropped() && m.has_domain())
+        .filter(|m| m.domain().index() == domain)
+        .collect();
+
+    if members.is_empty() {
+        return Err(format!("there is no domain {}", domain));
+    }
+    if n.is_shard_merger() {
+        return Err("shard mergers must be in a domain of their own".to_owned());
+    }
+    if members
+        .iter()
+        .any(|m| m.sharded_by().is_none() != n.sharded_by().is_none())
+    {
+        return Err(format!(
+            "domain {} is sharded differently from the node",
+            domain
+        ));
+    }
+    let sharder = graph
+        .neighbors_directed(node, petgraph::EdgeDirection::Incoming)
+        .map(|p| &graph[p])
+        .any(|p| p.is_sharder() && p.has_domain() && p.domain().index() == domain);
+    if sharder {
+        return Err(format!(
+            "domain {} holds the sharder above the node",
+            domain
+        ));
+    }
+    if reenters(graph, node, domain) {
+        return Err(format!(
+            "a path into the node would leave domain {} and come back into it",
+            domain
+        ));
+    }
+    Ok(())
+}
+
+/// The groups that `DomainStrategy::FewestCuts` puts new nodes in. All the nodes in a group go in
+/// the same domain.
+struct Groups {
+    // union-find forest over the nodes that can be grouped
+    parent: HashMap<NodeIndex, NodeIndex>,
+    // the members of each group, by the root of its tree
+    members: HashMap<NodeIndex, Vec<NodeIndex>>,
+    // the number of nodes in each group that count towards `max_nodes`, by root
+    size: HashMap<NodeIndex, usize>,
+    // the domain that each group has gone in, by root
+    domain: HashMap<NodeIndex, usize>,
+    max_nodes: usize,
+}
+
+impl Groups {
+    /// Group the given new nodes, which are in topological order. Bases, shard mergers, and nodes
+    /// that have been placed are left out, as they are assigned to domains by rules of their own.
+    fn new(
+        graph: &Graph,
+        nodes: &[NodeIndex],
+        placements: &HashMap<NodeIndex, Placement>,
+        max_nodes: usize,
+    ) -> Self {
+        let mut groups = Groups {
+            parent: HashMap::new(),
+            members: HashMap::new(),
+            size: HashMap::new(),
+            domain: HashMap::new(),
+            max_nodes,
+        };
+        for &ni in nodes {
+            let n = &graph[ni];
+            if n.is_base()
+                || n.is_shard_merger()
+                || n.name().starts_with("BOUNDARY_")
+                || placements.contains_key(&ni)
+            {
+                continue;
+            }
+            groups.parent.insert(ni, ni);
+            groups.members.insert(ni, vec![ni]);
+            groups.size.insert(ni, if n.is_reader() { 0 } else { 1 });
+        }
+
+        // greedily join the groups at either end of every edge between them, starting at the top
+        // of the graph, as long as the joined group is small enough and can be in one domain
+        for &child in nodes {
+            if !groups.parent.contains_key(&child) {
+                continue;
+            }
+            let parents: Vec<_> = graph
+                .neighbors_directed(child, petgraph::EdgeDirection::Incoming)
+                .filter(|p| groups.parent.contains_key(p))
+                .collect();
+            for p in parents {
+                if graph[p].is_sharder() {
+                    // the children of a sharder start a new sharding, in a domain of their own
+                    continue;
+                }
+                if graph[p].sharded_by().is_none() != graph[child].sharded_by().is_none() {
+                    continue;
+                }
+                let (a, b) = (groups.find(p), groups.find(child));
+                if a == b || groups.size[&a] + groups.size[&b] > max_nodes {
+                    continue;
+                }
+                if groups.rejoins(graph, a, b) {
+                    continue;
+                }
+                groups.union(a, b);
+            }
+        }
+        groups
+    }
+
+    fn find(&mut self, ni: NodeIndex) -> NodeIndex {
+        let parent = self.parent[&ni];
+        if parent == ni {
+            return ni;
+        }
+        let root = self.find(parent);
+        self.parent.insert(ni, root);
+        root
+    }
+
+    fn union(&mut self, a: NodeIndex, b: NodeIndex) {
+        let (root, other) = if self.members[&a].len() >= self.members[&b].len() {
+            (a, b)
+        } else {
+            (b, a)
+        };
+        self.parent.insert(other, root);
+        let mut members = self.members.remove(&other).unwrap();
+        self.members.get_mut(&root).unwrap().append(&mut members);
+        let size = self.size.remove(&other).unwrap();
+        *self.size.get_mut(&root).unwrap() += size;
+    }
+
+    /// Whether some path that leaves the groups with roots `a` and `b` leads back into them, which
+    /// would make a path that leaves their domain and comes back into it if they were joined.
+    fn rejoins(&self, graph: &Graph, a: NodeIndex, b: NodeIndex) -> bool {
+        let inside: HashSet<_> = self.members[&a]
+            .iter()
+            .chain(&self.members[&b])
+            .cloned()
+            .collect();
+        let mut stack: Vec<_> = inside
+            .iter()
+            .flat_map(|&ni| graph.neighbors_directed(ni, petgraph::EdgeDirection::Outgoing))
+            .filter(|ni| !inside.contains(ni))
+            .collect();
+        let mut seen = HashSet::new();
+        while let Some(ni) = stack.pop() {
+            if inside.contains(&ni) {
+                return true;
+            }
+            if seen.insert(ni) {
+                stack.extend(graph.neighbors_directed(ni, petgraph::EdgeDirection::Outgoing));
+            }
+        }
+        false
+    }
+
+    /// The existing domain that `node` should go in, if any. `sizes` has the number of nodes that
+    /// count towards `max_nodes` in each domain.
+    ///
+    /// The first node of a group to be assigned picks the domain for the whole group: the one that
+    /// the most edges into the group come from, among those that have room for the group.
+    fn domain_for(
+        &mut self,
+        graph: &Graph,
+        node: NodeIndex,
+        sizes: &HashMap<usize, usize>,
+    ) -> Option<usize> {
+        let root = self.find(node);
+        if let Some(&domain) = self.domain.get(&root) {
+            // a node that came into the graph in between may have made a path back into the domain
+            return if reenters(graph, node, domain) {
+                None
+            } else {
+                Some(domain)
+            };
+        }
+
+        let members = &self.members[&root];
+        let mut edges: Vec<(usize, usize)> = Vec::new();
+        let mut ruled_out = HashSet::new();
+        for &m in members {
+            for p in graph.neighbors_directed(m, petgraph::EdgeDirection::Incoming) {
+                let pn = &graph[p];
+                if pn.is_source() || !pn.has_domain() {
+                    continue;
+                }
+                let domain = pn.domain().index();
+                if pn.is_sharder() || pn.sharded_by().is_none() != graph[m].sharded_by().is_none() {
+                    ruled_out.insert(domain);
+                    continue;
+                }
+                match edges.iter_mut().find(|&&mut (d, _)| d == domain) {
+                    Some(e) => e.1 += 1,
+                    None => edges.push((domain, 1)),
+                }
+            }
+        }
+        // the sort is stable, so ties go to the domain that was seen first
+        edges.sort_by(|a, b| b.1.cmp(&a.1));
+
+        // groups of nothing but readers always go with what they read
+        let size = self.size[&root];
+        edges
+            .into_iter()
+            .map(|(domain, _)| domain)
+            .filter(|domain| !ruled_out.contains(domain))
+            .filter(|domain| size == 0 || sizes.get(domain).unwrap_or(&0) + size <= self.max_nodes)
+            .find(|&domain| !members.iter().any(|&m| reenters(graph, m, domain)))
+    }
+
+    /// Record that `node` went in `domain`. The first node of a group to be assigned decides the
+    /// domain of the group.
+    fn assigned(&mut self, node: NodeIndex, domain: usize) {
+        let root = self.find(node);
+        self.domain.entry(root).or_insert(domain);
+    }
+}
+
+/// Assign every new node to a domain, following the given strategy.
+///
+/// Nodes with an entry in `placements` go where it says, and it is an error if one of them can't.
 pub fn assign(
     log: &Logger,
     graph: &mut Graph,
     source: NodeIndex,
     new: &HashSet<NodeIndex>,
+    placements: &HashMap<NodeIndex, Placement>,
+    strategy: DomainStrategy,
     ndomains: &mut usize,
-) {
+) -> Result<(), String> {
     // we need to walk the data flow graph and assign domains to all new nodes.
     // we generally want as few domains as possible, but in *some* cases we must make new ones.
     // specifically:
@@ -34,12 +289,66 @@ pub fn assign(
         topo_list.push(node);
     }
 
+    let mut groups = match strategy {
+        DomainStrategy::FirstParent => None,
+        DomainStrategy::FewestCuts { max_nodes } => {
+            Some(Groups::new(&*graph, &topo_list, placements, max_nodes))
+        }
+    };
+    // the number of nodes in each domain, not counting readers
+    let mut sizes: HashMap<usize, usize> = HashMap::new();
+    for ni in graph.node_indices() {
+        let n = &graph[ni];
+        if ni != source && !n.is_dropped() && !n.is_reader() && n.has_domain() {
+            *sizes.entry(n.domain().index()).or_insert(0) += 1;
+        }
+    }
+
     let mut next_domain = || {
         *ndomains += 1;
         *ndomains - 1
     };
 
     for node in topo_list {
+        if let Some(&placement) = placements.get(&node) {
+            let assignment = match placement {
+                Placement::Dedicated => next_domain(),
+                Placement::With(other) => {
+                    if !graph[other].has_domain() {
+                        return Err(format!(
+                            "node {} ({}) can't be placed with node {}, which has no domain yet",
+                            node.index(),
+                            graph[node].name(),
+                            other.index()
+                        ));
+                    }
+                    graph[other].domain().index()
+                }
+                Placement::Domain(d) => d,
+            };
+            if placement != Placement::Dedicated {
+                check_placement(&*graph, node, assignment).map_err(|e| {
+                    format!(
+                        "node {} ({}) can't be placed in domain {}: {}",
+                        node.index(),
+                        graph[node].name(),
+                        assignment,
+                        e
+                    )
+                })?;
+            }
+
+            debug!(log, "node placed in domain";
+               "node" => node.index(),
+               "type" => ?graph[node],
+               "domain" => assignment);
+            graph[node].add_to(assignment.into());
+            if !graph[node].is_reader() {
+                *sizes.entry(assignment).or_insert(0) += 1;
+            }
+            continue;
+        }
+
         let assignment = (|| {
             let graph = &*graph;
             let n = &graph[node];
@@ -113,22 +422,13 @@ pub fn assign(
                 return next_domain();
             }
 
-            let any_parents = move |prime: &Fn(&Node) -> bool, check: &Fn(&Node) -> bool| {
-                let mut stack: Vec<_> = graph
-                    .neighbors_directed(node, petgraph::EdgeDirection::Incoming)
-                    .filter(move |&p| prime(&graph[p]))
-                    .collect();
-                while let Some(p) = stack.pop() {
-                    if graph[p].is_source() {
-                        continue;
-                    }
-                    if check(&graph[p]) {
-                        return true;
-                    }
-                    stack.extend(graph.neighbors_directed(p, petgraph::EdgeDirection::Incoming));
-                }
-                return false;
-            };
+            if let Some(ref mut groups) = groups {
+                let assignment = groups
+                    .domain_for(graph, node, &sizes)
+                    .unwrap_or_else(|| next_domain());
+                groups.assigned(node, assignment);
+                return assignment;
+            }
 
             let parents: Vec<_> = graph
                 .neighbors_directed(node, petgraph::EdgeDirection::Incoming)
@@ -155,10 +455,7 @@ pub fn assign(
 
                 if let Some(candidate) = assignment {
                     // let's make sure we don't construct a-b-a path
-                    if any_parents(
-                        &|p| p.has_domain() && p.domain().index() != candidate,
-                        &|pp| pp.domain().index() == candidate,
-                    ) {
+                    if reenters(graph, node, candidate) {
                         assignment = None;
                         continue;
                     }
@@ -181,10 +478,7 @@ pub fn assign(
                             continue;
                         }
                         let candidate = s.domain().index();
-                        if any_parents(
-                            &|p| p.has_domain() && p.domain().index() != candidate,
-                            &|pp| pp.domain().index() == candidate,
-                        ) {
+                        if reenters(graph, node, candidate) {
                             continue;
                         }
                         assignment = Some(candidate);
@@ -204,5 +498,9 @@ pub fn assign(
            "type" => ?graph[node],
            "domain" => ?assignment);
         graph[node].add_to(assignment.into());
+        if !graph[node].is_reader() {
+            *sizes.entry(assignment).or_insert(0) += 1;
+        }
     }
+    Ok(())
 }
@@ -7,6 +7,7 @@
 //!  - State must be replayed for materializations in other domains that need it
 
 use crate::controller;
+use crate::controller::migrate::events::{MigrationEventKind, Reporter};
 use dataflow::prelude::*;
 
 use std::collections::{HashMap, HashSet};
@@ -16,11 +17,15 @@ use petgraph::graph::NodeIndex;
 
 use slog::Logger;
 
+/// Send the new nodes of each existing domain to that domain. The nodes that have been sent are
+/// noted in `informed`, so that they can be removed again if the migration fails.
 pub fn inform(
     log: &Logger,
     controller: &mut controller::ControllerInner,
     nodes: HashMap<DomainIndex, Vec<(NodeIndex, bool)>>,
-) {
+    informed: &mut HashMap<DomainIndex, Vec<LocalNodeIndex>>,
+    reporter: &mut Reporter,
+) -> Result<(), String> {
     let source = controller.source;
     for (domain, nodes) in nodes {
         let log = log.new(o!("domain" => domain.index()));
@@ -39,6 +44,7 @@ pub fn inform(
             continue;
         }
 
+        let mut added = Vec::new();
         for (ni, new) in nodes {
             if !new {
                 continue;
@@ -58,6 +64,7 @@ pub fn inform(
                 .collect();
 
             trace!(log, "request addition of node"; "node" => ni.index());
+            let local = node.local_addr();
             ctx.send_to_healthy(
                 box Packet::AddNode {
                     node: node,
@@ -65,7 +72,29 @@ pub fn inform(
                 },
                 &controller.workers,
             )
-            .unwrap();
+            .map_err(|e| {
+                format!(
+                    "failed to add node {} to domain {}: {:?}",
+                    ni.index(),
+                    domain.index(),
+                    e
+                )
+            })?;
+            ctx.wait_for_ack().map_err(|e| {
+                format!(
+                    "failed to add node {} to domain {}: {:?}",
+                    ni.index(),
+                    domain.index(),
+                    e
+                )
+            })?;
+            informed.entry(domain).or_insert_with(Vec::new).push(local);
+            added.push(ni);
         }
+        reporter.report(MigrationEventKind::DomainInformed {
+            domain,
+            nodes: added,
+        });
     }
+    Ok(())
 }
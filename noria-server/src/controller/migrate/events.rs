@@ -0,0 +1,198 @@
+//! Events that report on the progress of a migration as it is committed.
+//!
+//! A migration only reports events if asked to through `Migration::events`. Events are sent as the
+//! migration goes along, so they can be watched from another thread while the migration is still
+//! being committed, or be collected once it has finished. Phase transitions are logged either
+//! way.
+
+use dataflow::prelude::*;
+
+use slog;
+use std::sync::mpsc;
+use std::time::{Duration, Instant, SystemTime};
+
+/// The phases that committing a migration goes through, in order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MigrationPhase {
+    /// Checking the new nodes, sharding them, assigning them to domains, and adding ingress and
+    /// egress nodes.
+    Planning,
+    /// Booting the domains that the migration adds.
+    Booting,
+    /// Sending new nodes to existing domains, and changing the columns of existing bases.
+    Informing,
+    /// Connecting new nodes to their parents in other domains.
+    Connecting,
+    /// Replaying state into new materializations, and readying the new nodes.
+    Replaying,
+    /// Checking that the replays filled new state consistently with the state they replayed from,
+    /// if asked to.
+    Verifying,
+    /// Exposing readers that were held back until all of them were ready.
+    Exposing,
+    /// Having the new readers of replaced queries serve reads meant for the old ones.
+    Swapping,
+    /// Replacing the operators of existing nodes in place, and correcting what their children
+    /// hold.
+    Modifying,
+    /// Giving existing unions their new parents, and sending them the rows those parents have.
+    Merging,
+}
+
+/// Something that happened while a migration was being committed.
+#[derive(Clone, Debug)]
+pub struct MigrationEvent {
+    /// When it happened.
+    pub at: SystemTime,
+    /// How long after the migration started it happened.
+    pub elapsed: Duration,
+    /// What happened.
+    pub kind: MigrationEventKind,
+}
+
+/// The kinds of `MigrationEvent`.
+#[derive(Clone, Debug)]
+pub enum MigrationEventKind {
+    /// The migration is being committed. Lists the nodes it added, by index and name.
+    Started { nodes: Vec<(NodeIndex, String)> },
+    /// The migration entered the given phase.
+    PhaseStarted(MigrationPhase),
+    /// The migration left the given phase, which took as long as given.
+    PhaseFinished {
+        phase: MigrationPhase,
+        took: Duration,
+    },
+    /// A new domain was booted with the given nodes, and all of its shards have reported in.
+    DomainBooted {
+        domain: DomainIndex,
+        shards: usize,
+        nodes: Vec<NodeIndex>,
+    },
+    /// The given new nodes were sent to an existing domain.
+    DomainInformed {
+        domain: DomainIndex,
+        nodes: Vec<NodeIndex>,
+    },
+    /// State is about to be replayed into the given node. This is replay number `replay` (counting
+    /// from 1) of the `of` that the migration needs, and it shares the domains that it starts at
+    /// with live updates as `priority` says.
+    ReplayStarted {
+        node: NodeIndex,
+        name: String,
+        domain: DomainIndex,
+        replay: usize,
+        of: usize,
+        priority: ReplayPriority,
+    },
+    /// Replay into the given node finished, after the given number of records had arrived at its
+    /// domain.
+    ReplayFinished {
+        node: NodeIndex,
+        name: String,
+        domain: DomainIndex,
+        replay: usize,
+        of: usize,
+        records: usize,
+        took: Duration,
+    },
+    /// The domain of the given new node acknowledged that the node is ready for updates.
+    NodeReady {
+        node: NodeIndex,
+        name: String,
+        domain: DomainIndex,
+    },
+    /// The given readers, which had been held back, were exposed to reads.
+    ReadersExposed { nodes: Vec<NodeIndex> },
+    /// The migration was committed, taking as long as given in total, and in each phase.
+    Finished {
+        took: Duration,
+        phases: Vec<(MigrationPhase, Duration)>,
+    },
+    /// The migration failed with the given error, after taking as long as given in total, and in
+    /// each phase it got to. This is sent before the error is returned.
+    Failed {
+        error: String,
+        took: Duration,
+        phases: Vec<(MigrationPhase, Duration)>,
+    },
+}
+
+/// Keeps track of how long each phase of a migration takes, logs when each starts and finishes, and
+/// sends events to whoever asked for them, if anyone did.
+pub(crate) struct Reporter {
+    tx: Option<mpsc::Sender<MigrationEvent>>,
+    log: slog::Logger,
+    start: Instant,
+    phase: Option<(MigrationPhase, Instant)>,
+    phases: Vec<(MigrationPhase, Duration)>,
+}
+
+impl Reporter {
+    pub(crate) fn new(
+        tx: Option<mpsc::Sender<MigrationEvent>>,
+        start: Instant,
+        log: slog::Logger,
+    ) -> Self {
+        Reporter {
+            tx,
+            log,
+            start,
+            phase: None,
+            phases: Vec::new(),
+        }
+    }
+
+    /// Send an event, unless no one is listening.
+    pub(crate) fn report(&mut self, kind: MigrationEventKind) {
+        if let Some(ref tx) = self.tx {
+            let event = MigrationEvent {
+                at: SystemTime::now(),
+                elapsed: self.start.elapsed(),
+                kind,
+            };
+            if tx.send(event).is_err() {
+                // the receiver has gone away, so don't bother with any more events
+                self.tx = None;
+            }
+        }
+    }
+
+    /// Whether events are being sent anywhere. Useful to skip work that is only needed to build an
+    /// event.
+    pub(crate) fn is_reporting(&self) -> bool {
+        self.tx.is_some()
+    }
+
+    /// Move on to the given phase, finishing the current one.
+    pub(crate) fn phase(&mut self, phase: MigrationPhase) {
+        self.end_phase();
+        debug!(self.log, "migration phase started"; "phase" => ?phase);
+        self.phase = Some((phase, Instant::now()));
+        self.report(MigrationEventKind::PhaseStarted(phase));
+    }
+
+    fn end_phase(&mut self) {
+        if let Some((phase, start)) = self.phase.take() {
+            let took = start.elapsed();
+            debug!(self.log, "migration phase finished";
+                   "phase" => ?phase, "ms" => took.as_millis());
+            self.phases.push((phase, took));
+            self.report(MigrationEventKind::PhaseFinished { phase, took });
+        }
+    }
+
+    /// Finish the migration, successfully or not.
+    pub(crate) fn finish(mut self, error: Option<&str>) {
+        self.end_phase();
+        let took = self.start.elapsed();
+        let phases = self.phases.split_off(0);
+        self.report(match error {
+            None => MigrationEventKind::Finished { took, phases },
+            Some(error) => MigrationEventKind::Failed {
+                error: error.to_owned(),
+                took,
+                phases,
+            },
+        });
+    }
+}
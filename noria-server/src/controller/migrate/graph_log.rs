@@ -0,0 +1,372 @@
+//! A record of the logical changes that migrations make to the graph, from which an empty
+//! controller can rebuild the graph.
+//!
+//! The log refers to nodes by name rather than by index. Which index a node gets depends on the
+//! ingress, egress, and sharder nodes that were added before it, and so on how earlier migrations
+//! happened to be laid out across domains and workers.
+
+use bincode;
+use dataflow::node::special::Base;
+use dataflow::prelude::*;
+use failure::{self, ResultExt};
+use slog;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::controller::migrate::Migration;
+
+/// The logical operations that built a graph, grouped by the migration that made them.
+///
+/// Graphs that are built from recipes are restored from their recipes when the controller
+/// restarts, and should not also be rebuilt from a log.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct GraphLog {
+    /// The operations of each migration, in the order the migrations were committed.
+    pub migrations: Vec<Vec<GraphOperation>>,
+}
+
+/// A change that a migration made to the graph.
+///
+/// Nodes are referred to by the key they were given when they were added. That is their name,
+/// unless another node that was still in the graph had that key already, in which case `#` and a
+/// number are appended to it.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum GraphOperation {
+    /// A base table was added.
+    AddBase {
+        node: String,
+        name: String,
+        fields: Vec<String>,
+        base: Base,
+    },
+    /// An operator was added. `parents` has the index the operator knows each of its ancestors
+    /// by, and the key of that ancestor.
+    AddOperator {
+        node: String,
+        name: String,
+        fields: Vec<String>,
+        operator: NodeOperator,
+        parents: Vec<(NodeIndex, String)>,
+    },
+    /// The output of `node` was made readable through the view named `view`, or through an
+    /// anonymous view, keyed on `key`.
+    Maintain {
+        view: Option<String>,
+        node: String,
+        key: Vec<usize>,
+        index: IndexType,
+    },
+    /// The view named `view` was switched over to the output of `node`.
+    Replace { view: String, node: String },
+    /// The memory used by the view of `node` was bounded.
+    MemoryLimit { node: String, bytes: usize },
+    /// The view of `node` was kept sorted by `column`.
+    KeepSorted { node: String, column: usize },
+    /// The view of `node` was allowed to be scanned.
+    AllowScans { node: String },
+    /// The view of `node` was told when to make writes visible to reads.
+    PublishPolicy { node: String, policy: PublishPolicy },
+    /// The state of `node` was put in the given backend.
+    StateBackend { node: String, backend: StateBackend },
+    /// The base `node` was sharded `shards` ways by `column`.
+    ShardBase {
+        node: String,
+        column: usize,
+        shards: usize,
+    },
+    /// The planner was told whether to materialize `node`.
+    Materialize {
+        node: String,
+        hint: MaterializationHint,
+    },
+    /// `node` was limited to sending on `records` records at a time.
+    OutputLimit { node: String, records: usize },
+    /// A column was added to the base `node`.
+    AddColumn {
+        node: String,
+        field: String,
+        default: DataType,
+    },
+    /// A column was dropped from the base `node`.
+    DropColumn { node: String, column: usize },
+    /// The operator of `node` was replaced in place, and the node given the new `fields`.
+    /// `parents` is as for `AddOperator`.
+    Modify {
+        node: String,
+        fields: Vec<String>,
+        operator: NodeOperator,
+        parents: Vec<(NodeIndex, String)>,
+    },
+    /// The union `union` was made to also read from `parent`, emitting the columns in `emit`.
+    AddUnionParent {
+        union: String,
+        parent: String,
+        emit: Vec<usize>,
+    },
+    /// `node` was removed, along with its readers and the ancestors that nothing else used.
+    Remove { node: String },
+    /// The base named `from` was renamed.
+    RenameBase { from: String, to: String },
+    /// The view named `from` was renamed.
+    RenameQuery { from: String, to: String },
+}
+
+impl GraphLog {
+    /// Read a log that a controller wrote to the given file.
+    pub fn read_from<P: AsRef<Path>>(path: P) -> Result<Self, failure::Error> {
+        let path = path.as_ref();
+        let f = fs::File::open(path)
+            .with_context(|_| format!("failed to open graph log {}", path.display()))?;
+        Ok(bincode::deserialize_from(io::BufReader::new(f))
+            .with_context(|_| format!("failed to read graph log {}", path.display()))?)
+    }
+
+    /// Write the log to the given file. The file is replaced as a whole, so it always holds a
+    /// complete log, even if writing fails part way through.
+    pub fn write_to<P: AsRef<Path>>(&self, path: P) -> Result<(), failure::Error> {
+        let path = path.as_ref();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let bytes = bincode::serialize(self)?;
+        {
+            let mut f = fs::File::create(&tmp)
+                .with_context(|_| format!("failed to create {}", Path::new(&tmp).display()))?;
+            f.write_all(&bytes)?;
+            f.sync_all()?;
+        }
+        fs::rename(&tmp, path)
+            .with_context(|_| format!("failed to replace graph log {}", path.display()))?;
+        Ok(())
+    }
+}
+
+/// Keeps the log of the graph a controller runs, and writes it out after every migration.
+pub(crate) struct GraphRecorder {
+    log: GraphLog,
+    /// The keys of the nodes that are in the log.
+    keys: HashMap<NodeIndex, String>,
+    sink: Option<PathBuf>,
+}
+
+impl GraphRecorder {
+    /// Start an empty log, which is written to `sink`, if given.
+    pub(crate) fn new(sink: Option<PathBuf>) -> Self {
+        GraphRecorder {
+            log: GraphLog::default(),
+            keys: HashMap::new(),
+            sink,
+        }
+    }
+
+    /// The log so far, and the keys of the nodes in it.
+    pub(crate) fn recorded(&self) -> (GraphLog, HashMap<NodeIndex, String>) {
+        (self.log.clone(), self.keys.clone())
+    }
+
+    /// Carry on from the log that another controller recorded of the same graph, and write it
+    /// out.
+    pub(crate) fn resume(
+        &mut self,
+        log: &slog::Logger,
+        recorded: GraphLog,
+        keys: HashMap<NodeIndex, String>,
+    ) {
+        self.log = recorded;
+        self.keys = keys;
+        if let Some(ref path) = self.sink {
+            if let Err(e) = self.log.write_to(path) {
+                error!(log, "failed to write graph log"; "path" => %path.display(), "error" => %e);
+            }
+        }
+    }
+
+    /// The key the log knows the given node by, if any.
+    pub(crate) fn key(&self, ni: NodeIndex) -> Option<&String> {
+        self.keys.get(&ni)
+    }
+
+    /// `wanted`, if no node that is still in the graph has that key, and neither do the nodes in
+    /// `pending`. Otherwise, `wanted` with the first number that makes it free appended.
+    pub(crate) fn free_key(
+        &self,
+        graph: &Graph,
+        wanted: &str,
+        pending: &HashMap<NodeIndex, String>,
+    ) -> String {
+        let taken = |key: &str| {
+            self.keys
+                .iter()
+                .any(|(&ni, k)| k == key && !graph[ni].is_dropped())
+                || pending.values().any(|k| k == key)
+        };
+        if !taken(wanted) {
+            return wanted.to_owned();
+        }
+        (1..)
+            .map(|i| format!("{}#{}", wanted, i))
+            .find(|key| !taken(key))
+            .unwrap()
+    }
+
+    /// Add the operations of a migration that was committed to the log, along with the keys of
+    /// the nodes it added, and write the log out.
+    pub(crate) fn record(
+        &mut self,
+        log: &slog::Logger,
+        graph: &Graph,
+        ops: Vec<GraphOperation>,
+        keys: HashMap<NodeIndex, String>,
+    ) {
+        self.keys.extend(keys);
+        self.keys.retain(|&ni, _| !graph[ni].is_dropped());
+        self.append(log, ops);
+    }
+
+    /// Add what a migration that failed did to the graph anyway to the log: the columns it added
+    /// to or dropped from existing bases, and, if `removed` is set, the nodes it removed.
+    pub(crate) fn record_failed(
+        &mut self,
+        log: &slog::Logger,
+        ops: Vec<GraphOperation>,
+        removed: bool,
+    ) {
+        let ops = ops
+            .into_iter()
+            .filter(|op| match *op {
+                GraphOperation::AddColumn { .. } | GraphOperation::DropColumn { .. } => true,
+                GraphOperation::Remove { .. } => removed,
+                _ => false,
+            })
+            .collect();
+        self.append(log, ops);
+    }
+
+    fn append(&mut self, log: &slog::Logger, ops: Vec<GraphOperation>) {
+        if ops.is_empty() {
+            return;
+        }
+        self.log.migrations.push(ops);
+
+        if let Some(ref path) = self.sink {
+            if let Err(e) = self.log.write_to(path) {
+                error!(log, "failed to write graph log"; "path" => %path.display(), "error" => %e);
+            }
+        }
+    }
+}
+
+fn find(nodes: &HashMap<String, NodeIndex>, key: &str) -> Result<NodeIndex, String> {
+    nodes
+        .get(key)
+        .cloned()
+        .ok_or_else(|| format!("graph log refers to unknown node {}", key))
+}
+
+/// Make the changes that one migration in a log made.
+///
+/// `nodes` maps the keys of the nodes that earlier migrations in the log added to the nodes that
+/// stand in for them in this graph, and the nodes that this migration adds are added to it.
+pub(crate) fn replay(
+    mig: &mut Migration,
+    ops: Vec<GraphOperation>,
+    nodes: &mut HashMap<String, NodeIndex>,
+) -> Result<(), String> {
+    // the log only has the nodes that were actually added, so none of them may be reused
+    mig.disable_reuse();
+
+    for op in ops {
+        match op {
+            GraphOperation::AddBase {
+                node,
+                name,
+                fields,
+                base,
+            } => {
+                let ni = mig.add_base_node(Some(node.clone()), name, fields, base);
+                nodes.insert(node, ni);
+            }
+            GraphOperation::AddOperator {
+                node,
+                name,
+                fields,
+                mut operator,
+                parents,
+            } => {
+                let mut remap = HashMap::new();
+                for (ancestor, key) in parents {
+                    remap.insert(ancestor, IndexPair::from(find(nodes, &key)?));
+                }
+                operator.reparent(&remap);
+                let ni = mig.add_operator(Some(node.clone()), name, fields, operator);
+                nodes.insert(node, ni);
+            }
+            GraphOperation::Modify {
+                node,
+                fields,
+                mut operator,
+                parents,
+            } => {
+                let mut remap = HashMap::new();
+                for (ancestor, key) in parents {
+                    remap.insert(ancestor, IndexPair::from(find(nodes, &key)?));
+                }
+                operator.reparent(&remap);
+                mig.modify(find(nodes, &node)?, fields, operator);
+            }
+            GraphOperation::AddUnionParent {
+                union,
+                parent,
+                emit,
+            } => mig.add_union_parent(find(nodes, &union)?, find(nodes, &parent)?, emit),
+            GraphOperation::Maintain {
+                view,
+                node,
+                key,
+                index,
+            } => {
+                mig.maintain_as(view, find(nodes, &node)?, &key[..], index);
+            }
+            GraphOperation::Replace { view, node } => mig.replace(&view, find(nodes, &node)?),
+            GraphOperation::MemoryLimit { node, bytes } => {
+                mig.set_memory_limit(find(nodes, &node)?, bytes)
+            }
+            GraphOperation::KeepSorted { node, column } => {
+                mig.keep_sorted(find(nodes, &node)?, column)
+            }
+            GraphOperation::AllowScans { node } => mig.allow_scans(find(nodes, &node)?),
+            GraphOperation::PublishPolicy { node, policy } => {
+                mig.set_publish_policy(find(nodes, &node)?, policy)
+            }
+            GraphOperation::StateBackend { node, backend } => {
+                mig.set_state_backend(find(nodes, &node)?, backend)
+            }
+            GraphOperation::ShardBase {
+                node,
+                column,
+                shards,
+            } => mig.shard_base(find(nodes, &node)?, column, shards),
+            GraphOperation::Materialize { node, hint } => {
+                mig.materialize(find(nodes, &node)?, hint)
+            }
+            GraphOperation::OutputLimit { node, records } => {
+                mig.set_output_limit(find(nodes, &node)?, records)
+            }
+            GraphOperation::AddColumn {
+                node,
+                field,
+                default,
+            } => {
+                mig.add_column(find(nodes, &node)?, field, default);
+            }
+            GraphOperation::DropColumn { node, column } => {
+                mig.drop_column(find(nodes, &node)?, column)
+            }
+            GraphOperation::Remove { node } => mig.remove(find(nodes, &node)?),
+            GraphOperation::RenameBase { from, to } => mig.rename_base(&from, &to),
+            GraphOperation::RenameQuery { from, to } => mig.rename_query(&from, &to),
+        }
+    }
+    Ok(())
+}
@@ -6,8 +6,10 @@
 //! module).
 
 use crate::controller::domain_handle::DomainHandle;
+use crate::controller::migrate::events::{MigrationEventKind, Reporter};
 use crate::controller::{inner::graphviz, keys, WorkerIdentifier, WorkerStatus};
 use dataflow::prelude::*;
+use noria::debug::plan::{PlannedMaterialization, PlannedReplay};
 use petgraph;
 use petgraph::graph::NodeIndex;
 use slog::Logger;
@@ -19,6 +21,95 @@ mod plan;
 
 type Indices = HashSet<Vec<usize>>;
 
+// map all the indices to the corresponding columns in the parent
+fn map_indices(
+    n: &Node,
+    parent: NodeIndex,
+    indices: &HashSet<Vec<usize>>,
+) -> Result<HashSet<Vec<usize>>, String> {
+    indices
+        .iter()
+        .map(|index| {
+            index
+                .iter()
+                .map(|&col| {
+                    if !n.is_internal() {
+                        if n.is_base() {
+                            unreachable!();
+                        }
+                        return Ok(col);
+                    }
+
+                    let really = n.parent_columns(col);
+                    let really = really
+                        .into_iter()
+                        .find(|&(anc, _)| anc == parent)
+                        .and_then(|(_, col)| col);
+
+                    really.ok_or_else(|| {
+                        format!(
+                            "could not resolve obligation past operator;\
+                             node => {}, ancestor => {}, column => {}",
+                            n.global_addr().index(),
+                            parent.index(),
+                            col
+                        )
+                    })
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Whether readying the given new node means replaying state into it, given the indices it is to
+/// get.
+fn needs_replay(graph: &Graph, ni: NodeIndex, index_on: &Indices) -> bool {
+    let n = &graph[ni];
+    if n.is_base() {
+        // a new base must be empty
+        return false;
+    }
+    !index_on.is_empty() || n.with_reader(|r| r.is_materialized()).unwrap_or(false)
+}
+
+/// The columns that a node whose materialization is forced should be indexed on: the key of a
+/// reader below it, or the columns its children look it up by. If nothing reads the node, it is
+/// indexed on its first column, like a base node would be.
+fn forced_index(graph: &Graph, ni: NodeIndex) -> Vec<usize> {
+    for child in graph.neighbors_directed(ni, petgraph::EdgeDirection::Outgoing) {
+        let c = &graph[child];
+        if let Ok(Some(key)) = c.with_reader(|r| r.key().map(Vec::from)) {
+            return key;
+        }
+        if c.is_internal() {
+            if let Some((cols, _)) = c.suggest_indexes(child).remove(&ni) {
+                return cols;
+            }
+        }
+    }
+    vec![0]
+}
+
+/// A full replay that filled the state of a new node.
+#[derive(Clone, Debug)]
+pub(crate) struct Replayed {
+    /// The node whose state was filled.
+    pub target: NodeIndex,
+    /// The paths the replay went along, each starting at the node whose state was replayed.
+    pub paths: Vec<Vec<NodeIndex>>,
+}
+
+/// What `Materializations` knows about the graph in between migrations, for a controller that
+/// takes over the graph to carry on from.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct MaterializationsState {
+    have: HashMap<NodeIndex, Indices>,
+    partial: HashSet<NodeIndex>,
+    partial_enabled: bool,
+    domains_on_path: HashMap<Tag, Vec<DomainIndex>>,
+    next_tag: usize,
+}
+
 pub struct Materializations {
     log: Logger,
 
@@ -28,9 +119,19 @@ pub struct Materializations {
     partial: HashSet<NodeIndex>,
     partial_enabled: bool,
 
+    // new materializations are restored from this checkpoint where possible
+    checkpoint: Option<u64>,
+    // whether new bases are recovered from their write-ahead logs
+    recover_logs: bool,
+    // how the full replays of the current call to `commit` share their domains with live updates
+    priority: ReplayPriority,
+
     // TODO: this doesn't belong here
     pub domains_on_path: HashMap<Tag, Vec<DomainIndex>>,
 
+    // the full replays that the last call to `commit` ran
+    replayed: Vec<Replayed>,
+
     tag_generator: AtomicUsize,
 }
 
@@ -46,8 +147,14 @@ impl Materializations {
             partial: HashSet::default(),
             partial_enabled: true,
 
+            checkpoint: None,
+            recover_logs: false,
+            priority: ReplayPriority::default(),
+
             domains_on_path: Default::default(),
 
+            replayed: Vec::new(),
+
             tag_generator: AtomicUsize::default(),
         }
     }
@@ -61,6 +168,44 @@ impl Materializations {
     pub fn disable_partial(&mut self) {
         self.partial_enabled = false;
     }
+
+    /// Restore new full materializations from the checkpoint with the given identifier, rather
+    /// than replaying them, if every shard of their domain has a copy of their state. Pass `None`
+    /// to go back to always replaying.
+    pub fn restore_from(&mut self, checkpoint: Option<u64>) {
+        self.checkpoint = checkpoint;
+    }
+
+    /// Recover new bases from their write-ahead logs, on top of any state restored for them from
+    /// a checkpoint.
+    ///
+    /// The checkpointed state of other nodes need not agree with the bases' checkpointed state, let
+    /// alone with what the bases recover from their logs, so while recovering, every other new
+    /// full materialization is replayed from the recovered bases instead.
+    pub fn recover_logs(&mut self, recover: bool) {
+        self.recover_logs = recover;
+    }
+
+    /// What is materialized where, and which replay paths there are.
+    pub(crate) fn export(&self) -> MaterializationsState {
+        assert!(self.added.is_empty());
+        MaterializationsState {
+            have: self.have.clone(),
+            partial: self.partial.clone(),
+            partial_enabled: self.partial_enabled,
+            domains_on_path: self.domains_on_path.clone(),
+            next_tag: self.tag_generator.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Carry on from what another controller exported for the same graph.
+    pub(crate) fn import(&mut self, state: MaterializationsState) {
+        self.have = state.have;
+        self.partial = state.partial;
+        self.partial_enabled = state.partial_enabled;
+        self.domains_on_path = state.domains_on_path;
+        self.tag_generator = AtomicUsize::new(state.next_tag);
+    }
 }
 
 impl Materializations {
@@ -68,6 +213,115 @@ impl Materializations {
         Tag(self.tag_generator.fetch_add(1, Ordering::SeqCst) as u32)
     }
 
+    /// Find the node that will hold the state for lookups into `ni` on the given indices, along
+    /// with the indices as they map onto that node. Lookups are hoisted through query-through
+    /// operators until they reach a node that is already materialized, or one that they can't be
+    /// hoisted past.
+    fn lookup_target(
+        &self,
+        graph: &Graph,
+        ni: NodeIndex,
+        mut indices: Indices,
+    ) -> (NodeIndex, Indices) {
+        let mut mi = ni;
+        let mut m = &graph[mi];
+        loop {
+            if self.have.contains_key(&mi) {
+                break;
+            }
+            if !m.is_internal() || !m.can_query_through() {
+                break;
+            }
+            if m.materialization_hint() == MaterializationHint::Forced {
+                // it gets state of its own anyway
+                break;
+            }
+
+            let mut parents = graph.neighbors_directed(mi, petgraph::EdgeDirection::Incoming);
+            let parent = parents.next().unwrap();
+            assert_eq!(
+                parents.count(),
+                0,
+                "query_through had more than one ancestor"
+            );
+
+            // hoist index to parent
+            trace!(self.log, "hoisting indexing obligations";
+                   "for" => mi.index(),
+                   "to" => parent.index());
+            mi = parent;
+            indices = map_indices(m, mi, &indices).unwrap();
+            m = &graph[mi];
+        }
+        (mi, indices)
+    }
+
+    /// Check that the given new nodes won't need any node whose materialization is forbidden to
+    /// be materialized.
+    pub(super) fn check_hints(
+        &self,
+        graph: &Graph,
+        new: &HashSet<NodeIndex>,
+    ) -> Result<(), String> {
+        let forbidden =
+            |ni: NodeIndex| graph[ni].materialization_hint() == MaterializationHint::Forbidden;
+
+        for &ni in new {
+            let n = &graph[ni];
+            if n.is_base() && forbidden(ni) {
+                return Err(format!(
+                    "base node {} ({}) is forbidden from being materialized, but bases always are",
+                    ni.index(),
+                    n.name()
+                ));
+            }
+            if n.is_reader() {
+                continue;
+            }
+
+            if let Some((li, mi, _)) = self.unmaterializable_lookups(graph, ni).into_iter().next() {
+                let through = if mi == li {
+                    String::new()
+                } else {
+                    format!(" (through node {})", li.index())
+                };
+                return Err(format!(
+                    "node {} ({}) is forbidden from being materialized, but node {} ({}) \
+                     needs to look up rows in it{}",
+                    mi.index(),
+                    graph[mi].name(),
+                    ni.index(),
+                    n.name(),
+                    through
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The lookups that the given node does, into its ancestors or its own state, that nothing
+    /// would have state for, because the node that would have to hold that state is forbidden from
+    /// being materialized. Each is given as the node that is looked up in, the node that would
+    /// have to hold the state, and the columns that are looked up on.
+    pub(super) fn unmaterializable_lookups(
+        &self,
+        graph: &Graph,
+        ni: NodeIndex,
+    ) -> Vec<(NodeIndex, NodeIndex, Vec<usize>)> {
+        let mut missing = Vec::new();
+        for (li, (cols, lookup)) in graph[ni].suggest_indexes(ni) {
+            if !lookup {
+                continue;
+            }
+            let (mi, _) = self.lookup_target(graph, li, Some(cols.clone()).into_iter().collect());
+            if graph[mi].materialization_hint() == MaterializationHint::Forbidden {
+                missing.push((li, mi, cols));
+            }
+        }
+        missing.sort();
+        missing
+    }
+
     /// Extend the current set of materializations with any additional materializations needed to
     /// satisfy indexing obligations in the given set of (new) nodes.
     fn extend(&mut self, graph: &Graph, new: &HashSet<NodeIndex>) {
@@ -128,6 +382,11 @@ impl Materializations {
                 n.suggest_indexes(ni)
             };
 
+            if n.materialization_hint() == MaterializationHint::Forced && !indices.contains_key(&ni)
+            {
+                indices.insert(ni, (forced_index(graph, ni), true));
+            }
+
             if indices.is_empty() && n.is_base() {
                 // we must *always* materialize base nodes
                 // so, just make up some column to index on
@@ -154,46 +413,6 @@ impl Materializations {
             }
         }
 
-        // map all the indices to the corresponding columns in the parent
-        fn map_indices(
-            n: &Node,
-            parent: NodeIndex,
-            indices: &HashSet<Vec<usize>>,
-        ) -> Result<HashSet<Vec<usize>>, String> {
-            indices
-                .iter()
-                .map(|index| {
-                    index
-                        .iter()
-                        .map(|&col| {
-                            if !n.is_internal() {
-                                if n.is_base() {
-                                    unreachable!();
-                                }
-                                return Ok(col);
-                            }
-
-                            let really = n.parent_columns(col);
-                            let really = really
-                                .into_iter()
-                                .find(|&(anc, _)| anc == parent)
-                                .and_then(|(_, col)| col);
-
-                            really.ok_or_else(|| {
-                                format!(
-                                    "could not resolve obligation past operator;\
-                                     node => {}, ancestor => {}, column => {}",
-                                    n.global_addr().index(),
-                                    parent.index(),
-                                    col
-                                )
-                            })
-                        })
-                        .collect()
-                })
-                .collect()
-        }
-
         // lookup obligations are fairly rigid, in that they require a materialization, and can
         // only be pushed through query-through nodes, and never across domains. so, we deal with
         // those first.
@@ -203,35 +422,17 @@ impl Materializations {
         // partial node may add indices to only a subset of the intermediate partial views between
         // it and the nearest full materialization (because the intermediate ones haven't been
         // marked as materialized yet).
-        for (ni, mut indices) in lookup_obligations {
+        for (ni, indices) in lookup_obligations {
             // we want to find the closest materialization that allows lookups (i.e., counting
             // query-through operators).
-            let mut mi = ni;
-            let mut m = &graph[mi];
-            loop {
-                if self.have.contains_key(&mi) {
-                    break;
-                }
-                if !m.is_internal() || !m.can_query_through() {
-                    break;
-                }
-
-                let mut parents = graph.neighbors_directed(mi, petgraph::EdgeDirection::Incoming);
-                let parent = parents.next().unwrap();
-                assert_eq!(
-                    parents.count(),
-                    0,
-                    "query_through had more than one ancestor"
-                );
-
-                // hoist index to parent
-                trace!(self.log, "hoisting indexing obligations";
-                       "for" => mi.index(),
-                       "to" => parent.index());
-                mi = parent;
-                indices = map_indices(m, mi, &indices).unwrap();
-                m = &graph[mi];
-            }
+            let (mi, indices) = self.lookup_target(graph, ni, indices);
+            assert_ne!(
+                graph[mi].materialization_hint(),
+                MaterializationHint::Forbidden,
+                "materialization of node {} is forbidden, but node {} needs it",
+                mi.index(),
+                ni.index()
+            );
 
             for columns in indices {
                 info!(self.log,
@@ -301,6 +502,39 @@ impl Materializations {
                 able = false;
             }
 
+            // state kept on disk can't be partial
+            if graph[ni].state_backend() == StateBackend::Disk {
+                warn!(self.log, "full because on disk"; "node" => ni.index());
+                able = false;
+            }
+
+            // range lookups can't tell which keys they would need replayed
+            if graph[ni].with_reader(|r| r.index_type()) == Ok(IndexType::BTreeMap) {
+                warn!(self.log, "full because ordered"; "node" => ni.index());
+                able = false;
+            }
+
+            // evictions aren't applied to the sorted copy of a reader's rows
+            if graph[ni]
+                .with_reader(|r| r.sorted_by().is_some())
+                .unwrap_or(false)
+            {
+                warn!(self.log, "full because sorted"; "node" => ni.index());
+                able = false;
+            }
+
+            // scans only see the keys that are present
+            if graph[ni].with_reader(|r| r.is_scannable()).unwrap_or(false) {
+                warn!(self.log, "full because scannable"; "node" => ni.index());
+                able = false;
+            }
+
+            // a miss in one shard of a scattered reader says nothing about the other shards
+            if graph[ni].is_scattered_reader() {
+                warn!(self.log, "full because scattered"; "node" => ni.index());
+                able = false;
+            }
+
             // we are already fully materialized, so can't be made partial
             if !new.contains(&ni)
                 && self.added.get(&ni).map(|i| i.len()).unwrap_or(0)
@@ -428,18 +662,36 @@ impl Materializations {
         }
     }
 
+    /// The columns of each index on the state of the given node, in order. Empty if the node isn't
+    /// materialized, or is a reader.
+    pub fn get_indices(&self, index: &NodeIndex) -> Vec<Vec<usize>> {
+        let mut indices: Vec<_> = self
+            .have
+            .get(index)
+            .map(|indices| indices.iter().cloned().collect())
+            .unwrap_or_default();
+        indices.sort();
+        indices
+    }
+
     /// Commit to all materialization decisions since the last time `commit` was called.
     ///
     /// This includes setting up replay paths, adding new indices to existing materializations, and
-    /// populating new materializations.
+    /// populating new materializations. New bases are written the rows given for them in `seeds`
+    /// as soon as they are ready, so that the replays into the nodes below them include the rows.
     pub(super) fn commit(
         &mut self,
         graph: &Graph,
         new: &HashSet<NodeIndex>,
+        seeds: &HashMap<NodeIndex, Vec<Vec<DataType>>>,
+        priority: ReplayPriority,
         domains: &mut HashMap<DomainIndex, DomainHandle>,
         workers: &HashMap<WorkerIdentifier, WorkerStatus>,
-    ) {
+        reporter: &mut Reporter,
+    ) -> Result<(), String> {
         self.extend(graph, new);
+        self.replayed.clear();
+        self.priority = priority;
 
         // check that we don't have fully materialized nodes downstream of partially materialized
         // nodes.
@@ -466,7 +718,7 @@ impl Materializations {
                 }
 
                 if let Some(pi) = any_partial(self, graph, ni) {
-                    println!("{}", graphviz(graph, true, &self));
+                    println!("{}", graphviz(graph, true, &self, None));
                     crit!(self.log, "partial materializations above full materialization";
                               "full" => ni.index(),
                               "partial" => pi.index());
@@ -514,7 +766,7 @@ impl Materializations {
                                                 .find(|c| !index.contains(&c))
                                         });
                                     if let Some(not_shared) = unshared {
-                                        println!("{}", graphviz(graph, true, &self));
+                                        println!("{}", graphviz(graph, true, &self, None));
                                         crit!(self.log, "partially overlapping partial indices";
                                                   "parent" => pni.index(),
                                                   "pcols" => ?index,
@@ -620,7 +872,8 @@ impl Materializations {
             let mut index_on = self.added.remove(&node).unwrap();
 
             // are they trying to make a non-materialized node materialized?
-            if self.have[&node] == index_on {
+            let newly_materialized = self.have[&node] == index_on;
+            if newly_materialized {
                 if self.partial.contains(&node) {
                     // we can't make this node partial if any of its children are materialized, as
                     // we might stop forwarding updates to them, which would make them very sad.
@@ -641,7 +894,7 @@ impl Materializations {
                             != self.have.get(&child).map(|i| i.len()).unwrap_or(0)
                         {
                             // node was previously materialized!
-                            println!("{}", graphviz(graph, true, &self));
+                            println!("{}", graphviz(graph, true, &self, None));
                             crit!(
                                 self.log,
                                 "attempting to make old non-materialized node with children partial";
@@ -667,21 +920,40 @@ impl Materializations {
                 info!(self.log, "adding partial index to existing {:?}", n);
                 let log = self.log.new(o!("node" => node.index()));
                 let log = mem::replace(&mut self.log, log);
-                self.setup(node, &mut index_on, graph, domains, workers);
+                let r = self.setup(node, &mut index_on, graph, domains, workers);
                 mem::replace(&mut self.log, log);
+                r?;
                 index_on.clear();
+            } else if !newly_materialized {
+                // the node already holds all of its rows, so its domain can index those instead of
+                // having them replayed. every shard indexes the rows it holds.
+                let domain = domains.get_mut(&n.domain()).unwrap();
+                for columns in index_on {
+                    info!(self.log, "adding index to existing {:?}", n; "cols" => ?columns);
+                    domain
+                        .send_to_healthy(
+                            box Packet::AddIndex {
+                                node: n.local_addr(),
+                                columns,
+                            },
+                            workers,
+                        )
+                        .map_err(|e| format!("failed to index node {}: {:?}", node.index(), e))?;
+                    domain
+                        .wait_for_ack()
+                        .map_err(|e| format!("failed to index node {}: {:?}", node.index(), e))?;
+                }
             } else if !n.sharded_by().is_none() {
                 // what do we even do here?!
-                println!("{}", graphviz(graph, true, &self));
+                println!("{}", graphviz(graph, true, &self, None));
                 crit!(self.log, "asked to add index to sharded node";
                            "node" => node.index(),
                            "cols" => ?index_on);
             // unimplemented!();
             } else {
                 use dataflow::payload::InitialState;
-                domains
-                    .get_mut(&n.domain())
-                    .unwrap()
+                let domain = domains.get_mut(&n.domain()).unwrap();
+                domain
                     .send_to_healthy(
                         box Packet::PrepareState {
                             node: n.local_addr(),
@@ -689,10 +961,31 @@ impl Materializations {
                         },
                         workers,
                     )
-                    .unwrap();
+                    .map_err(|e| format!("failed to index node {}: {:?}", node.index(), e))?;
+                domain
+                    .wait_for_ack()
+                    .map_err(|e| format!("failed to index node {}: {:?}", node.index(), e))?;
             }
         }
 
+        let restored = match self.checkpoint {
+            Some(id) => self.load_checkpoint(id, &make[..], graph, domains, workers),
+            None => HashSet::new(),
+        };
+
+        // count the full replays up front, so that progress can be reported as "replay i of n".
+        // partial replays only happen later, when reads miss.
+        let replays = make
+            .iter()
+            .filter(|&&ni| !restored.contains(&ni))
+            .filter(|&&ni| !self.partial.contains(&ni))
+            .filter(|&&ni| {
+                let empty = HashSet::new();
+                needs_replay(graph, ni, self.added.get(&ni).unwrap_or(&empty))
+            })
+            .count();
+        let mut replay = 0;
+
         // then, we start prepping new nodes
         for ni in make {
             let n = &graph[ni];
@@ -705,8 +998,62 @@ impl Materializations {
                 })
                 .unwrap_or_else(HashSet::new);
 
+            // operators that keep auxiliary state need it before anything is replayed through them
+            if n.is_internal() && n.uses_aux_state() {
+                let domain = domains.get_mut(&n.domain()).unwrap();
+                domain
+                    .send_to_healthy(
+                        box Packet::PrepareAuxState {
+                            node: n.local_addr(),
+                        },
+                        workers,
+                    )
+                    .map_err(|e| format!("failed to prepare node {}: {:?}", ni.index(), e))?;
+                domain
+                    .wait_for_ack()
+                    .map_err(|e| format!("failed to prepare node {}: {:?}", ni.index(), e))?;
+            }
+
             let start = ::std::time::Instant::now();
-            self.ready_one(ni, &mut index_on, graph, domains, workers);
+            if restored.contains(&ni) {
+                use dataflow::payload::InitialState;
+                info!(self.log, "restoring {:?} from checkpoint", n);
+                let domain = domains.get_mut(&n.domain()).unwrap();
+                domain
+                    .send_to_healthy(
+                        box Packet::PrepareState {
+                            node: n.local_addr(),
+                            state: InitialState::Checkpointed(index_on.drain().collect()),
+                        },
+                        workers,
+                    )
+                    .map_err(|e| format!("failed to restore node {}: {:?}", ni.index(), e))?;
+                domain
+                    .wait_for_ack()
+                    .map_err(|e| format!("failed to restore node {}: {:?}", ni.index(), e))?;
+            } else if !self.partial.contains(&ni) && needs_replay(graph, ni, &index_on) {
+                replay += 1;
+                reporter.report(MigrationEventKind::ReplayStarted {
+                    node: ni,
+                    name: n.name().to_owned(),
+                    domain: n.domain(),
+                    replay,
+                    of: replays,
+                    priority: self.priority,
+                });
+                let records = self.ready_one(ni, &mut index_on, graph, domains, workers)?;
+                reporter.report(MigrationEventKind::ReplayFinished {
+                    node: ni,
+                    name: n.name().to_owned(),
+                    domain: n.domain(),
+                    replay,
+                    of: replays,
+                    records,
+                    took: start.elapsed(),
+                });
+            } else {
+                self.ready_one(ni, &mut index_on, graph, domains, workers)?;
+            }
             let reconstructed = index_on.is_empty();
 
             // communicate to the domain in charge of a particular node that it should start
@@ -724,9 +1071,24 @@ impl Materializations {
                     },
                     workers,
                 )
-                .unwrap();
-            domain.wait_for_ack().unwrap();
+                .map_err(|e| format!("failed to ready node {}: {:?}", ni.index(), e))?;
+            domain
+                .wait_for_ack()
+                .map_err(|e| format!("failed to ready node {}: {:?}", ni.index(), e))?;
             trace!(self.log, "node ready"; "node" => ni.index());
+            reporter.report(MigrationEventKind::NodeReady {
+                node: ni,
+                name: n.name().to_owned(),
+                domain: n.domain(),
+            });
+
+            // the base's children come after it, so none of them are ready yet
+            if self.recover_logs && n.is_base() {
+                self.recover(ni, graph, domains, workers)?;
+            }
+            if let Some(rows) = seeds.get(&ni) {
+                self.seed(ni, &rows[..], graph, domains, workers)?;
+            }
 
             if reconstructed {
                 info!(self.log, "reconstruction completed";
@@ -737,10 +1099,186 @@ impl Materializations {
         }
 
         self.added.clear();
+        Ok(())
+    }
+
+    /// The full replays that the last call to `commit` ran to fill new state.
+    pub(super) fn replayed(&self) -> &[Replayed] {
+        &self.replayed[..]
+    }
+
+    /// Forget the materializations of the given nodes, which were added by a migration that
+    /// failed, along with any new indices on existing nodes that the migration didn't get to
+    /// build.
+    pub(super) fn forget(&mut self, nodes: &HashSet<NodeIndex>) {
+        for (ni, indices) in self.added.drain() {
+            let now_empty = match self.have.get_mut(&ni) {
+                Some(have) => {
+                    have.retain(|index| !indices.contains(index));
+                    have.is_empty()
+                }
+                None => false,
+            };
+            if now_empty {
+                self.have.remove(&ni);
+            }
+        }
+        for ni in nodes {
+            self.have.remove(ni);
+            self.partial.remove(ni);
+        }
+    }
+
+    /// Work out what `commit` would do for the given new nodes, without doing any of it: which
+    /// nodes would get state or new indices, and which replay paths would fill that state. The
+    /// materialization decisions are forgotten again once they have been described.
+    ///
+    /// `rows` gives the number of rows in the state of existing nodes, where it is known, and is
+    /// used to estimate how much each full replay would send.
+    pub(super) fn plan(
+        &mut self,
+        graph: &Graph,
+        new: &HashSet<NodeIndex>,
+        rows: &HashMap<NodeIndex, usize>,
+    ) -> (Vec<PlannedMaterialization>, Vec<PlannedReplay>) {
+        let had = self.have.clone();
+        let was_partial = self.partial.clone();
+        self.extend(graph, new);
+
+        let mut materializations = Vec::new();
+        let mut replays = Vec::new();
+        let mut topo = petgraph::visit::Topo::new(graph);
+        while let Some(ni) = topo.next(graph) {
+            let n = &graph[ni];
+            if n.is_source() || n.is_dropped() {
+                continue;
+            }
+
+            let is_new = new.contains(&ni);
+            let mut index_on: Vec<_> = match self.added.get(&ni) {
+                Some(indices) => indices.iter().cloned().collect(),
+                None if is_new => Vec::new(),
+                None => continue,
+            };
+            if is_new && index_on.is_empty() {
+                // new readers are replayed to on their key, just like in `setup`
+                n.with_reader(|r| {
+                    if r.is_materialized() {
+                        if let Some(key) = r.key() {
+                            index_on.push(Vec::from(key));
+                        }
+                    }
+                })
+                .unwrap_or(());
+            }
+            if index_on.is_empty() {
+                continue;
+            }
+            index_on.sort();
+
+            let partial = self.partial.contains(&ni);
+            materializations.push(PlannedMaterialization {
+                node: ni,
+                name: n.name().to_owned(),
+                new: is_new,
+                partial,
+                indexes: index_on.clone(),
+            });
+
+            // new bases start out empty, and new indices on existing full state are built by the
+            // domain from what it already has, so neither needs a replay.
+            if (is_new && n.is_base()) || (!is_new && !partial) {
+                continue;
+            }
+
+            // a full replay fills every index at once
+            let replayed = if partial {
+                &index_on[..]
+            } else {
+                &index_on[..1]
+            };
+            for index in replayed {
+                for path in plan::paths(self, graph, ni, partial, &index[..]) {
+                    let path: Vec<_> = path.into_iter().map(|(ni, _)| ni).collect();
+                    let mut domains = Vec::new();
+                    for &p in &path {
+                        let domain = graph[p].domain();
+                        if domains.last() != Some(&domain) {
+                            domains.push(domain);
+                        }
+                    }
+                    let estimated_rows = if partial {
+                        Some(0)
+                    } else {
+                        rows.get(&path[0]).cloned()
+                    };
+
+                    replays.push(PlannedReplay {
+                        target: ni,
+                        index: index.clone(),
+                        partial,
+                        path,
+                        domains,
+                        estimated_rows,
+                    });
+                }
+            }
+        }
+
+        self.added.clear();
+        self.have = had;
+        self.partial = was_partial;
+        (materializations, replays)
+    }
+
+    /// Have the domains of the given new nodes load their part of the checkpoint with the given
+    /// identifier, and return the fully materialized nodes whose state every shard has a copy of.
+    /// Any other node is replayed as usual.
+    fn load_checkpoint(
+        &self,
+        id: u64,
+        nodes: &[NodeIndex],
+        graph: &Graph,
+        domains: &mut HashMap<DomainIndex, DomainHandle>,
+        workers: &HashMap<WorkerIdentifier, WorkerStatus>,
+    ) -> HashSet<NodeIndex> {
+        let mut restored = HashSet::new();
+        let in_domains: HashSet<_> = nodes.iter().map(|&ni| graph[ni].domain()).collect();
+        for di in in_domains {
+            let domain = domains.get_mut(&di).unwrap();
+            domain
+                .send_to_healthy(box Packet::LoadCheckpoint { id }, workers)
+                .unwrap();
+
+            let mut shards_with = HashMap::new();
+            for saved in domain.wait_for_checkpoint().unwrap() {
+                for ni in saved.unwrap_or_default() {
+                    *shards_with.entry(ni).or_insert(0) += 1;
+                }
+            }
+
+            let before = restored.len();
+            restored.extend(
+                shards_with
+                    .into_iter()
+                    .filter(|&(ni, shards)| {
+                        shards == domain.shards()
+                            && self.have.contains_key(&ni)
+                            && !self.partial.contains(&ni)
+                            && (!self.recover_logs || graph[ni].is_base())
+                    })
+                    .map(|(ni, _)| ni),
+            );
+            info!(self.log, "loaded checkpoint";
+                  "domain" => di.index(),
+                  "restorable" => restored.len() - before);
+        }
+        restored
     }
 
     /// Perform all operations necessary to bring any materializations for the given node up, and
-    /// then mark that node as ready to receive updates.
+    /// then mark that node as ready to receive updates. Returns the number of records that were
+    /// replayed into the node.
     fn ready_one(
         &mut self,
         ni: NodeIndex,
@@ -748,9 +1286,9 @@ impl Materializations {
         graph: &Graph,
         domains: &mut HashMap<DomainIndex, DomainHandle>,
         workers: &HashMap<WorkerIdentifier, WorkerStatus>,
-    ) {
+    ) -> Result<usize, String> {
         let n = &graph[ni];
-        let mut has_state = !index_on.is_empty();
+        let has_state = !index_on.is_empty();
 
         if has_state {
             if self.partial.contains(&ni) {
@@ -766,38 +1304,115 @@ impl Materializations {
             // a new base must be empty, so we can materialize it immediately
             info!(self.log, "no need to replay empty new base"; "node" => ni.index());
             assert!(!self.partial.contains(&ni));
-            return;
+            return Ok(0);
         }
 
         // if this node doesn't need to be materialized, then we're done.
-        has_state = !index_on.is_empty();
-        n.with_reader(|r| {
-            if r.is_materialized() {
-                has_state = true;
-            }
-        })
-        .unwrap_or(());
-
-        if !has_state {
+        if !needs_replay(graph, ni, index_on) {
             debug!(self.log, "no need to replay non-materialized view"; "node" => ni.index());
-            return;
+            return Ok(0);
         }
 
         // we have a parent that has data, so we need to replay and reconstruct
         info!(self.log, "beginning reconstruction of {:?}", n);
         let log = self.log.new(o!("node" => ni.index()));
         let log = mem::replace(&mut self.log, log);
-        self.setup(ni, index_on, graph, domains, workers);
+        let r = self.setup(ni, index_on, graph, domains, workers);
         mem::replace(&mut self.log, log);
+        let records = r?;
 
         // NOTE: the state has already been marked ready by the replay completing, but we want to
         // wait for the domain to finish replay, which the ready executed by the outer commit()
         // loop does.
         index_on.clear();
-        return;
+        Ok(records)
+    }
+
+    /// Write the given rows to the new base `ni`, which must be ready while its children are not.
+    /// Each shard of the base is sent the rows that belong to it. Returns the number of rows
+    /// written.
+    fn seed(
+        &self,
+        ni: NodeIndex,
+        rows: &[Vec<DataType>],
+        graph: &Graph,
+        domains: &mut HashMap<DomainIndex, DomainHandle>,
+        workers: &HashMap<WorkerIdentifier, WorkerStatus>,
+    ) -> Result<usize, String> {
+        let n = &graph[ni];
+        let domain = domains.get_mut(&n.domain()).unwrap();
+        let shards = domain.shards();
+        let mut split = vec![Vec::new(); shards];
+        for (i, row) in rows.iter().enumerate() {
+            let shard = match n.sharded_by() {
+                // rows that are too short are rejected by the base anyway
+                Sharding::ByColumn(col, _) => row
+                    .get(col)
+                    .map(|key| noria::shard_by(key, shards))
+                    .unwrap_or(0),
+                _ => i % shards,
+            };
+            split[shard].push(row.clone());
+        }
+
+        // every shard replies, even if it has no rows to write
+        for (shard, rows) in split.into_iter().enumerate() {
+            domain
+                .send_to_healthy_shard(
+                    shard,
+                    box Packet::SeedBase {
+                        node: n.local_addr(),
+                        rows,
+                    },
+                    workers,
+                )
+                .map_err(|e| format!("failed to seed base {}: {:?}", ni.index(), e))?;
+        }
+        let mut seeded = 0;
+        let replies = domain
+            .wait_for_seeded()
+            .map_err(|e| format!("failed to seed base {}: {:?}", ni.index(), e))?;
+        for r in replies {
+            seeded += r.map_err(|e| format!("base {} rejected its seed: {}", n.name(), e))?;
+        }
+        info!(self.log, "seeded new base"; "node" => ni.index(), "rows" => seeded);
+        Ok(seeded)
+    }
+
+    /// Have every shard of the given base apply the writes in its write-ahead log that are not yet
+    /// in its state. Returns the number of writes applied.
+    fn recover(
+        &self,
+        ni: NodeIndex,
+        graph: &Graph,
+        domains: &mut HashMap<DomainIndex, DomainHandle>,
+        workers: &HashMap<WorkerIdentifier, WorkerStatus>,
+    ) -> Result<usize, String> {
+        let n = &graph[ni];
+        let domain = domains.get_mut(&n.domain()).unwrap();
+        domain
+            .send_to_healthy(
+                box Packet::RecoverBase {
+                    node: n.local_addr(),
+                },
+                workers,
+            )
+            .map_err(|e| format!("failed to recover base {}: {:?}", ni.index(), e))?;
+        let mut recovered = 0;
+        let replies = domain
+            .wait_for_recovered()
+            .map_err(|e| format!("failed to recover base {}: {:?}", ni.index(), e))?;
+        for r in replies {
+            recovered += r.map_err(|e| format!("failed to recover base {}: {}", n.name(), e))?;
+        }
+        info!(self.log, "recovered base from write-ahead log";
+              "node" => ni.index(), "writes" => recovered);
+        Ok(recovered)
     }
 
     /// Reconstruct the materialized state required by the given (new) node through replay.
+    ///
+    /// Returns the number of records that the replay sent to the node's domain.
     fn setup(
         &mut self,
         ni: NodeIndex,
@@ -805,7 +1420,7 @@ impl Materializations {
         graph: &Graph,
         domains: &mut HashMap<DomainIndex, DomainHandle>,
         workers: &HashMap<WorkerIdentifier, WorkerStatus>,
-    ) {
+    ) -> Result<usize, String> {
         if index_on.is_empty() {
             // we must be reconstructing a Reader.
             // figure out what key that Reader is using
@@ -823,41 +1438,66 @@ impl Materializations {
         let pending = {
             let mut plan = plan::Plan::new(self, graph, ni, domains, workers);
             for index in index_on.drain() {
-                plan.add(index);
+                plan.add(index)?;
             }
-            plan.finalize()
+            plan.finalize()?
         };
 
         if !pending.is_empty() {
             trace!(self.log, "all domains ready for replay");
 
             // prepare for, start, and wait for replays
+            if !self.partial.contains(&ni) {
+                self.replayed.push(Replayed {
+                    target: ni,
+                    paths: pending.iter().map(|p| p.path.clone()).collect(),
+                });
+            }
+
+            let target = graph[ni].domain();
+            let mut started = 0;
             for pending in pending {
                 // tell the first domain to start playing
                 trace!(self.log, "telling root domain to start replay";
                    "domain" => pending.source_domain.index());
 
-                domains
-                    .get_mut(&pending.source_domain)
-                    .unwrap()
+                let domain = domains.get_mut(&pending.source_domain).unwrap();
+                domain
                     .send_to_healthy(
                         box Packet::StartReplay {
                             tag: pending.tag,
                             from: pending.source,
+                            priority: self.priority,
                         },
                         workers,
                     )
-                    .unwrap();
+                    .map_err(|e| {
+                        format!("failed to start replay to node {}: {:?}", ni.index(), e)
+                    })?;
+
+                // the target may finish replaying before it acknowledges, so its acknowledgments
+                // are waited for along with the replay
+                if pending.source_domain == target {
+                    started += 1;
+                } else {
+                    domain.wait_for_ack().map_err(|e| {
+                        format!("failed to start replay to node {}: {:?}", ni.index(), e)
+                    })?;
+                }
             }
 
             // and then wait for the last domain to receive all the records
-            let target = graph[ni].domain();
             trace!(self.log,
                "waiting for done message from target";
                "domain" => target.index(),
             );
 
-            domains.get_mut(&target).unwrap().wait_for_ack().unwrap();
+            return domains
+                .get_mut(&target)
+                .unwrap()
+                .wait_for_replay(started)
+                .map_err(|e| format!("replay to node {} failed: {:?}", ni.index(), e));
         }
+        Ok(0)
     }
 }
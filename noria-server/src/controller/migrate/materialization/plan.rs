@@ -23,6 +23,8 @@ pub(crate) struct PendingReplay {
     pub source: LocalNodeIndex,
     pub source_domain: DomainIndex,
     pub target_domain: DomainIndex,
+    /// The nodes the replay goes through, starting at the node whose state is replayed.
+    pub path: Vec<NodeIndex>,
 }
 
 impl<'a> Plan<'a> {
@@ -50,69 +52,23 @@ impl<'a> Plan<'a> {
     }
 
     fn paths(&mut self, columns: &[usize]) -> Vec<Vec<(NodeIndex, Vec<Option<usize>>)>> {
-        let graph = self.graph;
-        let ni = self.node;
-        let paths = keys::provenance_of(graph, ni, &columns[..], Self::on_join(&self.graph));
-
-        // cut paths so they only reach to the the closest materialized node
-        let mut paths: Vec<_> = paths
-            .into_iter()
-            .map(|path| -> Vec<_> {
-                let mut found = false;
-                let mut path: Vec<_> = path
-                    .into_iter()
-                    .enumerate()
-                    .take_while(|&(i, (node, _))| {
-                        // remember, the paths are "backwards", so the first node is target node
-                        if i == 0 {
-                            return true;
-                        }
-
-                        // keep taking until we get our first materialized node
-                        // (`found` helps us emulate `take_while_inclusive`)
-                        if found {
-                            // we've already found a materialized node
-                            return false;
-                        }
-
-                        if self.m.have.contains_key(&node) {
-                            // we want to take this node, but not any later ones
-                            found = true;
-                        }
-                        true
-                    })
-                    .map(|(_, segment)| segment)
-                    .collect();
-                path.reverse();
-                path
-            })
-            .collect();
-
-        // since we cut off part of each path, we *may* now have multiple paths that are the same
-        // (i.e., if there was a union above the nearest materialization). this would be bad, as it
-        // would cause a domain to request replays *twice* for a key from one view!
-        paths.sort();
-        paths.dedup();
-
-        // all columns better resolve if we're doing partial
-        assert!(!self.partial || paths.iter().all(|p| p[0].1.iter().all(|c| c.is_some())));
-
-        paths
+        paths(self.m, self.graph, self.node, self.partial, columns)
     }
 
     /// Finds the appropriate replay paths for the given index, and inform all domains on those
     /// paths about them. It also notes if any data backfills will need to be run, which is
     /// eventually reported back by `finalize`.
-    pub fn add(&mut self, index_on: Vec<usize>) {
+    pub fn add(&mut self, index_on: Vec<usize>) -> Result<(), String> {
         if !self.partial && !self.paths.is_empty() {
             // non-partial views should not have one replay path per index. that would cause us to
             // replay several times, even though one full replay should always be sufficient.
             // we do need to keep track of the fact that there should be an index here though.
             self.tags.entry(index_on).or_default();
-            return;
+            return Ok(());
         }
 
         // inform domains about replay paths
+        let target = self.node;
         let mut tags = Vec::new();
         for path in self.paths(&index_on[..]) {
             let tag = self.m.next_tag();
@@ -160,7 +116,7 @@ impl<'a> Plan<'a> {
                 //  a domain may appear multiple times in this list if a path crosses into the same
                 //  domain more than once. currently, that will cause a deadlock.
                 if seen.contains(&domain) {
-                    println!("{}", graphviz(&self.graph, true, &self.m));
+                    println!("{}", graphviz(&self.graph, true, &self.m, None));
                     crit!(self.m.log, "detected a-b-a domain replay path");
                     unimplemented!();
                 }
@@ -300,6 +256,7 @@ impl<'a> Plan<'a> {
                                 source: self.graph[segments[0].1[0].0].local_addr(),
                                 source_domain: segments[0].0,
                                 target_domain: domain,
+                                path: self.paths[&tag].clone(),
                             });
                         }
                     }
@@ -324,7 +281,7 @@ impl<'a> Plan<'a> {
                                 },
                                 workers,
                             )
-                            .unwrap();
+                            .map_err(|e| failed(target, "set up replay path", domain, e))?;
                     } else {
                         assert!(n.is_sharder());
                     }
@@ -332,8 +289,10 @@ impl<'a> Plan<'a> {
 
                 trace!(self.m.log, "telling domain about replay path"; "domain" => domain.index());
                 let ctx = self.domains.get_mut(&domain).unwrap();
-                ctx.send_to_healthy(setup, self.workers).unwrap();
-                ctx.wait_for_ack().unwrap();
+                ctx.send_to_healthy(setup, self.workers)
+                    .map_err(|e| failed(target, "set up replay path", domain, e))?;
+                ctx.wait_for_ack()
+                    .map_err(|e| failed(target, "set up replay path", domain, e))?;
             }
 
             if !self.partial {
@@ -345,6 +304,7 @@ impl<'a> Plan<'a> {
         }
 
         self.tags.entry(index_on).or_default().extend(tags);
+        Ok(())
     }
 
     /// Instructs the target node to set up appropriate state for any new indices that have been
@@ -353,7 +313,7 @@ impl<'a> Plan<'a> {
     /// instantaneous.
     ///
     /// Returns a list of backfill replays that need to happen before the migration is complete.
-    pub fn finalize(mut self) -> Vec<PendingReplay> {
+    pub fn finalize(mut self) -> Result<Vec<PendingReplay>, String> {
         use dataflow::payload::InitialState;
 
         // NOTE: we cannot use the impl of DerefMut here, since it (reasonably) disallows getting
@@ -398,17 +358,19 @@ impl<'a> Plan<'a> {
                 }
             });
 
-        self.domains
-            .get_mut(&self.graph[self.node].domain())
-            .unwrap()
-            .send_to_healthy(
-                box Packet::PrepareState {
-                    node: self.graph[self.node].local_addr(),
-                    state: s,
-                },
-                self.workers,
-            )
-            .unwrap();
+        let node = self.node;
+        let domain = self.graph[node].domain();
+        let ctx = self.domains.get_mut(&domain).unwrap();
+        ctx.send_to_healthy(
+            box Packet::PrepareState {
+                node: self.graph[self.node].local_addr(),
+                state: s,
+            },
+            self.workers,
+        )
+        .map_err(|e| failed(node, "prepare state", domain, e))?;
+        ctx.wait_for_ack()
+            .map_err(|e| failed(node, "prepare state", domain, e))?;
 
         if !self.partial {
             // we know that this must be a *new* fully materialized node:
@@ -431,7 +393,7 @@ impl<'a> Plan<'a> {
         } else {
             assert!(self.pending.is_empty());
         }
-        self.pending
+        Ok(self.pending)
     }
 
     pub(crate) fn on_join<'b>(
@@ -511,3 +473,70 @@ impl<'a> Plan<'a> {
         }
     }
 }
+
+fn failed<E: ::std::fmt::Debug>(node: NodeIndex, what: &str, domain: DomainIndex, e: E) -> String {
+    format!(
+        "failed to {} for node {} in domain {}: {:?}",
+        what,
+        node.index(),
+        domain.index(),
+        e
+    )
+}
+
+/// Find the paths that replays to the given index of `node` would take, cut off at the closest
+/// materialized node on each path.
+pub(super) fn paths(
+    m: &super::Materializations,
+    graph: &Graph,
+    node: NodeIndex,
+    partial: bool,
+    columns: &[usize],
+) -> Vec<Vec<(NodeIndex, Vec<Option<usize>>)>> {
+    let paths = keys::provenance_of(graph, node, &columns[..], Plan::on_join(graph));
+
+    // cut paths so they only reach to the the closest materialized node
+    let mut paths: Vec<_> = paths
+        .into_iter()
+        .map(|path| -> Vec<_> {
+            let mut found = false;
+            let mut path: Vec<_> = path
+                .into_iter()
+                .enumerate()
+                .take_while(|&(i, (node, _))| {
+                    // remember, the paths are "backwards", so the first node is target node
+                    if i == 0 {
+                        return true;
+                    }
+
+                    // keep taking until we get our first materialized node
+                    // (`found` helps us emulate `take_while_inclusive`)
+                    if found {
+                        // we've already found a materialized node
+                        return false;
+                    }
+
+                    if m.have.contains_key(&node) {
+                        // we want to take this node, but not any later ones
+                        found = true;
+                    }
+                    true
+                })
+                .map(|(_, segment)| segment)
+                .collect();
+            path.reverse();
+            path
+        })
+        .collect();
+
+    // since we cut off part of each path, we *may* now have multiple paths that are the same
+    // (i.e., if there was a union above the nearest materialization). this would be bad, as it
+    // would cause a domain to request replays *twice* for a key from one view!
+    paths.sort();
+    paths.dedup();
+
+    // all columns better resolve if we're doing partial
+    assert!(!partial || paths.iter().all(|p| p[0].1.iter().all(|c| c.is_some())));
+
+    paths
+}
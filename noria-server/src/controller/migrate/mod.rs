@@ -22,21 +22,30 @@
 
 use dataflow::prelude::*;
 use dataflow::{node, payload};
+use noria::debug::plan::{MigrationPlan, PlannedNode};
 
 use rand::{thread_rng, Rng};
 use std::collections::{HashMap, HashSet};
-use std::time::Instant;
+use std::sync::mpsc;
+use std::time::{Instant, SystemTime};
 
 use crate::controller::{ControllerInner, DomainHandle, WorkerEndpoint, WorkerIdentifier};
 
+use self::events::{MigrationEvent, MigrationEventKind, MigrationPhase, Reporter};
+use self::graph_log::GraphOperation;
+use self::validation::ValidationError;
 use petgraph;
 use slog;
 
 pub mod assignment;
 pub mod augmentation;
+pub mod events;
+pub mod graph_log;
 pub mod materialization;
 pub mod routing;
 pub mod sharding;
+pub mod validation;
+pub mod verification;
 
 #[derive(Clone)]
 pub(super) enum ColumnChange {
@@ -51,8 +60,27 @@ pub(super) enum ColumnChange {
 pub struct Migration<'a> {
     pub(super) mainline: &'a mut ControllerInner,
     pub(super) added: Vec<NodeIndex>,
+    pub(super) removed: Vec<NodeIndex>,
     pub(super) columns: Vec<(NodeIndex, ColumnChange)>,
+    pub(super) modified: Vec<(NodeIndex, Vec<String>, NodeOperator)>,
+    pub(super) union_parents: Vec<(NodeIndex, NodeIndex, Vec<usize>)>,
+    pub(super) seeds: HashMap<NodeIndex, Vec<Vec<DataType>>>,
     pub(super) readers: HashMap<NodeIndex, NodeIndex>,
+    pub(super) placements: HashMap<NodeIndex, Placement>,
+    pub(super) worker: Option<WorkerIdentifier>,
+    pub(super) expose_atomically: bool,
+    pub(super) verify_replays: bool,
+    pub(super) replay_priority: ReplayPriority,
+    pub(super) crash_before_replay: Option<NodeIndex>,
+    pub(super) remove_before_ready: Option<NodeIndex>,
+    pub(super) events: Option<mpsc::Sender<MigrationEvent>>,
+    pub(super) replaced: Vec<(NodeIndex, NodeIndex)>,
+    pub(super) reuse: bool,
+    pub(super) reused: Vec<NodeIndex>,
+    pub(super) released: Vec<NodeIndex>,
+    pub(super) renamed: Vec<(NodeIndex, String)>,
+    pub(super) recorded: Vec<GraphOperation>,
+    pub(super) keys: HashMap<NodeIndex, String>,
 
     pub(super) start: Instant,
     pub(super) log: slog::Logger,
@@ -67,22 +95,53 @@ impl<'a> Migration<'a> {
     /// The returned identifier can later be used to refer to the added ingredient.
     /// Edges in the data flow graph are automatically added based on the ingredient's reported
     /// `ancestors`.
-    pub fn add_ingredient<S1, FS, S2, I>(&mut self, name: S1, fields: FS, mut i: I) -> NodeIndex
+    ///
+    /// If the graph already has a node that computes the same thing from the same parents, that
+    /// node is returned instead of adding a new one, unless `disable_reuse` has been called.
+    pub fn add_ingredient<S1, FS, S2, I>(&mut self, name: S1, fields: FS, i: I) -> NodeIndex
     where
         S1: ToString,
         S2: ToString,
         FS: IntoIterator<Item = S2>,
         I: Ingredient + Into<NodeOperator>,
     {
+        let fields = fields.into_iter().map(|f| f.to_string()).collect();
+        self.add_operator(None, name.to_string(), fields, i.into())
+    }
+
+    /// Add the given operator, giving it `key` in the graph log if that is free.
+    fn add_operator(
+        &mut self,
+        key: Option<String>,
+        name: String,
+        fields: Vec<String>,
+        mut i: NodeOperator,
+    ) -> NodeIndex {
+        // the log needs the operator as it was before it learned about the graph
+        let operator = i.clone();
         i.on_connected(&self.mainline.ingredients);
         let parents = i.ancestors();
         assert!(!parents.is_empty());
 
+        if self.reuse {
+            if let Some(ni) = self.find_equivalent(&i, parents[0], fields.len()) {
+                info!(self.log,
+                      "reusing existing node";
+                      "node" => ni.index(),
+                      "name" => &name
+                );
+                if !self.reused.contains(&ni) {
+                    self.reused.push(ni);
+                }
+                return ni;
+            }
+        }
+
         // add to the graph
         let ni =
             self.mainline
                 .ingredients
-                .add_node(node::Node::new(name.to_string(), fields, i.into()));
+                .add_node(node::Node::new(name.clone(), fields.clone(), i));
         info!(self.log,
               "adding new node";
               "node" => ni.index(),
@@ -95,10 +154,66 @@ impl<'a> Migration<'a> {
         for parent in parents {
             self.mainline.ingredients.add_edge(parent, ni, ());
         }
+
+        let node = self.add_key(ni, key);
+        let parents = operator
+            .ancestors()
+            .into_iter()
+            .map(|p| (p, self.key(p)))
+            .collect();
+        self.recorded.push(GraphOperation::AddOperator {
+            node,
+            name,
+            fields,
+            operator,
+            parents,
+        });
+
         // and tell the caller its id
         ni.into()
     }
 
+    /// Find a node that `i` could be replaced with: one that has `parent` as one of its parents,
+    /// emits the same number of columns, and has the same fingerprint as `i`.
+    ///
+    /// Nodes that feed a reader are never picked, so that each query keeps a leaf of its own.
+    fn find_equivalent<I: Ingredient>(
+        &self,
+        i: &I,
+        parent: NodeIndex,
+        ncols: usize,
+    ) -> Option<NodeIndex> {
+        let fingerprint = i.fingerprint()?;
+        let graph = &self.mainline.ingredients;
+
+        // children in other domains hide behind egress and ingress nodes
+        let mut candidates: Vec<_> = graph
+            .neighbors_directed(parent, petgraph::EdgeDirection::Outgoing)
+            .collect();
+        let mut seen = HashSet::new();
+        while let Some(ni) = candidates.pop() {
+            if !seen.insert(ni) {
+                continue;
+            }
+            let n = &graph[ni];
+            if n.is_egress() || n.is_ingress() || n.is_sharder() {
+                candidates.extend(graph.neighbors_directed(ni, petgraph::EdgeDirection::Outgoing));
+                continue;
+            }
+            if !n.is_internal() || n.fields().len() != ncols || self.removed.contains(&ni) {
+                continue;
+            }
+            let has_reader = self.readers.contains_key(&ni)
+                || graph
+                    .neighbors_directed(ni, petgraph::EdgeDirection::Outgoing)
+                    .any(|c| graph[c].is_reader());
+            if !has_reader && n.fingerprint().as_ref() == Some(&fingerprint) {
+                return Some(ni);
+            }
+        }
+        None
+    }
+
     /// Add the given `Base` to the Soup.
     ///
     /// The returned identifier can later be used to refer to the added ingredient.
@@ -113,11 +228,58 @@ impl<'a> Migration<'a> {
         S2: ToString,
         FS: IntoIterator<Item = S2>,
     {
+        let fields = fields.into_iter().map(|f| f.to_string()).collect();
+        self.add_base_node(None, name.to_string(), fields, b)
+    }
+
+    /// Write the given rows to `base`, which must have been added in this migration, when the
+    /// migration is committed.
+    ///
+    /// The rows are written as soon as the base's domain has it ready, and before any of the nodes
+    /// below it are replayed, so the new views hold the rows by the time they can first be read.
+    /// The rows are checked and persisted like any other write to the base, and are written as one
+    /// write, so no view ever holds only some of them. If the base rejects them, the migration
+    /// fails. The rows are data rather than a change to the graph, so they are not in the graph
+    /// log.
+    pub fn seed<I>(&mut self, base: NodeIndex, rows: I)
+    where
+        I: IntoIterator<Item = Vec<DataType>>,
+    {
+        // only new bases can be seeded
+        assert!(self.added.iter().any(|&ni| ni == base));
+        assert!(self.mainline.ingredients[base].is_base());
+        self.seeds.entry(base).or_insert_with(Vec::new).extend(rows);
+    }
+
+    /// Add the given base, giving it `key` in the graph log if that is free.
+    fn add_base_node(
+        &mut self,
+        key: Option<String>,
+        name: String,
+        fields: Vec<String>,
+        b: node::special::Base,
+    ) -> NodeIndex {
+        if let Some(schema) = b.schema() {
+            assert_eq!(
+                schema.len(),
+                fields.len(),
+                "base schema must declare the type of every column"
+            );
+        }
+        if let Some(col) = b.auto_increment() {
+            assert!(col < fields.len());
+            assert!(
+                b.key().is_some(),
+                "base with auto-increment column must have a primary key"
+            );
+        }
+
         // add to the graph
-        let ni = self
-            .mainline
-            .ingredients
-            .add_node(node::Node::new(name.to_string(), fields, b));
+        let base = b.clone();
+        let ni =
+            self.mainline
+                .ingredients
+                .add_node(node::Node::new(name.clone(), fields.clone(), b));
         info!(self.log,
               "adding new base";
               "node" => ni.index(),
@@ -129,10 +291,40 @@ impl<'a> Migration<'a> {
         self.mainline
             .ingredients
             .add_edge(self.mainline.source, ni, ());
+
+        let node = self.add_key(ni, key);
+        self.recorded.push(GraphOperation::AddBase {
+            node,
+            name,
+            fields,
+            base,
+        });
+
         // and tell the caller its id
         ni.into()
     }
 
+    /// Give the new node `ni` a key in the graph log: `key` if that is free, and something based
+    /// on the node's name otherwise.
+    fn add_key(&mut self, ni: NodeIndex, key: Option<String>) -> String {
+        let key = {
+            let graph = &self.mainline.ingredients;
+            let wanted = key.as_ref().map(|k| &k[..]).unwrap_or(graph[ni].name());
+            self.mainline.graph_log.free_key(graph, wanted, &self.keys)
+        };
+        self.keys.insert(ni, key.clone());
+        key
+    }
+
+    /// The key that the graph log knows the given node by.
+    fn key(&self, ni: NodeIndex) -> String {
+        self.keys
+            .get(&ni)
+            .or_else(|| self.mainline.graph_log.key(ni))
+            .cloned()
+            .unwrap_or_else(|| panic!("node {} is not in the graph log", ni.index()))
+    }
+
     /// Returns the context of this migration
     pub fn context(&self) -> &HashMap<String, DataType> {
         &self.context
@@ -180,6 +372,13 @@ impl<'a> Migration<'a> {
             assert_eq!(col_i1, col_i2);
         }
 
+        let base = self.key(node);
+        self.recorded.push(GraphOperation::AddColumn {
+            node: base,
+            field: field.clone(),
+            default: default.clone(),
+        });
+
         // also eventually propagate to domain clone
         self.columns.push((node, ColumnChange::Add(field, default)));
 
@@ -199,10 +398,188 @@ impl<'a> Migration<'a> {
         // we can't rely on DerefMut, since it disallows mutating Taken nodes
         base.get_base_mut().unwrap().drop_column(column);
 
+        let base = self.key(node);
+        self.recorded
+            .push(GraphOperation::DropColumn { node: base, column });
+
         // also eventually propagate to domain clone
         self.columns.push((node, ColumnChange::Drop(column)));
     }
 
+    /// Replace the operator of the existing node `n` with `operator` when the migration is
+    /// committed, without rebuilding any of the state below it. `fields` are the node's fields
+    /// once it is modified.
+    ///
+    /// A filter can be replaced by another filter of the same parent, and a projection by one of
+    /// the same parent that emits the same columns, optionally followed by new ones. The parent
+    /// must be fully materialized, and a projection that gains columns may only feed readers. The
+    /// node's domain works out from the parent's state which rows the node now emits differently,
+    /// and sends its children records that retract the old rows and add the new ones. Updates that
+    /// reach the node before that are processed by the old operator, and later ones by the new one.
+    pub fn modify<S, FS, I>(&mut self, n: NodeIndex, fields: FS, operator: I)
+    where
+        S: ToString,
+        FS: IntoIterator<Item = S>,
+        I: Ingredient + Into<NodeOperator>,
+    {
+        // not allowed to modify new nodes
+        assert!(!self.added.iter().any(|&ni| ni == n));
+
+        let fields: Vec<String> = fields.into_iter().map(|f| f.to_string()).collect();
+        let mut i: NodeOperator = operator.into();
+        // the log needs the operator as it was before it learned about the graph
+        let operator = i.clone();
+        i.on_connected(&self.mainline.ingredients);
+        self.modified.push((n, fields.clone(), i));
+
+        let node = self.key(n);
+        let parents = operator
+            .ancestors()
+            .into_iter()
+            .map(|p| (p, self.key(p)))
+            .collect();
+        self.recorded.push(GraphOperation::Modify {
+            node,
+            fields,
+            operator,
+            parents,
+        });
+    }
+
+    /// Make the existing union `union` also read from `parent` when the migration is committed,
+    /// emitting the columns selected in `emit` from each of its records, without rebuilding any of
+    /// the state below the union.
+    ///
+    /// `parent` may be new, such as a base for a new kind of record that is added in the same
+    /// migration. The parent's domain sends the union all the rows the parent has, followed by
+    /// the updates it emits from then on, and nothing runs in the domain in between, so each row
+    /// reaches the union exactly once even as writes keep coming in. The parent must thus be a
+    /// base or fully materialized, and neither the union nor anything below it may be partially
+    /// materialized. Neither may be sharded, and the union's domain must not already receive
+    /// updates from the parent for some other node.
+    pub fn add_union_parent(&mut self, union: NodeIndex, parent: NodeIndex, emit: Vec<usize>) {
+        self.union_parents.push((union, parent, emit.clone()));
+
+        let union = self.key(union);
+        let parent = self.key(parent);
+        self.recorded.push(GraphOperation::AddUnionParent {
+            union,
+            parent,
+            emit,
+        });
+    }
+
+    /// Remove the given node, along with its readers, when the migration is committed. Any of its
+    /// ancestors that are then no longer used by anything else are removed too, and their state is
+    /// released.
+    ///
+    /// The node must not be a base node, and nothing but its readers may depend on it once the
+    /// migration is committed (other than nodes that are also being removed).
+    pub fn remove(&mut self, node: NodeIndex) {
+        // not allowed to remove new nodes
+        assert!(!self.added.iter().any(|&ni| ni == node));
+        assert!(node != self.mainline.source);
+        assert!(!self.mainline.ingredients[node].is_base());
+        self.removed.push(node);
+
+        let node = self.key(node);
+        self.recorded.push(GraphOperation::Remove { node });
+    }
+
+    /// Remove the query maintained under the given name (see `maintain`), and everything only it
+    /// depends on, when the migration is committed. Returns the node whose output was maintained,
+    /// or `None` if there is no query by that name.
+    ///
+    /// Queries that were installed through a recipe should instead be removed by installing a
+    /// recipe without them, so that the recipe doesn't refer to nodes that are gone.
+    pub fn remove_query(&mut self, name: &str) -> Option<NodeIndex> {
+        let node = *self.mainline.outputs().get(name)?;
+        self.remove(node);
+        Some(node)
+    }
+
+    /// Serve the query maintained under the given name (see `maintain`) from `n` instead, once the
+    /// migration is committed. `n` must have been added in this migration, and is maintained with
+    /// the same key and kind of index as the query was.
+    ///
+    /// Reads keep going to the old version of the query until the new one has been fully replayed,
+    /// at which point both new and existing `View`s of the query switch over to it. The old reader
+    /// is then removed, along with any ancestors that nothing else uses. Since `View`s are tied to
+    /// the worker that serves them, new domains that the migration adds all go on the worker of
+    /// the old reader, and the migration fails if the new reader would end up elsewhere.
+    ///
+    /// Like with `remove_query`, queries that were installed through a recipe should instead be
+    /// changed through the recipe.
+    pub fn replace(&mut self, name: &str, n: NodeIndex) {
+        assert!(self.added.iter().any(|&ni| ni == n));
+        let old = *self
+            .mainline
+            .outputs()
+            .get(name)
+            .unwrap_or_else(|| panic!("no query named {} to replace", name));
+        let reader = self.mainline.find_view_for(old).unwrap();
+        let (key, index) = self.mainline.ingredients[reader]
+            .with_reader(|r| (r.key().map(Vec::from), r.index_type()))
+            .unwrap();
+        let key = key.unwrap_or_else(|| panic!("query {} has no key", name));
+        self.set_up_reader(n, Some(name.to_owned()), &key[..], index);
+
+        if self.worker.is_none() {
+            let domain = self.mainline.ingredients[reader].domain();
+            self.worker = Some(self.mainline.domains[&domain].assignment(0));
+        }
+        self.replaced.push((reader, n));
+
+        let node = self.key(n);
+        self.recorded.push(GraphOperation::Replace {
+            view: name.to_owned(),
+            node,
+        });
+    }
+
+    /// Rename the base named `from` to `to` when the migration is committed. Existing `Table`s of
+    /// the base keep working, but new ones are only had under the new name.
+    ///
+    /// Like with queries, bases that were created through a recipe should instead be changed
+    /// through the recipe.
+    pub fn rename_base(&mut self, from: &str, to: &str) {
+        let base = *self
+            .mainline
+            .inputs()
+            .get(from)
+            .unwrap_or_else(|| panic!("no base named {}", from));
+        self.renamed.push((base, to.to_owned()));
+        self.recorded.push(GraphOperation::RenameBase {
+            from: from.to_owned(),
+            to: to.to_owned(),
+        });
+    }
+
+    /// Rename the query maintained under the name `from` (see `maintain`) to `to` when the
+    /// migration is committed. Existing `View`s of the query keep working, but new ones are only
+    /// had under the new name.
+    ///
+    /// Like with `remove_query`, queries that were installed through a recipe should instead be
+    /// changed through the recipe.
+    pub fn rename_query(&mut self, from: &str, to: &str) {
+        let graph = &self.mainline.ingredients;
+        let reader = graph
+            .node_indices()
+            .find(|&ni| graph[ni].is_reader() && graph[ni].name() == from)
+            .unwrap_or_else(|| panic!("no query named {}", from));
+        self.renamed.push((reader, to.to_owned()));
+        self.recorded.push(GraphOperation::RenameQuery {
+            from: from.to_owned(),
+            to: to.to_owned(),
+        });
+    }
+
+    /// Let the bases and views added in this migration take the names of `node` and its readers,
+    /// which are going to be removed right after the migration is committed.
+    pub(super) fn release_names(&mut self, node: NodeIndex) {
+        self.released.push(node);
+    }
+
     #[cfg(test)]
     pub fn graph(&self) -> &Graph {
         self.mainline.graph()
@@ -223,76 +600,573 @@ impl<'a> Migration<'a> {
         }
     }
 
+    /// Add a reader like the given existing one, with the same name and reading from the same
+    /// node, and place it as given.
+    pub(super) fn copy_reader(&mut self, reader: NodeIndex, placement: Placement) -> NodeIndex {
+        let (n, r) = self.mainline.ingredients[reader]
+            .with_reader(|r| (r.is_for(), r.clone()))
+            .unwrap();
+        assert!(!self.readers.contains_key(&n));
+
+        let name = self.mainline.ingredients[reader].name().to_owned();
+        let r = self.mainline.ingredients[n].named_mirror(r, name);
+        let r = self.mainline.ingredients.add_node(r);
+        self.mainline.ingredients.add_edge(n, r, ());
+        self.readers.insert(n, r);
+        self.placements.insert(r, placement);
+        r
+    }
+
     /// Set up the given node such that its output can be efficiently queried.
     ///
     /// To query into the maintained state, use `ControllerInner::get_getter`.
     #[cfg(test)]
     pub fn maintain_anonymous(&mut self, n: NodeIndex, key: &[usize]) -> NodeIndex {
-        self.ensure_reader_for(n, None);
+        self.maintain_as(None, n, key, IndexType::default())
+    }
+
+    /// Set up the given node such that its output can be efficiently queried.
+    ///
+    /// To query into the maintained state, use `ControllerInner::get_getter`.
+    pub fn maintain(&mut self, name: String, n: NodeIndex, key: &[usize]) {
+        self.maintain_with_index(name, n, key, IndexType::HashMap)
+    }
+
+    /// Set up the given node such that its output can be queried through the given kind of index.
+    ///
+    /// Views with an ordered index (`IndexType::BTreeMap`) must be keyed on a single column, and
+    /// also support range lookups. They are always fully materialized.
+    pub fn maintain_with_index(
+        &mut self,
+        name: String,
+        n: NodeIndex,
+        key: &[usize],
+        index: IndexType,
+    ) {
+        self.maintain_as(Some(name), n, key, index);
+    }
+
+    /// Set up a reader for `n`, which is named `name` unless it is anonymous, and record it in the
+    /// graph log.
+    fn maintain_as(
+        &mut self,
+        name: Option<String>,
+        n: NodeIndex,
+        key: &[usize],
+        index: IndexType,
+    ) -> NodeIndex {
+        let ri = self.set_up_reader(n, name.clone(), key, index);
+        let node = self.key(n);
+        self.recorded.push(GraphOperation::Maintain {
+            view: name,
+            node,
+            key: Vec::from(key),
+            index,
+        });
+        ri
+    }
+
+    /// Give `n` a reader if it doesn't have one yet, and key the reader as given.
+    fn set_up_reader(
+        &mut self,
+        n: NodeIndex,
+        name: Option<String>,
+        key: &[usize],
+        index: IndexType,
+    ) -> NodeIndex {
+        self.ensure_reader_for(n, name);
+
         let ri = self.readers[&n];
 
         self.mainline.ingredients[ri]
-            .with_reader_mut(|r| r.set_key(key))
+            .with_reader_mut(|r| {
+                r.set_key(key);
+                r.set_index_type(index);
+            })
             .unwrap();
 
         ri
     }
 
-    /// Set up the given node such that its output can be efficiently queried.
+    /// Bound the memory used by the reader for `n`, which must already be maintained. Whenever a
+    /// shard of the reader exceeds `bytes`, it evicts its least recently read keys.
     ///
-    /// To query into the maintained state, use `ControllerInner::get_getter`.
-    pub fn maintain(&mut self, name: String, n: NodeIndex, key: &[usize]) {
-        self.ensure_reader_for(n, Some(name));
+    /// The limit only applies if the reader ends up being partially materialized.
+    pub fn set_memory_limit(&mut self, n: NodeIndex, bytes: usize) {
+        let ri = self.readers[&n];
+        self.mainline.ingredients[ri]
+            .with_reader_mut(|r| r.set_memory_limit(Some(bytes)))
+            .unwrap();
 
+        let node = self.key(n);
+        self.recorded
+            .push(GraphOperation::MemoryLimit { node, bytes });
+    }
+
+    /// Choose when the reader for `n`, which must already be maintained, makes the writes that
+    /// reach it visible to reads. Batching more writes into each swap of the reader's state
+    /// raises write throughput, at the cost of reads seeing writes later.
+    ///
+    /// The policy only takes effect if the reader is added in this migration.
+    pub fn set_publish_policy(&mut self, n: NodeIndex, policy: PublishPolicy) {
         let ri = self.readers[&n];
+        self.mainline.ingredients[ri]
+            .with_reader_mut(|r| r.set_publish_policy(policy))
+            .unwrap();
+
+        let node = self.key(n);
+        self.recorded
+            .push(GraphOperation::PublishPolicy { node, policy });
+    }
 
+    /// Keep the rows of each key of the reader for `n`, which must already be maintained, sorted
+    /// by `column`. Pages of rows ordered by that column (in either direction) can then be read
+    /// without sorting them for every read.
+    ///
+    /// Sorted views are always fully materialized.
+    pub fn keep_sorted(&mut self, n: NodeIndex, column: usize) {
+        let ri = self.readers[&n];
+        self.mainline.ingredients[ri]
+            .with_reader_mut(|r| r.set_sorted_by(Some(column)))
+            .unwrap();
+
+        let node = self.key(n);
+        self.recorded
+            .push(GraphOperation::KeepSorted { node, column });
+    }
+
+    /// Let the entire contents of the reader for `n`, which must already be maintained, be read in
+    /// batches with `View::scan`. Scans are refused by views that haven't opted in to them, since
+    /// reading all of a large view is costly.
+    ///
+    /// Scannable views are always fully materialized.
+    pub fn allow_scans(&mut self, n: NodeIndex) {
+        let ri = self.readers[&n];
         self.mainline.ingredients[ri]
-            .with_reader_mut(|r| r.set_key(key))
+            .with_reader_mut(|r| r.set_scannable(true))
             .unwrap();
+
+        let node = self.key(n);
+        self.recorded.push(GraphOperation::AllowScans { node });
+    }
+
+    /// Keep the state of `n`, which must have been added in this migration, in the given backend.
+    ///
+    /// This only has an effect if `n` ends up being materialized. Nodes kept on disk are always
+    /// fully materialized, and can grow larger than memory at the cost of slower lookups.
+    pub fn set_state_backend(&mut self, n: NodeIndex, backend: StateBackend) {
+        assert!(self.added.iter().any(|&ni| ni == n));
+        self.mainline.ingredients[n].set_state_backend(backend);
+
+        let node = self.key(n);
+        self.recorded
+            .push(GraphOperation::StateBackend { node, backend });
+    }
+
+    /// Tell the planner whether `n`, which must have been added in this migration, should be
+    /// materialized. `Forced` gives `n` state of its own, indexed on the columns it is read by;
+    /// `Forbidden` never gives it state, and the migration fails if some node needs it to have
+    /// state. The hint also holds for any later migrations.
+    pub fn materialize(&mut self, n: NodeIndex, hint: MaterializationHint) {
+        assert!(self.added.iter().any(|&ni| ni == n));
+        assert!(!self.mainline.ingredients[n].is_reader());
+        self.mainline.ingredients[n].set_materialization_hint(hint);
+
+        let node = self.key(n);
+        self.recorded
+            .push(GraphOperation::Materialize { node, hint });
+    }
+
+    /// Limit `n`, which must have been added in this migration, to sending on at most `records`
+    /// records at a time. Whatever `n` produces for a single write beyond that is sent on as
+    /// several updates, one after the other, rather than as one huge update. A join produces the
+    /// output for such a write bit by bit, rather than all at once.
+    pub fn set_output_limit(&mut self, n: NodeIndex, records: usize) {
+        assert!(self.added.iter().any(|&ni| ni == n));
+        self.mainline.ingredients[n].set_output_limit(records);
+
+        let node = self.key(n);
+        self.recorded
+            .push(GraphOperation::OutputLimit { node, records });
+    }
+
+    /// Shard `base`, which must have been added in this migration, `shards` ways by `column`.
+    ///
+    /// Each write to the base goes to the shard that its value in `column` hashes to, and the
+    /// nodes below the base are sharded the same way for as long as they only look up by that
+    /// column. Where a node needs all of the rows, such as a global aggregation or a reader keyed
+    /// on another column, the shards are merged back together above it. This is for graphs that
+    /// aren't sharded as a whole (see `ControllerBuilder::set_sharding`), and every node that is
+    /// sharded must be sharded the same number of ways.
+    pub fn shard_base(&mut self, base: NodeIndex, column: usize, shards: usize) {
+        assert!(self.added.iter().any(|&ni| ni == base));
+        assert!(shards > 1, "a base must be sharded at least two ways");
+        {
+            let n = &self.mainline.ingredients[base];
+            assert!(column < n.fields().len());
+            let b = n.get_base().expect("only bases can be sharded explicitly");
+            // every shard would assign ids independently, so they would not be unique
+            assert!(b.auto_increment().is_none());
+            if let Some(key) = b.key() {
+                // writes that change a row by its key must go to the shard that has that row
+                assert_eq!(key, &[column][..], "keyed bases shard by their key");
+            }
+        }
+        self.mainline.ingredients[base].shard_by(Sharding::ByColumn(column, shards));
+
+        let node = self.key(base);
+        self.recorded.push(GraphOperation::ShardBase {
+            node,
+            column,
+            shards,
+        });
+    }
+
+    /// Tell the planner which domain to put `n`, which must have been added in this migration,
+    /// in, rather than letting it choose. Committing the migration fails if the placement can't
+    /// be honored, for example because the domain is sharded and `n` isn't, or because `n` would
+    /// then share a domain with the sharder above it.
+    pub fn place(&mut self, n: NodeIndex, placement: Placement) {
+        assert!(self.added.iter().any(|&ni| ni == n));
+        self.placements.insert(n, placement);
+    }
+
+    /// Don't let reads see any of the views added in this migration until all of them are ready.
+    ///
+    /// Normally, each new view answers reads as soon as its own state has been replayed, so small
+    /// views may be readable well before large ones. With this set, every new view is held back
+    /// until all replays have finished, and they are then exposed together. If a replay fails,
+    /// committing panics with the node that failed, and none of the new views are exposed.
+    pub fn expose_atomically(&mut self) {
+        self.expose_atomically = true;
+    }
+
+    /// Check that the replays that fill new state did so consistently before letting reads see any
+    /// of the new views.
+    ///
+    /// Once all replays have finished, the number of rows each replay brought into new state, and
+    /// where possible their checksum, is compared to what the state it replayed from holds and
+    /// what the operators in between allow. If the two don't fit, committing fails with a
+    /// description of every discrepancy, and none of the new views are exposed. This makes
+    /// committing slower, as every replayed state has to be measured.
+    pub fn verify_replays(&mut self) {
+        self.verify_replays = true;
+    }
+
+    /// Choose how the domains that the migration's full replays start at share their time between
+    /// the replays and live updates. By default, replays are sent as fast as the domains will take
+    /// them, which makes for the shortest migration, but holds up live writes the most.
+    pub fn replay_priority(&mut self, priority: ReplayPriority) {
+        match priority {
+            ReplayPriority::FavorLive { every: 0 } => {
+                panic!("a replay must get a chunk in after some number of live updates")
+            }
+            ReplayPriority::Capped { records_per_sec: 0 } => {
+                panic!("a capped replay must be let through at some rate")
+            }
+            _ => {}
+        }
+        self.replay_priority = priority;
+    }
+
+    /// Always add new nodes, even where the graph already has an equivalent node that could be
+    /// reused. Useful when the new nodes should not share state or processing with other queries.
+    pub fn disable_reuse(&mut self) {
+        self.reuse = false;
+    }
+
+    /// The existing nodes that `add_ingredient` has returned in place of new ones so far.
+    pub fn reused(&self) -> &[NodeIndex] {
+        &self.reused[..]
+    }
+
+    /// Report what happens as this migration is committed through the returned channel.
+    ///
+    /// Events are sent while `commit` runs, starting with `MigrationEventKind::Started` and ending
+    /// with either `MigrationEventKind::Finished` or `MigrationEventKind::Failed`, the latter of
+    /// which is sent before the error is returned. Dropping the receiver stops the events.
+    pub fn events(&mut self) -> mpsc::Receiver<MigrationEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.events = Some(tx);
+        rx
+    }
+
+    /// Make the domain of `n` exit right before the new nodes are replayed, as if it had crashed.
+    #[cfg(test)]
+    pub(crate) fn crash_before_replay(&mut self, n: NodeIndex) {
+        self.crash_before_replay = Some(n);
+    }
+
+    /// Make the domain of `n` forget about `n` right before it is readied, so that the domain
+    /// refuses to ready it.
+    #[cfg(test)]
+    pub(crate) fn remove_before_ready(&mut self, n: NodeIndex) {
+        self.remove_before_ready = Some(n);
+    }
+
+    /// Check that the nodes added in this migration can be planned without materializing any
+    /// node whose materialization is forbidden. If they can't, the error explains which node
+    /// needs which, and committing the migration will fail the same way.
+    pub fn check_materialization_hints(&self) -> Result<(), String> {
+        let new: HashSet<_> = self
+            .added
+            .iter()
+            .chain(self.readers.values())
+            .cloned()
+            .collect();
+        self.mainline
+            .materializations
+            .check_hints(&self.mainline.ingredients, &new)
+    }
+
+    /// Check that the nodes added in this migration are hooked up in a way that can work: that
+    /// they only use columns their ancestors have, that unions' parents fit together, that the
+    /// state they look up rows in can be materialized, that they don't compare binary values by
+    /// order or expect other types than their ancestors' columns hold, and that records can reach
+    /// them. Committing or planning the migration fails if any
+    /// of the errors are fatal.
+    pub fn validate(&self) -> Vec<ValidationError> {
+        let new = self
+            .added
+            .iter()
+            .chain(self.readers.values())
+            .cloned()
+            .collect();
+        let removed = self.removed.iter().cloned().collect();
+        validation::validate(
+            &self.mainline.ingredients,
+            &self.mainline.materializations,
+            &new,
+            &removed,
+        )
     }
 
     /// Commit the changes introduced by this `Migration` to the master `Soup`.
     ///
     /// This will spin up an execution thread for each new thread domain, and hook those new
-    /// domains into the larger Soup graph. The returned map contains entry points through which
-    /// new updates should be sent to introduce them into the Soup.
-    pub fn commit(self) {
+    /// domains into the larger Soup graph.
+    ///
+    /// If a domain fails while the new nodes are being hooked up, the migration is rolled back:
+    /// existing domains forget the new nodes, new domains are shut down, and the graph is left as
+    /// it was before the migration. Nodes that the migration removed stay removed, and columns
+    /// that it added to or dropped from existing bases stay that way.
+    pub fn commit(self) -> Result<(), String> {
         info!(self.log, "finalizing migration"; "#nodes" => self.added.len());
 
+        let validation = self.validate();
+        let recorded = self.recorded;
         let log = self.log;
         let start = self.start;
         let mut mainline = self.mainline;
+
+        let mut reporter = Reporter::new(self.events, start, log.clone());
+        if reporter.is_reporting() {
+            let nodes = self
+                .added
+                .iter()
+                .map(|&ni| (ni, mainline.ingredients[ni].name().to_owned()))
+                .collect();
+            reporter.report(MigrationEventKind::Started { nodes });
+        }
+        reporter.phase(MigrationPhase::Planning);
+
+        // Bases and views are found by name, so the names of new ones must be free
+        let going = self
+            .removed
+            .iter()
+            .chain(&self.released)
+            .cloned()
+            .chain(self.replaced.iter().map(|&(old, _)| old))
+            .collect();
+        let mut new: HashSet<_> = self.added.iter().cloned().collect();
+        new.extend(self.readers.values().cloned());
+        if let Err(e) = check_names(mainline, &new, &going, &self.renamed) {
+            crit!(log, "{}", e);
+            rollback(&log, mainline, &new, Vec::new(), HashMap::new());
+            mainline.graph_log.record_failed(&log, recorded, false);
+            reporter.finish(Some(&e));
+            return Err(e);
+        }
+
+        // Paused domains hold on to the replays and updates that a migration sends them, and the
+        // migration would wait for them forever
+        if !mainline.paused.is_empty() {
+            let mut paused: Vec<_> = mainline.paused.iter().map(|di| di.index()).collect();
+            paused.sort();
+            let e = format!("cannot migrate while domains {:?} are paused", paused);
+            crit!(log, "{}", e);
+            rollback(&log, mainline, &new, Vec::new(), HashMap::new());
+            mainline.graph_log.record_failed(&log, recorded, false);
+            reporter.finish(Some(&e));
+            return Err(e);
+        }
+
+        // Nodes that are hooked up wrong would only fail once records reach them
+        if let Err(e) = validation::check(&log, &validation) {
+            crit!(log, "{}", e);
+            rollback(&log, mainline, &new, Vec::new(), HashMap::new());
+            mainline.graph_log.record_failed(&log, recorded, false);
+            reporter.finish(Some(&e));
+            return Err(e);
+        }
+
+        // Operators that are modified in place must not need any state to be rebuilt
+        let mut modified = Vec::new();
+        for (ni, fields, operator) in self.modified {
+            let checked = if modified.iter().any(|m: &Modification| m.node == ni) {
+                Err("it is modified more than once".to_owned())
+            } else {
+                check_modification(mainline, ni, fields, operator, &self.removed)
+            };
+            match checked {
+                Ok(m) => modified.push(m),
+                Err(e) => {
+                    let e = format!("cannot modify {}: {}", mainline.ingredients[ni].name(), e);
+                    crit!(log, "{}", e);
+                    rollback(&log, mainline, &new, Vec::new(), HashMap::new());
+                    mainline.graph_log.record_failed(&log, recorded, false);
+                    reporter.finish(Some(&e));
+                    return Err(e);
+                }
+            }
+        }
+
+        // Unions that gain parents must not need any state to be rebuilt either
+        let mut union_parents: Vec<(NodeIndex, NodeIndex, Vec<usize>)> = Vec::new();
+        for (union, parent, emit) in self.union_parents {
+            let checked = if union_parents
+                .iter()
+                .any(|&(u, p, _)| u == union && p == parent)
+            {
+                Err("it is added more than once".to_owned())
+            } else {
+                check_union_parent(mainline, union, parent, &emit, &new, &self.removed)
+            };
+            if let Err(e) = checked {
+                let e = format!(
+                    "cannot add {} as a parent of {}: {}",
+                    mainline.ingredients[parent].name(),
+                    mainline.ingredients[union].name(),
+                    e
+                );
+                crit!(log, "{}", e);
+                rollback(&log, mainline, &new, Vec::new(), HashMap::new());
+                mainline.graph_log.record_failed(&log, recorded, false);
+                reporter.finish(Some(&e));
+                return Err(e);
+            }
+            union_parents.push((union, parent, emit));
+        }
+
+        if let Err(e) = mainline
+            .materializations
+            .check_hints(&mainline.ingredients, &new)
+        {
+            panic!("cannot materialize migration: {}", e);
+        }
+
+        // Remove nodes first, so that the new nodes aren't routed through ingress and egress nodes
+        // that are about to go away
+        if !self.removed.is_empty() {
+            if let Err(e) = mainline.remove_with_unused_ancestors(&self.removed[..]) {
+                panic!("cannot remove nodes: {}", e);
+            }
+        }
+
         let mut new: HashSet<_> = self.added.into_iter().collect();
 
+        // The old readers of replaced queries, and the new readers that take over from them
+        let replaced: Vec<_> = self
+            .replaced
+            .iter()
+            .map(|&(old, n)| (old, self.readers[&n]))
+            .collect();
+
         // Readers are nodes too.
         for (_parent, reader) in self.readers {
             new.insert(reader);
         }
 
         // Shard the graph as desired
-        let mut swapped0 = if let Some(shards) = mainline.sharding {
+        let everywhere = mainline.sharding.is_some();
+        let shards = match sharding::factor(&mainline.ingredients, &new, mainline.sharding) {
+            Ok(shards) => shards,
+            Err(e) => {
+                let e = format!("cannot shard new nodes: {}", e);
+                crit!(log, "{}", e);
+                rollback(&log, mainline, &new, Vec::new(), HashMap::new());
+                mainline.graph_log.record_failed(&log, recorded, true);
+                reporter.finish(Some(&e));
+                return Err(e);
+            }
+        };
+        let mut swapped0 = if let Some(shards) = shards {
             sharding::shard(
                 &log,
                 &mut mainline.ingredients,
                 mainline.source,
                 &mut new,
                 shards,
+                everywhere,
             )
         } else {
             HashMap::default()
         };
 
         // Assign domains
-        assignment::assign(
+        if let Err(e) = assignment::assign(
             &log,
             &mut mainline.ingredients,
             mainline.source,
             &new,
+            &self.placements,
+            mainline.domain_strategy,
             &mut mainline.ndomains,
-        );
+        ) {
+            let e = format!("cannot place new nodes: {}", e);
+            crit!(log, "{}", e);
+            rollback(&log, mainline, &new, Vec::new(), HashMap::new());
+            mainline.graph_log.record_failed(&log, recorded, true);
+            reporter.finish(Some(&e));
+            return Err(e);
+        }
+
+        // Readers can only take over from readers on the same worker
+        for &(old, reader) in &replaced {
+            if let Err(e) = check_replacement(mainline, old, reader, self.worker.as_ref()) {
+                let e = format!(
+                    "cannot replace query {}: {}",
+                    mainline.ingredients[old].name(),
+                    e
+                );
+                crit!(log, "{}", e);
+                rollback(&log, mainline, &new, Vec::new(), HashMap::new());
+                mainline.graph_log.record_failed(&log, recorded, true);
+                reporter.finish(Some(&e));
+                return Err(e);
+            }
+        }
+
+        // Unions that gain parents in other domains are wired up like new nodes are. Parents in
+        // the union's domain are only connected once they can feed it, since a new one would
+        // otherwise send the union updates before the union knows about it.
+        let mut rewired = HashSet::new();
+        for &(union, parent, _) in &union_parents {
+            let graph = &mut mainline.ingredients;
+            if graph[parent].domain() != graph[union].domain() {
+                graph.add_edge(parent, union, ());
+                rewired.insert(union);
+            }
+        }
 
         // Set up ingress and egress nodes
-        let swapped1 = routing::add(&log, &mut mainline.ingredients, mainline.source, &mut new);
+        let swapped1 = routing::add(
+            &log,
+            &mut mainline.ingredients,
+            mainline.source,
+            &mut new,
+            &rewired,
+        );
 
         // Merge the swap lists
         for ((dst, src), instead) in swapped1 {
@@ -336,6 +1210,37 @@ impl<'a> Migration<'a> {
             }
         }
         let swapped = swapped0;
+
+        // Each union that gains a parent reads it through the parent itself, if they share a
+        // domain, or otherwise through an ingress that the parent's domain is yet to feed
+        let union_parents: Vec<_> = union_parents
+            .into_iter()
+            .map(|(union, parent, emit)| {
+                let via = swapped.get(&(union, parent)).cloned().unwrap_or(parent);
+                (union, parent, via, emit)
+            })
+            .collect();
+        let wired: Vec<_> = union_parents
+            .iter()
+            .map(|&(union, _, via, _)| (via, union))
+            .collect();
+        for &(union, parent, via, _) in &union_parents {
+            if let Err(e) = check_union_wiring(&mainline.ingredients, parent, via, &new) {
+                let e = format!(
+                    "cannot add {} as a parent of {}: {}",
+                    mainline.ingredients[parent].name(),
+                    mainline.ingredients[union].name(),
+                    e
+                );
+                crit!(log, "{}", e);
+                unwire(&mut mainline.ingredients, &wired);
+                rollback(&log, mainline, &new, Vec::new(), HashMap::new());
+                mainline.graph_log.record_failed(&log, recorded, true);
+                reporter.finish(Some(&e));
+                return Err(e);
+            }
+        }
+
         let mut sorted_new = new.iter().collect::<Vec<_>>();
         sorted_new.sort();
 
@@ -419,7 +1324,27 @@ impl<'a> Migration<'a> {
             }
         }
 
-        if let Some(shards) = mainline.sharding {
+        // The unions' new operators need the local addresses of the nodes they read through
+        let union_parents: Vec<_> = union_parents
+            .into_iter()
+            .map(|(union, parent, via, emit)| {
+                let u = &mainline.ingredients[union];
+                let mut operator = match **u {
+                    NodeOperator::Union(ref u) => u.clone(),
+                    _ => unreachable!(),
+                };
+                let columns = mainline.ingredients[parent].fields().len();
+                operator.add_parent(mainline.remap[&u.domain()][&via], emit, columns);
+                UnionParent {
+                    union,
+                    parent,
+                    via,
+                    operator: operator.into(),
+                }
+            })
+            .collect();
+
+        if let Some(shards) = shards {
             sharding::validate(&log, &mainline.ingredients, mainline.source, &new, shards)
         };
 
@@ -443,6 +1368,20 @@ impl<'a> Migration<'a> {
         // etc.
         // println!("{}", mainline);
 
+        // Hold back the new readers until all of them are ready, if asked to, or until their state
+        // has been verified. This has to happen before the domains are given their copies of the
+        // new nodes.
+        let mut hidden = Vec::new();
+        if self.expose_atomically || self.verify_replays {
+            for &ni in &new {
+                let n = &mut mainline.ingredients[ni];
+                if let Ok(true) = n.with_reader(|r| r.key().is_some()) {
+                    n.with_reader_mut(|r| r.set_hidden(true)).unwrap();
+                    hidden.push(ni);
+                }
+            }
+        }
+
         let mut uninformed_domain_nodes = mainline
             .ingredients
             .node_indices()
@@ -463,6 +1402,7 @@ impl<'a> Migration<'a> {
             .workers
             .iter()
             .filter(|(_, status)| status.healthy)
+            .filter(|(id, _)| self.worker.as_ref().map(|w| w == *id).unwrap_or(true))
             .map(|(id, status)| (id.clone(), status.sender.clone()))
             .collect();
         // Randomize worker iteration order, so that we avoid putting the domains on machines in
@@ -471,100 +1411,831 @@ impl<'a> Migration<'a> {
         let mut placer: Box<Iterator<Item = (WorkerIdentifier, WorkerEndpoint)>> =
             Box::new(placer_workers.into_iter().cycle());
 
-        // Boot up new domains (they'll ignore all updates for now)
-        debug!(log, "booting new domains");
-        for domain in changed_domains {
-            if mainline.domains.contains_key(&domain) {
-                // this is not a new domain
-                continue;
+        // Everything from here on changes the running domains, so keep track of what has been
+        // changed, so that it can all be undone if some domain fails along the way.
+        let mut booted = Vec::new();
+        let mut informed = HashMap::new();
+        let columns = self.columns;
+        let crash_before_replay = self.crash_before_replay;
+        let remove_before_ready = self.remove_before_ready;
+        let verify_replays = self.verify_replays;
+        let replay_priority = self.replay_priority;
+        let seeds = self.seeds;
+        let applied: Result<(), String> = try {
+            // Boot up new domains (they'll ignore all updates for now)
+            debug!(log, "booting new domains");
+            reporter.phase(MigrationPhase::Booting);
+            for domain in changed_domains {
+                if mainline.domains.contains_key(&domain) {
+                    // this is not a new domain
+                    continue;
+                }
+
+                let nodes = uninformed_domain_nodes.remove(&domain).unwrap();
+                let booting: Vec<_> = nodes.iter().map(|&(ni, _)| ni).collect();
+                let d = DomainHandle::new(
+                    domain,
+                    mainline.ingredients[nodes[0].0].sharded_by().shards(),
+                    &log,
+                    &mut mainline.ingredients,
+                    &mainline.domain_config,
+                    nodes,
+                    &mainline.persistence,
+                    &mainline.listen_addr,
+                    &mainline.channel_coordinator,
+                    &mut placer,
+                    &mut workers,
+                    mainline.epoch,
+                );
+                reporter.report(MigrationEventKind::DomainBooted {
+                    domain,
+                    shards: d.shards(),
+                    nodes: booting,
+                });
+                mainline.domains.insert(domain, d);
+                booted.push(domain);
             }
 
-            let nodes = uninformed_domain_nodes.remove(&domain).unwrap();
-            let d = DomainHandle::new(
-                domain,
-                mainline.ingredients[nodes[0].0].sharded_by().shards(),
+            // Add any new nodes to existing domains (they'll also ignore all updates for now)
+            debug!(log, "mutating existing domains");
+            reporter.phase(MigrationPhase::Informing);
+            augmentation::inform(
+                &log,
+                &mut mainline,
+                uninformed_domain_nodes,
+                &mut informed,
+                &mut reporter,
+            )?;
+
+            // Tell all base nodes and base ingress children about newly added columns
+            for (ni, change) in columns {
+                let mut inform = if let ColumnChange::Add(..) = change {
+                    // we need to inform all of the base's children too,
+                    // so that they know to add columns to existing records when replaying
+                    mainline
+                        .ingredients
+                        .neighbors_directed(ni, petgraph::EdgeDirection::Outgoing)
+                        .filter(|&eni| mainline.ingredients[eni].is_egress())
+                        .flat_map(|eni| {
+                            // find ingresses under this egress
+                            mainline
+                                .ingredients
+                                .neighbors_directed(eni, petgraph::EdgeDirection::Outgoing)
+                        })
+                        .collect()
+                } else {
+                    // ingress nodes don't need to know about deleted columns, because those are only
+                    // relevant when new writes enter the graph.
+                    Vec::new()
+                };
+                inform.push(ni);
+
+                for ni in inform {
+                    let n = &mainline.ingredients[ni];
+                    let m = match change.clone() {
+                        ColumnChange::Add(field, default) => box payload::Packet::AddBaseColumn {
+                            node: n.local_addr(),
+                            field: field,
+                            default: default,
+                        },
+                        ColumnChange::Drop(column) => box payload::Packet::DropBaseColumn {
+                            node: n.local_addr(),
+                            column: column,
+                        },
+                    };
+
+                    let domain = mainline.domains.get_mut(&n.domain()).unwrap();
+
+                    domain.send_to_healthy(m, &mainline.workers).map_err(|e| {
+                        format!("failed to change columns of {}: {:?}", ni.index(), e)
+                    })?;
+                    domain.wait_for_ack().map_err(|e| {
+                        format!("failed to change columns of {}: {:?}", ni.index(), e)
+                    })?;
+                }
+            }
+
+            // Set up inter-domain connections
+            // NOTE: once we do this, we are making existing domains block on new domains!
+            info!(log, "bringing up inter-domain connections");
+            reporter.phase(MigrationPhase::Connecting);
+            // (except for the ingress nodes of new union parents, which are fed later)
+            let fed: HashSet<_> = union_parents
+                .iter()
+                .filter(|up| up.via != up.parent)
+                .map(|up| up.via)
+                .collect();
+            routing::connect(
                 &log,
                 &mut mainline.ingredients,
-                &mainline.domain_config,
-                nodes,
-                &mainline.persistence,
-                &mainline.listen_addr,
-                &mainline.channel_coordinator,
-                &mut placer,
-                &mut workers,
-                mainline.epoch,
-            );
-            mainline.domains.insert(domain, d);
-        }
+                &mut mainline.domains,
+                &mainline.workers,
+                &new.difference(&fed).cloned().collect(),
+            )?;
+
+            if let Some(ni) = crash_before_replay {
+                let domain = mainline.ingredients[ni].domain();
+                warn!(log, "crashing domain on purpose"; "domain" => domain.index());
+                mainline
+                    .domains
+                    .get_mut(&domain)
+                    .unwrap()
+                    .send_to_healthy(box payload::Packet::Quit, &mainline.workers)
+                    .unwrap();
+            }
+            if let Some(ni) = remove_before_ready {
+                let n = &mainline.ingredients[ni];
+                warn!(log, "removing node on purpose"; "node" => ni.index());
+                mainline
+                    .domains
+                    .get_mut(&n.domain())
+                    .unwrap()
+                    .send_to_healthy(
+                        box payload::Packet::RemoveNodes {
+                            nodes: vec![n.local_addr()],
+                        },
+                        &mainline.workers,
+                    )
+                    .unwrap();
+            }
 
-        // Add any new nodes to existing domains (they'll also ignore all updates for now)
-        debug!(log, "mutating existing domains");
-        augmentation::inform(&log, &mut mainline, uninformed_domain_nodes);
+            // And now, the last piece of the puzzle -- set up materializations
+            info!(log, "initializing new materializations");
+            reporter.phase(MigrationPhase::Replaying);
+            mainline.materializations.commit(
+                &mainline.ingredients,
+                &new,
+                &seeds,
+                replay_priority,
+                &mut mainline.domains,
+                &mainline.workers,
+                &mut reporter,
+            )?;
+
+            if verify_replays {
+                info!(log, "verifying replayed state");
+                reporter.phase(MigrationPhase::Verifying);
+                let errors = verification::verify(
+                    &log,
+                    &mainline.ingredients,
+                    mainline.materializations.replayed(),
+                    &mut mainline.domains,
+                    &mainline.workers,
+                )?;
+                verification::check(&log, &errors)?;
+            }
 
-        // Tell all base nodes and base ingress children about newly added columns
-        for (ni, change) in self.columns {
-            let mut inform = if let ColumnChange::Add(..) = change {
-                // we need to inform all of the base's children too,
-                // so that they know to add columns to existing records when replaying
-                mainline
-                    .ingredients
-                    .neighbors_directed(ni, petgraph::EdgeDirection::Outgoing)
-                    .filter(|&eni| mainline.ingredients[eni].is_egress())
-                    .flat_map(|eni| {
-                        // find ingresses under this egress
-                        mainline
-                            .ingredients
-                            .neighbors_directed(eni, petgraph::EdgeDirection::Outgoing)
-                    })
-                    .collect()
-            } else {
-                // ingress nodes don't need to know about deleted columns, because those are only
-                // relevant when new writes enter the graph.
-                Vec::new()
-            };
-            inform.push(ni);
+            // All the new nodes have been replayed, so the held back readers can be let go. Tell all
+            // their domains before waiting for any of them, so that they're exposed close together.
+            if !hidden.is_empty() {
+                info!(log, "exposing new readers"; "#readers" => hidden.len());
+                reporter.phase(MigrationPhase::Exposing);
+                let mut by_domain = HashMap::new();
+                for &ni in &hidden {
+                    let n = &mainline.ingredients[ni];
+                    by_domain
+                        .entry(n.domain())
+                        .or_insert_with(Vec::new)
+                        .push(n.local_addr());
+                }
+                for (&di, nodes) in &by_domain {
+                    mainline
+                        .domains
+                        .get_mut(&di)
+                        .unwrap()
+                        .send_to_healthy(
+                            box payload::Packet::ExposeReaders {
+                                nodes: nodes.clone(),
+                            },
+                            &mainline.workers,
+                        )
+                        .map_err(|e| format!("failed to expose new readers: {:?}", e))?;
+                }
+                for di in by_domain.keys() {
+                    mainline
+                        .domains
+                        .get_mut(di)
+                        .unwrap()
+                        .wait_for_ack()
+                        .map_err(|e| format!("failed to expose new readers: {:?}", e))?;
+                }
+                for &ni in &hidden {
+                    mainline.ingredients[ni]
+                        .with_reader_mut(|r| r.set_hidden(false))
+                        .unwrap();
+                }
+                reporter.report(MigrationEventKind::ReadersExposed {
+                    nodes: hidden.clone(),
+                });
+            }
 
-            for ni in inform {
-                let n = &mainline.ingredients[ni];
-                let m = match change.clone() {
-                    ColumnChange::Add(field, default) => box payload::Packet::AddBaseColumn {
-                        node: n.local_addr(),
-                        field: field,
-                        default: default,
-                    },
-                    ColumnChange::Drop(column) => box payload::Packet::DropBaseColumn {
-                        node: n.local_addr(),
-                        column: column,
-                    },
-                };
+            // The new versions of replaced queries are ready, so they can start serving reads
+            // meant for the old ones.
+            if !replaced.is_empty() {
+                info!(log, "swapping in replacement readers"; "#readers" => replaced.len());
+                reporter.phase(MigrationPhase::Swapping);
+                for &(old, reader) in &replaced {
+                    let n = &mainline.ingredients[reader];
+                    let domain = mainline.domains.get_mut(&n.domain()).unwrap();
+                    domain
+                        .send_to_healthy(
+                            box payload::Packet::TakeOverReader {
+                                node: n.local_addr(),
+                                from: old,
+                            },
+                            &mainline.workers,
+                        )
+                        .map_err(|e| {
+                            format!("failed to swap in reader {}: {:?}", reader.index(), e)
+                        })?;
+                    domain.wait_for_ack().map_err(|e| {
+                        format!("failed to swap in reader {}: {:?}", reader.index(), e)
+                    })?;
+                }
+            }
+
+            // What modified operators send their children can't be taken back, so they go last
+            if !modified.is_empty() {
+                info!(log, "modifying operators in place"; "#nodes" => modified.len());
+                reporter.phase(MigrationPhase::Modifying);
+                for m in &modified {
+                    let n = &mainline.ingredients[m.node];
+                    let domain = mainline.domains.get_mut(&n.domain()).unwrap();
+                    domain
+                        .send_to_healthy(
+                            box payload::Packet::ModifyOperator {
+                                node: n.local_addr(),
+                                parent: mainline.ingredients[m.parent].local_addr(),
+                                operator: m.operator.clone(),
+                                fields: m.added.clone(),
+                            },
+                            &mainline.workers,
+                        )
+                        .map_err(|e| format!("failed to modify {}: {:?}", m.node.index(), e))?;
+                    domain
+                        .wait_for_ack()
+                        .map_err(|e| format!("failed to modify {}: {:?}", m.node.index(), e))?;
+                }
+            }
+
+            // The same goes for the rows that unions are sent from their new parents
+            if !union_parents.is_empty() {
+                info!(log, "adding union parents"; "#parents" => union_parents.len());
+                reporter.phase(MigrationPhase::Merging);
+                for up in &union_parents {
+                    if up.via == up.parent {
+                        mainline.ingredients.add_edge(up.parent, up.union, ());
+                    }
+                    let u = &mainline.ingredients[up.union];
+                    let domain = mainline.domains.get_mut(&u.domain()).unwrap();
+                    domain
+                        .send_to_healthy(
+                            box payload::Packet::AddUnionParent {
+                                node: u.local_addr(),
+                                parent: mainline.ingredients[up.via].local_addr(),
+                                operator: up.operator.clone(),
+                            },
+                            &mainline.workers,
+                        )
+                        .map_err(|e| {
+                            format!("failed to merge into {}: {:?}", up.union.index(), e)
+                        })?;
+                    domain.wait_for_ack().map_err(|e| {
+                        format!("failed to merge into {}: {:?}", up.union.index(), e)
+                    })?;
+                    if up.via == up.parent {
+                        continue;
+                    }
+
+                    // the parent's rows, and then its updates, only go to the new ingress now
+                    let egress = mainline
+                        .ingredients
+                        .neighbors_directed(up.via, petgraph::EdgeDirection::Incoming)
+                        .next()
+                        .unwrap();
+                    let egress = &mainline.ingredients[egress];
+                    let i = &mainline.ingredients[up.via];
+                    let domain = mainline.domains.get_mut(&egress.domain()).unwrap();
+                    domain
+                        .send_to_healthy(
+                            box payload::Packet::FeedEgress {
+                                node: egress.local_addr(),
+                                source: mainline.ingredients[up.parent].local_addr(),
+                                new_tx: (up.via, i.local_addr(), (i.domain(), 0)),
+                            },
+                            &mainline.workers,
+                        )
+                        .map_err(|e| format!("failed to feed {}: {:?}", up.via.index(), e))?;
+                    domain
+                        .wait_for_ack()
+                        .map_err(|e| format!("failed to feed {}: {:?}", up.via.index(), e))?;
+                }
+            }
+        };
+
+        if let Err(e) = applied {
+            crit!(log, "migration failed, rolling back: {}", e);
+            unwire(&mut mainline.ingredients, &wired);
+            rollback(&log, mainline, &new, booted, informed);
+            mainline.graph_log.record_failed(&log, recorded, true);
+            warn!(log, "migration rolled back"; "ms" => start.elapsed().as_millis());
+            let e = format!("migration failed, and was rolled back: {}", e);
+            reporter.finish(Some(&e));
+            return Err(e);
+        }
 
-                let domain = mainline.domains.get_mut(&n.domain()).unwrap();
+        // Nothing reads from the old versions of replaced queries any more
+        if !replaced.is_empty() {
+            let old: Vec<_> = replaced.iter().map(|&(old, _)| old).collect();
+            if let Err(e) = mainline.remove_with_unused_ancestors(&old[..]) {
+                panic!("cannot remove replaced readers: {}", e);
+            }
+        }
 
-                domain.send_to_healthy(m, &mainline.workers).unwrap();
-                domain.wait_for_ack().unwrap();
+        for (ni, name) in self.renamed {
+            info!(log, "renaming node"; "node" => ni.index(), "name" => &name);
+            mainline.ingredients[ni].set_name(name);
+        }
+
+        // The domains run the modified operators now, so the graph should describe them too
+        for m in modified {
+            // a node that gains columns only has readers for children
+            let children: Vec<_> = mainline
+                .ingredients
+                .neighbors_directed(m.node, petgraph::EdgeDirection::Outgoing)
+                .collect();
+            for ni in children.into_iter().chain(Some(m.node)) {
+                for f in &m.added {
+                    mainline.ingredients[ni].add_column(f);
+                }
             }
+            mainline.ingredients[m.node].replace_operator(m.operator);
         }
+        for up in union_parents {
+            mainline.ingredients[up.union].replace_operator(up.operator);
+        }
+
+        mainline
+            .graph_log
+            .record(&log, &mainline.ingredients, recorded, self.keys);
+        mainline.topology_version += 1;
+        mainline.last_migration = Some(SystemTime::now());
+        warn!(log, "migration completed"; "ms" => start.elapsed().as_millis());
+        reporter.finish(None);
+        Ok(())
+    }
+
+    /// Work out what committing this `Migration` would do, without doing any of it.
+    ///
+    /// This shards the new nodes, assigns them to domains, adds the ingress and egress nodes they
+    /// need, and decides what to materialize just like `commit` does, but stops before any domain
+    /// is told about the migration. Nodes that are to be removed are only listed. `rows` gives the
+    /// number of rows in the state of existing nodes, where it is known, and is used to estimate
+    /// how much would be replayed.
+    ///
+    /// The new nodes are left in the graph, so this should only be called through
+    /// `ControllerInner::plan_migration`, which puts the graph back the way it was.
+    pub(super) fn plan(self, rows: &HashMap<NodeIndex, usize>) -> Result<MigrationPlan, String> {
+        validation::check(&self.log, &self.validate())?;
+        self.check_materialization_hints()?;
+        let going = self.removed.iter().chain(&self.released).cloned().collect();
+        let mut new: HashSet<_> = self.added.iter().cloned().collect();
+        new.extend(self.readers.values().cloned());
+        check_names(self.mainline, &new, &going, &self.renamed)?;
 
-        // Set up inter-domain connections
-        // NOTE: once we do this, we are making existing domains block on new domains!
-        info!(log, "bringing up inter-domain connections");
-        routing::connect(
+        let log = self.log;
+        let mainline = self.mainline;
+
+        let mut new: HashSet<_> = self.added.into_iter().collect();
+        new.extend(self.readers.values().cloned());
+
+        if let Some(shards) = sharding::factor(&mainline.ingredients, &new, mainline.sharding)? {
+            sharding::shard(
+                &log,
+                &mut mainline.ingredients,
+                mainline.source,
+                &mut new,
+                shards,
+                mainline.sharding.is_some(),
+            );
+        }
+        let ndomains = mainline.ndomains;
+        assignment::assign(
             &log,
             &mut mainline.ingredients,
-            &mut mainline.domains,
-            &mainline.workers,
+            mainline.source,
             &new,
+            &self.placements,
+            mainline.domain_strategy,
+            &mut mainline.ndomains,
+        )
+        .map_err(|e| format!("cannot place new nodes: {}", e))?;
+        routing::add(
+            &log,
+            &mut mainline.ingredients,
+            mainline.source,
+            &mut new,
+            &HashSet::new(),
         );
 
-        // And now, the last piece of the puzzle -- set up materializations
-        info!(log, "initializing new materializations");
-        mainline.materializations.commit(
-            &mainline.ingredients,
-            &new,
-            &mut mainline.domains,
+        let mut sorted_new: Vec<_> = new
+            .iter()
+            .cloned()
+            .filter(|&ni| ni != mainline.source)
+            .filter(|&ni| !mainline.ingredients[ni].is_dropped())
+            .collect();
+        sorted_new.sort();
+        let nodes = sorted_new
+            .into_iter()
+            .map(|ni| {
+                let n = &mainline.ingredients[ni];
+                PlannedNode {
+                    node: ni,
+                    name: n.name().to_owned(),
+                    description: format!("{:?}", n),
+                    domain: n.domain(),
+                    new_domain: n.domain().index() >= ndomains,
+                    placed: self.placements.contains_key(&ni),
+                    shards: n.sharded_by().shards(),
+                }
+            })
+            .collect();
+
+        let (materializations, replays) =
+            mainline
+                .materializations
+                .plan(&mainline.ingredients, &new, rows);
+
+        Ok(MigrationPlan {
+            nodes,
+            removed: self.removed,
+            reused: self.reused,
+            materializations,
+            replays,
+            strategy: mainline.domain_strategy,
+        })
+    }
+}
+
+/// Check that no new base, and no new view, would have the name of another one once the migration
+/// is committed. `going` are the nodes that are removed along with their readers, or replaced,
+/// and whose names are therefore free to take. Bases and views are looked up separately, so a
+/// base and a view may share a name.
+fn check_names(
+    mainline: &ControllerInner,
+    new: &HashSet<NodeIndex>,
+    going: &HashSet<NodeIndex>,
+    renamed: &[(NodeIndex, String)],
+) -> Result<(), String> {
+    let graph = &mainline.ingredients;
+    let renamed: HashMap<_, _> = renamed.iter().map(|(ni, name)| (*ni, &name[..])).collect();
+    let changing = |ni: &NodeIndex| new.contains(ni) || renamed.contains_key(ni);
+
+    // nodes that keep their names go first, so that only new names can be found to be taken
+    let mut nodes: Vec<_> = graph.node_indices().filter(|ni| !changing(ni)).collect();
+    nodes.extend(graph.node_indices().filter(|ni| changing(ni)));
+
+    let mut bases = HashMap::new();
+    let mut views = HashMap::new();
+    for ni in nodes {
+        let n = &graph[ni];
+        let name = renamed.get(&ni).cloned().unwrap_or_else(|| n.name());
+        let (kind, names) = if n.is_base() {
+            ("base", &mut bases)
+        } else if let Ok(of) = n.with_reader(|r| r.is_for()) {
+            if going.contains(&of) {
+                continue;
+            }
+            ("view", &mut views)
+        } else {
+            continue;
+        };
+        if going.contains(&ni) {
+            continue;
+        }
+
+        if let Some(other) = names.insert(name, ni) {
+            // a reader that is copied to take over from another one shares its name
+            let copy = n.is_reader()
+                && graph[other].with_reader(|r| r.is_for()) == n.with_reader(|r| r.is_for());
+            if changing(&ni) && !copy {
+                return Err(format!("there already is a {} named {}", kind, name));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check that `reader` can take over serving reads from `old`, which means that every shard of the
+/// two must be on the same worker. `worker` is where any new domains are going to be put.
+fn check_replacement(
+    mainline: &ControllerInner,
+    old: NodeIndex,
+    reader: NodeIndex,
+    worker: Option<&WorkerIdentifier>,
+) -> Result<(), String> {
+    let old = &mainline.domains[&mainline.ingredients[old].domain()];
+    let w = old.assignment(0);
+    if (1..old.shards()).any(|i| old.assignment(i) != w) {
+        return Err("the old reader is spread across several workers".to_owned());
+    }
+
+    let n = &mainline.ingredients[reader];
+    if n.sharded_by().shards().unwrap_or(1) != old.shards() {
+        return Err("the new reader is sharded differently from the old one".to_owned());
+    }
+    let same_worker = match mainline.domains.get(&n.domain()) {
+        Some(d) => (0..d.shards()).all(|i| d.assignment(i) == w),
+        None => worker == Some(&w),
+    };
+    if !same_worker {
+        return Err("the new reader would be on a different worker than the old one".to_owned());
+    }
+    Ok(())
+}
+
+/// The node that `parent` stands in for, if it's one of the ingress, egress, and sharder nodes
+/// that the migrations add to get records across domains and shards.
+fn logical_parent(graph: &Graph, parent: NodeIndex) -> NodeIndex {
+    let mut logical = parent;
+    while graph[logical].is_ingress() || graph[logical].is_egress() || graph[logical].is_sharder() {
+        logical = graph
+            .neighbors_directed(logical, petgraph::EdgeDirection::Incoming)
+            .next()
+            .unwrap();
+    }
+    logical
+}
+
+/// An operator that is to be replaced in place, ready to be sent to its domain.
+struct Modification {
+    node: NodeIndex,
+    /// The node that the operator reads from in its domain.
+    parent: NodeIndex,
+    /// The new operator, with its parent resolved to its index in the domain.
+    operator: NodeOperator,
+    /// The fields of the columns that the new operator adds.
+    added: Vec<String>,
+}
+
+/// Check that `node` can be given the new operator and fields without rebuilding any state.
+fn check_modification(
+    mainline: &ControllerInner,
+    node: NodeIndex,
+    fields: Vec<String>,
+    mut operator: NodeOperator,
+    removed: &[NodeIndex],
+) -> Result<Modification, String> {
+    let graph = &mainline.ingredients;
+    let n = &graph[node];
+    if !n.is_internal() {
+        return Err("only operators can be modified".to_owned());
+    }
+    if removed.contains(&node) {
+        return Err("it is also being removed".to_owned());
+    }
+
+    // a parent in another domain, or that is sharded differently, is read through an ingress
+    let mut parents = graph.neighbors_directed(node, petgraph::EdgeDirection::Incoming);
+    let parent = match (parents.next(), parents.next()) {
+        (Some(parent), None) => parent,
+        _ => return Err("only operators with a single parent can be modified".to_owned()),
+    };
+    let logical = logical_parent(graph, parent);
+    if operator.ancestors() != vec![logical] {
+        return Err("the new operator must read from the same parent".to_owned());
+    }
+
+    let mut remap = mainline.remap[&n.domain()].clone();
+    let local = remap[&parent];
+    remap.insert(logical, local);
+    operator.on_commit(node, &remap);
+    let added = n.check_modify(&operator)?;
+
+    let old = n.fields();
+    if fields.len() != old.len() + added || !fields.starts_with(old) {
+        return Err(format!(
+            "its fields must be the current ones, followed by {} new ones",
+            added
+        ));
+    }
+    if mainline
+        .materializations
+        .get_status(&parent, &graph[parent])
+        != MaterializationStatus::Full
+    {
+        return Err("the node it reads from in its domain must be fully materialized".to_owned());
+    }
+    if mainline.materializations.get_status(&node, n) == MaterializationStatus::Partial {
+        return Err("partially materialized operators can't be modified".to_owned());
+    }
+    if added != 0
+        && graph
+            .neighbors_directed(node, petgraph::EdgeDirection::Outgoing)
+            .any(|c| !graph[c].is_reader())
+    {
+        return Err("columns can only be added to operators that only feed readers".to_owned());
+    }
+
+    Ok(Modification {
+        node,
+        parent,
+        operator,
+        added: fields[old.len()..].to_vec(),
+    })
+}
+
+/// A parent that an existing union gains, ready to be sent to the domains.
+struct UnionParent {
+    union: NodeIndex,
+    parent: NodeIndex,
+    /// The node that the union reads the parent through in its domain.
+    via: NodeIndex,
+    /// The union's new operator, with the new parent resolved to its index in the domain.
+    operator: NodeOperator,
+}
+
+/// Check that the existing `union` can start reading from `parent` without rebuilding any state.
+fn check_union_parent(
+    mainline: &ControllerInner,
+    union: NodeIndex,
+    parent: NodeIndex,
+    emit: &[usize],
+    new: &HashSet<NodeIndex>,
+    removed: &[NodeIndex],
+) -> Result<(), String> {
+    let graph = &mainline.ingredients;
+    let u = &graph[union];
+    let p = &graph[parent];
+    let is_union = u.is_internal()
+        && match **u {
+            NodeOperator::Union(ref u) => !u.is_shard_merger(),
+            _ => false,
+        };
+    if !is_union || new.contains(&union) {
+        return Err("only existing unions can gain parents".to_owned());
+    }
+    if removed.contains(&union) || removed.contains(&parent) {
+        return Err("it or the union is also being removed".to_owned());
+    }
+    if !p.is_base() && !p.is_internal() {
+        return Err("only bases and operators can feed a union".to_owned());
+    }
+    if graph
+        .neighbors_directed(union, petgraph::EdgeDirection::Incoming)
+        .any(|ni| logical_parent(graph, ni) == parent)
+    {
+        return Err("the union already reads from it".to_owned());
+    }
+
+    if emit.len() != u.fields().len() {
+        return Err(format!(
+            "it must emit as many columns as the union has ({})",
+            u.fields().len()
+        ));
+    }
+    if emit.windows(2).any(|w| w[1] < w[0]) {
+        return Err("unions don't support column reordering".to_owned());
+    }
+    if emit.iter().any(|&c| c >= p.fields().len()) {
+        return Err("it doesn't have all the columns it is to emit".to_owned());
+    }
+
+    if !p.is_base()
+        && (new.contains(&parent)
+            || mainline.materializations.get_status(&parent, p) != MaterializationStatus::Full)
+    {
+        return Err("it must be a base, or already be fully materialized".to_owned());
+    }
+    if !u.sharded_by().is_none() {
+        return Err("sharded unions can't gain parents".to_owned());
+    }
+
+    // the replay paths that fill holes below the union don't know about the new parent
+    let mut below = vec![union];
+    let mut seen = HashSet::new();
+    while let Some(ni) = below.pop() {
+        if !seen.insert(ni) {
+            continue;
+        }
+        if mainline.materializations.get_status(&ni, &graph[ni]) == MaterializationStatus::Partial {
+            return Err("nothing below the union may be partially materialized".to_owned());
+        }
+        below.extend(graph.neighbors_directed(ni, petgraph::EdgeDirection::Outgoing));
+    }
+    Ok(())
+}
+
+/// Check that the union reads its new parent through a node that only it reads, so that the
+/// parent's domain can start feeding that node without anything else seeing the rows twice.
+fn check_union_wiring(
+    graph: &Graph,
+    parent: NodeIndex,
+    via: NodeIndex,
+    new: &HashSet<NodeIndex>,
+) -> Result<(), String> {
+    if !graph[parent].sharded_by().is_none() {
+        return Err("sharded nodes can't feed existing unions".to_owned());
+    }
+    if via == parent {
+        return Ok(());
+    }
+    if !new.contains(&via) {
+        return Err("the union's domain already receives its updates".to_owned());
+    }
+    let from_egress = graph
+        .neighbors_directed(via, petgraph::EdgeDirection::Incoming)
+        .all(|ni| graph[ni].is_egress());
+    if !from_egress
+        || graph
+            .neighbors_directed(via, petgraph::EdgeDirection::Outgoing)
+            .count()
+            != 1
+    {
+        return Err("other new nodes in the union's domain read from it too".to_owned());
+    }
+    Ok(())
+}
+
+/// Take out the edges that a failed migration added to existing unions.
+fn unwire(graph: &mut Graph, wired: &[(NodeIndex, NodeIndex)]) {
+    for &(from, to) in wired {
+        if let Some(edge) = graph.find_edge(from, to) {
+            graph.remove_edge(edge);
+        }
+    }
+}
+
+/// Undo what a failed migration did to the running domains, and forget about the nodes it added.
+///
+/// Domains may well be unreachable at this point, so nothing here waits for them to reply. A
+/// domain that can't be told to forget about the new nodes can't be sending anything to them
+/// either.
+fn rollback(
+    log: &slog::Logger,
+    mainline: &mut ControllerInner,
+    new: &HashSet<NodeIndex>,
+    booted: Vec<DomainIndex>,
+    informed: HashMap<DomainIndex, Vec<LocalNodeIndex>>,
+) {
+    // existing egress nodes must stop sending to the new ingress nodes
+    for &ni in new {
+        if !mainline.ingredients[ni].is_ingress() {
+            continue;
+        }
+        let senders: Vec<_> = mainline
+            .ingredients
+            .neighbors_directed(ni, petgraph::EdgeDirection::Incoming)
+            .filter(|sender| !new.contains(sender))
+            .collect();
+        for sender in senders {
+            let s = &mainline.ingredients[sender];
+            if s.is_egress() {
+                debug!(log, "disconnecting egress"; "egress" => sender.index(), "ingress" => ni.index());
+                drop(
+                    mainline
+                        .domains
+                        .get_mut(&s.domain())
+                        .unwrap()
+                        .send_to_healthy(
+                            box payload::Packet::RemoveEgressTx {
+                                node: s.local_addr(),
+                                target: ni,
+                            },
+                            &mainline.workers,
+                        ),
+                );
+            }
+        }
+    }
+
+    // existing domains must drop the new nodes they were given
+    for (di, nodes) in informed {
+        debug!(log, "removing new nodes from domain"; "domain" => di.index());
+        drop(mainline.domains.get_mut(&di).unwrap().send_to_healthy(
+            box payload::Packet::RemoveNodes { nodes },
             &mainline.workers,
-        );
+        ));
+    }
 
-        warn!(log, "migration completed"; "ms" => start.elapsed().as_millis());
+    // and new domains are of no use to anyone
+    for di in booted {
+        debug!(log, "shutting down new domain"; "domain" => di.index());
+        let mut d = mainline.domains.remove(&di).unwrap();
+        drop(d.send_to_healthy(box payload::Packet::Quit, &mainline.workers));
+    }
+
+    for &ni in new {
+        let neighbors: Vec<_> = mainline.ingredients.neighbors_undirected(ni).collect();
+        for other in neighbors {
+            if let Some(edge) = mainline.ingredients.find_edge(other, ni) {
+                mainline.ingredients.remove_edge(edge);
+            }
+            if let Some(edge) = mainline.ingredients.find_edge(ni, other) {
+                mainline.ingredients.remove_edge(edge);
+            }
+        }
+        mainline.ingredients[ni].remove();
     }
+    mainline.materializations.forget(new);
 }
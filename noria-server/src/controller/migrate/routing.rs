@@ -10,6 +10,7 @@ use crate::controller::domain_handle::DomainHandle;
 use crate::controller::{WorkerIdentifier, WorkerStatus};
 use dataflow::node;
 use dataflow::prelude::*;
+use noria::channel::tcp::SendError;
 use petgraph;
 use petgraph::graph::NodeIndex;
 use slog::Logger;
@@ -17,11 +18,16 @@ use std::collections::{HashMap, HashSet};
 
 /// Add in ingress and egress nodes as appropriate in the graph to facilitate cross-domain
 /// communication.
+///
+/// Besides the parents of new nodes, the parents of the existing nodes in `rewired` are connected
+/// too. Only edges that don't cross domains yet are affected, which for an existing node are the
+/// edges from the parents it has gained.
 pub fn add(
     log: &Logger,
     graph: &mut Graph,
     source: NodeIndex,
     new: &mut HashSet<NodeIndex>,
+    rewired: &HashSet<NodeIndex>,
 ) -> HashMap<(NodeIndex, NodeIndex), NodeIndex> {
     // find all new nodes in topological order. we collect first since we'll be mutating the graph
     // below. it's convenient to have the nodes in topological order, because we then know that
@@ -37,7 +43,7 @@ pub fn add(
         if graph[node].is_dropped() {
             continue;
         }
-        if !new.contains(&node) {
+        if !new.contains(&node) && !rewired.contains(&node) {
             continue;
         }
         topo_list.push(node);
@@ -268,7 +274,7 @@ pub(super) fn connect(
     domains: &mut HashMap<DomainIndex, DomainHandle>,
     workers: &HashMap<WorkerIdentifier, WorkerStatus>,
     new: &HashSet<NodeIndex>,
-) {
+) -> Result<(), String> {
     // ensure all egress nodes contain the tx channel of the domains of their child ingress nodes
     for &node in new {
         let n = &graph[node];
@@ -308,7 +314,7 @@ pub(super) fn connect(
                                 },
                                 workers,
                             )
-                            .unwrap();
+                            .map_err(|e| connect_failed(node, e))?;
                     }
                 } else {
                     // consider the case where len != 1. that must mean that the
@@ -325,7 +331,7 @@ pub(super) fn connect(
                             },
                             workers,
                         )
-                        .unwrap();
+                        .map_err(|e| connect_failed(node, e))?;
                 }
             } else if sender_node.is_sharder() {
                 trace!(log,
@@ -346,11 +352,20 @@ pub(super) fn connect(
                         },
                         workers,
                     )
-                    .unwrap();
+                    .map_err(|e| connect_failed(node, e))?;
             } else if sender_node.is_source() {
             } else {
                 unreachable!("ingress parent is not a sender");
             }
         }
     }
+    Ok(())
+}
+
+fn connect_failed(ingress: NodeIndex, e: SendError) -> String {
+    format!(
+        "failed to connect ingress node {} to its sender: {:?}",
+        ingress.index(),
+        e
+    )
 }
@@ -6,12 +6,55 @@ use petgraph::graph::NodeIndex;
 use slog::Logger;
 use std::collections::{HashMap, HashSet};
 
+/// How many ways the nodes that a migration adds should be sharded, if at all.
+///
+/// If sharding is enabled for the whole graph, that is the configured number of shards.
+/// Otherwise, only bases that were sharded explicitly and the nodes below them are sharded, and
+/// all of them must be sharded the same number of ways.
+pub fn factor(
+    graph: &Graph,
+    new: &HashSet<NodeIndex>,
+    everywhere: Option<usize>,
+) -> Result<Option<usize>, String> {
+    let mut factor = everywhere;
+    for &ni in new {
+        // new bases may have been sharded explicitly, and new nodes inherit the sharding of the
+        // existing nodes they read from
+        let declared = Some(ni).filter(|&ni| graph[ni].is_base());
+        let parents = graph
+            .neighbors_directed(ni, petgraph::EdgeDirection::Incoming)
+            .filter(|p| !new.contains(p));
+        for n in declared.into_iter().chain(parents) {
+            match (factor, graph[n].sharded_by().shards()) {
+                (_, None) => {}
+                (None, shards) => factor = shards,
+                (Some(f), Some(shards)) if f != shards => {
+                    return Err(format!(
+                        "{} is sharded {} ways, but other nodes are sharded {} ways",
+                        graph[n].name(),
+                        shards,
+                        f
+                    ));
+                }
+                (Some(_), Some(_)) => {}
+            }
+        }
+    }
+    Ok(factor)
+}
+
+/// Decide how to shard each new node, adding sharders and shard mergers where the sharding has to
+/// change between a node and its input.
+///
+/// If `everywhere` is false, only the nodes below bases that were sharded explicitly are sharded;
+/// the rest of the graph is left unsharded.
 pub fn shard(
     log: &Logger,
     graph: &mut Graph,
     source: NodeIndex,
     new: &mut HashSet<NodeIndex>,
     sharding_factor: usize,
+    everywhere: bool,
 ) -> HashMap<(NodeIndex, NodeIndex), NodeIndex> {
     let mut topo_list = Vec::with_capacity(new.len());
     let mut topo = petgraph::visit::Topo::new(&*graph);
@@ -32,11 +75,29 @@ pub fn shard(
     // we want to shard every node by its "input" index. if the index required from a parent
     // doesn't match the current sharding key, we need to do a shuffle (i.e., a Union + Sharder).
     'nodes: for node in topo_list {
+        let auto_increment = graph[node].get_base().and_then(|b| b.auto_increment());
+        if auto_increment.is_some() {
+            // every shard would assign ids independently, so they would not be unique
+            info!(log, "not sharding base with auto-increment column"; "node" => ?node);
+            continue;
+        }
+        if graph[node].is_base() && !graph[node].sharded_by().is_none() {
+            info!(log, "keeping explicit sharding of base";
+                  "node" => ?node,
+                  "sharding" => ?graph[node].sharded_by());
+            continue;
+        }
+
         let mut input_shardings: HashMap<_, _> = graph
             .neighbors_directed(node, petgraph::EdgeDirection::Incoming)
             .map(|ni| (ni, graph[ni].sharded_by()))
             .collect();
 
+        if !everywhere && input_shardings.values().all(|s| s.is_none()) {
+            // nothing above this node was sharded explicitly, so it stays unsharded too
+            continue;
+        }
+
         let mut need_sharding = if graph[node].is_internal() || graph[node].is_base() {
             // suggest_indexes is okay because `node` *must* be new, and therefore will return
             // global node indices.
@@ -48,23 +109,36 @@ pub fn shard(
                 continue;
             }
 
-            let s = graph[node]
-                .with_reader(|r| r.key())
-                .unwrap()
-                .and_then(|c| {
-                    if c.len() == 1 {
-                        Some(Sharding::ByColumn(c[0], sharding_factor))
-                    } else {
-                        None
-                    }
+            let mut s = graph[node]
+                .with_reader(|r| {
+                    r.key().and_then(|c| {
+                        // range lookups on an ordered reader would have to visit every shard
+                        if c.len() == 1 && r.index_type() == IndexType::HashMap {
+                            Some(Sharding::ByColumn(c[0], sharding_factor))
+                        } else {
+                            None
+                        }
+                    })
                 })
+                .unwrap()
                 .unwrap_or(Sharding::ForcedNone);
+            if !everywhere && s != input_shardings[&ni] {
+                // a reader keyed on another column than its input keeps its input's sharding
+                // rather than having its rows shuffled all over again, and reads from it gather
+                // the rows for a key from every shard. readers that can't be read that way are
+                // read from the merged shards instead.
+                s = match (s, input_shardings[&ni]) {
+                    (Sharding::ByColumn(..), input @ Sharding::ByColumn(..))
+                    | (Sharding::ByColumn(..), input @ Sharding::Random(..)) => input,
+                    _ => Sharding::ForcedNone,
+                };
+            }
             if s.is_none() {
                 info!(log, "de-sharding prior to stream-only reader"; "node" => ?node);
             } else {
-                info!(log, "sharding reader"; "node" => ?node);
+                info!(log, "sharding reader"; "node" => ?node, "sharding" => ?s);
                 graph[node]
-                    .with_reader_mut(|r| r.shard(sharding_factor))
+                    .with_reader_mut(|r| r.shard(s.shards().unwrap()))
                     .unwrap();
             }
 
@@ -387,6 +461,12 @@ pub fn shard(
             if graph[p].is_base() {
                 trace!(log, "well, its parent is a base");
 
+                // only bases that were sharded explicitly are sharded
+                if !everywhere {
+                    trace!(log, "no, parent is weird (not sharded explicitly)");
+                    continue;
+                }
+
                 // we can't shard compound bases (yet)
                 if let Some(k) = graph[p].get_base().unwrap().key() {
                     if k.len() != 1 {
@@ -542,30 +622,41 @@ pub fn shard(
         }
     }
 
-    // and finally, because we don't *currently* support sharded shuffles (i.e., going directly
-    // from one sharding to another), we replace such patterns with a merge + a shuffle. the merge
-    // will ensure that replays from the first sharding are turned into a single update before
-    // arriving at the second sharding, and the merged sharder will ensure that nshards is set
-    // correctly.
+    // and finally, sharders in sharded domains shuffle records directly from every shard of their
+    // parent to the shards of their child. each of those shards thus hears from every shard
+    // above, and so needs a shard merger right below the sharder to combine their replays into one
+    // before they reach the child.
     let sharded_sharders: Vec<_> = new
         .iter()
         .filter(|&&n| graph[n].is_sharder() && !graph[n].sharded_by().is_none())
         .cloned()
         .collect();
     for n in sharded_sharders {
-        // sharding what?
-        let p = {
-            let mut ps = graph.neighbors_directed(n, petgraph::EdgeDirection::Incoming);
-            let p = ps.next().unwrap();
-            assert_eq!(ps.count(), 0);
-            p
-        };
-        error!(log, "preventing unsupported sharded shuffle"; "sharder" => ?n);
-        reshard(log, new, &mut swaps, graph, p, n, Sharding::ForcedNone);
-        graph
-            .node_weight_mut(n)
-            .unwrap()
-            .shard_by(Sharding::ForcedNone);
+        let col = graph[n].with_sharder(|s| s.sharded_by()).unwrap();
+        let n_sharding = graph[n].sharded_by();
+        let merger: NodeOperator = ops::union::Union::new_deshard(n, n_sharding).into();
+        let mut merger = graph[n].mirror(merger);
+        merger.shard_by(Sharding::ByColumn(col, sharding_factor));
+        let merger = graph.add_node(merger);
+        new.insert(merger);
+        info!(log, "merging sharded shuffle"; "sharder" => ?n, "merger" => ?merger);
+
+        let cs: Vec<_> = graph
+            .neighbors_directed(n, petgraph::EdgeDirection::Outgoing)
+            .collect();
+        for c in cs {
+            let e = graph.find_edge(n, c).unwrap();
+            let w = graph.remove_edge(e).unwrap();
+            graph.add_edge(merger, c, w);
+        }
+        graph.add_edge(n, merger, ());
+
+        // the children now refer to the merger wherever they referred to the sharder
+        for instead in swaps.values_mut() {
+            if *instead == n {
+                *instead = merger;
+            }
+        }
     }
 
     // check that we didn't mess anything up
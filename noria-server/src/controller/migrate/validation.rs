@@ -0,0 +1,352 @@
+//! Checks that the nodes a migration adds are hooked up in a way that can work, before any of them
+//! is sent to a domain.
+//!
+//! Most mistakes in how nodes are put together would otherwise only show once records reach them,
+//! as a panic deep inside some domain.
+
+use dataflow::prelude::*;
+use noria::filter::Value;
+use noria::ColumnType;
+use petgraph;
+use slog;
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::controller::migrate::materialization::Materializations;
+
+/// Something wrong with a node that a migration adds.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ValidationError {
+    /// The node that is wrong.
+    pub node: NodeIndex,
+    /// The node's name.
+    pub name: String,
+    /// What is wrong with it.
+    pub kind: ValidationErrorKind,
+}
+
+/// The ways in which a node can be wrong.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ValidationErrorKind {
+    /// The node's operator reads column `column` of `ancestor`, which only has `columns` columns.
+    MissingColumn {
+        ancestor: NodeIndex,
+        column: usize,
+        columns: usize,
+    },
+    /// The node is a reader keyed on column `column` of the node it reads, which only has
+    /// `columns` columns.
+    MissingKeyColumn { column: usize, columns: usize },
+    /// The node emits `emits` columns for records from `ancestor`, but is meant to have `columns`
+    /// columns. This is what happens when the parents of a union don't fit together.
+    ArityMismatch {
+        ancestor: NodeIndex,
+        emits: usize,
+        columns: usize,
+    },
+    /// The node looks up rows in `ancestor` on the given columns, but `target`, which would have to
+    /// hold the state for those lookups, is forbidden from being materialized.
+    MissingMaterialization {
+        ancestor: NodeIndex,
+        target: NodeIndex,
+        columns: Vec<usize>,
+    },
+    /// The node compares binary values by order, but they can only be compared for equality.
+    /// `column` is the column of `ancestor` that holds the values, or `None` if they are a
+    /// constant that the node compares the columns of `ancestor` with.
+    OrderedBinary {
+        ancestor: NodeIndex,
+        column: Option<usize>,
+    },
+    /// The node only accepts values of type `expected` in column `column` of `ancestor`, but the
+    /// schema of the base that the column comes from declares it to hold `found` values.
+    WrongColumnType {
+        ancestor: NodeIndex,
+        column: usize,
+        expected: ColumnType,
+        found: ColumnType,
+    },
+    /// The node is a base whose auto-increment column `column` is declared by its schema to hold
+    /// `found` values, rather than integers.
+    NonIntegerAutoIncrement { column: usize, found: ColumnType },
+    /// None of the node's ancestors lead back to a base that is staying, so the node would never
+    /// see a record.
+    Unreachable,
+    /// Nothing reads what the node computes: it has no children, and no reader.
+    Unused,
+}
+
+impl ValidationError {
+    /// Whether the migration must not be committed because of this error. Only unused nodes are
+    /// allowed, as they do no harm beyond the work spent on keeping them up to date.
+    pub fn is_fatal(&self) -> bool {
+        match self.kind {
+            ValidationErrorKind::Unused => false,
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "node {} ({}) ", self.node.index(), self.name)?;
+        match self.kind {
+            ValidationErrorKind::MissingColumn {
+                ancestor,
+                column,
+                columns,
+            } => write!(
+                f,
+                "reads column {} of node {}, which has {} columns",
+                column,
+                ancestor.index(),
+                columns
+            ),
+            ValidationErrorKind::MissingKeyColumn { column, columns } => write!(
+                f,
+                "is keyed on column {} of a node that has {} columns",
+                column, columns
+            ),
+            ValidationErrorKind::ArityMismatch {
+                ancestor,
+                emits,
+                columns,
+            } => write!(
+                f,
+                "has {} columns, but emits {} for records from node {}",
+                columns,
+                emits,
+                ancestor.index()
+            ),
+            ValidationErrorKind::MissingMaterialization {
+                ancestor,
+                target,
+                ref columns,
+            } => write!(
+                f,
+                "looks up rows in node {} on columns {:?}, but node {} is forbidden from being \
+                 materialized",
+                ancestor.index(),
+                columns,
+                target.index()
+            ),
+            ValidationErrorKind::OrderedBinary {
+                ancestor,
+                column: Some(column),
+            } => write!(
+                f,
+                "compares column {} of node {}, which holds binary values, by order, but binary \
+                 values can only be compared for equality",
+                column,
+                ancestor.index()
+            ),
+            ValidationErrorKind::OrderedBinary { column: None, .. } => write!(
+                f,
+                "compares a binary constant by order, but binary values can only be compared for \
+                 equality"
+            ),
+            ValidationErrorKind::WrongColumnType {
+                ancestor,
+                column,
+                expected,
+                found,
+            } => write!(
+                f,
+                "expects column {} of node {} to hold values of type {}, but it holds {}",
+                column,
+                ancestor.index(),
+                expected,
+                found
+            ),
+            ValidationErrorKind::NonIntegerAutoIncrement { column, found } => write!(
+                f,
+                "has auto-increment column {}, which holds values of type {} rather than int",
+                column, found
+            ),
+            ValidationErrorKind::Unreachable => write!(f, "is not reachable from any base"),
+            ValidationErrorKind::Unused => write!(f, "is not read by anything"),
+        }
+    }
+}
+
+/// Check the given new nodes, which include the new readers. `removed` are the existing nodes
+/// that the migration removes. The errors are sorted by node.
+pub(super) fn validate(
+    graph: &Graph,
+    materializations: &Materializations,
+    new: &HashSet<NodeIndex>,
+    removed: &HashSet<NodeIndex>,
+) -> Vec<ValidationError> {
+    let mut sorted_new: Vec<_> = new.iter().cloned().collect();
+    sorted_new.sort();
+
+    let mut errors = Vec::new();
+    for ni in sorted_new {
+        let n = &graph[ni];
+        if n.is_dropped() {
+            continue;
+        }
+        let mut error = |kind| {
+            errors.push(ValidationError {
+                node: ni,
+                name: n.name().to_owned(),
+                kind,
+            })
+        };
+
+        if n.is_internal() {
+            let mut referenced = n.referenced_columns();
+            referenced.sort();
+            referenced.dedup();
+            let mut missing_columns = false;
+            for (ancestor, column) in referenced {
+                let columns = graph[ancestor].fields().len();
+                if column >= columns {
+                    missing_columns = true;
+                    error(ValidationErrorKind::MissingColumn {
+                        ancestor,
+                        column,
+                        columns,
+                    });
+                }
+            }
+
+            for ancestor in n.ancestors() {
+                let columns = graph[ancestor].fields().len();
+                if let Some(emits) = n.emitted_columns(ancestor, columns) {
+                    if emits != n.fields().len() {
+                        error(ValidationErrorKind::ArityMismatch {
+                            ancestor,
+                            emits,
+                            columns: n.fields().len(),
+                        });
+                    }
+                }
+            }
+
+            // lookups and column types can't be traced through columns that don't exist
+            if !missing_columns {
+                for (ancestor, target, columns) in
+                    materializations.unmaterializable_lookups(graph, ni)
+                {
+                    error(ValidationErrorKind::MissingMaterialization {
+                        ancestor,
+                        target,
+                        columns,
+                    });
+                }
+
+                for (ancestor, value) in n.ordered_values() {
+                    let column = match value {
+                        Value::Constant(DataType::Bytes(..)) => None,
+                        Value::Column(c)
+                            if column_type(graph, ancestor, c) == Some(ColumnType::Bytes) =>
+                        {
+                            Some(c)
+                        }
+                        _ => continue,
+                    };
+                    error(ValidationErrorKind::OrderedBinary { ancestor, column });
+                }
+
+                for (ancestor, column, expected) in n.typed_columns() {
+                    match column_type(graph, ancestor, column) {
+                        Some(found) if found != expected => {
+                            error(ValidationErrorKind::WrongColumnType {
+                                ancestor,
+                                column,
+                                expected,
+                                found,
+                            })
+                        }
+                        _ => {}
+                    }
+                }
+            }
+
+            if graph
+                .neighbors_directed(ni, petgraph::EdgeDirection::Outgoing)
+                .next()
+                .is_none()
+            {
+                error(ValidationErrorKind::Unused);
+            }
+        }
+
+        if let Some(b) = n.get_base() {
+            if let (Some(column), Some(schema)) = (b.auto_increment(), b.schema()) {
+                match schema.get(column).map(|c| c.column_type) {
+                    Some(found) if found != ColumnType::Int => {
+                        error(ValidationErrorKind::NonIntegerAutoIncrement { column, found })
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        if let Ok((of, Some(key))) = n.with_reader(|r| (r.is_for(), r.key().map(Vec::from))) {
+            let columns = graph[of].fields().len();
+            for column in key {
+                if column >= columns {
+                    error(ValidationErrorKind::MissingKeyColumn { column, columns });
+                }
+            }
+        }
+
+        if !reaches_base(graph, ni, removed) {
+            error(ValidationErrorKind::Unreachable);
+        }
+    }
+    errors
+}
+
+/// The type of the values in column `column` of node `ni`, as declared by the schema of the base
+/// that the column comes from. `None` if the column is computed, or if the base has no schema.
+fn column_type(graph: &Graph, ni: NodeIndex, column: usize) -> Option<ColumnType> {
+    let n = &graph[ni];
+    if let Some(b) = n.get_base() {
+        return b.schema().and_then(|s| s.get(column)).map(|c| c.column_type);
+    }
+    if !n.is_internal() {
+        return None;
+    }
+    n.parent_columns(column)
+        .into_iter()
+        .filter_map(|(p, c)| c.map(|c| (p, c)))
+        .filter(|&(p, _)| p != ni)
+        .filter_map(|(p, c)| column_type(graph, p, c))
+        .next()
+}
+
+/// Whether some base that isn't being removed is an ancestor of the given node, or is the node.
+fn reaches_base(graph: &Graph, ni: NodeIndex, removed: &HashSet<NodeIndex>) -> bool {
+    let mut seen = HashSet::new();
+    let mut stack = vec![ni];
+    while let Some(ni) = stack.pop() {
+        if !seen.insert(ni) || removed.contains(&ni) || graph[ni].is_dropped() {
+            continue;
+        }
+        if graph[ni].is_base() {
+            return true;
+        }
+        stack.extend(graph.neighbors_directed(ni, petgraph::EdgeDirection::Incoming));
+    }
+    false
+}
+
+/// Log the errors that aren't fatal, and turn the ones that are into a single error.
+pub(super) fn check(log: &slog::Logger, errors: &[ValidationError]) -> Result<(), String> {
+    let mut fatal = Vec::new();
+    for e in errors {
+        if e.is_fatal() {
+            fatal.push(e.to_string());
+        } else {
+            warn!(log, "{}", e);
+        }
+    }
+    if fatal.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("invalid graph: {}", fatal.join("; ")))
+    }
+}
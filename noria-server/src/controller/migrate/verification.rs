@@ -0,0 +1,261 @@
+//! Checks, once a migration's replays have finished, that the state they filled fits the state
+//! they replayed from, before any of the new views are exposed to reads.
+//!
+//! Every full replay goes from a materialized node to the new state, and what the operators along
+//! the way say about the rows they hold (see `RowBound`) bounds how many rows the new state should
+//! have. Where the operators pass rows on untouched, the rows themselves are compared by checksum.
+
+use crate::controller::domain_handle::DomainHandle;
+use crate::controller::migrate::materialization::Replayed;
+use crate::controller::{WorkerIdentifier, WorkerStatus};
+use dataflow::payload;
+use dataflow::prelude::*;
+use slog;
+use std::collections::HashMap;
+use std::fmt;
+use std::thread;
+use std::time::Duration;
+
+/// How many times the two ends of a replay path are measured before they are said to disagree.
+/// Writes that are still on their way from one end to the other can make them disagree briefly.
+const ATTEMPTS: usize = 3;
+/// How long to wait before measuring the two ends of a path again.
+const RETRY_MS: u64 = 50;
+
+/// New state that doesn't fit the state it was replayed from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VerificationError {
+    /// The node whose state was replayed into.
+    pub node: NodeIndex,
+    /// The node's name.
+    pub name: String,
+    /// The node whose state was replayed.
+    pub source: NodeIndex,
+    /// How the two disagree.
+    pub kind: VerificationErrorKind,
+}
+
+/// The ways in which replayed state can disagree with its source.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VerificationErrorKind {
+    /// The node should hold as many rows as its source, which has `expected` rows, but holds
+    /// `rows` rows.
+    RowCount { rows: usize, expected: usize },
+    /// The node holds as many rows as its source, but not the same ones.
+    Checksum { checksum: u64, expected: u64 },
+    /// The node holds `rows` rows, more than the `bound` rows that its source has.
+    TooManyRows { rows: usize, bound: usize },
+    /// The node holds no rows, although its source has `expected` rows, and every operator in
+    /// between keeps some rows of any input.
+    Empty { expected: usize },
+}
+
+impl fmt::Display for VerificationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "node {} ({}), replayed from node {}, ",
+            self.node.index(),
+            self.name,
+            self.source.index()
+        )?;
+        match self.kind {
+            VerificationErrorKind::RowCount { rows, expected } => {
+                write!(f, "has {} rows, but should have {}", rows, expected)
+            }
+            VerificationErrorKind::Checksum { checksum, expected } => write!(
+                f,
+                "has rows with checksum {:#x}, but should have {:#x}",
+                checksum, expected
+            ),
+            VerificationErrorKind::TooManyRows { rows, bound } => {
+                write!(f, "has {} rows, but should have at most {}", rows, bound)
+            }
+            VerificationErrorKind::Empty { expected } => write!(
+                f,
+                "is empty, but should have some of the {} rows replayed",
+                expected
+            ),
+        }
+    }
+}
+
+/// What is known about how many rows `n` holds compared to the node it reads from.
+fn row_bound(n: &Node) -> RowBound {
+    if n.is_internal() {
+        n.row_bound()
+    } else {
+        // ingress, egress, sharder, shard merger, and reader nodes pass their input on as it is
+        RowBound::Same
+    }
+}
+
+/// Compare what a replay's target holds to what its source holds, given what is known about the
+/// operators in between.
+fn compare(
+    bound: RowBound,
+    (rows, checksum): (usize, u64),
+    (expected, expected_checksum): (usize, u64),
+) -> Option<VerificationErrorKind> {
+    match bound {
+        RowBound::Same | RowBound::AsMany if rows != expected => {
+            Some(VerificationErrorKind::RowCount { rows, expected })
+        }
+        RowBound::Same if checksum != expected_checksum => Some(VerificationErrorKind::Checksum {
+            checksum,
+            expected: expected_checksum,
+        }),
+        RowBound::AtMostSome | RowBound::AtMost if rows > expected => {
+            Some(VerificationErrorKind::TooManyRows {
+                rows,
+                bound: expected,
+            })
+        }
+        RowBound::AtMostSome if rows == 0 && expected != 0 => {
+            Some(VerificationErrorKind::Empty { expected })
+        }
+        _ => None,
+    }
+}
+
+/// The number of rows materialized at the given node across all its shards, along with their
+/// checksum, or `None` if it isn't materialized.
+fn digest(
+    graph: &Graph,
+    ni: NodeIndex,
+    domains: &mut HashMap<DomainIndex, DomainHandle>,
+    workers: &HashMap<WorkerIdentifier, WorkerStatus>,
+) -> Result<Option<(usize, u64)>, String> {
+    let n = &graph[ni];
+    let domain = domains.get_mut(&n.domain()).unwrap();
+    domain
+        .send_to_healthy(
+            box payload::Packet::GetDigest {
+                node: n.local_addr(),
+            },
+            workers,
+        )
+        .map_err(|e| format!("failed to request digest of {}: {:?}", ni.index(), e))?;
+    let shards = domain
+        .wait_for_digests()
+        .map_err(|e| format!("failed to get digest of {}: {:?}", ni.index(), e))?;
+
+    // each shard has a disjoint subset of the rows, so their counts and checksums add up
+    Ok(shards.into_iter().fold(Some((0, 0u64)), |sum, d| {
+        let (rows, checksum) = sum?;
+        let (r, c) = d?;
+        Some((rows + r, checksum.wrapping_add(c)))
+    }))
+}
+
+/// Check the state that the given replays filled against the state they replayed from. Paths
+/// through operators that say nothing about how many rows they hold, such as joins and unions, are
+/// not checked.
+pub(super) fn verify(
+    log: &slog::Logger,
+    graph: &Graph,
+    replayed: &[Replayed],
+    domains: &mut HashMap<DomainIndex, DomainHandle>,
+    workers: &HashMap<WorkerIdentifier, WorkerStatus>,
+) -> Result<Vec<VerificationError>, String> {
+    let mut errors = Vec::new();
+    for r in replayed {
+        for path in &r.paths {
+            let source = path[0];
+            let bound = path[1..]
+                .iter()
+                .map(|&ni| row_bound(&graph[ni]))
+                .fold(RowBound::Same, RowBound::then);
+            if bound == RowBound::Unknown {
+                debug!(log, "not verifying replay path through unknown operators";
+                       "target" => r.target.index(), "source" => source.index());
+                continue;
+            }
+
+            for attempt in 1..=ATTEMPTS {
+                // records only flow from the source towards the target, so measuring the target
+                // first can't make it seem to have rows that the source doesn't
+                let target = digest(graph, r.target, domains, workers)?;
+                let from = digest(graph, source, domains, workers)?;
+                let kind = match (target, from) {
+                    (Some(target), Some(from)) => compare(bound, target, from),
+                    _ => None,
+                };
+                match kind {
+                    None => break,
+                    Some(_) if attempt < ATTEMPTS => {
+                        thread::sleep(Duration::from_millis(RETRY_MS));
+                    }
+                    Some(kind) => errors.push(VerificationError {
+                        node: r.target,
+                        name: graph[r.target].name().to_owned(),
+                        source,
+                        kind,
+                    }),
+                }
+            }
+        }
+    }
+    Ok(errors)
+}
+
+/// Log the given errors, and turn them into a single error if there are any.
+pub(super) fn check(log: &slog::Logger, errors: &[VerificationError]) -> Result<(), String> {
+    if errors.is_empty() {
+        return Ok(());
+    }
+    for e in errors {
+        error!(log, "replayed state is inconsistent"; "error" => %e);
+    }
+    let errors: Vec<_> = errors.iter().map(|e| e.to_string()).collect();
+    Err(format!(
+        "replayed state is inconsistent: {}",
+        errors.join("; ")
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounds_compose_to_what_is_least_known() {
+        assert_eq!(RowBound::Same.then(RowBound::AsMany), RowBound::AsMany);
+        assert_eq!(
+            RowBound::AtMost.then(RowBound::AtMostSome),
+            RowBound::AtMost
+        );
+        assert_eq!(RowBound::Unknown.then(RowBound::Same), RowBound::Unknown);
+    }
+
+    #[test]
+    fn compares_by_bound() {
+        assert_eq!(compare(RowBound::Same, (3, 7), (3, 7)), None);
+        assert_eq!(
+            compare(RowBound::Same, (3, 7), (3, 8)),
+            Some(VerificationErrorKind::Checksum {
+                checksum: 7,
+                expected: 8
+            })
+        );
+        assert_eq!(compare(RowBound::AsMany, (3, 7), (3, 8)), None);
+        assert_eq!(
+            compare(RowBound::AsMany, (2, 7), (3, 8)),
+            Some(VerificationErrorKind::RowCount {
+                rows: 2,
+                expected: 3
+            })
+        );
+        assert_eq!(compare(RowBound::AtMost, (0, 0), (3, 8)), None);
+        assert_eq!(
+            compare(RowBound::AtMost, (4, 0), (3, 8)),
+            Some(VerificationErrorKind::TooManyRows { rows: 4, bound: 3 })
+        );
+        assert_eq!(
+            compare(RowBound::AtMostSome, (0, 0), (3, 8)),
+            Some(VerificationErrorKind::Empty { expected: 3 })
+        );
+        assert_eq!(compare(RowBound::AtMostSome, (0, 0), (0, 0)), None);
+        assert_eq!(compare(RowBound::Unknown, (9, 0), (3, 8)), None);
+    }
+}
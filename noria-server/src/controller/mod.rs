@@ -1,15 +1,22 @@
 /// Only allow processing this many inputs in a domain before we handle timer events, acks, etc.
 const FORCE_INPUT_YIELD_EVERY: usize = 64;
 
+/// How often a domain whose sends an injected fault holds back tries to send them again.
+const STALLED_SEND_RETRY_MS: u64 = 10;
+
 use async_bincode::{AsyncBincodeReader, AsyncBincodeWriter, SyncDestination};
 use bincode;
 use bufstream::BufStream;
 use crate::controller::domain_handle::DomainHandle;
+use crate::controller::faults::{Arrival, Departure};
 use crate::controller::inner::{ControllerInner, WorkerStatus};
+use crate::controller::migrate::graph_log::GraphLog;
 use crate::controller::recipe::Recipe;
+use crate::controller::scheduler::Scheduler;
 use crate::controller::sql::reuse::ReuseConfigType;
 use crate::coordination::{CoordinationMessage, CoordinationPayload, DomainDescriptor};
 use dataflow::{
+    backpressure::Gauges, dead_letters::DeadLetters, metrics::Metrics,
     payload::SourceChannelIdentifier, prelude::Executor, Domain, DomainBuilder, DomainConfig,
     Packet, PersistenceParameters, Readers,
 };
@@ -23,21 +30,28 @@ use hyper::{self, Method, StatusCode};
 use noria::channel::{
     self,
     poll::{PollEvent, ProcessResult},
-    DualTcpStream, TcpSender, CONNECTION_FROM_BASE,
+    DomainConnectionBuilder, DualTcpStream, TcpSender, WriteAck, CONNECTION_FROM_BASE,
 };
 use noria::consensus::{Authority, Epoch, STATE_KEY};
-use noria::internal::{DomainIndex, LocalOrNot};
+use noria::debug::dead_letters::Destination;
+use noria::debug::plan::DomainStrategy;
+use noria::debug::stats::DomainFailure;
+use noria::internal::{DomainIndex, LocalNodeIndex, LocalOrNot};
 use noria::{ControllerDescriptor, Input};
 use rand;
 use serde_json;
 use slog;
+use std::any::Any;
+use std::cell::{Cell, RefCell};
 use std::collections::{HashMap, VecDeque};
 use std::fs;
 use std::io::{self, ErrorKind};
 use std::net::{IpAddr, SocketAddr};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
 use std::sync::{
     atomic::{AtomicUsize, Ordering},
-    Arc, Mutex,
+    Arc, Mutex, Once, ONCE_INIT,
 };
 use std::thread::{self, JoinHandle};
 use std::time::{self, Duration};
@@ -61,14 +75,22 @@ pub(crate) mod security;
 pub(crate) mod sql;
 
 mod builder;
+mod consistency;
+mod diagnosis;
+mod faults;
 mod handle;
 mod inner;
 mod mir_to_flow;
+mod quiesce;
 mod readers;
+mod scheduler;
+mod takeover;
 
 pub use crate::controller::builder::ControllerBuilder;
+pub use crate::controller::faults::{FaultId, FaultInjector};
 pub use crate::controller::handle::LocalControllerHandle;
 pub use crate::controller::migrate::Migration;
+pub use crate::controller::takeover::ControlState;
 pub use noria::builders::*;
 pub use noria::prelude::*;
 
@@ -99,6 +121,16 @@ pub(crate) struct ControllerConfig {
     pub quorum: usize,
     pub reuse: ReuseConfigType,
     pub threads: Option<usize>,
+    /// Where to write the log of the graph after every migration.
+    #[serde(default)]
+    pub graph_log: Option<PathBuf>,
+    /// How migrations assign new nodes to domains.
+    #[serde(default)]
+    pub domain_strategy: DomainStrategy,
+    /// If set, workers run all their domains in a single task, and hand the domains their packets
+    /// one at a time in an order picked by this seed.
+    #[serde(default)]
+    pub deterministic: Option<u64>,
 }
 impl Default for ControllerConfig {
     fn default() -> Self {
@@ -111,6 +143,12 @@ impl Default for ControllerConfig {
             domain_config: DomainConfig {
                 concurrent_replays: 512,
                 replay_batch_timeout: time::Duration::new(0, 10_000),
+                expiry_sweep_interval: time::Duration::from_secs(1),
+                expiry_batch_size: 1024,
+                replay_workers: 1,
+                backpressure: None,
+                channels: Default::default(),
+                malformed: Default::default(),
             },
             persistence: Default::default(),
             heartbeat_every: Duration::from_secs(1),
@@ -121,10 +159,26 @@ impl Default for ControllerConfig {
             threads: Some(2),
             #[cfg(not(any(debug_assertions, test)))]
             threads: None,
+            graph_log: None,
+            domain_strategy: DomainStrategy::default(),
+            #[cfg(test)]
+            deterministic: deterministic_seed_from_env(),
+            #[cfg(not(test))]
+            deterministic: None,
         }
     }
 }
 
+/// The seed that tests schedule domains with, if `NORIA_DETERMINISTIC_SEED` is set, so that the
+/// whole suite can be run under the deterministic scheduler.
+#[cfg(test)]
+fn deterministic_seed_from_env() -> Option<u64> {
+    std::env::var("NORIA_DETERMINISTIC_SEED").ok().map(|seed| {
+        seed.parse()
+            .expect("NORIA_DETERMINISTIC_SEED must be a number")
+    })
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub(crate) struct ControllerState {
     pub config: ControllerConfig,
@@ -132,6 +186,17 @@ pub(crate) struct ControllerState {
 
     pub recipe_version: usize,
     pub recipes: Vec<String>,
+
+    #[serde(default)]
+    pub checkpoint: Option<Checkpoint>,
+}
+
+/// The last checkpoint of materialized state that every domain completed.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub(crate) struct Checkpoint {
+    pub id: u64,
+    /// The checkpoint can only be restored into a graph built from the same recipe.
+    pub recipe_version: usize,
 }
 
 enum Event {
@@ -151,7 +216,7 @@ enum Event {
     #[cfg(test)]
     ManualMigration {
         f: Box<FnBox(&mut Migration) + Send + 'static>,
-        done: futures::sync::oneshot::Sender<()>,
+        done: futures::sync::oneshot::Sender<Result<(), String>>,
     },
 }
 
@@ -180,6 +245,8 @@ fn start_instance<A: Authority + 'static>(
     config: ControllerConfig,
     memory_limit: Option<usize>,
     memory_check_frequency: Option<Duration>,
+    rebuild: Option<GraphLog>,
+    faults: FaultInjector,
     log: slog::Logger,
 ) -> Result<LocalControllerHandle<A>, failure::Error> {
     let mut rt = tokio::runtime::Builder::new();
@@ -200,6 +267,9 @@ fn start_instance<A: Authority + 'static>(
     let (trigger, valve) = Valve::new();
     let (tx, rx) = futures::sync::mpsc::unbounded();
 
+    // the counters of the domains that this instance's worker runs
+    let metrics = Metrics::new();
+
     // we'll be listening for a couple of different types of events:
     // first, events from workers
     let wport = tokio::net::TcpListener::bind(&SocketAddr::new(listen_addr, 0))?;
@@ -260,6 +330,9 @@ fn start_instance<A: Authority + 'static>(
                         CoordinationPayload::RemoveDomain => fw(e, false),
                         CoordinationPayload::AssignDomain(..) => fw(e, false),
                         CoordinationPayload::DomainBooted(..) => fw(e, false),
+                        CoordinationPayload::DomainMoved(..) => fw(e, false),
+                        CoordinationPayload::DomainFailed(..) => fw(e, true),
+                        CoordinationPayload::TakeOver(..) => fw(e, false),
                         CoordinationPayload::Register { .. } => fw(e, true),
                         CoordinationPayload::Heartbeat => fw(e, true),
                     },
@@ -324,6 +397,38 @@ fn start_instance<A: Authority + 'static>(
                                     }
                                 }
                             }
+                            CoordinationPayload::DomainMoved(dd) => {
+                                if let InstanceState::Active { epoch, .. } = worker_state {
+                                    if epoch == msg.epoch {
+                                        let domain = dd.domain();
+                                        let shard = dd.shard();
+                                        let addr = dd.addr();
+                                        debug!(
+                                            log,
+                                            "found that domain {}.{} moved to {:?}",
+                                            domain.index(),
+                                            shard,
+                                            addr
+                                        );
+                                        coord.update_remote((domain, shard), addr);
+                                    }
+                                }
+                            }
+                            CoordinationPayload::TakeOver(addr) => {
+                                if let InstanceState::Active {
+                                    ref mut epoch,
+                                    ref takeover,
+                                    ..
+                                } = worker_state
+                                {
+                                    info!(log, "another controller is taking over";
+                                          "addr" => ?addr, "epoch" => ?msg.epoch);
+                                    *epoch = msg.epoch;
+                                    if takeover.unbounded_send((addr, msg.epoch)).is_err() {
+                                        error!(log, "failed to switch to new controller");
+                                    }
+                                }
+                            }
                             _ => unreachable!(),
                         },
                         Event::LeaderChange(state, descriptor) => {
@@ -362,6 +467,8 @@ fn start_instance<A: Authority + 'static>(
                                 &ioh,
                                 log.clone(),
                                 (memory_limit, memory_check_frequency),
+                                faults.clone(),
+                                metrics.clone(),
                                 &state,
                                 &descriptor,
                                 waddr,
@@ -370,17 +477,21 @@ fn start_instance<A: Authority + 'static>(
                                 rep_rx,
                             );
 
-                            if let Err(e) = ctrl {
-                                error!(log, "failed to connect to controller");
-                                eprintln!("{:?}", e);
-                            } else {
-                                // now we can start accepting dataflow messages
-                                worker_state = InstanceState::Active {
-                                    epoch: state.epoch,
-                                    add_domain: rep_tx,
-                                    trigger,
-                                };
-                                warn!(log, "Connected to new leader");
+                            match ctrl {
+                                Err(e) => {
+                                    error!(log, "failed to connect to controller");
+                                    eprintln!("{:?}", e);
+                                }
+                                Ok(takeover) => {
+                                    // now we can start accepting dataflow messages
+                                    worker_state = InstanceState::Active {
+                                        epoch: state.epoch,
+                                        add_domain: rep_tx,
+                                        trigger,
+                                        takeover,
+                                    };
+                                    warn!(log, "Connected to new leader");
+                                }
                             }
                         }
                         e => unreachable!("{:?} is not a worker event", e),
@@ -408,6 +519,7 @@ fn start_instance<A: Authority + 'static>(
         let authority2 = authority.clone();
 
         let mut campaign = campaign;
+        let mut rebuild = rebuild;
         rt.spawn(
             ctrl_rx
                 .map_err(|_| unreachable!())
@@ -434,6 +546,11 @@ fn start_instance<A: Authority + 'static>(
                                     block_on(|| ctrl.handle_heartbeat(&msg).unwrap());
                                 }
                             }
+                            CoordinationPayload::DomainFailed(failure) => {
+                                if let Some(ref mut ctrl) = controller {
+                                    block_on(|| ctrl.handle_domain_failure(failure));
+                                }
+                            }
                             _ => unreachable!(),
                         },
                         Event::ExternalRequest(method, path, query, body, reply_tx) => {
@@ -457,8 +574,8 @@ fn start_instance<A: Authority + 'static>(
                             if let Some(ref mut ctrl) = controller {
                                 if !ctrl.workers.is_empty() {
                                     block_on(|| {
-                                        ctrl.migrate(move |m| f.call_box((m,)));
-                                        done.send(()).unwrap();
+                                        let r = ctrl.try_migrate(move |m| f.call_box((m,)));
+                                        done.send(r).unwrap();
                                     });
                                 }
                             } else {
@@ -481,8 +598,10 @@ fn start_instance<A: Authority + 'static>(
                             block_on(move || c.join().unwrap());
                             controller = Some(ControllerInner::new(
                                 listen_addr,
+                                waddr,
                                 log.clone(),
                                 state.clone(),
+                                rebuild.take(),
                             ));
                         }
                         Event::CampaignError(e) => {
@@ -507,7 +626,7 @@ fn start_instance<A: Authority + 'static>(
     }
 
     Ok(LocalControllerHandle::new(
-        authority, tx, trigger, rt, iopool,
+        authority, tx, trigger, rt, iopool, metrics,
     ))
 }
 
@@ -524,9 +643,18 @@ enum InstanceState {
         epoch: Epoch,
         trigger: Trigger,
         add_domain: UnboundedSender<DomainBuilder>,
+        /// Tells the worker which controller to talk to, once another one has taken over.
+        takeover: UnboundedSender<(SocketAddr, Epoch)>,
     },
 }
 
+/// What the worker's connection to its controller is handed.
+enum ToController {
+    Message(CoordinationPayload),
+    /// Another controller has taken over, at the given address and in the given epoch.
+    TakeOver(SocketAddr, Epoch),
+}
+
 impl InstanceState {
     fn take(&mut self) -> Self {
         ::std::mem::replace(self, InstanceState::Pining)
@@ -579,13 +707,15 @@ fn listen_df(
     ioh: &tokio_io_pool::Handle,
     log: slog::Logger,
     (memory_limit, evict_every): (Option<usize>, Option<Duration>),
+    faults: FaultInjector,
+    metrics: Metrics,
     state: &ControllerState,
     desc: &ControllerDescriptor,
     waddr: SocketAddr,
     coord: Arc<ChannelCoordinator>,
     on: IpAddr,
     replicas: futures::sync::mpsc::UnboundedReceiver<DomainBuilder>,
-) -> Result<(), failure::Error> {
+) -> Result<UnboundedSender<(SocketAddr, Epoch)>, failure::Error> {
     // first, try to connect to controller
     let ctrl = ::std::net::TcpStream::connect(&desc.internal_addr)?;
     let ctrl = tokio::net::TcpStream::from_std(ctrl, &Default::default())?;
@@ -606,27 +736,42 @@ fn listen_df(
     let heartbeat_every = state.config.heartbeat_every;
 
     let (ctrl_tx, ctrl_rx) = futures::sync::mpsc::unbounded();
+    let (takeover_tx, takeover_rx) = futures::sync::mpsc::unbounded();
 
     // reader setup
     let readers = Arc::new(Mutex::new(HashMap::new()));
     let rport = tokio::net::TcpListener::bind(&SocketAddr::new(on, 0))?;
     let raddr = rport.local_addr()?;
 
-    // start controller message handler
+    // start controller message handler. once another controller takes over, messages go to it
+    // instead, but keep the source they had, since that is what controllers know the worker by.
     let ctrl = AsyncBincodeWriter::from(ctrl).for_async();
     tokio::spawn(
         ctrl_rx
-            .map(move |cm| CoordinationMessage {
-                source: ctrl_addr,
-                payload: cm,
-                epoch,
+            .map(ToController::Message)
+            .select(takeover_rx.map(|(addr, epoch)| ToController::TakeOver(addr, epoch)))
+            .fold((ctrl, epoch), move |(ctrl, epoch), m| match m {
+                ToController::Message(cm) => Either::A(
+                    ctrl.send(CoordinationMessage {
+                        source: ctrl_addr,
+                        payload: cm,
+                        epoch,
+                    })
+                    .map(move |ctrl| (ctrl, epoch))
+                    .map_err(|e| {
+                        // if the controller goes away, another will be elected, and the worker
+                        // will be restarted, so there's no reason to do anything too drastic here.
+                        eprintln!("controller went away: {:?}", e);
+                    }),
+                ),
+                ToController::TakeOver(addr, epoch) => Either::B(
+                    tokio::net::TcpStream::connect(&addr)
+                        .map(move |ctrl| (AsyncBincodeWriter::from(ctrl).for_async(), epoch))
+                        .map_err(move |e| {
+                            eprintln!("could not connect to controller at {:?}: {:?}", addr, e);
+                        }),
+                ),
             })
-            .map_err(|e| panic!("{:?}", e))
-            .forward(ctrl.sink_map_err(|e| {
-                // if the controller goes away, another will be elected, and the worker will be
-                // restarted, so there's no reason to do anything too drastic here.
-                eprintln!("controller went away: {:?}", e);
-            }))
             .map(|_| ()),
     );
 
@@ -660,6 +805,11 @@ fn listen_df(
             }),
     );
 
+    // whether each of the domains that this worker runs is overloaded
+    let gauges = Gauges::new();
+    // the packets that the domains of this worker could not deliver
+    let dead_letters = DeadLetters::new();
+
     let state_sizes = Arc::new(Mutex::new(HashMap::new()));
     if let Some(evict_every) = evict_every {
         let log = log.clone();
@@ -679,12 +829,20 @@ fn listen_df(
         );
     }
 
+    // under the deterministic scheduler, domains are handed to it rather than spawned
+    let scheduler = state.config.deterministic.map(|seed| {
+        let (tx, rx) = futures::sync::mpsc::unbounded();
+        tokio::spawn(Scheduler::new(seed, rx, log.clone()));
+        tx
+    });
+
     tokio::spawn(
         replicas
             .map_err(|e| -> io::Error { panic!("{:?}", e) })
             .fold(ctrl_tx, move |ctrl_tx, d| {
                 let idx = d.index;
                 let shard = d.shard.unwrap_or(0);
+                let moving = d.snapshot.is_some();
                 let addr: io::Result<_> = try {
                     let on = tokio::net::TcpListener::bind(&SocketAddr::new(on, 0))?;
                     let addr = on.local_addr()?;
@@ -696,32 +854,54 @@ fn listen_df(
                         coord.clone(),
                         &valve,
                         state_size.clone(),
+                        metrics.clone(),
+                        gauges.clone(),
+                        dead_letters.clone(),
                     );
 
                     let (tx, rx) = futures::sync::mpsc::unbounded();
 
-                    // need to register the domain with the local channel coordinator.
-                    // local first to ensure that we don't unnecessarily give away remote for a
-                    // local thing if there's a race
-                    coord.insert_local((idx, shard), tx);
-                    coord.insert_remote((idx, shard), addr);
+                    if moving {
+                        // a copy of a domain that is moving here is only sent to once it has
+                        // taken over, which the controller tells every worker about
+                        coord.insert_moving_local((idx, shard), addr, tx);
+                    } else {
+                        // need to register the domain with the local channel coordinator.
+                        // local first to ensure that we don't unnecessarily give away remote for
+                        // a local thing if there's a race
+                        coord.insert_local((idx, shard), tx);
+                        coord.insert_remote((idx, shard), addr);
+                    }
 
                     block_on(|| state_sizes.lock().unwrap().insert((idx, shard), state_size));
 
-                    tokio::spawn(Replica::new(&valve, d, on, rx, log.clone(), coord.clone()));
-
-                    trace!(
-                        log,
-                        "informed controller that domain {}.{} is at {:?}",
-                        idx.index(),
-                        shard,
-                        addr
+                    let replica = Replica::new(
+                        &valve,
+                        d,
+                        on,
+                        rx,
+                        log.clone(),
+                        coord.clone(),
+                        ctrl_tx.clone(),
+                        faults.clone(),
                     );
+                    match scheduler {
+                        Some(ref scheduler) => scheduler
+                            .unbounded_send(replica)
+                            .map_err(|_| io::Error::new(ErrorKind::Other, "scheduler went away"))?,
+                        None => {
+                            tokio::spawn(replica);
+                        }
+                    }
+
+                    info!(log, "booted domain";
+                          "domain" => idx.index(), "shard" => shard, "addr" => ?addr);
 
                     addr
                 };
 
                 match addr {
+                    Ok(_) if moving => Either::B(future::ok(ctrl_tx)),
                     Ok(addr) => Either::A(
                         ctrl_tx
                             .clone()
@@ -740,7 +920,7 @@ fn listen_df(
             .map(|_| ()),
     );
 
-    Ok(())
+    Ok(takeover_tx)
 }
 
 fn listen_internal(
@@ -948,6 +1128,7 @@ fn instance_campaign<A: Authority + 'static>(
                         epoch,
                         recipe_version: 0,
                         recipes: vec![],
+                        checkpoint: None,
                     }),
                     Some(ref state) if state.epoch > epoch => Err(()),
                     Some(mut state) => {
@@ -1048,14 +1229,66 @@ fn do_eviction(
     Result::Ok::<_, ()>(()).into_future()
 }
 
+thread_local! {
+    /// Whether this thread is polling a domain.
+    static IN_DOMAIN: Cell<bool> = Cell::new(false);
+    /// Where the last panic in a domain on this thread happened, as seen by the panic hook before
+    /// the stack was unwound.
+    static DOMAIN_PANIC: RefCell<Option<(String, backtrace::Backtrace)>> = RefCell::new(None);
+}
+
+/// Make panics in domains note where they happened, so that `Replica` can report that along with
+/// the panic. Panics elsewhere are left to whatever hook was there before.
+fn install_domain_panic_hook() {
+    static INSTALL: Once = ONCE_INIT;
+    INSTALL.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if IN_DOMAIN.with(|d| d.get()) {
+                let location = match info.location() {
+                    Some(l) => format!("{}:{}", l.file(), l.line()),
+                    None => "[unknown]".to_owned(),
+                };
+                let backtrace = backtrace::Backtrace::new();
+                DOMAIN_PANIC.with(|p| *p.borrow_mut() = Some((location, backtrace)));
+            }
+            previous(info);
+        }));
+    });
+}
+
+/// The message that a panic was started with.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    match payload.downcast_ref::<&'static str>() {
+        Some(s) => *s,
+        None => match payload.downcast_ref::<String>() {
+            Some(s) => &**s,
+            None => "Box<Any>",
+        },
+    }
+}
+
 struct Replica {
     domain: Domain,
     log: slog::Logger,
 
     coord: Arc<ChannelCoordinator>,
+    /// Where to report that the domain has failed.
+    ctrl_tx: UnboundedSender<CoordinationPayload>,
+    /// Faults that tests want injected into the domain's packets.
+    faults: FaultInjector,
+    /// Packets held back by an injected delay, with when they may be processed.
+    delayed: Vec<(time::Instant, Box<Packet>)>,
+    /// Wakes us up when the earliest of the `delayed` packets may be processed.
+    release: Option<tokio::timer::Delay>,
+    /// Wakes us up to try the sends that an injected fault held back again.
+    retry: Option<tokio::timer::Delay>,
 
     incoming: Valved<tokio::net::tcp::Incoming>,
-    locals: futures::sync::mpsc::UnboundedReceiver<Box<Packet>>,
+    locals: channel::LocalReceiver<Box<Packet>>,
+    /// Packets taken off `locals` that the domain has yet to process. The channel can't say how
+    /// many packets are in it, so they are moved here to be counted (see `report_pressure`).
+    inbox: VecDeque<Box<Packet>>,
     inputs: StreamUnordered<
         DualTcpStream<
             BufStream<tokio::net::TcpStream>,
@@ -1064,40 +1297,98 @@ struct Replica {
             SyncDestination,
         >,
     >,
-    outputs: FnvHashMap<
-        ReplicaIndex,
-        (
-            Box<dyn Sink<SinkItem = Box<Packet>, SinkError = bincode::Error> + Send>,
-            bool,
-        ),
-    >,
+    /// Whether each of the `inputs` is from a client writing to a base, rather than from another
+    /// domain.
+    from_base: FnvHashMap<usize, bool>,
+    outputs: FnvHashMap<ReplicaIndex, Output>,
 
     outbox: FnvHashMap<ReplicaIndex, VecDeque<Box<Packet>>>,
+    /// The domain shards that have moved to the given address, but that are still sent to where
+    /// they were until the last packet for there has gone out.
+    redirecting: Vec<(ReplicaIndex, SocketAddr)>,
+    /// The domain shards that we had packets for that they would not take yet, as of the last
+    /// flush.
+    blocked_on: Vec<ReplicaIndex>,
+    /// When we last went to sleep, if we are asleep.
+    parked: Option<time::Instant>,
     timeout: Option<tokio::timer::Delay>,
     sendback: Sendback,
 }
 
+/// Where a replica sends the packets for one domain shard.
+enum Output {
+    /// A domain shard in this process, which is sent all the packets that are ready for it at once,
+    /// so that a burst of packets costs it a single wakeup.
+    Local(channel::LocalSender<Box<Packet>>),
+    /// A connection that packets are queued up on, and whether any are yet to be flushed.
+    Remote(
+        Box<dyn Sink<SinkItem = Box<Packet>, SinkError = bincode::Error> + Send>,
+        bool,
+    ),
+}
+
+impl Output {
+    /// Whether packets have been queued up on the connection, but not flushed yet.
+    fn pending(&self) -> bool {
+        match *self {
+            Output::Local(_) => false,
+            Output::Remote(_, pending) => pending,
+        }
+    }
+}
+
+/// The ingress that the given packet is an update for, if an egress numbered it for that ingress,
+/// and so holds on to it for re-sending if the edge that it was sent over has standbys.
+fn numbered_for(m: &Packet) -> Option<LocalNodeIndex> {
+    match *m {
+        Packet::Message {
+            link, seq: Some(_), ..
+        } => Some(link.dst),
+        _ => None,
+    }
+}
+
+/// Whether an update numbered for the given ingress is re-sent to a standby, given the ingresses
+/// whose edges have been switched over to their standbys.
+fn resent(numbered: Option<LocalNodeIndex>, switched: &[LocalNodeIndex]) -> bool {
+    numbered.map(|i| switched.contains(&i)).unwrap_or(false)
+}
+
 impl Replica {
     pub fn new(
         valve: &Valve,
         mut domain: Domain,
         on: tokio::net::TcpListener,
-        locals: futures::sync::mpsc::UnboundedReceiver<Box<Packet>>,
+        locals: channel::LocalReceiver<Box<Packet>>,
         log: slog::Logger,
         cc: Arc<ChannelCoordinator>,
+        ctrl_tx: UnboundedSender<CoordinationPayload>,
+        faults: FaultInjector,
     ) -> Self {
+        install_domain_panic_hook();
+
         let id = domain.id();
         let id = format!("{}.{}", id.0.index(), id.1);
         domain.booted(on.local_addr().unwrap());
         Replica {
             coord: cc,
+            ctrl_tx,
+            faults,
+            delayed: Vec::new(),
+            release: None,
+            retry: None,
             domain,
             incoming: valve.wrap(on.incoming()),
             locals,
+            inbox: VecDeque::new(),
             log: log.new(o!{"id" => id}),
             inputs: Default::default(),
+            from_base: Default::default(),
             outputs: Default::default(),
             outbox: Default::default(),
+            redirecting: Vec::new(),
+            blocked_on: Vec::new(),
+            parked: None,
             sendback: Default::default(),
             timeout: None,
         }
@@ -1106,41 +1397,59 @@ impl Replica {
     fn try_ack(&mut self) -> Result<(), failure::Error> {
         let inputs = &mut self.inputs;
         let pending = &mut self.sendback.pending;
+        let arrived = &mut self.sendback.arrived;
+        let metrics = self.domain.metrics();
+        let dead_letters = self.domain.dead_letters();
+        let lost = |e: &dyn fmt::Display| dead_letters.post(Destination::Client, "Ack", 0, e);
 
         // first, queue up any additional writes we have to do
-        let mut err = Vec::new();
-        self.sendback.back.retain(|&streami, n| {
-            use std::ops::SubAssign;
-
-            let stream = &mut inputs[streami];
+        self.sendback.back.retain(|&streami, acks| {
+            let stream = match inputs.get_mut(streami) {
+                Some(stream) => stream,
+                None => {
+                    // the client disconnected before its writes were processed
+                    for _ in acks.drain(..) {
+                        lost(&"the client has disconnected");
+                    }
+                    arrived.remove(&streami);
+                    return false;
+                }
+            };
 
-            let end = *n;
-            for i in 0..end {
-                match stream.start_send(true) {
+            let mut first = true;
+            while let Some(ack) = acks.pop_front() {
+                match stream.start_send(ack) {
                     Ok(AsyncSink::Ready) => {
-                        if i == 0 {
+                        if first {
                             pending.insert(streami);
+                            first = false;
+                        }
+                        // acks go out in the order the writes came in
+                        if let Some(at) = arrived.get_mut(&streami).and_then(VecDeque::pop_front) {
+                            metrics.write_acked(at.elapsed());
                         }
-                        n.sub_assign(1);
                     }
-                    Ok(AsyncSink::NotReady(_)) => {
+                    Ok(AsyncSink::NotReady(ack)) => {
+                        acks.push_front(ack);
                         break;
                     }
                     Err(e) => {
-                        // start_send shouldn't generally error
-                        err.push(e.into());
+                        // the client can't be reached, so none of the other acks it is owed can
+                        // be delivered either
+                        lost(&e);
+                        for _ in acks.drain(..) {
+                            lost(&e);
+                        }
+                        arrived.remove(&streami);
                     }
                 }
             }
 
-            *n > 0
+            !acks.is_empty()
         });
 
-        if !err.is_empty() {
-            return Err(err.swap_remove(0));
-        }
-
         // then, try to send on any streams we may be able to
+        let mut err = Vec::new();
         pending.retain(|&streami| {
             let stream = &mut inputs[streami];
             match stream.poll_complete() {
@@ -1177,41 +1486,161 @@ impl Replica {
     }
 
     fn try_flush(&mut self) -> Result<(), failure::Error> {
+        let start = time::Instant::now();
+        for (ri, from, to) in self.domain.take_redirects() {
+            if !self.outputs.contains_key(&ri) {
+                // nothing has been sent to where the domain was yet, but the packet that says
+                // that nothing more will be must go there, even if this worker already knows that
+                // the domain has moved
+                let tx = DomainConnectionBuilder::for_domain(from)
+                    .with_capacity(self.domain.channels().domain)
+                    .build_async()?;
+                self.outputs.insert(ri, Output::Remote(Box::new(tx), false));
+            }
+            self.redirecting.push((ri, to));
+        }
+
         let cc = &self.coord;
+        let faults = &self.faults;
+        let log = &self.log;
         let outputs = &mut self.outputs;
+        let domain = &mut self.domain;
+        let dead_letters = domain.dead_letters().clone();
 
         // just like in try_ack:
         // first, queue up any additional writes we have to do
         let mut err = Vec::new();
+        // the domains whose receiving end has gone away
+        let mut gone = Vec::new();
+        // the updates to re-send to the standbys of edges that sent to those domains
+        let mut resend = FnvHashMap::default();
+        // whether an injected fault held back any sends
+        let mut stalled = false;
         for (&ri, ms) in &mut self.outbox {
             if ms.is_empty() {
                 continue;
             }
 
-            let &mut (ref mut tx, ref mut pending) = outputs.entry(ri).or_insert_with(|| {
+            let sending = time::Instant::now();
+            let to = Destination::Domain(ri.0, ri.1);
+            if !outputs.contains_key(&ri) {
                 while !cc.has(&ri) {}
-                let tx = cc.builder_for(&ri).unwrap().build_async().unwrap();
-                (tx, true)
-            });
+                let builder = cc.builder_for(&ri).unwrap();
+                let output = match builder.local() {
+                    Some(chan) => Ok(Output::Local(chan)),
+                    None => builder.build_async().map(|tx| Output::Remote(tx, true)),
+                };
+                match output {
+                    Ok(output) => {
+                        outputs.insert(ri, output);
+                    }
+                    Err(e) => {
+                        let switched = domain.fail_over(ri, &mut resend);
+                        for m in ms.drain(..) {
+                            if !resent(numbered_for(&m), &switched) {
+                                dead_letters.post(to, m.kind(), m.records(), &e);
+                            }
+                        }
+                        continue;
+                    }
+                }
+            }
 
-            while let Some(m) = ms.pop_front() {
-                match tx.start_send(m) {
-                    Ok(AsyncSink::Ready) => {
-                        // we queued something, so we'll need to send!
-                        *pending = true;
+            // whether an injected fault holds back the next send, in which case it is noted
+            let held_back = |err: &mut Vec<bincode::Error>, stalled: &mut bool| {
+                match faults.on_send(ri.0) {
+                    Some(Departure::Fail(e)) => {
+                        error!(log, "failed to send to domain: {}", e;
+                               "domain" => ri.0.index(), "shard" => ri.1);
+                        err.push(Box::new(bincode::ErrorKind::Io(e)));
+                        true
                     }
-                    Ok(AsyncSink::NotReady(m)) => {
-                        // put back the m we tried to send
-                        ms.push_front(m);
-                        // there's also no use in trying to enqueue more packets
-                        break;
+                    Some(Departure::Stall) => {
+                        // just as if the sink were not ready
+                        *stalled = true;
+                        true
                     }
-                    Err(e) => {
-                        err.push(e);
-                        break;
+                    None => false,
+                }
+            };
+
+            match *outputs.get_mut(&ri).unwrap() {
+                Output::Local(ref chan) => {
+                    // a domain in this process gets the packets themselves, records and all, and
+                    // gets all of those that are ready in a single send
+                    let mut batch = Vec::with_capacity(ms.len());
+                    while let Some(m) = ms.pop_front() {
+                        if held_back(&mut err, &mut stalled) {
+                            ms.push_front(m);
+                            break;
+                        }
+                        batch.push(m);
+                    }
+                    if batch.is_empty() {
+                        continue;
+                    }
+                    if let Err(e) = chan.unbounded_send(batch) {
+                        // just like when a remote receiver is gone below
+                        let switched = domain.fail_over(ri, &mut resend);
+                        let why = io::Error::new(ErrorKind::BrokenPipe, "local peer went away");
+                        for m in e.into_inner().into_iter().chain(ms.drain(..)) {
+                            if !resent(numbered_for(&m), &switched) {
+                                dead_letters.post(to, m.kind(), m.records(), &why);
+                            }
+                        }
+                        gone.push(ri);
+                    }
+                }
+                Output::Remote(ref mut tx, ref mut pending) => {
+                    while let Some(m) = ms.pop_front() {
+                        if held_back(&mut err, &mut stalled) {
+                            ms.push_front(m);
+                            break;
+                        }
+
+                        let (kind, records, numbered) =
+                            (m.kind(), m.records(), numbered_for(&m));
+                        match tx.start_send(m) {
+                            Ok(AsyncSink::Ready) => {
+                                // we queued something, so we'll need to send!
+                                *pending = true;
+                            }
+                            Ok(AsyncSink::NotReady(m)) => {
+                                // put back the m we tried to send
+                                ms.push_front(m);
+                                // there's also no use in trying to enqueue more packets
+                                break;
+                            }
+                            Err(e) => {
+                                // the receiver is gone, so none of the other packets for it can
+                                // be delivered either, except for the updates sent over edges
+                                // that have standbys, which those are re-sent
+                                let switched = domain.fail_over(ri, &mut resend);
+                                if !resent(numbered, &switched) {
+                                    dead_letters.post(to, kind, records, &e);
+                                }
+                                for m in ms.drain(..) {
+                                    if !resent(numbered_for(&m), &switched) {
+                                        dead_letters.post(to, m.kind(), m.records(), &e);
+                                    }
+                                }
+                                gone.push(ri);
+                                break;
+                            }
+                        }
                     }
                 }
             }
+            domain.time_budget().sent(ri, sending.elapsed());
+        }
+
+        // the sink is of no use anymore, so the next packet for the domain connects anew
+        for ri in gone {
+            outputs.remove(&ri);
+        }
+        let failed_over = !resend.is_empty();
+        for (ri, ms) in resend {
+            self.outbox.entry(ri).or_default().extend(ms);
         }
 
         if !err.is_empty() {
@@ -1219,24 +1648,76 @@ impl Replica {
         }
 
         // then, try to do any sends that are still pending
-        for &mut (ref mut tx, ref mut pending) in outputs.values_mut() {
-            if !*pending {
-                continue;
-            }
+        for (&ri, output) in outputs.iter_mut() {
+            let (tx, pending) = match *output {
+                Output::Remote(ref mut tx, ref mut pending) if *pending => (tx, pending),
+                _ => continue,
+            };
 
+            let sending = time::Instant::now();
             match tx.poll_complete() {
                 Ok(Async::Ready(())) => {
                     *pending = false;
                 }
                 Ok(Async::NotReady) => {}
-                Err(e) => err.push(e),
+                Err(e) => {
+                    error!(log, "failed to flush sends to domain: {:?}", e;
+                           "domain" => ri.0.index(), "shard" => ri.1);
+                    err.push(e);
+                }
             }
+            domain.time_budget().sent(ri, sending.elapsed());
         }
 
         if !err.is_empty() {
             return Err(err.swap_remove(0).into());
         }
 
+        // once everything for where a domain was has gone out, the next packet for it connects to
+        // where it is now
+        let outbox = &self.outbox;
+        self.redirecting.retain(|&(ri, to)| {
+            let flushed = outbox.get(&ri).map(|ms| ms.is_empty()).unwrap_or(true)
+                && outputs.get(&ri).map(|output| !output.pending()).unwrap_or(true);
+            if flushed {
+                outputs.remove(&ri);
+                cc.update_remote(ri, to);
+            }
+            !flushed
+        });
+
+        // what is left over waits for the domain shards it is for to take more
+        let mut blocked_on: Vec<_> = self
+            .outbox
+            .iter()
+            .filter(|(_, ms)| !ms.is_empty())
+            .map(|(&ri, _)| ri)
+            .chain(
+                self.outputs
+                    .iter()
+                    .filter(|(_, output)| output.pending())
+                    .map(|(&ri, _)| ri),
+            )
+            .collect();
+        blocked_on.sort();
+        blocked_on.dedup();
+        self.blocked_on = blocked_on;
+
+        if stalled && self.retry.is_none() {
+            let retry = time::Duration::from_millis(STALLED_SEND_RETRY_MS);
+            let mut retry = tokio::timer::Delay::new(time::Instant::now() + retry);
+            if let Async::Ready(()) = retry.poll()? {
+                futures::task::current().notify();
+            } else {
+                self.retry = Some(retry);
+            }
+        }
+
+        self.domain.metrics().sending(start.elapsed());
+        if failed_over {
+            // the standbys that took over are yet to be sent what they may have missed
+            return self.try_flush();
+        }
         Ok(())
     }
 
@@ -1264,10 +1745,13 @@ impl Replica {
                     set_nonblocking(&stream, true);
 
                     debug!(self.log, "accepted new connection"; "base" => ?is_base);
+                    let channels = self.domain.channels();
                     let slot = self.inputs.stream_slot();
                     let token = slot.token();
                     let tcp = if is_base {
-                        DualTcpStream::upgrade(BufStream::new(stream), move |input| {
+                        let stream =
+                            BufStream::with_capacities(channels.input, channels.input, stream);
+                        DualTcpStream::upgrade(stream, move |input| {
                             Box::new(Packet::Input {
                                 inner: input,
                                 src: Some(SourceChannelIdentifier { token }),
@@ -1275,9 +1759,12 @@ impl Replica {
                             })
                         })
                     } else {
-                        BufStream::with_capacities(2 * 1024 * 1024, 4 * 1024, stream).into()
+                        // nothing is ever written back to other domains
+                        BufStream::with_capacities(channels.domain, 4 * 1024, stream).into()
                     };
                     slot.insert(tcp);
+                    self.from_base.insert(token, is_base);
+                    self.report_connections();
                 }
                 None => {
                     return Ok(false);
@@ -1300,27 +1787,131 @@ impl Replica {
         }
         Ok(())
     }
+
+    /// Tell the domain how many connections from other domains and from clients are open.
+    fn report_connections(&mut self) {
+        let inputs = self.from_base.values().filter(|&&is_base| is_base).count();
+        let domains = self.from_base.len() - inputs;
+        self.domain.update_connections(domains, inputs);
+    }
+
+    /// Forget about a connection that has closed, posting the acks that a client on it is still
+    /// owed as dead letters.
+    fn input_closed(&mut self, streami: usize) {
+        self.from_base.remove(&streami);
+        self.report_connections();
+        self.domain.input_closed(streami);
+        if let Some(acks) = self.sendback.back.remove(&streami) {
+            for _ in acks {
+                self.domain.dead_letters().post(
+                    Destination::Client,
+                    "Ack",
+                    0,
+                    &"the client has disconnected",
+                );
+            }
+        }
+        self.sendback.pending.remove(&streami);
+        self.sendback.arrived.remove(&streami);
+    }
+
+    /// Apply the faults injected into packets arriving at the domain to a packet that has just
+    /// arrived. Returns the packet if the domain should process it now.
+    fn admit(&mut self, packet: Box<Packet>) -> Option<Box<Packet>> {
+        match self.faults.on_arrival(self.domain.id().0, &packet) {
+            None => Some(packet),
+            Some(Arrival::Drop) => {
+                debug!(self.log, "dropping packet by injected fault");
+                None
+            }
+            Some(Arrival::Delay(by)) => {
+                self.delayed.push((time::Instant::now() + by, packet));
+                if self.delayed.len() == 1 {
+                    // nothing is set to wake us up when the packet may be processed yet
+                    futures::task::current().notify();
+                }
+                None
+            }
+            Some(Arrival::Crash) => panic!("domain crashed by injected fault"),
+        }
+    }
+
+    /// Take the next packet from the local channel, after moving everything that is in the channel
+    /// into the `inbox`.
+    fn poll_local(&mut self) -> Poll<Option<Box<Packet>>, ()> {
+        loop {
+            match self.locals.poll()? {
+                Async::Ready(Some(packets)) => self.inbox.extend(packets),
+                Async::Ready(None) if self.inbox.is_empty() => return Ok(Async::Ready(None)),
+                Async::Ready(None) | Async::NotReady => break,
+            }
+        }
+        Ok(match self.inbox.pop_front() {
+            Some(packet) => Async::Ready(Some(packet)),
+            None => Async::NotReady,
+        })
+    }
+
+    /// Tell the domain how many packets are waiting for it: those in its `inbox`, plus `queued`
+    /// that were queued up elsewhere, those held back by injected delays, and those it has yet to
+    /// send on.
+    fn report_pressure(&mut self, queued: usize) {
+        let waiting = queued
+            + self.inbox.len()
+            + self.delayed.len()
+            + self.outbox.values().map(VecDeque::len).sum::<usize>();
+        self.domain.update_pressure(waiting);
+    }
+
+    /// Take the packets held back by injected delays that may now be processed, in the order in
+    /// which they became due, and make sure we're woken up when the next one does.
+    fn take_due(&mut self) -> Result<Vec<Box<Packet>>, tokio::timer::Error> {
+        if self.delayed.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let now = time::Instant::now();
+        let (mut due, held): (Vec<_>, Vec<_>) =
+            self.delayed.drain(..).partition(|&(at, _)| at <= now);
+        self.delayed = held;
+
+        self.release = None;
+        if let Some(next) = self.delayed.iter().map(|&(at, _)| at).min() {
+            let mut release = tokio::timer::Delay::new(next);
+            if let Async::Ready(()) = release.poll()? {
+                // became due while we were looking
+                futures::task::current().notify();
+            } else {
+                self.release = Some(release);
+            }
+        }
+
+        due.sort_by_key(|&(at, _)| at);
+        Ok(due.into_iter().map(|(_, packet)| packet).collect())
+    }
 }
 
 #[derive(Default)]
 struct Sendback {
-    // map from inputi to number of (empty) ACKs
-    back: FnvHashMap<usize, usize>,
+    // map from inputi to the ACKs that are yet to be sent to it
+    back: FnvHashMap<usize, VecDeque<WriteAck>>,
+    // map from inputi to when each write that it is yet to be sent an ACK for arrived
+    arrived: FnvHashMap<usize, VecDeque<time::Instant>>,
     pending: FnvHashSet<usize>,
 }
 
 impl Executor for Sendback {
-    fn send_back(&mut self, id: SourceChannelIdentifier, _: ()) {
-        use std::ops::AddAssign;
-        self.back.entry(id.token).or_insert(0).add_assign(1);
+    fn send_back(&mut self, id: SourceChannelIdentifier, ack: WriteAck) {
+        self.back
+            .entry(id.token)
+            .or_insert_with(VecDeque::new)
+            .push_back(ack);
     }
 }
 
-impl Future for Replica {
-    type Item = ();
-    type Error = ();
-    fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
-        let r: Result<Async<Self::Item>, failure::Error> = try {
+impl Replica {
+    fn poll_domain(&mut self) -> Result<Async<()>, failure::Error> {
+        let r: Result<Async<()>, failure::Error> = try {
             // FIXME: check if we should call update_state_sizes (every evict_every)
 
             // are there are any new connections?
@@ -1332,6 +1923,26 @@ impl Future for Replica {
             // have any of our timers expired?
             self.try_timeout().context("check timeout")?;
 
+            // is it time to try the sends that an injected fault held back again?
+            if let Some(mut retry) = self.retry.take() {
+                if let Async::NotReady = retry.poll().context("check stalled sends")? {
+                    self.retry = Some(retry);
+                }
+            }
+
+            // may any packets that were held back by injected delays be processed yet?
+            for packet in self.take_due().context("check delayed packets")? {
+                let d = &mut self.domain;
+                let sb = &mut self.sendback;
+                let ob = &mut self.outbox;
+                if let ProcessResult::StopPolling =
+                    block_on(|| d.on_event(sb, PollEvent::Process(packet), ob))
+                {
+                    // domain got a message to quit
+                    return Ok(Async::Ready(()));
+                }
+            }
+
             // we have three logical input sources: receives from local domains, receives from
             // remote domains, and remote mutators. we want to achieve some kind of fairness among
             // these, but bias the data-flow towards finishing work it has accepted (i.e., domain
@@ -1357,18 +1968,20 @@ impl Future for Replica {
                 let mut interrupted = false;
                 for i in 0..FORCE_INPUT_YIELD_EVERY {
                     if !local_done && (check_local || remote_done) {
-                        match self.locals.poll() {
+                        match self.poll_local() {
                             Ok(Async::Ready(Some(packet))) => {
-                                let d = &mut self.domain;
-                                let sb = &mut self.sendback;
-                                let ob = &mut self.outbox;
-
-                                if let ProcessResult::StopPolling =
-                                    block_on(|| d.on_event(sb, PollEvent::Process(packet), ob))
-                                {
-                                    // domain got a message to quit
-                                    // TODO: should we finish up remaining work?
-                                    return Ok(Async::Ready(()));
+                                if let Some(packet) = self.admit(packet) {
+                                    let d = &mut self.domain;
+                                    let sb = &mut self.sendback;
+                                    let ob = &mut self.outbox;
+
+                                    if let ProcessResult::StopPolling =
+                                        block_on(|| d.on_event(sb, PollEvent::Process(packet), ob))
+                                    {
+                                        // domain got a message to quit
+                                        // TODO: should we finish up remaining work?
+                                        return Ok(Async::Ready(()));
+                                    }
                                 }
                             }
                             Ok(Async::Ready(None)) => {
@@ -1390,22 +2003,31 @@ impl Future for Replica {
                     if !remote_done && (!check_local || local_done) {
                         match self.inputs.poll() {
                             Ok(Async::Ready(Some((StreamYield::Item(packet), _)))) => {
-                                let d = &mut self.domain;
-                                let sb = &mut self.sendback;
-                                let ob = &mut self.outbox;
-
-                                if let ProcessResult::StopPolling =
-                                    block_on(|| d.on_event(sb, PollEvent::Process(packet), ob))
-                                {
-                                    // domain got a message to quit
-                                    // TODO: should we finish up remaining work?
-                                    return Ok(Async::Ready(()));
+                                if let Packet::Input { src: Some(src), .. } = *packet {
+                                    // how long the client waits for the write to be acknowledged
+                                    // is counted from here
+                                    self.sendback
+                                        .arrived
+                                        .entry(src.token)
+                                        .or_default()
+                                        .push_back(time::Instant::now());
+                                }
+                                if let Some(packet) = self.admit(packet) {
+                                    let d = &mut self.domain;
+                                    let sb = &mut self.sendback;
+                                    let ob = &mut self.outbox;
+
+                                    if let ProcessResult::StopPolling =
+                                        block_on(|| d.on_event(sb, PollEvent::Process(packet), ob))
+                                    {
+                                        // domain got a message to quit
+                                        // TODO: should we finish up remaining work?
+                                        return Ok(Async::Ready(()));
+                                    }
                                 }
                             }
                             Ok(Async::Ready(Some((StreamYield::Finished(_stream), streami)))) => {
-                                self.sendback.back.remove(&streami);
-                                self.sendback.pending.remove(&streami);
-                                // FIXME: what about if a later flush flushes to this stream?
+                                self.input_closed(streami);
                             }
                             Ok(Async::Ready(None)) => {
                                 // we probably haven't booted yet
@@ -1439,6 +2061,7 @@ impl Future for Replica {
                 // send to downstream
                 // TODO: send fail == exiting?
                 self.try_flush().context("downstream flush (after)")?;
+                self.report_pressure(0);
 
                 // send acks
                 self.try_ack()?;
@@ -1463,18 +2086,88 @@ impl Future for Replica {
 
                 // we need to poll the delay to ensure we'll get woken up
                 self.try_timeout().context("check timeout after setting")?;
+
+                if self.timeout.is_none() {
+                    // the timeout had already expired, so the domain may have more to send, and
+                    // nothing is going to wake us up to do so.
+                    futures::task::current().notify();
+                }
             }
 
             readiness
         };
+        r
+    }
+
+    /// Tell the controller that the domain has stopped processing, and why.
+    fn report_failure(&self, message: String, backtrace: Option<String>) {
+        let (domain, shard) = self.domain.id();
+        let failure = DomainFailure {
+            domain,
+            shard,
+            message,
+            backtrace,
+        };
+        let failed = CoordinationPayload::DomainFailed(failure);
+        if let Err(_) = self.ctrl_tx.unbounded_send(failed) {
+            warn!(self.log, "controller went away before hearing of failure");
+        }
+    }
+
+    /// Run `f` on the replica, and if the domain fails along the way, by panicking or with an
+    /// error, tell the controller why.
+    ///
+    /// A panic that unwinds out of a domain is caught by the runtime, which just drops the
+    /// domain, so without this, nobody would hear about it. The domain's state may be left in any
+    /// shape by the panic, so callers must give up on the domain when this returns an error.
+    fn guard<T, F>(&mut self, f: F) -> Result<T, ()>
+    where
+        F: FnOnce(&mut Self) -> Result<T, failure::Error>,
+    {
+        IN_DOMAIN.with(|d| d.set(true));
+        let r = panic::catch_unwind(AssertUnwindSafe(|| f(self)));
+        IN_DOMAIN.with(|d| d.set(false));
 
         match r {
-            Ok(k) => Ok(k),
-            Err(e) => {
+            Ok(Ok(t)) => Ok(t),
+            Ok(Err(e)) => {
                 crit!(self.log, "replica failure: {:?}", e);
+                self.report_failure(format!("{:?}", e), None);
                 Err(())
             }
+            Err(payload) => {
+                let (location, backtrace) = match DOMAIN_PANIC.with(|p| p.borrow_mut().take()) {
+                    Some((location, backtrace)) => (location, Some(format!("{:?}", backtrace))),
+                    None => ("[unknown]".to_owned(), None),
+                };
+                let message = format!("panicked at '{}', {}", panic_message(&*payload), location);
+                crit!(self.log, "domain {}", message);
+                self.report_failure(message, backtrace);
+                Err(())
+            }
+        }
+    }
+}
+
+impl Future for Replica {
+    type Item = ();
+    type Error = ();
+    fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
+        let woken = time::Instant::now();
+        if let Some(parked) = self.parked.take() {
+            self.domain
+                .time_budget()
+                .waited(woken - parked, &self.blocked_on);
+        }
+
+        let r = self.guard(|r| r.poll_domain());
+
+        let parked = time::Instant::now();
+        self.domain.time_budget().worked(parked - woken);
+        if let Ok(Async::NotReady) = r {
+            self.parked = Some(parked);
         }
+        r
     }
 }
 
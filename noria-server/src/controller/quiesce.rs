@@ -0,0 +1,136 @@
+//! Brings the graph to rest, so that everything written before it was asked to is visible to reads.
+//!
+//! Writes are held back at the bases for as long as it takes, and a barrier is then passed down the
+//! graph along the same channels as the writes themselves. A domain shard passes the barrier on to
+//! the domains below once it has received it from every shard that sends to it and has processed,
+//! replayed and published everything it had taken in before then. Since channels deliver packets
+//! in the order they were sent, a domain that has passed the barrier has nothing left from before
+//! it, and the graph is at rest once every domain shard has passed it.
+//!
+//! Each domain shard is told how many shards it is to receive the barrier from, which is worked out
+//! from the graph the same way that `migrate::routing` decides which shards send to which.
+
+use crate::controller::domain_handle::{DomainHandle, WaitError};
+use crate::controller::{WorkerIdentifier, WorkerStatus};
+use dataflow::payload;
+use dataflow::prelude::*;
+use petgraph;
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
+
+/// The shards of other domains that each shard of the given domain receives packets from.
+fn senders(
+    graph: &Graph,
+    domain: DomainIndex,
+    domains: &HashMap<DomainIndex, DomainHandle>,
+) -> Vec<HashSet<(DomainIndex, usize)>> {
+    let shards = domains[&domain].shards();
+    let mut senders = vec![HashSet::new(); shards];
+    for ni in graph.node_indices() {
+        let n = &graph[ni];
+        if !n.is_ingress() || n.is_dropped() || n.domain() != domain {
+            continue;
+        }
+        for p in graph.neighbors_directed(ni, petgraph::EdgeDirection::Incoming) {
+            let p = &graph[p];
+            if p.is_source() {
+                continue;
+            }
+            let from = p.domain();
+            let from_shards = domains[&from].shards();
+            if p.is_egress() && shards != 1 && !p.sharded_by().is_none() {
+                // a sharded egress only sends to the shard of the same index
+                for (i, s) in senders.iter_mut().enumerate() {
+                    s.insert((from, i));
+                }
+            } else {
+                for s in &mut senders {
+                    s.extend((0..from_shards).map(|i| (from, i)));
+                }
+            }
+        }
+    }
+    senders
+}
+
+/// Send the barrier with the given identifier through the given domains, and wait until `deadline`
+/// for them to pass it. Returns the domain shards that had not passed it by then.
+fn pass_barrier(
+    graph: &Graph,
+    domains: &mut HashMap<DomainIndex, DomainHandle>,
+    workers: &HashMap<WorkerIdentifier, WorkerStatus>,
+    healthy: &[DomainIndex],
+    id: u64,
+    deadline: Instant,
+) -> Result<Vec<(DomainIndex, usize)>, String> {
+    for &di in healthy {
+        let senders = senders(graph, di, domains);
+        let dh = domains.get_mut(&di).unwrap();
+        for (i, s) in senders.into_iter().enumerate() {
+            let p = payload::Packet::Quiesce {
+                id,
+                senders: s.len(),
+            };
+            dh.send_to_healthy_shard(i, box p, workers)
+                .map_err(|e| format!("failed to quiesce {}.{}: {:?}", di.index(), i, e))?;
+        }
+    }
+
+    let mut stuck = Vec::new();
+    for &di in healthy {
+        let dh = domains.get_mut(&di).unwrap();
+        match dh.wait_for_quiesced(id, deadline) {
+            Ok(shards) => stuck.extend(shards.into_iter().map(|i| (di, i))),
+            Err(WaitError::Exited) => stuck.extend((0..dh.shards()).map(|i| (di, i))),
+            Err(e) => return Err(format!("failed to quiesce {}: {:?}", di.index(), e)),
+        }
+    }
+    Ok(stuck)
+}
+
+/// Hold back or let through writes at every one of the given domains.
+fn hold_writes(
+    domains: &mut HashMap<DomainIndex, DomainHandle>,
+    workers: &HashMap<WorkerIdentifier, WorkerStatus>,
+    healthy: &[DomainIndex],
+    hold: bool,
+) -> Result<(), String> {
+    for di in healthy {
+        domains
+            .get_mut(di)
+            .unwrap()
+            .send_to_healthy(box payload::Packet::HoldWrites(hold), workers)
+            .map_err(|e| format!("failed to hold writes at {}: {:?}", di.index(), e))?;
+    }
+    Ok(())
+}
+
+/// Drain every domain in the graph behind the barrier with the given identifier, giving up at
+/// `deadline`. Returns the domain shards that had not passed the barrier by then, which includes
+/// every shard of domains that have failed.
+pub(super) fn quiesce(
+    graph: &Graph,
+    domains: &mut HashMap<DomainIndex, DomainHandle>,
+    workers: &HashMap<WorkerIdentifier, WorkerStatus>,
+    id: u64,
+    deadline: Instant,
+) -> Result<Vec<(DomainIndex, usize)>, String> {
+    let mut stuck = Vec::new();
+    let mut healthy = Vec::new();
+    for (&di, dh) in domains.iter() {
+        if dh.failed() {
+            stuck.extend((0..dh.shards()).map(|i| (di, i)));
+        } else {
+            healthy.push(di);
+        }
+    }
+
+    hold_writes(domains, workers, &healthy, true)?;
+    let passed = pass_barrier(graph, domains, workers, &healthy, id, deadline);
+    // writes are let through again whether or not the graph came to rest
+    hold_writes(domains, workers, &healthy, false)?;
+
+    stuck.extend(passed?);
+    stuck.sort();
+    Ok(stuck)
+}
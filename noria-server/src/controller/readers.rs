@@ -1,5 +1,5 @@
 use bincode;
-use dataflow::backlog::SingleReadHandle;
+use dataflow::backlog::{self, SingleReadHandle};
 use dataflow::prelude::*;
 use dataflow::Readers;
 use futures::future::{self, Either};
@@ -9,13 +9,17 @@ use std::{mem, time};
 use tokio;
 use tokio::prelude::*;
 
-use noria::{ReadQuery, ReadReply};
+use noria::filter::{FilterError, RowFilter};
+use noria::{ReadMeta, ReadQuery, ReadReply};
 
 /// If a blocking reader finds itself waiting this long for a backfill to complete, it will
 /// re-issue the replay request. To avoid the system falling over if replays are slow for a little
 /// while, waiting readers will use exponential backoff on this delay if they continue to miss.
 const RETRY_TIMEOUT_US: u64 = 1_000;
 
+/// How often a client waiting for its write to be visible checks whether it is.
+const MARKER_POLL_US: u64 = 100;
+
 thread_local! {
     static READERS: RefCell<HashMap<
         (NodeIndex, usize),
@@ -23,166 +27,499 @@ thread_local! {
     >> = Default::default();
 }
 
+/// Find the reader for the given target. It may not exist yet if the view is still being set up.
+///
+/// Cached handles are only used until their reader is removed or replaced, after which the reader
+/// is looked up again, so that reads follow a view to the reader that has taken over from it.
+fn find_reader<'a>(
+    cache: &'a mut HashMap<(NodeIndex, usize), SingleReadHandle>,
+    readers: &Readers,
+    target: &(NodeIndex, usize),
+) -> Option<&'a SingleReadHandle> {
+    use std::collections::hash_map::Entry;
+    match cache.entry(*target) {
+        Entry::Occupied(mut e) => {
+            if e.get().is_retired() {
+                match readers.lock().unwrap().get(target) {
+                    Some(reader) => *e.get_mut() = reader.clone(),
+                    None => {
+                        e.remove();
+                        return None;
+                    }
+                }
+            }
+            Some(e.into_mut())
+        }
+        Entry::Vacant(e) => {
+            let reader = readers.lock().unwrap().get(target)?.clone();
+            Some(e.insert(reader))
+        }
+    }
+}
+
+/// What to make of the rows read for each key.
+type Then<T> = Box<Fn(&[Vec<DataType>]) -> T + Send>;
+
 fn dup(rs: &[Vec<DataType>]) -> Vec<Vec<DataType>> {
     rs.into_iter()
         .map(|r| r.iter().map(|v| v.deep_clone()).collect())
         .collect()
 }
 
+/// Copy out only the given columns of a row, in the given order.
+fn project_row(r: &[DataType], columns: &[usize]) -> Vec<DataType> {
+    columns.iter().map(|&c| r[c].deep_clone()).collect()
+}
+
+/// Copy out the rows for a key, keeping only the given columns if there are any.
+fn dup_projected(project: Option<Vec<usize>>) -> Then<Vec<Vec<DataType>>> {
+    match project {
+        None => Box::new(dup),
+        Some(columns) => Box::new(move |rs: &[Vec<DataType>]| -> Vec<Vec<DataType>> {
+            rs.iter().map(|r| project_row(r, &columns)).collect()
+        }),
+    }
+}
+
+/// Copy out the rows for a key that pass the given filter, if there is one, keeping only the
+/// given columns if there are any. Rows are filtered before they are projected, since the filter
+/// may be on columns that aren't returned.
+fn dup_matching(
+    filter: Option<RowFilter>,
+    project: Option<Vec<usize>>,
+) -> Then<Vec<Vec<DataType>>> {
+    match filter {
+        None => dup_projected(project),
+        Some(filter) => Box::new(move |rs: &[Vec<DataType>]| -> Vec<Vec<DataType>> {
+            rs.iter()
+                .filter(|r| filter.matches(r))
+                .map(|r| match project {
+                    Some(ref columns) => project_row(r, columns),
+                    None => r.iter().map(|v| v.deep_clone()).collect(),
+                })
+                .collect()
+        }),
+    }
+}
+
+/// Look up all the given keys in the same version of the target reader's state, without
+/// blocking. The results for the keys that hit are filled into `read`, and those keys are cleared.
+/// Unless the read is going to block for them, backfills are triggered for the keys that missed.
+///
+/// Returns how up to date the keys that hit are, or `Err(())` if the reader is not yet ready.
+fn read_now<T>(
+    s: &Readers,
+    target: &(NodeIndex, usize),
+    keys: &mut [Vec<DataType>],
+    read: &mut [T],
+    block: bool,
+    then: &Then<T>,
+) -> Result<ReadMeta, ()> {
+    READERS.with(|readers_cache| {
+        let mut readers_cache = readers_cache.borrow_mut();
+        let reader = find_reader(&mut readers_cache, s, target).ok_or(())?;
+
+        let (found, meta) = reader.try_find_many_with_meta_and(keys, &**then)?;
+        let mut missed = 0;
+        for (i, rs) in found.into_iter().enumerate() {
+            if let Some(rs) = rs {
+                // immediate hit!
+                read[i] = rs;
+                keys[i].clear();
+            } else {
+                // otherwise, we'll have to trigger a partial replay
+                missed += 1;
+            }
+        }
+        reader.metrics().read(keys.len(), missed);
+        if block && missed != 0 {
+            reader.metrics().blocked();
+        }
+
+        if !block {
+            // trigger backfills for all the keys we missed on for later
+            for key in keys.iter() {
+                if !key.is_empty() {
+                    reader.trigger(key);
+                }
+            }
+        }
+        Ok(meta)
+    })
+}
+
+/// Read the given keys, passing the rows for each through `then`, and reply with the results, and
+/// how up to date they are, using `reply`. If `block` is set, and some of the keys miss or the
+/// reader is not yet ready, this waits for them; otherwise, misses are returned as `T::default()`.
+fn read_keys<T: Clone + Default>(
+    s: &Readers,
+    target: (NodeIndex, usize),
+    mut keys: Vec<Vec<DataType>>,
+    block: bool,
+    ready_timeout: Option<time::Duration>,
+    then: Then<T>,
+    reply: Box<Fn(Result<(Vec<T>, ReadMeta), ()>) -> ReadReply + Send>,
+) -> Either<future::FutureResult<ReadReply, bincode::Error>, BlockingRead<T>> {
+    let mut read = vec![T::default(); keys.len()];
+    match read_now(s, &target, &mut keys, &mut read, block, &then) {
+        Ok(meta) if !block || keys.iter().all(|k| k.is_empty()) => {
+            Either::A(future::ok(reply(Ok((read, meta)))))
+        }
+        Err(()) if !block => Either::A(future::ok(reply(Err(())))),
+        found => {
+            // some keys missed, or the map is not yet ready, so wait for it to be swapped in, and
+            // then for the missing keys
+            let trigger = time::Duration::from_micros(RETRY_TIMEOUT_US);
+            let retry = time::Duration::from_micros(10);
+            let now = time::Instant::now();
+            Either::B(BlockingRead {
+                target,
+                keys,
+                read,
+                meta: found.ok(),
+                truth: s.clone(),
+                retry: tokio::timer::Interval::new(now + retry, retry),
+                trigger_timeout: trigger,
+                next_trigger: now,
+                ready_deadline: ready_timeout.map(|t| now + t),
+                then,
+                reply,
+            })
+        }
+    }
+}
+
+/// The view and filter of a query, if it has a filter.
+fn query_filter(m: &ReadQuery) -> Option<(&(NodeIndex, usize), &RowFilter)> {
+    let (target, filter) = match *m {
+        ReadQuery::Normal {
+            ref target,
+            ref filter,
+            ..
+        }
+        | ReadQuery::WithMeta {
+            ref target,
+            ref filter,
+            ..
+        }
+        | ReadQuery::Count {
+            ref target,
+            ref filter,
+            ..
+        }
+        | ReadQuery::Size {
+            ref target,
+            ref filter,
+        }
+        | ReadQuery::Range {
+            ref target,
+            ref filter,
+            ..
+        }
+        | ReadQuery::Scan {
+            ref target,
+            ref filter,
+            ..
+        }
+        | ReadQuery::Page {
+            ref target,
+            ref filter,
+            ..
+        } => (target, filter),
+        ReadQuery::Marker { .. } => return None,
+    };
+    filter.as_ref().map(|filter| (target, filter))
+}
+
+/// Check that the query's filter, if it has one, can be used on the rows of the view it reads.
+///
+/// Clients check their filters before sending them, but a filter that gets here anyway is refused
+/// rather than evaluated. Filters on views whose readers don't exist yet can't be checked, and are
+/// then evaluated as they are, which leaves out every row they can't be evaluated for.
+fn check_filter(m: &ReadQuery, s: &Readers) -> Result<(), FilterError> {
+    let (target, filter) = match query_filter(m) {
+        Some(tf) => tf,
+        None => return Ok(()),
+    };
+    READERS.with(|readers_cache| {
+        let mut readers_cache = readers_cache.borrow_mut();
+        match find_reader(&mut readers_cache, s, target) {
+            Some(reader) => filter.check(reader.columns()),
+            None => Ok(()),
+        }
+    })
+}
+
 pub(crate) fn handle_message(
     m: ReadQuery,
     s: &mut Readers,
 ) -> impl Future<Item = ReadReply, Error = bincode::Error> + Send {
+    if let Err(e) = check_filter(&m, s) {
+        return Either::A(future::ok(ReadReply::Invalid(e)));
+    }
+
     match m {
         ReadQuery::Normal {
             target,
-            mut keys,
+            filter,
+            keys,
+            project,
+            block,
+            ready_timeout,
+        } => {
+            let reply = Box::new(|read: Result<(Vec<Vec<Vec<DataType>>>, ReadMeta), ()>| {
+                ReadReply::Normal(read.map(|(rows, _)| rows))
+            });
+            let then = dup_matching(filter, project);
+            match read_keys(s, target, keys, block, ready_timeout, then, reply) {
+                Either::A(now) => Either::A(now),
+                Either::B(blocking) => Either::B(Either::A(blocking)),
+            }
+        }
+        ReadQuery::WithMeta {
+            target,
+            filter,
+            keys,
             block,
+            ready_timeout,
         } => {
-            let immediate = READERS.with(|readers_cache| {
+            let reply = Box::new(ReadReply::WithMeta);
+            let then = dup_matching(filter, None);
+            match read_keys(s, target, keys, block, ready_timeout, then, reply) {
+                Either::A(now) => Either::A(now),
+                Either::B(blocking) => Either::B(Either::A(blocking)),
+            }
+        }
+        ReadQuery::Count {
+            target,
+            filter,
+            keys,
+            block,
+            ready_timeout,
+        } => {
+            let reply = Box::new(|read: Result<(Vec<usize>, ReadMeta), ()>| {
+                ReadReply::Count(read.map(|(counts, _)| counts))
+            });
+            let then: Then<usize> = match filter {
+                None => Box::new(|rs: &[Vec<DataType>]| rs.len()),
+                Some(filter) => Box::new(move |rs: &[Vec<DataType>]| {
+                    rs.iter().filter(|r| filter.matches(r)).count()
+                }),
+            };
+            match read_keys(s, target, keys, block, ready_timeout, then, reply) {
+                Either::A(now) => Either::A(now),
+                Either::B(blocking) => Either::B(Either::B(Either::A(blocking))),
+            }
+        }
+        ReadQuery::Marker {
+            target,
+            marker,
+            timeout,
+        } => {
+            let seen = READERS.with(|readers_cache| {
                 let mut readers_cache = readers_cache.borrow_mut();
-                let reader = readers_cache.entry(target.clone()).or_insert_with(|| {
-                    let readers = s.lock().unwrap();
-                    readers.get(&target).unwrap().clone()
-                });
-
-                let mut ret = Vec::with_capacity(keys.len());
-                ret.resize(keys.len(), Vec::new());
-
-                // first do non-blocking reads for all keys to see if we can return immediately
-                let found = keys
-                    .iter_mut()
-                    .map(|key| {
-                        let rs = reader.try_find_and(key, dup).map(|r| r.0);
-                        (key, rs)
-                    })
-                    .enumerate();
-
-                let mut ready = true;
-                for (i, (key, v)) in found {
-                    match v {
-                        Ok(Some(rs)) => {
-                            // immediate hit!
-                            ret[i] = rs;
-                            *key = vec![];
-                        }
-                        Err(()) => {
-                            // map not yet ready
-                            ready = false;
-                            *key = vec![];
-                            break;
-                        }
-                        Ok(None) => {
-                            // triggered partial replay
-                        }
-                    }
-                }
+                find_reader(&mut readers_cache, s, &target)
+                    .map(|reader| reader.take_marker(marker))
+                    .unwrap_or(false)
+            });
+            if seen {
+                Either::A(future::ok(ReadReply::Marker(true)))
+            } else {
+                let poll = time::Duration::from_micros(MARKER_POLL_US);
+                let now = time::Instant::now();
+                Either::B(Either::B(Either::B(WaitForMarker {
+                    target,
+                    marker,
+                    truth: s.clone(),
+                    poll: tokio::timer::Interval::new(now + poll, poll),
+                    deadline: now + timeout,
+                })))
+            }
+        }
+        ReadQuery::Size { target, filter } => {
+            let size = READERS.with(|readers_cache| {
+                let mut readers_cache = readers_cache.borrow_mut();
+                let reader = find_reader(&mut readers_cache, s, &target).unwrap();
 
-                if !ready {
-                    return Ok(ReadReply::Normal(Err(())));
+                match filter {
+                    Some(ref filter) => reader.len_matching(filter),
+                    None => reader.len(),
                 }
+            });
 
-                if !block {
-                    // trigger backfills for all the keys we missed on for later
-                    for key in &keys {
-                        if !key.is_empty() {
-                            reader.trigger(key);
-                        }
-                    }
-                }
+            Either::A(future::ok(ReadReply::Size(size)))
+        }
+        ReadQuery::Range {
+            target,
+            filter,
+            lower,
+            upper,
+            limit,
+        } => {
+            let found = READERS.with(|readers_cache| {
+                let mut readers_cache = readers_cache.borrow_mut();
+                let reader = find_reader(&mut readers_cache, s, &target).unwrap();
 
-                Err((keys, ret))
+                let (lower, upper) = (lower.as_bound(), upper.as_bound());
+                reader.find_range_and(lower, upper, limit, filter.as_ref(), |r| {
+                    r.iter().map(|v| v.deep_clone()).collect()
+                })
             });
 
-            match immediate {
-                Ok(reply) => Either::A(Either::A(future::ok(reply))),
-                Err((keys, ret)) => {
-                    if !block {
-                        Either::A(Either::A(future::ok(ReadReply::Normal(Ok(ret)))))
-                    } else {
-                        let trigger = time::Duration::from_micros(RETRY_TIMEOUT_US);
-                        let retry = time::Duration::from_micros(10);
-                        let now = time::Instant::now();
-                        Either::A(Either::B(BlockingRead {
-                            target,
-                            keys,
-                            read: ret,
-                            truth: s.clone(),
-                            retry: tokio::timer::Interval::new(now + retry, retry),
-                            trigger_timeout: trigger,
-                            next_trigger: now,
-                        }))
-                    }
-                }
-            }
+            Either::A(future::ok(ReadReply::Range(found)))
         }
-        ReadQuery::Size { target } => {
-            let size = READERS.with(|readers_cache| {
+        ReadQuery::Scan {
+            target,
+            filter,
+            after,
+            limit,
+        } => {
+            let found = READERS.with(|readers_cache| {
                 let mut readers_cache = readers_cache.borrow_mut();
-                let reader = readers_cache.entry(target.clone()).or_insert_with(|| {
-                    let readers = s.lock().unwrap();
-                    readers.get(&target).unwrap().clone()
-                });
+                let reader = find_reader(&mut readers_cache, s, &target).unwrap();
 
-                reader.len()
+                let after = after.as_ref().map(|k| &k[..]);
+                reader.scan_and(after, limit, filter.as_ref(), |r| {
+                    r.iter().map(|v| v.deep_clone()).collect()
+                })
             });
 
-            Either::B(future::ok(ReadReply::Size(size)))
+            Either::A(future::ok(ReadReply::Scan(found)))
+        }
+        ReadQuery::Page {
+            target,
+            filter,
+            key,
+            order_by,
+            offset,
+            limit,
+            project,
+            ready_timeout,
+        } => {
+            let found = READERS.with(|readers_cache| {
+                let mut readers_cache = readers_cache.borrow_mut();
+                let reader = find_reader(&mut readers_cache, s, &target)?;
+                let page = reader
+                    .try_find_page_and(&key, filter.as_ref(), order_by, offset, limit, |r| {
+                        match project {
+                            Some(ref columns) => project_row(r, columns),
+                            None => r.iter().map(|v| v.deep_clone()).collect(),
+                        }
+                    })
+                    .unwrap_or(None);
+                if page.is_some() {
+                    // a miss is counted by the read that then waits for the key
+                    reader.metrics().read(1, 0);
+                }
+                page
+            });
+
+            match found {
+                Some(page) => Either::A(future::ok(ReadReply::Page(Ok(page)))),
+                None => {
+                    // either the key is missing, or the view isn't ready yet. in both cases, wait
+                    // for all its rows that pass the filter like a blocking read would, and then
+                    // sort them. the rows are read whole, since they may be ordered by a column
+                    // that isn't returned.
+                    let reply = Box::new(
+                        move |rows: Result<(Vec<Vec<Vec<DataType>>>, ReadMeta), ()>| {
+                            ReadReply::Page(rows.map(|(mut rows, _)| {
+                                let rows = rows.swap_remove(0);
+                                backlog::page_of(&rows[..], None, order_by, offset, limit, |r| {
+                                    match project {
+                                        Some(ref columns) => project_row(r, columns),
+                                        None => r.to_vec(),
+                                    }
+                                })
+                            }))
+                        },
+                    );
+                    let then = dup_matching(filter, None);
+                    match read_keys(s, target, vec![key], true, ready_timeout, then, reply) {
+                        Either::A(now) => Either::A(now),
+                        Either::B(blocking) => Either::B(Either::A(blocking)),
+                    }
+                }
+            }
         }
     }
 }
 
-struct BlockingRead {
-    read: Vec<Vec<Vec<DataType>>>,
+struct BlockingRead<T> {
+    read: Vec<T>,
+    // how up to date the keys read so far are, if any have been read
+    meta: Option<ReadMeta>,
     target: (NodeIndex, usize),
     keys: Vec<Vec<DataType>>,
     truth: Readers,
     retry: tokio::timer::Interval,
     trigger_timeout: time::Duration,
     next_trigger: time::Instant,
+    // when to give up if the reader still hasn't been swapped in
+    ready_deadline: Option<time::Instant>,
+    // what to make of the rows for each key, and how to reply once all the keys have been read
+    then: Then<T>,
+    reply: Box<Fn(Result<(Vec<T>, ReadMeta), ()>) -> ReadReply + Send>,
 }
 
-impl Future for BlockingRead {
+impl<T> Future for BlockingRead<T> {
     type Item = ReadReply;
     type Error = bincode::Error;
     fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
         READERS.with(move |readers_cache| {
             let mut readers_cache = readers_cache.borrow_mut();
-            let s = &self.truth;
-            let target = &self.target;
-            let reader = readers_cache.entry(self.target.clone()).or_insert_with(|| {
-                let readers = s.lock().unwrap();
-                readers.get(target).unwrap().clone()
-            });
 
             let mut triggered = false;
             let mut missing = false;
+            let mut ready = true;
             let now = time::Instant::now();
-            for (i, key) in self.keys.iter_mut().enumerate() {
-                if key.is_empty() {
-                    // already have this value
-                } else {
-                    // note that this *does* mean we'll trigger replay multiple times for things
-                    // that miss and aren't replayed in time, which is a little sad. but at the
-                    // same time, that replay trigger will just be ignored by the target domain.
-                    match reader.try_find_and(key, dup).map(|r| r.0) {
-                        Ok(Some(rs)) => {
-                            self.read[i] = rs;
-                            key.clear();
-                        }
-                        Err(()) => {
-                            unreachable!("map became not ready?");
-                        }
-                        Ok(None) => {
-                            if now > self.next_trigger {
-                                // maybe the key was filled but then evicted, and we missed it?
-                                reader.trigger(key);
-                                triggered = true;
+            match find_reader(&mut readers_cache, &self.truth, &self.target) {
+                Some(reader) => {
+                    for (i, key) in self.keys.iter_mut().enumerate() {
+                        if key.is_empty() {
+                            // already have this value
+                        } else {
+                            // note that this *does* mean we'll trigger replay multiple times for
+                            // things that miss and aren't replayed in time, which is a little
+                            // sad. but at the same time, that replay trigger will just be
+                            // ignored by the target domain.
+                            match reader.try_find_with_meta_and(key, &*self.then) {
+                                Ok((Some(rs), meta)) => {
+                                    self.read[i] = rs;
+                                    self.meta = Some(match self.meta {
+                                        Some(m) => m.and(meta),
+                                        None => meta,
+                                    });
+                                    key.clear();
+                                }
+                                Err(()) => {
+                                    // once the map is swapped in, it stays ready, so there's no
+                                    // point in looking at the other keys.
+                                    ready = false;
+                                    break;
+                                }
+                                Ok((None, _)) => {
+                                    if now > self.next_trigger {
+                                        // maybe the key was filled but then evicted, and we
+                                        // missed it?
+                                        reader.trigger(key);
+                                        triggered = true;
+                                    }
+                                    missing = true;
+                                }
                             }
-                            missing = true;
                         }
                     }
                 }
+                None => ready = false,
+            }
+
+            if !ready {
+                // the view is still being built
+                if self.ready_deadline.map(|d| now > d).unwrap_or(false) {
+                    return Ok(Async::Ready((self.reply)(Err(()))));
+                }
+                missing = true;
             }
 
             if triggered {
@@ -200,11 +537,49 @@ impl Future for BlockingRead {
                     }
                 }
             } else {
-                Ok(Async::Ready(ReadReply::Normal(Ok(mem::replace(
-                    &mut self.read,
-                    Vec::new(),
-                )))))
+                let read = mem::replace(&mut self.read, Vec::new());
+                let meta = self
+                    .meta
+                    .expect("blocking read finished without reading any keys");
+                Ok(Async::Ready((self.reply)(Ok((read, meta)))))
             }
         })
     }
 }
+
+/// Waits for the write with the given marker to be made visible by the target reader.
+struct WaitForMarker {
+    target: (NodeIndex, usize),
+    marker: u64,
+    truth: Readers,
+    poll: tokio::timer::Interval,
+    deadline: time::Instant,
+}
+
+impl Future for WaitForMarker {
+    type Item = ReadReply;
+    type Error = bincode::Error;
+    fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
+        loop {
+            let seen = READERS.with(|readers_cache| {
+                let mut readers_cache = readers_cache.borrow_mut();
+                find_reader(&mut readers_cache, &self.truth, &self.target)
+                    .map(|reader| reader.take_marker(self.marker))
+                    .unwrap_or(false)
+            });
+            if seen {
+                return Ok(Async::Ready(ReadReply::Marker(true)));
+            }
+            if time::Instant::now() > self.deadline {
+                return Ok(Async::Ready(ReadReply::Marker(false)));
+            }
+
+            match self.poll.poll() {
+                Ok(Async::Ready(Some(_))) => {}
+                Ok(Async::Ready(None)) => unreachable!("interval stopped yielding"),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(e) => unreachable!("{:?}", e),
+            }
+        }
+    }
+}
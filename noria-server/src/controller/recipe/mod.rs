@@ -448,6 +448,12 @@ impl Recipe {
             })
             .collect();
 
+        // the removed queries and bases are only removed once the migration has been committed,
+        // but new ones may already take their names
+        for &ni in &result.removed_leaves {
+            mig.release_names(ni);
+        }
+
         Ok(result)
     }
 
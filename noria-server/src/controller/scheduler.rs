@@ -0,0 +1,306 @@
+//! Deterministic execution of a worker's domains, for tests.
+//!
+//! Normally, every domain is a task of its own, and the order in which domains see each other's
+//! packets depends on how the runtime's threads happen to interleave. That makes bugs that only
+//! show up under some orders very hard to reproduce. A `Scheduler` instead owns every domain of a
+//! worker, and runs them all in a single task. The packets that have arrived for each domain are
+//! queued up without being processed, and at every step the scheduler picks one non-empty queue
+//! using a random number generator seeded by the test, and has its domain process the packet at
+//! the head of it. The domain goes through the same handling code as it would in its own task.
+//!
+//! Packets that domains send each other are only queued at their destination once the sender's
+//! step is over, so with a fixed seed, the domains see those packets in the same order every time.
+//! Timers only fire once no packets are queued anywhere, earliest deadline first, so they don't
+//! reorder packets either. Packets from outside the worker, like writes from clients and control
+//! messages from the controller, are still queued in the order in which they arrive. So are packets
+//! that were held back by an injected delay (see `FaultInjector`), once the delay is up.
+
+use crate::controller::{block_on, Replica, FORCE_INPUT_YIELD_EVERY};
+use dataflow::Packet;
+use failure::{self, ResultExt};
+use futures::sync::mpsc::UnboundedReceiver;
+use noria::channel::poll::{PollEvent, ProcessResult};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use slog;
+use std::collections::VecDeque;
+use std::time;
+use streamunordered::StreamYield;
+use tokio;
+use tokio::prelude::*;
+
+/// A domain run by the `Scheduler`, along with the packets that have arrived for it.
+struct Scheduled {
+    replica: Replica,
+    /// Packets from the domain's local channel: from other domains and the controller.
+    locals: VecDeque<Box<Packet>>,
+    /// Packets from the domain's network connections: writes from clients.
+    remotes: VecDeque<Box<Packet>>,
+    /// When the domain next wants to be told that time has passed, if it does.
+    deadline: Option<time::Instant>,
+    /// Whether the domain has stopped, either because it was told to or because it failed.
+    done: bool,
+}
+
+impl Scheduled {
+    fn new(replica: Replica) -> Self {
+        Scheduled {
+            replica,
+            locals: VecDeque::new(),
+            remotes: VecDeque::new(),
+            deadline: None,
+            done: false,
+        }
+    }
+
+    fn has_packets(&self) -> bool {
+        !self.locals.is_empty() || !self.remotes.is_empty()
+    }
+
+    /// Queue up every packet that has arrived for the domain, without processing any of them.
+    fn gather(&mut self) {
+        let locals = &mut self.locals;
+        let remotes = &mut self.remotes;
+        let r = self.replica.guard(|r| {
+            if !r.try_new().context("check for new connections")? {
+                // incoming socket closed -- no more clients will arrive
+                return Ok(true);
+            }
+
+            // packets held back by injected delays are queued as if they had just arrived
+            locals.extend(r.take_due().context("check delayed packets")?);
+
+            loop {
+                match r.locals.poll() {
+                    Ok(Async::Ready(Some(packet))) => locals.extend(r.admit(packet)),
+                    Ok(Async::Ready(None)) => {
+                        // local input stream finished
+                        return Ok(true);
+                    }
+                    Ok(Async::NotReady) => break,
+                    Err(e) => {
+                        error!(r.log, "local input stream failed: {:?}", e);
+                        break;
+                    }
+                }
+            }
+
+            loop {
+                match r.inputs.poll() {
+                    Ok(Async::Ready(Some((StreamYield::Item(packet), _)))) => {
+                        remotes.extend(r.admit(packet))
+                    }
+                    Ok(Async::Ready(Some((StreamYield::Finished(_stream), streami)))) => {
+                        r.input_closed(streami);
+                    }
+                    Ok(Async::Ready(None)) | Ok(Async::NotReady) => break,
+                    Err(e) => {
+                        error!(r.log, "input stream failed: {:?}", e);
+                        break;
+                    }
+                }
+            }
+            Ok(false)
+        });
+        self.done = r.unwrap_or(true);
+    }
+
+    /// Have the domain process the packet at the head of one of its queues, and send on whatever
+    /// that makes it send.
+    fn step(&mut self, remote: bool) {
+        let packet = if remote {
+            self.remotes.pop_front()
+        } else {
+            self.locals.pop_front()
+        };
+        let packet = packet.expect("scheduled a domain with nothing to process");
+        // the packets from other domains that are still queued up here, rather than in the replica
+        let queued = self.locals.len();
+
+        let r = self.replica.guard(|r| {
+            let d = &mut r.domain;
+            let sb = &mut r.sendback;
+            let ob = &mut r.outbox;
+            let quit = match block_on(|| d.on_event(sb, PollEvent::Process(packet), ob)) {
+                // domain got a message to quit
+                ProcessResult::StopPolling => true,
+                _ => false,
+            };
+            r.flush()?;
+            r.report_pressure(queued);
+            Ok(quit)
+        });
+        self.done = r.unwrap_or(true);
+    }
+
+    /// Tell the domain that time has passed.
+    fn fire(&mut self) {
+        self.deadline = None;
+        let r = self.replica.guard(|r| {
+            block_on(|| {
+                r.domain
+                    .on_event(&mut r.sendback, PollEvent::Timeout, &mut r.outbox)
+            });
+            r.flush()?;
+            Ok(())
+        });
+        self.done = r.is_err();
+    }
+
+    /// Tell the domain that it has run out of packets for now, and note when it next wants to be
+    /// told that time has passed.
+    fn resume(&mut self) {
+        let deadline = &mut self.deadline;
+        let r = self.replica.guard(|r| {
+            let mut timeout = None;
+            r.domain.on_event(
+                &mut r.sendback,
+                PollEvent::ResumePolling(&mut timeout),
+                &mut r.outbox,
+            );
+            *deadline = timeout.map(|timeout| time::Instant::now() + timeout);
+            r.flush()?;
+            Ok(())
+        });
+        self.done = r.is_err();
+    }
+}
+
+impl Replica {
+    /// Send the packets the domain has queued for other domains, and the acks it owes clients.
+    fn flush(&mut self) -> Result<(), failure::Error> {
+        self.try_flush().context("downstream flush")?;
+        self.try_ack()
+    }
+}
+
+/// Runs every domain of a worker in a single task, and hands the domains the packets that have
+/// arrived for them one at a time, in an order picked by a seeded random number generator.
+pub(super) struct Scheduler {
+    rng: StdRng,
+    /// Domains that have been booted on this worker, to be run by the scheduler.
+    booted: UnboundedReceiver<Replica>,
+    /// The domains being run, in the order they were booted.
+    domains: Vec<Scheduled>,
+    /// Wakes the scheduler up when the earliest deadline of a domain has passed.
+    timer: Option<tokio::timer::Delay>,
+    log: slog::Logger,
+}
+
+impl Scheduler {
+    pub(super) fn new(seed: u64, booted: UnboundedReceiver<Replica>, log: slog::Logger) -> Self {
+        let mut bytes = [0; 32];
+        for (i, b) in bytes.iter_mut().take(8).enumerate() {
+            *b = (seed >> (8 * i)) as u8;
+        }
+
+        info!(log, "running domains deterministically"; "seed" => seed);
+        Scheduler {
+            rng: StdRng::from_seed(bytes),
+            booted,
+            domains: Vec::new(),
+            timer: None,
+            log,
+        }
+    }
+
+    /// Take on the domains that have been booted since we last looked. Returns false once no
+    /// more domains can arrive.
+    fn take_booted(&mut self) -> bool {
+        loop {
+            match self.booted.poll() {
+                Ok(Async::Ready(Some(replica))) => {
+                    let mut domain = Scheduled::new(replica);
+                    domain.resume();
+                    self.domains.push(domain);
+                }
+                Ok(Async::Ready(None)) => return false,
+                Ok(Async::NotReady) => return true,
+                Err(()) => unreachable!(),
+            }
+        }
+    }
+}
+
+impl Future for Scheduler {
+    type Item = ();
+    type Error = ();
+    fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
+        let more = self.take_booted();
+
+        for _ in 0..FORCE_INPUT_YIELD_EVERY {
+            for domain in &mut self.domains {
+                domain.gather();
+            }
+            let log = &self.log;
+            self.domains.retain(|domain| {
+                if domain.done {
+                    let (di, shard) = domain.replica.domain.id();
+                    debug!(log, "domain stopped"; "domain" => di.index(), "shard" => shard);
+                }
+                !domain.done
+            });
+            if !more && self.domains.is_empty() {
+                return Ok(Async::Ready(()));
+            }
+
+            // every queue that has a packet waiting is a candidate, in a fixed order, so that the
+            // seed alone decides which is picked
+            let mut candidates = Vec::new();
+            for (i, domain) in self.domains.iter().enumerate() {
+                if !domain.locals.is_empty() {
+                    candidates.push((i, false));
+                }
+                if !domain.remotes.is_empty() {
+                    candidates.push((i, true));
+                }
+            }
+
+            if !candidates.is_empty() {
+                let (i, remote) = candidates[self.rng.gen_range(0, candidates.len())];
+                let domain = &mut self.domains[i];
+                domain.step(remote);
+                if !domain.done && !domain.has_packets() {
+                    domain.resume();
+                }
+                continue;
+            }
+
+            // nothing is in flight, so time may pass. the domains whose deadlines are up are told
+            // so one at a time, earliest first, with ties going to the domain that booted first.
+            let now = time::Instant::now();
+            let due = self
+                .domains
+                .iter()
+                .enumerate()
+                .filter_map(|(i, domain)| domain.deadline.map(|deadline| (deadline, i)))
+                .filter(|&(deadline, _)| deadline <= now)
+                .min();
+            if let Some((_, i)) = due {
+                let domain = &mut self.domains[i];
+                domain.fire();
+                if !domain.done {
+                    domain.resume();
+                }
+                continue;
+            }
+
+            // wait for more packets, or for the next deadline
+            self.timer = None;
+            let next = self.domains.iter().filter_map(|d| d.deadline).min();
+            if let Some(next) = next {
+                let mut timer = tokio::timer::Delay::new(next);
+                match timer.poll() {
+                    Ok(Async::NotReady) => self.timer = Some(timer),
+                    // the deadline passed while we were looking
+                    Ok(Async::Ready(())) | Err(_) => continue,
+                }
+            }
+            return Ok(Async::NotReady);
+        }
+
+        // we could keep going, but let the other tasks on this thread get a word in
+        futures::task::current().notify();
+        Ok(Async::NotReady)
+    }
+}
@@ -1,4 +1,5 @@
 use crate::controller::sql::mir::SqlToMirConverter;
+use crate::controller::sql::passes::count_star_rewrite::counted_column;
 use crate::controller::sql::query_graph::{QueryGraph, QueryGraphEdge};
 use mir::{Column, MirNodeRef};
 use nom_sql::FunctionExpression::*;
@@ -10,8 +11,8 @@ fn target_columns_from_computed_column(computed_col: &nom_sql::Column) -> Column
     use nom_sql::FunctionExpression::*;
 
     match *computed_col.function.as_ref().unwrap().deref() {
+        Count(ref col, _) => Column::from(counted_column(col).0),
         Avg(ref col, _)
-        | Count(ref col, _)
         | GroupConcat(ref col, _)
         | Max(ref col)
         | Min(ref col)
@@ -8,6 +8,7 @@ use dataflow::ops::filter::FilterCondition;
 use dataflow::ops::join::JoinType;
 pub use mir::FlowNode;
 
+use crate::controller::sql::passes::count_star_rewrite::counted_column;
 use crate::controller::sql::query_graph::{OutputColumn, QueryGraph};
 use crate::controller::sql::query_signature::Signature;
 use nom_sql::{
@@ -860,18 +861,26 @@ impl SqlToMirConverter {
                 GroupedNodeType::Aggregation(Aggregation::SUM),
                 distinct,
             ),
-            Count(ref col, distinct) => mknode(
-                &Column::from(col),
-                GroupedNodeType::Aggregation(Aggregation::COUNT),
-                distinct,
-            ),
+            Count(ref col, distinct) => {
+                let (col, star) = counted_column(col);
+                let kind = if star {
+                    Aggregation::COUNT_ALL
+                } else {
+                    Aggregation::COUNT
+                };
+                mknode(
+                    &Column::from(col),
+                    GroupedNodeType::Aggregation(kind),
+                    distinct,
+                )
+            }
             CountStar => {
                 // XXX(malte): there is no "over" column, but our aggregation operators' API
-                // requires one to be specified, so we earlier rewrote it to use the last parent
-                // column (see passes/count_star_rewrite.rs). However, this isn't *entirely*
-                // faithful to COUNT(*) semantics, because COUNT(*) is supposed to count all
-                // rows including those with NULL values, and we don't have a mechanism to do that
-                // (but we also don't have a NULL value, so maybe we're okay).
+                // requires one to be specified, so we earlier rewrote it to count a column that
+                // the query does not otherwise use (see passes/count_star_rewrite.rs). That
+                // column may well be NULL for some rows, so the rewritten count is marked, and
+                // lowered to `Aggregation::COUNT_ALL` above, which counts rows whatever the
+                // column holds.
                 panic!("COUNT(*) should have been rewritten earlier!")
             }
             Max(ref col) => mknode(
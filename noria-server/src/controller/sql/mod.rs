@@ -1441,7 +1441,11 @@ mod tests {
             // added the aggregation, a project helper, the edge view, and reader
             assert_eq!(mig.graph().node_count(), 5);
             // check aggregation view
-            let f = Box::new(FunctionExpression::Count(Column::from("votes.aid"), false));
+            let counted = Column {
+                function: Some(Box::new(FunctionExpression::CountStar)),
+                ..Column::from("votes.aid")
+            };
+            let f = Box::new(FunctionExpression::Count(counted, false));
             let qid = query_id_hash(
                 &["computed_columns", "votes"],
                 &[&Column::from("votes.userid")],
@@ -1454,7 +1458,7 @@ mod tests {
             );
             let agg_view = get_node(&inc, mig, &format!("q_{:x}_n0", qid));
             assert_eq!(agg_view.fields(), &["userid", "count"]);
-            assert_eq!(agg_view.description(true), "|*| γ[0]");
+            assert_eq!(agg_view.description(true), "|*|(*) γ[0]");
             // check edge view -- note that it's not actually currently possible to read from
             // this for a lack of key (the value would be the key). Hence, the view also has a
             // bogokey column.
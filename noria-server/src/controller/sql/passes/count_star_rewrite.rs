@@ -1,14 +1,35 @@
 use nom_sql::{
-    Column, ConditionBase, ConditionExpression, ConditionTree, FieldDefinitionExpression, SqlQuery,
-    Table,
+    Column, ConditionBase, ConditionExpression, ConditionTree, FieldDefinitionExpression,
+    FunctionExpression, SqlQuery, Table,
 };
 
 use std::collections::HashMap;
 
+/// Rewrites `COUNT(*)` to a `COUNT` over a column of the first table in the query that the query
+/// does not group by or filter on, since every aggregation needs a column to aggregate over.
+///
+/// That column may hold `NULL`s, which a plain `COUNT` skips, so the rewritten `COUNT` is marked
+/// by giving the column it counts a `CountStar` function of its own. The marker is taken off again
+/// by `counted_column` when the query is lowered, and the count is then made to count every row,
+/// whatever the column holds.
 pub trait CountStarRewrite {
     fn rewrite_count_star(self, write_schemas: &HashMap<String, Vec<String>>) -> SqlQuery;
 }
 
+/// The column that a `COUNT` counts, and whether the `COUNT` was rewritten from `COUNT(*)`.
+pub fn counted_column(col: &Column) -> (Column, bool) {
+    match col.function {
+        Some(box FunctionExpression::CountStar) => {
+            let col = Column {
+                function: None,
+                ..col.clone()
+            };
+            (col, true)
+        }
+        _ => (col.clone(), false),
+    }
+}
+
 fn extract_condition_columns(ce: &ConditionExpression) -> Vec<Column> {
     match *ce {
         ConditionExpression::LogicalOp(ConditionTree {
@@ -68,7 +89,7 @@ impl CountStarRewrite for SqlQuery {
                             name: bogo_column.clone(),
                             alias: None,
                             table: Some(bogo_table.name.clone()),
-                            function: None,
+                            function: Some(Box::new(CountStar)),
                         },
                         false,
                     )));
@@ -109,18 +130,25 @@ impl CountStarRewrite for SqlQuery {
 
 #[cfg(test)]
 mod tests {
-    use super::CountStarRewrite;
-    use nom_sql::{Column, FieldDefinitionExpression, SqlQuery};
+    use super::{counted_column, CountStarRewrite};
+    use nom_sql::{Column, FieldDefinitionExpression, FunctionExpression, SqlQuery};
     use std::collections::HashMap;
 
+    // the column that a rewritten COUNT(*) counts, with its marker
+    fn counted(name: &str) -> Column {
+        Column {
+            function: Some(Box::new(FunctionExpression::CountStar)),
+            ..Column::from(name)
+        }
+    }
+
     #[test]
     fn it_expands_count_star() {
         use nom_sql::parser::parse_query;
-        use nom_sql::FunctionExpression;
 
         // SELECT COUNT(*) FROM users;
         // -->
-        // SELECT COUNT(users.id) FROM users;
+        // SELECT COUNT(users.id) FROM users;, with users.id marked as counted for COUNT(*)
         let q = parse_query("SELECT COUNT(*) FROM users;").unwrap();
         let mut schema = HashMap::new();
         schema.insert(
@@ -138,11 +166,19 @@ mod tests {
                         alias: None,
                         table: None,
                         function: Some(Box::new(FunctionExpression::Count(
-                            Column::from("users.id"),
+                            counted("users.id"),
                             false,
                         ))),
                     })]
                 );
+                assert_eq!(
+                    counted_column(&counted("users.id")),
+                    (Column::from("users.id"), true)
+                );
+                assert_eq!(
+                    counted_column(&Column::from("users.id")),
+                    (Column::from("users.id"), false)
+                );
             }
             // if we get anything other than a selection query back, something really weird is up
             _ => panic!(),
@@ -152,7 +188,6 @@ mod tests {
     #[test]
     fn it_expands_count_star_with_group_by() {
         use nom_sql::parser::parse_query;
-        use nom_sql::FunctionExpression;
 
         // SELECT COUNT(*) FROM users GROUP BY id;
         // -->
@@ -174,7 +209,7 @@ mod tests {
                         alias: None,
                         table: None,
                         function: Some(Box::new(FunctionExpression::Count(
-                            Column::from("users.name"),
+                            counted("users.name"),
                             false,
                         ))),
                     })]
@@ -0,0 +1,165 @@
+//! Everything that a fresh controller needs to take over a running graph from another one.
+//!
+//! The domains keep running while the controller is replaced. The new controller connects to the
+//! workers and domains where the old one left them, checks that every domain shard still has the
+//! nodes that the exported graph places there, and only then has the domains send their control
+//! replies, and the workers their heartbeats, to it instead.
+//!
+//! The workers and domains are known by the addresses they were given when the graph was built,
+//! so the state can only be imported while those are still up.
+
+use crate::controller::migrate::graph_log::GraphLog;
+use crate::controller::migrate::materialization::MaterializationsState;
+use crate::controller::{Checkpoint, WorkerIdentifier};
+use bincode;
+use dataflow::prelude::*;
+use failure::{self, ResultExt};
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// A worker, and where to reach it.
+#[derive(Clone, Serialize, Deserialize)]
+pub(super) struct WorkerPlacement {
+    /// What the controller knows the worker by.
+    pub id: WorkerIdentifier,
+    /// Where the worker listens for the controller.
+    pub listen_addr: SocketAddr,
+    /// Where the worker serves reads.
+    pub read_addr: SocketAddr,
+}
+
+/// The workers that run the shards of a domain, and where the shards listen for packets.
+#[derive(Clone, Serialize, Deserialize)]
+pub(super) struct DomainPlacement {
+    pub index: DomainIndex,
+    pub shards: Vec<(WorkerIdentifier, SocketAddr)>,
+}
+
+/// The state of a controller that another controller can take over a running graph with. See
+/// `LocalControllerHandle::export_control_state`.
+///
+/// Besides the graph itself, this holds where every domain shard runs and how to reach it, what
+/// is materialized and along which replay paths, the log of the graph, the last checkpoint, and
+/// when the graph last changed.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ControlState {
+    pub(super) graph: Graph,
+    pub(super) source: NodeIndex,
+    pub(super) ndomains: usize,
+    pub(super) remap: HashMap<DomainIndex, HashMap<NodeIndex, IndexPair>>,
+    pub(super) sharding: Option<usize>,
+    pub(super) persistence: PersistenceParameters,
+    pub(super) materializations: MaterializationsState,
+    pub(super) graph_log: GraphLog,
+    pub(super) keys: HashMap<NodeIndex, String>,
+    pub(super) checkpoint: Option<Checkpoint>,
+    pub(super) topology_version: u64,
+    pub(super) last_migration: Option<SystemTime>,
+
+    pub(super) workers: Vec<WorkerPlacement>,
+    pub(super) domains: Vec<DomainPlacement>,
+}
+
+impl ControlState {
+    /// The version of the topology that was exported (see `TopologyDescription::version`).
+    pub fn topology_version(&self) -> u64 {
+        self.topology_version
+    }
+
+    /// When the last migration that changed the exported graph was committed, if any was.
+    pub fn last_migration(&self) -> Option<SystemTime> {
+        self.last_migration
+    }
+
+    /// Read state that was written to the given file.
+    pub fn read_from<P: AsRef<Path>>(path: P) -> Result<Self, failure::Error> {
+        let path = path.as_ref();
+        let f = fs::File::open(path)
+            .with_context(|_| format!("failed to open control state {}", path.display()))?;
+        Ok(bincode::deserialize_from(io::BufReader::new(f))
+            .with_context(|_| format!("failed to read control state {}", path.display()))?)
+    }
+
+    /// Write the state to the given file.
+    pub fn write_to<P: AsRef<Path>>(&self, path: P) -> Result<(), failure::Error> {
+        let path = path.as_ref();
+        let mut f = fs::File::create(path)
+            .with_context(|_| format!("failed to create control state {}", path.display()))?;
+        f.write_all(&bincode::serialize(self)?)?;
+        f.sync_all()?;
+        Ok(())
+    }
+}
+
+/// How the nodes that a shard of the given domain says it has differ from the nodes that `graph`
+/// places in that domain, one line per difference.
+pub(super) fn drift(
+    graph: &Graph,
+    domain: DomainIndex,
+    shard: usize,
+    nodes: Vec<(NodeIndex, LocalNodeIndex)>,
+) -> Vec<String> {
+    let expected: BTreeSet<_> = graph
+        .node_indices()
+        .filter(|&ni| {
+            let n = &graph[ni];
+            !n.is_source() && n.has_domain() && n.domain() == domain
+        })
+        .map(|ni| (ni, graph[ni].local_addr()))
+        .collect();
+    let actual: BTreeSet<_> = nodes.into_iter().collect();
+
+    let at = format!("domain {}.{}", domain.index(), shard);
+    let mut drift: Vec<_> = expected
+        .difference(&actual)
+        .map(|&(ni, local)| format!("{} does not have node {} (as {})", at, ni.index(), local))
+        .collect();
+    drift.extend(actual.difference(&expected).map(|&(ni, local)| {
+        format!(
+            "{} has node {} (as {}), which it should not",
+            at,
+            ni.index(),
+            local
+        )
+    }));
+    drift
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dataflow::node;
+
+    #[test]
+    fn it_lists_the_nodes_a_domain_should_and_should_not_have() {
+        let mut graph = Graph::new();
+        graph.add_node(node::Node::new("source", &["x"], node::special::Source));
+        let mut add = |name: &str, domain: usize, local: u32| {
+            let mut n = node::Node::new(name, &["x"], node::special::Base::default());
+            n.add_to(DomainIndex::from(domain));
+            let ni = graph.add_node(n);
+            let mut ip: IndexPair = ni.into();
+            ip.set_local(unsafe { LocalNodeIndex::make(local) });
+            graph[ni].set_finalized_addr(ip);
+            ni
+        };
+        let a = add("a", 0, 0);
+        let b = add("b", 0, 1);
+        let c = add("c", 1, 0);
+        let local = |i| unsafe { LocalNodeIndex::make(i) };
+
+        let d0 = DomainIndex::from(0);
+        assert!(drift(&graph, d0, 0, vec![(a, local(0)), (b, local(1))]).is_empty());
+        assert_eq!(
+            drift(&graph, d0, 1, vec![(a, local(0)), (c, local(1))]),
+            vec![
+                "domain 0.1 does not have node 2 (as l1)",
+                "domain 0.1 has node 3 (as l1), which it should not",
+            ]
+        );
+    }
+}
@@ -1,6 +1,7 @@
 use dataflow::prelude::*;
 use dataflow::DomainBuilder;
 use noria::consensus::Epoch;
+use noria::debug::stats::DomainFailure;
 use std::net::SocketAddr;
 
 /// Coordination-layer message wrapper; adds a mandatory `source` field to each message.
@@ -36,6 +37,13 @@ pub enum CoordinationPayload {
     RemoveDomain,
     /// Domain connectivity gossip.
     DomainBooted(DomainDescriptor),
+    /// A domain has moved to another worker, and is now reached at the given address.
+    DomainMoved(DomainDescriptor),
+    /// A domain on the worker has stopped processing, and won't process anything more.
+    DomainFailed(DomainFailure),
+    /// Another controller has taken over the domains that the worker runs, and listens for
+    /// workers on the given address.
+    TakeOver(SocketAddr),
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
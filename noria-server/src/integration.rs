@@ -8,8 +8,11 @@ use dataflow::ops::join::JoinSource::*;
 use dataflow::ops::join::{Join, JoinSource, JoinType};
 use dataflow::ops::project::Project;
 use dataflow::ops::union::Union;
-use dataflow::{DurabilityMode, PersistenceParameters};
+use dataflow::{DurabilityMode, IndexType, PersistenceParameters, ReplayPriority, StateBackend};
 use noria::consensus::LocalAuthority;
+use noria::debug::metrics::MetricsSnapshot;
+use noria::debug::plan::DomainStrategy;
+use noria::internal::DomainIndex;
 use noria::DataType;
 
 use std::collections::HashMap;
@@ -75,6 +78,30 @@ fn sleep() {
     thread::sleep(get_settle_time());
 }
 
+// Asserts that the view `name` holds exactly the given rows, in any order, by comparing checksums
+// rather than fetching the view's contents. Only keys that have been read count towards the
+// contents of a partially materialized view.
+fn assert_view_checksum(
+    g: &mut LocalControllerHandle<LocalAuthority>,
+    name: &str,
+    rows: &[Vec<DataType>],
+) {
+    assert_eq!(
+        g.view_checksum(name).unwrap(),
+        Some(noria::checksum(rows)),
+        "view {} does not hold the expected rows",
+        name
+    );
+}
+
+// Asserts that every fully materialized node holds the rows that its operator computes from the
+// rows in the base tables, once the writes so far have been processed.
+fn assert_consistent(g: &mut LocalControllerHandle<LocalAuthority>) {
+    sleep();
+    let report = g.check_consistency().unwrap();
+    assert!(report.is_consistent(), "{}", report);
+}
+
 #[test]
 fn it_works_basic() {
     // set up graph
@@ -152,6 +179,8 @@ fn it_works_basic() {
 
     // send a query to c
     //assert_eq!(cq.lookup(&[id.clone()], true), Ok(vec![vec![1.into(), 6.into()]]));
+
+    assert_consistent(&mut g);
 }
 
 #[test]
@@ -228,6 +257,8 @@ fn base_mutation() {
         read.lookup(&[1.into()], true).unwrap(),
         vec![vec![1.into(), 2.into()]]
     );
+
+    assert_consistent(&mut g);
 }
 
 #[test]
@@ -279,6 +310,8 @@ fn shared_interdomain_ancestor() {
         cq.lookup(&[id.clone()], true).unwrap(),
         vec![vec![id.clone(), 4.into()]]
     );
+
+    assert_consistent(&mut g);
 }
 
 #[test]
@@ -336,56 +369,5324 @@ fn it_works_w_mat() {
     assert!(res.iter().any(|r| r == &vec![id.clone(), 4.into()]));
     assert!(res.iter().any(|r| r == &vec![id.clone(), 5.into()]));
     assert!(res.iter().any(|r| r == &vec![id.clone(), 6.into()]));
+
+    assert_consistent(&mut g);
 }
 
 #[test]
-fn it_works_w_partial_mat() {
-    // set up graph
-    let mut g = build_local("it_works_w_partial_mat");
-    let (a, b) = g.migrate(|mig| {
-        let a = mig.add_base("a", &["a", "b"], Base::default());
-        let b = mig.add_base("b", &["a", "b"], Base::default());
-        (a, b)
+fn it_works_with_bytes() {
+    let mut g = build_local("it_works_with_bytes");
+    g.migrate(|mig| {
+        let a = mig.add_base("a", &["k", "v"], Base::default());
+        mig.maintain_anonymous(a, &[0]);
     });
 
+    let mut aq = g.view("a").unwrap();
     let mut muta = g.table("a").unwrap();
-    let id: DataType = 1.into();
+    let k: DataType = vec![0xca, 0xfe].into();
+    let v: DataType = vec![0u8; 64].into();
 
-    // send a few values on a
-    muta.insert(vec![id.clone(), 1.into()]).unwrap();
-    muta.insert(vec![id.clone(), 2.into()]).unwrap();
-    muta.insert(vec![id.clone(), 3.into()]).unwrap();
+    // binary values can be used both as keys and as values
+    muta.insert(vec![k.clone(), v.clone()]).unwrap();
+    sleep();
+    assert_eq!(
+        aq.lookup(&[k.clone()], true).unwrap(),
+        vec![vec![k.clone(), v.clone()]]
+    );
 
-    // give it some time to propagate
+    // values over the configured limit are rejected before they are sent
+    muta.set_max_value_size(Some(32));
+    match muta.insert(vec![vec![0xbe, 0xef].into(), v.clone()]) {
+        Err(noria::error::TableError::ValueTooLarge(ref col, 64, 32)) if col == "v" => {}
+        r => panic!("expected oversized value to be rejected, got {:?}", r),
+    }
+    muta.insert(vec![vec![0xbe, 0xef].into(), vec![1u8; 32].into()])
+        .unwrap();
+}
+
+#[test]
+fn it_rejects_oversized_values_in_base() {
+    use noria::error::{TableError, WriteError};
+    use noria::{ColumnSchema, ColumnType, Modification};
+
+    let mut g = build_local("it_rejects_oversized_values_in_base");
+    g.migrate(|mig| {
+        let schema = vec![
+            ColumnSchema::new("k", ColumnType::Int, false),
+            ColumnSchema::new("v", ColumnType::Bytes, true),
+        ];
+        let base = Base::default()
+            .with_key(vec![0])
+            .with_schema(schema)
+            .with_max_value_size(32);
+        let a = mig.add_base("a", &["k", "v"], base);
+        mig.maintain_anonymous(a, &[0]);
+    });
+
+    let mut aq = g.view("a").unwrap();
+    let mut muta = g.table("a").unwrap();
+    let big: DataType = vec![0u8; 64].into();
+
+    // tables pick up the base's limit, and turn oversized values away early
+    match muta.insert(vec![1.into(), big.clone()]) {
+        Err(TableError::ValueTooLarge(ref col, 64, 32)) if col == "v" => {}
+        r => panic!("expected oversized value to be rejected, got {:?}", r),
+    }
+
+    // but the base enforces its limit even if the table does not
+    muta.set_max_value_size(None);
+    match muta.insert(vec![1.into(), big.clone()]) {
+        Err(TableError::Rejected(WriteError::ValueTooLarge(ref col, 64, 32))) if col == "v" => {}
+        r => panic!("expected oversized value to be rejected, got {:?}", r),
+    }
+    match muta.update(vec![2.into()], vec![(1, Modification::Set(big.clone()))]) {
+        Err(TableError::Rejected(WriteError::ValueTooLarge(ref col, 64, 32))) if col == "v" => {}
+        r => panic!("expected oversized value to be rejected, got {:?}", r),
+    }
+
+    muta.insert(vec![1.into(), vec![1u8; 32].into()]).unwrap();
     sleep();
+    assert_eq!(
+        aq.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), vec![1u8; 32].into()]]
+    );
+}
 
-    let _ = g.migrate(move |mig| {
-        let mut emits = HashMap::new();
-        emits.insert(a, vec![0, 1]);
-        emits.insert(b, vec![0, 1]);
-        let u = Union::new(emits);
-        let c = mig.add_ingredient("c", &["a", "b"], u);
-        mig.maintain_anonymous(c, &[0]);
-        c
+#[test]
+fn it_rejects_writes_that_violate_base_schema() {
+    use noria::error::{TableError, WriteError};
+    use noria::{ColumnSchema, ColumnType};
+
+    let mut g = build_local("it_rejects_writes_that_violate_base_schema");
+    g.migrate(|mig| {
+        let schema = vec![
+            ColumnSchema::new("id", ColumnType::Int, false),
+            ColumnSchema::new("name", ColumnType::Text, true),
+        ];
+        let a = mig.add_base("a", &["id", "name"], Base::default().with_schema(schema));
+        mig.maintain_anonymous(a, &[0]);
     });
 
-    // give it some time to propagate
+    let mut aq = g.view("a").unwrap();
+    let mut muta = g.table("a").unwrap();
+    assert_eq!(
+        muta.base_schema().map(|cols| cols[1].clone()),
+        Some(ColumnSchema::new("name", ColumnType::Text, true))
+    );
+
+    match muta.insert(vec!["one".into(), "alice".into()]) {
+        Err(TableError::Rejected(WriteError::WrongType(
+            ref col,
+            ColumnType::Int,
+            ColumnType::Text,
+        ))) if col == "id" => {}
+        r => panic!("expected mistyped value to be rejected, got {:?}", r),
+    }
+    match muta.insert(vec![DataType::None, "alice".into()]) {
+        Err(TableError::Rejected(WriteError::NotNullable(ref col))) if col == "id" => {}
+        r => panic!("expected NULL value to be rejected, got {:?}", r),
+    }
+
+    // a rejected write must not affect the next one
+    muta.insert(vec![1.into(), DataType::None]).unwrap();
+    muta.insert(vec![2.into(), "bob".into()]).unwrap();
     sleep();
 
-    let mut cq = g.view("c").unwrap();
+    assert_eq!(
+        aq.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), DataType::None]]
+    );
+    assert_eq!(
+        aq.lookup(&[2.into()], true).unwrap(),
+        vec![vec![2.into(), "bob".into()]]
+    );
+}
 
-    // because the reader is partial, we should have no key until we read
-    assert_eq!(cq.len().unwrap(), 0);
+#[test]
+fn it_assigns_auto_increment_ids() {
+    let mut g = build_local("it_assigns_auto_increment_ids");
+    g.migrate(|mig| {
+        let b = Base::default().with_key(vec![0]).with_auto_increment(0);
+        let a = mig.add_base("a", &["id", "name"], b);
+        mig.maintain_anonymous(a, &[0]);
+    });
 
-    // now do some reads
-    let res = cq.lookup(&[id.clone()], true).unwrap();
-    assert_eq!(res.len(), 3);
-    assert!(res.iter().any(|r| r == &vec![id.clone(), 1.into()]));
-    assert!(res.iter().any(|r| r == &vec![id.clone(), 2.into()]));
-    assert!(res.iter().any(|r| r == &vec![id.clone(), 3.into()]));
+    let mut aq = g.view("a").unwrap();
+    let mut muta = g.table("a").unwrap();
+
+    let ids = muta
+        .insert_returning_ids(vec![
+            vec![DataType::None, "alice".into()],
+            vec![DataType::None, "bob".into()],
+        ])
+        .unwrap();
+    assert_eq!(ids, vec![1, 2]);
+    let ids = muta
+        .insert_returning_ids(vec![vec![DataType::None, "carol".into()]])
+        .unwrap();
+    assert_eq!(ids, vec![3]);
+    sleep();
+
+    // the assigned ids flow downstream like any other value
+    assert_eq!(
+        aq.lookup(&[2.into()], true).unwrap(),
+        vec![vec![2.into(), "bob".into()]]
+    );
+    assert_eq!(
+        aq.lookup(&[3.into()], true).unwrap(),
+        vec![vec![3.into(), "carol".into()]]
+    );
+}
+
+#[test]
+fn it_fills_in_default_values() {
+    use noria::{ColumnDefault, ColumnSchema, ColumnType};
+
+    let mut g = build_local("it_fills_in_default_values");
+    g.migrate(|mig| {
+        let schema = vec![
+            ColumnSchema::new("id", ColumnType::Int, false),
+            ColumnSchema::new("name", ColumnType::Text, true)
+                .with_default(ColumnDefault::Value("anon".into())),
+            ColumnSchema::new("karma", ColumnType::Int, false)
+                .with_default(ColumnDefault::Value(0.into())),
+        ];
+        let b = Base::default().with_key(vec![0]).with_schema(schema);
+        let a = mig.add_base("a", &["id", "name", "karma"], b);
+        mig.maintain_anonymous(a, &[0]);
+    });
+
+    let mut aq = g.view("a").unwrap();
+    let mut muta = g.table("a").unwrap();
+
+    // trailing columns can be left out
+    muta.insert(vec![1.into()]).unwrap();
+    muta.insert(vec![2.into(), "bob".into()]).unwrap();
+    // named writes can leave out any column with a default
+    muta.insert_named(vec![("id", 3.into()), ("karma", 5.into())])
+        .unwrap();
+    match muta.insert_named(vec![("name", "eve".into())]) {
+        Err(noria::error::TableError::Rejected(noria::error::WriteError::NoDefault(ref col)))
+            if col == "id" => {}
+        r => panic!("expected write without an id to be rejected, got {:?}", r),
+    }
+    sleep();
+
+    assert_eq!(
+        aq.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), "anon".into(), 0.into()]]
+    );
+    assert_eq!(
+        aq.lookup(&[2.into()], true).unwrap(),
+        vec![vec![2.into(), "bob".into(), 0.into()]]
+    );
+    assert_eq!(
+        aq.lookup(&[3.into()], true).unwrap(),
+        vec![vec![3.into(), "anon".into(), 5.into()]]
+    );
+}
+
+#[test]
+fn it_enforces_unique_keys() {
+    use dataflow::node::special::OnDuplicateKey;
+
+    let mut g = build_local("it_enforces_unique_keys");
+    g.migrate(|mig| {
+        let b = Base::default()
+            .with_key(vec![0])
+            .on_duplicate_key(OnDuplicateKey::Reject);
+        let a = mig.add_base("a", &["id", "v"], b);
+        mig.maintain_anonymous(a, &[0]);
+        let b = Base::default()
+            .with_key(vec![0])
+            .on_duplicate_key(OnDuplicateKey::Replace);
+        let b = mig.add_base("b", &["id", "v"], b);
+        mig.maintain_anonymous(b, &[0]);
+    });
+
+    let mut aq = g.view("a").unwrap();
+    let mut muta = g.table("a").unwrap();
+    muta.insert(vec![1.into(), "x".into()]).unwrap();
+    match muta.insert(vec![1.into(), "y".into()]) {
+        Err(noria::error::TableError::Rejected(noria::error::WriteError::DuplicateKey(ref k)))
+            if k == &[1.into()] => {}
+        r => panic!("expected duplicate key to be rejected, got {:?}", r),
+    }
+    sleep();
+    assert_eq!(
+        aq.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), "x".into()]]
+    );
+
+    let mut bq = g.view("b").unwrap();
+    let mut mutb = g.table("b").unwrap();
+    mutb.insert(vec![1.into(), "x".into()]).unwrap();
+    mutb.insert(vec![1.into(), "y".into()]).unwrap();
+    sleep();
+    assert_eq!(
+        bq.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), "y".into()]]
+    );
+}
+
+#[test]
+fn it_deletes_and_updates_by_key() {
+    use noria::Modification;
+
+    let mut g = build_local("it_deletes_and_updates_by_key");
+    g.migrate(|mig| {
+        let vote = mig.add_base(
+            "vote",
+            &["id", "article"],
+            Base::default().with_key(vec![0]),
+        );
+        let vc = mig.add_ingredient(
+            "votecount",
+            &["article", "votes"],
+            Aggregation::COUNT.over(vote, 0, &[1]),
+        );
+        mig.maintain_anonymous(vc, &[0]);
+    });
+
+    let mut vc = g.view("votecount").unwrap();
+    let mut vote = g.table("vote").unwrap();
+    for id in 1..4 {
+        vote.insert(vec![id.into(), 1.into()]).unwrap();
+    }
+    sleep();
+    assert_eq!(
+        vc.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 3.into()]]
+    );
+
+    // deleting a row retracts it from the count
+    vote.delete(vec![1.into()]).unwrap();
+    sleep();
+    assert_eq!(
+        vc.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 2.into()]]
+    );
+
+    // updating a row moves it between groups
+    vote.update(vec![2.into()], vec![(1, Modification::Set(2.into()))])
+        .unwrap();
+    sleep();
+    assert_eq!(
+        vc.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 1.into()]]
+    );
+    assert_eq!(
+        vc.lookup(&[2.into()], true).unwrap(),
+        vec![vec![2.into(), 1.into()]]
+    );
+
+    // deleting or updating a key that isn't there is an error
+    for r in vec![
+        vote.delete(vec![1.into()]),
+        vote.update(vec![4.into()], vec![(1, Modification::Set(1.into()))]),
+    ] {
+        match r {
+            Err(noria::error::TableError::Rejected(noria::error::WriteError::KeyNotFound(_))) => {}
+            r => panic!("expected missing key to be rejected, got {:?}", r),
+        }
+    }
+    sleep();
+    assert_eq!(
+        vc.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 1.into()]]
+    );
+
+    assert_consistent(&mut g);
+}
+
+#[test]
+fn it_modifies_single_columns() {
+    let mut g = build_local("it_modifies_single_columns");
+    g.migrate(|mig| {
+        let vote = mig.add_base(
+            "vote",
+            &["id", "article", "weight"],
+            Base::default().with_key(vec![0]),
+        );
+        let vc = mig.add_ingredient(
+            "votes",
+            &["article", "weight"],
+            Aggregation::SUM.over(vote, 2, &[1]),
+        );
+        mig.maintain_anonymous(vc, &[0]);
+    });
+
+    let mut vc = g.view("votes").unwrap();
+    let mut vote = g.table("vote").unwrap();
+    for id in 1..4 {
+        vote.insert(vec![id.into(), 1.into(), 1.into()]).unwrap();
+    }
+    sleep();
+    assert_eq!(
+        vc.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 3.into()]]
+    );
+
+    // modifying a column that isn't grouped by changes the row in its group
+    vote.modify(vec![1.into()], vec![(2, 5.into())]).unwrap();
+    sleep();
+    assert_eq!(
+        vc.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 7.into()]]
+    );
+
+    // modifying the column that is grouped by moves the row between groups
+    vote.modify(vec![1.into()], vec![(1, 2.into())]).unwrap();
+    sleep();
+    assert_eq!(
+        vc.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 2.into()]]
+    );
+    assert_eq!(
+        vc.lookup(&[2.into()], true).unwrap(),
+        vec![vec![2.into(), 5.into()]]
+    );
+
+    match vote.modify(vec![4.into()], vec![(2, 1.into())]) {
+        Err(noria::error::TableError::Rejected(noria::error::WriteError::KeyNotFound(_))) => {}
+        r => panic!("expected missing key to be rejected, got {:?}", r),
+    }
+
+    assert_consistent(&mut g);
+}
+
+#[test]
+fn it_works_with_compound_keys() {
+    let mut g = build_local("it_works_with_compound_keys");
+    g.migrate(|mig| {
+        let a = mig.add_base("a", &["x", "y", "z"], Base::default().with_key(vec![0, 1]));
+        mig.maintain_anonymous(a, &[0, 1]);
+        let wide = mig.add_base(
+            "wide",
+            &["c0", "c1", "c2", "c3", "c4", "c5", "c6", "v"],
+            Base::default(),
+        );
+        mig.maintain_anonymous(wide, &[0, 1, 2, 3, 4, 5, 6]);
+    });
+
+    let mut a = g.table("a").unwrap();
+    let mut ar = g.view("a").unwrap();
+    let mut w = g.table("wide").unwrap();
+    let mut wide = g.view("wide").unwrap();
+    a.insert(vec![1.into(), 2.into(), 3.into()]).unwrap();
+    a.insert(vec![1.into(), 3.into(), 4.into()]).unwrap();
+    let key: Vec<DataType> = (0..7).map(DataType::from).collect();
+    let mut row = key.clone();
+    row.push(1.into());
+    w.insert(row.clone()).unwrap();
+    row[6] = 42.into();
+    w.insert(row.clone()).unwrap();
+    sleep();
+
+    // both keys have to match for a row to be found
+    assert_eq!(
+        ar.lookup(&[1.into(), 2.into()], true).unwrap(),
+        vec![vec![1.into(), 2.into(), 3.into()]]
+    );
+    assert!(ar.lookup(&[2.into(), 1.into()], true).unwrap().is_empty());
+
+    // keys wider than six columns work too
+    let mut expected = key.clone();
+    expected.push(1.into());
+    assert_eq!(wide.lookup(&key, true).unwrap(), vec![expected]);
+
+    // deletes go by the full compound key
+    a.delete(vec![1.into(), 3.into()]).unwrap();
+    sleep();
+    assert!(ar.lookup(&[1.into(), 3.into()], true).unwrap().is_empty());
+    assert_eq!(
+        ar.lookup(&[1.into(), 2.into()], true).unwrap(),
+        vec![vec![1.into(), 2.into(), 3.into()]]
+    );
+
+    assert_consistent(&mut g);
+}
+
+#[test]
+fn it_looks_up_ranges_in_ordered_views() {
+    use std::ops::Bound;
+
+    let mut g = build_local("it_looks_up_ranges_in_ordered_views");
+    g.migrate(|mig| {
+        let scores = mig.add_base("scores", &["player", "score"], Base::default());
+        mig.maintain_with_index("by_score".into(), scores, &[1], IndexType::BTreeMap);
+        let players = mig.add_ingredient("players", &["player", "score"], Identity::new(scores));
+        mig.maintain("by_player".into(), players, &[0]);
+    });
+
+    let mut scores = g.table("scores").unwrap();
+    let mut by_score = g.view("by_score").unwrap();
+    let mut by_player = g.view("by_player").unwrap();
+    for &(player, score) in &[("a", 30), ("b", 10), ("c", 20), ("d", 20)] {
+        scores.insert(vec![player.into(), score.into()]).unwrap();
+    }
+    sleep();
+
+    let mut range = |lower, upper, limit| -> (Vec<i64>, bool) {
+        let (rows, truncated) = by_score.lookup_range(lower, upper, limit).unwrap();
+        let scores = rows.into_iter().map(|r| (&r[1]).into()).collect();
+        (scores, truncated)
+    };
+    assert_eq!(
+        range(Bound::Included(10.into()), Bound::Included(20.into()), None),
+        (vec![10, 20, 20], false)
+    );
+    assert_eq!(
+        range(Bound::Excluded(10.into()), Bound::Unbounded, None),
+        (vec![20, 20, 30], false)
+    );
+    assert_eq!(
+        range(Bound::Excluded(20.into()), Bound::Excluded(30.into()), None),
+        (vec![], false)
+    );
+    assert_eq!(
+        range(Bound::Included(30.into()), Bound::Included(10.into()), None),
+        (vec![], false)
+    );
+
+    // limits cut the range short, and say so
+    assert_eq!(
+        range(Bound::Unbounded, Bound::Unbounded, Some(2)),
+        (vec![10, 20], true)
+    );
+    assert_eq!(
+        range(Bound::Included(20.into()), Bound::Unbounded, Some(3)),
+        (vec![20, 20, 30], false)
+    );
+
+    // views with a hash index can't do range lookups
+    match by_player.lookup_range(Bound::Unbounded, Bound::Unbounded, None) {
+        Err(noria::error::ViewError::NotOrdered) => {}
+        r => panic!("expected range lookup to be rejected, got {:?}", r),
+    }
+}
+
+#[test]
+fn it_multi_looks_up_keys_in_order() {
+    let mut g = build_local("it_multi_looks_up_keys_in_order");
+    g.migrate(|mig| {
+        let a = mig.add_base("a", &["id", "x"], Base::default());
+        mig.maintain("by_id".into(), a, &[0]);
+    });
+
+    let mut a = g.table("a").unwrap();
+    let mut by_id = g.view("by_id").unwrap();
+    for i in 0..10 {
+        a.insert(vec![i.into(), (i * 10).into()]).unwrap();
+    }
+    sleep();
+
+    // results come back in the order of the keys, even though the keys are spread across shards,
+    // and keys with no rows get an empty result
+    let keys: Vec<i64> = vec![7, 3, 42, 0, 8, 3];
+    let results = by_id
+        .multi_lookup(keys.iter().map(|&k| vec![k.into()]).collect(), true)
+        .unwrap();
+    assert_eq!(results.len(), keys.len());
+    for (k, rs) in keys.into_iter().zip(results) {
+        if k < 10 {
+            assert_eq!(rs, vec![vec![k.into(), (k * 10).into()]]);
+        } else {
+            assert!(rs.is_empty());
+        }
+    }
+}
+
+#[test]
+fn it_counts_rows_without_reading_them() {
+    let mut g = build_local("it_counts_rows_without_reading_them");
+    g.migrate(|mig| {
+        let votes = mig.add_base("votes", &["aid", "uid"], Base::default());
+        let by_aid = mig.add_ingredient("by_aid", &["aid", "uid"], Identity::new(votes));
+        mig.maintain("votes_by_aid".into(), by_aid, &[0]);
+    });
+
+    let mut votes = g.table("votes").unwrap();
+    let mut view = g.view("votes_by_aid").unwrap();
+    for uid in 0..10 {
+        votes.insert(vec![1.into(), uid.into()]).unwrap();
+    }
+    for uid in 0..3 {
+        votes.insert(vec![2.into(), uid.into()]).unwrap();
+    }
+    sleep();
+
+    // blocking counts wait for missing keys to be replayed, just like blocking lookups
+    assert_eq!(view.count(&[1.into()], true).unwrap(), 10);
+    assert!(view.exists(&[2.into()], true).unwrap());
+    assert!(!view.exists(&[3.into()], true).unwrap());
+
+    // the counts come back in the order of the keys, even though the keys are spread across shards
+    let keys: Vec<i64> = vec![2, 3, 1, 2];
+    let keys = keys.into_iter().map(|k| vec![k.into()]).collect();
+    assert_eq!(view.count_multi(keys, true).unwrap(), vec![3, 0, 10, 3]);
+
+    // keys that have already been read are kept up to date
+    votes.insert(vec![1.into(), 10.into()]).unwrap();
+    votes.insert(vec![3.into(), 0.into()]).unwrap();
+    sleep();
+    assert_eq!(view.count(&[1.into()], false).unwrap(), 11);
+    assert!(view.exists(&[3.into()], false).unwrap());
+}
+
+#[test]
+fn it_reads_projected_columns() {
+    use noria::error::ViewError;
+    use noria::Direction;
+
+    let mut g = build_local("it_reads_projected_columns");
+    g.migrate(|mig| {
+        let cols = &["author", "id", "title", "score"];
+        let posts = mig.add_base("posts", cols, Base::default());
+        let by_author = mig.add_ingredient("by_author", cols, Identity::new(posts));
+        mig.maintain("posts_by_author".into(), by_author, &[0]);
+    });
+
+    let mut posts = g.table("posts").unwrap();
+    let mut view = g.view("posts_by_author").unwrap();
+    for id in 0i64..10 {
+        let title = format!("post {}", id);
+        let row: Vec<DataType> = vec![(id % 2).into(), id.into(), title.into(), (10 - id).into()];
+        posts.insert(row).unwrap();
+    }
+    sleep();
+
+    // columns come back in the order they were asked for
+    let mut rows = view.lookup_projected(&[1.into()], &[3, 1], true).unwrap();
+    rows.sort();
+    let expected: Vec<Vec<DataType>> = vec![
+        vec![1.into(), 9.into()],
+        vec![3.into(), 7.into()],
+        vec![5.into(), 5.into()],
+        vec![7.into(), 3.into()],
+        vec![9.into(), 1.into()],
+    ];
+    assert_eq!(rows, expected);
+
+    // asking for every column is the same as not projecting at all
+    let mut whole = view.lookup(&[0.into()], true).unwrap();
+    let mut all = view
+        .lookup_projected(&[0.into()], &[0, 1, 2, 3], true)
+        .unwrap();
+    whole.sort();
+    all.sort();
+    assert_eq!(whole, all);
+
+    // projection composes with multi-key reads across shards
+    let keys = vec![vec![1.into()], vec![2.into()], vec![0.into()]];
+    let results = view.multi_lookup_projected(keys, &[0], true).unwrap();
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[0], vec![vec![DataType::from(1)]; 5]);
+    assert!(results[1].is_empty());
+    assert_eq!(results[2], vec![vec![DataType::from(0)]; 5]);
+
+    // and with pagination, which can order by a column that isn't returned
+    let (page, total) = view
+        .lookup_page_projected(&[0.into()], (3, Direction::Descending), 1, 2, &[2])
+        .unwrap();
+    let expected: Vec<Vec<DataType>> = vec![vec!["post 2".into()], vec!["post 4".into()]];
+    assert_eq!(page, expected);
+    assert_eq!(total, 5);
+
+    // columns the view doesn't have are rejected
+    match view.lookup_projected(&[0.into()], &[1, 4], true) {
+        Err(ViewError::NoSuchColumn(4)) => {}
+        r => panic!("expected missing column, got {:?}", r),
+    }
+}
+
+#[test]
+fn it_never_reports_reads_as_fresher_than_they_are() {
+    use std::time::SystemTime;
+
+    let mut g = build_local("it_never_reports_reads_as_fresher_than_they_are");
+    g.migrate(|mig| {
+        let log = mig.add_base("log", &["id", "seq"], Base::default());
+        let by_id = mig.add_ingredient("by_id", &["id", "seq"], Identity::new(log));
+        mig.maintain("log_by_id".into(), by_id, &[0]);
+    });
+
+    let mut log = g.table("log").unwrap();
+    let mut view = g.view("log_by_id").unwrap();
+    let (rows, meta) = view.lookup_with_meta(&[1.into()], true).unwrap();
+    assert!(rows.is_empty());
+    assert_eq!(meta.written, None);
+    assert!(!meta.full);
+
+    // read while writing, and remember when each write was acknowledged
+    let n: i64 = 200;
+    let mut acked = Vec::new();
+    let mut reads = Vec::new();
+    for seq in 0..n {
+        log.insert(vec![1.into(), seq.into()]).unwrap();
+        acked.push(SystemTime::now());
+        reads.push(view.lookup_with_meta(&[1.into()], seq % 2 == 0).unwrap());
+    }
+    sleep();
+    reads.push(view.lookup_with_meta(&[1.into()], true).unwrap());
+
+    // every write that had been acknowledged by the time of the most recent write that a read
+    // claims to include is in the rows it returned
+    for (rows, meta) in reads {
+        let written = match meta.written {
+            Some(written) => written,
+            None => continue,
+        };
+        let seqs: Vec<i64> = rows
+            .into_iter()
+            .map(|r| -> i64 { (&r[1]).into() })
+            .collect();
+        for (seq, &acked) in acked.iter().enumerate() {
+            if acked <= written {
+                assert!(seqs.contains(&(seq as i64)), "missing write {}", seq);
+            }
+        }
+    }
+
+    // once everything has propagated, the last write but one is known to be included
+    let (rows, meta) = view.lookup_with_meta(&[1.into()], true).unwrap();
+    assert_eq!(rows.len(), n as usize);
+    assert!(meta.written.unwrap() >= acked[n as usize - 2]);
+}
+
+#[test]
+fn it_publishes_writes_as_views_ask() {
+    use dataflow::PublishPolicy;
+    use std::time::SystemTime;
+
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(None);
+    g.set_persistence(get_persistence_params("it_publishes_writes_as_views_ask"));
+    let mut g = g.build_local().unwrap();
+    g.migrate(|mig| {
+        let log = mig.add_base("log", &["id", "seq"], Base::default());
+        let eager = mig.add_ingredient("eager", &["id", "seq"], Identity::new(log));
+        mig.maintain("eager".into(), eager, &[0]);
+        let lazy = mig.add_ingredient("lazy", &["id", "seq"], Identity::new(log));
+        mig.maintain("lazy".into(), lazy, &[0]);
+        mig.set_publish_policy(lazy, PublishPolicy::Interval(Duration::from_secs(3600)));
+    });
+
+    let mut log = g.table("log").unwrap();
+    let mut eager = g.view("eager").unwrap();
+    let mut lazy = g.view("lazy").unwrap();
+    let (_, ready) = lazy.lookup_with_meta(&[1.into()], true).unwrap();
+    let before = SystemTime::now();
+    assert!(ready.published.unwrap() <= before);
+
+    log.insert(vec![1.into(), 1.into()]).unwrap();
+    sleep();
+
+    // by default, a view publishes writes as soon as its domain has nothing else to do
+    let (rows, meta) = eager.lookup_with_meta(&[1.into()], true).unwrap();
+    assert_eq!(rows.len(), 1);
+    assert!(meta.published.unwrap() >= before);
+
+    // but one that publishes at most once an hour is still showing what it had when it was ready
+    let (rows, meta) = lazy.lookup_with_meta(&[1.into()], true).unwrap();
+    assert!(rows.is_empty());
+    assert_eq!(meta.published, ready.published);
+}
+
+#[test]
+fn it_paginates_while_writing() {
+    use noria::Direction;
+
+    let mut g = build_local("it_paginates_while_writing");
+    g.migrate(|mig| {
+        let posts = mig.add_base("posts", &["author", "id", "score"], Base::default());
+        mig.maintain("by_author".into(), posts, &[0]);
+        let sorted = mig.add_ingredient("sorted", &["author", "id", "score"], Identity::new(posts));
+        mig.maintain("sorted_by_author".into(), sorted, &[0]);
+        mig.keep_sorted(sorted, 2);
+    });
+
+    // lots of posts by one author, with lots of ties in score
+    let n: i64 = 500;
+    let mut posts = g.table("posts").unwrap();
+    let rows: Vec<Vec<DataType>> = (0..n)
+        .map(|i| vec![1.into(), i.into(), (i % 50).into()])
+        .collect();
+    posts.insert_all(rows).unwrap();
+    sleep();
+
+    // ties are broken by the rest of the row
+    let mut expected: Vec<(i64, i64)> = (0..n).map(|i| (i % 50, i)).collect();
+    expected.sort_by(|a, b| b.cmp(a));
+    let expected: Vec<i64> = expected.into_iter().map(|(_, id)| id).collect();
+
+    let mut next = n;
+    for view in &["by_author", "sorted_by_author"] {
+        let mut view = g.view(view).unwrap();
+        let mut seen: Vec<i64> = Vec::new();
+        let mut offset = 0;
+        loop {
+            let (page, total) = view
+                .lookup_page(&[1.into()], (2, Direction::Descending), offset, 25)
+                .unwrap();
+            assert!(total >= n as usize);
+            if page.is_empty() {
+                break;
+            }
+            offset += page.len();
+            seen.extend(page.into_iter().map(|r| -> i64 { (&r[1]).into() }));
+
+            // neither posts by other authors nor posts that sort after the ones already seen move
+            // anything between pages
+            posts
+                .insert(vec![2.into(), next.into(), 100.into()])
+                .unwrap();
+            posts
+                .insert(vec![1.into(), next.into(), (-next).into()])
+                .unwrap();
+            next += 1;
+        }
+
+        let mut unique = seen.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), seen.len());
+        let original: Vec<i64> = seen.into_iter().filter(|&id| id < n).collect();
+        assert_eq!(original, expected);
+    }
+}
+
+#[test]
+fn it_scans_a_view_while_writing() {
+    use noria::error::ViewError;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    let mut g = build_local("it_scans_a_view_while_writing");
+    g.migrate(|mig| {
+        let a = mig.add_base("a", &["id", "x"], Base::new(vec![]).with_key(vec![0]));
+        mig.maintain("unscannable".into(), a, &[0]);
+        let scannable = mig.add_ingredient("scannable", &["id", "x"], Identity::new(a));
+        mig.maintain("scannable".into(), scannable, &[0]);
+        mig.allow_scans(scannable);
+    });
+
+    // the rows with even ids stay put for the whole scan
+    let n: i64 = 2_000;
+    let mut table = g.table("a").unwrap().into_exclusive().unwrap();
+    let rows: Vec<Vec<DataType>> = (0..n).map(|i| vec![(2 * i).into(), i.into()]).collect();
+    table.insert_all(rows).unwrap();
+    sleep();
+
+    match g.view("unscannable").unwrap().scan(100).next() {
+        Some(Err(ViewError::NotScannable)) => {}
+        r => panic!("expected scan to be refused, got {:?}", r),
+    }
+
+    // while rows with odd ids, which come between them, keep being added and removed
+    let started = Arc::new(AtomicBool::new(false));
+    let done = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let started = started.clone();
+        let done = done.clone();
+        thread::spawn(move || {
+            let mut i = 0;
+            while !done.load(Ordering::SeqCst) {
+                let id = DataType::from(2 * (i % n) + 1);
+                table.insert(vec![id.clone(), i.into()]).unwrap();
+                table.delete(vec![id]).unwrap();
+                started.store(true, Ordering::SeqCst);
+                i += 1;
+            }
+        })
+    };
+    while !started.load(Ordering::SeqCst) {
+        thread::yield_now();
+    }
+
+    let mut view = g.view("scannable").unwrap();
+    let mut ids: Vec<i64> = Vec::new();
+    let mut batches = 0;
+    for batch in view.scan(100) {
+        let batch = batch.unwrap();
+        batches += 1;
+        ids.extend(batch.into_iter().map(|r| -> i64 { (&r[0]).into() }));
+    }
+    done.store(true, Ordering::SeqCst);
+    writer.join().unwrap();
+
+    // every stable row was returned exactly once. every key has a single row, so no batch holds
+    // more rows than were asked for.
+    assert!(batches >= n as usize / 100);
+    let mut stable: Vec<i64> = ids.iter().cloned().filter(|id| id % 2 == 0).collect();
+    stable.sort();
+    assert_eq!(stable, (0..n).map(|i| 2 * i).collect::<Vec<_>>());
+    let mut unique = ids.clone();
+    unique.sort();
+    unique.dedup();
+    assert_eq!(unique.len(), ids.len());
+}
+
+#[test]
+fn it_only_returns_rows_that_pass_a_views_filter() {
+    use noria::error::ViewError;
+    use noria::filter::{FilterCondition, Operator, RowFilter, Value};
+    use noria::Direction;
+    use std::ops::Bound;
+
+    let mut g = build_local("it_only_returns_rows_that_pass_a_views_filter");
+    g.migrate(|mig| {
+        let posts = mig.add_base("posts", &["id", "tenant", "topic"], Base::default());
+        mig.maintain("by_topic".into(), posts, &[2]);
+        mig.maintain_with_index("by_id".into(), posts, &[0], IndexType::BTreeMap);
+        let all = mig.add_ingredient("all", &["id", "tenant", "topic"], Identity::new(posts));
+        mig.maintain("all".into(), all, &[2]);
+        mig.allow_scans(all);
+    });
+
+    // tenant 1 has the posts with ids 1, 4, .., 28, of which 4, 10, .., 28 are about topic 0
+    let mut posts = g.table("posts").unwrap();
+    let rows: Vec<Vec<DataType>> = (0..30)
+        .map(|i: i64| vec![i.into(), (i % 3).into(), (i % 2).into()])
+        .collect();
+    posts.insert_all(rows).unwrap();
+    sleep();
+
+    let tenant = RowFilter::equal(1, 1);
+    let ids = |rows: &[Vec<DataType>]| -> Vec<i64> {
+        assert!(rows.iter().all(|r| r[1] == 1.into()), "{:?}", rows);
+        let mut ids: Vec<i64> = rows.iter().map(|r| (&r[0]).into()).collect();
+        ids.sort();
+        ids
+    };
+    let (ours, ours_on_0) = (
+        vec![1, 4, 7, 10, 13, 16, 19, 22, 25, 28],
+        vec![4, 10, 16, 22, 28],
+    );
+
+    let mut by_topic = g.view_filtered("by_topic", tenant.clone()).unwrap();
+    assert_eq!(by_topic.filter(), Some(&tenant));
+    assert_eq!(ids(&by_topic.lookup(&[0.into()], true).unwrap()), ours_on_0);
+    let both = by_topic
+        .multi_lookup(vec![vec![0.into()], vec![1.into()]], true)
+        .unwrap();
+    assert_eq!(ids(&both.concat()), ours);
+    let (rows, _) = by_topic.lookup_with_meta(&[0.into()], true).unwrap();
+    assert_eq!(ids(&rows), ours_on_0);
+    assert_eq!(by_topic.count(&[0.into()], true).unwrap(), 5);
+    assert_eq!(by_topic.len().unwrap(), 2);
+
+    // the filter is on a column that isn't returned
+    let projected = by_topic.lookup_projected(&[0.into()], &[0], true).unwrap();
+    let mut projected: Vec<i64> = projected.iter().map(|r| (&r[0]).into()).collect();
+    projected.sort();
+    assert_eq!(projected, ours_on_0);
+
+    // pages, ranges, and scans only count the rows that pass
+    let (page, total) = by_topic
+        .lookup_page(&[0.into()], (0, Direction::Ascending), 1, 2)
+        .unwrap();
+    assert_eq!((ids(&page), total), (vec![10, 16], 5));
+    let mut by_id = g.view_filtered("by_id", tenant.clone()).unwrap();
+    let (rows, truncated) = by_id
+        .lookup_range(Bound::Excluded(1.into()), Bound::Unbounded, Some(3))
+        .unwrap();
+    assert_eq!((ids(&rows), truncated), (vec![4, 7, 10], true));
+    let mut all = g.view_filtered("all", tenant.clone()).unwrap();
+    let scanned: Vec<Vec<DataType>> = all.scan(1).map(|batch| batch.unwrap()).flatten().collect();
+    assert_eq!(ids(&scanned), ours);
+
+    // handles made from a filtered view keep its filter, and it can only be narrowed
+    let mut exclusive = by_topic.clone().into_exclusive().unwrap();
+    let rows = exclusive.lookup(&[1.into()], true).unwrap();
+    assert_eq!(ids(&rows), vec![1, 7, 13, 19, 25]);
+    let mut narrower = by_topic.filtered(RowFilter::equal(2, 0)).unwrap();
+    assert!(narrower.lookup(&[1.into()], true).unwrap().is_empty());
+    assert!(!narrower.exists(&[1.into()], true).unwrap());
+    match g.view("by_topic").unwrap().filtered(RowFilter::equal(3, 1)) {
+        Err(ViewError::NoSuchColumn(3)) => {}
+        Err(e) => panic!("expected filter to be refused for its column, got {:?}", e),
+        Ok(_) => panic!("filter on a column the view doesn't have was accepted"),
+    }
+    let like = RowFilter::new(vec![(
+        2,
+        FilterCondition::Comparison(Operator::Like, Value::Constant("a%".into())),
+    )]);
+    match g.view("by_topic").unwrap().filtered(like) {
+        Err(ViewError::UnsupportedOperator(Operator::Like)) => {}
+        Err(e) => panic!("expected filter to be refused for its operator, got {:?}", e),
+        Ok(_) => panic!("filter with an operator that can't compare values was accepted"),
+    }
+
+    // a view that no rows pass is empty, but the same view unfiltered is not
+    let mut nobody = g.view_filtered("by_topic", RowFilter::equal(1, 5)).unwrap();
+    assert_eq!(nobody.len().unwrap(), 0);
+    assert!(nobody.lookup(&[0.into()], true).unwrap().is_empty());
+    let mut everyone = g.view("by_topic").unwrap();
+    assert_eq!(everyone.count(&[0.into()], true).unwrap(), 15);
+
+    // later writes are filtered too
+    posts.insert(vec![30.into(), 0.into(), 0.into()]).unwrap();
+    posts.insert(vec![31.into(), 1.into(), 0.into()]).unwrap();
+    sleep();
+    let mut ours_on_0 = ours_on_0;
+    ours_on_0.push(31);
+    assert_eq!(ids(&narrower.lookup(&[0.into()], true).unwrap()), ours_on_0);
+}
+
+#[test]
+fn it_blocks_reads_until_view_is_ready() {
+    use noria::builders::ViewBuilder;
+    use noria::error::ViewError;
+    use std::sync::mpsc;
+
+    // the new view must be fully materialized, so that it isn't ready until it has been replayed
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(None);
+    g.set_persistence(get_persistence_params(
+        "it_blocks_reads_until_view_is_ready",
+    ));
+    let mut g = g.build_local().unwrap();
+    let a = g.migrate(|mig| {
+        let a = mig.add_base("a", &["id", "x"], Base::default());
+        mig.maintain("early".into(), a, &[0]);
+        a
+    });
+
+    // give the view we add next plenty to replay
+    let n: i64 = 20_000;
+    let mut table = g.table("a").unwrap();
+    let rows: Vec<Vec<DataType>> = (0..n).map(|i| vec![i.into(), i.into()]).collect();
+    for chunk in rows.chunks(1000) {
+        table.insert_all(chunk.to_vec()).unwrap();
+    }
+    sleep();
+
+    // the controller won't hand out the new view until the migration has finished, so build it
+    // from the existing one, which lives on the same worker
+    let early = g
+        .rpc::<_, Option<ViewBuilder>>("view_builder", "early")
+        .unwrap()
+        .unwrap();
+    let (tx, rx) = mpsc::channel();
+    let reader = thread::spawn(move || {
+        let node = rx.recv().unwrap();
+        let mut late = ViewBuilder { node, ..early }.build_exclusive().unwrap();
+        let key = vec![DataType::from(n - 1)];
+
+        match late.lookup(&key, false) {
+            Err(ViewError::NotYetAvailable) => {}
+            r => panic!("expected view to not be ready yet, got {:?}", r),
+        }
+        late.set_ready_timeout(Some(Duration::from_millis(1)));
+        match late.lookup(&key, true) {
+            Err(ViewError::NotYetAvailable) => {}
+            r => panic!("expected blocking read to time out, got {:?}", r),
+        }
+        late.set_ready_timeout(None);
+        late.lookup(&key, true).unwrap()
+    });
+
+    g.migrate(move |mig| {
+        let late = mig.add_ingredient("late", &["id", "x"], Identity::new(a));
+        tx.send(mig.maintain_anonymous(late, &[0])).unwrap();
+    });
+    assert_eq!(
+        reader.join().unwrap(),
+        vec![vec![(n - 1).into(), (n - 1).into()]]
+    );
+}
+
+#[test]
+fn it_exposes_new_views_together() {
+    use dataflow::ops::filter::{Filter, FilterCondition, Operator, Value};
+    use noria::builders::ViewBuilder;
+    use noria::error::ViewError;
+    use std::sync::mpsc;
+
+    // the new views must be fully materialized, so that they aren't ready until they have been
+    // replayed, and unsharded, so that they end up in the same domain as their base
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(None);
+    g.set_persistence(get_persistence_params("it_exposes_new_views_together"));
+    let mut g = g.build_local().unwrap();
+    let a = g.migrate(|mig| {
+        let a = mig.add_base("a", &["id", "x"], Base::default());
+        mig.maintain("early".into(), a, &[0]);
+        a
+    });
+
+    // give the big view plenty to replay, and the small one very little
+    let n: i64 = 20_000;
+    let mut table = g.table("a").unwrap();
+    let rows: Vec<Vec<DataType>> = (0..n).map(|i| vec![i.into(), i.into()]).collect();
+    for chunk in rows.chunks(1000) {
+        table.insert_all(chunk.to_vec()).unwrap();
+    }
+    sleep();
+
+    let early = g
+        .rpc::<_, Option<ViewBuilder>>("view_builder", "early")
+        .unwrap()
+        .unwrap();
+    let (tx, rx) = mpsc::channel();
+    let reader = thread::spawn(move || {
+        let (small, big) = rx.recv().unwrap();
+        let mut small = ViewBuilder {
+            node: small,
+            ..early.clone()
+        }
+        .build_exclusive()
+        .unwrap();
+        let mut big = ViewBuilder { node: big, ..early }
+            .build_exclusive()
+            .unwrap();
+
+        // as soon as the small view answers, so must the big one
+        loop {
+            match small.lookup(&[0.into()], false) {
+                Err(ViewError::NotYetAvailable) => thread::yield_now(),
+                r => {
+                    assert_eq!(r.unwrap(), vec![vec![0.into(), 0.into()]]);
+                    break;
+                }
+            }
+        }
+        big.lookup(&[(n - 1).into()], false).unwrap()
+    });
+
+    g.migrate(move |mig| {
+        mig.expose_atomically();
+        let cond = FilterCondition::Comparison(Operator::Less, Value::Constant(10.into()));
+        let small = mig.add_ingredient("small", &["id", "x"], Filter::new(a, &[None, Some(cond)]));
+        let big = mig.add_ingredient("big", &["id", "x"], Identity::new(a));
+        let small = mig.maintain_anonymous(small, &[0]);
+        let big = mig.maintain_anonymous(big, &[0]);
+        tx.send((small, big)).unwrap();
+    });
+    assert_eq!(
+        reader.join().unwrap(),
+        vec![vec![(n - 1).into(), (n - 1).into()]]
+    );
+}
+
+#[test]
+fn it_rolls_back_failed_migrations() {
+    // the new view must be fully materialized, so that it has to be replayed
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(None);
+    g.set_persistence(get_persistence_params("it_rolls_back_failed_migrations"));
+    let mut g = g.build_local().unwrap();
+    let a = g.migrate(|mig| {
+        let a = mig.add_base("a", &["id", "x"], Base::default());
+        mig.maintain("early".into(), a, &[0]);
+        a
+    });
+
+    let mut table = g.table("a").unwrap();
+    let mut early = g.view("early").unwrap();
+    table.insert(vec![1.into(), 2.into()]).unwrap();
+    sleep();
+
+    // the new view gets a domain of its own, which dies before the view has been replayed
+    let err = g
+        .try_migrate(move |mig| {
+            let late = mig.add_ingredient("BOUNDARY_late", &["id", "x"], Identity::new(a));
+            mig.maintain("late".into(), late, &[0]);
+            mig.crash_before_replay(late);
+        })
+        .unwrap_err();
+    assert!(err.contains("rolled back"), "unexpected error: {}", err);
+    assert!(!g.outputs().unwrap().contains_key("late"));
+
+    // the old view is unaffected
+    table.insert(vec![3.into(), 4.into()]).unwrap();
+    sleep();
+    assert_eq!(
+        early.lookup(&[3.into()], true).unwrap(),
+        vec![vec![3.into(), 4.into()]]
+    );
+
+    // and the new view can be added after all
+    g.migrate(move |mig| {
+        let late = mig.add_ingredient("late", &["id", "x"], Identity::new(a));
+        mig.maintain("late".into(), late, &[0]);
+    });
+    let mut late = g.view("late").unwrap();
+    assert_eq!(
+        late.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 2.into()]]
+    );
+    assert_eq!(
+        late.lookup(&[3.into()], true).unwrap(),
+        vec![vec![3.into(), 4.into()]]
+    );
+}
+
+#[test]
+fn it_rolls_back_migrations_that_a_domain_refuses() {
+    use dataflow::Placement;
+
+    let mut g = ControllerBuilder::default();
+    g.set_sharding(None);
+    g.set_persistence(get_persistence_params(
+        "it_rolls_back_migrations_that_a_domain_refuses",
+    ));
+    let mut g = g.build_local().unwrap();
+    let a = g.migrate(|mig| {
+        let a = mig.add_base("a", &["id", "x"], Base::default());
+        mig.maintain("early".into(), a, &[0]);
+        a
+    });
+
+    let mut table = g.table("a").unwrap();
+    let mut early = g.view("early").unwrap();
+    table.insert(vec![1.into(), 2.into()]).unwrap();
+    sleep();
+
+    // the new node goes in the domain of the base, which has forgotten it by the time it is told
+    // to ready it, and says so
+    let err = g
+        .try_migrate(move |mig| {
+            let gone = mig.add_ingredient("gone", &["id", "x"], Identity::new(a));
+            mig.place(gone, Placement::With(a));
+            mig.remove_before_ready(gone);
+        })
+        .unwrap_err();
+    assert!(err.contains("ready node"), "unexpected error: {}", err);
+    assert!(err.contains("NoSuchNode"), "unexpected error: {}", err);
+    assert!(err.contains("rolled back"), "unexpected error: {}", err);
+
+    // the domain keeps going, and later migrations don't trip over leftover replies
+    table.insert(vec![3.into(), 4.into()]).unwrap();
+    sleep();
+    assert_eq!(
+        early.lookup(&[3.into()], true).unwrap(),
+        vec![vec![3.into(), 4.into()]]
+    );
+    g.migrate(move |mig| {
+        let late = mig.add_ingredient("late", &["id", "x"], Identity::new(a));
+        mig.maintain("late".into(), late, &[0]);
+    });
+    let mut late = g.view("late").unwrap();
+    assert_eq!(
+        late.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 2.into()]]
+    );
+}
+
+#[test]
+fn it_plans_migrations_without_running_them() {
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(None);
+    g.set_persistence(get_persistence_params(
+        "it_plans_migrations_without_running_them",
+    ));
+    let mut g = g.build_local().unwrap();
+    g.install_recipe(
+        "CREATE TABLE a (id int, x int);
+         QUERY early: SELECT id, x FROM a WHERE id = ?;",
+    )
+    .unwrap();
+
+    let a = g.inputs().unwrap()["a"];
+    let mut table = g.table("a").unwrap();
+    for i in 0..3 {
+        table.insert(vec![i.into(), 1.into()]).unwrap();
+    }
+    sleep();
+
+    let before = g.graphviz().unwrap();
+    let late = "QUERY late: SELECT id, x FROM a WHERE x = ?;";
+    let plan = g.plan_recipe(late).unwrap();
+
+    // the new view is planned, and has to be filled by replaying the base
+    let reader = plan.node("late").unwrap();
+    assert!(plan.materialization(reader.node).unwrap().new);
+    assert!(plan
+        .replays
+        .iter()
+        .any(|r| r.target == reader.node && r.path[0] == a && r.estimated_rows == Some(3)));
+    assert!(plan.to_string().contains("late"));
+
+    // but nothing has changed
+    assert_eq!(g.graphviz().unwrap(), before);
+    assert!(!g.outputs().unwrap().contains_key("late"));
+    table.insert(vec![3.into(), 1.into()]).unwrap();
+    sleep();
+    let mut early = g.view("early").unwrap();
+    assert_eq!(
+        early.lookup(&[3.into()], true).unwrap(),
+        vec![vec![3.into(), 1.into()]]
+    );
+
+    // and the planned view can still be added for real
+    g.extend_recipe(late).unwrap();
+    let mut late = g.view("late").unwrap();
+    assert_eq!(late.lookup(&[1.into()], true).unwrap().len(), 4);
+}
+
+#[test]
+fn it_reuses_equivalent_nodes() {
+    use dataflow::prelude::Ingredient;
+
+    let mut g = build_local_unsharded("it_reuses_equivalent_nodes");
+    let (a, b, ja) = g.migrate(|mig| {
+        let a = mig.add_base("a", &["id", "x"], Base::default());
+        let b = mig.add_base("b", &["id", "y"], Base::default());
+        let j = Join::new(a, b, JoinType::Inner, vec![B(0, 0), L(1), R(1)]);
+        let j = mig.add_ingredient("ja", &["id", "x", "y"], j);
+        let q = mig.add_ingredient("qa", &["id", "x"], Project::new(j, &[0, 1], None, None));
+        mig.maintain("qa".into(), q, &[0]);
+        (a, b, j)
+    });
+
+    // the second query joins the same way, so it gets the join of the first one
+    let (jb, reused) = g.migrate(move |mig| {
+        let j = Join::new(a, b, JoinType::Inner, vec![B(0, 0), L(1), R(1)]);
+        let j = mig.add_ingredient("jb", &["id", "x", "y"], j);
+        let q = mig.add_ingredient("qb", &["id", "y"], Project::new(j, &[0, 2], None, None));
+        mig.maintain("qb".into(), q, &[0]);
+        (j, mig.reused().to_vec())
+    });
+    assert_eq!(jb, ja);
+    assert_eq!(reused, vec![ja]);
+
+    let joins = g.migrate(|mig| {
+        let graph = mig.graph();
+        graph
+            .node_indices()
+            .filter(|&ni| graph[ni].is_internal() && graph[ni].is_join())
+            .count()
+    });
+    assert_eq!(joins, 1);
+    let joins = g
+        .statistics()
+        .unwrap()
+        .values()
+        .flat_map(|&(_, ref nodes)| nodes.values().filter(|n| n.desc.contains("⋈")))
+        .count();
+    assert_eq!(joins, 1);
+
+    // both queries see the joined rows
+    let mut ta = g.table("a").unwrap();
+    let mut tb = g.table("b").unwrap();
+    ta.insert(vec![1.into(), 2.into()]).unwrap();
+    tb.insert(vec![1.into(), 3.into()]).unwrap();
+    sleep();
+    let mut qa = g.view("qa").unwrap();
+    let mut qb = g.view("qb").unwrap();
+    assert_eq!(
+        qa.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 2.into()]]
+    );
+    assert_eq!(
+        qb.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 3.into()]]
+    );
+
+    // unless reuse is turned off
+    let jc = g.migrate(move |mig| {
+        mig.disable_reuse();
+        let j = Join::new(a, b, JoinType::Inner, vec![B(0, 0), L(1), R(1)]);
+        let j = mig.add_ingredient("jc", &["id", "x", "y"], j);
+        mig.maintain("qc".into(), j, &[0]);
+        j
+    });
+    assert_ne!(jc, ja);
+}
+
+#[test]
+fn it_renders_graph_as_dot() {
+    let mut g = build_local_unsharded("it_renders_graph_as_dot");
+    g.migrate(|mig| {
+        let a = mig.add_base("a\"b", &["id", "x"], Base::default());
+        // the count gets a domain of its own
+        let c = mig.add_ingredient(
+            "BOUNDARY_c",
+            &["x", "n"],
+            Aggregation::COUNT.over(a, 0, &[1]),
+        );
+        mig.maintain("c".into(), c, &[0]);
+    });
+    let mut table = g.table("a\"b").unwrap();
+    table.insert(vec![1.into(), 2.into()]).unwrap();
+    sleep();
+
+    let dot = g.graphviz().unwrap();
+    assert!(dot.starts_with("digraph {\n"), "{}", dot);
+    assert!(dot.ends_with("}"), "{}", dot);
+
+    // quotes in names are escaped
+    assert!(dot.contains("a\\\"b"), "{}", dot);
+    assert!(!dot.contains("a\"b"), "{}", dot);
+
+    // each domain is a cluster, and edges between them are dashed
+    assert!(dot.contains("subgraph cluster_d0 {"), "{}", dot);
+    assert!(dot.contains("subgraph cluster_d1 {"), "{}", dot);
+    assert!(dot.contains("style=dashed"), "{}", dot);
+
+    // readers look different, and state is labeled with its indexes and size
+    assert!(dot.contains("shape=Mrecord"), "{}", dot);
+    assert!(dot.contains("indexed on [0]"), "{}", dot);
+    assert!(dot.contains(" bytes"), "{}", dot);
+}
+
+#[test]
+fn it_describes_topology() {
+    use noria::debug::topology::{NodeKind, TopologyDescription};
+
+    let mut g = build_local_unsharded("it_describes_topology");
+    let (a, c) = g.migrate(|mig| {
+        let a = mig.add_base("a", &["id", "x"], Base::default().with_key(vec![0]));
+        // the count gets a domain of its own
+        let c = mig.add_ingredient(
+            "BOUNDARY_c",
+            &["x", "n"],
+            Aggregation::COUNT.over(a, 0, &[1]),
+        );
+        mig.maintain("c".into(), c, &[0]);
+        (a, c)
+    });
+
+    // the description survives a round-trip through JSON
+    let json = serde_json::to_string(&g.describe().unwrap()).unwrap();
+    let topology: TopologyDescription = serde_json::from_str(&json).unwrap();
+
+    let base = &topology.bases["a"];
+    assert_eq!(base.node, a);
+    assert_eq!(base.columns, vec!["id", "x"]);
+    assert_eq!(base.key, Some(vec![0]));
+    assert_eq!(topology.node(a).unwrap().kind, NodeKind::Base);
+
+    let view = &topology.views["c"];
+    assert_eq!(view.of, c);
+    assert_eq!(view.columns, vec!["x", "n"]);
+    assert_eq!(view.key, Some(vec![0]));
+    assert_eq!(topology.node(view.reader).unwrap().kind, NodeKind::Reader);
+
+    let count = topology.node(c).unwrap();
+    assert_eq!(count.kind, NodeKind::Internal);
+    assert!(count.operator.is_some());
+    assert_eq!(count.children, vec![view.reader]);
+    assert_eq!(count.parents.len(), 1);
+    assert_eq!(
+        topology.node(count.parents[0]).unwrap().kind,
+        NodeKind::Ingress
+    );
+
+    let domain = topology.domain(count.domain).unwrap();
+    assert_ne!(topology.node(a).unwrap().domain, count.domain);
+    assert!(domain.nodes.contains(&c));
+    assert_eq!(domain.workers.len(), 1);
+
+    // the version only changes when the graph does
+    assert_eq!(g.describe().unwrap().version, topology.version);
+    g.migrate(move |mig| {
+        let d = mig.add_ingredient("d", &["id", "x"], Identity::new(a));
+        mig.maintain("d".into(), d, &[0]);
+    });
+    let later = g.describe().unwrap();
+    assert!(later.version > topology.version);
+    assert!(later.views.contains_key("d"));
+}
+
+#[test]
+fn it_finds_tables_and_views_by_name() {
+    use noria::error::NotFound;
+
+    let mut g = build_local_unsharded("it_finds_tables_and_views_by_name");
+    let a = g.migrate(|mig| {
+        let a = mig.add_base("a", &["id", "x"], Base::default());
+        let q = mig.add_ingredient("q", &["id", "x"], Identity::new(a));
+        mig.maintain("q".into(), q, &[0]);
+        a
+    });
+
+    // names that are taken can't be given out again
+    let err = g
+        .try_migrate(|mig| {
+            mig.add_base("a", &["id"], Base::default());
+        })
+        .unwrap_err();
+    assert!(err.contains("base named a"), "unexpected error: {}", err);
+    let err = g
+        .try_migrate(move |mig| {
+            let q = mig.add_ingredient("q2", &["id", "x"], Identity::new(a));
+            mig.maintain("q".into(), q, &[1]);
+        })
+        .unwrap_err();
+    assert!(err.contains("view named q"), "unexpected error: {}", err);
+
+    // and the table and view that have them are still there
+    let mut table = g.table("a").unwrap();
+    table.insert(vec![1.into(), 2.into()]).unwrap();
+    sleep();
+    let mut q = g.view("q").unwrap();
+    assert_eq!(
+        q.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 2.into()]]
+    );
+
+    // renamed views are found under their new name only, while existing handles keep working
+    g.migrate(|mig| mig.rename_query("q", "r"));
+    match g.view("q").unwrap_err().downcast::<NotFound>() {
+        Ok(NotFound::View(ref name)) if name == "q" => {}
+        r => panic!("expected view not to be found, got {:?}", r),
+    }
+    let mut r = g.view("r").unwrap();
+    assert_eq!(r.lookup(&[1.into()], true).unwrap().len(), 1);
+    assert_eq!(q.lookup(&[1.into()], true).unwrap().len(), 1);
+
+    // the old name is free to take
+    g.migrate(move |mig| {
+        let q = mig.add_ingredient("q2", &["id", "x"], Identity::new(a));
+        mig.maintain("q".into(), q, &[1]);
+    });
+    let mut q2 = g.view("q").unwrap();
+    assert_eq!(q2.lookup(&[2.into()], true).unwrap().len(), 1);
+
+    // removed views and unknown tables aren't found either
+    g.migrate(|mig| {
+        mig.remove_query("r").unwrap();
+    });
+    match g.view("r").unwrap_err().downcast::<NotFound>() {
+        Ok(NotFound::View(ref name)) if name == "r" => {}
+        r => panic!("expected view not to be found, got {:?}", r),
+    }
+    match g.table("b").unwrap_err().downcast::<NotFound>() {
+        Ok(NotFound::Table(ref name)) if name == "b" => {}
+        r => panic!("expected table not to be found, got {:?}", r),
+    }
+}
+
+#[test]
+fn it_validates_migrations() {
+    use crate::controller::Migration;
+    use crate::{ValidationError, ValidationErrorKind};
+    use dataflow::ops::filter::{Filter, FilterCondition, Operator, Value};
+    use dataflow::ops::project::{ProjectExpression, ProjectExpressionBase};
+    use dataflow::MaterializationHint;
+    use noria::{ColumnSchema, ColumnType, TimeUnit};
+
+    // runs a migration that is expected to fail, and returns what validation found along with
+    // what the migration returned and the error it failed with
+    fn invalid<F, T>(
+        g: &mut LocalControllerHandle<LocalAuthority>,
+        f: F,
+    ) -> (T, Vec<ValidationError>, String)
+    where
+        F: FnOnce(&mut Migration) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = ::std::sync::mpsc::channel();
+        let err = g
+            .try_migrate(move |mig| {
+                let r = f(mig);
+                tx.send((r, mig.validate())).unwrap();
+            })
+            .unwrap_err();
+        let (r, errors) = rx.recv().unwrap();
+        (r, errors, err)
+    }
+
+    let mut g = build_local_unsharded("it_validates_migrations");
+    let (a, b, q) = g.migrate(|mig| {
+        let a = mig.add_base("a", &["id", "x"], Base::default());
+        let b = mig.add_base("b", &["id", "y", "z"], Base::default());
+        let q = mig.add_ingredient("q", &["id", "x"], Identity::new(a));
+        mig.maintain("q".into(), q, &[0]);
+        (a, b, q)
+    });
+
+    // an operator that emits a column its parent doesn't have
+    let (p, errors, err) = invalid(&mut g, move |mig| {
+        let p = mig.add_ingredient("p", &["id", "w"], Project::new(a, &[0, 2], None, None));
+        mig.maintain_anonymous(p, &[0]);
+        p
+    });
+    assert_eq!(
+        errors,
+        vec![ValidationError {
+            node: p,
+            name: "p".into(),
+            kind: ValidationErrorKind::MissingColumn {
+                ancestor: a,
+                column: 2,
+                columns: 2,
+            },
+        }]
+    );
+    assert!(err.contains("reads column 2"), "unexpected error: {}", err);
+
+    // a filter that compares binary values by order, whether they come from a column whose
+    // declared type is binary or from a constant
+    let ((c, f), errors, err) = invalid(&mut g, move |mig| {
+        let schema = vec![
+            ColumnSchema::new("id", ColumnType::Int, false),
+            ColumnSchema::new("data", ColumnType::Bytes, true),
+        ];
+        let c = mig.add_base("c", &["id", "data"], Base::default().with_schema(schema));
+        let cond = FilterCondition::Comparison(Operator::Less, Value::Column(0));
+        let f = mig.add_ingredient("f", &["id", "data"], Filter::new(c, &[None, Some(cond)]));
+        mig.maintain_anonymous(f, &[0]);
+        (c, f)
+    });
+    assert_eq!(
+        errors,
+        vec![ValidationError {
+            node: f,
+            name: "f".into(),
+            kind: ValidationErrorKind::OrderedBinary {
+                ancestor: c,
+                column: Some(1),
+            },
+        }]
+    );
+    assert!(err.contains("by order"), "unexpected error: {}", err);
+
+    let (f, errors, _) = invalid(&mut g, move |mig| {
+        let bytes: DataType = vec![0xca, 0xfe].into();
+        let cond = FilterCondition::Comparison(Operator::GreaterOrEqual, bytes.into());
+        let f = mig.add_ingredient("f", &["id", "x"], Filter::new(a, &[None, Some(cond)]));
+        mig.maintain_anonymous(f, &[0]);
+        f
+    });
+    assert_eq!(errors.len(), 1, "{:?}", errors);
+    assert_eq!(errors[0].node, f);
+    assert_eq!(
+        errors[0].kind,
+        ValidationErrorKind::OrderedBinary {
+            ancestor: a,
+            column: None,
+        }
+    );
+
+    // a projection that truncates a column whose declared type is not a timestamp
+    let ((d, p), errors, err) = invalid(&mut g, move |mig| {
+        let schema = vec![
+            ColumnSchema::new("id", ColumnType::Int, false),
+            ColumnSchema::new("at", ColumnType::Text, true),
+        ];
+        let d = mig.add_base("d", &["id", "at"], Base::default().with_schema(schema));
+        let day = ProjectExpression::Truncate(TimeUnit::Day, ProjectExpressionBase::Column(1));
+        let p = Project::new(d, &[0], None, Some(vec![day]));
+        let p = mig.add_ingredient("p", &["id", "day"], p);
+        mig.maintain_anonymous(p, &[0]);
+        (d, p)
+    });
+    assert_eq!(errors.len(), 1, "{:?}", errors);
+    assert_eq!(errors[0].node, p);
+    assert_eq!(
+        errors[0].kind,
+        ValidationErrorKind::WrongColumnType {
+            ancestor: d,
+            column: 1,
+            expected: ColumnType::Timestamp,
+            found: ColumnType::Text,
+        }
+    );
+    assert!(err.contains("type timestamp"), "unexpected error: {}", err);
+
+    // a base whose auto-increment column is declared to hold something other than integers
+    let (e, errors, _) = invalid(&mut g, move |mig| {
+        let schema = vec![
+            ColumnSchema::new("id", ColumnType::Text, false),
+            ColumnSchema::new("x", ColumnType::Int, true),
+        ];
+        let e = Base::default()
+            .with_key(vec![0])
+            .with_schema(schema)
+            .with_auto_increment(0);
+        let e = mig.add_base("e", &["id", "x"], e);
+        mig.maintain_anonymous(e, &[0]);
+        e
+    });
+    assert_eq!(errors.len(), 1, "{:?}", errors);
+    assert_eq!(errors[0].node, e);
+    assert_eq!(
+        errors[0].kind,
+        ValidationErrorKind::NonIntegerAutoIncrement {
+            column: 0,
+            found: ColumnType::Text,
+        }
+    );
+
+    // a reader keyed on a column the view doesn't have
+    let (r, errors, _) = invalid(&mut g, move |mig| {
+        let i = mig.add_ingredient("i", &["id", "x"], Identity::new(a));
+        mig.maintain_anonymous(i, &[3])
+    });
+    assert_eq!(errors.len(), 1, "{:?}", errors);
+    assert_eq!(errors[0].node, r);
+    assert_eq!(
+        errors[0].kind,
+        ValidationErrorKind::MissingKeyColumn {
+            column: 3,
+            columns: 2,
+        }
+    );
+
+    // a union whose parents emit different numbers of columns
+    let (u, errors, _) = invalid(&mut g, move |mig| {
+        let mut emits = HashMap::new();
+        emits.insert(a, vec![0, 1]);
+        emits.insert(b, vec![0]);
+        let u = mig.add_ingredient("u", &["id", "v"], Union::new(emits));
+        mig.maintain_anonymous(u, &[0]);
+        u
+    });
+    assert_eq!(errors.len(), 1, "{:?}", errors);
+    assert_eq!(errors[0].node, u);
+    assert_eq!(
+        errors[0].kind,
+        ValidationErrorKind::ArityMismatch {
+            ancestor: b,
+            emits: 1,
+            columns: 2,
+        }
+    );
+
+    // a join that has nowhere to look up the rows of one of its parents
+    let ((bi, j), errors, _) = invalid(&mut g, move |mig| {
+        let bi = mig.add_ingredient("bi", &["id", "y", "z"], Identity::new(b));
+        mig.materialize(bi, MaterializationHint::Forbidden);
+        let j = Join::new(a, bi, JoinType::Inner, vec![B(0, 0), L(1), R(1)]);
+        let j = mig.add_ingredient("j", &["id", "x", "y"], j);
+        mig.maintain_anonymous(j, &[0]);
+        (bi, j)
+    });
+    assert_eq!(errors.len(), 1, "{:?}", errors);
+    assert_eq!(errors[0].node, j);
+    assert_eq!(
+        errors[0].kind,
+        ValidationErrorKind::MissingMaterialization {
+            ancestor: bi,
+            target: bi,
+            columns: vec![0],
+        }
+    );
+
+    // nodes below a node that is being removed
+    let ((i, r), errors, _) = invalid(&mut g, move |mig| {
+        mig.remove_query("q").unwrap();
+        let i = mig.add_ingredient("i", &["id", "x"], Identity::new(q));
+        (i, mig.maintain_anonymous(i, &[0]))
+    });
+    let unreachable: Vec<_> = errors
+        .iter()
+        .filter(|e| e.kind == ValidationErrorKind::Unreachable)
+        .map(|e| e.node)
+        .collect();
+    assert_eq!(unreachable, vec![i, r]);
+
+    // nodes that nothing reads don't stop a migration
+    let (l, errors) = g.migrate(move |mig| {
+        let l = mig.add_ingredient("l", &["id", "x"], Identity::new(a));
+        (l, mig.validate())
+    });
+    assert_eq!(errors.len(), 1, "{:?}", errors);
+    assert_eq!(errors[0].node, l);
+    assert_eq!(errors[0].kind, ValidationErrorKind::Unused);
+    assert!(!errors[0].is_fatal());
+
+    // and none of the failed migrations left anything behind
+    let mut table = g.table("a").unwrap();
+    table.insert(vec![1.into(), 2.into()]).unwrap();
+    sleep();
+    let mut q = g.view("q").unwrap();
+    assert_eq!(
+        q.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 2.into()]]
+    );
+}
+
+#[test]
+fn it_rebuilds_graph_from_log() {
+    use crate::{GraphLog, GraphOperation};
+
+    let dir = tempfile::tempdir().unwrap();
+    let build = |name: &str, rebuild: Option<GraphLog>| {
+        let mut b = ControllerBuilder::default();
+        b.set_persistence(get_persistence_params(name));
+        b.set_graph_log(dir.path().join(name));
+        if let Some(log) = rebuild {
+            b.rebuild_from(log);
+        }
+        b.build_local().unwrap()
+    };
+
+    let mut g = build("it_rebuilds_graph_from_log", None);
+    let (a, b) = g.migrate(|mig| {
+        let a = mig.add_base("a", &["id", "x"], Base::new(vec![]).with_key(vec![0]));
+        let b = mig.add_base("b", &["id", "y"], Base::new(vec![]).with_key(vec![0]));
+        let j = Join::new(a, b, JoinType::Inner, vec![B(0, 0), L(1), R(1)]);
+        let j = mig.add_ingredient("j", &["id", "x", "y"], j);
+        mig.maintain("j".into(), j, &[0]);
+        let c = mig.add_ingredient("c", &["x", "n"], Aggregation::COUNT.over(a, 0, &[1]));
+        mig.maintain("c".into(), c, &[0]);
+        (a, b)
+    });
+    g.migrate(move |mig| {
+        mig.add_column(b, "z", 0.into());
+        let ia = mig.add_ingredient("i", &["id", "x"], Identity::new(a));
+        mig.maintain("ia".into(), ia, &[0]);
+        let ib = mig.add_ingredient("i", &["id", "y", "z"], Identity::new(b));
+        mig.maintain("ib".into(), ib, &[0]);
+        let mut emits = HashMap::new();
+        emits.insert(a, vec![0, 1]);
+        emits.insert(b, vec![0, 2]);
+        let u = mig.add_ingredient("u", &["id", "v"], Union::new(emits));
+        mig.maintain("u".into(), u, &[0]);
+        mig.rename_query("c", "count");
+    });
+    drop(g);
+
+    let log = GraphLog::read_from(dir.path().join("it_rebuilds_graph_from_log")).unwrap();
+    assert_eq!(log.migrations.len(), 2);
+    // the second node named i is told apart from the first
+    let added: Vec<_> = log.migrations[1]
+        .iter()
+        .filter_map(|op| match *op {
+            GraphOperation::AddOperator { ref node, .. } => Some(&node[..]),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(added, vec!["i", "i#1", "u"]);
+
+    let mut g = build("it_rebuilds_graph_from_log_again", Some(log.clone()));
+
+    // the rebuilt graph is logged just like the original was
+    let relog = GraphLog::read_from(dir.path().join("it_rebuilds_graph_from_log_again")).unwrap();
+    let lengths = |log: &GraphLog| log.migrations.iter().map(Vec::len).collect::<Vec<_>>();
+    assert_eq!(lengths(&relog), lengths(&log));
+
+    let mut a = g.table("a").unwrap();
+    let mut b = g.table("b").unwrap();
+    assert_eq!(b.columns(), &["id", "y", "z"]);
+    a.insert(vec![1.into(), 10.into()]).unwrap();
+    a.insert(vec![2.into(), 10.into()]).unwrap();
+    b.insert(vec![1.into(), 20.into(), 30.into()]).unwrap();
+    sleep();
+
+    let mut j = g.view("j").unwrap();
+    assert_eq!(
+        j.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 10.into(), 20.into()]]
+    );
+    let mut count = g.view("count").unwrap();
+    assert_eq!(
+        count.lookup(&[10.into()], true).unwrap(),
+        vec![vec![10.into(), 2.into()]]
+    );
+    assert!(g.view("c").is_err());
+    let mut ia = g.view("ia").unwrap();
+    assert_eq!(
+        ia.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 10.into()]]
+    );
+    let mut ib = g.view("ib").unwrap();
+    assert_eq!(
+        ib.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 20.into(), 30.into()]]
+    );
+    let mut u = g.view("u").unwrap();
+    let mut rows = u.lookup(&[1.into()], true).unwrap();
+    rows.sort();
+    assert_eq!(
+        rows,
+        vec![vec![1.into(), 10.into()], vec![1.into(), 30.into()]]
+    );
+}
+
+#[test]
+fn it_indexes_existing_state_in_place() {
+    use dataflow::Placement;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    // the join must share a domain with the base, so that it looks up the base's own state
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(None);
+    g.set_persistence(get_persistence_params("it_indexes_existing_state_in_place"));
+    let mut g = g.build_local().unwrap();
+    let a =
+        g.migrate(|mig| mig.add_base("a", &["id", "group"], Base::new(vec![]).with_key(vec![0])));
+
+    let n = 2_000;
+    let groups = 10;
+    let row = move |i: i64| vec![i.into(), (i % groups).into()];
+    let mut table = g.table("a").unwrap().into_exclusive().unwrap();
+    table
+        .insert_all((0..n / 2).map(row).collect::<Vec<_>>())
+        .unwrap();
+    sleep();
+
+    // keep writing while the base is indexed on the group
+    let started = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let started = started.clone();
+        thread::spawn(move || {
+            for i in n / 2..n {
+                table.insert(row(i)).unwrap();
+                started.store(true, Ordering::SeqCst);
+            }
+        })
+    };
+    while !started.load(Ordering::SeqCst) {
+        thread::yield_now();
+    }
+
+    g.migrate(move |mig| {
+        let b = mig.add_base("b", &["group", "name"], Base::new(vec![]).with_key(vec![0]));
+        // records from b look up the rows of a by group
+        let j = Join::new(b, a, JoinType::Inner, vec![B(0, 1), L(1), R(0)]);
+        let j = mig.add_ingredient("j", &["group", "name", "id"], j);
+        mig.place(j, Placement::With(a));
+        mig.maintain("j".into(), j, &[0]);
+    });
+    writer.join().unwrap();
+    sleep();
+
+    // the names look up the rows of a through the index that was built during the writes
+    let mut names = g.table("b").unwrap();
+    for group in 0..groups {
+        names
+            .insert(vec![group.into(), format!("g{}", group).into()])
+            .unwrap();
+    }
+    sleep();
+
+    let mut j = g.view("j").unwrap();
+    for group in 0..groups {
+        let mut ids: Vec<_> = j
+            .lookup(&[group.into()], true)
+            .unwrap()
+            .into_iter()
+            .map(|r| r[2].clone())
+            .collect();
+        ids.sort();
+        let expected: Vec<DataType> = (0..n)
+            .filter(|i| i % groups == group)
+            .map(|i| i.into())
+            .collect();
+        assert_eq!(ids, expected, "group {}", group);
+    }
+}
+
+#[test]
+fn it_places_nodes_as_asked() {
+    use dataflow::ops::filter::{Filter, FilterCondition, Operator, Value};
+    use dataflow::Placement;
+
+    let mut g = build_local_unsharded("it_places_nodes_as_asked");
+    let (a, f) = g.migrate(|mig| {
+        let a = mig.add_base("a", &["id", "x"], Base::default());
+        // a node named like this would normally get a domain of its own
+        let cond = FilterCondition::Comparison(Operator::Equal, Value::Constant(1.into()));
+        let f = mig.add_ingredient(
+            "BOUNDARY_f",
+            &["id", "x"],
+            Filter::new(a, &[None, Some(cond)]),
+        );
+        mig.place(f, Placement::With(a));
+        mig.maintain_anonymous(f, &[0]);
+        (a, f)
+    });
+
+    // the filter reads straight from the base, without an egress/ingress pair in between
+    let (same_domain, parents) = g.migrate(move |mig| {
+        let graph = mig.graph();
+        let parents: Vec<_> = graph
+            .neighbors_directed(f, ::petgraph::EdgeDirection::Incoming)
+            .collect();
+        (graph[f].domain() == graph[a].domain(), parents)
+    });
+    assert!(same_domain);
+    assert_eq!(parents, vec![a]);
+
+    let mut table = g.table("a").unwrap();
+    let mut view = g.view("BOUNDARY_f").unwrap();
+    table.insert(vec![1.into(), 1.into()]).unwrap();
+    table.insert(vec![2.into(), 2.into()]).unwrap();
+    sleep();
+    assert_eq!(
+        view.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 1.into()]]
+    );
+    assert!(view.lookup(&[2.into()], true).unwrap().is_empty());
+
+    // placements that can't be honored fail the migration
+    let err = g
+        .try_migrate(move |mig| {
+            let i = mig.add_ingredient("i", &["id", "x"], Identity::new(a));
+            mig.place(i, Placement::Domain(1000));
+            mig.maintain("i".into(), i, &[0]);
+        })
+        .unwrap_err();
+    assert!(
+        err.contains("there is no domain 1000"),
+        "unexpected error: {}",
+        err
+    );
+    assert!(!g.outputs().unwrap().contains_key("i"));
+}
+
+#[test]
+fn it_keeps_diamonds_in_one_domain() {
+    use dataflow::ops::filter::{Filter, FilterCondition, Operator, Value};
+
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(None);
+    g.set_domain_strategy(DomainStrategy::FewestCuts { max_nodes: 3 });
+    g.set_persistence(get_persistence_params("it_keeps_diamonds_in_one_domain"));
+    let mut g = g.build_local().unwrap();
+    let a = g.migrate(|mig| mig.add_base("a", &["id", "x"], Base::default()));
+
+    let (f1, f2, u) = g.migrate(move |mig| {
+        let with_x = |v: i32| {
+            let cond = FilterCondition::Comparison(Operator::Equal, Value::Constant(v.into()));
+            Filter::new(a, &[None, Some(cond)])
+        };
+        let f1 = mig.add_ingredient("f1", &["id", "x"], with_x(1));
+        let f2 = mig.add_ingredient("f2", &["id", "x"], with_x(2));
+        let mut emits = HashMap::new();
+        emits.insert(f1, vec![0, 1]);
+        emits.insert(f2, vec![0, 1]);
+        let u = mig.add_ingredient("u", &["id", "x"], Union::new(emits));
+        mig.maintain("u".into(), u, &[0]);
+        (f1, f2, u)
+    });
+
+    // the whole diamond, reader included, is in one domain, which can't be the base's as that
+    // would make it too large
+    let domains = g.migrate(move |mig| {
+        let graph = mig.graph();
+        let reader = graph
+            .neighbors_directed(u, ::petgraph::EdgeDirection::Outgoing)
+            .find(|&ni| graph[ni].is_reader())
+            .unwrap();
+        [a, f1, f2, u, reader]
+            .iter()
+            .map(|&ni| graph[ni].domain())
+            .collect::<Vec<_>>()
+    });
+    assert_ne!(domains[0], domains[1]);
+    assert!(domains[1..].iter().all(|&d| d == domains[1]));
+
+    let mut table = g.table("a").unwrap();
+    let mut view = g.view("u").unwrap();
+    for i in 0..4 {
+        table.insert(vec![i.into(), i.into()]).unwrap();
+    }
+    sleep();
+    assert_eq!(
+        view.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 1.into()]]
+    );
+    assert_eq!(
+        view.lookup(&[2.into()], true).unwrap(),
+        vec![vec![2.into(), 2.into()]]
+    );
+    assert!(view.lookup(&[3.into()], true).unwrap().is_empty());
+}
+
+#[test]
+fn it_assigns_domains_by_first_parent_if_asked() {
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(None);
+    g.set_domain_strategy(DomainStrategy::FirstParent);
+    g.set_persistence(get_persistence_params(
+        "it_assigns_domains_by_first_parent_if_asked",
+    ));
+    let mut g = g.build_local().unwrap();
+    let a = g.migrate(|mig| mig.add_base("a", &["id", "x"], Base::default()));
+
+    // every node goes with its parent, no matter how many there are
+    let chain = g.migrate(move |mig| {
+        let mut chain = vec![a];
+        for i in 0..4 {
+            let parent = *chain.last().unwrap();
+            let n = mig.add_ingredient(format!("i{}", i), &["id", "x"], Identity::new(parent));
+            chain.push(n);
+        }
+        mig.maintain("i3".into(), *chain.last().unwrap(), &[0]);
+        chain
+    });
+    let domains: Vec<_> = g.migrate(move |mig| {
+        let graph = mig.graph();
+        chain.iter().map(|&ni| graph[ni].domain()).collect()
+    });
+    assert!(domains.iter().all(|&d| d == domains[0]));
+}
+
+#[test]
+fn it_serves_old_view_until_replacement_is_ready() {
+    use noria::builders::ViewBuilder;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    // the new version must be fully materialized, so that it only takes over once it has all the
+    // rows the old one has
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(None);
+    g.set_persistence(get_persistence_params(
+        "it_serves_old_view_until_replacement_is_ready",
+    ));
+    let mut g = g.build_local().unwrap();
+    let a = g.migrate(|mig| {
+        let a = mig.add_base("a", &["id", "x"], Base::default());
+        let q = mig.add_ingredient("q1", &["id", "x"], Identity::new(a));
+        mig.maintain("q".into(), q, &[0]);
+        a
+    });
+
+    let n = 1000;
+    let mut table = g.table("a").unwrap();
+    let rows: Vec<Vec<DataType>> = (0..n).map(|i| vec![i.into(), i.into()]).collect();
+    table.insert_all(rows).unwrap();
+    sleep();
+
+    // keep reading through a view of the old version while it is being replaced
+    let old = g
+        .rpc::<_, Option<ViewBuilder>>("view_builder", "q")
+        .unwrap()
+        .unwrap();
+    let done = Arc::new(AtomicBool::new(false));
+    let reader = {
+        let done = done.clone();
+        thread::spawn(move || {
+            let mut q = old.build_exclusive().unwrap();
+            let mut columns = Vec::new();
+            let mut i = 0;
+            while !done.load(Ordering::SeqCst) {
+                let rows = q.lookup(&[(i % n).into()], false).unwrap();
+                assert_eq!(rows.len(), 1, "read {} found {:?}", i, rows);
+                if columns.last() != Some(&rows[0].len()) {
+                    columns.push(rows[0].len());
+                }
+                i += 1;
+            }
+            columns
+        })
+    };
+
+    g.migrate(move |mig| {
+        let q = mig.add_ingredient(
+            "q2",
+            &["id", "x", "y"],
+            Project::new(a, &[0, 1], Some(vec![42.into()]), None),
+        );
+        mig.replace("q", q);
+    });
+    sleep();
+    done.store(true, Ordering::SeqCst);
+
+    // reads went to the old version, and then to the new one, and never found nothing
+    assert_eq!(reader.join().unwrap(), vec![2, 3]);
+    assert_eq!(
+        g.view("q").unwrap().lookup(&[0.into()], true).unwrap(),
+        vec![vec![0.into(), 0.into(), 42.into()]]
+    );
+
+    // the old version is gone
+    let (q1, q2) = g.migrate(|mig| {
+        let graph = mig.graph();
+        let find = |name| graph.node_indices().find(|&ni| graph[ni].name() == name);
+        let q1 = find("q1").map(|ni| graph[ni].is_dropped());
+        let q2 = find("q2").map(|ni| graph[ni].is_dropped());
+        (q1, q2)
+    });
+    assert_eq!(q1, Some(true));
+    assert_eq!(q2, Some(false));
+}
+
+#[test]
+fn it_verifies_replayed_state() {
+    use crate::{MigrationEventKind, MigrationPhase};
+    use dataflow::ops::filter::{Filter, FilterCondition, Operator, Value};
+
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(None);
+    g.set_persistence(get_persistence_params("it_verifies_replayed_state"));
+    let mut g = g.build_local().unwrap();
+    let a = g.migrate(|mig| mig.add_base("a", &["id", "x"], Base::default()));
+
+    let n = 100;
+    let mut table = g.table("a").unwrap();
+    for i in 0..n {
+        table.insert(vec![i.into(), (i % 10).into()]).unwrap();
+    }
+    sleep();
+
+    // the new views pass rows on as they are, drop some of them, and group them
+    let events = g.migrate(move |mig| {
+        let events = mig.events();
+        mig.verify_replays();
+        let i = mig.add_ingredient("i", &["id", "x"], Identity::new(a));
+        mig.maintain("i".into(), i, &[0]);
+        let cond = FilterCondition::Comparison(Operator::Equal, Value::Constant(1.into()));
+        let f = mig.add_ingredient("f", &["id", "x"], Filter::new(a, &[None, Some(cond)]));
+        mig.maintain("f".into(), f, &[0]);
+        let c = mig.add_ingredient("c", &["x", "n"], Aggregation::COUNT.over(a, 0, &[1]));
+        mig.maintain("c".into(), c, &[0]);
+        events
+    });
+    let started: Vec<_> = events
+        .iter()
+        .filter_map(|e| match e.kind {
+            MigrationEventKind::PhaseStarted(phase) => Some(phase),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        &started[started.len() - 3..],
+        &[
+            MigrationPhase::Replaying,
+            MigrationPhase::Verifying,
+            MigrationPhase::Exposing
+        ]
+    );
+
+    let mut i = g.view("i").unwrap();
+    assert_eq!(
+        i.lookup(&[7.into()], true).unwrap(),
+        vec![vec![7.into(), 7.into()]]
+    );
+    let mut f = g.view("f").unwrap();
+    assert_eq!(f.lookup(&[11.into()], true).unwrap().len(), 1);
+    assert!(f.lookup(&[12.into()], true).unwrap().is_empty());
+    let mut c = g.view("c").unwrap();
+    assert_eq!(
+        c.lookup(&[3.into()], true).unwrap(),
+        vec![vec![3.into(), 10.into()]]
+    );
+}
+
+#[test]
+fn it_replays_in_parallel() {
+    use dataflow::ops::filter::{Filter, FilterCondition, Operator, Value};
+
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(None);
+    g.set_replay_workers(4);
+    g.set_persistence(get_persistence_params("it_replays_in_parallel"));
+    let mut g = g.build_local().unwrap();
+    let a = g.migrate(|mig| mig.add_base("a", &["id", "x"], Base::default()));
+
+    // enough rows that the replay chunks are handed out to the workers
+    let n = 10_000;
+    let mut table = g.table("a").unwrap();
+    table
+        .insert_all((0..n).map(|i| vec![i.into(), (i % 100).into()]))
+        .unwrap();
+    sleep();
+
+    g.migrate(move |mig| {
+        let cond = FilterCondition::Comparison(Operator::Equal, Value::Constant(1.into()));
+        let f = mig.add_ingredient("f", &["id", "x"], Filter::new(a, &[None, Some(cond)]));
+        mig.maintain("f".into(), f, &[1]);
+        let c = mig.add_ingredient("c", &["x", "n"], Aggregation::COUNT.over(a, 0, &[1]));
+        mig.maintain("c".into(), c, &[0]);
+    });
+
+    let mut f = g.view("f").unwrap();
+    assert_eq!(f.lookup(&[1.into()], true).unwrap().len(), 100);
+    let mut c = g.view("c").unwrap();
+    for x in 0..100 {
+        assert_eq!(
+            c.lookup(&[x.into()], true).unwrap(),
+            vec![vec![x.into(), 100.into()]]
+        );
+    }
+}
+
+#[test]
+fn it_shards_bases_as_asked() {
+    use noria::builders::ViewBuilder;
+
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(None);
+    g.set_persistence(get_persistence_params("it_shards_bases_as_asked"));
+    let mut g = g.build_local().unwrap();
+    let vote = g.migrate(|mig| {
+        let vote = mig.add_base("vote", &["aid", "uid"], Base::default());
+        mig.shard_base(vote, 0, 2);
+        // each shard can count the votes for its own articles
+        let vc = mig.add_ingredient(
+            "vc",
+            &["aid", "votes"],
+            Aggregation::COUNT.over(vote, 1, &[0]),
+        );
+        mig.maintain("vc".into(), vc, &[0]);
+        // but the votes of a user may be in any shard, so they are read from all of them
+        let byuser = mig.add_ingredient("byuser", &["aid", "uid"], Identity::new(vote));
+        mig.maintain("byuser".into(), byuser, &[1]);
+        // and bases that aren't sharded explicitly stay unsharded
+        let other = mig.add_base("other", &["id"], Base::default());
+        mig.maintain("other".into(), other, &[0]);
+        vote
+    });
+
+    let mut shards = |view: &str| {
+        g.rpc::<_, Option<ViewBuilder>>("view_builder", view)
+            .unwrap()
+            .unwrap()
+            .shards
+            .len()
+    };
+    assert_eq!(shards("vc"), 2);
+    assert_eq!(shards("byuser"), 2);
+    assert_eq!(shards("other"), 1);
+
+    let mut table = g.table("vote").unwrap();
+    table
+        .insert_all((0..100).map(|i| vec![(i % 10).into(), i.into()]))
+        .unwrap();
+    sleep();
+
+    let mut vc = g.view("vc").unwrap();
+    assert_eq!(
+        vc.lookup(&[3.into()], true).unwrap(),
+        vec![vec![3.into(), 10.into()]]
+    );
+    let mut byuser = g.view("byuser").unwrap();
+    assert_eq!(
+        byuser.lookup(&[42.into()], true).unwrap(),
+        vec![vec![2.into(), 42.into()]]
+    );
+
+    // new views below the sharded base are filled from all of its shards
+    g.migrate(move |mig| {
+        let uc = mig.add_ingredient(
+            "uc",
+            &["uid", "votes"],
+            Aggregation::COUNT.over(vote, 0, &[1]),
+        );
+        mig.maintain("uc".into(), uc, &[0]);
+    });
+    let mut uc = g.view("uc").unwrap();
+    assert_eq!(
+        uc.lookup(&[42.into()], true).unwrap(),
+        vec![vec![42.into(), 1.into()]]
+    );
+}
+
+#[test]
+fn it_gathers_reads_from_all_shards() {
+    use noria::builders::ViewBuilder;
+    use noria::Direction;
+
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(None);
+    g.set_persistence(get_persistence_params("it_gathers_reads_from_all_shards"));
+    let mut g = g.build_local().unwrap();
+    g.migrate(|mig| {
+        let vote = mig.add_base("vote", &["aid", "uid"], Base::default());
+        mig.shard_base(vote, 0, 4);
+        let byarticle = mig.add_ingredient("byarticle", &["aid", "uid"], Identity::new(vote));
+        mig.maintain("byarticle".into(), byarticle, &[0]);
+        let byuser = mig.add_ingredient("byuser", &["aid", "uid"], Identity::new(vote));
+        mig.maintain("byuser".into(), byuser, &[1]);
+    });
+
+    let mut builder = |view: &str| {
+        g.rpc::<_, Option<ViewBuilder>>("view_builder", view)
+            .unwrap()
+            .unwrap()
+    };
+    let byarticle = builder("byarticle");
+    assert_eq!((byarticle.shards.len(), byarticle.scatter), (4, false));
+    let byuser = builder("byuser");
+    assert_eq!((byuser.shards.len(), byuser.scatter), (4, true));
+
+    let votes: Vec<Vec<DataType>> = (0..200)
+        .map(|i| vec![(i % 20).into(), (i % 7).into()])
+        .collect();
+    let mut table = g.table("vote").unwrap();
+    table.insert_all(votes.clone()).unwrap();
+    sleep();
+
+    let sorted = |mut rows: Vec<Vec<DataType>>| {
+        rows.sort();
+        rows
+    };
+    let votes_where = |column: usize, key: i32| -> Vec<Vec<DataType>> {
+        sorted(
+            votes
+                .iter()
+                .filter(|v| v[column] == DataType::from(key))
+                .cloned()
+                .collect(),
+        )
+    };
+
+    // the votes for an article are all in the one shard the article hashes to
+    let mut byarticle = g.view("byarticle").unwrap();
+    assert_eq!(byarticle.len().unwrap(), votes.len());
+    let aids: Vec<_> = (0..21).map(|aid| vec![aid.into()]).collect();
+    let found = byarticle.multi_lookup(aids, true).unwrap();
+    for (aid, rows) in found.iter().enumerate() {
+        assert_eq!(sorted(rows.clone()), votes_where(0, aid as i32));
+    }
+    assert_eq!(sorted(found.concat()), sorted(votes.clone()));
+
+    // while the votes of a user are gathered from all of the shards
+    let mut byuser = g.view("byuser").unwrap();
+    assert_eq!(byuser.len().unwrap(), votes.len());
+    let uids: Vec<_> = (0..8).map(|uid| vec![uid.into()]).collect();
+    let found = byuser.multi_lookup(uids.clone(), true).unwrap();
+    for (uid, rows) in found.iter().enumerate() {
+        assert_eq!(sorted(rows.clone()), votes_where(1, uid as i32));
+    }
+    assert_eq!(sorted(found.concat()), sorted(votes.clone()));
+
+    let (available, complete) = byuser.multi_lookup_available(uids, true).unwrap();
+    assert!(complete);
+    assert_eq!(sorted(available.concat()), sorted(votes.clone()));
+
+    let user = votes_where(1, 3);
+    assert_eq!(byuser.count(&[3.into()], true).unwrap(), user.len());
+    let (rows, _) = byuser.lookup_with_meta(&[3.into()], true).unwrap();
+    assert_eq!(sorted(rows), user);
+    assert_eq!(
+        byuser
+            .lookup_page(&[3.into()], (0, Direction::Ascending), 2, 5)
+            .unwrap(),
+        (user[2..7].to_vec(), user.len())
+    );
+
+    assert_consistent(&mut g);
+}
+
+#[test]
+fn it_shuffles_between_shardings() {
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(None);
+    g.set_persistence(get_persistence_params("it_shuffles_between_shardings"));
+    let mut g = g.build_local().unwrap();
+    let vote = g.migrate(|mig| {
+        let vote = mig.add_base("vote", &["aid", "uid"], Base::default());
+        mig.shard_base(vote, 0, 4);
+        vote
+    });
+
+    // the votes that are already there are replayed through the shuffle
+    let mut table = g.table("vote").unwrap();
+    table
+        .insert_all((0..100).map(|i| vec![(i % 10).into(), (i % 5).into()]))
+        .unwrap();
+    sleep();
+
+    // counting the votes of each user needs them shuffled from every shard of the base to the
+    // shards of the count, which shouldn't go through a single merged shard
+    g.migrate(move |mig| {
+        let uc = mig.add_ingredient(
+            "uc",
+            &["uid", "votes"],
+            Aggregation::COUNT.over(vote, 0, &[1]),
+        );
+        mig.maintain("uc".into(), uc, &[0]);
+    });
+    assert!(!g.graphviz().unwrap().contains("desharded"));
+
+    // votes for different articles come in through all the shards of the base at once
+    let writers: Vec<_> = (0..4)
+        .map(|w| {
+            let mut table = g.table("vote").unwrap().into_exclusive().unwrap();
+            thread::spawn(move || {
+                for i in 0..250 {
+                    table
+                        .insert(vec![(w * 250 + i).into(), (i % 5).into()])
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for w in writers {
+        w.join().unwrap();
+    }
+    sleep();
+
+    let mut uc = g.view("uc").unwrap();
+    for uid in 0..5 {
+        assert_eq!(
+            uc.lookup(&[uid.into()], true).unwrap(),
+            vec![vec![uid.into(), 220.into()]]
+        );
+    }
+
+    assert_consistent(&mut g);
+}
+
+#[test]
+fn it_modifies_operators_in_place() {
+    use dataflow::ops::filter::{Filter, FilterCondition, Operator, Value};
+    use dataflow::Placement;
+
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(None);
+    g.set_persistence(get_persistence_params("it_modifies_operators_in_place"));
+    let mut g = g.build_local().unwrap();
+    let a = g.migrate(|mig| mig.add_base("a", &["id", "x"], Base::default()));
+
+    let n = 20;
+    let mut table = g.table("a").unwrap();
+    for i in 0..n {
+        table.insert(vec![i.into(), i.into()]).unwrap();
+    }
+    sleep();
+
+    // the filters read the base's state directly, so they live in its domain
+    let above = move |x: i32| {
+        let cond = FilterCondition::Comparison(Operator::Greater, Value::Constant(x.into()));
+        Filter::new(a, &[None, Some(cond)])
+    };
+    let (f, p) = g.migrate(move |mig| {
+        let f = mig.add_ingredient("f", &["id", "x"], above(10));
+        mig.place(f, Placement::With(a));
+        mig.maintain("f".into(), f, &[0]);
+        let p = mig.add_ingredient("p", &["id", "x"], Project::new(a, &[0, 1], None, None));
+        mig.place(p, Placement::With(a));
+        mig.maintain("p".into(), p, &[0]);
+        (f, p)
+    });
+    let mut view = g.view("f").unwrap();
+    let mut ids = move || -> Vec<i32> {
+        (0..n)
+            .filter(|&i| !view.lookup(&[i.into()], true).unwrap().is_empty())
+            .collect()
+    };
+    assert_eq!(ids(), (11..n).collect::<Vec<_>>());
+
+    // tightening the filter retracts rows, and loosening it brings rows back
+    g.migrate(move |mig| mig.modify(f, &["id", "x"], above(15)));
+    sleep();
+    assert_eq!(ids(), (16..n).collect::<Vec<_>>());
+    g.migrate(move |mig| mig.modify(f, &["id", "x"], above(5)));
+    sleep();
+    assert_eq!(ids(), (6..n).collect::<Vec<_>>());
+
+    // and new writes go through the new filter
+    table.insert(vec![n.into(), 7.into()]).unwrap();
+    table.insert(vec![(n + 1).into(), 3.into()]).unwrap();
+    sleep();
+    let mut view = g.view("f").unwrap();
+    assert_eq!(view.lookup(&[n.into()], true).unwrap().len(), 1);
+    assert!(view.lookup(&[(n + 1).into()], true).unwrap().is_empty());
+
+    // a projection can gain columns, which show up in existing rows
+    g.migrate(move |mig| {
+        let project = Project::new(a, &[0, 1], Some(vec![42.into()]), None);
+        mig.modify(p, &["id", "x", "answer"], project);
+    });
+    sleep();
+    let mut view = g.view("p").unwrap();
+    assert_eq!(
+        view.lookup(&[3.into()], true).unwrap(),
+        vec![vec![3.into(), 3.into(), 42.into()]]
+    );
+
+    // but operators can't be swapped for another kind, and only filters and projections can be
+    // modified at all
+    let err = g
+        .try_migrate(move |mig| mig.modify(f, &["id", "x"], Identity::new(a)))
+        .unwrap_err();
+    assert!(err.contains("different kind"), "unexpected error: {}", err);
+    let i = g.migrate(move |mig| {
+        let i = mig.add_ingredient("i", &["id", "x"], Identity::new(a));
+        mig.maintain("i".into(), i, &[0]);
+        i
+    });
+    let err = g
+        .try_migrate(move |mig| mig.modify(i, &["id", "x"], Identity::new(a)))
+        .unwrap_err();
+    assert!(err.contains("in place"), "unexpected error: {}", err);
+}
+
+#[test]
+fn it_seeds_new_bases() {
+    use dataflow::node::special::OnDuplicateKey;
+
+    let mut g = build_local("it_seeds_new_bases");
+    let n = 10_000;
+    g.migrate(move |mig| {
+        let a = mig.add_base("a", &["id", "x"], Base::default());
+        mig.seed(a, (0..n).map(|i: i32| vec![i.into(), (i % 10).into()]));
+        let c = mig.add_ingredient("c", &["x", "n"], Aggregation::COUNT.over(a, 0, &[1]));
+        mig.maintain("c".into(), c, &[0]);
+        let s = mig.add_ingredient("s", &["x", "sum"], Aggregation::SUM.over(a, 0, &[1]));
+        mig.maintain("s".into(), s, &[0]);
+    });
+
+    // the seed is there as soon as the views are, without waiting for it to propagate
+    let mut c = g.view("c").unwrap();
+    let mut s = g.view("s").unwrap();
+    for x in 0..10 {
+        assert_eq!(
+            c.lookup(&[x.into()], true).unwrap(),
+            vec![vec![x.into(), (n / 10).into()]]
+        );
+        let sum: i32 = (0..n).filter(|i| i % 10 == x).sum();
+        assert_eq!(
+            s.lookup(&[x.into()], true).unwrap(),
+            vec![vec![x.into(), sum.into()]]
+        );
+    }
+
+    // and writes after the seed add to it
+    let mut table = g.table("a").unwrap();
+    table.insert(vec![n.into(), 3.into()]).unwrap();
+    sleep();
+    assert_eq!(
+        c.lookup(&[3.into()], true).unwrap(),
+        vec![vec![3.into(), (n / 10 + 1).into()]]
+    );
+
+    // a seed that the base rejects fails the migration
+    let err = g
+        .try_migrate(|mig| {
+            let b = Base::default()
+                .with_key(vec![0])
+                .on_duplicate_key(OnDuplicateKey::Reject);
+            let b = mig.add_base("b", &["id", "v"], b);
+            mig.seed(b, vec![vec![1.into(), 1.into()], vec![1.into(), 2.into()]]);
+            mig.maintain_anonymous(b, &[0]);
+        })
+        .unwrap_err();
+    assert!(
+        err.contains("rejected its seed"),
+        "unexpected error: {}",
+        err
+    );
+}
+
+#[test]
+fn it_adds_union_parents() {
+    use dataflow::Placement;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(None);
+    g.set_persistence(get_persistence_params("it_adds_union_parents"));
+    let mut g = g.build_local().unwrap();
+    let (u, c) = g.migrate(|mig| {
+        let a = mig.add_base("a", &["id", "page"], Base::default());
+        let b = mig.add_base("b", &["id", "page"], Base::default());
+        let mut emits = HashMap::new();
+        emits.insert(a, vec![0, 1]);
+        emits.insert(b, vec![0, 1]);
+        let u = mig.add_ingredient("u", &["id", "page"], Union::new(emits));
+        let c = mig.add_ingredient("c", &["page", "n"], Aggregation::COUNT.over(u, 0, &[1]));
+        mig.maintain("c".into(), c, &[0]);
+        (u, c)
+    });
+    // a base that the union doesn't read from yet
+    let e = g.migrate(|mig| mig.add_base("e", &["id", "user", "page"], Base::default()));
+
+    let n = 1_000;
+    let pages = 5;
+    let row = move |i: i32| vec![i.into(), (i % pages).into()];
+    for base in &["a", "b"] {
+        let mut table = g.table(base).unwrap();
+        table
+            .insert_all((0..n).map(row).collect::<Vec<_>>())
+            .unwrap();
+    }
+    let mut table = g.table("e").unwrap().into_exclusive().unwrap();
+    table
+        .insert_all(
+            (0..n / 2)
+                .map(|i| vec![i.into(), 0.into(), (i % pages).into()])
+                .collect::<Vec<_>>(),
+        )
+        .unwrap();
+    sleep();
+
+    // keep writing to e while the union starts reading from it
+    let started = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let started = started.clone();
+        thread::spawn(move || {
+            for i in n / 2..n {
+                table
+                    .insert(vec![i.into(), 0.into(), (i % pages).into()])
+                    .unwrap();
+                started.store(true, Ordering::SeqCst);
+            }
+        })
+    };
+    while !started.load(Ordering::SeqCst) {
+        thread::yield_now();
+    }
+
+    // e reaches the union from its own domain, and the new base d from the union's domain
+    g.migrate(move |mig| {
+        mig.add_union_parent(u, e, vec![0, 2]);
+        let d = mig.add_base("d", &["id", "page"], Base::default());
+        mig.seed(d, (0..n).map(row));
+        mig.place(d, Placement::With(u));
+        mig.add_union_parent(u, d, vec![0, 1]);
+    });
+    writer.join().unwrap();
+    sleep();
+
+    // every row of every parent is counted exactly once
+    let mut counts = g.view("c").unwrap();
+    for page in 0..pages {
+        assert_eq!(
+            counts.lookup(&[page.into()], true).unwrap(),
+            vec![vec![page.into(), (4 * n / pages).into()]],
+            "page {}",
+            page
+        );
+    }
+
+    // and later writes to the new parents are counted too
+    g.table("d").unwrap().insert(row(n)).unwrap();
+    g.table("e")
+        .unwrap()
+        .insert(vec![n.into(), 0.into(), (n % pages).into()])
+        .unwrap();
+    sleep();
+    assert_eq!(
+        counts.lookup(&[(n % pages).into()], true).unwrap(),
+        vec![vec![(n % pages).into(), (4 * n / pages + 2).into()]]
+    );
+
+    let err = g
+        .try_migrate(move |mig| mig.add_union_parent(u, e, vec![0, 2]))
+        .unwrap_err();
+    assert!(
+        err.contains("already reads from it"),
+        "unexpected error: {}",
+        err
+    );
+    let err = g
+        .try_migrate(move |mig| mig.add_union_parent(c, e, vec![2, 0]))
+        .unwrap_err();
+    assert!(
+        err.contains("only existing unions"),
+        "unexpected error: {}",
+        err
+    );
+}
+
+#[test]
+fn it_reports_migration_events() {
+    use crate::{MigrationEventKind, MigrationPhase};
+    use dataflow::Placement;
+
+    // the new view must be fully materialized, so that it is replayed when the migration commits
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(None);
+    g.set_persistence(get_persistence_params("it_reports_migration_events"));
+    let mut g = g.build_local().unwrap();
+    let a = g.migrate(|mig| mig.add_base("a", &["id", "x"], Base::default()));
+
+    let n = 100;
+    let mut table = g.table("a").unwrap();
+    for i in 0..n {
+        table.insert(vec![i.into(), (i % 10).into()]).unwrap();
+    }
+    sleep();
+
+    let (events, c) = g.migrate(move |mig| {
+        let events = mig.events();
+        let c = mig.add_ingredient("c", &["x", "n"], Aggregation::COUNT.over(a, 0, &[1]));
+        mig.maintain_anonymous(c, &[0]);
+        (events, c)
+    });
+    let events: Vec<_> = events.iter().map(|e| e.kind).collect();
+
+    match events.first() {
+        Some(MigrationEventKind::Started { nodes }) => {
+            assert_eq!(nodes, &vec![(c, "c".to_owned())]);
+        }
+        e => panic!("expected migration to start, got {:?}", e),
+    }
+    let started: Vec<_> = events
+        .iter()
+        .filter_map(|e| match *e {
+            MigrationEventKind::PhaseStarted(phase) => Some(phase),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        started,
+        vec![
+            MigrationPhase::Planning,
+            MigrationPhase::Booting,
+            MigrationPhase::Informing,
+            MigrationPhase::Connecting,
+            MigrationPhase::Replaying,
+        ]
+    );
+    assert!(events.iter().any(|e| match *e {
+        MigrationEventKind::ReplayFinished {
+            node, records, of, ..
+        } => node == c && records == n as usize && of == 2,
+        _ => false,
+    }));
+    assert!(events.iter().any(|e| match *e {
+        MigrationEventKind::NodeReady { node, .. } => node == c,
+        _ => false,
+    }));
+    match events.last() {
+        Some(MigrationEventKind::Finished { phases, .. }) => {
+            let phases: Vec<_> = phases.iter().map(|&(p, _)| p).collect();
+            assert_eq!(phases, started);
+        }
+        e => panic!("expected migration to finish, got {:?}", e),
+    }
+
+    // failed migrations say so before the error is returned
+    let (tx, rx) = ::std::sync::mpsc::channel();
+    g.try_migrate(move |mig| {
+        tx.send(mig.events()).unwrap();
+        let i = mig.add_ingredient("i", &["id", "x"], Identity::new(a));
+        mig.place(i, Placement::Domain(1000));
+    })
+    .unwrap_err();
+    let events = rx.recv().unwrap();
+    match events.iter().last().map(|e| e.kind) {
+        Some(MigrationEventKind::Failed { error, .. }) => {
+            assert!(error.contains("there is no domain 1000"), "{}", error);
+        }
+        e => panic!("expected migration to fail, got {:?}", e),
+    }
+}
+
+// Replays the 50_000 rows of a base into a new view in its domain while a client keeps writing to
+// the base, and returns the 99th percentile of how long those writes took to be acknowledged, as
+// the client saw it and as the domain's metrics report it.
+fn replay_under_load(name: &str, priority: ReplayPriority) -> (Duration, f64) {
+    use crate::MigrationEventKind;
+    use dataflow::Placement;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Instant;
+
+    // the base's state has to be cloned for the replay, rather than streamed from disk
+    let mut params = get_persistence_params(name);
+    params.mode = DurabilityMode::MemoryOnly;
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(None);
+    g.set_persistence(params);
+    let mut g = g.build_local().unwrap();
+    let a = g.migrate(|mig| mig.add_base("a", &["id", "x"], Base::default()));
+
+    let n: i64 = 50_000;
+    let mut table = g.table("a").unwrap().into_exclusive().unwrap();
+    let rows: Vec<Vec<DataType>> = (0..n).map(|i| vec![i.into(), 0.into()]).collect();
+    table.insert_all(rows).unwrap();
+    sleep();
+
+    let started = Arc::new(AtomicBool::new(false));
+    let migrating = Arc::new(AtomicBool::new(false));
+    let done = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let started = started.clone();
+        let migrating = migrating.clone();
+        let done = done.clone();
+        thread::spawn(move || {
+            let mut took = Vec::new();
+            let mut i = n;
+            while !done.load(Ordering::SeqCst) {
+                let start = Instant::now();
+                table.insert(vec![i.into(), 0.into()]).unwrap();
+                if migrating.load(Ordering::SeqCst) {
+                    took.push(start.elapsed());
+                }
+                started.store(true, Ordering::SeqCst);
+                i += 1;
+            }
+            (i - n, took)
+        })
+    };
+    while !started.load(Ordering::SeqCst) {
+        thread::yield_now();
+    }
+
+    migrating.store(true, Ordering::SeqCst);
+    let events = g.migrate(move |mig| {
+        let events = mig.events();
+        mig.replay_priority(priority);
+        let late = mig.add_ingredient("late", &["id", "x"], Identity::new(a));
+        mig.place(late, Placement::With(a));
+        mig.maintain("late".into(), late, &[1]);
+        events
+    });
+    migrating.store(false, Ordering::SeqCst);
+    done.store(true, Ordering::SeqCst);
+    let (written, mut took) = writer.join().unwrap();
+    assert!(events.iter().any(|e| match e.kind {
+        MigrationEventKind::ReplayStarted { priority: p, .. } => p == priority,
+        _ => false,
+    }));
+
+    // however the replay made way for the writes, it all ends up in the view
+    sleep();
+    let mut late = g.view("late").unwrap();
+    let rows = late.lookup(&[0.into()], true).unwrap();
+    assert_eq!(rows.len() as i64, n + written);
+
+    took.sort();
+    assert!(!took.is_empty());
+    let p99 = took[took.len() * 99 / 100];
+    let metrics = g.metrics().unwrap();
+    let reported = metrics
+        .quantile("noria_domain_write_latency_ns", &[], 0.99)
+        .unwrap();
+    (p99, reported)
+}
+
+#[test]
+fn it_lets_replays_make_way_for_live_writes() {
+    let (replay, replay_reported) = replay_under_load(
+        "it_lets_replays_make_way_for_live_writes_replay",
+        ReplayPriority::FavorReplay,
+    );
+    let (live, live_reported) = replay_under_load(
+        "it_lets_replays_make_way_for_live_writes_live",
+        ReplayPriority::FavorLive { every: 1 },
+    );
+    assert!(live * 2 < replay, "{:?} vs {:?}", live, replay);
+    assert!(
+        live_reported * 2.0 < replay_reported,
+        "{} vs {}",
+        live_reported,
+        replay_reported
+    );
+}
+
+#[test]
+fn it_moves_views_to_new_domain() {
+    let mut g = build_local_unsharded("it_moves_views_to_new_domain");
+    let (vc, vc2) = g.migrate(|mig| {
+        let vote = mig.add_base("vote", &["user", "id"], Base::default());
+        let vc = mig.add_ingredient(
+            "votecount",
+            &["id", "votes"],
+            Aggregation::COUNT.over(vote, 0, &[1]),
+        );
+        let vc2 = mig.add_ingredient("votecount2", &["id", "votes"], Identity::new(vc));
+        mig.maintain_anonymous(vc, &[0]);
+        mig.maintain_anonymous(vc2, &[0]);
+        (vc, vc2)
+    });
+
+    let reader_domains = move |g: &mut LocalControllerHandle<LocalAuthority>| {
+        g.migrate(move |mig| {
+            let graph = mig.graph();
+            let domain_of_reader = |n| {
+                graph
+                    .node_indices()
+                    .find(|&r| graph[r].with_reader(|r| r.is_for() == n).unwrap_or(false))
+                    .map(|r| graph[r].domain())
+                    .unwrap()
+            };
+            (
+                graph[vc].domain(),
+                domain_of_reader(vc),
+                domain_of_reader(vc2),
+            )
+        })
+    };
+    let (d, r1, r2) = reader_domains(&mut g);
+    assert_eq!(d, r1);
+    assert_eq!(d, r2);
+
+    let mut vc_state = g.view("votecount").unwrap();
+    let mut vc2_state = g.view("votecount2").unwrap();
+    let mut add = g.table("vote").unwrap().into_exclusive().unwrap();
+
+    let ids = 1000;
+    let votes = 7;
+
+    // keep writing while the views are moved
+    let jh = thread::spawn(move || {
+        let user: DataType = 0.into();
+        add.batch_insert((0..votes).flat_map(|_| (0..ids).map(|i| vec![user.clone(), i.into()])))
+            .unwrap()
+    });
+
+    let moved = g.move_views(&["votecount", "votecount2"]).unwrap();
+    jh.join().unwrap();
+
+    let (d2, r1, r2) = reader_domains(&mut g);
+    assert_eq!(d2, d);
+    assert_eq!(r1.index(), moved);
+    assert_eq!(r2.index(), moved);
+    assert_ne!(r1, d);
+
+    // the existing views were taken over by the new readers, and no writes were lost
+    sleep();
+    for i in 0..ids {
+        let expected = vec![vec![i.into(), votes.into()]];
+        assert_eq!(vc_state.lookup(&[i.into()], true).unwrap(), expected);
+        assert_eq!(vc2_state.lookup(&[i.into()], true).unwrap(), expected);
+    }
+    assert_eq!(
+        g.view("votecount")
+            .unwrap()
+            .lookup(&[0.into()], true)
+            .unwrap(),
+        vec![vec![0.into(), votes.into()]]
+    );
+
+    // views that don't exist can't be moved
+    assert!(g.move_views(&["votecount", "nonexistent"]).is_err());
+}
+
+#[test]
+fn it_bulk_loads() {
+    let mut g = build_local("it_bulk_loads");
+    g.migrate(|mig| {
+        let vote = mig.add_base(
+            "vote",
+            &["id", "article"],
+            Base::default().with_key(vec![0]),
+        );
+        let vc = mig.add_ingredient(
+            "votecount",
+            &["article", "votes"],
+            Aggregation::COUNT.over(vote, 0, &[1]),
+        );
+        mig.maintain_anonymous(vc, &[0]);
+    });
+
+    let mut vc = g.view("votecount").unwrap();
+    let mut vote = g.table("vote").unwrap();
+    let rows = (0..10_000).map(|id| vec![id.into(), (id % 10).into()]);
+    let mut reported = Vec::new();
+    let loaded = vote.bulk_load(rows, 3_000, |n| reported.push(n)).unwrap();
+    assert_eq!(loaded, 10_000);
+    assert_eq!(reported, vec![3_000, 6_000, 9_000, 10_000]);
+
+    sleep();
+    for article in 0..10 {
+        assert_eq!(
+            vc.lookup(&[article.into()], true).unwrap(),
+            vec![vec![article.into(), 1_000.into()]]
+        );
+    }
+}
+
+#[test]
+fn it_expires_rows() {
+    let mut g = ControllerBuilder::default();
+    g.set_sharding(DEFAULT_SHARDING);
+    g.set_persistence(get_persistence_params("it_expires_rows"));
+    g.set_expiry_sweep(Duration::from_millis(50), 1024);
+    let mut g = g.build_local().unwrap();
+    g.migrate(|mig| {
+        let vote = mig.add_base(
+            "vote",
+            &["id", "article"],
+            Base::default()
+                .with_key(vec![0])
+                .with_ttl(Duration::from_secs(1)),
+        );
+        let vc = mig.add_ingredient(
+            "votecount",
+            &["article", "votes"],
+            Aggregation::COUNT.over(vote, 0, &[1]),
+        );
+        mig.maintain_anonymous(vc, &[0]);
+    });
+
+    let mut vc = g.view("votecount").unwrap();
+    let mut vote = g.table("vote").unwrap();
+    vote.insert(vec![1.into(), 1.into()]).unwrap();
+    vote.insert(vec![2.into(), 1.into()]).unwrap();
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(
+        vc.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 2.into()]]
+    );
+
+    thread::sleep(Duration::from_millis(600));
+    vote.insert(vec![3.into(), 1.into()]).unwrap();
+
+    // the first two votes should now have expired, but not the third
+    thread::sleep(Duration::from_millis(600));
+    assert_eq!(
+        vc.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 1.into()]]
+    );
+}
+
+#[test]
+fn it_works_w_partial_mat() {
+    // set up graph
+    let mut g = build_local("it_works_w_partial_mat");
+    let (a, b) = g.migrate(|mig| {
+        let a = mig.add_base("a", &["a", "b"], Base::default());
+        let b = mig.add_base("b", &["a", "b"], Base::default());
+        (a, b)
+    });
+
+    let mut muta = g.table("a").unwrap();
+    let id: DataType = 1.into();
+
+    // send a few values on a
+    muta.insert(vec![id.clone(), 1.into()]).unwrap();
+    muta.insert(vec![id.clone(), 2.into()]).unwrap();
+    muta.insert(vec![id.clone(), 3.into()]).unwrap();
+
+    // give it some time to propagate
+    sleep();
+
+    let _ = g.migrate(move |mig| {
+        let mut emits = HashMap::new();
+        emits.insert(a, vec![0, 1]);
+        emits.insert(b, vec![0, 1]);
+        let u = Union::new(emits);
+        let c = mig.add_ingredient("c", &["a", "b"], u);
+        mig.maintain_anonymous(c, &[0]);
+        c
+    });
+
+    // give it some time to propagate
+    sleep();
+
+    let mut cq = g.view("c").unwrap();
+
+    // because the reader is partial, we should have no key until we read
+    assert_eq!(cq.len().unwrap(), 0);
+
+    // now do some reads
+    let res = cq.lookup(&[id.clone()], true).unwrap();
+    assert_eq!(res.len(), 3);
+    assert!(res.iter().any(|r| r == &vec![id.clone(), 1.into()]));
+    assert!(res.iter().any(|r| r == &vec![id.clone(), 2.into()]));
+    assert!(res.iter().any(|r| r == &vec![id.clone(), 3.into()]));
+
+    // should have one key in the reader now
+    assert_eq!(cq.len().unwrap(), 1);
+}
+
+#[test]
+fn it_lists_largest_materializations() {
+    let mut g = build_local_unsharded("it_lists_largest_materializations");
+    g.migrate(|mig| {
+        mig.add_base("big", &["k", "v"], Base::new(vec![]).with_key(vec![0]));
+        mig.add_base("small", &["k", "v"], Base::new(vec![]).with_key(vec![0]));
+    });
+
+    let mut big = g.table("big").unwrap();
+    let mut small = g.table("small").unwrap();
+    for k in 0..100 {
+        big.insert(vec![k.into(), "some text that takes up space".into()])
+            .unwrap();
+    }
+    small.insert(vec![0.into(), 0.into()]).unwrap();
+    sleep();
+
+    let largest = g.largest_materializations(2).unwrap();
+    assert_eq!(largest.len(), 2);
+    assert_eq!(largest[0].1, "big");
+    assert_eq!(largest[1].1, "small");
+    assert!(largest[0].2 > largest[1].2);
+}
+
+#[test]
+fn it_summarizes_statistics_per_node_and_domain() {
+    use noria::debug::stats::Freshness;
+    use noria::debug::topology::NodeKind;
+
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(None);
+    g.set_persistence(get_persistence_params(
+        "it_summarizes_statistics_per_node_and_domain",
+    ));
+    let mut g = g.build_local().unwrap();
+    let (a, c) = g.migrate(|mig| {
+        let a = mig.add_base("a", &["id", "x"], Base::new(vec![]).with_key(vec![0]));
+        let c = mig.add_ingredient("c", &["x", "n"], Aggregation::COUNT.over(a, 0, &[1]));
+        mig.maintain("counts".into(), c, &[0]);
+        (a, c)
+    });
+
+    let n = 100;
+    let mut table = g.table("a").unwrap();
+    for i in 0..n {
+        table.insert(vec![i.into(), (i % 10).into()]).unwrap();
+    }
+    sleep();
+
+    let stats = g.statistics().unwrap();
+    assert_eq!(stats.totals.stale_domains, 0);
+    assert_eq!(stats.totals.unreachable_domains, 0);
+    assert!(stats
+        .domain_entries
+        .iter()
+        .all(|d| d.freshness == Freshness::Fresh));
+
+    let base = stats.node(a).unwrap();
+    assert_eq!(base.kind, NodeKind::Base);
+    assert_eq!(base.public_name, Some("a".to_owned()));
+    assert_eq!(base.processed_records, n as u64);
+    assert_eq!(base.rows, Some(n as usize));
+
+    let count = stats.node(c).unwrap();
+    assert_eq!(count.kind, NodeKind::Internal);
+    assert_eq!(count.public_name, Some("counts".to_owned()));
+    assert_eq!(count.processed_records, n as u64);
+    assert_eq!(count.rows, Some(10));
+    assert!(count.bytes > 0);
+
+    let reader = stats
+        .nodes
+        .iter()
+        .find(|n| n.kind == NodeKind::Reader)
+        .unwrap();
+    assert_eq!(reader.public_name, Some("counts".to_owned()));
+    assert_eq!(reader.rows, None);
+
+    // the domains add up to the whole graph
+    assert_eq!(
+        stats.domain_entries.iter().map(|d| d.nodes).sum::<usize>(),
+        stats.totals.nodes
+    );
+    assert_eq!(
+        stats.nodes.iter().map(|n| n.processed_records).sum::<u64>(),
+        stats.totals.processed_records
+    );
+    assert!(stats.domain(base.domain).unwrap().rows >= n as usize);
+
+    let report = stats.to_string();
+    assert!(report.contains("top materializations by memory"));
+    assert!(report.contains(&format!("n{} c (counts)", c.index())));
+}
+
+#[test]
+fn it_reports_configured_channel_capacities() {
+    use crate::ChannelConfig;
+
+    let channels = ChannelConfig {
+        control: 4 * 1024,
+        domain: 64 * 1024,
+        input: 16 * 1024,
+        replay: 2 * 1024,
+    };
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(None);
+    g.set_channels(channels.clone());
+    g.set_persistence(get_persistence_params(
+        "it_reports_configured_channel_capacities",
+    ));
+    let mut g = g.build_local().unwrap();
+    let (a, c) = g.migrate(|mig| {
+        let a = mig.add_base("a", &["id", "x"], Base::default());
+        let c = mig.add_ingredient(
+            "BOUNDARY_c",
+            &["x", "n"],
+            Aggregation::COUNT.over(a, 0, &[1]),
+        );
+        mig.maintain("c".into(), c, &[0]);
+        (a, c)
+    });
+
+    let mut table = g.table("a").unwrap();
+    table.insert(vec![1.into(), 7.into()]).unwrap();
+    sleep();
+
+    let stats = g.statistics().unwrap();
+    let base = stats.node(a).unwrap().domain;
+    let count = stats.node(c).unwrap().domain;
+    assert_ne!(base, count);
+    for &domain in &[base, count] {
+        let ch = &stats[&(domain, 0)].0.channels;
+        assert_eq!(ch.control.capacity, channels.control);
+        assert_eq!(ch.control.connections, 1);
+        assert_eq!(ch.domain.capacity, channels.domain);
+        assert_eq!(ch.input.capacity, channels.input);
+        assert_eq!(ch.replay.capacity, channels.replay);
+    }
+
+    // only the base is written to
+    assert_eq!(stats[&(base, 0)].0.channels.input.connections, 1);
+    assert_eq!(stats[&(count, 0)].0.channels.input.connections, 0);
+}
+
+#[test]
+#[should_panic(expected = "the domain channel capacity must be non-zero")]
+fn it_rejects_channels_without_room() {
+    use crate::ChannelConfig;
+
+    let mut g = ControllerBuilder::default();
+    g.set_channels(ChannelConfig {
+        domain: 0,
+        ..ChannelConfig::default()
+    });
+}
+
+#[test]
+fn it_reports_domains_that_panic() {
+    use dataflow::ops::project::{ProjectExpression, ProjectExpressionBase};
+    use nom_sql::ArithmeticOperator;
+    use noria::debug::stats::Freshness;
+    use std::time::Instant;
+
+    let mut g = build_local_unsharded("it_reports_domains_that_panic");
+    let p = g.migrate(|mig| {
+        let a = mig.add_base("a", &["id", "x"], Base::default());
+        // adding a number to text panics
+        let plus_one = ProjectExpression::new(
+            ArithmeticOperator::Add,
+            ProjectExpressionBase::Column(1),
+            ProjectExpressionBase::Literal(1.into()),
+        );
+        let p = mig.add_ingredient(
+            "p",
+            &["id", "x", "x1"],
+            Project::new(a, &[0, 1], None, Some(vec![plus_one])),
+        );
+        mig.maintain("plus_one".into(), p, &[0]);
+        p
+    });
+
+    let mut a = g.table("a").unwrap();
+    a.insert(vec![1.into(), "one".into()]).unwrap();
+
+    let start = Instant::now();
+    let stats = loop {
+        let stats = g.statistics().unwrap();
+        if !stats.failures.is_empty() {
+            break stats;
+        }
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "the panic was never reported"
+        );
+        thread::sleep(Duration::from_millis(50));
+    };
+
+    let domain = stats.node(p).unwrap().domain;
+    assert_eq!(stats.failures.len(), 1);
+    let failure = &stats.failures[0];
+    assert_eq!(failure.domain, domain);
+    assert!(failure.message.contains("can't +"), "{}", failure.message);
+    assert!(failure.backtrace.is_some());
+    assert_eq!(stats.domain(domain).unwrap().freshness, Freshness::Failed);
+    assert_eq!(stats.totals.failed_domains, 1);
+    assert!(stats.to_string().contains(&failure.to_string()));
+}
+
+#[test]
+fn it_runs_domains_deterministically() {
+    use std::time::Instant;
+
+    // the order in which the union sees the writes to its two parents decides the order of the
+    // rows in the view
+    fn run(seed: u64) -> Vec<Vec<DataType>> {
+        let mut g = ControllerBuilder::default();
+        g.set_sharding(None);
+        g.set_persistence(get_persistence_params("it_runs_domains_deterministically"));
+        g.set_deterministic(seed);
+        let mut g = g.build_local().unwrap();
+        g.migrate(|mig| {
+            let a = mig.add_base("a", &["k", "v"], Base::default());
+            let b = mig.add_base("b", &["k", "v"], Base::default());
+
+            let mut emits = HashMap::new();
+            emits.insert(a, vec![0, 1]);
+            emits.insert(b, vec![0, 1]);
+            let u = mig.add_ingredient("u", &["k", "v"], Union::new(emits));
+            mig.maintain("u".into(), u, &[0]);
+        });
+
+        let mut a = g.table("a").unwrap();
+        let mut b = g.table("b").unwrap();
+        for i in 0..20 {
+            a.insert(vec![1.into(), i.into()]).unwrap();
+            b.insert(vec![1.into(), (100 + i).into()]).unwrap();
+        }
+
+        let mut u = g.view("u").unwrap();
+        let start = Instant::now();
+        loop {
+            let rows = u.lookup(&[1.into()], true).unwrap();
+            if rows.len() == 40 {
+                return rows;
+            }
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    let first = run(42);
+    assert_eq!(run(42), first);
+}
+
+#[test]
+fn it_processes_packets_held_back_by_injected_delays() {
+    use crate::FaultInjector;
+    use std::time::Instant;
+
+    let faults = FaultInjector::new();
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(None);
+    g.set_fault_injector(faults.clone());
+    g.set_persistence(get_persistence_params(
+        "it_processes_packets_held_back_by_injected_delays",
+    ));
+    let mut g = g.build_local().unwrap();
+    let c = g.migrate(|mig| {
+        let a = mig.add_base("a", &["id", "x"], Base::default());
+        // the count gets a domain of its own, so its updates arrive in packets from the base's
+        let c = mig.add_ingredient(
+            "BOUNDARY_c",
+            &["x", "n"],
+            Aggregation::COUNT.over(a, 0, &[1]),
+        );
+        mig.maintain("c".into(), c, &[0]);
+        c
+    });
+    let domain = g.statistics().unwrap().node(c).unwrap().domain;
+
+    let mut table = g.table("a").unwrap();
+    let mut view = g.view("c").unwrap();
+    let delay = faults.delay(Duration::from_secs(2), domain, |p| p.is_regular());
+    let start = Instant::now();
+    table.insert(vec![1.into(), 7.into()]).unwrap();
+    sleep();
+    assert!(view.lookup(&[7.into()], true).unwrap().is_empty());
+
+    // the update still gets there once the delay is up
+    loop {
+        let rows = view.lookup(&[7.into()], true).unwrap();
+        if !rows.is_empty() {
+            assert_eq!(rows, vec![vec![7.into(), 1.into()]]);
+            break;
+        }
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "the delayed update never arrived"
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(start.elapsed() >= Duration::from_secs(2));
+
+    // and without the policy, updates arrive right away again
+    assert!(faults.remove(delay));
+    assert!(!faults.remove(delay));
+    table.insert(vec![2.into(), 7.into()]).unwrap();
+    sleep();
+    assert_eq!(
+        view.lookup(&[7.into()], true).unwrap(),
+        vec![vec![7.into(), 2.into()]]
+    );
+}
+
+#[test]
+fn it_rejects_writes_while_a_domain_below_is_overloaded() {
+    use crate::{Backpressure, FaultInjector, OverloadPolicy};
+    use noria::error::{TableError, WriteError};
+    use std::time::Instant;
+
+    let faults = FaultInjector::new();
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(None);
+    g.set_fault_injector(faults.clone());
+    g.set_backpressure(Backpressure {
+        high: 16,
+        low: 4,
+        policy: OverloadPolicy::Reject,
+    });
+    g.set_persistence(get_persistence_params(
+        "it_rejects_writes_while_a_domain_below_is_overloaded",
+    ));
+    let mut g = g.build_local().unwrap();
+    let c = g.migrate(|mig| {
+        let a = mig.add_base("a", &["id", "x"], Base::default());
+        let c = mig.add_ingredient(
+            "BOUNDARY_c",
+            &["x", "n"],
+            Aggregation::COUNT.over(a, 0, &[1]),
+        );
+        mig.maintain("c".into(), c, &[0]);
+        c
+    });
+    let domain = g.statistics().unwrap().node(c).unwrap().domain;
+    let label = domain.index().to_string();
+
+    // the count is slowed down, so the updates sent to it pile up
+    let mut table = g.table("a").unwrap();
+    let mut view = g.view("c").unwrap();
+    let delay = faults.delay(Duration::from_millis(500), domain, |p| p.is_regular());
+    let mut accepted: i64 = 0;
+    let mut rejected = 0;
+    for i in 0..2000 {
+        match table.insert(vec![i.into(), 7.into()]) {
+            Ok(_) => accepted += 1,
+            Err(TableError::Rejected(WriteError::Overloaded)) => rejected += 1,
+            Err(e) => panic!("unexpected write error: {:?}", e),
+        }
+    }
+    assert!(rejected > 0);
+
+    // only so many updates were let through before writes were turned away
+    let m = g.local_metrics();
+    let peak = m.sum("noria_domain_peak_queued_packets", &[("domain", &label)]);
+    assert!(peak >= 16 && peak < 100, "{} packets piled up", peak);
+    assert_eq!(m.sum("noria_domain_throttled_writes", &[]), rejected);
+
+    // the writes that were accepted all make it to the count, after which writes are let through
+    assert!(faults.remove(delay));
+    let start = Instant::now();
+    loop {
+        let rows = view.lookup(&[7.into()], true).unwrap();
+        if rows == vec![vec![7.into(), accepted.into()]] {
+            break;
+        }
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "the count never caught up: {:?}",
+            rows
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+    sleep();
+    table.insert(vec![2000.into(), 7.into()]).unwrap();
+}
+
+#[test]
+fn it_blocks_writes_while_a_domain_below_is_overloaded() {
+    use crate::{Backpressure, FaultInjector, OverloadPolicy};
+    use std::time::Instant;
+
+    let faults = FaultInjector::new();
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(None);
+    g.set_fault_injector(faults.clone());
+    g.set_backpressure(Backpressure {
+        high: 16,
+        low: 4,
+        policy: OverloadPolicy::Block,
+    });
+    g.set_persistence(get_persistence_params(
+        "it_blocks_writes_while_a_domain_below_is_overloaded",
+    ));
+    let mut g = g.build_local().unwrap();
+    let c = g.migrate(|mig| {
+        let a = mig.add_base("a", &["id", "x"], Base::default());
+        let c = mig.add_ingredient(
+            "BOUNDARY_c",
+            &["x", "n"],
+            Aggregation::COUNT.over(a, 0, &[1]),
+        );
+        mig.maintain("c".into(), c, &[0]);
+        c
+    });
+    let domain = g.statistics().unwrap().node(c).unwrap().domain;
+    let label = domain.index().to_string();
+
+    // every write gets through, but has to wait for the slowed down count to catch up first
+    let mut table = g.table("a").unwrap();
+    let mut view = g.view("c").unwrap();
+    faults.delay(Duration::from_millis(100), domain, |p| p.is_regular());
+    for i in 0..200 {
+        table.insert(vec![i.into(), 7.into()]).unwrap();
+    }
+
+    let m = g.local_metrics();
+    let peak = m.sum("noria_domain_peak_queued_packets", &[("domain", &label)]);
+    assert!(peak >= 16 && peak < 100, "{} packets piled up", peak);
+    assert!(m.sum("noria_domain_throttled_writes", &[]) > 0);
+
+    let start = Instant::now();
+    loop {
+        let rows = view.lookup(&[7.into()], true).unwrap();
+        if rows == vec![vec![7.into(), 200.into()]] {
+            break;
+        }
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "the count never caught up: {:?}",
+            rows
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn it_pauses_and_resumes_domains() {
+    use crate::{Backpressure, OverloadPolicy};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(None);
+    g.set_backpressure(Backpressure {
+        high: 16,
+        low: 4,
+        policy: OverloadPolicy::Block,
+    });
+    g.set_persistence(get_persistence_params("it_pauses_and_resumes_domains"));
+    let mut g = g.build_local().unwrap();
+    let c = g.migrate(|mig| {
+        let a = mig.add_base("a", &["id", "x"], Base::default());
+        let c = mig.add_ingredient(
+            "BOUNDARY_c",
+            &["x", "n"],
+            Aggregation::COUNT.over(a, 0, &[1]),
+        );
+        mig.maintain("c".into(), c, &[0]);
+        c
+    });
+    let domain = g.statistics().unwrap().node(c).unwrap().domain;
+
+    let mut table = g.table("a").unwrap();
+    table.insert(vec![0.into(), 0.into()]).unwrap();
+    sleep();
+    g.pause_domain(domain).unwrap();
+    let checksum = g.checksum(c).unwrap();
+    assert!(checksum.is_some());
+
+    // the writer is held up once enough updates pile up at the paused count
+    let written = Arc::new(AtomicUsize::new(1));
+    let writer = {
+        let written = written.clone();
+        let mut table = table.into_exclusive().unwrap();
+        thread::spawn(move || {
+            for i in 1..200 {
+                table.insert(vec![i.into(), (i % 5).into()]).unwrap();
+                written.fetch_add(1, Ordering::SeqCst);
+            }
+        })
+    };
+    thread::sleep(Duration::from_secs(1));
+    assert!(written.load(Ordering::SeqCst) < 200);
+
+    // the paused domain still answers, and nothing has changed its state
+    assert_eq!(g.checksum(c).unwrap(), checksum);
+    let stats = g.statistics().unwrap();
+    assert!(stats.domains[&(domain, 0)].0.paused);
+    assert!(stats.domains[&(domain, 0)].0.queued_packets > 0);
+    let e = g
+        .try_migrate(|mig| {
+            mig.add_base("b", &["id"], Base::default());
+        })
+        .unwrap_err();
+    assert!(e.contains("paused"), "{}", e);
+
+    // once resumed, the count picks up every write, as if it had never been paused
+    g.resume_domain(domain).unwrap();
+    writer.join().unwrap();
+    sleep();
+    assert!(!g.statistics().unwrap().domains[&(domain, 0)].0.paused);
+    let mut view = g.view("c").unwrap();
+    for x in 0..5 {
+        assert_eq!(
+            view.lookup(&[x.into()], true).unwrap(),
+            vec![vec![x.into(), 40.into()]]
+        );
+    }
+}
+
+#[test]
+fn it_moves_a_domain_while_writes_continue() {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::time::Instant;
+
+    let build = |authority: &Arc<LocalAuthority>| {
+        let mut g = ControllerBuilder::default();
+        g.set_persistence(get_persistence_params("it_moves_a_domain"));
+        g.set_sharding(None);
+        g.disable_partial();
+        g.set_quorum(2);
+        g.build(authority.clone()).unwrap()
+    };
+    let authority = Arc::new(LocalAuthority::new());
+    let mut g = build(&authority);
+    let _worker = build(&authority);
+    let (a, c) = g.migrate(|mig| {
+        let a = mig.add_base("a", &["id", "x"], Base::default());
+        let c = mig.add_ingredient(
+            "BOUNDARY_c",
+            &["x", "n"],
+            Aggregation::COUNT.over(a, 0, &[1]),
+        );
+        let d = mig.add_ingredient("BOUNDARY_d", &["x", "n"], Identity::new(c));
+        mig.maintain("d".into(), d, &[0]);
+        (a, c)
+    });
+    let stats = g.statistics().unwrap();
+    let domain = stats.node(c).unwrap().domain;
+    let workers: Vec<_> = g.instances().unwrap().into_iter().map(|w| w.0).collect();
+    assert_eq!(workers.len(), 2);
+
+    // domains with bases stay where they are
+    let base = stats.node(a).unwrap().domain;
+    let e = g.move_domain(base, workers[0]).unwrap_err().to_string();
+    assert!(e.contains("bases"), "{}", e);
+
+    let mut table = g.table("a").unwrap().into_exclusive().unwrap();
+    for i in 0..1000 {
+        table.insert(vec![i.into(), (i % 5).into()]).unwrap();
+    }
+
+    // writes keep going while the count moves to the other worker, and are at most held up while
+    // the domain above it is paused
+    let written = Arc::new(AtomicUsize::new(1000));
+    let done = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let written = written.clone();
+        let done = done.clone();
+        thread::spawn(move || {
+            let mut slowest = Duration::from_millis(0);
+            let mut i = 1000;
+            while !done.load(Ordering::SeqCst) {
+                let start = Instant::now();
+                table.insert(vec![i.into(), (i % 5).into()]).unwrap();
+                slowest = slowest.max(start.elapsed());
+                written.fetch_add(1, Ordering::SeqCst);
+                i += 1;
+            }
+            slowest
+        })
+    };
+    thread::sleep(Duration::from_millis(100));
+
+    let before = written.load(Ordering::SeqCst);
+    let moved = workers
+        .iter()
+        .filter_map(|&w| g.move_domain(domain, w).ok())
+        .next()
+        .expect("the domain could not be moved to either worker");
+    let during = written.load(Ordering::SeqCst) - before;
+    thread::sleep(Duration::from_millis(100));
+    done.store(true, Ordering::SeqCst);
+    let slowest = writer.join().unwrap();
+
+    assert_eq!(moved.domain, domain);
+    assert_ne!(moved.from, moved.to);
+    assert_eq!(moved.snapshot_rows, 5);
+    assert!(moved.paused < moved.total);
+    assert!(during > 0, "no writes went through during the move");
+    assert!(slowest < Duration::from_secs(2), "a write took {:?}", slowest);
+
+    // the moved count has seen every write exactly once, and keeps up with new ones
+    let mut table = g.table("a").unwrap();
+    table.insert(vec![(-1).into(), 0.into()]).unwrap();
+    sleep();
+    let written = written.load(Ordering::SeqCst) + 1;
+    let mut view = g.view("d").unwrap();
+    let mut total = 0;
+    for x in 0..5 {
+        let rows = view.lookup(&[x.into()], true).unwrap();
+        assert_eq!(rows.len(), 1, "{:?}", rows);
+        let n: i64 = (&rows[0][1]).into();
+        total += n;
+    }
+    assert_eq!(total, written as i64);
+    assert_eq!(
+        g.statistics().unwrap().domains.keys().filter(|k| k.0 == domain).count(),
+        1
+    );
+
+    // and it can be moved back
+    let back = g.move_domain(domain, moved.from).unwrap();
+    assert_eq!(back.to, moved.from);
+    assert_eq!(back.snapshot_rows, 5);
+}
+
+#[test]
+fn it_applies_every_acknowledged_write_to_a_new_base() {
+    use noria::ControllerHandle;
+
+    let authority = Arc::new(LocalAuthority::new());
+    let mut g = ControllerBuilder::default();
+    g.set_persistence(get_persistence_params("it_applies_every_acknowledged_write"));
+    let mut g = g.build(authority.clone()).unwrap();
+
+    // bases are added one at a time, and another client writes to each as soon as it shows up
+    let n = 10;
+    let writer = thread::spawn(move || {
+        let mut h = ControllerHandle::make(authority).unwrap();
+        let mut acked = Vec::new();
+        for i in 0..n {
+            let name = format!("t{}", i);
+            while !h.inputs().unwrap().contains_key(&name) {
+                thread::sleep(Duration::from_millis(1));
+            }
+            let mut table = h.table(&name).unwrap();
+            for j in 0..20 {
+                table.insert(vec![j.into()]).unwrap();
+                acked.push((i, j));
+            }
+        }
+        acked
+    });
+    for i in 0..n {
+        g.migrate(move |mig| {
+            let t = mig.add_base(format!("t{}", i), &["x"], Base::default());
+            mig.maintain_anonymous(t, &[0]);
+        });
+    }
+
+    let acked = writer.join().unwrap();
+    assert_eq!(acked.len(), n * 20);
+    sleep();
+    let mut views: Vec<_> = (0..n)
+        .map(|i| g.view(&format!("t{}", i)).unwrap())
+        .collect();
+    for (i, j) in acked {
+        assert_eq!(
+            views[i].lookup(&[j.into()], true).unwrap(),
+            vec![vec![j.into()]],
+            "write {} to t{} went missing",
+            j,
+            i
+        );
+    }
+}
+
+#[test]
+fn it_quiesces_with_writes_in_flight() {
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(Some(2));
+    g.set_persistence(get_persistence_params("it_quiesces_with_writes_in_flight"));
+    let mut g = g.build_local().unwrap();
+    g.migrate(|mig| {
+        let a = mig.add_base("a", &["id", "x"], Base::default());
+        let c = mig.add_ingredient("c", &["x", "n"], Aggregation::COUNT.over(a, 0, &[1]));
+        mig.maintain("c".into(), c, &[0]);
+    });
+
+    // the writes have all reached the base, but are yet to make it through the rest of the graph
+    let mut table = g.table("a").unwrap();
+    for i in 0..500 {
+        table.insert(vec![i.into(), (i % 5).into()]).unwrap();
+    }
+    g.quiesce(Duration::from_secs(10)).unwrap();
+
+    let mut view = g.view("c").unwrap();
+    for x in 0..5 {
+        let rows = view.lookup(&[x.into()], false).unwrap();
+        assert_eq!(rows, vec![vec![x.into(), 100.into()]]);
+    }
+    let report = g.check_consistency().unwrap();
+    assert!(report.is_consistent(), "{}", report);
+
+    // writes are let through again afterwards
+    table.insert(vec![500.into(), 0.into()]).unwrap();
+    sleep();
+    let rows = view.lookup(&[0.into()], false).unwrap();
+    assert_eq!(rows, vec![vec![0.into(), 101.into()]]);
+}
+
+#[test]
+fn it_names_the_domains_that_did_not_quiesce_in_time() {
+    use crate::FaultInjector;
+    use dataflow::Packet;
+    use noria::error::NotQuiesced;
+
+    let faults = FaultInjector::new();
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(None);
+    g.set_fault_injector(faults.clone());
+    g.set_persistence(get_persistence_params(
+        "it_names_the_domains_that_did_not_quiesce_in_time",
+    ));
+    let mut g = g.build_local().unwrap();
+    let c = g.migrate(|mig| {
+        let a = mig.add_base("a", &["id", "x"], Base::default());
+        let c = mig.add_ingredient(
+            "BOUNDARY_c",
+            &["x", "n"],
+            Aggregation::COUNT.over(a, 0, &[1]),
+        );
+        mig.maintain("c".into(), c, &[0]);
+        c
+    });
+    let domain = g.statistics().unwrap().node(c).unwrap().domain;
+
+    // the updates to the count, and the barrier behind them, are slowed down
+    let mut table = g.table("a").unwrap();
+    let delay = faults.delay(Duration::from_secs(2), domain, |p| match *p {
+        Packet::Message { .. } | Packet::Barrier { .. } => true,
+        _ => false,
+    });
+    for i in 0..10 {
+        table.insert(vec![i.into(), 7.into()]).unwrap();
+    }
+    let e = g.quiesce(Duration::from_millis(500)).unwrap_err();
+    let stuck = e.downcast::<NotQuiesced>().unwrap();
+    assert!(stuck.domains.contains(&(domain, 0)), "{:?}", stuck.domains);
+
+    // once they are through, the next quiesce completes
+    assert!(faults.remove(delay));
+    thread::sleep(Duration::from_secs(2));
+    g.quiesce(Duration::from_secs(10)).unwrap();
+    let mut view = g.view("c").unwrap();
+    let rows = view.lookup(&[7.into()], false).unwrap();
+    assert_eq!(rows, vec![vec![7.into(), 10.into()]]);
+}
+
+#[test]
+fn it_waits_for_writes_to_be_visible() {
+    use dataflow::ops::filter::{Filter, FilterCondition, Operator, Value};
+
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(Some(2));
+    g.set_persistence(get_persistence_params("it_waits_for_writes_to_be_visible"));
+    let mut g = g.build_local().unwrap();
+    g.migrate(|mig| {
+        let a = mig.add_base("a", &["id", "x"], Base::default());
+        let c = mig.add_ingredient(
+            "BOUNDARY_c",
+            &["x", "n"],
+            Aggregation::COUNT.over(a, 0, &[1]),
+        );
+        mig.maintain("c".into(), c, &[0]);
+        // nothing ever makes it past this one
+        let cond = FilterCondition::Comparison(Operator::Less, Value::Constant(0.into()));
+        let none = mig.add_ingredient("none", &["id", "x"], Filter::new(a, &[None, Some(cond)]));
+        mig.maintain("none".into(), none, &[0]);
+    });
+
+    // each write is visible as soon as the wait for it is over
+    let mut table = g.table("a").unwrap();
+    let mut c = g.view("c").unwrap();
+    for i in 0..10 {
+        let row = vec![i.into(), 7.into()];
+        table
+            .batch_insert_visible(vec![row], &mut c, Duration::from_secs(10))
+            .unwrap();
+        let rows = c.lookup(&[7.into()], false).unwrap();
+        assert_eq!(rows, vec![vec![7.into(), (i + 1).into()]]);
+    }
+
+    // and the wait also ends for writes that don't change the view at all
+    let mut none = g.view("none").unwrap();
+    let row = vec![10.into(), 7.into()];
+    table
+        .batch_insert_visible(vec![row], &mut none, Duration::from_secs(10))
+        .unwrap();
+    assert!(none.lookup(&[10.into()], false).unwrap().is_empty());
+}
+
+#[test]
+fn it_stops_waiting_for_writes_that_are_held_up() {
+    use crate::FaultInjector;
+    use noria::error::TableError;
+
+    let faults = FaultInjector::new();
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(None);
+    g.set_fault_injector(faults.clone());
+    g.set_persistence(get_persistence_params(
+        "it_stops_waiting_for_writes_that_are_held_up",
+    ));
+    let mut g = g.build_local().unwrap();
+    let c = g.migrate(|mig| {
+        let a = mig.add_base("a", &["id", "x"], Base::default());
+        let c = mig.add_ingredient(
+            "BOUNDARY_c",
+            &["x", "n"],
+            Aggregation::COUNT.over(a, 0, &[1]),
+        );
+        mig.maintain("c".into(), c, &[0]);
+        c
+    });
+    let domain = g.statistics().unwrap().node(c).unwrap().domain;
+
+    // the count is slowed down past the time the write is waited for
+    let mut table = g.table("a").unwrap();
+    let mut view = g.view("c").unwrap();
+    let delay = faults.delay(Duration::from_secs(2), domain, |p| p.is_regular());
+    let row = vec![1.into(), 7.into()];
+    match table.batch_insert_visible(vec![row], &mut view, Duration::from_millis(500)) {
+        Err(TableError::NotVisible(_)) => {}
+        r => panic!("expected the write not to be visible in time: {:?}", r),
+    }
+
+    // the write still goes through in the end
+    assert!(faults.remove(delay));
+    thread::sleep(Duration::from_secs(2));
+    let rows = view.lookup(&[7.into()], true).unwrap();
+    assert_eq!(rows, vec![vec![7.into(), 1.into()]]);
+}
+
+// Adds three counts over the same base, each in a domain of its own, and returns the domains of
+// the first two.
+fn standby_counts(g: &mut LocalControllerHandle<LocalAuthority>) -> (DomainIndex, DomainIndex) {
+    let (c1, c2) = g.migrate(|mig| {
+        let a = mig.add_base("a", &["id", "x"], Base::default());
+        let mut counts = Vec::new();
+        for name in &["c1", "c2", "c3"] {
+            let c = mig.add_ingredient(
+                format!("BOUNDARY_{}", name),
+                &["x", "n"],
+                Aggregation::COUNT.over(a, 0, &[1]),
+            );
+            mig.maintain(name.to_string(), c, &[0]);
+            counts.push(c);
+        }
+        (counts[0], counts[1])
+    });
+    let stats = g.statistics().unwrap();
+    (
+        stats.node(c1).unwrap().domain,
+        stats.node(c2).unwrap().domain,
+    )
+}
+
+// Waits for the counts in the view `name`, and in the count "c3", which is never interrupted, to
+// both be `n` for every key.
+fn wait_for_counts(g: &mut LocalControllerHandle<LocalAuthority>, name: &str, n: i32) {
+    use std::time::Instant;
+
+    let mut view = g.view(name).unwrap();
+    let mut c3 = g.view("c3").unwrap();
+    let keys: Vec<Vec<DataType>> = (0..5).map(|x| vec![x.into()]).collect();
+    let expected: Vec<_> = (0..5).map(|x| vec![vec![x.into(), n.into()]]).collect();
+    let start = Instant::now();
+    loop {
+        let rows = view.multi_lookup(keys.clone(), true).unwrap();
+        if rows == expected && c3.multi_lookup(keys.clone(), true).unwrap() == expected {
+            break;
+        }
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "{} never caught up: {:?}",
+            name,
+            rows
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn it_fails_over_to_a_standby_when_a_domain_crashes() {
+    use crate::FaultInjector;
+    use std::time::Instant;
+
+    let faults = FaultInjector::new();
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(None);
+    g.set_fault_injector(faults.clone());
+    g.set_persistence(get_persistence_params(
+        "it_fails_over_to_a_standby_when_a_domain_crashes",
+    ));
+    let mut g = g.build_local().unwrap();
+    let (primary, standby) = standby_counts(&mut g);
+    g.add_standby(primary, standby).unwrap();
+
+    // the primary fails midway through the writes
+    let mut table = g.table("a").unwrap();
+    for i in 0..100 {
+        table.insert(vec![i.into(), (i % 5).into()]).unwrap();
+    }
+    faults.crash(primary, |p| p.is_regular());
+    for i in 100..200 {
+        table.insert(vec![i.into(), (i % 5).into()]).unwrap();
+    }
+    let start = Instant::now();
+    while g.statistics().unwrap().failures.is_empty() {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "the crash was never reported"
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    // the standby ends up with every update, including those the primary had been sent before
+    // it failed, and nothing is posted as undeliverable
+    wait_for_counts(&mut g, "c2", 40);
+    assert!(g.dead_letters().unwrap().is_empty());
+    let metrics = g.metrics().unwrap();
+    assert_eq!(metrics.sum("noria_domain_failovers", &[]), 1);
+}
+
+#[test]
+fn it_switches_between_a_domain_and_its_standby() {
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(None);
+    g.set_persistence(get_persistence_params(
+        "it_switches_between_a_domain_and_its_standby",
+    ));
+    let mut g = g.build_local().unwrap();
+    let (primary, standby) = standby_counts(&mut g);
+    assert!(g.add_standby(primary, primary).is_err());
+    g.add_standby(primary, standby).unwrap();
+
+    // each of the two is sent what it missed while the other was switched to
+    let mut table = g.table("a").unwrap();
+    for i in 0..100 {
+        table.insert(vec![i.into(), (i % 5).into()]).unwrap();
+    }
+    g.switch_to_standby(primary, standby).unwrap();
+    wait_for_counts(&mut g, "c2", 20);
+    for i in 100..200 {
+        table.insert(vec![i.into(), (i % 5).into()]).unwrap();
+    }
+    g.switch_to_standby(standby, primary).unwrap();
+    wait_for_counts(&mut g, "c1", 40);
+}
+
+#[test]
+fn it_reports_injected_send_failures() {
+    use crate::FaultInjector;
+    use std::io;
+    use std::time::Instant;
+
+    let faults = FaultInjector::new();
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(None);
+    g.set_fault_injector(faults.clone());
+    g.set_persistence(get_persistence_params("it_reports_injected_send_failures"));
+    let mut g = g.build_local().unwrap();
+    let (a, c) = g.migrate(|mig| {
+        let a = mig.add_base("a", &["id", "x"], Base::default());
+        let c = mig.add_ingredient(
+            "BOUNDARY_c",
+            &["x", "n"],
+            Aggregation::COUNT.over(a, 0, &[1]),
+        );
+        mig.maintain("c".into(), c, &[0]);
+        (a, c)
+    });
+    let stats = g.statistics().unwrap();
+    let base_domain = stats.node(a).unwrap().domain;
+    let count_domain = stats.node(c).unwrap().domain;
+    assert_ne!(base_domain, count_domain);
+
+    // the base's domain fails to send its update to the count's
+    faults.fail_sends(1, count_domain, io::ErrorKind::BrokenPipe);
+    let mut table = g.table("a").unwrap();
+    table.insert(vec![1.into(), 7.into()]).unwrap();
+
+    let start = Instant::now();
+    let stats = loop {
+        let stats = g.statistics().unwrap();
+        if !stats.failures.is_empty() {
+            break stats;
+        }
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "the send failure was never reported"
+        );
+        thread::sleep(Duration::from_millis(50));
+    };
+
+    assert_eq!(stats.failures.len(), 1);
+    let failure = &stats.failures[0];
+    assert_eq!(failure.domain, base_domain);
+    assert!(
+        failure.message.contains("injected send failure"),
+        "{}",
+        failure.message
+    );
+}
+
+#[test]
+fn it_attributes_time_spent_waiting_on_a_stalled_domain_to_its_sender() {
+    use crate::FaultInjector;
+    use std::time::Instant;
+
+    let faults = FaultInjector::new();
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(None);
+    g.set_fault_injector(faults.clone());
+    g.set_persistence(get_persistence_params(
+        "it_attributes_time_spent_waiting_on_a_stalled_domain_to_its_sender",
+    ));
+    let mut g = g.build_local().unwrap();
+    let (a, c) = g.migrate(|mig| {
+        let a = mig.add_base("a", &["id", "x"], Base::default());
+        let c = mig.add_ingredient(
+            "BOUNDARY_c",
+            &["x", "n"],
+            Aggregation::COUNT.over(a, 0, &[1]),
+        );
+        mig.maintain("c".into(), c, &[0]);
+        (a, c)
+    });
+    let stats = g.statistics().unwrap();
+    let base_domain = stats.node(a).unwrap().domain;
+    let count_domain = stats.node(c).unwrap().domain;
+    assert_ne!(base_domain, count_domain);
+
+    // the count's domain stops taking the base's updates for a while
+    let stall = faults.stall_sends(count_domain);
+    let mut table = g.table("a").unwrap();
+    for i in 0..10 {
+        table.insert(vec![i.into(), 7.into()]).unwrap();
+    }
+    thread::sleep(Duration::from_secs(2));
+
+    // which the base's domain spends most of its time waiting on
+    let stats = g.statistics().unwrap();
+    let budget = &stats.domain(base_domain).unwrap().time_budget;
+    let blocked = budget.blocked().recent;
+    let rest = budget.idle.recent + budget.processing().recent + budget.sending().recent;
+    assert!(blocked > rest, "{:?}", budget);
+    assert_eq!(budget.blocked_on().unwrap().domain, count_domain);
+    let (sender, on) = stats.blocked_senders()[0];
+    assert_eq!(sender.domain, base_domain);
+    assert_eq!(on.domain, count_domain);
+
+    // and the updates get through once the count's domain takes them again
+    faults.remove(stall);
+    let mut view = g.view("c").unwrap();
+    let start = Instant::now();
+    while view.lookup(&[7.into()], true).unwrap() != vec![vec![7.into(), 10.into()]] {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "the held back updates never got through"
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn it_posts_packets_for_a_domain_that_is_gone_as_dead_letters() {
+    use crate::FaultInjector;
+    use noria::debug::dead_letters::Destination;
+    use std::time::Instant;
+
+    let faults = FaultInjector::new();
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(None);
+    g.set_fault_injector(faults.clone());
+    g.set_persistence(get_persistence_params(
+        "it_posts_packets_for_a_domain_that_is_gone_as_dead_letters",
+    ));
+    let mut g = g.build_local().unwrap();
+    let (a, c) = g.migrate(|mig| {
+        let a = mig.add_base("a", &["id", "x"], Base::default());
+        let c = mig.add_ingredient(
+            "BOUNDARY_c",
+            &["x", "n"],
+            Aggregation::COUNT.over(a, 0, &[1]),
+        );
+        mig.maintain("c".into(), c, &[0]);
+        (a, c)
+    });
+    let stats = g.statistics().unwrap();
+    let base_domain = stats.node(a).unwrap().domain;
+    let count_domain = stats.node(c).unwrap().domain;
+    assert_ne!(base_domain, count_domain);
+
+    // the count's domain fails on the first update it gets, and its receiving end goes with it
+    faults.crash(count_domain, |p| p.is_regular());
+    let mut table = g.table("a").unwrap();
+    table.insert(vec![1.into(), 7.into()]).unwrap();
+    let start = Instant::now();
+    while g.statistics().unwrap().failures.is_empty() {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "the crash was never reported"
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+    sleep();
+
+    // the base's domain can't deliver the next update, but carries on
+    table.insert(vec![2.into(), 7.into()]).unwrap();
+    let letters = loop {
+        let letters = g.dead_letters().unwrap();
+        if !letters.is_empty() {
+            break letters;
+        }
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "the undeliverable update was never posted"
+        );
+        thread::sleep(Duration::from_millis(50));
+    };
+    assert_eq!(letters.len(), 1);
+    assert_eq!(letters[0].domain, base_domain);
+    assert_eq!(letters[0].to, Destination::Domain(count_domain, 0));
+    assert_eq!(letters[0].kind, "Message");
+    assert_eq!(letters[0].records, 1);
+
+    table.insert(vec![3.into(), 7.into()]).unwrap();
+    let stats = g.statistics().unwrap();
+    assert_eq!(stats.failures.len(), 1);
+    assert_eq!(stats.failures[0].domain, count_domain);
+    let domain = base_domain.index().to_string();
+    let metrics = g.metrics().unwrap();
+    assert_eq!(
+        metrics.sum("noria_domain_dead_letters", &[("domain", &domain)]),
+        2
+    );
+}
+
+#[test]
+fn it_posts_acks_for_a_client_that_is_gone_as_dead_letters() {
+    use crate::FaultInjector;
+    use dataflow::Packet;
+    use noria::builders::TableBuilder;
+    use noria::channel::{DomainConnectionBuilder, TcpSender};
+    use noria::debug::dead_letters::Destination;
+    use noria::internal::LocalOrNot;
+    use noria::{Input, TableOperation};
+    use std::time::Instant;
+
+    let faults = FaultInjector::new();
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(None);
+    g.set_fault_injector(faults.clone());
+    g.set_persistence(get_persistence_params(
+        "it_posts_acks_for_a_client_that_is_gone_as_dead_letters",
+    ));
+    let mut g = g.build_local().unwrap();
+    let a = g.migrate(|mig| mig.add_base("a", &["id", "x"], Base::default()));
+    let base_domain = g.statistics().unwrap().node(a).unwrap().domain;
+
+    // the client hangs up before its write has been processed, and so before it is acknowledged
+    let delay = faults.delay(Duration::from_millis(500), base_domain, |p| match *p {
+        Packet::Input { .. } => true,
+        _ => false,
+    });
+    let b: TableBuilder = g
+        .rpc::<_, Option<TableBuilder>>("table_builder", "a")
+        .unwrap()
+        .unwrap();
+    let mut tx: TcpSender<LocalOrNot<Input>> = DomainConnectionBuilder::for_base(b.txs[0])
+        .build_sync()
+        .unwrap();
+    tx.send(LocalOrNot::new(Input {
+        dst: b.addr,
+        data: vec![TableOperation::Insert(vec![1.into(), 7.into()])],
+        tracer: None,
+        markers: Vec::new(),
+    }))
+    .unwrap();
+    drop(tx);
+
+    let start = Instant::now();
+    let letters = loop {
+        let letters = g.dead_letters().unwrap();
+        if !letters.is_empty() {
+            break letters;
+        }
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "the undeliverable ack was never posted"
+        );
+        thread::sleep(Duration::from_millis(50));
+    };
+    assert_eq!(letters.len(), 1);
+    assert_eq!(letters[0].domain, base_domain);
+    assert_eq!(letters[0].to, Destination::Client);
+    assert_eq!(letters[0].kind, "Ack");
+
+    // the base's domain carries on
+    faults.remove(delay);
+    g.table("a")
+        .unwrap()
+        .insert(vec![2.into(), 7.into()])
+        .unwrap();
+    assert!(g.statistics().unwrap().failures.is_empty());
+}
+
+#[test]
+fn it_drops_or_dead_letters_records_an_operator_cannot_process() {
+    use dataflow::MalformedRecords;
+    use noria::debug::dead_letters::Destination;
+
+    for &policy in &[MalformedRecords::DropAndCount, MalformedRecords::DeadLetter] {
+        let mut g = ControllerBuilder::default();
+        g.disable_partial();
+        g.set_sharding(None);
+        g.set_malformed_records(policy);
+        g.set_persistence(get_persistence_params(&format!(
+            "it_drops_or_dead_letters_records_an_operator_cannot_process_{:?}",
+            policy
+        )));
+        let mut g = g.build_local().unwrap();
+        let s = g.migrate(|mig| {
+            let a = mig.add_base("a", &["id", "g", "v"], Base::default().with_key(vec![0]));
+            let s = mig.add_ingredient("s", &["g", "sum"], Aggregation::SUM.over(a, 2, &[1]));
+            mig.maintain_anonymous(s, &[0]);
+            s
+        });
+        let mut table = g.table("a").unwrap();
+        let mut sums = g.view("s").unwrap();
+
+        // nothing checks the type of the column that is summed on its way in
+        table.insert(vec![1.into(), 1.into(), 2.into()]).unwrap();
+        table.insert(vec![2.into(), 1.into(), "x".into()]).unwrap();
+        sleep();
+        assert_eq!(
+            sums.lookup(&[1.into()], true).unwrap(),
+            vec![vec![1.into(), 2.into()]]
+        );
+
+        // the row is retracted, and that is passed over just the same
+        table.delete(vec![2.into()]).unwrap();
+        sleep();
+        assert_eq!(
+            sums.lookup(&[1.into()], true).unwrap(),
+            vec![vec![1.into(), 2.into()]]
+        );
+
+        let stats = g.statistics().unwrap();
+        assert_eq!(stats.node(s).unwrap().malformed_records, 2);
+        let letters = g.dead_letters().unwrap();
+        if policy == MalformedRecords::DeadLetter {
+            let row: Vec<DataType> = vec![2.into(), 1.into(), "x".into()];
+            assert_eq!(letters.len(), 2);
+            for (letter, kind) in letters.iter().zip(&["Positive", "Negative"]) {
+                match letter.to {
+                    Destination::Node { node, .. } => assert_eq!(node, s),
+                    ref to => panic!("posted to {:?}", to),
+                }
+                assert_eq!(&letter.kind, kind);
+                assert_eq!(letter.record.as_ref(), Some(&row));
+            }
+        } else {
+            assert!(letters.is_empty());
+        }
+    }
+}
+
+#[test]
+fn it_replays_captured_domains() {
+    use crate::Replay;
+
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(None);
+    g.set_persistence(get_persistence_params("it_replays_captured_domains"));
+    let mut g = g.build_local().unwrap();
+    let c = g.migrate(|mig| {
+        let a = mig.add_base("a", &["id", "x"], Base::default());
+        let c = mig.add_ingredient(
+            "BOUNDARY_c",
+            &["x", "n"],
+            Aggregation::COUNT.over(a, 0, &[1]),
+        );
+        mig.maintain("c".into(), c, &[0]);
+        c
+    });
+    let domain = g.statistics().unwrap().node(c).unwrap().domain;
+
+    // the capture starts out with the state the count has by then
+    let mut table = g.table("a").unwrap();
+    table.insert(vec![0.into(), 9.into()]).unwrap();
+    sleep();
+
+    let dir = tempfile::tempdir().unwrap();
+    let files = g.start_capture(domain, dir.path()).unwrap();
+    assert_eq!(files.len(), 1);
+    table.insert(vec![1.into(), 7.into()]).unwrap();
+    table.insert(vec![2.into(), 7.into()]).unwrap();
+    table.insert(vec![3.into(), 8.into()]).unwrap();
+    sleep();
+    g.stop_capture(domain).unwrap();
+
+    // nothing that the count sees after the capture stops makes it into the file
+    table.insert(vec![4.into(), 8.into()]).unwrap();
+    sleep();
+
+    let log = slog::Logger::root(slog::Discard, o!());
+    let mut replay = Replay::open(&files[0], log.clone()).unwrap();
+    assert_eq!(replay.run_to(0).unwrap(), 0);
+    assert_eq!(replay.rows(c).unwrap(), vec![vec![9.into(), 1.into()]]);
+
+    // the count goes through the updates one at a time, so some prefix has seen just the first
+    let mut partway = false;
+    while replay.step().unwrap().is_some() {
+        let mut rows = replay.rows(c).unwrap();
+        rows.sort();
+        partway = partway || rows == vec![vec![7.into(), 1.into()], vec![9.into(), 1.into()]];
+    }
+    assert!(partway);
+    assert_eq!(replay.skipped(), 0);
+
+    let mut rows = replay.rows(c).unwrap();
+    rows.sort();
+    assert_eq!(
+        rows,
+        vec![
+            vec![7.into(), 2.into()],
+            vec![8.into(), 1.into()],
+            vec![9.into(), 1.into()],
+        ]
+    );
+
+    // replaying again gets to the same place
+    let mut again = Replay::open(&files[0], log).unwrap();
+    assert_eq!(again.run().unwrap(), replay.seen());
+    let mut rows_again = again.rows(c).unwrap();
+    rows_again.sort();
+    assert_eq!(rows_again, rows);
+}
+
+#[test]
+fn it_logs_migration_and_domain_events() {
+    use slog::KV;
+    use std::fmt;
+    use std::sync::Mutex;
+
+    /// Keeps the message of everything logged through it, along with the domain it is about.
+    #[derive(Clone, Default)]
+    struct Messages(Arc<Mutex<Vec<(String, Option<String>)>>>);
+
+    struct Domain(Option<String>);
+    impl slog::Serializer for Domain {
+        fn emit_arguments(&mut self, key: slog::Key, val: &fmt::Arguments) -> slog::Result {
+            if key == "domain" {
+                self.0 = Some(val.to_string());
+            }
+            Ok(())
+        }
+    }
+
+    impl slog::Drain for Messages {
+        type Ok = ();
+        type Err = slog::Never;
+        fn log(&self, record: &slog::Record, values: &slog::OwnedKVList) -> Result<(), Self::Err> {
+            let mut domain = Domain(None);
+            values.serialize(record, &mut domain).unwrap();
+            record.kv().serialize(record, &mut domain).unwrap();
+            let msg = record.msg().to_string();
+            self.0.lock().unwrap().push((msg, domain.0));
+            Ok(())
+        }
+    }
+
+    let messages = Messages::default();
+    let mut g = ControllerBuilder::default();
+    g.log_with(slog::Logger::root(messages.clone(), o!()));
+    g.disable_partial();
+    g.set_sharding(None);
+    g.set_persistence(get_persistence_params(
+        "it_logs_migration_and_domain_events",
+    ));
+    let mut g = g.build_local().unwrap();
+    let a = g.migrate(|mig| mig.add_base("a", &["id", "x"], Base::default()));
+    let mut table = g.table("a").unwrap();
+    table.insert(vec![1.into(), 7.into()]).unwrap();
+    sleep();
+
+    // the count goes in a domain of its own, which the base's rows are then replayed into
+    g.migrate(move |mig| {
+        let c = mig.add_ingredient(
+            "BOUNDARY_c",
+            &["x", "n"],
+            Aggregation::COUNT.over(a, 0, &[1]),
+        );
+        mig.maintain("c".into(), c, &[0]);
+    });
+    let mut c = g.view("c").unwrap();
+    assert_eq!(
+        c.lookup(&[7.into()], true).unwrap(),
+        vec![vec![7.into(), 1.into()]]
+    );
+
+    let messages = messages.0.lock().unwrap();
+    let logged = |msg: &str| messages.iter().any(|&(ref m, _)| m == msg);
+    for msg in &[
+        "migration phase started",
+        "migration phase finished",
+        "received control packet",
+        "node added",
+        "node ready",
+        "starting replay",
+        "acknowledging replay completed",
+    ] {
+        assert!(logged(msg), "nothing logged {:?}", msg);
+    }
+
+    // events from within a domain say which domain they are about
+    assert!(messages
+        .iter()
+        .filter(|&&(ref m, _)| m == "booted domain" || m == "node added")
+        .all(|&(_, ref domain)| domain.is_some()));
+}
+
+#[test]
+fn it_counts_node_domain_and_reader_metrics() {
+    let mut g = build_local_unsharded("it_counts_node_domain_and_reader_metrics");
+    let a = g.migrate(|mig| {
+        let a = mig.add_base("a", &["id", "x"], Base::new(vec![]).with_key(vec![0]));
+        let c = mig.add_ingredient("c", &["x", "n"], Aggregation::COUNT.over(a, 0, &[1]));
+        mig.maintain("c".into(), c, &[0]);
+        a
+    });
+    let a = a.index().to_string();
+
+    let mut table = g.table("a").unwrap();
+    table.insert(vec![1.into(), 7.into()]).unwrap();
+    table.insert(vec![2.into(), 7.into()]).unwrap();
+    table.delete(vec![1.into()]).unwrap();
+    sleep();
+
+    // the view is partial, so the first read misses and waits for the key to be filled
+    let mut c = g.view("c").unwrap();
+    assert_eq!(
+        c.lookup(&[7.into()], true).unwrap(),
+        vec![vec![7.into(), 1.into()]]
+    );
+    assert_eq!(
+        c.lookup(&[7.into()], true).unwrap(),
+        vec![vec![7.into(), 1.into()]]
+    );
+
+    let check = |m: &MetricsSnapshot| {
+        assert_eq!(m.sum("noria_node_records_in", &[("node", &a)]), 3);
+        assert_eq!(m.sum("noria_node_records_out", &[("node", &a)]), 3);
+        assert_eq!(m.sum("noria_node_negative_records", &[("node", &a)]), 1);
+        assert!(m.sum("noria_node_process_calls", &[("node", &a)]) >= 1);
+        assert!(m.has("noria_node_process_time_ns", &[("node", &a)]));
+        assert!(m.sum("noria_domain_packets_handled", &[("kind", "Input")]) >= 1);
+        assert!(m.sum("noria_domain_loop_iterations", &[]) >= 1);
+        assert!(m.has("noria_domain_send_time_ns", &[]));
+        assert!(m.sum("noria_reader_gets", &[]) >= 2);
+        assert!(m.sum("noria_reader_misses", &[]) >= 1);
+        assert!(m.sum("noria_reader_blocking_waits", &[]) >= 1);
+    };
+
+    // the domains all run in this process, so reading the counters directly sees the same values
+    // as asking the domains for them
+    let local = g.local_metrics();
+    check(&local);
+    let remote = g.metrics().unwrap();
+    check(&remote);
+    assert!(remote
+        .to_string()
+        .contains("# TYPE noria_node_records_in counter\n"));
+}
+
+#[test]
+fn it_diagnoses_the_domain_that_holds_up_the_graph() {
+    use dataflow::ops::grouped::concat::{GroupConcat, TextComponent};
+    use noria::debug::diagnosis::SlowDomainThresholds;
+
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(None);
+    g.set_persistence(get_persistence_params(
+        "it_diagnoses_the_domain_that_holds_up_the_graph",
+    ));
+    let mut g = g.build_local().unwrap();
+    let c = g.migrate(|mig| {
+        let a = mig.add_base("a", &["id", "x"], Base::default());
+        // the concatenation is rebuilt from the whole group for every update, so every update to
+        // a big group is expensive
+        let c = mig.add_ingredient(
+            "BOUNDARY_c",
+            &["x", "ids"],
+            GroupConcat::new(a, vec![TextComponent::Column(0)], "#".to_owned()),
+        );
+        mig.maintain("c".into(), c, &[0]);
+        c
+    });
+    let domain = g.statistics().unwrap().node(c).unwrap().domain;
+
+    let mut table = g.table("a").unwrap();
+    table
+        .insert_all((0..20_000).map(|i| vec![i.into(), 1.into()]))
+        .unwrap();
+    // the writes are acknowledged once the base has them, long before the concatenation is done
+    for i in 20_000..20_300 {
+        table.insert(vec![i.into(), 1.into()]).unwrap();
+    }
+
+    let thresholds = SlowDomainThresholds {
+        min_queued: 5.0,
+        ..Default::default()
+    };
+    let slow = g.diagnose(thresholds.clone()).unwrap();
+    assert_eq!(slow.len(), 1, "{:?}", slow);
+    assert_eq!(slow[0].domain, domain);
+    assert_eq!(slow[0].slowest.as_ref().unwrap().node, c, "{}", slow[0]);
+
+    // the metrics were only read once the backlog was gone, so there's nothing to report since
+    sleep();
+    assert_eq!(g.diagnose(thresholds).unwrap(), vec![]);
+}
+
+#[test]
+fn it_keeps_hot_keys_in_memory_limited_readers() {
+    use common::SizeOf;
+
+    let row = |k: i32| vec![DataType::from(k), DataType::from(k * 10)];
+    let hot: Vec<i32> = (0..5).collect();
+
+    // leave room for the hot keys and a few cold ones
+    let limit = 10 * row(0).deep_size_of() as usize;
+
+    let mut g = build_local_unsharded("it_keeps_hot_keys_in_memory_limited_readers");
+    g.migrate(move |mig| {
+        let a = mig.add_base("a", &["k", "v"], Base::new(vec![]).with_key(vec![0]));
+        let c = mig.add_ingredient("c", &["k", "v"], Identity::new(a));
+        mig.maintain("c".to_owned(), c, &[0]);
+        mig.set_memory_limit(c, limit);
+    });
+
+    let mut muta = g.table("a").unwrap();
+    for k in 0..100 {
+        muta.insert(row(k)).unwrap();
+    }
+    sleep();
+
+    let mut cq = g.view("c").unwrap();
+
+    // every cold key is read once, and the hot keys are read after each one
+    for k in 5..100 {
+        assert_eq!(cq.lookup(&[k.into()], true).unwrap(), vec![row(k)]);
+        for &h in &hot {
+            assert_eq!(cq.lookup(&[h.into()], true).unwrap(), vec![row(h)]);
+        }
+    }
+
+    // the cold keys have been evicted to stay within the limit, but the hot keys are resident
+    assert!(cq.len().unwrap() <= 11);
+    for &h in &hot {
+        assert_eq!(cq.lookup(&[h.into()], false).unwrap(), vec![row(h)]);
+    }
+
+    let evicted: u64 = g
+        .statistics()
+        .unwrap()
+        .values()
+        .flat_map(|&(_, ref nodes)| nodes.values().map(|n| n.evicted_keys))
+        .sum();
+    assert!(evicted >= 100 - 11);
+
+    // the coordinator can also evict particular keys
+    g.evict_view("c", Some(vec![vec![0.into()]])).unwrap();
+    sleep();
+    assert!(cq.lookup(&[0.into()], false).unwrap().is_empty());
+    assert_eq!(cq.lookup(&[1.into()], false).unwrap(), vec![row(1)]);
+}
+
+#[test]
+fn it_works_with_state_on_disk() {
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(DEFAULT_SHARDING);
+    g.set_persistence(get_persistence_params("it_works_with_state_on_disk"));
+    let mut g = g.build_local().unwrap();
+    let vc = g.migrate(|mig| {
+        let article = mig.add_base(
+            "article",
+            &["id", "title"],
+            Base::default().with_key(vec![0]),
+        );
+        let vote = mig.add_base(
+            "vote",
+            &["id", "article"],
+            Base::default().with_key(vec![0]),
+        );
+        let vc = mig.add_ingredient(
+            "votecount",
+            &["article", "votes"],
+            Aggregation::COUNT.over(vote, 0, &[1]),
+        );
+        mig.set_state_backend(vc, StateBackend::Disk);
+
+        // the join looks up the count on disk for every article that arrives
+        let j = Join::new(article, vc, JoinType::Left, vec![B(0, 0), L(1), R(1)]);
+        let awvc = mig.add_ingredient("awvc", &["id", "title", "votes"], j);
+        mig.maintain_anonymous(awvc, &[0]);
+        vc
+    });
+
+    let mut article = g.table("article").unwrap();
+    let mut vote = g.table("vote").unwrap();
+    let mut awvc = g.view("awvc").unwrap();
+
+    // enough articles that replaying a shard of the count takes several chunks
+    let articles = 1000;
+    let votes = (0..2 * articles).map(|id| vec![DataType::from(id), (id % articles).into()]);
+    vote.batch_insert(votes).unwrap();
+    vote.delete(vec![0.into()]).unwrap();
+    sleep();
+    let titles = (0..articles).map(|id| vec![DataType::from(id), format!("Article {}", id).into()]);
+    article.batch_insert(titles).unwrap();
+    sleep();
+
+    assert_eq!(
+        awvc.lookup(&[0.into()], true).unwrap(),
+        vec![vec![0.into(), "Article 0".into(), 1.into()]]
+    );
+    assert_eq!(
+        awvc.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), "Article 1".into(), 2.into()]]
+    );
+
+    // a view added later is populated by streaming the count off disk
+    g.migrate(move |mig| {
+        mig.maintain_anonymous(vc, &[0]);
+    });
+
+    let mut vcq = g.view("votecount").unwrap();
+    assert_eq!(
+        vcq.lookup(&[0.into()], true).unwrap(),
+        vec![vec![0.into(), 1.into()]]
+    );
+    for id in 1..articles {
+        assert_eq!(
+            vcq.lookup(&[id.into()], true).unwrap(),
+            vec![vec![id.into(), 2.into()]]
+        );
+    }
+}
+
+#[test]
+fn it_respects_materialization_hints() {
+    use dataflow::ops::filter::{Filter, FilterCondition, Operator, Value};
+    use dataflow::MaterializationHint;
+    use noria::MaterializationStatus;
+
+    let mut g = build_local("it_respects_materialization_hints");
+    let (fv, vc, ai) = g.migrate(|mig| {
+        let article = mig.add_base(
+            "article",
+            &["id", "title"],
+            Base::default().with_key(vec![0]),
+        );
+        let vote = mig.add_base(
+            "vote",
+            &["id", "article"],
+            Base::default().with_key(vec![0]),
+        );
+
+        // the aggregation's parent is only ever replayed through, so it needn't have state
+        let cond = Some(FilterCondition::Comparison(
+            Operator::Greater,
+            Value::Constant(0.into()),
+        ));
+        let fv = mig.add_ingredient("fv", &["id", "article"], Filter::new(vote, &[cond, None]));
+        mig.materialize(fv, MaterializationHint::Forbidden);
+        let vc = mig.add_ingredient(
+            "votecount",
+            &["article", "votes"],
+            Aggregation::COUNT.over(fv, 0, &[1]),
+        );
+
+        // nothing needs the identity to have state, but it gets some anyway
+        let ai = mig.add_ingredient("ai", &["id", "title"], Identity::new(article));
+        mig.materialize(ai, MaterializationHint::Forced);
+
+        let j = Join::new(ai, vc, JoinType::Left, vec![B(0, 0), L(1), R(1)]);
+        let awvc = mig.add_ingredient("awvc", &["id", "title", "votes"], j);
+        mig.maintain_anonymous(awvc, &[0]);
+        (fv, vc, ai)
+    });
+
+    let mut article = g.table("article").unwrap();
+    let mut vote = g.table("vote").unwrap();
+    let mut awvc = g.view("awvc").unwrap();
+    article.insert(vec![1.into(), "Article 1".into()]).unwrap();
+    vote.batch_insert(vec![
+        vec![0.into(), 1.into()],
+        vec![1.into(), 1.into()],
+        vec![2.into(), 1.into()],
+    ])
+    .unwrap();
+    sleep();
+    assert_eq!(
+        awvc.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), "Article 1".into(), 2.into()]]
+    );
+
+    // the hints still hold for nodes added in later migrations
+    g.migrate(move |mig| {
+        let vc2 = mig.add_ingredient(
+            "votecount2",
+            &["article", "votes"],
+            Aggregation::COUNT.over(fv, 0, &[1]),
+        );
+        mig.maintain_anonymous(vc2, &[0]);
+    });
+    let mut vc2 = g.view("votecount2").unwrap();
+    assert_eq!(
+        vc2.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 2.into()]]
+    );
+
+    let stats = g.statistics().unwrap();
+    let materialized = |ni| {
+        stats
+            .values()
+            .filter_map(|&(_, ref nodes)| nodes.get(&ni))
+            .any(|n| match n.materialized {
+                MaterializationStatus::Not => false,
+                _ => true,
+            })
+    };
+    assert!(!materialized(fv));
+    assert!(materialized(vc));
+    assert!(materialized(ai));
+}
+
+#[test]
+fn it_explains_forbidden_materializations() {
+    use dataflow::MaterializationHint;
+
+    let mut g = build_local("it_explains_forbidden_materializations");
+    let err = g.migrate(|mig| {
+        let article = mig.add_base(
+            "article",
+            &["id", "title"],
+            Base::default().with_key(vec![0]),
+        );
+        let vote = mig.add_base(
+            "vote",
+            &["id", "article"],
+            Base::default().with_key(vec![0]),
+        );
+        let vc = mig.add_ingredient(
+            "votecount",
+            &["article", "votes"],
+            Aggregation::COUNT.over(vote, 0, &[1]),
+        );
+        let j = Join::new(article, vc, JoinType::Left, vec![B(0, 0), L(1), R(1)]);
+        let awvc = mig.add_ingredient("awvc", &["id", "title", "votes"], j);
+        mig.maintain_anonymous(awvc, &[0]);
+
+        // the join has to look up counts, so they can't go without state
+        mig.materialize(vc, MaterializationHint::Forbidden);
+        let err = mig.check_materialization_hints().unwrap_err();
+
+        // leave it up to the planner instead, so that the migration can go through
+        mig.materialize(vc, MaterializationHint::Auto);
+        assert_eq!(mig.check_materialization_hints(), Ok(()));
+        err
+    });
+    assert!(err.contains("votecount"), "{}", err);
+    assert!(err.contains("forbidden"), "{}", err);
+
+    let mut article = g.table("article").unwrap();
+    let mut vote = g.table("vote").unwrap();
+    let mut awvc = g.view("awvc").unwrap();
+    article.insert(vec![1.into(), "Article 1".into()]).unwrap();
+    vote.insert(vec![0.into(), 1.into()]).unwrap();
+    sleep();
+    assert_eq!(
+        awvc.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), "Article 1".into(), 1.into()]]
+    );
+}
+
+#[test]
+fn it_spills_joins_with_huge_keys() {
+    use std::time::Instant;
+
+    // a single article with this many votes, all of which join with it at once
+    const VOTES: i64 = 1_000_000;
+
+    let mut g = build_local("it_spills_joins_with_huge_keys");
+    let j = g.migrate(|mig| {
+        let article = mig.add_base(
+            "article",
+            &["id", "title"],
+            Base::default().with_key(vec![0]),
+        );
+        let vote = mig.add_base("vote", &["id", "article"], Base::default());
+
+        let j = Join::new(article, vote, JoinType::Inner, vec![B(0, 1), L(1), R(0)]);
+        let j = mig.add_ingredient("av", &["id", "title", "vote"], j);
+        let vc = mig.add_ingredient(
+            "votecount",
+            &["article", "votes"],
+            Aggregation::COUNT.over(j, 2, &[0]),
+        );
+        mig.maintain_anonymous(vc, &[0]);
+        j
+    });
+
+    let mut article = g.table("article").unwrap();
+    let mut vote = g.table("vote").unwrap();
+    let mut vc = g.view("votecount").unwrap();
+
+    // the votes don't join with anything yet
+    let votes: Vec<Vec<DataType>> = (0..VOTES).map(|id| vec![id.into(), 1.into()]).collect();
+    for chunk in votes.chunks(10_000) {
+        vote.insert_all(chunk.to_vec()).unwrap();
+    }
+    drop(votes);
+    sleep();
+    assert!(vc.lookup(&[1.into()], true).unwrap().is_empty());
+
+    // the article joins with all of them, as does the delete of it. the join's output for each is
+    // sent downstream in pieces, rather than as a single million-record update.
+    let mut wait_for = |count: i64| {
+        let start = Instant::now();
+        loop {
+            let rows = vc.lookup(&[1.into()], true).unwrap();
+            if rows == vec![vec![1.into(), count.into()]] {
+                break;
+            }
+            assert!(
+                start.elapsed() < Duration::from_secs(60),
+                "count never reached {}: {:?}",
+                count,
+                rows
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    };
+    article.insert(vec![1.into(), "Article 1".into()]).unwrap();
+    wait_for(VOTES);
+    article.delete(vec![1.into()]).unwrap();
+    wait_for(0);
+
+    let spilled: u64 = g
+        .statistics()
+        .unwrap()
+        .values()
+        .filter_map(|&(_, ref nodes)| nodes.get(&j))
+        .map(|n| n.spilled_keys)
+        .sum();
+    assert!(spilled >= 2);
+}
+
+#[test]
+fn it_limits_how_many_records_a_join_sends_on_at_once() {
+    use std::time::Instant;
+
+    const VOTES: i64 = 100_000;
+    const LIMIT: usize = 10_000;
+
+    let mut g = build_local("it_limits_how_many_records_a_join_sends_on_at_once");
+    let (j, vc) = g.migrate(|mig| {
+        let article = mig.add_base(
+            "article",
+            &["id", "title"],
+            Base::default().with_key(vec![0]),
+        );
+        let vote = mig.add_base("vote", &["id", "article"], Base::default());
+
+        let j = Join::new(article, vote, JoinType::Inner, vec![B(0, 1), L(1), R(0)]);
+        let j = mig.add_ingredient("av", &["id", "title", "vote"], j);
+        mig.set_output_limit(j, LIMIT);
+        let vc = mig.add_ingredient(
+            "votecount",
+            &["article", "votes"],
+            Aggregation::COUNT.over(j, 2, &[0]),
+        );
+        mig.maintain_anonymous(vc, &[0]);
+        (j, vc)
+    });
+
+    let mut article = g.table("article").unwrap();
+    let mut vote = g.table("vote").unwrap();
+    let mut vc_view = g.view("votecount").unwrap();
+
+    let votes: Vec<Vec<DataType>> = (0..VOTES).map(|id| vec![id.into(), 1.into()]).collect();
+    for chunk in votes.chunks(10_000) {
+        vote.insert_all(chunk.to_vec()).unwrap();
+    }
+    sleep();
+
+    let mut wait_for = |count: i64| {
+        let start = Instant::now();
+        loop {
+            let rows = vc_view.lookup(&[1.into()], true).unwrap();
+            if rows == vec![vec![1.into(), count.into()]] {
+                break;
+            }
+            assert!(
+                start.elapsed() < Duration::from_secs(60),
+                "count never reached {}: {:?}",
+                count,
+                rows
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    };
+    let vc_node = vc.index().to_string();
+    let calls = |g: &mut LocalControllerHandle<LocalAuthority>| {
+        g.metrics()
+            .unwrap()
+            .sum("noria_node_process_calls", &[("node", &vc_node[..])])
+    };
+
+    // the article joins with every vote, and the join's output for it reaches the count in
+    // updates of at most the limit each. so does the output for the delete of the article.
+    let before = calls(&mut g);
+    article.insert(vec![1.into(), "Article 1".into()]).unwrap();
+    wait_for(VOTES);
+    let inserted = calls(&mut g);
+    assert!(inserted - before >= VOTES as u64 / LIMIT as u64);
+
+    article.delete(vec![1.into()]).unwrap();
+    wait_for(0);
+    assert!(calls(&mut g) - inserted >= VOTES as u64 / LIMIT as u64);
+
+    // the join is the node that amplified its input the most, by as much as a single article
+    // joins with votes
+    let stats = g.statistics().unwrap();
+    let top = stats.amplifiers()[0];
+    assert_eq!(top.node, j);
+    assert_eq!(top.peak_records_out, VOTES as u64);
+    assert_eq!(top.peak_fanout, VOTES as u64);
+}
+
+#[test]
+fn it_checksums_materializations() {
+    let mut g = build_local("it_checksums_materializations");
+    let vote = g.migrate(|mig| {
+        let vote = mig.add_base("vote", &["user", "id"], Base::default());
+        let vc = mig.add_ingredient(
+            "votecount",
+            &["id", "votes"],
+            Aggregation::COUNT.over(vote, 0, &[1]),
+        );
+        mig.maintain_anonymous(vc, &[0]);
+        vote
+    });
+
+    let mut mutv = g.table("vote").unwrap();
+    let votes: Vec<Vec<DataType>> = (0..10)
+        .map(|user| vec![user.into(), (user % 3).into()])
+        .collect();
+    mutv.insert_all(votes.clone()).unwrap();
+    sleep();
+
+    let mut vc = g.view("votecount").unwrap();
+    for id in 0..3 {
+        vc.lookup(&[id.into()], true).unwrap();
+    }
+    let counts = vec![
+        vec![0.into(), 4.into()],
+        vec![1.into(), 3.into()],
+        vec![2.into(), 3.into()],
+    ];
+    assert_view_checksum(&mut g, "votecount", &counts);
+    assert_eq!(g.checksum(vote).unwrap(), Some(noria::checksum(&votes)));
+
+    // a view that is built by replaying the base agrees with one that was built incrementally
+    g.migrate(move |mig| {
+        let vc2 = mig.add_ingredient(
+            "votecount2",
+            &["id", "votes"],
+            Aggregation::COUNT.over(vote, 0, &[1]),
+        );
+        mig.maintain_anonymous(vc2, &[0]);
+    });
+    let mut vc2 = g.view("votecount2").unwrap();
+    for id in 0..3 {
+        vc2.lookup(&[id.into()], true).unwrap();
+    }
+    assert_eq!(
+        g.view_checksum("votecount2").unwrap(),
+        g.view_checksum("votecount").unwrap()
+    );
+}
+
+#[test]
+fn it_removes_queries_and_their_unused_ancestors() {
+    let mut g = build_local("it_removes_queries_and_their_unused_ancestors");
+    let (j, vc) = g.migrate(|mig| {
+        let article = mig.add_base("article", &["id", "title"], Base::default());
+        let vote = mig.add_base("vote", &["id", "user"], Base::default());
+        let j = Join::new(article, vote, JoinType::Inner, vec![B(0, 0), L(1), R(1)]);
+        let j = mig.add_ingredient("awv", &["id", "title", "user"], j);
+        mig.maintain("awv".into(), j, &[0]);
+        let vc = mig.add_ingredient(
+            "votecount",
+            &["id", "votes"],
+            Aggregation::COUNT.over(j, 2, &[0]),
+        );
+        mig.maintain("votecount".into(), vc, &[0]);
+        (j, vc)
+    });
+
+    let mut article = g.table("article").unwrap();
+    let mut vote = g.table("vote").unwrap();
+    let mut awv = g.view("awv").unwrap();
+    let mut votecount = g.view("votecount").unwrap();
+    for id in 0..10 {
+        article.insert(vec![id.into(), "title".into()]).unwrap();
+        for user in 0..10 {
+            vote.insert(vec![id.into(), user.into()]).unwrap();
+        }
+    }
+    sleep();
+    for id in 0..10 {
+        assert_eq!(awv.lookup(&[id.into()], true).unwrap().len(), 10);
+        assert_eq!(
+            votecount.lookup(&[id.into()], true).unwrap(),
+            vec![vec![id.into(), 10.into()]]
+        );
+    }
+
+    let mem_sizes = |g: &mut LocalControllerHandle<LocalAuthority>| {
+        let mut sizes = HashMap::new();
+        for &(_, ref nodes) in g.statistics().unwrap().values() {
+            for (&ni, n) in nodes {
+                *sizes.entry(ni).or_insert(0) += n.mem_size;
+            }
+        }
+        sizes
+    };
+    let before = mem_sizes(&mut g);
+    assert!(before[&vc] > 0);
+
+    g.migrate(move |mig| {
+        assert_eq!(mig.remove_query("nonexistent"), None);
+        assert_eq!(mig.remove_query("votecount"), Some(vc));
+    });
+    sleep();
+
+    // the count and its reader are gone, and so is the memory they used
+    assert!(g.view("votecount").is_err());
+    assert!(!g.outputs().unwrap().contains_key("votecount"));
+    let after = mem_sizes(&mut g);
+    assert!(!after.contains_key(&vc));
+    assert!(after.contains_key(&j));
+    assert!(after.values().sum::<u64>() < before.values().sum::<u64>());
+
+    // while the query that shares the join keeps working
+    vote.insert(vec![0.into(), 10.into()]).unwrap();
+    article.insert(vec![10.into(), "new".into()]).unwrap();
+    vote.insert(vec![10.into(), 0.into()]).unwrap();
+    sleep();
+    assert_eq!(awv.lookup(&[0.into()], true).unwrap().len(), 11);
+    assert_eq!(
+        awv.lookup(&[10.into()], true).unwrap(),
+        vec![vec![10.into(), "new".into(), 0.into()]]
+    );
+}
+
+#[test]
+fn partial_reads_match_full_materialization() {
+    use noria::Modification;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn setup(g: &mut LocalControllerHandle<LocalAuthority>) {
+        g.migrate(|mig| {
+            let article = mig.add_base(
+                "article",
+                &["id", "title"],
+                Base::default().with_key(vec![0]),
+            );
+            let vote = mig.add_base(
+                "vote",
+                &["id", "article"],
+                Base::default().with_key(vec![0]),
+            );
+            let vc = mig.add_ingredient(
+                "votecount",
+                &["id", "votes"],
+                Aggregation::COUNT.over(vote, 0, &[1]),
+            );
+            mig.maintain_anonymous(vc, &[0]);
+            let j = Join::new(article, vc, JoinType::Left, vec![B(0, 0), L(1), R(1)]);
+            let awvc = mig.add_ingredient("awvc", &["id", "title", "votes"], j);
+            mig.maintain_anonymous(awvc, &[0]);
+        });
+    }
 
-    // should have one key in the reader now
-    assert_eq!(cq.len().unwrap(), 1);
+    // the same workload goes to a graph that uses partial materialization wherever it can and to a
+    // twin that materializes everything, and reads from the two must always agree.
+    let mut partial = build_local("partial_reads_match_full_materialization");
+    let mut full = {
+        let mut b = ControllerBuilder::default();
+        b.disable_partial();
+        b.set_sharding(DEFAULT_SHARDING);
+        b.set_persistence(get_persistence_params(
+            "partial_reads_match_full_materialization_full",
+        ));
+        b.build_local().unwrap()
+    };
+    setup(&mut partial);
+    setup(&mut full);
+
+    let mut tables: Vec<_> = [&mut partial, &mut full]
+        .iter_mut()
+        .map(|g| (g.table("article").unwrap(), g.table("vote").unwrap()))
+        .collect();
+    let mut views: Vec<_> = [&mut partial, &mut full]
+        .iter_mut()
+        .map(|g| (g.view("votecount").unwrap(), g.view("awvc").unwrap()))
+        .collect();
+
+    const ARTICLES: i32 = 20;
+    let mut rng = StdRng::from_seed([42; 32]);
+    let mut votes = Vec::new();
+    let mut next_vote = 0;
+    for round in 0..10 {
+        for _ in 0..50 {
+            let op = rng.gen_range(0, 10);
+            if op == 0 {
+                let id = rng.gen_range(0, ARTICLES);
+                let title = format!("article {} (rev {})", id, round);
+                for (article, _) in &mut tables {
+                    article
+                        .insert_or_update(
+                            vec![id.into(), title.clone().into()],
+                            vec![(1, Modification::Set(title.clone().into()))],
+                        )
+                        .unwrap();
+                }
+            } else if op < 3 && !votes.is_empty() {
+                let i = rng.gen_range(0, votes.len());
+                let id: i32 = votes.swap_remove(i);
+                for (_, vote) in &mut tables {
+                    vote.delete(vec![id.into()]).unwrap();
+                }
+            } else {
+                let article_id = rng.gen_range(0, ARTICLES);
+                for (_, vote) in &mut tables {
+                    vote.insert(vec![next_vote.into(), article_id.into()])
+                        .unwrap();
+                }
+                votes.push(next_vote);
+                next_vote += 1;
+            }
+        }
+        sleep();
+
+        // reading fills some keys in the partial graph, whose later updates then have to be
+        // applied rather than dropped
+        for _ in 0..5 {
+            let id: DataType = rng.gen_range(0, ARTICLES).into();
+            let mut results = Vec::new();
+            for (vc, awvc) in &mut views {
+                let mut counts = vc.lookup(&[id.clone()], true).unwrap();
+                let mut articles = awvc.lookup(&[id.clone()], true).unwrap();
+                counts.sort();
+                articles.sort();
+                results.push((counts, articles));
+            }
+            assert_eq!(results[0], results[1], "views disagree on key {:?}", id);
+        }
+    }
 }
 
 #[test]
@@ -478,6 +5779,8 @@ fn it_works_deletion() {
         cq.lookup(&[1.into()], true).unwrap(),
         vec![vec![1.into(), 4.into()]]
     );
+
+    assert_consistent(&mut g);
 }
 
 #[test]
@@ -509,6 +5812,40 @@ fn it_works_with_sql_recipe() {
     assert_eq!(result[0][0], 2.into());
 }
 
+#[test]
+fn it_counts_rows_with_nulls_for_count_star() {
+    let mut g = build_local("it_counts_rows_with_nulls_for_count_star");
+    let sql = "
+        CREATE TABLE Car (brand varchar(255), color varchar(255), id int);
+        QUERY CountCars: SELECT COUNT(*) FROM Car WHERE brand = ?;
+        QUERY CountColors: SELECT COUNT(color) FROM Car WHERE brand = ?;
+    ";
+    g.install_recipe(sql).unwrap();
+
+    // COUNT(*) counts over color, the first column that isn't filtered on, which is NULL here
+    let mut mutator = g.table("Car").unwrap();
+    mutator
+        .insert(vec!["Volvo".into(), DataType::None, 1.into()])
+        .unwrap();
+    mutator
+        .insert(vec!["Volvo".into(), "red".into(), 2.into()])
+        .unwrap();
+    mutator
+        .insert(vec!["Volvo".into(), DataType::None, 3.into()])
+        .unwrap();
+    sleep();
+
+    let mut cars = g.view("CountCars").unwrap();
+    let result = cars.lookup(&["Volvo".into()], true).unwrap();
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0][0], 3.into());
+
+    let mut colors = g.view("CountColors").unwrap();
+    let result = colors.lookup(&["Volvo".into()], true).unwrap();
+    assert_eq!(result.len(), 1);
+    assert_eq!(result[0][0], 1.into());
+}
+
 #[test]
 fn it_works_with_vote() {
     let mut g = build_local("it_works_with_vote");
@@ -546,6 +5883,8 @@ fn it_works_with_vote() {
         empty[0],
         vec![1i64.into(), "Article".into(), DataType::None]
     );
+
+    assert_consistent(&mut g);
 }
 
 #[test]
@@ -588,6 +5927,8 @@ fn it_works_with_double_query_through() {
 
     let empty = getter.lookup(&[2i64.into()], true).unwrap();
     assert_eq!(empty.len(), 0);
+
+    assert_consistent(&mut g);
 }
 
 #[test]
@@ -654,6 +5995,8 @@ fn forced_shuffle_despite_same_shard() {
     let result = getter.lookup(&[cid.into()], true).unwrap();
     assert_eq!(result.len(), 1);
     assert_eq!(result[0][1], price.into());
+
+    assert_consistent(&mut g);
 }
 
 #[test]
@@ -686,6 +6029,8 @@ fn double_shuffle() {
     let result = getter.lookup(&[cid.into()], true).unwrap();
     assert_eq!(result.len(), 1);
     assert_eq!(result[0][1], price.into());
+
+    assert_consistent(&mut g);
 }
 
 #[test]
@@ -763,6 +6108,327 @@ fn it_recovers_persisted_bases() {
     }
 }
 
+#[test]
+fn it_restores_checkpointed_state() {
+    let authority = Arc::new(LocalAuthority::new());
+    let dir = tempfile::tempdir().unwrap();
+    let mut persistence_params = PersistenceParameters::new(
+        DurabilityMode::MemoryOnly,
+        Duration::from_millis(1),
+        Some(String::from("it_restores_checkpointed_state")),
+        1,
+    );
+    persistence_params.log_dir = Some(dir.path().to_owned());
+    let build = || {
+        let mut g = ControllerBuilder::default();
+        g.set_persistence(persistence_params.clone());
+        g.set_sharding(None);
+        g.disable_partial();
+        g.build(authority.clone()).unwrap()
+    };
+    let check = |g: &mut LocalControllerHandle<LocalAuthority>| {
+        let mut price = g.view("CarPrice").unwrap();
+        let mut count = g.view("CountCars").unwrap();
+        for i in 1..10 {
+            let result = price.lookup(&[i.into()], true).unwrap();
+            assert_eq!(result, vec![vec![(i * 10).into()]]);
+        }
+        let result = count.lookup(&["Volvo".into()], true).unwrap();
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0][0], 9.into());
+    };
+
+    {
+        let mut g = build();
+        let sql = "
+            CREATE TABLE Car (id int, brand varchar(255), price int, PRIMARY KEY(id));
+            QUERY CarPrice: SELECT price FROM Car WHERE id = ?;
+            QUERY CountCars: SELECT COUNT(*) FROM Car WHERE brand = ?;
+        ";
+        g.install_recipe(sql).unwrap();
+
+        let mut mutator = g.table("Car").unwrap();
+        for i in 1..10 {
+            let price = i * 10;
+            mutator
+                .insert(vec![i.into(), "Volvo".into(), price.into()])
+                .unwrap();
+        }
+        sleep();
+
+        g.checkpoint().unwrap();
+    }
+
+    // none of the writes were persisted, so all the data must come from the checkpoint
+    {
+        let mut g = build();
+        check(&mut g);
+    }
+
+    // a domain that has lost its checkpoint is rebuilt through replay instead. the base table has
+    // its own domain, which is created first.
+    for entry in std::fs::read_dir(dir.path()).unwrap() {
+        let path = entry.unwrap().path();
+        if !path.to_string_lossy().ends_with("-0_0.checkpoint") {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+    let mut g = build();
+    check(&mut g);
+}
+
+#[test]
+fn it_hands_the_graph_over_to_another_controller() {
+    use dataflow::Placement;
+
+    let build = |authority: &Arc<LocalAuthority>, quorum| {
+        let mut g = ControllerBuilder::default();
+        g.set_persistence(get_persistence_params("it_hands_the_graph_over"));
+        g.set_sharding(None);
+        g.set_quorum(quorum);
+        g.build(authority.clone()).unwrap()
+    };
+
+    // a graph on two workers, one of which also runs the controller
+    let authority = Arc::new(LocalAuthority::new());
+    let mut g = build(&authority, 2);
+    let _worker = build(&authority, 2);
+    assert!(g.inputs().unwrap().is_empty());
+    let (a, b) = g.migrate(|mig| {
+        let a = mig.add_base("a", &["a", "b"], Base::new(vec![]).with_key(vec![0]));
+        let b = mig.add_base("b", &["a", "b"], Base::new(vec![]).with_key(vec![0]));
+        let mut emits = HashMap::new();
+        emits.insert(a, vec![0, 1]);
+        emits.insert(b, vec![0, 1]);
+        let c = mig.add_ingredient("c", &["a", "b"], Union::new(emits));
+        mig.maintain_anonymous(c, &[0]);
+        (a, b)
+    });
+
+    let mut muta = g.table("a").unwrap();
+    let mut mutb = g.table("b").unwrap();
+    muta.insert(vec![1.into(), 2.into()]).unwrap();
+    mutb.insert(vec![1.into(), 4.into()]).unwrap();
+    sleep();
+
+    let stale = g.export_control_state().unwrap();
+    assert!(stale.last_migration().is_some());
+
+    // a graph that has changed since it was exported is not taken over
+    g.migrate(move |mig| {
+        let mut emits = HashMap::new();
+        emits.insert(a, vec![0, 1]);
+        let d = mig.add_ingredient("d", &["a", "b"], Union::new(emits));
+        mig.place(d, Placement::With(a));
+    });
+    let state = g.export_control_state().unwrap();
+    assert!(state.topology_version() > stale.topology_version());
+
+    let authority2 = Arc::new(LocalAuthority::new());
+    let mut g2 = build(&authority2, 1);
+    let e = g2.import_control_state(&stale).unwrap_err().to_string();
+    assert!(e.contains("which it should not"), "{}", e);
+
+    g2.import_control_state(&state).unwrap();
+    let (before, after) = (g.describe().unwrap(), g2.describe().unwrap());
+    assert_eq!(after.version, before.version);
+    assert_eq!(after.nodes.len(), before.nodes.len());
+    assert_eq!(after.domains.len(), before.domains.len());
+
+    // the new controller can read and write the existing graph, and extend it
+    let mut cq = g2.view("c").unwrap();
+    assert_eq!(cq.lookup(&[1.into()], true).unwrap().len(), 2);
+    let mut mutb = g2.table("b").unwrap();
+    mutb.insert(vec![2.into(), 6.into()]).unwrap();
+    sleep();
+    assert_eq!(
+        cq.lookup(&[2.into()], true).unwrap(),
+        vec![vec![2.into(), 6.into()]]
+    );
+
+    g2.migrate(move |mig| {
+        let mut emits = HashMap::new();
+        emits.insert(b, vec![0, 1]);
+        let e = mig.add_ingredient("e", &["a", "b"], Union::new(emits));
+        mig.maintain_anonymous(e, &[0]);
+    });
+    let mut eq = g2.view("e").unwrap();
+    assert_eq!(
+        eq.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 4.into()]]
+    );
+    assert_eq!(g2.describe().unwrap().version, state.topology_version() + 1);
+}
+
+#[test]
+fn it_logs_base_writes_ahead() {
+    use dataflow::wal;
+    use dataflow::{SyncPolicy, WalParameters};
+    use noria::TableOperation;
+
+    let dir = tempfile::tempdir().unwrap();
+    let mut persistence_params = PersistenceParameters::new(
+        DurabilityMode::MemoryOnly,
+        Duration::from_millis(1),
+        Some(String::from("it_logs_base_writes_ahead")),
+        1,
+    );
+    persistence_params.log_dir = Some(dir.path().to_owned());
+    // a segment per entry, so that the checkpoint has segments to delete
+    persistence_params.wal = Some(WalParameters {
+        sync: SyncPolicy::EveryWrite,
+        segment_size: 1,
+        ..Default::default()
+    });
+    let mut g = ControllerBuilder::default();
+    g.set_persistence(persistence_params.clone());
+    g.set_sharding(None);
+    g.disable_partial();
+    let mut g = g.build_local().unwrap();
+    g.install_recipe(
+        "CREATE TABLE Car (id int, price int, PRIMARY KEY(id));
+         QUERY CarPrice: SELECT price FROM Car WHERE id = ?;",
+    )
+    .unwrap();
+
+    let mut mutator = g.table("Car").unwrap();
+    for i in 1..10 {
+        mutator.insert(vec![i.into(), (i * 10).into()]).unwrap();
+    }
+    mutator.delete(vec![3.into()]).unwrap();
+    sleep();
+
+    // every write is in the log, in order, though group commit may have batched some together
+    let entries = wal::read(&persistence_params, "Car", 0).unwrap();
+    let seqs: Vec<_> = entries.iter().map(|e| e.seq).collect();
+    assert_eq!(seqs, (1..=entries.len() as u64).collect::<Vec<_>>());
+    let ops: Vec<_> = entries.into_iter().flat_map(|e| e.ops).collect();
+    assert_eq!(ops.len(), 10);
+    assert_eq!(ops[0], TableOperation::Insert(vec![1.into(), 10.into()]));
+    assert_eq!(
+        ops[9],
+        TableOperation::Delete {
+            key: vec![3.into()]
+        }
+    );
+
+    // once the base's state is checkpointed, only the segment being appended to is kept
+    assert!(wal::segments(&persistence_params, "Car", 0).unwrap().len() > 1);
+    g.checkpoint().unwrap();
+    let segments = wal::segments(&persistence_params, "Car", 0).unwrap();
+    assert_eq!(segments.len(), 1);
+    assert!(wal::read_segment(&segments[0].1).unwrap().is_empty());
+
+    let mut price = g.view("CarPrice").unwrap();
+    assert_eq!(
+        price.lookup(&[2.into()], true).unwrap(),
+        vec![vec![20.into()]]
+    );
+}
+
+#[test]
+fn it_recovers_acknowledged_writes() {
+    use dataflow::{SyncPolicy, WalParameters};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    let batches: i64 = 100;
+    let batch: i64 = 1_000;
+
+    let authority = Arc::new(LocalAuthority::new());
+    let dir = tempfile::tempdir().unwrap();
+    let mut persistence_params = PersistenceParameters::new(
+        DurabilityMode::MemoryOnly,
+        Duration::from_millis(1),
+        Some(String::from("it_recovers_acknowledged_writes")),
+        1,
+    );
+    persistence_params.log_dir = Some(dir.path().to_owned());
+    // the controller goes away, but the process doesn't, so nothing needs to be synced
+    persistence_params.wal = Some(WalParameters {
+        sync: SyncPolicy::Never,
+        segment_size: 64 * 1024,
+        recover: true,
+    });
+    let build = || {
+        let mut g = ControllerBuilder::default();
+        g.set_persistence(persistence_params.clone());
+        g.set_sharding(None);
+        g.disable_partial();
+        g.build(authority.clone()).unwrap()
+    };
+
+    let mut g = build();
+    g.install_recipe(
+        "CREATE TABLE Item (id int, batch int, PRIMARY KEY(id));
+         QUERY Items: SELECT COUNT(*) FROM Item WHERE batch = ?;",
+    )
+    .unwrap();
+
+    // each batch is written in one go, so it is in the log either in full or not at all
+    let acked = Arc::new(AtomicUsize::new(0));
+    let mut table = g.table("Item").unwrap().into_exclusive().unwrap();
+    let writer = {
+        let acked = acked.clone();
+        thread::spawn(move || {
+            for b in 0..batches {
+                let rows: Vec<Vec<DataType>> = (0..batch)
+                    .map(|i| vec![(b * batch + i).into(), b.into()])
+                    .collect();
+                if table.insert_all(rows).is_err() {
+                    break;
+                }
+                acked.fetch_add(1, Ordering::SeqCst);
+            }
+        })
+    };
+
+    // checkpoint part of the way through, and kill the controller at some later point
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .subsec_nanos();
+    let kill_at = 20 + nanos as usize % 70;
+    while acked.load(Ordering::SeqCst) < kill_at / 2 {
+        thread::yield_now();
+    }
+    g.checkpoint().unwrap();
+    while acked.load(Ordering::SeqCst) < kill_at {
+        thread::yield_now();
+    }
+    drop(g);
+    writer.join().unwrap();
+    let acked = acked.load(Ordering::SeqCst);
+    assert!(acked >= kill_at);
+
+    // every acknowledged batch is counted exactly once, and the others in full or not at all
+    let mut g = build();
+    let mut items = g.view("Items").unwrap();
+    for b in 0..batches {
+        let count = items.lookup(&[b.into()], true).unwrap();
+        if (b as usize) < acked {
+            assert_eq!(count, vec![vec![batch.into()]], "batch {}", b);
+        } else {
+            assert!(
+                count.is_empty() || count == vec![vec![batch.into()]],
+                "batch {}",
+                b
+            );
+        }
+    }
+
+    // and the recovered base carries on from where its log left off
+    let mut table = g.table("Item").unwrap();
+    table
+        .insert(vec![(batches * batch).into(), 0.into()])
+        .unwrap();
+    sleep();
+    assert_eq!(
+        items.lookup(&[0.into()], true).unwrap(),
+        vec![vec![(batch + 1).into()]]
+    );
+}
+
 #[test]
 fn mutator_churn() {
     let mut g = build_local("mutator_churn");
@@ -946,6 +6612,8 @@ fn it_works_with_join_arithmetic() {
     let result = getter.lookup(&[id.into()], true).unwrap();
     assert_eq!(result.len(), 1);
     assert_eq!(result[0][1], (price as f64 * fraction).into());
+
+    assert_consistent(&mut g);
 }
 
 #[test]
@@ -1072,7 +6740,9 @@ fn votes() {
 
     // check that article 2 doesn't have any votes
     let res = endq.lookup(&[a2.clone()], true).unwrap();
-    assert!(res.len() <= 1) // could be 1 if we had zero-rows
+    assert!(res.len() <= 1); // could be 1 if we had zero-rows
+
+    assert_consistent(&mut g);
 }
 
 #[test]
@@ -1171,6 +6841,8 @@ fn simple_migration() {
         bq.lookup(&[id.clone()], true).unwrap(),
         vec![vec![1.into(), 4.into()]]
     );
+
+    assert_consistent(&mut g);
 }
 
 #[test]
@@ -1207,10 +6879,11 @@ fn add_columns() {
     muta.insert(vec![id.clone(), "z".into()]).unwrap();
     sleep();
 
-    // check that a got it, and added the new, third column's default
+    // check that a got it, and added the new, third column's default. the existing row should
+    // also have been back-filled with the default.
     let res = aq.lookup(&[id.clone()], true).unwrap();
     assert_eq!(res.len(), 2);
-    assert!(res.contains(&vec![id.clone(), "y".into()]));
+    assert!(res.contains(&vec![id.clone(), "y".into(), 3.into()]));
     assert!(res.contains(&vec![id.clone(), "z".into(), 3.into()]));
 
     // get a new muta and send a new value on it
@@ -1222,11 +6895,80 @@ fn add_columns() {
     // check that a got it, and included the third column
     let res = aq.lookup(&[id.clone()], true).unwrap();
     assert_eq!(res.len(), 3);
-    assert!(res.contains(&vec![id.clone(), "y".into()]));
+    assert!(res.contains(&vec![id.clone(), "y".into(), 3.into()]));
     assert!(res.contains(&vec![id.clone(), "z".into(), 3.into()]));
     assert!(res.contains(&vec![id.clone(), "a".into(), 10.into()]));
 }
 
+#[test]
+fn it_backfills_added_columns() {
+    use dataflow::ops::filter::{Filter, FilterCondition, Operator, Value};
+
+    let cond = |op, v: DataType| Some(FilterCondition::Comparison(op, Value::Constant(v)));
+
+    let mut g = build_local("it_backfills_added_columns");
+    let a = g.migrate(move |mig| {
+        let a = mig.add_base(
+            "a",
+            &["id", "v"],
+            Base::new(vec![0.into(), 0.into()]).with_key(vec![0]),
+        );
+        let f = Filter::new(a, &[None, cond(Operator::Greater, 1.into())]);
+        let f = mig.add_ingredient("f", &["id", "v"], f);
+        mig.maintain_anonymous(f, &[0]);
+        a
+    });
+    let mut fq = g.view("f").unwrap();
+    let mut muta = g.table("a").unwrap();
+    muta.insert(vec![1.into(), 2.into()]).unwrap();
+    muta.insert(vec![2.into(), 0.into()]).unwrap();
+    sleep();
+    assert_eq!(
+        fq.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 2.into()]]
+    );
+
+    // add a column, and a filter that uses it
+    g.migrate(move |mig| {
+        mig.add_column(a, "c", "x".into());
+        let f = Filter::new(a, &[None, None, cond(Operator::Equal, "x".into())]);
+        let f = mig.add_ingredient("g", &["id", "v", "c"], f);
+        mig.maintain_anonymous(f, &[0]);
+    });
+    sleep();
+
+    // existing rows have been back-filled, both in the base and in the old filter's view
+    let mut gq = g.view("g").unwrap();
+    assert_eq!(
+        fq.lookup(&[1.into()], true).unwrap(),
+        vec![vec![1.into(), 2.into(), "x".into()]]
+    );
+    assert_eq!(
+        gq.lookup(&[2.into()], true).unwrap(),
+        vec![vec![2.into(), 0.into(), "x".into()]]
+    );
+
+    // deleting a back-filled row removes it downstream
+    muta.delete(vec![1.into()]).unwrap();
+    sleep();
+    assert!(fq.lookup(&[1.into()], true).unwrap().is_empty());
+
+    // old and new tables can both still write
+    muta.insert(vec![3.into(), 3.into()]).unwrap();
+    let mut muta = g.table("a").unwrap();
+    muta.insert(vec![4.into(), 4.into(), "y".into()]).unwrap();
+    sleep();
+    assert_eq!(
+        fq.lookup(&[3.into()], true).unwrap(),
+        vec![vec![3.into(), 3.into(), "x".into()]]
+    );
+    assert_eq!(
+        fq.lookup(&[4.into()], true).unwrap(),
+        vec![vec![4.into(), 4.into(), "y".into()]]
+    );
+    assert!(gq.lookup(&[4.into()], true).unwrap().is_empty());
+}
+
 #[test]
 fn migrate_added_columns() {
     let id: DataType = "x".into();
@@ -1333,15 +7075,18 @@ fn migrate_drop_columns() {
     muta2.insert(vec![id.clone()]).unwrap();
     sleep();
 
+    // the rows that were there before c was added should have been back-filled with its default
     let res = aq.lookup(&[id.clone()], true).unwrap();
     assert_eq!(res.len(), 5);
-    // NOTE: if we *hadn't* read bx and b above, they would have also have c because it would have
-    // been added when the lookups caused partial backfills.
-    assert!(res.contains(&vec![id.clone(), "bx".into()]));
-    assert!(res.contains(&vec![id.clone(), "b".into()]));
+    assert!(res.contains(&vec![id.clone(), "bx".into(), "c".into()]));
     assert!(res.contains(&vec![id.clone(), "b".into(), "cy".into()]));
     assert!(res.contains(&vec![id.clone(), "bz".into(), "c".into()]));
-    assert!(res.contains(&vec![id.clone(), "b".into(), "c".into()]));
+    assert_eq!(
+        res.iter()
+            .filter(|&r| r == &vec![id.clone(), "b".into(), "c".into()])
+            .count(),
+        2
+    );
 }
 
 #[test]
@@ -1580,6 +7325,8 @@ fn full_aggregation_with_bogokey() {
         aggq.lookup(&[0.into()], true).unwrap(),
         vec![vec![0.into(), 4.into()]]
     );
+
+    assert_consistent(&mut g);
 }
 
 #[test]
@@ -1625,6 +7372,8 @@ fn crossing_migration() {
     assert_eq!(res.len(), 2);
     assert!(res.contains(&vec![id.clone(), 2.into()]));
     assert!(res.contains(&vec![id.clone(), 4.into()]));
+
+    assert_consistent(&mut g);
 }
 
 #[test]
@@ -1675,6 +7424,8 @@ fn independent_domain_migration() {
         bq.lookup(&[id.clone()], true).unwrap(),
         vec![vec![1.into(), 4.into()]]
     );
+
+    assert_consistent(&mut g);
 }
 
 #[test]
@@ -1719,6 +7470,8 @@ fn domain_amend_migration() {
     assert_eq!(res.len(), 2);
     assert!(res.contains(&vec![id.clone(), 2.into()]));
     assert!(res.contains(&vec![id.clone(), 4.into()]));
+
+    assert_consistent(&mut g);
 }
 
 #[test]
@@ -1872,6 +7625,8 @@ fn do_full_vote_migration(old_puts_after: bool) {
             assert_eq!(row[3], 1.into(), "all articles should have one vote");
         }
     }
+
+    assert_consistent(&mut g);
 }
 
 #[test]
@@ -1953,6 +7708,93 @@ fn live_writes() {
             vec![vec![i.into(), votes.into()]]
         );
     }
+
+    assert_consistent(&mut g);
+}
+
+#[test]
+fn it_rebuilds_state_below_a_domain_exactly_once() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    let mut g = ControllerBuilder::default();
+    g.disable_partial();
+    g.set_sharding(None);
+    // so that each new aggregation gets a domain of its own, and is filled over an edge
+    g.set_domain_strategy(DomainStrategy::FewestCuts { max_nodes: 1 });
+    g.set_persistence(get_persistence_params(
+        "it_rebuilds_state_below_a_domain_exactly_once",
+    ));
+    let mut g = g.build_local().unwrap();
+    let vc = g.migrate(|mig| {
+        let vote = mig.add_base("vote", &["user", "id"], Base::default());
+        let vc = mig.add_ingredient(
+            "votecount",
+            &["id", "votes"],
+            Aggregation::COUNT.over(vote, 0, &[1]),
+        );
+        mig.maintain_anonymous(vc, &[0]);
+        vc
+    });
+
+    let ids = 100;
+    let mut add = g.table("vote").unwrap().into_exclusive().unwrap();
+    let done = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let done = done.clone();
+        thread::spawn(move || {
+            let mut rounds = 0;
+            while !done.load(Ordering::SeqCst) {
+                add.batch_insert((0..ids).map(|i| vec![rounds.into(), i.into()]))
+                    .unwrap();
+                rounds += 1;
+            }
+            rounds
+        })
+    };
+
+    // fill a new aggregation from the materialized count while writes keep going through it
+    sleep();
+    let vc2 = g.migrate(move |mig| {
+        let vc2 = mig.add_ingredient(
+            "votecount2",
+            &["id", "votes"],
+            Aggregation::SUM.over(vc, 1, &[0]),
+        );
+        mig.maintain_anonymous(vc2, &[0]);
+        vc2
+    });
+    let domains = g.migrate(move |mig| (mig.graph()[vc].domain(), mig.graph()[vc2].domain()));
+    assert_ne!(domains.0, domains.1);
+    sleep();
+    done.store(true, Ordering::SeqCst);
+    let rounds: i32 = writer.join().unwrap();
+    sleep();
+
+    // and fill the same aggregation again once nothing is being written
+    g.migrate(move |mig| {
+        let vc3 = mig.add_ingredient(
+            "votecount3",
+            &["id", "votes"],
+            Aggregation::SUM.over(vc, 1, &[0]),
+        );
+        mig.maintain_anonymous(vc3, &[0]);
+    });
+
+    let mut vc = g.view("votecount").unwrap();
+    let mut vc2 = g.view("votecount2").unwrap();
+    let mut vc3 = g.view("votecount3").unwrap();
+    for i in 0..ids {
+        let expected = vec![vec![i.into(), rounds.into()]];
+        assert_eq!(vc.lookup(&[i.into()], true).unwrap(), expected);
+        assert_eq!(vc3.lookup(&[i.into()], true).unwrap(), expected);
+        assert_eq!(vc2.lookup(&[i.into()], true).unwrap(), expected);
+    }
+
+    let m = g.local_metrics();
+    assert_eq!(m.sum("noria_domain_lost_packets", &[]), 0);
+    assert_eq!(m.sum("noria_domain_duplicate_packets", &[]), 0);
+
+    assert_consistent(&mut g);
 }
 
 #[test]
@@ -2011,6 +7853,8 @@ fn state_replay_migration_query() {
 
     // there are (/should be) no records with x == 3
     assert!(out.lookup(&[3.into()], true).unwrap().is_empty());
+
+    assert_consistent(&mut g);
 }
 
 #[test]
@@ -368,10 +368,20 @@ mod coordination;
 #[cfg(test)]
 mod integration;
 
+pub use crate::controller::migrate::events::{MigrationEvent, MigrationEventKind, MigrationPhase};
+pub use crate::controller::migrate::graph_log::{GraphLog, GraphOperation};
+pub use crate::controller::migrate::validation::{ValidationError, ValidationErrorKind};
 pub use crate::controller::sql::reuse::ReuseConfigType;
-pub use crate::controller::{ControllerBuilder, LocalControllerHandle};
-pub use dataflow::{DurabilityMode, PersistenceParameters};
+pub use crate::controller::{
+    ControlState, ControllerBuilder, FaultId, FaultInjector, LocalControllerHandle,
+};
+pub use dataflow::{
+    Backpressure, ChannelConfig, DurabilityMode, IndexType, MalformedRecords, MaterializationHint,
+    OverloadPolicy, PersistenceParameters, Placement, PublishPolicy, Replay, ReplayPriority,
+    StateBackend, SyncPolicy, WalParameters,
+};
 pub use noria::consensus::LocalAuthority;
+pub use noria::debug::plan::DomainStrategy;
 pub use noria::*;
 pub use petgraph::graph::NodeIndex;
 
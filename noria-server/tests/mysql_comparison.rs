@@ -313,6 +313,7 @@ fn check_query(
                         DataType::Real(i, f) => ((i as f64) + (f as f64) * 1.0e-9).to_string(),
                         DataType::Text(_) | DataType::TinyText(_) => v.into(),
                         DataType::Timestamp(_) => unimplemented!(),
+                        DataType::Bytes(_) => unimplemented!(),
                     })
                     .collect()
             })
@@ -21,7 +21,7 @@ pub mod poll;
 pub mod rpc;
 pub mod tcp;
 
-pub use self::tcp::{channel, DualTcpStream, TcpReceiver, TcpSender};
+pub use self::tcp::{channel, DualTcpStream, TcpReceiver, TcpSender, WriteAck};
 
 pub const CONNECTION_FROM_BASE: u8 = 1;
 pub const CONNECTION_FROM_DOMAIN: u8 = 0;
@@ -29,11 +29,19 @@ pub const CONNECTION_FROM_DOMAIN: u8 = 0;
 pub struct Remote;
 pub struct MaybeLocal;
 
+/// The sending end of an in-process connection. Each send carries a batch of items, so that a
+/// burst of them costs the receiving end a single wakeup.
+pub type LocalSender<T> = futures::sync::mpsc::UnboundedSender<Vec<T>>;
+
+/// The receiving end of an in-process connection (see `LocalSender`).
+pub type LocalReceiver<T> = futures::sync::mpsc::UnboundedReceiver<Vec<T>>;
+
 pub struct DomainConnectionBuilder<D, T> {
     sport: Option<u16>,
     addr: SocketAddr,
-    chan: Option<futures::sync::mpsc::UnboundedSender<T>>,
+    chan: Option<LocalSender<T>>,
     is_for_base: bool,
+    capacity: Option<usize>,
     _marker: D,
 }
 
@@ -44,6 +52,20 @@ impl<T> DomainConnectionBuilder<Remote, T> {
             chan: None,
             addr,
             is_for_base: true,
+            capacity: None,
+            _marker: Remote,
+        }
+    }
+
+    /// A connection to the domain at `addr` that goes over TCP even if the domain runs in this
+    /// process, for when the domain can't be looked up by its index, as while it is being moved.
+    pub fn for_domain(addr: SocketAddr) -> Self {
+        DomainConnectionBuilder {
+            sport: None,
+            chan: None,
+            addr,
+            is_for_base: false,
+            capacity: None,
             _marker: Remote,
         }
     }
@@ -59,6 +81,12 @@ impl<D, T> DomainConnectionBuilder<D, T> {
         self.sport = Some(sport);
         self
     }
+
+    /// Buffer up to `cap` bytes on the connection, if it goes over TCP.
+    pub fn with_capacity(mut self, cap: usize) -> Self {
+        self.capacity = Some(cap);
+        self
+    }
 }
 
 impl<T> DomainConnectionBuilder<Remote, T>
@@ -71,16 +99,20 @@ where
         // TODO: async
         // we must currently write and call flush, because the remote end (currently) does a
         // synchronous read upon accepting a connection.
+        let capacity = self.capacity;
         let s = self.build_sync()?.into_inner().into_inner()?;
 
         tokio::net::TcpStream::from_std(s, &tokio::reactor::Handle::default())
-            .map(BufWriter::new)
+            .map(|s| match capacity {
+                Some(cap) => BufWriter::with_capacity(cap, s),
+                None => BufWriter::new(s),
+            })
             .map(AsyncBincodeWriter::from)
             .map(AsyncBincodeWriter::for_async)
     }
 
     pub fn build_sync(self) -> io::Result<TcpSender<T>> {
-        let mut s = TcpSender::connect_from(self.sport, &self.addr)?;
+        let mut s = TcpSender::connect_from(self.sport, &self.addr, self.capacity)?;
         {
             let s = s.get_mut();
             s.write_all(&[if self.is_for_base {
@@ -114,24 +146,51 @@ impl<T> Sender for futures::sync::mpsc::UnboundedSender<T> {
     }
 }
 
+/// Sends every item through an in-process connection in a batch of its own.
+struct OneAtATime<T>(LocalSender<T>);
+
+impl<T> Sender for OneAtATime<T> {
+    type Item = T;
+
+    fn send(&mut self, t: Self::Item) -> Result<(), tcp::SendError> {
+        Sender::send(&mut self.0, vec![t])
+    }
+}
+
 impl<T> DomainConnectionBuilder<MaybeLocal, T>
 where
     T: serde::Serialize + 'static + Send,
 {
+    /// Whether the connection will go over an in-process channel rather than over TCP.
+    pub fn is_local(&self) -> bool {
+        self.chan.is_some()
+    }
+
+    /// The in-process channel that the connection would go over, if any. Unlike the connections
+    /// that are built, it can be sent several items at once.
+    pub fn local(&self) -> Option<LocalSender<T>> {
+        self.chan.clone()
+    }
+
+    /// Build the connection. If it goes over an in-process channel, every item is sent in a batch
+    /// of its own.
     pub fn build_async(
         self,
     ) -> io::Result<Box<dyn Sink<SinkItem = T, SinkError = bincode::Error> + Send>> {
         if let Some(chan) = self.chan {
-            Ok(
-                Box::new(chan.sink_map_err(|_| serde::de::Error::custom("failed to do local send")))
-                    as Box<_>,
-            )
+            Ok(Box::new(
+                chan.sink_map_err(|_| -> bincode::Error {
+                    serde::de::Error::custom("failed to do local send")
+                })
+                .with(|t| -> Result<_, bincode::Error> { Ok(vec![t]) }),
+            ) as Box<_>)
         } else {
             DomainConnectionBuilder {
                 sport: self.sport,
                 chan: None,
                 addr: self.addr,
                 is_for_base: false,
+                capacity: self.capacity,
                 _marker: Remote,
             }
             .build_async()
@@ -141,13 +200,14 @@ where
 
     pub fn build_sync(self) -> io::Result<Box<dyn Sender<Item = T> + Send>> {
         if let Some(chan) = self.chan {
-            Ok(Box::new(chan))
+            Ok(Box::new(OneAtATime(chan)))
         } else {
             DomainConnectionBuilder {
                 sport: self.sport,
                 chan: None,
                 addr: self.addr,
                 is_for_base: false,
+                capacity: self.capacity,
                 _marker: Remote,
             }
             .build_sync()
@@ -237,7 +297,10 @@ struct ChannelCoordinatorInner<K: Eq + Hash + Clone, T> {
     /// Map from key to remote address.
     addrs: HashMap<K, SocketAddr>,
     /// Map from key to channel sender for local connections.
-    locals: HashMap<K, futures::sync::mpsc::UnboundedSender<T>>,
+    locals: HashMap<K, LocalSender<T>>,
+    /// Map from key to the address of, and channel sender for, a local copy that is to take over
+    /// from wherever the key is reached now.
+    moving: HashMap<K, (SocketAddr, LocalSender<T>)>,
 }
 
 pub struct ChannelCoordinator<K: Eq + Hash + Clone, T> {
@@ -250,6 +313,7 @@ impl<K: Eq + Hash + Clone, T> ChannelCoordinator<K, T> {
             inner: RwLock::new(ChannelCoordinatorInner {
                 addrs: Default::default(),
                 locals: Default::default(),
+                moving: Default::default(),
             }),
         }
     }
@@ -259,7 +323,34 @@ impl<K: Eq + Hash + Clone, T> ChannelCoordinator<K, T> {
         inner.addrs.insert(key, addr);
     }
 
-    pub fn insert_local(&self, key: K, chan: futures::sync::mpsc::UnboundedSender<T>) {
+    /// Note that `key` is now reached at `addr`. If it was reached at another address before, it
+    /// has moved there, and the in-process channel to where it was is dropped. If the copy that it
+    /// moved to runs in this process, it is reached through its in-process channel from now on.
+    pub fn update_remote(&self, key: K, addr: SocketAddr) {
+        let mut inner = self.inner.write().unwrap();
+        if inner.addrs.get(&key) != Some(&addr) {
+            inner.locals.remove(&key);
+            inner.addrs.insert(key.clone(), addr);
+        }
+        if inner.moving.get(&key).map(|&(at, _)| at == addr) == Some(true) {
+            let (_, chan) = inner.moving.remove(&key).unwrap();
+            inner.locals.insert(key, chan);
+        }
+    }
+
+    /// Hold on to the in-process channel to a copy of `key` that listens at `addr`, but that is
+    /// yet to take over. `key` is reached through it once it is updated to `addr`.
+    pub fn insert_moving_local(
+        &self,
+        key: K,
+        addr: SocketAddr,
+        chan: LocalSender<T>,
+    ) {
+        let mut inner = self.inner.write().unwrap();
+        inner.moving.insert(key, (addr, chan));
+    }
+
+    pub fn insert_local(&self, key: K, chan: LocalSender<T>) {
         let mut inner = self.inner.write().unwrap();
         inner.locals.insert(key, chan);
     }
@@ -299,6 +390,7 @@ impl<K: Eq + Hash + Clone, T> ChannelCoordinator<K, T> {
             addr: *inner.addrs.get(key)?,
             chan: inner.locals.get(key).cloned(),
             is_for_base: false,
+            capacity: None,
             _marker: MaybeLocal,
         })
     }
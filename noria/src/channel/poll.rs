@@ -158,6 +158,11 @@ where
         }
     }
 
+    /// The number of channels whose sender has hung up.
+    pub fn closed_channels(&self) -> usize {
+        self.inner.channels.iter().filter(|c| c.is_none()).count()
+    }
+
     pub fn get_listener_addr(&self) -> Option<SocketAddr> {
         self.inner.get_listener_addr()
     }
@@ -281,6 +286,9 @@ impl<T: Serialize + DeserializeOwned> PollingLoop<T> {
     pub fn get_listener_addr(&self) -> Option<SocketAddr> {
         self.polling_loop.get_listener_addr()
     }
+    pub fn closed_channels(&self) -> usize {
+        self.polling_loop.closed_channels()
+    }
 
     /// Execute steps of the polling loop until process_event() returns `StopPolling`.
     pub fn run_polling_loop<F>(&mut self, mut process_event: F)
@@ -15,6 +15,13 @@ use throttled_reader::ThrottledReader;
 use tokio::prelude::*;
 
 use super::{DeserializeReceiver, NonBlockingWriter, ReceiveError};
+use crate::error::WriteError;
+
+/// The acknowledgement sent back to a client for each write it sends to a base table.
+///
+/// A successful write is acknowledged with the ids its rows were assigned by the base table's
+/// auto-increment column, if it has one.
+pub type WriteAck = Result<Vec<i64>, WriteError>;
 
 #[derive(Debug, Fail)]
 pub enum SendError {
@@ -59,24 +66,47 @@ pub struct TcpSender<T> {
 
 impl<T: Serialize> TcpSender<T> {
     pub fn new(stream: std::net::TcpStream) -> Result<Self, io::Error> {
+        Self::new_inner(None, stream)
+    }
+
+    pub fn with_capacity(cap: usize, stream: std::net::TcpStream) -> Result<Self, io::Error> {
+        Self::new_inner(Some(cap), stream)
+    }
+
+    fn new_inner(cap: Option<usize>, stream: std::net::TcpStream) -> Result<Self, io::Error> {
         stream.set_nodelay(true).unwrap();
+        let stream = if let Some(cap) = cap {
+            BufStream::with_capacities(cap, cap, stream)
+        } else {
+            BufStream::new(stream)
+        };
+
         Ok(Self {
-            stream: BufStream::new(stream),
+            stream,
             poisoned: false,
             phantom: PhantomData,
         })
     }
 
-    pub(crate) fn connect_from(sport: Option<u16>, addr: &SocketAddr) -> Result<Self, io::Error> {
+    pub(crate) fn connect_from(
+        sport: Option<u16>,
+        addr: &SocketAddr,
+        cap: Option<usize>,
+    ) -> Result<Self, io::Error> {
         let s = net2::TcpBuilder::new_v4()?
             .reuse_address(true)?
             .bind((Ipv4Addr::UNSPECIFIED, sport.unwrap_or(0)))?
             .connect(addr)?;
-        Self::new(s)
+        Self::new_inner(cap, s)
     }
 
     pub fn connect(addr: &SocketAddr) -> Result<Self, io::Error> {
-        Self::connect_from(None, addr)
+        Self::connect_from(None, addr, None)
+    }
+
+    /// Connect to `addr`, buffering up to `cap` bytes in either direction.
+    pub fn connect_with_capacity(addr: &SocketAddr, cap: usize) -> Result<Self, io::Error> {
+        Self::connect_from(None, addr, Some(cap))
     }
 
     pub fn get_mut(&mut self) -> &mut BufStream<std::net::TcpStream> {
@@ -139,9 +169,9 @@ pub enum RecvError {
 }
 
 pub enum DualTcpStream<S, T, T2, D> {
-    Passthrough(AsyncBincodeStream<S, T, bool, D>),
+    Passthrough(AsyncBincodeStream<S, T, WriteAck, D>),
     Upgrade(
-        AsyncBincodeStream<S, T2, bool, D>,
+        AsyncBincodeStream<S, T2, WriteAck, D>,
         Box<FnMut(T2) -> T + Send + Sync>,
     ),
 }
@@ -154,7 +184,8 @@ impl<S, T, T2> From<S> for DualTcpStream<S, T, T2, SyncDestination> {
 
 impl<S, T, T2> DualTcpStream<S, T, T2, SyncDestination> {
     pub fn upgrade<F: 'static + FnMut(T2) -> T + Send + Sync>(stream: S, f: F) -> Self {
-        let s: AsyncBincodeStream<S, T2, bool, SyncDestination> = AsyncBincodeStream::from(stream);
+        let s: AsyncBincodeStream<S, T2, WriteAck, SyncDestination> =
+            AsyncBincodeStream::from(stream);
         DualTcpStream::Upgrade(s, Box::new(f))
     }
 
@@ -169,9 +200,9 @@ impl<S, T, T2> DualTcpStream<S, T, T2, SyncDestination> {
 impl<S, T, T2, D> Sink for DualTcpStream<S, T, T2, D>
 where
     S: AsyncWrite,
-    AsyncBincodeWriter<S, bool, D>: Sink<SinkItem = bool, SinkError = bincode::Error>,
+    AsyncBincodeWriter<S, WriteAck, D>: Sink<SinkItem = WriteAck, SinkError = bincode::Error>,
 {
-    type SinkItem = bool;
+    type SinkItem = WriteAck;
     type SinkError = bincode::Error;
     fn start_send(
         &mut self,
@@ -1,7 +1,11 @@
 #[cfg(debug_assertions)]
 use assert_infrequent;
 use crate::consensus::{self, Authority};
-use crate::debug::stats;
+use crate::data::DataType;
+use crate::debug::{consistency, dead_letters, diagnosis, metrics, plan, stats, topology};
+use crate::error::{NotFound, NotQuiesced};
+use crate::filter::RowFilter;
+use crate::internal::DomainIndex;
 use crate::table::{Table, TableBuilder, TableRpc};
 use crate::view::{View, ViewBuilder, ViewRpc};
 use crate::ActivationResult;
@@ -17,6 +21,7 @@ use serde::Serialize;
 use serde_json;
 use std::collections::{BTreeMap, HashMap};
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
@@ -208,7 +213,15 @@ impl<A: Authority> ControllerHandle<A> {
         self.rpc("outputs", &())
     }
 
+    /// Enumerate the workers that have joined, by the address that the controller knows each one
+    /// by, along with whether it is healthy and how long ago it last sent a heartbeat.
+    pub fn instances(&mut self) -> Result<Vec<(SocketAddr, bool, Duration)>, failure::Error> {
+        self.rpc("instances", &())
+    }
+
     /// Obtain a `View` that allows you to query the given external view.
+    ///
+    /// Fails with `error::NotFound` if there is no view by that name.
     pub fn view(&mut self, name: &str) -> Result<View, failure::Error> {
         // This call attempts to detect if this function is being called in a loop. If this is
         // getting false positives, then it is safe to increase the allowed hit count, however, the
@@ -218,7 +231,7 @@ impl<A: Authority> ControllerHandle<A> {
 
         self.rpc::<_, Option<ViewBuilder>>("view_builder", name)
             .context(format!("building View for {}", name))?
-            .ok_or_else(|| format_err!("view {} does not exist", name))
+            .ok_or_else(|| failure::Error::from(NotFound::View(name.to_owned())))
             .and_then(|mut g| {
                 if let Some(port) = self.local_port {
                     g = g.with_local_port(port);
@@ -234,8 +247,19 @@ impl<A: Authority> ControllerHandle<A> {
             })
     }
 
+    /// Obtain a `View` of the given external view that only returns the rows that pass `filter`
+    /// (see `View::filtered`).
+    ///
+    /// Fails with `error::NotFound` if there is no view by that name, and with
+    /// `ViewError::NoSuchColumn` if the filter reads a column that the view does not have.
+    pub fn view_filtered(&mut self, name: &str, filter: RowFilter) -> Result<View, failure::Error> {
+        Ok(self.view(name)?.filtered(filter)?)
+    }
+
     /// Obtain a `Table` that allows you to perform writes, deletes, and other operations on the
     /// given base table.
+    ///
+    /// Fails with `error::NotFound` if there is no table by that name.
     pub fn table(&mut self, name: &str) -> Result<Table, failure::Error> {
         // This call attempts to detect if this function is being called in a loop. If this
         // is getting false positives, then it is safe to increase the allowed hit count.
@@ -244,7 +268,7 @@ impl<A: Authority> ControllerHandle<A> {
 
         self.rpc::<_, Option<TableBuilder>>("table_builder", name)
             .context(format!("building Table for {}", name))?
-            .ok_or_else(|| format_err!("view {} does not exist", name))
+            .ok_or_else(|| failure::Error::from(NotFound::Table(name.to_owned())))
             .and_then(|mut m| {
                 if let Some(port) = self.local_port {
                     m = m.with_local_port(port);
@@ -265,6 +289,45 @@ impl<A: Authority> ControllerHandle<A> {
         Ok(self.rpc("get_statistics", &()).context("getting stats")?)
     }
 
+    /// Get the current values of the counters that every domain keeps as it runs, and of those of
+    /// their nodes and readers. The same metrics can be scraped in the Prometheus text format
+    /// with a `GET` request for `/metrics` on the controller.
+    pub fn metrics(&mut self) -> Result<metrics::MetricsSnapshot, failure::Error> {
+        Ok(self.rpc("metrics", &()).context("getting metrics")?)
+    }
+
+    /// Find the domain shards that packets kept piling up at since the last call to `diagnose`, or
+    /// since they started, while they weren't held up sending to the domains below them. Each
+    /// finding names the node that spent the most time processing in that domain shard.
+    pub fn diagnose(
+        &mut self,
+        thresholds: diagnosis::SlowDomainThresholds,
+    ) -> Result<Vec<diagnosis::SlowDomain>, failure::Error> {
+        Ok(self
+            .rpc("diagnose", thresholds)
+            .context("diagnosing slow domains")?)
+    }
+
+    /// Take the packets that domains could not deliver since the last call to `dead_letters`,
+    /// because the domain or client they were headed for had gone away. Each worker only keeps
+    /// the most recent ones, and those of a worker that has no running domains left can't be
+    /// taken.
+    pub fn dead_letters(&mut self) -> Result<Vec<dead_letters::DeadLetter>, failure::Error> {
+        Ok(self
+            .rpc("dead_letters", &())
+            .context("draining dead letters")?)
+    }
+
+    /// Describe the bases, views, and nodes of the dataflow graph, and where its domains run.
+    ///
+    /// The description's `version` only changes when the graph does, so a description can be
+    /// reused for as long as the version it has is current.
+    pub fn describe(&mut self) -> Result<topology::TopologyDescription, failure::Error> {
+        Ok(self
+            .rpc("describe", &())
+            .context("describing the dataflow graph")?)
+    }
+
     /// Flush all partial state, evicting all rows present.
     pub fn flush_partial(&mut self) -> Result<(), failure::Error> {
         self.rpc("flush_partial", &())
@@ -272,6 +335,173 @@ impl<A: Authority> ControllerHandle<A> {
         Ok(())
     }
 
+    /// List the `n` materializations that take up the most memory, largest first, along with
+    /// their name and estimated size in bytes.
+    pub fn largest_materializations(
+        &mut self,
+        n: usize,
+    ) -> Result<Vec<(NodeIndex, String, u64)>, failure::Error> {
+        Ok(self
+            .rpc("largest_materializations", n)
+            .context("listing largest materializations")?)
+    }
+
+    /// Compute a checksum of the rows materialized at the given node (see `checksum`), or `None`
+    /// if the node is not materialized. Only the rows that are present count towards the checksum
+    /// of a partially materialized node.
+    pub fn checksum(&mut self, node: NodeIndex) -> Result<Option<u64>, failure::Error> {
+        Ok(self
+            .rpc("checksum", node)
+            .context(format!("computing checksum of node {}", node.index()))?)
+    }
+
+    /// Compute a checksum of the rows in the view `name` that are visible to reads (see
+    /// `checksum`). Only the rows for keys that have been filled count towards the checksum of a
+    /// partially materialized view.
+    pub fn view_checksum(&mut self, name: &str) -> Result<Option<u64>, failure::Error> {
+        Ok(self
+            .rpc("view_checksum", name)
+            .context(format!("computing checksum of view {}", name))?)
+    }
+
+    /// Recompute every fully materialized node from the rows in the base tables, and report the
+    /// rows that each one is missing or has in excess, along with the nodes the expected rows were
+    /// computed along.
+    ///
+    /// Nodes are read one after the other, so the report is only meaningful once the writes to the
+    /// bases have been processed.
+    pub fn check_consistency(&mut self) -> Result<consistency::ConsistencyReport, failure::Error> {
+        Ok(self
+            .rpc("check_consistency", &())
+            .context("checking consistency")?)
+    }
+
+    /// Bring the graph to rest: wait until every write that had reached a base table has been
+    /// processed by every node below it, and is visible to reads. Writes that arrive in the
+    /// meantime are held back, and are let through once this returns.
+    ///
+    /// If the graph has not drained within `timeout`, or some domains have failed, this returns a
+    /// `NotQuiesced` error that names the domain shards that had yet to drain.
+    pub fn quiesce(&mut self, timeout: Duration) -> Result<(), failure::Error> {
+        let stuck: Vec<(DomainIndex, usize)> = self.rpc("quiesce", timeout).context("quiescing")?;
+        if stuck.is_empty() {
+            Ok(())
+        } else {
+            Err(NotQuiesced { domains: stuck }.into())
+        }
+    }
+
+    /// Evict the given keys from the view `name`. If no keys are given, the view's least recently
+    /// read keys are evicted until it is within its memory limit.
+    ///
+    /// Only partially materialized views can evict keys.
+    pub fn evict_view(
+        &mut self,
+        name: &str,
+        keys: Option<Vec<Vec<DataType>>>,
+    ) -> Result<(), failure::Error> {
+        self.rpc("evict_view", (name, keys))
+            .context(format!("evicting from view {}", name))?;
+        Ok(())
+    }
+
+    /// Save all fully materialized state to disk, so that it can be restored rather than
+    /// recomputed when the controller is next restarted.
+    ///
+    /// Not available when writes to base tables are persisted.
+    pub fn checkpoint(&mut self) -> Result<(), failure::Error> {
+        self.rpc("checkpoint", &()).context("checkpointing state")?;
+        Ok(())
+    }
+
+    /// Have every shard of the given domain write each event it sees from now on to a capture
+    /// file in the directory `into`, which must be writable by the workers the shards run on.
+    /// Returns the files written to, one per shard. Domains with partial state can't be captured.
+    ///
+    /// A capture can be re-run outside of a running system with `noria_server::Replay`.
+    pub fn start_capture<P: Into<PathBuf>>(
+        &mut self,
+        domain: DomainIndex,
+        into: P,
+    ) -> Result<Vec<PathBuf>, failure::Error> {
+        Ok(self
+            .rpc("capture", (domain, Some(into.into())))
+            .context(format!("starting capture of domain {}", domain.index()))?)
+    }
+
+    /// Stop the given domain from capturing the events it sees.
+    pub fn stop_capture(&mut self, domain: DomainIndex) -> Result<(), failure::Error> {
+        self.rpc::<_, Vec<PathBuf>>("capture", (domain, None::<PathBuf>))
+            .context(format!("stopping capture of domain {}", domain.index()))?;
+        Ok(())
+    }
+
+    /// Have every shard of the given domain stop processing updates and writes until it is
+    /// resumed. The domain holds on to what arrives in the meantime, and the bases whose writes
+    /// flow to it eventually stop accepting writes, rather than dropping any. The domain still
+    /// reports statistics and checksums while it is paused.
+    ///
+    /// Migrations fail for as long as any domain is paused.
+    pub fn pause_domain(&mut self, domain: DomainIndex) -> Result<(), failure::Error> {
+        Ok(self
+            .rpc("pause_domain", domain)
+            .context(format!("pausing domain {}", domain.index()))?)
+    }
+
+    /// Have a paused domain process everything it held on to, in the order it arrived, and carry
+    /// on as before.
+    pub fn resume_domain(&mut self, domain: DomainIndex) -> Result<(), failure::Error> {
+        Ok(self
+            .rpc("resume_domain", domain)
+            .context(format!("resuming domain {}", domain.index()))?)
+    }
+
+    /// Hold the domain `standby` in reserve for the domain `primary`. The domains that send
+    /// updates to both stop sending them to `standby`, and switch over to it if `primary` fails,
+    /// first re-sending it the updates that it missed. Re-adds `standby` if it failed earlier.
+    ///
+    /// `standby` must hold the same nodes as `primary`, and must have been sent the same updates
+    /// until now, as two copies of a view that are added in the same migration are.
+    pub fn add_standby(
+        &mut self,
+        primary: DomainIndex,
+        standby: DomainIndex,
+    ) -> Result<(), failure::Error> {
+        Ok(self
+            .rpc("add_standby", (primary, standby))
+            .context(format!("adding standby {}", standby.index()))?)
+    }
+
+    /// Switch the domains that send updates to the domain `from` over to its standby `to`.
+    pub fn switch_to_standby(
+        &mut self,
+        from: DomainIndex,
+        to: DomainIndex,
+    ) -> Result<(), failure::Error> {
+        Ok(self
+            .rpc("switch_to_standby", (from, to))
+            .context(format!("switching to standby {}", to.index()))?)
+    }
+
+    /// Move the domain to the worker `to`, as listed by `instances`, while it keeps running.
+    ///
+    /// A snapshot of the domain's state is first shipped to a copy of the domain on `to`, while
+    /// the domain keeps processing updates and logs them. The domains that send updates to it are
+    /// then paused just long enough for the copy to catch up on the logged updates, and resumed
+    /// once they send to the copy instead.
+    ///
+    /// Only domains that are not sharded, that have no bases or readers, and that have no partial
+    /// state in them or below them, can be moved.
+    pub fn move_domain(
+        &mut self,
+        domain: DomainIndex,
+        to: SocketAddr,
+    ) -> Result<stats::MoveStats, failure::Error> {
+        Ok(self
+            .rpc("move_domain", (domain, to))
+            .context(format!("moving domain {}", domain.index()))?)
+    }
+
     /// Extend the existing recipe with the given set of queries.
     pub fn extend_recipe(
         &mut self,
@@ -282,6 +512,28 @@ impl<A: Authority> ControllerHandle<A> {
             .context(String::from(recipe_addition))?)
     }
 
+    /// Work out what extending the existing recipe with the given set of queries would do to the
+    /// dataflow graph, without changing anything.
+    pub fn plan_recipe(
+        &mut self,
+        recipe_addition: &str,
+    ) -> Result<plan::MigrationPlan, failure::Error> {
+        Ok(self
+            .rpc::<_, plan::MigrationPlan>("plan_recipe", recipe_addition)
+            .context(String::from(recipe_addition))?)
+    }
+
+    /// Move the readers of the given views into a new domain of their own, so that keeping them
+    /// up to date no longer slows down the domain they are in now. The views must all be in the
+    /// same domain, and it must not be sharded. Returns the index of the new domain.
+    ///
+    /// Existing `View`s of the moved views keep working while they are moved, and after.
+    pub fn move_views(&mut self, views: &[&str]) -> Result<usize, failure::Error> {
+        Ok(self
+            .rpc::<_, usize>("move_views", views)
+            .context(format!("moving {:?}", views))?)
+    }
+
     /// Replace the existing recipe with this one.
     pub fn install_recipe(&mut self, new_recipe: &str) -> Result<ActivationResult, failure::Error> {
         Ok(self
@@ -1,12 +1,13 @@
 use arccstr::ArcCStr;
 
-use chrono::{self, NaiveDateTime};
+use chrono::{self, DateTime, NaiveDateTime, Timelike, Utc};
 
 use nom_sql::Literal;
 
 use std::fmt;
 use std::hash::{Hash, Hasher};
 use std::ops::{Add, Div, Mul, Sub};
+use std::sync::Arc;
 
 const FLOAT_PRECISION: f64 = 1_000_000_000.0;
 const TINYTEXT_WIDTH: usize = 15;
@@ -23,6 +24,11 @@ const TINYTEXT_WIDTH: usize = 15;
 #[warn(variant_size_differences)]
 pub enum DataType {
     /// An empty value.
+    ///
+    /// This is Noria's equivalent of SQL `NULL`. For the purposes of grouping, hashing, and
+    /// indexing, `None` compares equal to itself and orders after all other values, so that all
+    /// `NULL`s end up in the same group. Comparisons with SQL semantics (see
+    /// `DataType::sql_cmp`) instead treat any comparison involving `None` as unknown.
     None,
     /// A 32-bit numeric value.
     Int(i32),
@@ -36,7 +42,78 @@ pub enum DataType {
     /// A tiny string that fits in a pointer
     TinyText([u8; TINYTEXT_WIDTH]),
     /// A timestamp for date/time types.
+    ///
+    /// Timestamps have nanosecond precision, and are always interpreted as UTC.
     Timestamp(NaiveDateTime),
+    /// A reference-counted opaque binary value.
+    ///
+    /// Binary values can be compared for equality, hashed, and used as keys, but their ordering is
+    /// arbitrary and they cannot be used in range comparisons (e.g., in filters).
+    Bytes(Arc<Vec<u8>>),
+}
+
+/// Granularities that a `DataType::Timestamp` can be truncated to using `DataType::truncate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum TimeUnit {
+    /// Truncate to the start of the hour.
+    Hour,
+    /// Truncate to midnight, which effectively extracts the date.
+    Day,
+}
+
+impl fmt::Display for TimeUnit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TimeUnit::Hour => write!(f, "hour"),
+            TimeUnit::Day => write!(f, "date"),
+        }
+    }
+}
+
+/// The kinds of values a base table column can be declared to hold.
+///
+/// Each `ColumnType` covers all the `DataType` variants that represent that kind of value, so
+/// `ColumnType::Int` accepts both `DataType::Int` and `DataType::BigInt`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ColumnType {
+    /// Signed integers.
+    Int,
+    /// Fixed-point real numbers.
+    Real,
+    /// Strings.
+    Text,
+    /// Date and time values.
+    Timestamp,
+    /// Opaque binary values.
+    Bytes,
+}
+
+impl ColumnType {
+    /// Determine the kind of the given value.
+    ///
+    /// Returns `None` for `DataType::None`, since SQL `NULL` does not have a type of its own.
+    pub fn of(value: &DataType) -> Option<ColumnType> {
+        match *value {
+            DataType::None => None,
+            DataType::Int(..) | DataType::BigInt(..) => Some(ColumnType::Int),
+            DataType::Real(..) => Some(ColumnType::Real),
+            DataType::Text(..) | DataType::TinyText(..) => Some(ColumnType::Text),
+            DataType::Timestamp(..) => Some(ColumnType::Timestamp),
+            DataType::Bytes(..) => Some(ColumnType::Bytes),
+        }
+    }
+}
+
+impl fmt::Display for ColumnType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ColumnType::Int => write!(f, "int"),
+            ColumnType::Real => write!(f, "real"),
+            ColumnType::Text => write!(f, "text"),
+            ColumnType::Timestamp => write!(f, "timestamp"),
+            ColumnType::Bytes => write!(f, "bytes"),
+        }
+    }
 }
 
 impl fmt::Display for DataType {
@@ -59,6 +136,13 @@ impl fmt::Display for DataType {
                 }
             }
             DataType::Timestamp(ts) => write!(f, "{}", ts.format("%c")),
+            DataType::Bytes(ref bs) => {
+                write!(f, "x'")?;
+                for b in bs.iter() {
+                    write!(f, "{:02x}", b)?;
+                }
+                write!(f, "'")
+            }
         }
     }
 }
@@ -76,6 +160,7 @@ impl fmt::Debug for DataType {
                 write!(f, "TinyText({:?})", text)
             }
             DataType::Timestamp(ts) => write!(f, "Timestamp({:?})", ts),
+            DataType::Bytes(ref bs) => write!(f, "Bytes({:?})", bs),
             DataType::Real(..) => write!(f, "Real({})", self),
             DataType::Int(n) => write!(f, "Int({})", n),
             DataType::BigInt(n) => write!(f, "BigInt({})", n),
@@ -91,9 +176,74 @@ impl DataType {
     pub fn deep_clone(&self) -> Self {
         match *self {
             DataType::Text(ref cstr) => DataType::Text(ArcCStr::from(&**cstr)),
+            DataType::Bytes(ref bs) => DataType::Bytes(Arc::new(Vec::clone(bs))),
             ref dt => dt.clone(),
         }
     }
+
+    /// Checks if this value is `DataType::None` (i.e., SQL `NULL`).
+    pub fn is_none(&self) -> bool {
+        match *self {
+            DataType::None => true,
+            _ => false,
+        }
+    }
+
+    /// The length in bytes of a variable-length value (text or binary data).
+    ///
+    /// Returns `None` for values of fixed size, which includes `TinyText`.
+    pub fn value_size(&self) -> Option<usize> {
+        match *self {
+            DataType::Text(ref t) => Some(t.to_bytes().len()),
+            DataType::Bytes(ref bs) => Some(bs.len()),
+            _ => None,
+        }
+    }
+
+    /// Truncate a timestamp to the given granularity.
+    ///
+    /// Any value that is not a timestamp, including `DataType::None`, truncates to
+    /// `DataType::None`. Operators that truncate columns reject values of other types before they
+    /// get here.
+    pub fn truncate(&self, unit: TimeUnit) -> DataType {
+        match *self {
+            DataType::Timestamp(ts) => {
+                let ts = match unit {
+                    TimeUnit::Hour => ts.date().and_hms(ts.hour(), 0, 0),
+                    TimeUnit::Day => ts.date().and_hms(0, 0, 0),
+                };
+                DataType::Timestamp(ts)
+            }
+            _ => DataType::None,
+        }
+    }
+
+    /// Compare this value to `other` following SQL `NULL` semantics.
+    ///
+    /// If either value is `DataType::None`, the result of the comparison is unknown, and `None` is
+    /// returned. Otherwise, the values are compared using their regular ordering. Operators that
+    /// evaluate predicates (e.g., filters) should use this rather than `Ord`, as `Ord` considers
+    /// two `NULL`s to be equal.
+    pub fn sql_cmp(&self, other: &DataType) -> Option<Ordering> {
+        if self.is_none() || other.is_none() {
+            None
+        } else {
+            Some(self.cmp(other))
+        }
+    }
+
+    /// Position of this value's type in the total order across types: Ints, Reals, Text,
+    /// Timestamps, Bytes, None.
+    fn type_rank(&self) -> u8 {
+        match *self {
+            DataType::Int(..) | DataType::BigInt(..) => 0,
+            DataType::Real(..) => 1,
+            DataType::Text(..) | DataType::TinyText(..) => 2,
+            DataType::Timestamp(..) => 3,
+            DataType::Bytes(..) => 4,
+            DataType::None => 5,
+        }
+    }
 }
 
 impl PartialEq for DataType {
@@ -129,6 +279,7 @@ impl PartialEq for DataType {
             }
             (&DataType::Real(ai, af), &DataType::Real(bi, bf)) => ai == bi && af == bf,
             (&DataType::Timestamp(tsa), &DataType::Timestamp(tsb)) => tsa == tsb,
+            (&DataType::Bytes(ref a), &DataType::Bytes(ref b)) => a == b,
             (&DataType::None, &DataType::None) => true,
 
             _ => false,
@@ -166,14 +317,11 @@ impl Ord for DataType {
                 ai.cmp(bi).then_with(|| af.cmp(bf))
             }
             (&DataType::Timestamp(tsa), &DataType::Timestamp(ref tsb)) => tsa.cmp(tsb),
+            (&DataType::Bytes(ref a), &DataType::Bytes(ref b)) => a.cmp(b),
             (&DataType::None, &DataType::None) => Ordering::Equal,
 
-            // order Ints, Reals, Text, Timestamps, None
-            (&DataType::Int(..), _) | (&DataType::BigInt(..), _) => Ordering::Greater,
-            (&DataType::Real(..), _) => Ordering::Greater,
-            (&DataType::Text(..), _) | (&DataType::TinyText(..), _) => Ordering::Greater,
-            (&DataType::Timestamp(..), _) => Ordering::Greater,
-            (&DataType::None, _) => Ordering::Greater,
+            // values of different types order by type
+            _ => self.type_rank().cmp(&other.type_rank()),
         }
     }
 }
@@ -198,8 +346,98 @@ impl Hash for DataType {
                 t.hash(state)
             }
             DataType::Timestamp(ts) => ts.hash(state),
+            DataType::Bytes(ref bs) => bs.hash(state),
+        }
+    }
+}
+
+// FNV-1a, fed with a fixed, little-endian encoding of each value, so that row hashes are the same
+// across processes and architectures (unlike those produced through `Hash`).
+struct StableHasher(u64);
+
+impl StableHasher {
+    fn new() -> Self {
+        StableHasher(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= u64::from(b);
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        for i in 0..8 {
+            self.write(&[(n >> (8 * i)) as u8]);
         }
     }
+
+    fn write_value(&mut self, d: &DataType) {
+        // numbers and strings are tagged the same regardless of representation, as they compare
+        // equal regardless of representation.
+        match *d {
+            DataType::None => self.write(&[0]),
+            DataType::Int(..) | DataType::BigInt(..) => {
+                let n: i64 = d.into();
+                self.write(&[1]);
+                self.write_u64(n as u64);
+            }
+            DataType::Real(i, f) => {
+                self.write(&[2]);
+                self.write_u64(i as u64);
+                self.write_u64(i64::from(f) as u64);
+            }
+            DataType::Text(..) | DataType::TinyText(..) => {
+                let t: Cow<str> = d.into();
+                self.write(&[3]);
+                self.write_u64(t.len() as u64);
+                self.write(t.as_bytes());
+            }
+            DataType::Timestamp(ts) => {
+                self.write(&[4]);
+                self.write_u64(ts.timestamp() as u64);
+                self.write_u64(u64::from(ts.timestamp_subsec_nanos()));
+            }
+            DataType::Bytes(ref bs) => {
+                self.write(&[5]);
+                self.write_u64(bs.len() as u64);
+                self.write(&bs[..]);
+            }
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        // spread the bits around, so that summing row hashes doesn't let similar rows cancel out
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+/// Compute an order-independent checksum over a multiset of rows.
+///
+/// Two multisets of rows that are equal have the same checksum, no matter what order the rows are
+/// given in, so this can be used to cheaply compare materializations (see
+/// `ControllerHandle::checksum`). Rows that differ are very unlikely to have the same checksum,
+/// but it is not impossible. Checksums are stable across processes and architectures.
+///
+/// The checksum of the union of two multisets is the (wrapping) sum of their checksums.
+pub fn checksum<I, R>(rows: I) -> u64
+where
+    I: IntoIterator<Item = R>,
+    R: AsRef<[DataType]>,
+{
+    rows.into_iter().fold(0u64, |sum, row| {
+        let row = row.as_ref();
+        let mut h = StableHasher::new();
+        h.write_u64(row.len() as u64);
+        for d in row {
+            h.write_value(d);
+        }
+        sum.wrapping_add(h.finish())
+    })
 }
 
 impl From<i64> for DataType {
@@ -247,7 +485,7 @@ impl<'a> From<&'a Literal> for DataType {
             Literal::Integer(i) => i.into(),
             Literal::String(ref s) => s.as_str().into(),
             Literal::CurrentTimestamp => {
-                let ts = chrono::Local::now().naive_local();
+                let ts = chrono::Utc::now().naive_utc();
                 DataType::Timestamp(ts)
             }
             Literal::FixedPoint(ref r) => {
@@ -265,7 +503,7 @@ impl From<Literal> for DataType {
             Literal::Integer(i) => i.into(),
             Literal::String(s) => s.as_str().into(),
             Literal::CurrentTimestamp => {
-                let ts = chrono::Local::now().naive_local();
+                let ts = chrono::Utc::now().naive_utc();
                 DataType::Timestamp(ts)
             }
             Literal::FixedPoint(r) => DataType::Real(i64::from(r.integral), r.fractional as i32),
@@ -351,6 +589,70 @@ impl<'a> Into<f64> for &'a DataType {
     }
 }
 
+impl From<NaiveDateTime> for DataType {
+    fn from(ts: NaiveDateTime) -> Self {
+        DataType::Timestamp(ts)
+    }
+}
+
+impl From<DateTime<Utc>> for DataType {
+    fn from(ts: DateTime<Utc>) -> Self {
+        DataType::Timestamp(ts.naive_utc())
+    }
+}
+
+impl<'a> Into<NaiveDateTime> for &'a DataType {
+    fn into(self) -> NaiveDateTime {
+        match *self {
+            DataType::Timestamp(ts) => ts,
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Into<NaiveDateTime> for DataType {
+    fn into(self) -> NaiveDateTime {
+        (&self).into()
+    }
+}
+
+impl<'a> Into<DateTime<Utc>> for &'a DataType {
+    fn into(self) -> DateTime<Utc> {
+        let ts: NaiveDateTime = self.into();
+        DateTime::from_utc(ts, Utc)
+    }
+}
+
+impl From<Vec<u8>> for DataType {
+    fn from(bs: Vec<u8>) -> Self {
+        DataType::Bytes(Arc::new(bs))
+    }
+}
+
+impl<'a> From<&'a [u8]> for DataType {
+    fn from(bs: &'a [u8]) -> Self {
+        DataType::Bytes(Arc::new(bs.to_vec()))
+    }
+}
+
+impl<'a> Into<&'a [u8]> for &'a DataType {
+    fn into(self) -> &'a [u8] {
+        match *self {
+            DataType::Bytes(ref bs) => &bs[..],
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Into<Vec<u8>> for DataType {
+    fn into(self) -> Vec<u8> {
+        match self {
+            DataType::Bytes(bs) => Arc::try_unwrap(bs).unwrap_or_else(|bs| Vec::clone(&bs)),
+            _ => unreachable!(),
+        }
+    }
+}
+
 impl From<String> for DataType {
     fn from(s: String) -> Self {
         let len = s.as_bytes().len();
@@ -481,6 +783,25 @@ pub enum TableOperation {
         /// The key used to identify the row to update.
         key: Vec<DataType>,
     },
+    /// Insert the contained row after replacing the values of the `defaulted` columns with those
+    /// columns' default values.
+    InsertDefaulted {
+        /// The row to insert.
+        row: Vec<DataType>,
+        /// The columns of `row` whose values should be replaced with their defaults.
+        defaulted: Vec<usize>,
+    },
+    /// Set some of the columns of the existing row with the given `key`, and leave the rest as
+    /// they are. Unlike `Update`, only the columns that change are sent to the base table, which
+    /// turns the modification into a retraction of the old row and an insertion of the new one.
+    ///
+    /// New operations go after this one, so that logged operations still decode as they did.
+    Modify {
+        /// The key used to identify the row to modify.
+        key: Vec<DataType>,
+        /// The new values of the modified columns, by column index.
+        set: Vec<(usize, DataType)>,
+    },
 }
 
 impl TableOperation {
@@ -489,6 +810,7 @@ impl TableOperation {
         match *self {
             TableOperation::Insert(ref r) => Some(r),
             TableOperation::InsertOrUpdate { ref row, .. } => Some(row),
+            TableOperation::InsertDefaulted { ref row, .. } => Some(row),
             _ => None,
         }
     }
@@ -507,6 +829,49 @@ pub type Datas = Vec<Vec<DataType>>;
 mod tests {
     use super::*;
 
+    #[test]
+    fn checksum_ignores_order_and_representation() {
+        let rows: Vec<Vec<DataType>> = vec![
+            vec![1.into(), "a".into()],
+            vec![DataType::BigInt(2), DataType::None],
+            vec![(-3).into(), "hello".into()],
+        ];
+        let reordered: Vec<Vec<DataType>> = vec![
+            vec![(-3).into(), "hello".into()],
+            vec![DataType::BigInt(1), "a".into()],
+            vec![2.into(), DataType::None],
+        ];
+        assert_eq!(checksum(&rows), checksum(&reordered));
+
+        // the checksum must not change between processes or architectures
+        assert_eq!(checksum(&rows), 0xff88_5749_5cba_38ef);
+
+        // it is over a multiset, so duplicates count
+        assert_ne!(checksum(&rows), checksum(&rows[1..]));
+        assert_ne!(
+            checksum(&rows[1..]),
+            checksum(rows.iter().chain(&rows[1..]))
+        );
+        assert_eq!(
+            checksum(rows.iter().chain(&rows[1..])),
+            checksum(&rows).wrapping_add(checksum(&rows[1..]))
+        );
+        assert_eq!(checksum(Vec::<Vec<DataType>>::new()), 0);
+
+        // values don't run into each other
+        assert_ne!(
+            checksum(vec![vec![DataType::None, 1.into()]]),
+            checksum(vec![vec![1.into(), DataType::None]])
+        );
+    }
+
+    #[test]
+    fn it_fits_in_sixteen_bytes() {
+        // every variant is at most 15 bytes next to its tag, and the wide ones are reference
+        // counted, so rows cost 16 bytes per value
+        assert_eq!(::std::mem::size_of::<DataType>(), 16);
+    }
+
     #[test]
     fn real_to_string() {
         let a: DataType = (2.5).into();
@@ -577,6 +942,124 @@ mod tests {
         let _ = &a + &b;
     }
 
+    #[test]
+    fn null_semantics() {
+        let null = DataType::None;
+        let one = DataType::from(1);
+        let text = DataType::from("a");
+
+        // NULLs group together
+        assert_eq!(null, DataType::None);
+        assert_eq!(null.cmp(&DataType::None), Ordering::Equal);
+
+        // ... and order after everything else, regardless of which side they're on
+        assert_eq!(null.cmp(&one), Ordering::Greater);
+        assert_eq!(one.cmp(&null), Ordering::Less);
+        assert_eq!(null.cmp(&text), Ordering::Greater);
+        assert_eq!(text.cmp(&null), Ordering::Less);
+
+        // but any SQL comparison involving NULL is unknown
+        assert_eq!(null.sql_cmp(&DataType::None), None);
+        assert_eq!(null.sql_cmp(&one), None);
+        assert_eq!(one.sql_cmp(&null), None);
+        assert_eq!(one.sql_cmp(&one), Some(Ordering::Equal));
+        assert_eq!(one.sql_cmp(&2.into()), Some(Ordering::Less));
+
+        assert!(null.is_none());
+        assert!(!one.is_none());
+        assert_eq!(format!("{:?}", null), "None");
+    }
+
+    #[test]
+    fn mixed_type_ordering() {
+        use chrono::NaiveDate;
+
+        // one value of each type, in the order they should sort in
+        let ordered: Vec<DataType> = vec![
+            DataType::Int(-5),
+            DataType::BigInt(7),
+            DataType::Real(-1, 500_000_000),
+            DataType::from("a"),
+            DataType::from("this is a long string"),
+            DataType::Timestamp(NaiveDate::from_ymd(2018, 1, 1).and_hms(9, 0, 0)),
+            DataType::Bytes(Arc::new(vec![1, 2, 3])),
+            DataType::None,
+        ];
+
+        for (i, a) in ordered.iter().enumerate() {
+            for (j, b) in ordered.iter().enumerate() {
+                assert_eq!(a.cmp(b), i.cmp(&j), "{:?} vs {:?}", a, b);
+            }
+        }
+
+        // ints of either width are compared by value
+        assert!(DataType::BigInt(-10) < DataType::Int(-5));
+        assert!(DataType::Int(3) < DataType::BigInt(7));
+    }
+
+    #[test]
+    fn timestamps() {
+        use chrono::NaiveDate;
+
+        let ts = NaiveDate::from_ymd(2018, 10, 1).and_hms_nano(13, 37, 42, 1);
+        let dt: DataType = ts.into();
+        assert_eq!(dt, DataType::Timestamp(ts));
+
+        // round-trips through the chrono types
+        let back: NaiveDateTime = (&dt).into();
+        assert_eq!(back, ts);
+        let utc: DateTime<Utc> = (&dt).into();
+        assert_eq!(DataType::from(utc), dt);
+
+        // timestamps are ordered chronologically
+        let later: DataType = NaiveDate::from_ymd(2018, 10, 2).and_hms(0, 0, 0).into();
+        assert!(dt < later);
+        assert_eq!(dt.sql_cmp(&later), Some(Ordering::Less));
+
+        // and can be truncated
+        assert_eq!(
+            dt.truncate(TimeUnit::Hour),
+            NaiveDate::from_ymd(2018, 10, 1).and_hms(13, 0, 0).into()
+        );
+        assert_eq!(
+            dt.truncate(TimeUnit::Day),
+            NaiveDate::from_ymd(2018, 10, 1).and_hms(0, 0, 0).into()
+        );
+        assert_eq!(DataType::None.truncate(TimeUnit::Day), DataType::None);
+        assert_eq!(DataType::from(1).truncate(TimeUnit::Hour), DataType::None);
+    }
+
+    #[test]
+    fn bytes() {
+        let hash = |dt: &DataType| {
+            use std::collections::hash_map::DefaultHasher;
+            let mut s = DefaultHasher::new();
+            dt.hash(&mut s);
+            s.finish()
+        };
+
+        let a: DataType = vec![0xde, 0xad].into();
+        let b: DataType = (&[0xde, 0xad][..]).into();
+        let c: DataType = vec![0xbe, 0xef].into();
+
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert_eq!(hash(&a), hash(&b));
+        assert_ne!(hash(&a), hash(&c));
+        assert_eq!(a.deep_clone(), a);
+
+        // binary values are never confused with strings
+        assert_ne!(a, DataType::from("\u{de}\u{ad}"));
+
+        assert_eq!(format!("{}", a), "x'dead'");
+        assert_eq!(format!("{:?}", c), "Bytes([190, 239])");
+
+        let bs: &[u8] = (&a).into();
+        assert_eq!(bs, &[0xde, 0xad]);
+        let bs: Vec<u8> = c.into();
+        assert_eq!(bs, vec![0xbe, 0xef]);
+    }
+
     #[test]
     fn data_type_debug() {
         let tiny_text: DataType = "hi".into();
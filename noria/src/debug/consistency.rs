@@ -0,0 +1,78 @@
+use crate::data::DataType;
+use petgraph::graph::NodeIndex;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// How many of a divergence's rows are shown when it is displayed.
+const SHOWN_ROWS: usize = 5;
+
+/// What was found by recomputing every view from the rows in the base tables, and comparing the
+/// result to the rows that are materialized.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ConsistencyReport {
+    /// The materialized nodes whose rows were compared to what their operators compute.
+    pub checked: Vec<NodeIndex>,
+    /// The materialized nodes whose rows could not be recomputed, and why.
+    pub unchecked: Vec<(NodeIndex, String)>,
+    /// The materialized nodes whose rows differ from what their operators compute.
+    pub divergences: Vec<Divergence>,
+}
+
+impl ConsistencyReport {
+    /// Whether every node that was checked holds the rows it should.
+    pub fn is_consistent(&self) -> bool {
+        self.divergences.is_empty()
+    }
+}
+
+impl fmt::Display for ConsistencyReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} nodes checked, {} not checked, {} diverged",
+            self.checked.len(),
+            self.unchecked.len(),
+            self.divergences.len()
+        )?;
+        for d in &self.divergences {
+            write!(f, "\n  {}", d)?;
+        }
+        Ok(())
+    }
+}
+
+/// A materialized node that holds other rows than its operator computes.
+///
+/// Rows are listed once for every time that they are missing or extra.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Divergence {
+    /// The node's index in the graph.
+    pub node: NodeIndex,
+    /// The node's name.
+    pub name: String,
+    /// The nodes that the expected rows were computed with, in topological order: the
+    /// materialized nodes whose rows were used, then the nodes whose operators computed from them,
+    /// ending with `node`.
+    pub path: Vec<NodeIndex>,
+    /// Rows that the node should hold, but doesn't.
+    pub missing: Vec<Vec<DataType>>,
+    /// Rows that the node holds, but shouldn't.
+    pub extra: Vec<Vec<DataType>>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let path: Vec<_> = self.path.iter().map(|n| n.index().to_string()).collect();
+        write!(
+            f,
+            "node {} ({}), computed along {}, is missing {} rows {:?} and has {} extra rows {:?}",
+            self.node.index(),
+            self.name,
+            path.join(" -> "),
+            self.missing.len(),
+            &self.missing[..self.missing.len().min(SHOWN_ROWS)],
+            self.extra.len(),
+            &self.extra[..self.extra.len().min(SHOWN_ROWS)],
+        )
+    }
+}
@@ -0,0 +1,81 @@
+use crate::internal::DomainIndex;
+use crate::DataType;
+use petgraph::graph::NodeIndex;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::SystemTime;
+
+/// Where a packet that could not be delivered was headed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Destination {
+    /// A shard of a domain, which may be the sending domain itself.
+    Domain(DomainIndex, usize),
+    /// A client that wrote to a base table, and was owed an acknowledgement.
+    Client,
+    /// A node of the sending domain, whose operator could not process a record that the node
+    /// `from` sent it.
+    Node {
+        /// The node whose operator could not process the record.
+        node: NodeIndex,
+        /// The node that sent the record.
+        from: NodeIndex,
+    },
+}
+
+/// A packet that a shard of a domain could not deliver, because the receiving end had gone away, or
+/// a record that an operator could not process.
+///
+/// Only a summary of a packet is kept, not the records it carried.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DeadLetter {
+    /// The domain that sent the packet.
+    pub domain: DomainIndex,
+    /// The shard of the domain that sent the packet.
+    pub shard: usize,
+    /// Where the packet was headed.
+    pub to: Destination,
+    /// The kind of packet, or `Ack` for an acknowledgement of a write, or `Positive` or `Negative`
+    /// for a record that an operator could not process.
+    pub kind: String,
+    /// The number of records the packet carried.
+    pub records: usize,
+    /// When the packet was found to be undeliverable.
+    pub at: SystemTime,
+    /// Why the packet could not be delivered.
+    pub error: String,
+    /// The record that an operator could not process, if that is what the letter is for.
+    #[serde(default)]
+    pub record: Option<Vec<DataType>>,
+}
+
+impl fmt::Display for DeadLetter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Destination::Node { node, from } = self.to {
+            return write!(
+                f,
+                "domain {}.{} could not process a {} record at node {}, sent by node {}: {}",
+                self.domain.index(),
+                self.shard,
+                self.kind.to_lowercase(),
+                node.index(),
+                from.index(),
+                self.error
+            );
+        }
+
+        write!(
+            f,
+            "domain {}.{} could not deliver {} with {} records to ",
+            self.domain.index(),
+            self.shard,
+            self.kind,
+            self.records
+        )?;
+        match self.to {
+            Destination::Domain(d, shard) => write!(f, "domain {}.{}", d.index(), shard)?,
+            Destination::Client => write!(f, "a client")?,
+            Destination::Node { .. } => unreachable!(),
+        }
+        write!(f, ": {}", self.error)
+    }
+}
@@ -0,0 +1,84 @@
+use crate::internal::DomainIndex;
+use petgraph::graph::NodeIndex;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::Duration;
+
+/// When a domain is considered to be the bottleneck of the graph.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SlowDomainThresholds {
+    /// The average number of packets waiting for a domain, at or above which its input is
+    /// considered to be near capacity. If writes are throttled (see `Backpressure`), this is best
+    /// kept somewhat below the number of waiting packets at which a domain becomes overloaded.
+    pub min_queued: f64,
+    /// The largest share of its time that a domain may spend sending to other domains and still be
+    /// considered the bottleneck. A domain that spends more than that waiting on its sends is held
+    /// up by the domains it sends to, rather than being slow itself.
+    pub max_send_share: f64,
+}
+
+impl Default for SlowDomainThresholds {
+    fn default() -> Self {
+        SlowDomainThresholds {
+            min_queued: 64.0,
+            max_send_share: 0.1,
+        }
+    }
+}
+
+/// The node of a domain that took the most time to process what it was given.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SlowNode {
+    /// The node's index in the graph.
+    pub node: NodeIndex,
+    /// The node's name.
+    pub name: String,
+    /// The time the node spent processing.
+    pub process_time: Duration,
+}
+
+/// A shard of a domain that packets kept piling up at, even though it was not held up sending to
+/// the domains below it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SlowDomain {
+    /// The domain.
+    pub domain: DomainIndex,
+    /// The shard of the domain.
+    pub shard: usize,
+    /// How long the domain was watched for.
+    pub window: Duration,
+    /// The average number of packets waiting for the domain.
+    pub queued: f64,
+    /// The share of the time that the domain spent sending to other domains.
+    pub send_share: f64,
+    /// The share of the time that the domain's nodes spent processing.
+    pub process_share: f64,
+    /// The node of the domain that spent the most time processing, if any did.
+    pub slowest: Option<SlowNode>,
+}
+
+impl fmt::Display for SlowDomain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "domain {}.{} had {:.1} packets waiting on average over {:?}, spent {:.0}% of the time \
+             processing and {:.0}% sending",
+            self.domain.index(),
+            self.shard,
+            self.queued,
+            self.window,
+            self.process_share * 100.0,
+            self.send_share * 100.0,
+        )?;
+        if let Some(ref n) = self.slowest {
+            write!(
+                f,
+                ", most of it in node {} ({}), which took {:?}",
+                n.node.index(),
+                n.name,
+                n.process_time
+            )?;
+        }
+        Ok(())
+    }
+}
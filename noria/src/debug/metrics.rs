@@ -0,0 +1,192 @@
+use std::cmp;
+use std::fmt;
+
+/// The current value of one metric, for one domain shard, node, or reader.
+///
+/// Every metric is a counter that only goes up for as long as the thing it is about exists. Times
+/// are in nanoseconds.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sample {
+    /// The name of the metric, like `noria_node_records_in`.
+    pub name: String,
+    /// What the value is about, like the domain and node it is for.
+    pub labels: Vec<(String, String)>,
+    /// The value of the counter.
+    pub value: u64,
+}
+
+impl Sample {
+    /// The value of the label with the given name, if the sample has one.
+    pub fn label(&self, name: &str) -> Option<&str> {
+        self.labels
+            .iter()
+            .find(|&&(ref l, _)| l == name)
+            .map(|&(_, ref v)| &v[..])
+    }
+
+    fn matches(&self, name: &str, labels: &[(&str, &str)]) -> bool {
+        self.name == name && labels.iter().all(|&(l, v)| self.label(l) == Some(v))
+    }
+}
+
+/// The metrics of a set of domains, and of their nodes and readers, at some point in time.
+///
+/// The samples are flat, and each carries its own labels, so that they can be handed to an
+/// exporter as they are. The `Display` implementation writes them out in the Prometheus text
+/// exposition format.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct MetricsSnapshot {
+    /// Every sample, grouped by metric.
+    pub samples: Vec<Sample>,
+}
+
+impl MetricsSnapshot {
+    /// The value of the metric with the given name, added up across the samples that have all the
+    /// given labels. This is 0 if no samples match.
+    pub fn sum(&self, name: &str, labels: &[(&str, &str)]) -> u64 {
+        self.samples
+            .iter()
+            .filter(|s| s.matches(name, labels))
+            .map(|s| s.value)
+            .sum()
+    }
+
+    /// Whether there are any samples of the metric with the given name and all the given labels.
+    pub fn has(&self, name: &str, labels: &[(&str, &str)]) -> bool {
+        self.samples.iter().any(|s| s.matches(name, labels))
+    }
+
+    /// The `q`-quantile, such as 0.99, of the histogram `name`, over the samples that have all the
+    /// given labels. The histogram's buckets are the samples of `name_bucket`, and the quantile is
+    /// the upper bound of the bucket that it falls into, as given by the `le` label. Buckets with
+    /// the same bound are added up across samples. This is `None` if nothing has been counted.
+    pub fn quantile(&self, name: &str, labels: &[(&str, &str)], q: f64) -> Option<f64> {
+        let name = format!("{}_bucket", name);
+        let mut buckets: Vec<(f64, u64)> = Vec::new();
+        for s in self.samples.iter().filter(|s| s.matches(&name, labels)) {
+            let le = match s.label("le") {
+                Some("+Inf") => std::f64::INFINITY,
+                Some(le) => match le.parse() {
+                    Ok(le) => le,
+                    Err(_) => continue,
+                },
+                None => continue,
+            };
+            match buckets.iter_mut().find(|b| b.0 == le) {
+                Some(b) => b.1 += s.value,
+                None => buckets.push((le, s.value)),
+            }
+        }
+        buckets.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+        // every bucket counts all that is counted in the buckets below it too
+        let total = buckets.last()?.1;
+        let rank = cmp::max((q * total as f64).ceil() as u64, 1);
+        buckets.iter().find(|b| b.1 >= rank).map(|b| b.0)
+    }
+
+    /// Add the samples of another snapshot, keeping the samples of each metric together.
+    pub fn extend(&mut self, other: MetricsSnapshot) {
+        self.samples.extend(other.samples);
+        // the sort is stable, so samples of the same metric stay in the order they were taken
+        self.samples.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+}
+
+impl fmt::Display for MetricsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut last = None;
+        for s in &self.samples {
+            if last != Some(&s.name) {
+                writeln!(f, "# TYPE {} counter", s.name)?;
+                last = Some(&s.name);
+            }
+            write!(f, "{}", s.name)?;
+            for (i, &(ref l, ref v)) in s.labels.iter().enumerate() {
+                let v = v
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace('\n', "\\n");
+                write!(f, "{}{}=\"{}\"", if i == 0 { "{" } else { "," }, l, v)?;
+            }
+            if !s.labels.is_empty() {
+                write!(f, "}}")?;
+            }
+            writeln!(f, " {}", s.value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(name: &str, labels: &[(&str, &str)], value: u64) -> Sample {
+        Sample {
+            name: name.to_owned(),
+            labels: labels
+                .iter()
+                .map(|&(l, v)| (l.to_owned(), v.to_owned()))
+                .collect(),
+            value,
+        }
+    }
+
+    #[test]
+    fn it_adds_up_matching_samples() {
+        let m = MetricsSnapshot {
+            samples: vec![
+                sample("calls", &[("node", "1"), ("shard", "0")], 3),
+                sample("calls", &[("node", "1"), ("shard", "1")], 4),
+                sample("calls", &[("node", "2"), ("shard", "0")], 5),
+                sample("time", &[("node", "1"), ("shard", "0")], 100),
+            ],
+        };
+        assert_eq!(m.sum("calls", &[("node", "1")]), 7);
+        assert_eq!(m.sum("calls", &[]), 12);
+        assert_eq!(m.sum("calls", &[("node", "3")]), 0);
+        assert!(m.has("time", &[("shard", "0")]));
+        assert!(!m.has("time", &[("shard", "1")]));
+    }
+
+    #[test]
+    fn it_finds_quantiles_of_histograms() {
+        let bucket = |shard, le, value| sample("t_bucket", &[("shard", shard), ("le", le)], value);
+        let m = MetricsSnapshot {
+            samples: vec![
+                bucket("0", "10", 90),
+                bucket("0", "100", 99),
+                bucket("0", "+Inf", 100),
+                bucket("1", "10", 0),
+                bucket("1", "100", 0),
+                bucket("1", "+Inf", 100),
+            ],
+        };
+        let inf = Some(std::f64::INFINITY);
+        assert_eq!(m.quantile("t", &[("shard", "0")], 0.5), Some(10.0));
+        assert_eq!(m.quantile("t", &[("shard", "0")], 0.99), Some(100.0));
+        assert_eq!(m.quantile("t", &[("shard", "0")], 1.0), inf);
+        assert_eq!(m.quantile("t", &[("shard", "1")], 0.5), inf);
+        // across both shards, 90 of the 200 are within 10, and 99 within 100
+        assert_eq!(m.quantile("t", &[], 0.45), Some(10.0));
+        assert_eq!(m.quantile("t", &[], 0.5), inf);
+        assert_eq!(m.quantile("t", &[("shard", "2")], 0.5), None);
+        assert_eq!(m.quantile("u", &[], 0.5), None);
+    }
+
+    #[test]
+    fn it_writes_the_prometheus_format() {
+        let mut m = MetricsSnapshot {
+            samples: vec![sample("b", &[("name", "say \"hi\"")], 1)],
+        };
+        m.extend(MetricsSnapshot {
+            samples: vec![sample("a", &[], 2), sample("b", &[("name", "x")], 3)],
+        });
+        assert_eq!(
+            m.to_string(),
+            "# TYPE a counter\na 2\n\
+             # TYPE b counter\nb{name=\"say \\\"hi\\\"\"} 1\nb{name=\"x\"} 3\n"
+        );
+    }
+}
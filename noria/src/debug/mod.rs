@@ -1,5 +1,23 @@
+/// Types related to checking materialized views against the base tables.
+pub mod consistency;
+
+/// Types related to packets that domains could not deliver.
+pub mod dead_letters;
+
+/// Types related to finding the domains that hold up the rest of the graph.
+pub mod diagnosis;
+
+/// Types related to the counters that domains keep as they run.
+pub mod metrics;
+
+/// Types related to planning migrations.
+pub mod plan;
+
 /// Types related to graph statistics.
 pub mod stats;
 
+/// Types related to describing the dataflow graph.
+pub mod topology;
+
 /// Types related to operator tracing.
 pub mod trace;
@@ -0,0 +1,225 @@
+use crate::internal::DomainIndex;
+use petgraph::graph::NodeIndex;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// What committing a migration would do, as worked out by planning it without committing it.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct MigrationPlan {
+    /// The nodes the migration would add, including the ingress, egress, and sharding nodes it
+    /// needs, in the order they would be added.
+    pub nodes: Vec<PlannedNode>,
+    /// The nodes the migration was asked to remove. Ancestors that would be removed along with
+    /// them because nothing else uses them are not listed.
+    pub removed: Vec<NodeIndex>,
+    /// Existing nodes that the migration would use in place of equivalent new ones.
+    pub reused: Vec<NodeIndex>,
+    /// The state that would be created, or that would gain new indexes.
+    pub materializations: Vec<PlannedMaterialization>,
+    /// The replay paths that would be set up to fill new state.
+    pub replays: Vec<PlannedReplay>,
+    /// How the new nodes were assigned to domains.
+    #[serde(default)]
+    pub strategy: DomainStrategy,
+}
+
+/// How a migration assigns the nodes it adds to domains.
+///
+/// Bases, shard mergers, and nodes that the migration placed itself are assigned the same way by
+/// every strategy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DomainStrategy {
+    /// Each node goes in the domain of the first of its parents whose domain it can be in, or
+    /// else in the domain of one of its siblings. Domains grow without bound.
+    FirstParent,
+    /// The new nodes are grouped so that as few edges as possible go between groups, without
+    /// letting a group grow beyond `max_nodes` nodes. Each group then goes in the domain that the
+    /// most edges into it come from, if the domain has room for it, or else in a new domain.
+    ///
+    /// Readers do not count towards `max_nodes`, and always go in the group of the node they read.
+    FewestCuts {
+        /// The most nodes, not counting readers, that a domain may have.
+        max_nodes: usize,
+    },
+}
+
+impl Default for DomainStrategy {
+    fn default() -> Self {
+        DomainStrategy::FewestCuts { max_nodes: 256 }
+    }
+}
+
+impl fmt::Display for DomainStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DomainStrategy::FirstParent => write!(f, "first parent"),
+            DomainStrategy::FewestCuts { max_nodes } => {
+                write!(f, "fewest cuts, at most {} nodes per domain", max_nodes)
+            }
+        }
+    }
+}
+
+/// A node that a migration would add.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PlannedNode {
+    /// The node's index in the graph.
+    pub node: NodeIndex,
+    /// The node's name.
+    pub name: String,
+    /// A textual description of the node.
+    pub description: String,
+    /// The domain the node would be assigned to.
+    pub domain: DomainIndex,
+    /// Whether that domain would be created by the migration.
+    pub new_domain: bool,
+    /// Whether the node was put in that domain because the migration asked for it to be.
+    pub placed: bool,
+    /// The number of shards the node would be split into, if it is sharded.
+    pub shards: Option<usize>,
+}
+
+/// State that a migration would create, or add indexes to.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PlannedMaterialization {
+    /// The node that holds the state.
+    pub node: NodeIndex,
+    /// The node's name.
+    pub name: String,
+    /// Whether the node is added by the migration. If it isn't, `indexes` only lists the indexes
+    /// that the migration adds to the node's existing state.
+    pub new: bool,
+    /// Whether the state would be partially materialized.
+    pub partial: bool,
+    /// The columns of each index on the state.
+    pub indexes: Vec<Vec<usize>>,
+}
+
+/// A replay path that a migration would set up.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PlannedReplay {
+    /// The node whose state the replay fills.
+    pub target: NodeIndex,
+    /// The columns of the index the replay fills.
+    pub index: Vec<usize>,
+    /// Whether the replay is for partial state. Such replays only happen when a read misses,
+    /// rather than when the migration is committed.
+    pub partial: bool,
+    /// The nodes the replay goes through, starting at the node whose state is replayed.
+    pub path: Vec<NodeIndex>,
+    /// The domains the replay goes through, in order.
+    pub domains: Vec<DomainIndex>,
+    /// The number of rows the replay would send when the migration is committed: the number of
+    /// rows in the state it starts from for full replays, and 0 for partial ones. `None` if the
+    /// size of that state isn't known.
+    pub estimated_rows: Option<usize>,
+}
+
+impl MigrationPlan {
+    /// The planned node with the given name, if there is one.
+    pub fn node(&self, name: &str) -> Option<&PlannedNode> {
+        self.nodes.iter().find(|n| n.name == name)
+    }
+
+    /// The planned materialization of the given node, if there is one.
+    pub fn materialization(&self, node: NodeIndex) -> Option<&PlannedMaterialization> {
+        self.materializations.iter().find(|m| m.node == node)
+    }
+
+    /// The domains that the migration would create.
+    pub fn new_domains(&self) -> Vec<DomainIndex> {
+        let mut domains: Vec<_> = self
+            .nodes
+            .iter()
+            .filter(|n| n.new_domain)
+            .map(|n| n.domain)
+            .collect();
+        domains.sort();
+        domains.dedup();
+        domains
+    }
+
+    /// The estimated number of rows that would be replayed when the migration is committed,
+    /// counting only the replays whose size is known.
+    pub fn estimated_replay_rows(&self) -> usize {
+        self.replays.iter().filter_map(|r| r.estimated_rows).sum()
+    }
+}
+
+impl fmt::Display for MigrationPlan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} new nodes in {} new domains, {} removed, {} reused",
+            self.nodes.len(),
+            self.new_domains().len(),
+            self.removed.len(),
+            self.reused.len()
+        )?;
+        writeln!(f, "domains chosen by {}", self.strategy)?;
+        for n in &self.nodes {
+            write!(
+                f,
+                "  + n{} {} [{}] in domain {}",
+                n.node.index(),
+                n.name,
+                n.description,
+                n.domain.index()
+            )?;
+            if n.new_domain {
+                write!(f, " (new)")?;
+            }
+            if n.placed {
+                write!(f, " (as asked)")?;
+            }
+            if let Some(shards) = n.shards {
+                write!(f, ", {} shards", shards)?;
+            }
+            writeln!(f)?;
+        }
+        for n in &self.removed {
+            writeln!(f, "  - n{}", n.index())?;
+        }
+        for n in &self.reused {
+            writeln!(f, "  = n{} (reused)", n.index())?;
+        }
+
+        writeln!(f, "{} materializations", self.materializations.len())?;
+        for m in &self.materializations {
+            writeln!(
+                f,
+                "  {} n{} {}: {} on {:?}",
+                if m.new { "new" } else { "existing" },
+                m.node.index(),
+                m.name,
+                if m.partial { "partial" } else { "full" },
+                m.indexes
+            )?;
+        }
+
+        writeln!(
+            f,
+            "{} replay paths, ~{} rows",
+            self.replays.len(),
+            self.estimated_replay_rows()
+        )?;
+        for r in &self.replays {
+            let path: Vec<_> = r.path.iter().map(|n| format!("n{}", n.index())).collect();
+            let domains: Vec<_> = r.domains.iter().map(|d| d.index()).collect();
+            write!(
+                f,
+                "  {} n{} on {:?}: {} through domains {:?}",
+                if r.partial { "partial" } else { "full" },
+                r.target.index(),
+                r.index,
+                path.join(" -> "),
+                domains
+            )?;
+            match r.estimated_rows {
+                Some(rows) => writeln!(f, ", ~{} rows", rows)?,
+                None => writeln!(f, ", unknown size")?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,15 +1,19 @@
+use crate::debug::topology::NodeKind;
 use crate::internal::*;
 use crate::MaterializationStatus;
 use petgraph::graph::NodeIndex;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::ops::AddAssign;
 
 type DomainMap = HashMap<(DomainIndex, usize), (DomainStats, HashMap<NodeIndex, NodeStats>)>;
 
 /// Statistics about a domain.
 ///
 /// All times are in nanoseconds.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct DomainStats {
     /// Total wall-clock time elapsed while processing in this domain.
     pub total_time: u64,
@@ -17,32 +21,646 @@ pub struct DomainStats {
     pub total_ptime: u64,
     /// Total wall-clock time spent waiting for work in this domain.
     pub wait_time: u64,
+    /// Number of packets that the domain has taken in but not yet processed: writes waiting to be
+    /// persisted, updates held back while a replay passes through, and packets the domain has
+    /// queued for itself.
+    #[serde(default)]
+    pub queued_packets: u64,
+    /// Number of updates that the domain's egress nodes sent as part of an earlier update to the
+    /// same downstream domain, rather than on their own.
+    #[serde(default)]
+    pub coalesced_packets: u64,
+    /// The connections that the domain sends and receives packets over, and how large their
+    /// buffers are.
+    #[serde(default)]
+    pub channels: DomainChannels,
+    /// Where the wall-clock time of the task that runs the domain has gone.
+    #[serde(default)]
+    pub time_budget: TimeBudgetStats,
+    /// Whether the domain has been paused, and so holds on to the packets that carry updates and
+    /// writes rather than processing them. Those count towards `queued_packets`.
+    #[serde(default)]
+    pub paused: bool,
+}
+
+/// The connections of one kind that a domain has open.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelStats {
+    /// The number of bytes that each of the connections buffers.
+    pub capacity: usize,
+    /// Number of such connections that are open.
+    pub connections: usize,
+}
+
+/// The connections that a domain sends and receives packets over, by what they are used for.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DomainChannels {
+    /// The connection over which the domain answers the controller.
+    pub control: ChannelStats,
+    /// Connections over which other domains send packets to the domain.
+    pub domain: ChannelStats,
+    /// Connections over which clients write to the domain's bases.
+    pub input: ChannelStats,
+    /// Connections over which the domain asks domains run by other workers to replay state.
+    pub replay: ChannelStats,
+}
+
+/// Time spent on one part of what a domain does, in nanoseconds.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimeSpent {
+    /// The time spent since the domain started.
+    pub total: u64,
+    /// The time spent lately. Time spent further back counts for exponentially less: time spent
+    /// ten seconds ago counts for half as much as time spent just now.
+    pub recent: u64,
+}
+
+impl AddAssign for TimeSpent {
+    fn add_assign(&mut self, other: TimeSpent) {
+        self.total += other.total;
+        self.recent += other.recent;
+    }
+}
+
+/// Time a domain spent processing regular updates in one of its nodes.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeTime {
+    /// The node's index in the graph.
+    pub node: NodeIndex,
+    /// The time spent in the node.
+    pub time: TimeSpent,
+}
+
+/// Time a domain spent on the packets it sends to one shard of another domain.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SendTime {
+    /// The domain that was sent to.
+    pub domain: DomainIndex,
+    /// The shard of the domain that was sent to.
+    pub shard: usize,
+    /// Time spent handing packets to the connection to the domain shard.
+    pub sending: TimeSpent,
+    /// Time spent waiting with packets for the domain shard that it would not take yet, while
+    /// there was nothing else to do.
+    pub blocked: TimeSpent,
+}
+
+/// Where the wall-clock time of the task that runs a domain has gone. The buckets add up to the
+/// time since the domain started.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimeBudgetStats {
+    /// Time spent processing regular updates, by node.
+    pub nodes: Vec<NodeTime>,
+    /// Time spent processing anything else: replays, control packets, and the domain's own
+    /// bookkeeping.
+    pub other: TimeSpent,
+    /// Time spent sending, or blocked on sending, by the domain shard sent to.
+    pub sends: Vec<SendTime>,
+    /// Time spent waiting for work with nothing left to send.
+    pub idle: TimeSpent,
+}
+
+impl TimeBudgetStats {
+    /// The time spent processing, in nodes or otherwise.
+    pub fn processing(&self) -> TimeSpent {
+        let mut t = self.other;
+        for n in &self.nodes {
+            t += n.time;
+        }
+        t
+    }
+
+    /// The time spent handing packets to other domains, across all the domain shards sent to.
+    pub fn sending(&self) -> TimeSpent {
+        let mut t = TimeSpent::default();
+        for s in &self.sends {
+            t += s.sending;
+        }
+        t
+    }
+
+    /// The time spent blocked on sending, across all the domain shards sent to.
+    pub fn blocked(&self) -> TimeSpent {
+        let mut t = TimeSpent::default();
+        for s in &self.sends {
+            t += s.blocked;
+        }
+        t
+    }
+
+    /// The domain shard that the domain has lately been blocked on sending to the most, if it has
+    /// been blocked on any lately.
+    pub fn blocked_on(&self) -> Option<&SendTime> {
+        self.sends
+            .iter()
+            .filter(|s| s.blocked.recent != 0)
+            .max_by_key(|s| s.blocked.recent)
+    }
+
+    /// Add the time spent by another shard of the same domain.
+    pub fn merge(&mut self, other: &TimeBudgetStats) {
+        for n in &other.nodes {
+            match self.nodes.iter_mut().find(|m| m.node == n.node) {
+                Some(m) => m.time += n.time,
+                None => self.nodes.push(n.clone()),
+            }
+        }
+        self.other += other.other;
+        for s in &other.sends {
+            let to = (s.domain, s.shard);
+            match self.sends.iter_mut().find(|t| (t.domain, t.shard) == to) {
+                Some(t) => {
+                    t.sending += s.sending;
+                    t.blocked += s.blocked;
+                }
+                None => self.sends.push(s.clone()),
+            }
+        }
+        self.idle += other.idle;
+    }
 }
 
 /// Statistics about a node.
 ///
 /// All times are in nanoseconds.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct NodeStats {
     /// A textual description of this node.
     pub desc: String,
+    /// Number of records this node has processed as regular updates. Replayed records are not
+    /// counted.
+    #[serde(default)]
+    pub processed_records: u64,
     /// Total wall-clock time elapsed while processing in this node.
     pub process_time: u64,
     /// Total thread time elapsed while processing in this node.
     pub process_ptime: u64,
-    /// Total memory size of this node's state.
+    /// Total memory size of this node's state, including its auxiliary state.
     pub mem_size: u64,
+    /// Approximate heap size of the auxiliary state that this node's operator keeps apart from
+    /// its materialized rows, if it keeps any.
+    #[serde(default)]
+    pub aux_bytes: u64,
     /// The materialization type of this node's state.
     pub materialized: MaterializationStatus,
+    /// Number of keys evicted from this node's state because of memory pressure. Only tracked
+    /// for readers.
+    pub evicted_keys: u64,
+    /// Number of bytes evicted from this node's state because of memory pressure.
+    pub evicted_bytes: u64,
+    /// A breakdown of the size of this node's state, if it has any. Not available for readers.
+    pub state_size: Option<StateSizeStats>,
+    /// Number of times the output for a single key was produced piece by piece, because the key
+    /// matched too many records. Only tracked for joins.
+    pub spilled_keys: u64,
+    /// The most records this node has sent on for a single regular update, whether in one piece
+    /// or in several.
+    #[serde(default)]
+    pub peak_records_out: u64,
+    /// The most records this node has sent on for each record of a single regular update, rounded
+    /// down.
+    #[serde(default)]
+    pub peak_fanout: u64,
+    /// Number of records this node's operator could not process, and that were dropped or
+    /// dead-lettered instead.
+    #[serde(default)]
+    pub malformed_records: u64,
+}
+
+/// An estimate of how much memory a materialized state takes up.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateSizeStats {
+    /// Number of distinct rows in the state.
+    pub rows: usize,
+    /// Approximate heap size of those rows, in bytes. Rows that appear in several indexes are
+    /// only counted once.
+    pub bytes: u64,
+    /// The indexes that rows are stored under.
+    pub indexes: Vec<IndexSizeStats>,
+}
+
+impl StateSizeStats {
+    /// The total estimated size of the state, including the overhead of its indexes.
+    pub fn total_bytes(&self) -> u64 {
+        self.bytes + self.indexes.iter().map(|i| i.overhead).sum::<u64>()
+    }
+}
+
+/// An estimate of how much memory a single index of a materialized state takes up.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexSizeStats {
+    /// The columns the index is keyed on.
+    pub columns: Vec<usize>,
+    /// Number of keys in the index, or 0 if it is not known.
+    pub keys: usize,
+    /// Number of rows stored under those keys.
+    pub rows: usize,
+    /// Approximate number of bytes used by the index itself, not counting the rows it points to.
+    pub overhead: u64,
 }
 
 /// Statistics about the Soup data-flow.
+///
+/// Besides what each shard of each domain reported, the statistics are added up per node, per
+/// domain, and across the graph. Domains that did not answer in time are not waited for; their
+/// entries say so.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GraphStats {
     #[serde(serialize_with = "serialize_domainmap")]
     #[serde(deserialize_with = "deserialize_domainmap")]
     #[doc(hidden)]
     pub domains: DomainMap,
+    /// Every node in the graph, by index.
+    #[serde(default)]
+    pub nodes: Vec<NodeEntry>,
+    /// Every domain, by index.
+    #[serde(default)]
+    pub domain_entries: Vec<DomainEntry>,
+    /// Every domain shard that has failed, by domain and shard.
+    #[serde(default)]
+    pub failures: Vec<DomainFailure>,
+    /// The totals across all domains.
+    #[serde(default)]
+    pub totals: GraphTotals,
+}
+
+/// How current the statistics of a domain are.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Freshness {
+    /// Every shard of the domain answered.
+    Fresh,
+    /// Some shard of the domain did not answer in time, so these are the last statistics that
+    /// every shard answered with.
+    Stale,
+    /// Some shard of the domain did not answer in time, and it has never answered before, so
+    /// there are no statistics for the domain.
+    Unreachable,
+    /// Some shard of the domain has failed, so these are the last statistics that every shard
+    /// answered with before that, if any.
+    Failed,
+}
+
+/// Why a shard of a domain stopped processing.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DomainFailure {
+    /// The domain that failed.
+    pub domain: DomainIndex,
+    /// The shard of the domain that failed.
+    pub shard: usize,
+    /// What the domain panicked with, or the error it stopped on.
+    pub message: String,
+    /// Where the domain was when it panicked, if that could be captured.
+    pub backtrace: Option<String>,
+}
+
+impl fmt::Display for DomainFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "domain {}.{} failed: {}",
+            self.domain.index(),
+            self.shard,
+            self.message
+        )
+    }
+}
+
+/// How moving a domain to another worker went.
+///
+/// All times are in nanoseconds.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MoveStats {
+    /// The domain that was moved.
+    pub domain: DomainIndex,
+    /// The worker the domain was moved from.
+    pub from: SocketAddr,
+    /// The worker the domain was moved to.
+    pub to: SocketAddr,
+    /// The number of rows in the snapshot of the domain's state that was shipped to `to`.
+    pub snapshot_rows: usize,
+    /// The number of updates that the domain processed while its snapshot was being shipped, and
+    /// that its copy on `to` then caught up on.
+    pub logged_updates: usize,
+    /// How long the whole move took.
+    pub total: u64,
+    /// How long the domains that send updates to the domain were paused for at the end of the
+    /// move. Writes to the bases above them are held back for as long.
+    pub paused: u64,
+}
+
+impl fmt::Display for Freshness {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Freshness::Fresh => write!(f, "fresh"),
+            Freshness::Stale => write!(f, "stale"),
+            Freshness::Unreachable => write!(f, "unreachable"),
+            Freshness::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+/// The statistics of a node, added up across its shards.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NodeEntry {
+    /// The node's index in the graph.
+    pub node: NodeIndex,
+    /// The node's name.
+    pub name: String,
+    /// The name of the table that the node is, or of the view that the node is or is read
+    /// through.
+    pub public_name: Option<String>,
+    /// What kind of node it is.
+    pub kind: NodeKind,
+    /// A textual description of the node's operator, for operators.
+    pub operator: Option<String>,
+    /// The domain the node is in.
+    pub domain: DomainIndex,
+    /// How current the node's statistics are.
+    pub freshness: Freshness,
+    /// Number of records the node has processed as regular updates.
+    pub processed_records: u64,
+    /// Total wall-clock time spent processing in the node, in nanoseconds.
+    pub process_time: u64,
+    /// The most records any shard of the node has sent on for a single regular update.
+    #[serde(default)]
+    pub peak_records_out: u64,
+    /// The most records any shard of the node has sent on for each record of a single regular
+    /// update, rounded down.
+    #[serde(default)]
+    pub peak_fanout: u64,
+    /// Number of records the node's operator could not process, summed over its shards.
+    #[serde(default)]
+    pub malformed_records: u64,
+    /// The materialization type of the node's state.
+    pub materialized: MaterializationStatus,
+    /// Number of rows in the node's state. Not known for readers.
+    pub rows: Option<usize>,
+    /// Estimated size of the node's state, in bytes.
+    pub bytes: u64,
+}
+
+/// The statistics of a domain, added up across its shards.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DomainEntry {
+    /// The domain's index.
+    pub domain: DomainIndex,
+    /// The number of shards the domain has.
+    pub shards: usize,
+    /// How current the domain's statistics are.
+    pub freshness: Freshness,
+    /// Number of nodes in the domain.
+    pub nodes: usize,
+    /// Total wall-clock time spent processing in the domain, in nanoseconds.
+    pub total_time: u64,
+    /// Total wall-clock time spent waiting for work in the domain, in nanoseconds.
+    pub wait_time: u64,
+    /// Number of records that the domain's nodes have processed, summed across the nodes. A
+    /// record that passes through several nodes counts once for every node.
+    pub processed_records: u64,
+    /// Number of rows in the domain's materializations, not counting readers.
+    pub rows: usize,
+    /// Estimated size of the domain's materializations, in bytes.
+    pub bytes: u64,
+    /// Number of packets that the domain has taken in but not yet processed.
+    pub queued_packets: u64,
+    /// Number of updates that the domain sent as part of an earlier update to the same
+    /// downstream domain.
+    pub coalesced_packets: u64,
+    /// Where the wall-clock time of the tasks that run the domain's shards has gone, added up
+    /// across the shards.
+    #[serde(default)]
+    pub time_budget: TimeBudgetStats,
+}
+
+/// The statistics of the whole graph.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct GraphTotals {
+    /// Number of nodes in the graph.
+    pub nodes: usize,
+    /// Number of domains in the graph.
+    pub domains: usize,
+    /// Number of domains whose statistics are stale.
+    pub stale_domains: usize,
+    /// Number of domains that did not answer, and so are not counted in the other totals.
+    pub unreachable_domains: usize,
+    /// Number of domains that some shard of has failed.
+    #[serde(default)]
+    pub failed_domains: usize,
+    /// Number of records processed, summed across all nodes.
+    pub processed_records: u64,
+    /// Number of rows in all materializations, not counting readers.
+    pub rows: usize,
+    /// Estimated size of all materializations, in bytes.
+    pub bytes: u64,
+    /// Number of packets waiting to be processed across all domains.
+    pub queued_packets: u64,
+    /// Number of updates sent as part of an earlier update, across all domains.
+    #[serde(default)]
+    pub coalesced_packets: u64,
+}
+
+impl GraphStats {
+    /// Put together statistics from what the shards of each domain reported, and the per-node and
+    /// per-domain entries made from those reports, and the domain shards that have failed. The
+    /// totals are added up from the domain entries.
+    pub fn new(
+        domains: DomainMap,
+        nodes: Vec<NodeEntry>,
+        domain_entries: Vec<DomainEntry>,
+        failures: Vec<DomainFailure>,
+    ) -> Self {
+        let mut totals = GraphTotals {
+            nodes: nodes.len(),
+            domains: domain_entries.len(),
+            ..GraphTotals::default()
+        };
+        for d in &domain_entries {
+            match d.freshness {
+                Freshness::Fresh => {}
+                Freshness::Stale => totals.stale_domains += 1,
+                Freshness::Unreachable => totals.unreachable_domains += 1,
+                Freshness::Failed => totals.failed_domains += 1,
+            }
+            totals.processed_records += d.processed_records;
+            totals.rows += d.rows;
+            totals.bytes += d.bytes;
+            totals.queued_packets += d.queued_packets;
+            totals.coalesced_packets += d.coalesced_packets;
+        }
+
+        GraphStats {
+            domains,
+            nodes,
+            domain_entries,
+            failures,
+            totals,
+        }
+    }
+
+    /// The statistics of the given node.
+    pub fn node(&self, node: NodeIndex) -> Option<&NodeEntry> {
+        self.nodes.iter().find(|n| n.node == node)
+    }
+
+    /// The statistics of the given domain.
+    pub fn domain(&self, domain: DomainIndex) -> Option<&DomainEntry> {
+        self.domain_entries.iter().find(|d| d.domain == domain)
+    }
+
+    /// The nodes that have sent on more records for a single regular update than they were given,
+    /// the ones with the highest `peak_fanout` first.
+    pub fn amplifiers(&self) -> Vec<&NodeEntry> {
+        let mut amplifiers: Vec<_> = self.nodes.iter().filter(|n| n.peak_fanout > 1).collect();
+        amplifiers.sort_by(|a, b| {
+            b.peak_fanout
+                .cmp(&a.peak_fanout)
+                .then(b.peak_records_out.cmp(&a.peak_records_out))
+                .then(a.node.cmp(&b.node))
+        });
+        amplifiers
+    }
+
+    /// The nodes whose operators have been handed records that they could not process, the ones
+    /// that were handed the most first.
+    pub fn malformed(&self) -> Vec<&NodeEntry> {
+        let mut malformed: Vec<_> = self
+            .nodes
+            .iter()
+            .filter(|n| n.malformed_records != 0)
+            .collect();
+        malformed.sort_by(|a, b| {
+            b.malformed_records
+                .cmp(&a.malformed_records)
+                .then(a.node.cmp(&b.node))
+        });
+        malformed
+    }
+
+    /// The domains that have lately spent time blocked on sending to other domains, along with
+    /// the domain shard that each has been blocked on the most. The domains that have been blocked
+    /// the longest come first.
+    pub fn blocked_senders(&self) -> Vec<(&DomainEntry, &SendTime)> {
+        let mut blocked: Vec<_> = self
+            .domain_entries
+            .iter()
+            .filter_map(|d| d.time_budget.blocked_on().map(|on| (d, on)))
+            .collect();
+        blocked.sort_by(|&(a, _), &(b, _)| {
+            let recent = |d: &DomainEntry| d.time_budget.blocked().recent;
+            recent(b).cmp(&recent(a)).then(a.domain.cmp(&b.domain))
+        });
+        blocked
+    }
+}
+
+// how many nodes or domains each of the tables printed by `GraphStats`' `Display` lists
+const TOP_NODES: usize = 10;
+
+impl fmt::Display for GraphStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let t = &self.totals;
+        writeln!(
+            f,
+            "{} nodes in {} domains ({} stale, {} unreachable, {} failed)",
+            t.nodes, t.domains, t.stale_domains, t.unreachable_domains, t.failed_domains
+        )?;
+        writeln!(
+            f,
+            "{} records processed, {} rows in {} bytes materialized, {} packets queued, {} updates \
+             coalesced",
+            t.processed_records, t.rows, t.bytes, t.queued_packets, t.coalesced_packets
+        )?;
+
+        let describe = |n: &NodeEntry| {
+            let mut s = format!("n{} {}", n.node.index(), n.name);
+            if let Some(ref public) = n.public_name {
+                if public != &n.name {
+                    s.push_str(&format!(" ({})", public));
+                }
+            }
+            s.push_str(&format!(" in domain {}", n.domain.index()));
+            if n.freshness != Freshness::Fresh {
+                s.push_str(&format!(" [{}]", n.freshness));
+            }
+            s
+        };
+
+        let mut by_memory: Vec<_> = self
+            .nodes
+            .iter()
+            .filter(|n| n.materialized != MaterializationStatus::Not)
+            .collect();
+        by_memory.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(a.node.cmp(&b.node)));
+        writeln!(f, "top materializations by memory:")?;
+        for n in by_memory.into_iter().take(TOP_NODES) {
+            let rows = n
+                .rows
+                .map(|r| r.to_string())
+                .unwrap_or_else(|| "?".to_owned());
+            writeln!(
+                f,
+                "  {:>12} bytes {:>10} rows  {}",
+                n.bytes,
+                rows,
+                describe(n)
+            )?;
+        }
+
+        let mut by_throughput: Vec<_> = self
+            .nodes
+            .iter()
+            .filter(|n| n.processed_records != 0)
+            .collect();
+        by_throughput.sort_by(|a, b| {
+            b.processed_records
+                .cmp(&a.processed_records)
+                .then(a.node.cmp(&b.node))
+        });
+        writeln!(f, "top nodes by throughput:")?;
+        for n in by_throughput.into_iter().take(TOP_NODES) {
+            writeln!(f, "  {:>12} records  {}", n.processed_records, describe(n))?;
+        }
+
+        writeln!(f, "top nodes by fan-out:")?;
+        for n in self.amplifiers().into_iter().take(TOP_NODES) {
+            writeln!(
+                f,
+                "  {:>12}x {:>12} records at most  {}",
+                n.peak_fanout,
+                n.peak_records_out,
+                describe(n)
+            )?;
+        }
+
+        writeln!(f, "top nodes by malformed records:")?;
+        for n in self.malformed().into_iter().take(TOP_NODES) {
+            writeln!(f, "  {:>12} records  {}", n.malformed_records, describe(n))?;
+        }
+
+        writeln!(f, "top domains by time blocked sending:")?;
+        for (d, on) in self.blocked_senders().into_iter().take(TOP_NODES) {
+            let blocked = d.time_budget.blocked();
+            writeln!(
+                f,
+                "  {:>12} ns recently {:>14} ns in total  domain {}, mostly on domain {}.{}",
+                blocked.recent,
+                blocked.total,
+                d.domain.index(),
+                on.domain.index(),
+                on.shard
+            )?;
+        }
+
+        for d in &self.domain_entries {
+            if d.freshness != Freshness::Fresh {
+                writeln!(f, "domain {} is {}", d.domain.index(), d.freshness)?;
+            }
+        }
+        for failure in &self.failures {
+            writeln!(f, "{}", failure)?;
+        }
+        Ok(())
+    }
 }
 
 use std::ops::Deref;
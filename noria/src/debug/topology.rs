@@ -0,0 +1,114 @@
+use crate::internal::DomainIndex;
+use crate::ColumnSchema;
+use petgraph::graph::NodeIndex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+
+/// A description of the dataflow graph, and of where its domains run.
+///
+/// Descriptions only change when the graph does, and `version` tells whether it has: two
+/// descriptions with the same version describe the same graph, so clients can hold on to one
+/// until they see a new version.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TopologyDescription {
+    /// Bumped by every migration, and whenever nodes are removed from the graph.
+    pub version: u64,
+    /// The base tables, by name.
+    pub bases: BTreeMap<String, BaseDescription>,
+    /// The views that can be read, by name.
+    pub views: BTreeMap<String, ViewDescription>,
+    /// Every node in the graph that hasn't been removed, in the order they were added.
+    pub nodes: Vec<NodeDescription>,
+    /// Every domain, in order.
+    pub domains: Vec<DomainDescription>,
+}
+
+/// A base table.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BaseDescription {
+    /// The base node.
+    pub node: NodeIndex,
+    /// The names of the table's columns, including any that have since been dropped.
+    pub columns: Vec<String>,
+    /// The declared type of each column, if the table has a schema.
+    pub schema: Option<Vec<ColumnSchema>>,
+    /// The columns of the table's primary key, if it has one.
+    pub key: Option<Vec<usize>>,
+}
+
+/// A view that can be read.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ViewDescription {
+    /// The reader node that holds the view.
+    pub reader: NodeIndex,
+    /// The node whose output the view holds.
+    pub of: NodeIndex,
+    /// The names of the view's columns.
+    pub columns: Vec<String>,
+    /// The columns the view is keyed on, if it is materialized.
+    pub key: Option<Vec<usize>>,
+}
+
+/// What a node does in the graph.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NodeKind {
+    /// A base table, which takes in writes.
+    Base,
+    /// An operator that computes over the output of its parents.
+    Internal,
+    /// Receives updates from another domain.
+    Ingress,
+    /// Sends updates to other domains.
+    Egress,
+    /// Splits updates among the shards of its children.
+    Sharder,
+    /// Holds the output of a node so that it can be read.
+    Reader,
+}
+
+/// A node in the graph.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NodeDescription {
+    /// The node's index in the graph.
+    pub node: NodeIndex,
+    /// The node's name.
+    pub name: String,
+    /// What kind of node it is.
+    pub kind: NodeKind,
+    /// A textual description of the node's operator, such as `⋈` for a join.
+    pub operator: String,
+    /// The names of the node's columns.
+    pub columns: Vec<String>,
+    /// The nodes the node gets its input from. Bases have none.
+    pub parents: Vec<NodeIndex>,
+    /// The nodes that get their input from the node.
+    pub children: Vec<NodeIndex>,
+    /// The domain the node is in.
+    pub domain: DomainIndex,
+    /// The number of shards the node is split into, if it is sharded.
+    pub shards: Option<usize>,
+}
+
+/// A domain, and the workers it runs on.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DomainDescription {
+    /// The domain's index.
+    pub domain: DomainIndex,
+    /// The address of the worker that runs each of the domain's shards, in order.
+    pub workers: Vec<SocketAddr>,
+    /// The nodes in the domain.
+    pub nodes: Vec<NodeIndex>,
+}
+
+impl TopologyDescription {
+    /// The description of the given node, if it is in the graph.
+    pub fn node(&self, node: NodeIndex) -> Option<&NodeDescription> {
+        self.nodes.iter().find(|n| n.node == node)
+    }
+
+    /// The description of the given domain, if it exists.
+    pub fn domain(&self, domain: DomainIndex) -> Option<&DomainDescription> {
+        self.domains.iter().find(|d| d.domain == domain)
+    }
+}
@@ -0,0 +1,247 @@
+//! Conditions on the values of rows, as used both by filter operators in the data-flow graph and
+//! by `View`s that only return some of the rows of the view they read from.
+
+use crate::data::DataType;
+use std::cmp::Ordering;
+use std::fmt::{self, Display};
+
+pub use nom_sql::Operator;
+
+/// What the value in a column is compared against.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum Value {
+    /// A fixed value.
+    Constant(DataType),
+    /// The value in another column of the same row.
+    Column(usize),
+}
+
+impl From<DataType> for Value {
+    fn from(dt: DataType) -> Self {
+        Value::Constant(dt)
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Constant(ref c) => write!(f, "{}", c),
+            Value::Column(ref ci) => write!(f, "col: {}", ci),
+        }
+    }
+}
+
+/// A condition on the value in a single column of a row.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum FilterCondition {
+    /// The value compares to the given value as the operator says.
+    Comparison(Operator, Value),
+    /// The value is one of the given values.
+    In(Vec<DataType>),
+}
+
+impl FilterCondition {
+    /// Whether column `i` of the given row satisfies this condition.
+    ///
+    /// A condition on a column that the row does not have, or that uses an operator that values
+    /// cannot be compared with (see `RowFilter::check`), does not hold for any row.
+    pub fn holds(&self, r: &[DataType], i: usize) -> bool {
+        let d = match r.get(i) {
+            Some(d) => d,
+            None => return false,
+        };
+        match *self {
+            FilterCondition::Comparison(ref op, ref f) => {
+                let v = match *f {
+                    Value::Constant(ref dt) => dt,
+                    Value::Column(c) => match r.get(c) {
+                        Some(v) => v,
+                        None => return false,
+                    },
+                };
+                compare(op, d, v)
+            }
+            FilterCondition::In(ref fs) => !d.is_none() && fs.contains(d),
+        }
+    }
+
+    /// The operator of this condition, if it is one that values cannot be compared with.
+    fn unsupported_operator(&self) -> Option<&Operator> {
+        match *self {
+            FilterCondition::Comparison(ref op, _) if !comparable(op) => Some(op),
+            _ => None,
+        }
+    }
+
+    /// The column other than the one it is on that this condition reads, if any.
+    fn other_column(&self) -> Option<usize> {
+        match *self {
+            FilterCondition::Comparison(_, Value::Column(c)) => Some(c),
+            _ => None,
+        }
+    }
+}
+
+/// Whether `op` compares two values, and can thus be used in a `FilterCondition::Comparison`.
+fn comparable(op: &Operator) -> bool {
+    match *op {
+        Operator::Equal
+        | Operator::NotEqual
+        | Operator::Greater
+        | Operator::GreaterOrEqual
+        | Operator::Less
+        | Operator::LessOrEqual => true,
+        _ => false,
+    }
+}
+
+/// Evaluate `d <op> v` with SQL `NULL` semantics.
+///
+/// Any comparison involving `DataType::None` is unknown, and is therefore considered not to match.
+/// This also holds for `NULL = NULL` and `NULL != x`. Operators that do not compare two values
+/// never match either.
+fn compare(op: &Operator, d: &DataType, v: &DataType) -> bool {
+    let ord = match d.sql_cmp(v) {
+        Some(ord) => ord,
+        None => return false,
+    };
+
+    match *op {
+        Operator::Equal => ord == Ordering::Equal,
+        Operator::NotEqual => ord != Ordering::Equal,
+        Operator::Greater => ord == Ordering::Greater,
+        Operator::GreaterOrEqual => ord != Ordering::Less,
+        Operator::Less => ord == Ordering::Less,
+        Operator::LessOrEqual => ord != Ordering::Greater,
+        _ => false,
+    }
+}
+
+/// Why a `RowFilter` cannot be used to filter the rows of a view.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Fail)]
+pub enum FilterError {
+    /// The filter reads a column that the view does not have.
+    #[fail(display = "the view has no column {}", _0)]
+    NoSuchColumn(usize),
+    /// The filter compares values with an operator that does not compare two values.
+    #[fail(display = "values cannot be filtered with {}", _0)]
+    UnsupportedOperator(Operator),
+}
+
+/// A predicate over the rows of a view, which holds for a row if every one of its conditions holds
+/// for the column that the condition is on.
+///
+/// A `View` that is given a row filter (see `View::filtered`) sends it along with every read, and
+/// the rows that it does not hold for are dropped by the worker that serves the read. Such rows
+/// are thus never sent to the client, which lets one view serve many clients that may each only
+/// see some of its rows.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RowFilter {
+    conditions: Vec<(usize, FilterCondition)>,
+}
+
+impl RowFilter {
+    /// A filter that holds for the rows whose columns satisfy all of the given conditions. Each
+    /// condition is paired with the index of the column it is on.
+    pub fn new(conditions: Vec<(usize, FilterCondition)>) -> Self {
+        RowFilter { conditions }
+    }
+
+    /// A filter that holds for the rows whose column at index `column` is equal to `value`.
+    pub fn equal<V: Into<DataType>>(column: usize, value: V) -> Self {
+        RowFilter::new(vec![(
+            column,
+            FilterCondition::Comparison(Operator::Equal, Value::Constant(value.into())),
+        )])
+    }
+
+    /// A filter that holds for the rows that both this filter and `other` hold for.
+    pub fn and(mut self, other: RowFilter) -> Self {
+        self.conditions.extend(other.conditions);
+        self
+    }
+
+    /// The conditions of this filter, each with the index of the column it is on.
+    pub fn conditions(&self) -> &[(usize, FilterCondition)] {
+        &self.conditions[..]
+    }
+
+    /// The highest index of a column that this filter reads, if it reads any.
+    pub fn max_column(&self) -> Option<usize> {
+        self.conditions
+            .iter()
+            .flat_map(|&(i, ref cond)| Some(i).into_iter().chain(cond.other_column()))
+            .max()
+    }
+
+    /// Check that this filter can be used on the rows of a view with the given number of
+    /// columns, that is, that it only reads columns the view has, and only compares values with
+    /// operators that compare two values.
+    pub fn check(&self, columns: usize) -> Result<(), FilterError> {
+        if let Some(c) = self.max_column().filter(|&c| c >= columns) {
+            return Err(FilterError::NoSuchColumn(c));
+        }
+        match self
+            .conditions
+            .iter()
+            .filter_map(|&(_, ref cond)| cond.unsupported_operator())
+            .next()
+        {
+            Some(op) => Err(FilterError::UnsupportedOperator(op.clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether the given row passes this filter.
+    pub fn matches(&self, r: &[DataType]) -> bool {
+        self.conditions
+            .iter()
+            .all(|&(i, ref cond)| cond.holds(r, i))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_matches_rows_that_meet_every_condition() {
+        let f = RowFilter::equal(0, 1).and(RowFilter::new(vec![(
+            1,
+            FilterCondition::Comparison(Operator::Less, Value::Column(2)),
+        )]));
+        assert_eq!(f.max_column(), Some(2));
+        assert!(f.matches(&[1.into(), 2.into(), 3.into()]));
+        assert!(!f.matches(&[2.into(), 2.into(), 3.into()]));
+        assert!(!f.matches(&[1.into(), 3.into(), 3.into()]));
+        // NULL matches nothing, not even NULL
+        assert!(!f.matches(&[DataType::None, 2.into(), 3.into()]));
+        assert!(!RowFilter::equal(0, DataType::None).matches(&[DataType::None]));
+        assert_eq!(RowFilter::new(vec![]).max_column(), None);
+    }
+
+    #[test]
+    fn it_rejects_filters_it_cannot_evaluate() {
+        let like = RowFilter::new(vec![(
+            0,
+            FilterCondition::Comparison(Operator::Like, Value::Constant("a%".into())),
+        )]);
+        let other = RowFilter::new(vec![(
+            0,
+            FilterCondition::Comparison(Operator::Equal, Value::Column(3)),
+        )]);
+        assert_eq!(RowFilter::equal(1, 1).check(2), Ok(()));
+        assert_eq!(RowFilter::equal(2, 1).check(2), Err(FilterError::NoSuchColumn(2)));
+        assert_eq!(other.check(2), Err(FilterError::NoSuchColumn(3)));
+        assert_eq!(
+            like.check(2),
+            Err(FilterError::UnsupportedOperator(Operator::Like))
+        );
+
+        // even unchecked, they just don't match anything
+        let r: [DataType; 2] = ["abc".into(), 1.into()];
+        assert!(!like.matches(&r));
+        assert!(!other.matches(&r));
+        assert!(!RowFilter::equal(2, 1).matches(&r));
+    }
+}
@@ -11,6 +11,10 @@ impl<T> LocalBypass<T> {
         &*self.0
     }
 
+    pub unsafe fn deref_mut(&mut self) -> &mut T {
+        &mut *self.0
+    }
+
     pub unsafe fn take(self) -> Box<T> {
         Box::from_raw(self.0)
     }
@@ -53,6 +57,13 @@ impl<T> LocalOrNotInner<T> {
         }
     }
 
+    pub unsafe fn deref_mut(&mut self) -> &mut T {
+        match self {
+            LocalOrNotInner::Local(ref mut l) => l.deref_mut(),
+            LocalOrNotInner::Not(ref mut t) => t,
+        }
+    }
+
     pub unsafe fn take(self) -> T {
         match self {
             LocalOrNotInner::Local(l) => *l.take(),
@@ -103,6 +114,15 @@ impl<T> LocalOrNot<T> {
         self.0.deref()
     }
 
+    #[doc(hidden)]
+    #[cfg_attr(
+        feature = "cargo-clippy",
+        allow(clippy::should_implement_trait)
+    )]
+    pub unsafe fn deref_mut(&mut self) -> &mut T {
+        self.0.deref_mut()
+    }
+
     #[doc(hidden)]
     pub unsafe fn take(self) -> T {
         self.0.take()
@@ -126,6 +126,8 @@ pub mod consensus;
 #[doc(hidden)]
 pub mod internal;
 
+pub mod filter;
+
 pub use crate::consensus::ZookeeperAuthority;
 use crate::internal::*;
 
@@ -139,9 +141,38 @@ pub mod prelude {
 
 /// Noria errors.
 pub mod error {
-    pub use crate::table::TableError;
+    pub use crate::table::{TableError, WriteError};
     pub use crate::view::ViewError;
 
+    /// There is no table or view by the given name. It may never have existed, or it may have
+    /// been removed or renamed since.
+    #[derive(Debug, Fail)]
+    pub enum NotFound {
+        /// There is no table by this name.
+        #[fail(display = "no table named {}", _0)]
+        Table(String),
+        /// There is no view by this name.
+        #[fail(display = "no view named {}", _0)]
+        View(String),
+    }
+
+    /// Some domain shards had yet to process everything written before a quiesce when it timed out.
+    #[derive(Debug, Fail)]
+    pub struct NotQuiesced {
+        /// The domain shards that had yet to drain, by domain and shard index.
+        pub domains: Vec<(crate::internal::DomainIndex, usize)>,
+    }
+
+    impl std::fmt::Display for NotQuiesced {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "domain shards still had work in flight:")?;
+            for &(d, shard) in &self.domains {
+                write!(f, " {}.{}", d.index(), shard)?;
+            }
+            Ok(())
+        }
+    }
+
     /// An error occured during transport (i.e., while sending or receiving).
     #[derive(Debug, Fail)]
     pub enum TransportError {
@@ -167,15 +198,17 @@ pub mod error {
 }
 
 pub use crate::controller::{ControllerDescriptor, ControllerHandle, ControllerPointer};
-pub use crate::data::{DataType, Modification, Operation, TableOperation};
-pub use crate::table::Table;
-pub use crate::view::View;
+pub use crate::data::{
+    checksum, ColumnType, DataType, Modification, Operation, TableOperation, TimeUnit,
+};
+pub use crate::table::{ColumnDefault, ColumnSchema, Table};
+pub use crate::view::{Direction, ReadMeta, Scan, ScanToken, View};
 
 #[doc(hidden)]
 pub use crate::table::Input;
 
 #[doc(hidden)]
-pub use crate::view::{ReadQuery, ReadReply};
+pub use crate::view::{compare_rows, RangeBound, ReadQuery, ReadReply};
 
 #[doc(hidden)]
 pub mod builders {
@@ -224,6 +257,12 @@ pub fn shard_by(dt: &DataType, shards: usize) -> usize {
             hasher.write(s.as_bytes());
             hasher.finish() as usize % shards
         }
+        DataType::Bytes(ref bs) => {
+            use std::hash::Hasher;
+            let mut hasher = fnv::FnvHasher::default();
+            hasher.write(&bs[..]);
+            hasher.finish() as usize % shards
+        }
         // a bit hacky: send all NULL values to the first shard
         DataType::None => 0,
         ref x => {
@@ -1,23 +1,50 @@
-use crate::channel::{tcp, DomainConnectionBuilder, TcpSender};
+use crate::channel::{tcp, DomainConnectionBuilder, TcpSender, WriteAck};
 use crate::data::*;
 use crate::debug::trace::Tracer;
 use crate::error::TransportError;
 use crate::internal::*;
+use crate::view::View;
 use crate::{ExclusiveConnection, LocalOrNot, SharedConnection};
 use nom_sql::CreateTableStatement;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io;
+use std::mem;
 use std::net::SocketAddr;
 use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
 use vec_map::VecMap;
 
+// how long (in ms) a write that a base table was not ready for is sent again for before giving up,
+// and how long to wait between tries.
+const NOT_READY_TIMEOUT_MS: u64 = 10_000;
+const NOT_READY_RETRY_MS: u64 = 10;
+
 #[doc(hidden)]
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Input {
     pub dst: LocalNodeIndex,
     pub data: Vec<TableOperation>,
     pub tracer: Tracer,
+    /// Markers of the writes whose clients wait for them to be visible in a view (see
+    /// `Table::batch_insert_visible`).
+    #[serde(default)]
+    pub markers: Vec<u64>,
+}
+
+/// A marker for a write that, with overwhelming likelihood, no other write carries, whichever
+/// process it comes from.
+fn new_marker() -> u64 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // every `RandomState` is keyed differently, and the keys are random to begin with
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let mut h = RandomState::new().build_hasher();
+    h.write_usize(NEXT.fetch_add(1, Ordering::Relaxed));
+    h.finish()
 }
 
 /// A failed Table operation.
@@ -37,11 +64,159 @@ pub enum TableError {
         _1
     )]
     WrongKeyColumnCount(usize, usize),
+    /// A value was larger than the maximum value size of the table. Such writes are turned away
+    /// before they are sent to the base table.
+    #[fail(
+        display = "value for column {} is {} bytes, which exceeds the limit of {} bytes",
+        _0,
+        _1,
+        _2
+    )]
+    ValueTooLarge(String, usize, usize),
+    /// A write named a column that does not exist in the base table.
+    #[fail(display = "no column named {}", _0)]
+    UnknownColumn(String),
+    /// The base table is sharded, but has a key of more than one column, so it is not known which
+    /// shard a row belongs to.
+    #[fail(display = "writes to sharded tables with compound keys are not supported")]
+    CompoundShardKey,
+    /// The base table rejected the write.
+    #[fail(display = "write rejected: {}", _0)]
+    Rejected(#[cause] WriteError),
+    /// The write was not visible in the view it was to be waited for in before the timeout. It
+    /// may still become visible later.
+    #[fail(display = "write not visible in the view within {:?}", _0)]
+    NotVisible(Duration),
     /// The underlying connection to Soup produced an error.
     #[fail(display = "{}", _0)]
     TransportError(#[cause] TransportError),
 }
 
+/// A reason for a base table to reject a write.
+///
+/// Base tables that have a declared schema check every write they receive against it before the
+/// write is processed. If any row in a write violates the schema, the whole write is rejected.
+#[derive(Clone, Debug, PartialEq, Fail, Serialize, Deserialize)]
+pub enum WriteError {
+    /// A row had the wrong number of columns.
+    #[fail(
+        display = "wrong number of columns specified: expected {}, got {}",
+        _0,
+        _1
+    )]
+    WrongColumnCount(usize, usize),
+    /// A value did not have the type declared for its column.
+    #[fail(display = "column {} expects values of type {}, got {}", _0, _1, _2)]
+    WrongType(String, ColumnType, ColumnType),
+    /// A `NULL` value was given for a column that is not nullable.
+    #[fail(display = "column {} does not accept NULL values", _0)]
+    NotNullable(String),
+    /// A row was inserted with a key that is already present in a base table that does not allow
+    /// duplicate keys.
+    #[fail(display = "a row with key {:?} already exists", _0)]
+    DuplicateKey(Vec<DataType>),
+    /// A value was omitted for a column that has no default value.
+    #[fail(display = "column {} has no default value", _0)]
+    NoDefault(String),
+    /// A row was deleted or updated by a key that is not present in the base table.
+    #[fail(display = "no row with key {:?} exists", _0)]
+    KeyNotFound(Vec<DataType>),
+    /// A value was larger than the maximum value size configured for the base table.
+    #[fail(
+        display = "value for column {} is {} bytes, which exceeds the limit of {} bytes",
+        _0,
+        _1,
+        _2
+    )]
+    ValueTooLarge(String, usize, usize),
+    /// The domains below the base table could not keep up with the writes to it, so the write was
+    /// turned away without being applied. It can be retried once they have caught up.
+    #[fail(display = "the base table is overloaded")]
+    Overloaded,
+    /// The base table has yet to be set up by the migration that added it, so the write was turned
+    /// away without being applied. `Table` sends it again until the base is ready, or until it
+    /// gives up.
+    #[fail(display = "the base table is not ready for writes yet")]
+    NotReady,
+}
+
+/// The value a base table column takes when a write does not give one.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ColumnDefault {
+    /// A fixed value.
+    Value(DataType),
+    /// The time at which the base table received the write.
+    CurrentTimestamp,
+}
+
+impl ColumnDefault {
+    /// Produce the default value for a write received at time `now`.
+    pub fn value(&self, now: &DataType) -> DataType {
+        match *self {
+            ColumnDefault::Value(ref v) => v.clone(),
+            ColumnDefault::CurrentTimestamp => now.clone(),
+        }
+    }
+}
+
+/// The declared type of a single base table column.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ColumnSchema {
+    /// The name of the column.
+    pub name: String,
+    /// The kind of values the column holds.
+    pub column_type: ColumnType,
+    /// Whether the column accepts `NULL` values.
+    pub nullable: bool,
+    /// The value the column takes when a write does not give one.
+    pub default: Option<ColumnDefault>,
+}
+
+impl ColumnSchema {
+    /// Declare a column named `name` that holds values of type `column_type`.
+    ///
+    /// The column does not accept `NULL` values unless `nullable` is set.
+    pub fn new<S: ToString>(name: S, column_type: ColumnType, nullable: bool) -> Self {
+        ColumnSchema {
+            name: name.to_string(),
+            column_type,
+            nullable,
+            default: None,
+        }
+    }
+
+    /// Give the column a default value.
+    ///
+    /// Panics if a `ColumnDefault::Value` does not have the column's type, or if a
+    /// `ColumnDefault::CurrentTimestamp` is given for a column that does not hold timestamps.
+    pub fn with_default(mut self, default: ColumnDefault) -> Self {
+        match default {
+            ColumnDefault::Value(ref v) => {
+                if let Err(e) = self.check(v) {
+                    panic!("invalid default value {:?}: {}", v, e);
+                }
+            }
+            ColumnDefault::CurrentTimestamp => assert_eq!(self.column_type, ColumnType::Timestamp),
+        }
+        self.default = Some(default);
+        self
+    }
+
+    /// Check that `value` may be stored in this column.
+    pub fn check(&self, value: &DataType) -> Result<(), WriteError> {
+        match ColumnType::of(value) {
+            None if self.nullable => Ok(()),
+            None => Err(WriteError::NotNullable(self.name.clone())),
+            Some(t) if t == self.column_type => Ok(()),
+            Some(t) => Err(WriteError::WrongType(
+                self.name.clone(),
+                self.column_type,
+                t,
+            )),
+        }
+    }
+}
+
 impl From<TransportError> for TableError {
     fn from(e: TransportError) -> Self {
         TableError::TransportError(e)
@@ -60,6 +235,8 @@ pub struct TableBuilder {
     pub table_name: String,
     pub columns: Vec<String>,
     pub schema: Option<CreateTableStatement>,
+    pub base_schema: Option<Vec<ColumnSchema>>,
+    pub max_value_size: Option<usize>,
 
     pub local_port: Option<u16>,
 }
@@ -98,6 +275,8 @@ impl TableBuilder {
             table_name: self.table_name,
             columns: self.columns,
             schema: self.schema,
+            base_schema: self.base_schema,
+            max_value_size: self.max_value_size,
             exclusivity: SharedConnection,
         })
     }
@@ -120,6 +299,8 @@ pub struct Table<E = SharedConnection> {
     table_name: String,
     columns: Vec<String>,
     schema: Option<CreateTableStatement>,
+    base_schema: Option<Vec<ColumnSchema>>,
+    max_value_size: Option<usize>,
 
     #[allow(dead_code)]
     exclusivity: E,
@@ -138,6 +319,8 @@ impl Clone for Table<SharedConnection> {
             table_name: self.table_name.clone(),
             columns: self.columns.clone(),
             schema: self.schema.clone(),
+            base_schema: self.base_schema.clone(),
+            max_value_size: self.max_value_size,
             exclusivity: SharedConnection,
         }
     }
@@ -162,6 +345,8 @@ impl Table<SharedConnection> {
             table_name: self.table_name.clone(),
             columns: self.columns.clone(),
             schema: self.schema.clone(),
+            base_schema: self.base_schema.clone(),
+            max_value_size: self.max_value_size,
             exclusivity: ExclusiveConnection,
         })
     }
@@ -194,11 +379,63 @@ impl<E> Table<E> {
         self.schema.as_ref()
     }
 
+    /// Get the column types declared for this base table, if any.
+    ///
+    /// If a schema was declared, the base table rejects writes whose values do not match it with
+    /// `TableError::Rejected`. Columns added after the schema was declared are not included.
+    pub fn base_schema(&self) -> Option<&[ColumnSchema]> {
+        self.base_schema.as_ref().map(|s| &s[..])
+    }
+
+    /// Limit the size of variable-length values (strings and binary values) written through this
+    /// `Table` to `bytes` bytes.
+    ///
+    /// Writes containing a larger value are rejected with `TableError::ValueTooLarge` before they
+    /// are sent to the base table. This is only an early exit: the base table enforces its own
+    /// limit (see `Base::with_max_value_size`) regardless, and rejects writes that exceed it with
+    /// `WriteError::ValueTooLarge`. By default, the base table's limit is used.
+    pub fn set_max_value_size(&mut self, bytes: Option<usize>) {
+        self.max_value_size = bytes;
+    }
+
     /// Get the local address this `Table` is bound to.
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
         self.domain_input_handle.borrow().local_addr()
     }
 
+    fn check_value(&self, col: usize, value: &DataType) -> Result<(), TableError> {
+        match (self.max_value_size, value.value_size()) {
+            (Some(limit), Some(size)) if size > limit => Err(TableError::ValueTooLarge(
+                self.columns[col].clone(),
+                size,
+                limit,
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check if every column from `from` onwards has a default value.
+    fn has_trailing_defaults(&self, from: usize) -> bool {
+        match self.base_schema {
+            Some(ref cols) if self.dropped.is_empty() && from <= cols.len() => {
+                cols[from..].iter().all(|c| c.default.is_some())
+            }
+            _ => false,
+        }
+    }
+
+    fn check_row(&self, row: &[DataType]) -> Result<(), TableError> {
+        if row.len() != self.columns.len()
+            && !(row.len() < self.columns.len() && self.has_trailing_defaults(row.len()))
+        {
+            return Err(TableError::WrongColumnCount(self.columns.len(), row.len()));
+        }
+        for (i, v) in row.iter().enumerate() {
+            self.check_value(i, v)?;
+        }
+        Ok(())
+    }
+
     fn inject_dropped_cols(&self, rs: &mut [TableOperation]) {
         let ndropped = self.dropped.len();
         if ndropped != 0 {
@@ -277,10 +514,11 @@ impl<E> Table<E> {
             dst: self.addr,
             data: ops,
             tracer,
+            markers: Vec::new(),
         }
     }
 
-    fn send(&mut self, ops: Vec<TableOperation>) -> Result<(), TransportError> {
+    fn send(&mut self, ops: Vec<TableOperation>) -> Result<Vec<i64>, TableError> {
         let tracer = self.tracer.take();
         let m = self.prep_records(tracer, ops);
         self.domain_input_handle
@@ -294,30 +532,71 @@ impl<E> Table<E> {
         I: IntoIterator<Item = V>,
         V: Into<TableOperation>,
     {
-        let mut dih = self.domain_input_handle.borrow_mut();
-        let mut batch_putter = dih.sender();
-
         let data = i
             .into_iter()
             .map(|row| {
                 let row: TableOperation = row.into();
                 if let Some(cols) = row.row() {
-                    if cols.len() != self.columns.len() {
-                        return Err(TableError::WrongColumnCount(self.columns.len(), cols.len()));
-                    }
+                    self.check_row(cols)?;
                 }
                 Ok(row)
             })
             .collect::<Result<_, _>>()?;
 
-        let tracer = self.tracer.clone();
+        let tracer = self.tracer.take();
         let m = self.prep_records(tracer, data);
-        batch_putter.enqueue(m, &self.key[..])?;
-        self.tracer.take();
-        batch_putter.wait()?;
+        self.domain_input_handle
+            .borrow_mut()
+            .send_until_ready(m, &self.key[..])?;
         Ok(())
     }
 
+    /// Perform multiple operations on this base table in one batch, and wait until their effects
+    /// are visible to reads from `view`, or until `timeout` has passed, whichever comes first.
+    ///
+    /// The wait ends even if the operations have no effect on the view at all, such as when every
+    /// row they produce is filtered out on the way. If the view is not visible in time, this
+    /// returns `TableError::NotVisible`, but the operations are still performed.
+    pub fn batch_insert_visible<I, V, F>(
+        &mut self,
+        i: I,
+        view: &mut View<F>,
+        timeout: Duration,
+    ) -> Result<(), TableError>
+    where
+        I: IntoIterator<Item = V>,
+        V: Into<TableOperation>,
+    {
+        let data = i
+            .into_iter()
+            .map(|row| {
+                let row: TableOperation = row.into();
+                if let Some(cols) = row.row() {
+                    self.check_row(cols)?;
+                }
+                Ok(row)
+            })
+            .collect::<Result<_, _>>()?;
+
+        let start = Instant::now();
+        let marker = new_marker();
+        let tracer = self.tracer.take();
+        let mut m = self.prep_records(tracer, data);
+        m.markers.push(marker);
+        self.domain_input_handle
+            .borrow_mut()
+            .base_send(m, &self.key[..])?;
+
+        let left = timeout
+            .checked_sub(start.elapsed())
+            .unwrap_or(Duration::from_millis(0));
+        if view.wait_for_marker(marker, left)? {
+            Ok(())
+        } else {
+            Err(TableError::NotVisible(timeout))
+        }
+    }
+
     /// Perform multiple operations on this base table and only wait for acks once they have all
     /// been enqueued.
     pub fn insert_then_wait<I, V>(&mut self, i: I) -> Result<(), TableError>
@@ -332,9 +611,7 @@ impl<E> Table<E> {
             let data = vec![row.into()];
 
             if let Some(cols) = data[0].row() {
-                if cols.len() != self.columns.len() {
-                    return Err(TableError::WrongColumnCount(self.columns.len(), cols.len()));
-                }
+                self.check_row(cols)?;
             }
 
             let tracer = self.tracer.clone();
@@ -357,9 +634,9 @@ impl<E> Table<E> {
         let mut batch_putter = dih.sender();
 
         for batch in i {
-            if let Some(cols) = batch[0].row() {
-                if cols.len() != self.columns.len() {
-                    return Err(TableError::WrongColumnCount(self.columns.len(), cols.len()));
+            for op in &batch {
+                if let Some(cols) = op.row() {
+                    self.check_row(cols)?;
                 }
             }
 
@@ -379,52 +656,141 @@ impl<E> Table<E> {
         V: Into<Vec<DataType>>,
     {
         let data = vec![TableOperation::Insert(u.into())];
-        if data[0].row().unwrap().len() != self.columns.len() {
-            return Err(TableError::WrongColumnCount(
-                self.columns.len(),
-                data[0].row().unwrap().len(),
-            ));
-        }
+        self.check_row(data[0].row().unwrap())?;
 
         self.send(data)?;
         Ok(())
     }
 
+    /// Insert a single row of data into this base table, giving its values by column name.
+    ///
+    /// Columns that are not named take the default values declared for them in the base table's
+    /// schema (see `Table::base_schema`). The write is rejected if any of them has no default.
+    pub fn insert_named<I, S>(&mut self, values: I) -> Result<(), TableError>
+    where
+        I: IntoIterator<Item = (S, DataType)>,
+        S: AsRef<str>,
+    {
+        let mut row = vec![DataType::None; self.columns.len()];
+        let mut given = vec![false; self.columns.len()];
+        for (col, v) in values {
+            let col = col.as_ref();
+            let i = self
+                .columns
+                .iter()
+                .position(|c| c == col)
+                .ok_or_else(|| TableError::UnknownColumn(col.to_owned()))?;
+            self.check_value(i, &v)?;
+            row[i] = v;
+            given[i] = true;
+        }
+
+        let defaulted = given
+            .into_iter()
+            .enumerate()
+            .filter(|&(_, given)| !given)
+            .map(|(i, _)| i)
+            .collect();
+        self.send(vec![TableOperation::InsertDefaulted { row, defaulted }])?;
+        Ok(())
+    }
+
     /// Insert multiple rows of data into this base table.
     pub fn insert_all<I, V>(&mut self, i: I) -> Result<(), TableError>
     where
         I: IntoIterator<Item = V>,
         V: Into<Vec<DataType>>,
     {
-        i.into_iter()
+        self.insert_returning_ids(i).map(|_| ())
+    }
+
+    /// Insert multiple rows of data into this base table, and return the ids that the base table
+    /// assigned to them.
+    ///
+    /// If the base table has an auto-increment column, the base table chooses the value of that
+    /// column for each inserted row, and the chosen values are returned in the order the rows were
+    /// given. The rows must still include a value (e.g., `DataType::None`) for the auto-increment
+    /// column, but that value is ignored. If the base table has no auto-increment column, no ids
+    /// are returned.
+    pub fn insert_returning_ids<I, V>(&mut self, i: I) -> Result<Vec<i64>, TableError>
+    where
+        I: IntoIterator<Item = V>,
+        V: Into<Vec<DataType>>,
+    {
+        let data = i
+            .into_iter()
             .map(|r| {
                 let row = r.into();
-                if row.len() != self.columns.len() {
-                    return Err(TableError::WrongColumnCount(self.columns.len(), row.len()));
-                }
+                self.check_row(&row)?;
                 Ok(TableOperation::Insert(row))
             })
-            .collect::<Result<Vec<_>, _>>()
-            .and_then(|data| {
-                self.send(data)?;
-                Ok(())
-            })
-            .map(|_| ())
+            .collect::<Result<Vec<_>, _>>()?;
+        self.send(data)
+    }
+
+    /// Load a large number of rows into this base table.
+    ///
+    /// Rows are sent in batches of `batch_size`, and a batch is only sent once the base table has
+    /// applied the previous one, so the load never has more than one batch in flight. After each
+    /// batch, `progress` is called with the number of rows loaded so far. Returns the total number
+    /// of rows loaded. If a batch is rejected, the rows in earlier batches remain loaded.
+    pub fn bulk_load<I, V, F>(
+        &mut self,
+        rows: I,
+        batch_size: usize,
+        mut progress: F,
+    ) -> Result<usize, TableError>
+    where
+        I: IntoIterator<Item = V>,
+        V: Into<Vec<DataType>>,
+        F: FnMut(usize),
+    {
+        assert!(batch_size > 0, "bulk loads must use non-empty batches");
+
+        let mut loaded = 0;
+        let mut batch = Vec::with_capacity(batch_size);
+        let mut rows = rows.into_iter().peekable();
+        while let Some(row) = rows.next() {
+            let row = row.into();
+            self.check_row(&row)?;
+            batch.push(TableOperation::Insert(row));
+
+            if batch.len() == batch_size || rows.peek().is_none() {
+                let batch = mem::replace(&mut batch, Vec::with_capacity(batch_size));
+                loaded += batch.len();
+                self.send(batch)?;
+                progress(loaded);
+            }
+        }
+        Ok(loaded)
     }
 
     /// Delete the row with the given key from this base table.
+    ///
+    /// If no row with the given key exists, the delete is rejected with
+    /// `TableError::Rejected(WriteError::KeyNotFound(key))`.
     pub fn delete<I>(&mut self, key: I) -> Result<(), TableError>
     where
         I: Into<Vec<DataType>>,
     {
-        self.send(vec![TableOperation::Delete { key: key.into() }])?;
+        assert!(
+            !self.key.is_empty() && self.key_is_primary,
+            "delete operations can only be applied to base nodes with key columns"
+        );
+
+        let key = key.into();
+        if key.len() != self.key.len() {
+            return Err(TableError::WrongKeyColumnCount(self.key.len(), key.len()));
+        }
+        self.send(vec![TableOperation::Delete { key }])?;
         Ok(())
     }
 
     /// Update the row with the given key in this base table.
     ///
     /// `u` is a set of column-modification pairs, where for each pair `(i, m)`, the modification
-    /// `m` will be applied to column `i` of the record with key `key`.
+    /// `m` will be applied to column `i` of the record with key `key`. If no row with the given
+    /// key exists, the update is rejected with `TableError::Rejected(WriteError::KeyNotFound(key))`.
     pub fn update<V>(&mut self, key: Vec<DataType>, u: V) -> Result<(), TableError>
     where
         V: IntoIterator<Item = (usize, Modification)>,
@@ -443,12 +809,45 @@ impl<E> Table<E> {
             if coli >= self.columns.len() {
                 return Err(TableError::WrongColumnCount(self.columns.len(), coli + 1));
             }
+            if let Modification::Set(ref v) = m {
+                self.check_value(coli, v)?;
+            }
             set[coli] = m;
         }
         self.send(vec![TableOperation::Update { key, set }])?;
         Ok(())
     }
 
+    /// Set the given columns of the row with the given key in this base table.
+    ///
+    /// `u` is a set of column-value pairs, where for each pair `(i, v)`, column `i` of the record
+    /// with key `key` is set to `v`. Only these values are sent to the base table, which makes
+    /// this cheaper than `Table::update` for wide rows. If no row with the given key exists, the
+    /// modification is rejected with `TableError::Rejected(WriteError::KeyNotFound(key))`.
+    pub fn modify<V>(&mut self, key: Vec<DataType>, u: V) -> Result<(), TableError>
+    where
+        V: IntoIterator<Item = (usize, DataType)>,
+    {
+        assert!(
+            !self.key.is_empty() && self.key_is_primary,
+            "modify operations can only be applied to base nodes with key columns"
+        );
+
+        if key.len() != self.key.len() {
+            return Err(TableError::WrongKeyColumnCount(self.key.len(), key.len()));
+        }
+
+        let set: Vec<_> = u.into_iter().collect();
+        for &(coli, ref v) in &set {
+            if coli >= self.columns.len() {
+                return Err(TableError::WrongColumnCount(self.columns.len(), coli + 1));
+            }
+            self.check_value(coli, v)?;
+        }
+        self.send(vec![TableOperation::Modify { key, set }])?;
+        Ok(())
+    }
+
     /// Perform a insert-or-update on this base table.
     ///
     /// If a row already exists for the key in `insert`, the existing row will instead be updated
@@ -466,18 +865,16 @@ impl<E> Table<E> {
             "update operations can only be applied to base nodes with key columns"
         );
 
-        if insert.len() != self.columns.len() {
-            return Err(TableError::WrongColumnCount(
-                self.columns.len(),
-                insert.len(),
-            ));
-        }
+        self.check_row(&insert)?;
 
         let mut set = vec![Modification::None; self.columns.len()];
         for (coli, m) in update {
             if coli >= self.columns.len() {
                 return Err(TableError::WrongColumnCount(self.columns.len(), coli + 1));
             }
+            if let Modification::Set(ref v) = m {
+                self.check_value(coli, v)?;
+            }
             set[coli] = m;
         }
 
@@ -504,6 +901,8 @@ impl<E> Table<E> {
 pub(crate) struct DomainInputHandle {
     txs: Vec<TcpSender<LocalOrNot<Input>>>,
     dst_is_local: bool,
+    /// The bases that each shard has taken a write for, which are known to be ready.
+    ready: HashSet<(LocalNodeIndex, usize)>,
 }
 
 pub(crate) type TableRpc = Rc<RefCell<DomainInputHandle>>;
@@ -526,6 +925,7 @@ impl DomainInputHandle {
         Ok(Self {
             txs: txs?,
             dst_is_local: false,
+            ready: HashSet::new(),
         })
     }
 
@@ -541,15 +941,152 @@ impl DomainInputHandle {
         BatchSendHandle::new(self)
     }
 
-    pub(crate) fn base_send(&mut self, i: Input, key: &[usize]) -> Result<(), TransportError> {
-        let mut s = BatchSendHandle::new(self);
-        s.enqueue(i, key)?;
-        s.wait().map_err(|_| {
-            tcp::SendError::IoError(io::Error::new(io::ErrorKind::Other, "write failed")).into()
+    pub(crate) fn base_send(&mut self, i: Input, key: &[usize]) -> Result<Vec<i64>, TableError> {
+        self.send_until_ready(i, key).map_err(|e| match e {
+            e @ TableError::Rejected(..) | e @ TableError::CompoundShardKey => e,
+            _ => TransportError::from(tcp::SendError::IoError(io::Error::new(
+                io::ErrorKind::Other,
+                "write failed",
+            )))
+            .into(),
         })
     }
 }
 
+    /// Send a write, and wait for it to be applied. Each shard whose base was not ready for its
+    /// part of the write is sent that part again, until the base is ready, or until it has been
+    /// tried for long enough. Writes to bases that every shard has taken a write for before are
+    /// sent as they are, without holding on to a copy.
+    ///
+    /// If a shard that had taken a write before still turns a part away as not ready, there is no
+    /// copy of that part to send again, and the write fails with `WriteError::NotReady`. The shard
+    /// is then no longer considered ready, so that later writes to it are kept until they are
+    /// taken.
+    pub(crate) fn send_until_ready(
+        &mut self,
+        i: Input,
+        key: &[usize],
+    ) -> Result<Vec<i64>, TableError> {
+        let dst = i.dst;
+        let deadline = Instant::now() + Duration::from_millis(NOT_READY_TIMEOUT_MS);
+        let mut pending = self.split(i, key)?;
+        let mut ids = Vec::new();
+        loop {
+            let mut unready = Vec::new();
+            let acks = {
+                let mut s = BatchSendHandle::new(self);
+                for (shard, i) in pending {
+                    if !s.dih.ready.contains(&(dst, shard)) {
+                        unready.push((shard, i.clone()));
+                    }
+                    s.send_to(shard, i)?;
+                }
+                s.acks()?
+            };
+
+            let mut rejected = None;
+            let mut refused = Vec::new();
+            for (shard, ack) in acks {
+                match ack {
+                    Err(WriteError::NotReady) => {
+                        self.ready.remove(&(dst, shard));
+                        refused.push(shard);
+                        continue;
+                    }
+                    Ok(assigned) => ids.extend(assigned),
+                    Err(e) => {
+                        rejected.get_or_insert(e);
+                    }
+                }
+                self.ready.insert((dst, shard));
+            }
+
+            if let Some(e) = rejected {
+                return Err(TableError::Rejected(e));
+            }
+            if refused.is_empty() {
+                return Ok(ids);
+            }
+            if Instant::now() >= deadline {
+                return Err(TableError::Rejected(WriteError::NotReady));
+            }
+            pending = resend(unready, &refused).map_err(TableError::Rejected)?;
+            thread::sleep(Duration::from_millis(NOT_READY_RETRY_MS));
+        }
+    }
+
+    /// Cut a write into the parts that go to each shard, by shard.
+    ///
+    /// Fails with `TableError::CompoundShardKey` if the base is sharded and its key has more than
+    /// one column, since only the column that a base is sharded by says which shard a row goes
+    /// to, and that column is not known here.
+    fn split(&self, mut i: Input, key: &[usize]) -> Result<Vec<(usize, Input)>, TableError> {
+        if self.txs.len() == 1 {
+            return Ok(vec![(0, i)]);
+        }
+        if key.is_empty() {
+            unreachable!("sharded base without a key?");
+        }
+        if key.len() != 1 {
+            return Err(TableError::CompoundShardKey);
+        }
+        let key_col = key[0];
+
+        let mut shard_writes = vec![Vec::new(); self.txs.len()];
+        for r in i.data.drain(..) {
+            let shard = {
+                let key = match r {
+                    TableOperation::Insert(ref r) => &r[key_col],
+                    TableOperation::Delete { ref key } => &key[0],
+                    TableOperation::Update { ref key, .. } => &key[0],
+                    TableOperation::Modify { ref key, .. } => &key[0],
+                    TableOperation::InsertOrUpdate { ref row, .. } => &row[key_col],
+                    TableOperation::InsertDefaulted { ref row, .. } => &row[key_col],
+                };
+                crate::shard_by(key, self.txs.len())
+            };
+            shard_writes[shard].push(r);
+        }
+
+        Ok(shard_writes
+            .into_iter()
+            .enumerate()
+            // a write that is waited for may show up below any shard, so all of them are told
+            .filter(|&(_, ref rs)| !rs.is_empty() || !i.markers.is_empty())
+            .map(|(s, rs)| {
+                let part = Input {
+                    dst: i.dst,
+                    tracer: i.tracer.clone(),
+                    data: rs,
+                    markers: i.markers.clone(),
+                };
+                (s, part)
+            })
+            .collect())
+    }
+}
+
+/// The parts of a write to send again to the shards that refused them as not ready, from the
+/// copies that were kept of the parts sent to shards that were not known to be ready.
+///
+/// Fails with `WriteError::NotReady` if a shard that refused its part has no copy of it, which
+/// happens when a shard that was known to be ready turns out not to be after all.
+fn resend(
+    kept: Vec<(usize, Input)>,
+    refused: &[usize],
+) -> Result<Vec<(usize, Input)>, WriteError> {
+    if refused
+        .iter()
+        .any(|shard| kept.iter().all(|&(s, _)| s != *shard))
+    {
+        return Err(WriteError::NotReady);
+    }
+    Ok(kept
+        .into_iter()
+        .filter(|&(shard, _)| refused.contains(&shard))
+        .collect())
+}
+
 pub(crate) struct BatchSendHandle<'a> {
     dih: &'a mut DomainInputHandle,
     sent: Vec<usize>,
@@ -561,73 +1098,90 @@ impl<'a> BatchSendHandle<'a> {
         Self { dih, sent }
     }
 
-    pub(crate) fn enqueue(&mut self, mut i: Input, key: &[usize]) -> Result<(), TransportError> {
-        if self.dih.txs.len() == 1 {
-            self.dih.txs[0].send(if self.dih.dst_is_local {
-                unsafe { LocalOrNot::for_local_transfer(i) }
-            } else {
-                LocalOrNot::new(i)
-            })?;
-            self.sent[0] += 1;
-        } else {
-            if key.is_empty() {
-                unreachable!("sharded base without a key?");
-            }
-            if key.len() != 1 {
-                // base sharded by complex key
-                unimplemented!();
-            }
-            let key_col = key[0];
-
-            let mut shard_writes = vec![Vec::new(); self.dih.txs.len()];
-            for r in i.data.drain(..) {
-                let shard = {
-                    let key = match r {
-                        TableOperation::Insert(ref r) => &r[key_col],
-                        TableOperation::Delete { ref key } => &key[0],
-                        TableOperation::Update { ref key, .. } => &key[0],
-                        TableOperation::InsertOrUpdate { ref row, .. } => &row[key_col],
-                    };
-                    crate::shard_by(key, self.dih.txs.len())
-                };
-                shard_writes[shard].push(r);
-            }
-
-            for (s, rs) in shard_writes.drain(..).enumerate() {
-                if !rs.is_empty() {
-                    let p = if self.dih.dst_is_local {
-                        unsafe {
-                            LocalOrNot::for_local_transfer(Input {
-                                dst: i.dst,
-                                tracer: i.tracer.clone(),
-                                data: rs,
-                            })
-                        }
-                    } else {
-                        LocalOrNot::new(Input {
-                            dst: i.dst,
-                            tracer: i.tracer.clone(),
-                            data: rs,
-                        })
-                    };
-
-                    self.dih.txs[s].send(p)?;
-                    self.sent[s] += 1;
-                }
-            }
+    pub(crate) fn enqueue(&mut self, i: Input, key: &[usize]) -> Result<(), TableError> {
+        for (shard, i) in self.dih.split(i, key)? {
+            self.send_to(shard, i)?;
         }
+        Ok(())
+    }
 
+    fn send_to(&mut self, shard: usize, i: Input) -> Result<(), TransportError> {
+        self.dih.txs[shard].send(if self.dih.dst_is_local {
+            unsafe { LocalOrNot::for_local_transfer(i) }
+        } else {
+            LocalOrNot::new(i)
+        })?;
+        self.sent[shard] += 1;
         Ok(())
     }
 
-    pub(crate) fn wait(self) -> Result<(), TransportError> {
+    /// Read the acknowledgment of every write sent, with the shard it was sent to.
+    fn acks(self) -> Result<Vec<(usize, WriteAck)>, TransportError> {
+        let mut acks = Vec::new();
         for (shard, n) in self.sent.into_iter().enumerate() {
             for _ in 0..n {
                 use bincode;
-                let _: bool = bincode::deserialize_from(&mut (&mut self.dih.txs[shard]).reader())?;
+                let ack: WriteAck =
+                    bincode::deserialize_from(&mut (&mut self.dih.txs[shard]).reader())
+                        .map_err(TransportError::from)?;
+                acks.push((shard, ack));
             }
         }
+        Ok(acks)
+    }
 
-        Ok(())
+    pub(crate) fn wait(self) -> Result<Vec<i64>, TableError> {
+        // we must read every ack, even after a rejection, so the next write sees its own acks
+        let mut ids = Vec::new();
+        let mut rejected = None;
+        for (_, ack) in self.acks()? {
+            match ack {
+                Ok(assigned) => ids.extend(assigned),
+                Err(e) => {
+                    rejected.get_or_insert(e);
+                }
+            }
+        }
+
+        match rejected {
+            Some(e) => Err(TableError::Rejected(e)),
+            None => Ok(ids),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn part(rows: i64) -> Input {
+        Input {
+            dst: unsafe { LocalNodeIndex::make(0) },
+            data: (0..rows)
+                .map(|i| TableOperation::Insert(vec![i.into()]))
+                .collect(),
+            tracer: None,
+            markers: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn it_resends_kept_parts_to_the_shards_that_refused_them() {
+        let kept = vec![(0, part(1)), (2, part(2))];
+        let again = resend(kept, &[2]).unwrap();
+        assert_eq!(again.len(), 1);
+        assert_eq!(again[0].0, 2);
+        assert_eq!(again[0].1.data.len(), 2);
+    }
+
+    #[test]
+    fn it_fails_writes_that_a_ready_shard_refuses() {
+        // shard 1 was known to be ready, so no copy was kept of its part
+        let kept = vec![(0, part(1))];
+        match resend(kept, &[0, 1]) {
+            Err(WriteError::NotReady) => {}
+            Err(e) => panic!("expected the write to fail as not ready, got {:?}", e),
+            Ok(_) => panic!("a part that was not kept was considered sent again"),
+        }
     }
 }
@@ -1,22 +1,39 @@
 use crate::channel::rpc::RpcClient;
 use crate::data::*;
 use crate::error::TransportError;
+use crate::filter::{FilterError, Operator, RowFilter};
 use crate::{ExclusiveConnection, SharedConnection};
 use petgraph::graph::NodeIndex;
 use std::cell::RefCell;
+use std::cmp::{self, Ordering};
 use std::collections::HashMap;
 use std::io;
 use std::net::SocketAddr;
+use std::ops::Bound;
 use std::rc::Rc;
+use std::time::{Duration, SystemTime};
 
 pub(crate) type ViewRpc = Rc<RefCell<RpcClient<ReadQuery, ReadReply>>>;
 
 /// A failed View operation.
 #[derive(Debug, Fail)]
 pub enum ViewError {
-    /// The given view is not yet available.
+    /// The given view is not yet available, because its state is still being built. Blocking
+    /// reads only fail this way if the view's ready timeout runs out first.
     #[fail(display = "the view is not yet available")]
     NotYetAvailable,
+    /// The view has no ordered index, and so cannot be looked up by range.
+    #[fail(display = "the view has no ordered index")]
+    NotOrdered,
+    /// The view was not set up to allow scans of its entire contents.
+    #[fail(display = "the view does not allow scans")]
+    NotScannable,
+    /// A read asked for a column that the view does not have.
+    #[fail(display = "the view has no column {}", _0)]
+    NoSuchColumn(usize),
+    /// A filter compared values with an operator that does not compare two values.
+    #[fail(display = "values cannot be filtered with {}", _0)]
+    UnsupportedOperator(Operator),
     /// A lower-level error occurred while communicating with Soup.
     #[fail(display = "{}", _0)]
     TransportError(#[cause] TransportError),
@@ -28,6 +45,23 @@ impl From<TransportError> for ViewError {
     }
 }
 
+impl From<FilterError> for ViewError {
+    fn from(e: FilterError) -> Self {
+        match e {
+            FilterError::NoSuchColumn(c) => ViewError::NoSuchColumn(c),
+            FilterError::UnsupportedOperator(op) => ViewError::UnsupportedOperator(op),
+        }
+    }
+}
+
+/// Fail with the error that a shard gave for refusing a query's filter, if it refused it.
+fn accepted(reply: ReadReply) -> Result<ReadReply, ViewError> {
+    match reply {
+        ReadReply::Invalid(e) => Err(e.into()),
+        reply => Ok(reply),
+    }
+}
+
 #[doc(hidden)]
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ReadQuery {
@@ -35,25 +69,255 @@ pub enum ReadQuery {
     Normal {
         /// Where to read from
         target: (NodeIndex, usize),
+        /// Only return the rows that pass this filter, if any
+        filter: Option<RowFilter>,
+        /// Keys to read with
+        keys: Vec<Vec<DataType>>,
+        /// Columns to return, in order, or `None` to return whole rows
+        project: Option<Vec<usize>>,
+        /// Whether to block if a partial replay is triggered, or if the view is not yet ready
+        block: bool,
+        /// How long a blocking read waits for the view to become ready, if not indefinitely
+        ready_timeout: Option<Duration>,
+    },
+    /// Count the rows for each of a number of keys in a leaf view
+    Count {
+        /// Where to read from
+        target: (NodeIndex, usize),
+        /// Only count the rows that pass this filter, if any
+        filter: Option<RowFilter>,
+        /// Keys to count the rows of
+        keys: Vec<Vec<DataType>>,
+        /// Whether to block if a partial replay is triggered, or if the view is not yet ready
+        block: bool,
+        /// How long a blocking read waits for the view to become ready, if not indefinitely
+        ready_timeout: Option<Duration>,
+    },
+    /// Read from a leaf view, and say how up to date the results are
+    WithMeta {
+        /// Where to read from
+        target: (NodeIndex, usize),
+        /// Only return the rows that pass this filter, if any
+        filter: Option<RowFilter>,
         /// Keys to read with
         keys: Vec<Vec<DataType>>,
-        /// Whether to block if a partial replay is triggered
+        /// Whether to block if a partial replay is triggered, or if the view is not yet ready
         block: bool,
+        /// How long a blocking read waits for the view to become ready, if not indefinitely
+        ready_timeout: Option<Duration>,
     },
     /// Read the size of a leaf view
     Size {
         /// Where to read from
         target: (NodeIndex, usize),
+        /// Only count the keys that have rows that pass this filter, if any
+        filter: Option<RowFilter>,
+    },
+    /// Read all rows whose key lies within a range from a leaf view with an ordered index
+    Range {
+        /// Where to read from
+        target: (NodeIndex, usize),
+        /// Only return the rows that pass this filter, if any
+        filter: Option<RowFilter>,
+        /// Lower end of the range
+        lower: RangeBound,
+        /// Upper end of the range
+        upper: RangeBound,
+        /// Maximum number of rows to return
+        limit: Option<usize>,
+    },
+    /// Read the next batch of all the rows of a leaf view, in key order
+    Scan {
+        /// Where to read from
+        target: (NodeIndex, usize),
+        /// Only return the rows that pass this filter, if any
+        filter: Option<RowFilter>,
+        /// Key to start after, or `None` to start at the first key
+        after: Option<Vec<DataType>>,
+        /// Minimum number of rows to return, if there are that many
+        limit: usize,
+    },
+    /// Read one page of the rows for a key from a leaf view, in a given order
+    Page {
+        /// Where to read from
+        target: (NodeIndex, usize),
+        /// Only return the rows that pass this filter, if any
+        filter: Option<RowFilter>,
+        /// Key to read with
+        key: Vec<DataType>,
+        /// Column to order the rows by, and in which direction
+        order_by: (usize, Direction),
+        /// Number of rows to skip
+        offset: usize,
+        /// Maximum number of rows to return
+        limit: usize,
+        /// Columns to return, in order, or `None` to return whole rows
+        project: Option<Vec<usize>>,
+        /// How long to wait for the view to become ready, if not indefinitely
+        ready_timeout: Option<Duration>,
+    },
+    /// Wait for a write to be visible in a leaf view
+    Marker {
+        /// Where to wait
+        target: (NodeIndex, usize),
+        /// The marker that the write carries
+        marker: u64,
+        /// How long to wait for
+        timeout: Duration,
     },
 }
 
+/// The direction in which to order rows.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Smallest values first.
+    Ascending,
+    /// Largest values first.
+    Descending,
+}
+
+/// Order two rows by the given column, in the given direction. Rows that are equal in that column
+/// are ordered by their remaining values, so that the order only depends on the rows themselves.
+#[doc(hidden)]
+pub fn compare_rows(
+    a: &[DataType],
+    b: &[DataType],
+    (column, direction): (usize, Direction),
+) -> Ordering {
+    let ord = a[column].cmp(&b[column]).then_with(|| a.cmp(b));
+    match direction {
+        Direction::Ascending => ord,
+        Direction::Descending => ord.reverse(),
+    }
+}
+
+/// What is known about how up to date the results of a read are.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadMeta {
+    /// When the most recent write that had reached the view when it was read was accepted by its
+    /// base table, or `None` if no writes had reached the view since it was created. The results
+    /// include the effects of that write, so this is never ahead of the rows that were returned.
+    ///
+    /// Writes to other base tables may have been accepted later and still not be reflected.
+    pub written: Option<SystemTime>,
+    /// When the view last made the writes that had reached it visible to reads, or `None` if it
+    /// had not yet done so. Depending on the publish policy it was given when it was added, a
+    /// view may hold on to writes for a while before making them visible, so writes that reached
+    /// it since then are not reflected.
+    #[serde(default)]
+    pub published: Option<SystemTime>,
+    /// Whether the rows came from a fully materialized view, as opposed to one that only holds
+    /// the keys that have been read and fills in the others on demand.
+    pub full: bool,
+}
+
+impl ReadMeta {
+    /// Combine what is known about two reads, such that the result holds for both of them.
+    #[doc(hidden)]
+    pub fn and(self, other: ReadMeta) -> ReadMeta {
+        ReadMeta {
+            written: cmp::min(self.written, other.written),
+            published: cmp::min(self.published, other.published),
+            full: self.full && other.full,
+        }
+    }
+}
+
+/// One end of a range lookup.
+#[doc(hidden)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum RangeBound {
+    /// The range includes this key.
+    Included(DataType),
+    /// The range stops just short of this key.
+    Excluded(DataType),
+    /// The range is unbounded in this direction.
+    Unbounded,
+}
+
+impl RangeBound {
+    /// Borrow this bound as a `std::ops::Bound`.
+    pub fn as_bound(&self) -> Bound<&DataType> {
+        match *self {
+            RangeBound::Included(ref k) => Bound::Included(k),
+            RangeBound::Excluded(ref k) => Bound::Excluded(k),
+            RangeBound::Unbounded => Bound::Unbounded,
+        }
+    }
+}
+
+impl From<Bound<DataType>> for RangeBound {
+    fn from(b: Bound<DataType>) -> Self {
+        match b {
+            Bound::Included(k) => RangeBound::Included(k),
+            Bound::Excluded(k) => RangeBound::Excluded(k),
+            Bound::Unbounded => RangeBound::Unbounded,
+        }
+    }
+}
+
 #[doc(hidden)]
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ReadReply {
     /// Errors if view isn't ready yet.
     Normal(Result<Vec<Datas>, ()>),
+    /// Rows for each key, and how up to date they all are. Errors if view isn't ready yet.
+    WithMeta(Result<(Vec<Datas>, ReadMeta), ()>),
+    /// Number of rows for each key. Errors if view isn't ready yet.
+    Count(Result<Vec<usize>, ()>),
     /// Read size of view
     Size(usize),
+    /// Rows in range, and whether there were more than the limit allowed for. Errors if view has
+    /// no ordered index.
+    Range(Result<(Datas, bool), ()>),
+    /// Rows in the batch, and the key to start the next batch after if there may be more. `None` if
+    /// view isn't ready yet. Errors if view does not allow scans.
+    Scan(Result<Option<(Datas, Option<Vec<DataType>>)>, ()>),
+    /// One page of rows, and the total number of rows for the key. Errors if view isn't ready
+    /// yet.
+    Page(Result<(Datas, usize), ()>),
+    /// Whether the write was visible before the timeout
+    Marker(bool),
+    /// The query's filter cannot be used on the rows of the view.
+    Invalid(FilterError),
+}
+
+/// Where a scan of a view's entire contents is at (see `View::scan_page`).
+///
+/// Tokens can be serialized, so that a scan can be picked up by another client, but they are only
+/// meaningful to the view that handed them out.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ScanToken {
+    shard: usize,
+    after: Option<Vec<DataType>>,
+}
+
+/// An iterator over batches of a view's entire contents (see `View::scan`).
+pub struct Scan<'a, E: 'a> {
+    view: &'a mut View<E>,
+    batch_size: usize,
+    // where the next batch starts, which is `None` once the scan is done or has failed
+    next: Option<Option<ScanToken>>,
+}
+
+impl<'a, E> Iterator for Scan<'a, E> {
+    type Item = Result<Datas, ViewError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let from = self.next.take()?;
+            match self.view.scan_page(from.as_ref(), self.batch_size) {
+                Ok((rows, next)) => {
+                    self.next = next.map(Some);
+                    if !rows.is_empty() {
+                        return Some(Ok(rows));
+                    }
+                    // an empty shard, or nothing left after the previous batch
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
 }
 
 #[doc(hidden)]
@@ -64,6 +328,12 @@ pub struct ViewBuilder {
     pub shards: Vec<SocketAddr>,
     // one per shard
     pub local_ports: Vec<u16>,
+    // whether the view is sharded by another column than its key
+    #[serde(default)]
+    pub scatter: bool,
+    // the rows that reads from the view may return, if not all of them
+    #[serde(default)]
+    pub filter: Option<RowFilter>,
 }
 
 impl ViewBuilder {
@@ -80,6 +350,9 @@ impl ViewBuilder {
             columns: self.columns,
             shard_addrs: self.shards,
             shards: conns,
+            scatter: self.scatter,
+            filter: self.filter,
+            ready_timeout: None,
             exclusivity: ExclusiveConnection,
         })
     }
@@ -128,6 +401,9 @@ impl ViewBuilder {
             columns: self.columns,
             shard_addrs: self.shards,
             shards: conns,
+            scatter: self.scatter,
+            filter: self.filter,
+            ready_timeout: None,
             exclusivity: SharedConnection,
         })
     }
@@ -139,11 +415,20 @@ impl ViewBuilder {
 /// connections to the Soup workers. For this reason, `View` is *not* `Send` or `Sync`. To
 /// get a handle that can be sent to a different thread (i.e., one with its own dedicated
 /// connections), call `View::into_exclusive`.
+///
+/// Reads from a view that is sharded by its key only go to the shard that a key belongs to. A view
+/// that is sharded by some other column may hold rows for a key in any of its shards, so reads
+/// from it go to all of them at once, and the rows they return are combined.
 pub struct View<E = SharedConnection> {
     node: NodeIndex,
     columns: Vec<String>,
     shards: Vec<ViewRpc>,
     shard_addrs: Vec<SocketAddr>,
+    scatter: bool,
+    // the rows that reads may return, if not all of them. it is sent along with every read, and
+    // can only be narrowed once it is set.
+    filter: Option<RowFilter>,
+    ready_timeout: Option<Duration>,
 
     #[allow(dead_code)]
     exclusivity: E,
@@ -156,6 +441,9 @@ impl Clone for View<SharedConnection> {
             columns: self.columns.clone(),
             shards: self.shards.clone(),
             shard_addrs: self.shard_addrs.clone(),
+            scatter: self.scatter,
+            filter: self.filter.clone(),
+            ready_timeout: self.ready_timeout,
             exclusivity: SharedConnection,
         }
     }
@@ -167,13 +455,18 @@ impl View<SharedConnection> {
     /// Produce a `View` with dedicated Soup connections so it can be safely sent across
     /// threads.
     pub fn into_exclusive(self) -> io::Result<View<ExclusiveConnection>> {
-        ViewBuilder {
+        let ready_timeout = self.ready_timeout;
+        let mut view = ViewBuilder {
             node: self.node,
             local_ports: vec![],
             columns: self.columns,
             shards: self.shard_addrs,
+            scatter: self.scatter,
+            filter: self.filter,
         }
-        .build_exclusive()
+        .build_exclusive()?;
+        view.set_ready_timeout(ready_timeout);
+        Ok(view)
     }
 }
 
@@ -187,6 +480,39 @@ impl<E> View<E> {
         self.columns.as_slice()
     }
 
+    /// Set how long blocking lookups wait for this view to finish being built before failing with
+    /// `ViewError::NotYetAvailable`. By default, they wait for as long as it takes.
+    pub fn set_ready_timeout(&mut self, timeout: Option<Duration>) {
+        self.ready_timeout = timeout;
+    }
+
+    /// Make this view only return the rows that pass the given filter, from now on.
+    ///
+    /// The filter is sent along with every read, and the rows it does not hold for are left out by
+    /// the workers that serve the reads, so they are never sent here. It applies to every way of
+    /// reading the view, including counts, scans, pages, and the view's size, which only counts
+    /// the keys that have rows that pass the filter. Views that are cloned from this view, or
+    /// made exclusive, keep the filter.
+    ///
+    /// A view that already has a filter keeps it, and then only returns the rows that pass both
+    /// filters, so a filtered view can be narrowed further but never widened again. Fails with
+    /// `ViewError::NoSuchColumn` if the filter reads a column that the view does not have, and
+    /// with `ViewError::UnsupportedOperator` if it uses an operator that does not compare two
+    /// values. In both cases the view is dropped rather than returned without the filter.
+    pub fn filtered(mut self, filter: RowFilter) -> Result<Self, ViewError> {
+        filter.check(self.columns.len())?;
+        self.filter = Some(match self.filter.take() {
+            Some(f) => f.and(filter),
+            None => filter,
+        });
+        Ok(self)
+    }
+
+    /// The filter that rows read from this view pass, if it has one (see `filtered`).
+    pub fn filter(&self) -> Option<&RowFilter> {
+        self.filter.as_ref()
+    }
+
     /// Get the local address this `View` is bound to.
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
         self.shards[0].borrow().local_addr()
@@ -199,9 +525,10 @@ impl<E> View<E> {
             let reply = shard
                 .send(&ReadQuery::Size {
                     target: (self.node, 0),
+                    filter: self.filter.clone(),
                 })
                 .map_err(TransportError::from)?;
-            match reply {
+            match accepted(reply)? {
                 ReadReply::Size(rows) => Ok(rows),
                 _ => unreachable!(),
             }
@@ -213,10 +540,11 @@ impl<E> View<E> {
                     let reply = shard
                         .send(&ReadQuery::Size {
                             target: (self.node, shardi),
+                            filter: self.filter.clone(),
                         })
                         .map_err(TransportError::from)?;
 
-                    match reply {
+                    match accepted(reply)? {
                         ReadReply::Size(rows) => Ok(acc + rows),
                         _ => unreachable!(),
                     }
@@ -225,38 +553,109 @@ impl<E> View<E> {
         }
     }
 
-    /// Retrieve the query results for the given parameter values.
+    /// Wait until the write that carries the given marker is visible to reads from every shard of
+    /// this view, or until `timeout` has passed. Returns whether it became visible in time.
     ///
-    /// The method will block if the results are not yet available only when `block` is `true`.
-    /// If `block` is false, misses will be returned as empty results. Any requested keys that have
-    /// missing state will be backfilled (asynchronously if `block` is `false`).
-    pub fn multi_lookup(
+    /// A shard only remembers a limited number of writes that no one has waited for yet, so the
+    /// wait should start soon after the write is acknowledged.
+    #[doc(hidden)]
+    pub fn wait_for_marker(
+        &mut self,
+        marker: u64,
+        timeout: Duration,
+    ) -> Result<bool, TransportError> {
+        let replies = self
+            .query_all(|target| ReadQuery::Marker {
+                target,
+                marker,
+                timeout,
+            })
+            .map_err(|e| match e {
+                ViewError::TransportError(e) => e,
+                e => unreachable!("waiting for a write failed: {:?}", e),
+            })?;
+        Ok(replies.into_iter().all(|r| match r {
+            ReadReply::Marker(seen) => seen,
+            _ => unreachable!(),
+        }))
+    }
+
+    /// Send a query built by `query` to every shard at once, and wait for all of their replies.
+    fn query_all<Q>(&mut self, query: Q) -> Result<Vec<ReadReply>, ViewError>
+    where
+        Q: Fn((NodeIndex, usize)) -> ReadQuery,
+    {
+        let node = self.node;
+        let mut borrow_all: Vec<_> = self.shards.iter().map(|s| s.borrow_mut()).collect();
+        let qs = borrow_all
+            .iter_mut()
+            .enumerate()
+            .map(|(shardi, shard)| {
+                shard
+                    .send_async(&query((node, shardi)))
+                    .map_err(TransportError::from)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut replies = Vec::with_capacity(qs.len());
+        for res in qs {
+            replies.push(res.wait().map_err(TransportError::from)?);
+        }
+        replies.into_iter().map(accepted).collect()
+    }
+
+    /// Send a query built by `query` for the given keys to the shards that may hold rows for them,
+    /// and collect the per-key results that `results` extracts from the replies in the order of
+    /// `keys`. The results for a key from different shards are combined using `merge`.
+    ///
+    /// If `partial` is set, shards that are not yet ready are left out instead of failing the
+    /// whole read, and the returned flag says whether every shard that was asked was ready.
+    fn query_keys<T, Q, R, M>(
         &mut self,
         keys: Vec<Vec<DataType>>,
-        block: bool,
-    ) -> Result<Vec<Datas>, ViewError> {
+        partial: bool,
+        query: Q,
+        results: R,
+        merge: M,
+    ) -> Result<(Vec<T>, bool), ViewError>
+    where
+        T: Clone + Default,
+        Q: Fn((NodeIndex, usize), Vec<Vec<DataType>>) -> ReadQuery,
+        R: Fn(ReadReply) -> Result<Vec<T>, ()>,
+        M: Fn(&mut T, T),
+    {
+        let nkeys = keys.len();
         if self.shards.len() == 1 {
             let mut shard = self.shards[0].borrow_mut();
             let reply = shard
-                .send(&ReadQuery::Normal {
-                    target: (self.node, 0),
-                    keys,
-                    block,
-                })
+                .send(&query((self.node, 0), keys))
                 .map_err(TransportError::from)?;
-            match reply {
-                ReadReply::Normal(Ok(rows)) => Ok(rows),
-                ReadReply::Normal(Err(())) => Err(ViewError::NotYetAvailable),
-                _ => unreachable!(),
+            match results(accepted(reply)?) {
+                Ok(found) => Ok((found, true)),
+                Err(()) if partial => Ok((vec![T::default(); nkeys], false)),
+                Err(()) => Err(ViewError::NotYetAvailable),
             }
         } else {
-            assert!(keys.iter().all(|k| k.len() == 1));
             let mut shard_queries = vec![Vec::new(); self.shards.len()];
-            for key in keys {
-                let shard = crate::shard_by(&key[0], self.shards.len());
-                shard_queries[shard].push(key);
+            // where in the input each shard's keys came from, so we can put the results back in
+            // the same order
+            let mut shard_positions = vec![Vec::new(); self.shards.len()];
+            if self.scatter {
+                // the rows for a key may be in any shard, so every shard is asked for every key
+                for (sq, sp) in shard_queries.iter_mut().zip(&mut shard_positions) {
+                    *sq = keys.clone();
+                    *sp = (0..nkeys).collect();
+                }
+            } else {
+                assert!(keys.iter().all(|k| k.len() == 1));
+                for (i, key) in keys.into_iter().enumerate() {
+                    let shard = crate::shard_by(&key[0], self.shards.len());
+                    shard_queries[shard].push(key);
+                    shard_positions[shard].push(i);
+                }
             }
 
+            let node = self.node;
             let mut borrow_all: Vec<_> = self.shards.iter().map(|s| s.borrow_mut()).collect();
 
             let qs = borrow_all
@@ -266,28 +665,416 @@ impl<E> View<E> {
                 .filter(|&(_, ref sq)| !sq.is_empty())
                 .map(|((shardi, shard), shard_queries)| {
                     use std::mem;
-                    Ok(shard
-                        .send_async(&ReadQuery::Normal {
-                            target: (self.node, shardi),
-                            keys: mem::replace(shard_queries, Vec::new()),
-                            block,
-                        })
-                        .map_err(TransportError::from)?)
+                    let keys = mem::replace(shard_queries, Vec::new());
+                    let reply = shard
+                        .send_async(&query((node, shardi), keys))
+                        .map_err(TransportError::from)?;
+                    Ok((shardi, reply))
                 })
                 .collect::<Result<Vec<_>, ViewError>>()?;
 
-            let mut results = Vec::new();
-            for res in qs {
-                let reply = res.wait().map_err(TransportError::from)?;
+            // all the replies are waited for even if some shard isn't ready, so that none of them
+            // are left behind on the connections
+            let mut found = vec![T::default(); nkeys];
+            let mut ready = true;
+            let mut refused = None;
+            for (shardi, res) in qs {
+                let reply = match accepted(res.wait().map_err(TransportError::from)?) {
+                    Ok(reply) => reply,
+                    Err(e) => {
+                        refused = Some(e);
+                        continue;
+                    }
+                };
+                match results(reply) {
+                    Ok(shard_found) => {
+                        for (i, r) in shard_positions[shardi].drain(..).zip(shard_found) {
+                            merge(&mut found[i], r);
+                        }
+                    }
+                    Err(()) => ready = false,
+                }
+            }
+
+            if let Some(e) = refused {
+                return Err(e);
+            }
+            if !ready && !partial {
+                return Err(ViewError::NotYetAvailable);
+            }
+            Ok((found, ready))
+        }
+    }
+
+    /// Retrieve the query results for the given parameter values.
+    ///
+    /// The results are returned in the same order as `keys`, one entry per key. The keys are all
+    /// looked up in a single request to each shard, and the keys that are answered from a given
+    /// shard all reflect the same version of that shard's state.
+    ///
+    /// The method will block if the results are not yet available only when `block` is `true`.
+    /// If `block` is false, misses will be returned as empty results. Any requested keys that have
+    /// missing state will be backfilled (asynchronously if `block` is `false`). When blocking,
+    /// only the keys that were missing are waited for.
+    ///
+    /// If the view is still being built, this fails with `ViewError::NotYetAvailable` when
+    /// `block` is `false`. When `block` is `true`, it instead waits for the view to become ready,
+    /// for at most the view's ready timeout (see `set_ready_timeout`).
+    pub fn multi_lookup(
+        &mut self,
+        keys: Vec<Vec<DataType>>,
+        block: bool,
+    ) -> Result<Vec<Datas>, ViewError> {
+        self.read_keys(keys, None, block, false)
+            .map(|(rows, _)| rows)
+    }
+
+    /// Retrieve the query results for the given parameter values from the shards of the view
+    /// that are ready, along with whether all of the shards that were asked were.
+    ///
+    /// This behaves like `multi_lookup`, except that shards that are still being built do not
+    /// fail the whole lookup with `ViewError::NotYetAvailable`. Instead, the rows they hold are
+    /// left out of the results, and `false` is returned alongside them. When `block` is `true`,
+    /// each shard is still waited for, for at most the view's ready timeout.
+    pub fn multi_lookup_available(
+        &mut self,
+        keys: Vec<Vec<DataType>>,
+        block: bool,
+    ) -> Result<(Vec<Datas>, bool), ViewError> {
+        self.read_keys(keys, None, block, true)
+    }
+
+    /// Retrieve only the given columns of the query results for the given parameter values.
+    ///
+    /// Each returned row holds the values of the columns at the indices in `columns`, in that
+    /// order. Only those values are copied out of the view and sent back, so this is cheaper than
+    /// `multi_lookup` when only a few columns of wide rows are needed. Otherwise, this behaves
+    /// like `multi_lookup`.
+    ///
+    /// Fails with `ViewError::NoSuchColumn` if the view has no column at one of the indices.
+    pub fn multi_lookup_projected(
+        &mut self,
+        keys: Vec<Vec<DataType>>,
+        columns: &[usize],
+        block: bool,
+    ) -> Result<Vec<Datas>, ViewError> {
+        let project = self.projection(columns)?;
+        self.read_keys(keys, project, block, false)
+            .map(|(rows, _)| rows)
+    }
+
+    /// Work out which columns to ask the reader for. If `columns` are simply all the view's
+    /// columns in order, whole rows are asked for, so that reads that happen to ask for every
+    /// column cost no more than unprojected reads.
+    fn projection(&self, columns: &[usize]) -> Result<Option<Vec<usize>>, ViewError> {
+        if let Some(&c) = columns.iter().find(|&&c| c >= self.columns.len()) {
+            return Err(ViewError::NoSuchColumn(c));
+        }
+
+        if columns.len() == self.columns.len() && columns.iter().enumerate().all(|(i, &c)| i == c) {
+            Ok(None)
+        } else {
+            Ok(Some(columns.to_vec()))
+        }
+    }
+
+    fn read_keys(
+        &mut self,
+        keys: Vec<Vec<DataType>>,
+        project: Option<Vec<usize>>,
+        block: bool,
+        partial: bool,
+    ) -> Result<(Vec<Datas>, bool), ViewError> {
+        let ready_timeout = self.ready_timeout;
+        let filter = self.filter.clone();
+        self.query_keys(
+            keys,
+            partial,
+            |target, keys| ReadQuery::Normal {
+                target,
+                filter: filter.clone(),
+                keys,
+                project: project.clone(),
+                block,
+                ready_timeout,
+            },
+            |reply| match reply {
+                ReadReply::Normal(rows) => rows,
+                _ => unreachable!(),
+            },
+            |rows, more| {
+                if rows.is_empty() {
+                    *rows = more;
+                } else {
+                    rows.extend(more);
+                }
+            },
+        )
+    }
+
+    /// Count the query results for each of the given parameter values, without retrieving them.
+    ///
+    /// The counts are returned in the same order as `keys`, and otherwise behave like the results
+    /// of `multi_lookup`. In particular, keys that miss are counted as having no rows unless
+    /// `block` is `true`.
+    pub fn count_multi(
+        &mut self,
+        keys: Vec<Vec<DataType>>,
+        block: bool,
+    ) -> Result<Vec<usize>, ViewError> {
+        let ready_timeout = self.ready_timeout;
+        let filter = self.filter.clone();
+        self.query_keys(
+            keys,
+            false,
+            |target, keys| ReadQuery::Count {
+                target,
+                filter: filter.clone(),
+                keys,
+                block,
+                ready_timeout,
+            },
+            |reply| match reply {
+                ReadReply::Count(counts) => counts,
+                _ => unreachable!(),
+            },
+            |n, more| *n += more,
+        )
+        .map(|(counts, _)| counts)
+    }
+
+    /// Count the query results for the given parameter value, without retrieving them.
+    ///
+    /// The method will block if the results are not yet available only when `block` is `true`.
+    pub fn count(&mut self, key: &[DataType], block: bool) -> Result<usize, ViewError> {
+        self.count_multi(vec![Vec::from(key)], block)
+            .map(|counts| counts[0])
+    }
+
+    /// Check whether there are any query results for the given parameter value, without
+    /// retrieving them.
+    ///
+    /// The method will block if the results are not yet available only when `block` is `true`.
+    pub fn exists(&mut self, key: &[DataType], block: bool) -> Result<bool, ViewError> {
+        self.count(key, block).map(|n| n > 0)
+    }
+
+    /// Retrieve the rows whose key lies within the given bounds, in key order. If `limit` is
+    /// given, at most that many rows are returned, along with whether any rows in range were left
+    /// out because of it.
+    ///
+    /// Only views maintained with an ordered index support range lookups. Such views are always
+    /// fully materialized and never sharded.
+    pub fn lookup_range(
+        &mut self,
+        lower: Bound<DataType>,
+        upper: Bound<DataType>,
+        limit: Option<usize>,
+    ) -> Result<(Datas, bool), ViewError> {
+        if self.shards.len() != 1 {
+            return Err(ViewError::NotOrdered);
+        }
+
+        let mut shard = self.shards[0].borrow_mut();
+        let reply = shard
+            .send(&ReadQuery::Range {
+                target: (self.node, 0),
+                filter: self.filter.clone(),
+                lower: lower.into(),
+                upper: upper.into(),
+                limit,
+            })
+            .map_err(TransportError::from)?;
+        match accepted(reply)? {
+            ReadReply::Range(Ok(found)) => Ok(found),
+            ReadReply::Range(Err(())) => Err(ViewError::NotOrdered),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Read the entire contents of this view, in batches of at least `batch_size` rows, except for
+    /// the last. Every batch holds all the rows of the keys in it, so a batch may hold more rows
+    /// than that. Keys are read in order within each shard, and shards are read one after the
+    /// other.
+    ///
+    /// Writes keep being applied while the view is being read, and each batch is read from the
+    /// state of the view as it is when the batch is read. A row that is in the view for the whole
+    /// of the scan is returned exactly once. A row that is added or removed while the scan goes
+    /// on may or may not be returned.
+    ///
+    /// Only views that were allowed to be scanned when they were added support this, and the scan
+    /// fails with `ViewError::NotScannable` otherwise. It fails with `ViewError::NotYetAvailable`
+    /// if the view isn't ready yet, rather than waiting for it. The iterator ends after the first
+    /// error.
+    pub fn scan(&mut self, batch_size: usize) -> Scan<E> {
+        Scan {
+            view: self,
+            batch_size,
+            next: Some(None),
+        }
+    }
+
+    /// Read one batch of the entire contents of this view, as `scan` does, starting where `from`
+    /// says, or at the start of the view if it is `None`. Along with the batch, a token for where
+    /// the next batch starts is returned, unless the scan is done.
+    ///
+    /// The batch may be empty even if the scan is not yet done, when a shard of the view is empty.
+    pub fn scan_page(
+        &mut self,
+        from: Option<&ScanToken>,
+        batch_size: usize,
+    ) -> Result<(Datas, Option<ScanToken>), ViewError> {
+        let shardi = from.map(|t| t.shard).unwrap_or(0);
+        let after = from.and_then(|t| t.after.clone());
+        assert!(shardi < self.shards.len(), "scan token is for another view");
+
+        let mut shard = self.shards[shardi].borrow_mut();
+        let reply = shard
+            .send(&ReadQuery::Scan {
+                target: (self.node, shardi),
+                filter: self.filter.clone(),
+                after,
+                limit: batch_size,
+            })
+            .map_err(TransportError::from)?;
+        match accepted(reply)? {
+            ReadReply::Scan(Ok(Some((rows, after)))) => {
+                let next = match after {
+                    Some(after) => Some(ScanToken {
+                        shard: shardi,
+                        after: Some(after),
+                    }),
+                    None if shardi + 1 < self.shards.len() => Some(ScanToken {
+                        shard: shardi + 1,
+                        after: None,
+                    }),
+                    None => None,
+                };
+                Ok((rows, next))
+            }
+            ReadReply::Scan(Ok(None)) => Err(ViewError::NotYetAvailable),
+            ReadReply::Scan(Err(())) => Err(ViewError::NotScannable),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Retrieve one page of the query results for the given parameter value: at most `limit`
+    /// rows, starting at `offset`, with the rows ordered by the value of the column at index
+    /// `order_by.0` in the direction given by `order_by.1`. The total number of rows for the
+    /// parameter value is returned along with the page.
+    ///
+    /// Rows with the same value in that column are ordered by their remaining values, so the
+    /// order is the same every time the same rows are read. The rows are sorted anew for every
+    /// call, unless the view was set up to keep its rows sorted by the same column.
+    ///
+    /// The method always blocks until the results are available, but waits for the view to become
+    /// ready for at most the view's ready timeout (see `set_ready_timeout`).
+    pub fn lookup_page(
+        &mut self,
+        key: &[DataType],
+        order_by: (usize, Direction),
+        offset: usize,
+        limit: usize,
+    ) -> Result<(Datas, usize), ViewError> {
+        self.read_page(key, order_by, offset, limit, None)
+    }
+
+    /// Retrieve one page of the query results for the given parameter value, like `lookup_page`,
+    /// but return only the columns at the indices in `columns`, in that order.
+    ///
+    /// `order_by` still refers to a column of the view, and need not be one of the returned
+    /// columns. Fails with `ViewError::NoSuchColumn` if the view has no column at one of the
+    /// indices in `columns`.
+    pub fn lookup_page_projected(
+        &mut self,
+        key: &[DataType],
+        order_by: (usize, Direction),
+        offset: usize,
+        limit: usize,
+        columns: &[usize],
+    ) -> Result<(Datas, usize), ViewError> {
+        let project = self.projection(columns)?;
+        self.read_page(key, order_by, offset, limit, project)
+    }
+
+    fn read_page(
+        &mut self,
+        key: &[DataType],
+        order_by: (usize, Direction),
+        offset: usize,
+        limit: usize,
+        project: Option<Vec<usize>>,
+    ) -> Result<(Datas, usize), ViewError> {
+        if self.scatter {
+            // any shard may hold rows that belong on the page, so the first `offset + limit` rows
+            // of every shard are merged here, and only then projected
+            let ready_timeout = self.ready_timeout;
+            let filter = self.filter.clone();
+            let replies = self.query_all(|target| ReadQuery::Page {
+                target,
+                filter: filter.clone(),
+                key: Vec::from(key),
+                order_by,
+                offset: 0,
+                limit: offset.saturating_add(limit),
+                project: None,
+                ready_timeout,
+            })?;
+
+            let mut rows = Vec::new();
+            let mut total = 0;
+            let mut ready = true;
+            for reply in replies {
                 match reply {
-                    ReadReply::Normal(Ok(rows)) => {
-                        results.extend(rows);
+                    ReadReply::Page(Ok((shard_rows, shard_total))) => {
+                        rows.extend(shard_rows);
+                        total += shard_total;
                     }
-                    ReadReply::Normal(Err(())) => return Err(ViewError::NotYetAvailable),
+                    ReadReply::Page(Err(())) => ready = false,
                     _ => unreachable!(),
                 }
             }
-            Ok(results)
+            if !ready {
+                return Err(ViewError::NotYetAvailable);
+            }
+
+            rows.sort_by(|a, b| compare_rows(a, b, order_by));
+            let page = rows
+                .into_iter()
+                .skip(offset)
+                .take(limit)
+                .map(|row| match project {
+                    Some(ref columns) => columns.iter().map(|&c| row[c].clone()).collect(),
+                    None => row,
+                })
+                .collect();
+            return Ok((page, total));
+        }
+
+        let shardi = if self.shards.len() == 1 {
+            0
+        } else {
+            assert_eq!(key.len(), 1);
+            crate::shard_by(&key[0], self.shards.len())
+        };
+
+        let mut shard = self.shards[shardi].borrow_mut();
+        let reply = shard
+            .send(&ReadQuery::Page {
+                target: (self.node, shardi),
+                filter: self.filter.clone(),
+                key: Vec::from(key),
+                order_by,
+                offset,
+                limit,
+                project,
+                ready_timeout: self.ready_timeout,
+            })
+            .map_err(TransportError::from)?;
+        match accepted(reply)? {
+            ReadReply::Page(Ok(page)) => Ok(page),
+            ReadReply::Page(Err(())) => Err(ViewError::NotYetAvailable),
+            _ => unreachable!(),
         }
     }
 
@@ -299,4 +1086,82 @@ impl<E> View<E> {
         self.multi_lookup(vec![Vec::from(key)], block)
             .map(|rs| rs.into_iter().next().unwrap())
     }
+
+    /// Retrieve only the given columns of the query results for the given parameter value (see
+    /// `multi_lookup_projected`).
+    ///
+    /// The method will block if the results are not yet available only when `block` is `true`.
+    pub fn lookup_projected(
+        &mut self,
+        key: &[DataType],
+        columns: &[usize],
+        block: bool,
+    ) -> Result<Datas, ViewError> {
+        self.multi_lookup_projected(vec![Vec::from(key)], columns, block)
+            .map(|rs| rs.into_iter().next().unwrap())
+    }
+
+    /// Retrieve the query results for the given parameter value, along with what is known about
+    /// how up to date they are. This can be used to decide whether results are fresh enough to
+    /// use, or whether to wait for a write to become visible first.
+    ///
+    /// The method will block if the results are not yet available only when `block` is `true`.
+    pub fn lookup_with_meta(
+        &mut self,
+        key: &[DataType],
+        block: bool,
+    ) -> Result<(Datas, ReadMeta), ViewError> {
+        if self.scatter {
+            let ready_timeout = self.ready_timeout;
+            let filter = self.filter.clone();
+            let replies = self.query_all(|target| ReadQuery::WithMeta {
+                target,
+                filter: filter.clone(),
+                keys: vec![Vec::from(key)],
+                block,
+                ready_timeout,
+            })?;
+
+            let mut found = Vec::new();
+            let mut found_meta: Option<ReadMeta> = None;
+            let mut ready = true;
+            for reply in replies {
+                match reply {
+                    ReadReply::WithMeta(Ok((mut rows, meta))) => {
+                        found.extend(rows.swap_remove(0));
+                        found_meta = Some(found_meta.map_or(meta, |m| m.and(meta)));
+                    }
+                    ReadReply::WithMeta(Err(())) => ready = false,
+                    _ => unreachable!(),
+                }
+            }
+            if !ready {
+                return Err(ViewError::NotYetAvailable);
+            }
+            return Ok((found, found_meta.unwrap()));
+        }
+
+        let shardi = if self.shards.len() == 1 {
+            0
+        } else {
+            assert_eq!(key.len(), 1);
+            crate::shard_by(&key[0], self.shards.len())
+        };
+
+        let mut shard = self.shards[shardi].borrow_mut();
+        let reply = shard
+            .send(&ReadQuery::WithMeta {
+                target: (self.node, shardi),
+                filter: self.filter.clone(),
+                keys: vec![Vec::from(key)],
+                block,
+                ready_timeout: self.ready_timeout,
+            })
+            .map_err(TransportError::from)?;
+        match accepted(reply)? {
+            ReadReply::WithMeta(Ok((mut rows, meta))) => Ok((rows.swap_remove(0), meta)),
+            ReadReply::WithMeta(Err(())) => Err(ViewError::NotYetAvailable),
+            _ => unreachable!(),
+        }
+    }
 }